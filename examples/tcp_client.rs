@@ -13,6 +13,7 @@ use tox::toxcore::crypto_core::*;
 use tox::toxcore::tcp::packet::*;
 use tox::toxcore::tcp::handshake::make_client_handshake;
 use tox::toxcore::tcp::codec;
+use tox::toxcore::tcp::proxy::{self, Proxy};
 use tox::toxcore::io_tokio::IoFuture;
 
 use failure::{Error, err_msg};
@@ -25,7 +26,6 @@ use futures::future::Either;
 use futures::sync::mpsc;
 
 use tokio_codec::Framed;
-use tokio::net::TcpStream;
 
 use std::{thread, time};
 
@@ -75,7 +75,11 @@ fn create_client(rx: mpsc::Receiver<Packet>, tx: mpsc::Sender<Packet>) -> IoFutu
         }
     };
 
-    let client = TcpStream::connect(&addr)
+    // Set to `Some(Proxy::Socks5 { .. })` or `Some(Proxy::Http { .. })` to
+    // connect to the relay via proxy
+    let proxy: Option<Proxy> = None;
+
+    let client = proxy::connect(proxy.as_ref(), &addr)
         .map_err(Error::from)
         .and_then(move |socket| {
             make_client_handshake(socket, &client_pk, &client_sk, &server_pk)
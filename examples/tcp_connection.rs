@@ -9,13 +9,13 @@ use tox::toxcore::crypto_core::{PublicKey, SecretKey};
 use tox::toxcore::tcp::packet::*;
 use tox::toxcore::tcp::handshake::make_client_handshake;
 use tox::toxcore::tcp::codec;
+use tox::toxcore::tcp::proxy::{self, Proxy};
 use tox::toxcore::tcp::client::*;
 
 use failure::{Error, err_msg};
 use futures::{Future, Sink, Stream};
 
 use tokio_codec::Framed;
-use tokio::net::TcpStream;
 
 fn main() {
     env_logger::init();
@@ -48,8 +48,12 @@ fn main() {
         processor
     } = ClientProcessor::new();
 
+    // Set to `Some(Proxy::Socks5 { .. })` or `Some(Proxy::Http { .. })` to
+    // connect to the relay via proxy
+    let proxy: Option<Proxy> = None;
+
     // Initialize network communication
-    let network = TcpStream::connect(&addr)
+    let network = proxy::connect(proxy.as_ref(), &addr)
         .map_err(Error::from)
         .and_then(move |socket| {
             make_client_handshake(socket, &client_pk, &client_sk, &server_pk)
@@ -6,10 +6,10 @@ extern crate env_logger;
 extern crate failure;
 
 use tox::toxcore::crypto_core::{PublicKey, SecretKey};
-use tox::toxcore::tcp::packet::*;
 use tox::toxcore::tcp::connections::*;
 
-use futures::{Future, Sink, Stream};
+use futures::{Future, Stream};
+use futures::sync::mpsc;
 
 use failure::{Error};
 
@@ -35,64 +35,31 @@ fn main() {
         193, 129, 141, 80, 158, 50, 133, 100,
         182, 179, 183, 234, 116, 142, 102, 53, 38]);
 
-    let connections = Connections::new(client_pk.clone(), client_sk.clone());
+    let (incoming_tx, incoming_rx) = mpsc::unbounded();
 
-    // Create ConnectionsProcessor
-    let ConnectionsProcessor {
-        from_net_crypto_tx,
-        to_net_crypto_rx,
-        from_server_tx,
-        to_server_rx,
-        processor
-    } = ConnectionsProcessor::new();
+    let connections = Connections::new(client_pk, client_sk, incoming_tx);
 
-    let add_relay = connections.add_relay(&addr, &server_pk, from_server_tx)
-        .map_err(Error::from);
+    let add_relay = connections.add_relay(addr, server_pk);
 
-    let network_writer = to_server_rx
-        .map_err(|()| unreachable!("rx can't fail"))
-        .for_each(move |(packet, _connection_id)| {
-            connections.send_packet(&server_pk, packet)
-        })
-        .map(|_| ());
+    // Keep connection to the friend via relays
+    let friend_pk = PublicKey([15, 107, 126, 130, 81, 55, 154, 157,
+        192, 117, 0, 225, 119, 43, 48, 117,
+        84, 109, 112, 57, 243, 216, 4, 171,
+        185, 111, 33, 146, 221, 31, 77, 118]);
+    connections.add_connection(friend_pk);
 
     // Read incoming messages
-    let incomings = to_net_crypto_rx
+    let incomings = incoming_rx
         .map_err(|()| unreachable!("rx can't fail"))
-        .for_each(|packet| {
-            println!("Got packet: {:?}", packet);
-            // We may send something to server using `from_client_tx`
-            //  but for now we do nothing
+        .for_each(|(packet, relay_pk)| {
+            println!("Got packet from {:?}: {:?}", relay_pk, packet);
             Ok(())
-        })
-        .map(|_| ());
-
-    let processor = processor
-        .map_err(Error::from)
-        .join(add_relay)
-        .map(|_| ());
-
-    // Combine network with incomings
-    let net_crypto = processor
-        .map_err(Error::from)
-        .select(network_writer)
-        .map(|_| ())
-        .map_err(|(e,_)| e)
-        .select(incomings)
-        .map(|_| ())
-        .map_err(|(_e,_)| ());
-
-    {
-        // Send RouteRequest to server
-        let friend_pk = PublicKey([15, 107, 126, 130, 81, 55, 154, 157,
-            192, 117, 0, 225, 119, 43, 48, 117,
-            84, 109, 112, 57, 243, 216, 4, 171,
-            185, 111, 33, 146, 221, 31, 77, 118]);
+        });
 
-        from_net_crypto_tx.clone().send((OutgoingPacket::RouteRequest(
-            RouteRequest { pk: friend_pk }
-        ), server_pk)).wait().unwrap();
-    }
+    let net_crypto = add_relay
+        .select(connections.run()).map(|_| ()).map_err(|(e, _)| e)
+        .select(incomings).map(|_| ()).map_err(|(e, _)| e)
+        .map_err(|e: Error| println!("Error: {:?}", e));
 
     // Connect to server and wait for incoming packets.
     // Ping/Pong will be sent automatically
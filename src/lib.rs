@@ -30,6 +30,8 @@ extern crate parking_lot;
 #[macro_use]
 extern crate failure;
 extern crate lru;
+extern crate sha1;
+extern crate base64;
 
 #[cfg(test)]
 extern crate tokio_timer;
@@ -54,7 +56,10 @@ pub mod toxcore {
     pub mod net_crypto;
     pub mod utils;
     pub mod friend_connection;
+    pub mod friend_requests;
     pub mod messenger;
+    pub mod conference;
+    pub mod tox;
 }
 
 /// Tox Encrypt Save (a.k.a. **TES**) module. Can be used to ecrypt / decrypt
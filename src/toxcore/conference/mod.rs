@@ -0,0 +1,1760 @@
+/*! The implementation of legacy conferences
+
+Conferences are group chats built on top of friend connections. Every
+conference has a unique id and is known under its own number by every
+member. Members of a conference are identified by 16 bits peer numbers that
+are assigned by the member that introduced them.
+
+A friend joins a conference by answering the invite with
+`ConferenceInviteResponse` packet. The inviter assigns a peer number to the
+friend and announces it to the conference with `NewPeer` message. The new
+member then queries the list of peers and the title from the inviter.
+
+Messages are sent to close connections of the conference and relayed by
+every peer to its own close connections so they reach all members. Close
+connections are introducers and a few peers that are closest to us by their
+long term `PublicKey`s. Peers that are not our friends are connected via
+friend connections as well.
+
+Every member sends `Ping` message periodically. Peers that were not heard
+from for `PEER_TIMEOUT` seconds are removed from the conference.
+
+*/
+
+pub mod packet;
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use futures::{future, Future, Stream};
+use futures::sync::mpsc;
+use parking_lot::RwLock;
+use tokio::timer::Interval;
+
+use toxcore::binary_io::*;
+use toxcore::conference::packet::*;
+use toxcore::crypto_core::*;
+use toxcore::dht::packet::MAX_CRYPTO_DATA_SIZE;
+use toxcore::friend_connection::FriendConnections;
+use toxcore::io_tokio::*;
+use toxcore::messenger::{Event, MessageKind, Messenger};
+use toxcore::messenger::packet::{
+    ConferenceInvite,
+    ConferenceInviteResponse,
+    ConferenceType,
+    CONFERENCE_UID_BYTES,
+    Packet as MessengerPacket,
+};
+use toxcore::net_crypto::NetCrypto;
+use toxcore::state_format::old::{ConferencePeerState, ConferenceState, Conferences as ConferencesState};
+use toxcore::time::*;
+
+/// Interval in seconds for running the main loop.
+const MAIN_LOOP_INTERVAL: u64 = 1;
+
+/// Interval in seconds for sending `Ping` messages to a conference.
+const PING_INTERVAL: u64 = 20;
+
+/// Peers that were not heard from for this amount of seconds are removed
+/// from the conference.
+const PEER_TIMEOUT: u64 = 60;
+
+/// Maximum number of close connections of a conference excluding
+/// introducers. Half of them are peers that are closest to us from one side
+/// and the other half from the other side.
+const MAX_CLOSE_CONNECTIONS: usize = 4;
+
+/// Size in bytes of the header of `DirectConference` packet.
+const DIRECT_CONFERENCE_HEADER_SIZE: usize = 4;
+
+/// Shorthand for the transmit half of the message channel for sending
+/// messenger events.
+type EventTx = mpsc::UnboundedSender<Event>;
+
+/// Shorthand for the transmit half of the message channel for sending DHT
+/// `PublicKey`s of peers we want to connect to.
+type DhtPkTx = mpsc::UnboundedSender<(PublicKey, PublicKey)>;
+
+/// Serialize packet to bytes that can be sent via `net_crypto`.
+fn serialize_packet<T: ToBytes>(packet: &T) -> Result<Vec<u8>, Error> {
+    let mut buf = [0; MAX_CRYPTO_DATA_SIZE];
+    match packet.to_bytes((&mut buf, 0)) {
+        Ok((_, size)) => Ok(buf[..size].to_vec()),
+        Err(e) => Err(Error::new(
+            ErrorKind::Other,
+            format!("Failed to serialize packet: {:?}", e)
+        )),
+    }
+}
+
+/// Value that is used to find peers that are close to us. It's made of the
+/// first 8 bytes of the `PublicKey`.
+fn comp_value(pk: &PublicKey) -> u64 {
+    pk.as_ref()[..8].iter().fold(0, |value, &byte| (value << 8) | u64::from(byte))
+}
+
+/// Event that happened in one of conferences.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ConferenceEvent {
+    /// Friend invited us to a conference. It can be joined with
+    /// `Conferences::join`.
+    Invite {
+        /// Long term `PublicKey` of the friend
+        pk: PublicKey,
+        /// Invite that should be passed to `Conferences::join`
+        invite: ConferenceInvite,
+    },
+    /// We learned our peer number and became a member of the conference.
+    Connected {
+        /// Number of the conference
+        conference_id: u16,
+    },
+    /// New peer joined the conference.
+    PeerJoined {
+        /// Number of the conference
+        conference_id: u16,
+        /// Number of the peer in the conference
+        peer_id: u16,
+        /// Long term `PublicKey` of the peer
+        pk: PublicKey,
+    },
+    /// Peer left the conference or timed out.
+    PeerLeft {
+        /// Number of the conference
+        conference_id: u16,
+        /// Number of the peer in the conference
+        peer_id: u16,
+        /// Long term `PublicKey` of the peer
+        pk: PublicKey,
+    },
+    /// Peer changed its name.
+    PeerName {
+        /// Number of the conference
+        conference_id: u16,
+        /// Number of the peer in the conference
+        peer_id: u16,
+        /// New name
+        name: String,
+    },
+    /// Title of the conference was changed.
+    Title {
+        /// Number of the conference
+        conference_id: u16,
+        /// Number of the peer that changed the title or `None` if the title
+        /// was received when joining the conference
+        peer_id: Option<u16>,
+        /// New title
+        title: String,
+    },
+    /// Chat or action message is received.
+    Message {
+        /// Number of the conference
+        conference_id: u16,
+        /// Number of the peer that sent the message
+        peer_id: u16,
+        /// Whether it's a normal or an action message
+        kind: MessageKind,
+        /// The message
+        message: String,
+    },
+    /// Lossy packet is received.
+    Lossy {
+        /// Number of the conference
+        conference_id: u16,
+        /// Number of the peer that sent the packet
+        peer_id: u16,
+        /// Data of the packet starting with its id
+        data: Vec<u8>,
+    },
+}
+
+/// Member of a conference.
+#[derive(Clone, Debug)]
+struct Peer {
+    /// Long term `PublicKey` of the peer.
+    real_pk: PublicKey,
+    /// DHT `PublicKey` of the peer.
+    temp_pk: PublicKey,
+    /// Name of the peer.
+    name: String,
+    /// Number of the last message received from the peer.
+    last_message_number: Option<u32>,
+    /// Number of the last lossy packet received from the peer.
+    last_lossy_number: Option<u16>,
+    /// Time when we heard from the peer for the last time.
+    last_active: Instant,
+}
+
+impl Peer {
+    /// Create new `Peer` without a name.
+    fn new(real_pk: PublicKey, temp_pk: PublicKey) -> Peer {
+        Peer {
+            real_pk,
+            temp_pk,
+            name: String::new(),
+            last_message_number: None,
+            last_lossy_number: None,
+            last_active: clock_now(),
+        }
+    }
+}
+
+/// Connection to a peer that is used to exchange packets of a conference.
+#[derive(Clone, Debug)]
+struct Connection {
+    /// Number of the conference on the peer's side. It's known after we
+    /// receive `OnlineConference` packet from the peer.
+    remote_id: Option<u16>,
+    /// Whether the peer invited us to the conference or we invited it. Such
+    /// connections are kept regardless of the distance to the peer.
+    introducer: bool,
+}
+
+/// Conference related data.
+#[derive(Clone, Debug)]
+struct Conference {
+    /// Type of the conference.
+    conference_type: ConferenceType,
+    /// Unique id of the conference.
+    unique_id: [u8; CONFERENCE_UID_BYTES],
+    /// Title of the conference.
+    title: String,
+    /// Our peer number. It's unknown until we receive it from the peer that
+    /// introduced us.
+    own_peer_id: Option<u16>,
+    /// Number of the last message we sent.
+    message_number: u32,
+    /// Number of the last lossy packet we sent.
+    lossy_number: u16,
+    /// Peers of the conference except us.
+    peers: HashMap<u16, Peer>,
+    /// Connections to peers by their long term `PublicKey`.
+    connections: HashMap<PublicKey, Connection>,
+    /// Time when we sent `Ping` message or peers query for the last time.
+    ping_time: Instant,
+}
+
+impl Conference {
+    /// Create new `Conference` without peers.
+    fn new(conference_type: ConferenceType, unique_id: [u8; CONFERENCE_UID_BYTES]) -> Conference {
+        Conference {
+            conference_type,
+            unique_id,
+            title: String::new(),
+            own_peer_id: None,
+            message_number: 0,
+            lossy_number: 0,
+            peers: HashMap::new(),
+            connections: HashMap::new(),
+            ping_time: clock_now(),
+        }
+    }
+
+    /// Check if we know our peer number in the conference.
+    fn is_connected(&self) -> bool {
+        self.own_peer_id.is_some()
+    }
+
+    /// Find number of the peer by its long term `PublicKey`.
+    fn peer_id_by_pk(&self, pk: PublicKey) -> Option<u16> {
+        self.peers.iter()
+            .find(|&(_, peer)| peer.real_pk == pk)
+            .map(|(&peer_id, _)| peer_id)
+    }
+
+    /// Let the connection to the peer that left the conference be dropped
+    /// even if the peer was our introducer.
+    fn forget_introducer(&mut self, pk: PublicKey) {
+        if let Some(connection) = self.connections.get_mut(&pk) {
+            connection.introducer = false;
+        }
+    }
+
+    /// Generate random peer number that is not used in the conference.
+    fn free_peer_id(&self) -> u16 {
+        loop {
+            let peer_id = random_u32() as u16;
+            if self.own_peer_id != Some(peer_id) && !self.peers.contains_key(&peer_id) {
+                return peer_id
+            }
+        }
+    }
+
+    /// Get long term `PublicKey`s of peers that are closest to us. We keep
+    /// connections to them to receive and relay messages.
+    fn close_peers(&self, own_pk: &PublicKey) -> Vec<PublicKey> {
+        let own_value = comp_value(own_pk);
+        let mut peers = self.peers.values()
+            .map(|peer| (comp_value(&peer.real_pk).wrapping_sub(own_value), peer.real_pk))
+            .collect::<Vec<_>>();
+        peers.sort_by_key(|&(distance, _)| distance);
+
+        if peers.len() > MAX_CLOSE_CONNECTIONS {
+            let half = MAX_CLOSE_CONNECTIONS / 2;
+            let tail = peers.split_off(peers.len() - half);
+            peers.truncate(half);
+            peers.extend(tail);
+        }
+
+        peers.into_iter().map(|(_, pk)| pk).collect()
+    }
+
+    /// Get connections that are established and know the number of the
+    /// conference on the peer's side.
+    fn online_connections(&self, friend_connections: &FriendConnections) -> Vec<(PublicKey, u16)> {
+        self.connections.iter()
+            .filter(|&(&pk, _)| friend_connections.is_friend_online(pk))
+            .filter_map(|(&pk, connection)| connection.remote_id.map(|remote_id| (pk, remote_id)))
+            .collect()
+    }
+}
+
+/// Struct that manages conferences we are members of and reports everything
+/// that happens in them as events.
+#[derive(Clone)]
+pub struct Conferences {
+    /// Our long term `PublicKey`.
+    real_pk: PublicKey,
+    /// Our DHT `PublicKey` that is sent to other peers.
+    dht_pk: PublicKey,
+    /// `NetCrypto` module that is used to send conference packets.
+    net_crypto: NetCrypto,
+    /// Friend connections that are used to connect to peers.
+    friend_connections: FriendConnections,
+    /// Messenger module that sends invites and keeps our name.
+    messenger: Messenger,
+    /// Conferences by their numbers.
+    conferences: Arc<RwLock<HashMap<u16, Conference>>>,
+    /// Sink to send conference events.
+    event_tx: Option<EventTx>,
+    /// Sink to send DHT `PublicKey`s of peers we connect to.
+    dht_pk_tx: Option<DhtPkTx>,
+}
+
+impl Conferences {
+    /// Create new `Conferences` object.
+    pub fn new(
+        real_pk: PublicKey,
+        dht_pk: PublicKey,
+        net_crypto: NetCrypto,
+        friend_connections: FriendConnections,
+        messenger: Messenger
+    ) -> Conferences {
+        Conferences {
+            real_pk,
+            dht_pk,
+            net_crypto,
+            friend_connections,
+            messenger,
+            conferences: Arc::new(RwLock::new(HashMap::new())),
+            event_tx: None,
+            dht_pk_tx: None,
+        }
+    }
+
+    /// Set sink to send conference events. Events are wrapped in
+    /// `Event::Conference` so they can share the stream with messenger
+    /// events.
+    pub fn set_event_sink(&mut self, event_tx: EventTx) {
+        self.event_tx = Some(event_tx);
+    }
+
+    /// Set sink to send DHT `PublicKey`s of peers that are not our friends
+    /// when we start connecting to them.
+    pub fn set_dht_pk_sink(&mut self, dht_pk_tx: DhtPkTx) {
+        self.dht_pk_tx = Some(dht_pk_tx);
+    }
+
+    /// Send event to the sink if it's set.
+    fn send_event(&self, event: ConferenceEvent) -> IoFuture<()> {
+        if let Some(ref event_tx) = self.event_tx {
+            send_to(event_tx, Event::Conference(event))
+        } else {
+            Box::new(future::ok(()))
+        }
+    }
+
+    /// Serialize conference packet and send it to a peer via `net_crypto`.
+    fn send_packet(&self, pk: PublicKey, packet: &Packet) -> IoFuture<()> {
+        match serialize_packet(packet) {
+            Ok(data) => self.net_crypto.send_lossless(pk, data),
+            Err(e) => Box::new(future::err(e)),
+        }
+    }
+
+    /// Send `OnlineConference` packet to a peer so it learns the number of
+    /// the conference on our side.
+    fn send_online(&self, pk: PublicKey, conference_id: u16, conference: &Conference) -> IoFuture<()> {
+        let packet = OnlineConference::new(conference_id, conference.conference_type, conference.unique_id);
+        self.send_packet(pk, &Packet::OnlineConference(packet))
+    }
+
+    /// Send message to all online connections of the conference except the
+    /// peer we received it from. Sending errors are ignored since peers
+    /// might go offline in the meantime.
+    fn relay_message(&self, conference: &Conference, packet: &MessageConference, except: Option<PublicKey>) -> IoFuture<()> {
+        let futures = conference.online_connections(&self.friend_connections).into_iter()
+            .filter(|&(pk, _)| Some(pk) != except)
+            .map(|(pk, remote_id)| {
+                let packet = MessageConference { conference_id: remote_id, .. packet.clone() };
+                self.send_packet(pk, &Packet::MessageConference(packet)).then(|_| Ok(()))
+            })
+            .collect::<Vec<_>>();
+        Box::new(future::join_all(futures).map(|_: Vec<()>| ()))
+    }
+
+    /// Send lossy packet to all online connections of the conference except
+    /// the peer we received it from.
+    fn relay_lossy(&self, conference: &Conference, packet: &LossyConference, except: Option<PublicKey>) -> IoFuture<()> {
+        let futures = conference.online_connections(&self.friend_connections).into_iter()
+            .filter(|&(pk, _)| Some(pk) != except)
+            .map(|(pk, remote_id)| {
+                let packet = LossyConference { conference_id: remote_id, .. packet.clone() };
+                let future: IoFuture<()> = match serialize_packet(&packet) {
+                    Ok(data) => self.net_crypto.send_lossy(pk, data),
+                    Err(e) => Box::new(future::err(e)),
+                };
+                future.then(|_| Ok(()))
+            })
+            .collect::<Vec<_>>();
+        Box::new(future::join_all(futures).map(|_: Vec<()>| ()))
+    }
+
+    /// Send new message from us to the conference.
+    fn broadcast_message(&self, conference: &mut Conference, payload: MessagePayload) -> IoFuture<()> {
+        let own_peer_id = if let Some(own_peer_id) = conference.own_peer_id {
+            own_peer_id
+        } else {
+            return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                "Not connected to the conference"
+            )))
+        };
+
+        conference.message_number = conference.message_number.wrapping_add(1);
+        let packet = MessageConference::new(0, own_peer_id, conference.message_number, payload);
+        self.relay_message(conference, &packet, None)
+    }
+
+    /// Start connecting to a peer. Peers that are not our friends are added
+    /// to friend connections.
+    fn connect(&self, conference_id: u16, conference: &Conference, pk: PublicKey, temp_pk: PublicKey) -> IoFuture<()> {
+        if self.friend_connections.is_friend_online(pk) {
+            // sending errors are not fatal since the connection might be
+            // lost in the meantime
+            return Box::new(self.send_online(pk, conference_id, conference).then(|_| Ok(())))
+        }
+
+        self.friend_connections.add_friend(pk);
+        if self.friend_connections.friend_dht_pk(pk).is_some() {
+            return Box::new(future::ok(()))
+        }
+
+        let dht_pk_future = if let Some(ref dht_pk_tx) = self.dht_pk_tx {
+            send_to(dht_pk_tx, (pk, temp_pk))
+        } else {
+            Box::new(future::ok(()))
+        };
+        Box::new(self.friend_connections.handle_dht_pk(pk, temp_pk).join(dht_pk_future).map(|_| ()))
+    }
+
+    /// Remove friend connection to a peer if it's not our friend and it's
+    /// not used by other conferences.
+    fn release(&self, conferences: &HashMap<u16, Conference>, pk: PublicKey) -> IoFuture<()> {
+        if self.messenger.has_friend(pk) || conferences.values().any(|conference| conference.connections.contains_key(&pk)) {
+            Box::new(future::ok(()))
+        } else {
+            Box::new(self.friend_connections.remove_friend(pk).then(|_| Ok(())))
+        }
+    }
+
+    /// Connect to peers that became close to us and drop connections to
+    /// peers that are not close anymore.
+    fn update_connections(&self, conferences: &mut HashMap<u16, Conference>, conference_id: u16) -> IoFuture<()> {
+        let mut futures = Vec::new();
+        let mut released = Vec::new();
+
+        if let Some(conference) = conferences.get_mut(&conference_id) {
+            let close_peers = conference.close_peers(&self.real_pk);
+
+            for &pk in &close_peers {
+                if conference.connections.contains_key(&pk) {
+                    continue
+                }
+
+                conference.connections.insert(pk, Connection { remote_id: None, introducer: false });
+                if let Some(peer_id) = conference.peer_id_by_pk(pk) {
+                    let temp_pk = conference.peers[&peer_id].temp_pk;
+                    futures.push(self.connect(conference_id, conference, pk, temp_pk));
+                }
+            }
+
+            let stale = conference.connections.iter()
+                .filter(|&(pk, connection)| !connection.introducer && !close_peers.contains(pk))
+                .map(|(&pk, _)| pk)
+                .collect::<Vec<_>>();
+            for pk in stale {
+                if let Some(Connection { remote_id: Some(remote_id), .. }) = conference.connections.remove(&pk) {
+                    let packet = DirectConference::new(remote_id, DirectPayload::PeerKill);
+                    futures.push(Box::new(self.send_packet(pk, &Packet::DirectConference(packet)).then(|_| Ok(()))));
+                }
+                released.push(pk);
+            }
+        }
+
+        for pk in released {
+            futures.push(self.release(conferences, pk));
+        }
+
+        Box::new(future::join_all(futures).map(|_| ()))
+    }
+
+    /// Find free number for a new conference.
+    fn free_conference_id(conferences: &HashMap<u16, Conference>) -> Result<u16, Error> {
+        (0 ..= u16::max_value())
+            .find(|conference_id| !conferences.contains_key(conference_id))
+            .ok_or_else(|| Error::new(ErrorKind::Other, "Too many conferences"))
+    }
+
+    /// Create new conference where we are the only member and get its
+    /// number.
+    pub fn create_conference(&self, conference_type: ConferenceType) -> Result<u16, Error> {
+        let mut conferences = self.conferences.write();
+        let conference_id = Conferences::free_conference_id(&conferences)?;
+
+        let mut unique_id = [0; CONFERENCE_UID_BYTES];
+        randombytes_into(&mut unique_id);
+        let mut conference = Conference::new(conference_type, unique_id);
+        conference.own_peer_id = Some(random_u32() as u16);
+
+        conferences.insert(conference_id, conference);
+        Ok(conference_id)
+    }
+
+    /// Get numbers of all conferences.
+    pub fn conferences(&self) -> Vec<u16> {
+        self.conferences.read().keys().cloned().collect()
+    }
+
+    /// Check if we are a member of the conference.
+    pub fn is_connected(&self, conference_id: u16) -> bool {
+        self.conferences.read().get(&conference_id).map_or(false, |conference| conference.is_connected())
+    }
+
+    /// Check if a friend connection to a peer is used by conferences.
+    pub fn has_connection(&self, pk: PublicKey) -> bool {
+        self.conferences.read().values().any(|conference| conference.connections.contains_key(&pk))
+    }
+
+    /// Get our peer number in the conference.
+    pub fn own_peer_id(&self, conference_id: u16) -> Option<u16> {
+        self.conferences.read().get(&conference_id).and_then(|conference| conference.own_peer_id)
+    }
+
+    /// Get title of the conference.
+    pub fn title(&self, conference_id: u16) -> Option<String> {
+        self.conferences.read().get(&conference_id).map(|conference| conference.title.clone())
+    }
+
+    /// Get all peers of the conference including us.
+    pub fn peers(&self, conference_id: u16) -> Option<Vec<PeerInfo>> {
+        self.conferences.read().get(&conference_id).map(|conference| self.peer_infos(conference))
+    }
+
+    /// Get info about all peers of the conference including us.
+    fn peer_infos(&self, conference: &Conference) -> Vec<PeerInfo> {
+        let own_info = conference.own_peer_id.map(|peer_id| PeerInfo {
+            peer_id,
+            real_pk: self.real_pk,
+            temp_pk: self.dht_pk,
+            name: self.messenger.name(),
+        });
+        own_info.into_iter()
+            .chain(conference.peers.iter().map(|(&peer_id, peer)| PeerInfo {
+                peer_id,
+                real_pk: peer.real_pk,
+                temp_pk: peer.temp_pk,
+                name: peer.name.clone(),
+            }))
+            .collect()
+    }
+
+    /// Invite an online friend to the conference.
+    pub fn invite(&self, conference_id: u16, friend_pk: PublicKey) -> IoFuture<()> {
+        if !self.messenger.is_friend_online(friend_pk) {
+            return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                format!("Friend {:?} is not online", friend_pk)
+            )))
+        }
+
+        let invite = match self.conferences.read().get(&conference_id) {
+            Some(conference) if conference.is_connected() =>
+                ConferenceInvite::new(conference_id, conference.conference_type, conference.unique_id),
+            _ => return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                format!("Not connected to the conference {}", conference_id)
+            ))),
+        };
+
+        self.messenger.send_packet(friend_pk, &MessengerPacket::ConferenceInvite(invite))
+    }
+
+    /// Join the conference we were invited to by a friend and get its
+    /// number. `Connected` event is sent when the friend confirms our
+    /// membership.
+    pub fn join(&self, friend_pk: PublicKey, invite: &ConferenceInvite) -> IoFuture<u16> {
+        if !self.messenger.is_friend_online(friend_pk) {
+            return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                format!("Friend {:?} is not online", friend_pk)
+            )))
+        }
+
+        let mut conferences = self.conferences.write();
+        if conferences.values().any(|conference| conference.unique_id == invite.unique_id) {
+            return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                "Already in the conference"
+            )))
+        }
+
+        let conference_id = match Conferences::free_conference_id(&conferences) {
+            Ok(conference_id) => conference_id,
+            Err(e) => return Box::new(future::err(e)),
+        };
+
+        let mut conference = Conference::new(invite.conference_type, invite.unique_id);
+        conference.connections.insert(friend_pk, Connection { remote_id: Some(invite.conference_id), introducer: true });
+        conferences.insert(conference_id, conference);
+
+        let response = ConferenceInviteResponse::new(
+            conference_id,
+            invite.conference_id,
+            invite.conference_type,
+            invite.unique_id
+        );
+        let response_future = self.messenger.send_packet(friend_pk, &MessengerPacket::ConferenceInviteResponse(response));
+        let query = DirectConference::new(invite.conference_id, DirectPayload::PeerQuery);
+        let query_future = self.send_packet(friend_pk, &Packet::DirectConference(query));
+
+        let conferences = self.conferences.clone();
+        let future = response_future
+            .and_then(|()| query_future)
+            .then(move |result| match result {
+                Ok(()) => Ok(conference_id),
+                Err(e) => {
+                    conferences.write().remove(&conference_id);
+                    Err(e)
+                },
+            });
+        Box::new(future)
+    }
+
+    /// Leave the conference letting other peers know about it.
+    pub fn leave(&self, conference_id: u16) -> IoFuture<()> {
+        let mut conferences = self.conferences.write();
+        let mut conference = if let Some(conference) = conferences.remove(&conference_id) {
+            conference
+        } else {
+            return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                format!("No conference with number {}", conference_id)
+            )))
+        };
+
+        let mut futures = Vec::new();
+        if let Some(own_peer_id) = conference.own_peer_id {
+            futures.push(self.broadcast_message(&mut conference, MessagePayload::KillPeer { peer_id: own_peer_id }));
+        }
+        for (pk, remote_id) in conference.online_connections(&self.friend_connections) {
+            let packet = DirectConference::new(remote_id, DirectPayload::PeerKill);
+            futures.push(Box::new(self.send_packet(pk, &Packet::DirectConference(packet)).then(|_| Ok(()))));
+        }
+
+        let messages_future = future::join_all(futures);
+        let release_futures = conference.connections.keys()
+            .map(|&pk| self.release(&conferences, pk))
+            .collect::<Vec<_>>();
+        Box::new(messages_future.and_then(|_| future::join_all(release_futures)).map(|_| ()))
+    }
+
+    /// Set title of the conference and send it to all peers.
+    pub fn set_title(&self, conference_id: u16, title: String) -> IoFuture<()> {
+        if title.len() > MAX_CONFERENCE_NAME_LENGTH {
+            return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                format!("Title is too long: {} bytes", title.len())
+            )))
+        }
+
+        let mut conferences = self.conferences.write();
+        let conference = match conferences.get_mut(&conference_id) {
+            Some(conference) if conference.is_connected() => conference,
+            _ => return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                format!("Not connected to the conference {}", conference_id)
+            ))),
+        };
+
+        conference.title = title.clone();
+        self.broadcast_message(conference, MessagePayload::Title(title))
+    }
+
+    /// Send chat or action message to the conference.
+    pub fn send_message(&self, conference_id: u16, kind: MessageKind, message: String) -> IoFuture<()> {
+        if message.is_empty() || message.len() > MAX_CONFERENCE_MESSAGE_DATA_SIZE {
+            return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                format!("Invalid message length: {} bytes", message.len())
+            )))
+        }
+
+        let mut conferences = self.conferences.write();
+        let conference = if let Some(conference) = conferences.get_mut(&conference_id) {
+            conference
+        } else {
+            return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                format!("No conference with number {}", conference_id)
+            )))
+        };
+
+        let payload = match kind {
+            MessageKind::Normal => MessagePayload::Message(message),
+            MessageKind::Action => MessagePayload::Action(message),
+        };
+        self.broadcast_message(conference, payload)
+    }
+
+    /// Send lossy packet to the conference. First byte of the data is the id
+    /// of the packet.
+    pub fn send_lossy(&self, conference_id: u16, data: Vec<u8>) -> IoFuture<()> {
+        if data.is_empty() {
+            return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                "Lossy packet is empty"
+            )))
+        }
+
+        let mut conferences = self.conferences.write();
+        let (conference, own_peer_id) = match conferences.get_mut(&conference_id) {
+            Some(conference) => match conference.own_peer_id {
+                Some(own_peer_id) => (conference, own_peer_id),
+                None => return Box::new(future::err(Error::new(
+                    ErrorKind::Other,
+                    format!("Not connected to the conference {}", conference_id)
+                ))),
+            },
+            None => return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                format!("No conference with number {}", conference_id)
+            ))),
+        };
+
+        conference.lossy_number = conference.lossy_number.wrapping_add(1);
+        let packet = LossyConference::new(0, own_peer_id, conference.lossy_number, data);
+        self.relay_lossy(conference, &packet, None)
+    }
+
+    /// Send our name to all conferences. It should be called when our name
+    /// is changed.
+    pub fn send_name(&self) -> IoFuture<()> {
+        let name = self.messenger.name();
+        let mut conferences = self.conferences.write();
+        let futures = conferences.values_mut()
+            .filter(|conference| conference.is_connected())
+            .map(|conference| self.broadcast_message(conference, MessagePayload::Name(name.clone())))
+            .collect::<Vec<_>>();
+        Box::new(future::join_all(futures).map(|_| ()))
+    }
+
+    /// Get state of conferences we are members of to save it.
+    pub fn save(&self) -> ConferencesState {
+        let now = unix_time(SystemTime::now());
+        let conferences = self.conferences.read().values()
+            .filter_map(|conference| conference.own_peer_id.map(|peer_number| ConferenceState {
+                conference_type: conference.conference_type,
+                unique_id: conference.unique_id,
+                message_number: conference.message_number,
+                lossy_message_number: conference.lossy_number,
+                peer_number,
+                title: conference.title.clone().into_bytes(),
+                peers: conference.peers.iter().map(|(&peer_number, peer)| ConferencePeerState {
+                    real_pk: peer.real_pk,
+                    temp_pk: peer.temp_pk,
+                    peer_number,
+                    last_active: now.saturating_sub(clock_elapsed(peer.last_active).as_secs()),
+                    name: peer.name.clone().into_bytes(),
+                }).collect(),
+            }))
+            .collect();
+        ConferencesState(conferences)
+    }
+
+    /// Restore conferences from saved state and start connecting to their
+    /// peers. Conferences we are already members of are skipped.
+    pub fn load(&self, state: &ConferencesState) -> IoFuture<()> {
+        let mut conferences = self.conferences.write();
+        let mut futures = Vec::new();
+
+        for saved in &state.0 {
+            if conferences.values().any(|conference| conference.unique_id == saved.unique_id) {
+                continue
+            }
+
+            let conference_id = match Conferences::free_conference_id(&conferences) {
+                Ok(conference_id) => conference_id,
+                Err(e) => return Box::new(future::err(e)),
+            };
+
+            let mut conference = Conference::new(saved.conference_type, saved.unique_id);
+            conference.title = String::from_utf8_lossy(&saved.title).into_owned();
+            conference.own_peer_id = Some(saved.peer_number);
+            conference.message_number = saved.message_number;
+            conference.lossy_number = saved.lossy_message_number;
+            for peer in &saved.peers {
+                if peer.real_pk == self.real_pk {
+                    continue
+                }
+                let mut restored = Peer::new(peer.real_pk, peer.temp_pk);
+                restored.name = String::from_utf8_lossy(&peer.name).into_owned();
+                conference.peers.insert(peer.peer_number, restored);
+            }
+
+            conferences.insert(conference_id, conference);
+            futures.push(self.update_connections(&mut conferences, conference_id));
+        }
+
+        Box::new(future::join_all(futures).map(|_| ()))
+    }
+
+    /// Handle status change of friend connection. When the connection
+    /// becomes established we send `OnlineConference` packets for all
+    /// conferences that use it.
+    pub fn handle_connection_status(&self, pk: PublicKey, status: bool) -> IoFuture<()> {
+        let mut conferences = self.conferences.write();
+        let mut futures = Vec::new();
+
+        for (&conference_id, conference) in conferences.iter_mut() {
+            if !conference.connections.contains_key(&pk) {
+                continue
+            }
+
+            if status {
+                futures.push(Box::new(self.send_online(pk, conference_id, conference).then(|_| Ok(()))));
+            } else if let Some(connection) = conference.connections.get_mut(&pk) {
+                connection.remote_id = None;
+            }
+        }
+
+        Box::new(future::join_all(futures).map(|_: Vec<()>| ()))
+    }
+
+    /// Handle lossless packet received from a friend or a peer via
+    /// `net_crypto`.
+    pub fn handle_lossless(&self, pk: PublicKey, data: &[u8]) -> IoFuture<()> {
+        if data.first() == Some(&0x60) {
+            return match MessengerPacket::from_bytes(data) {
+                IResult::Done(_, MessengerPacket::ConferenceInvite(packet)) => self.handle_invite(pk, packet),
+                IResult::Done(_, MessengerPacket::ConferenceInviteResponse(packet)) =>
+                    self.handle_invite_response(pk, packet),
+                _ => Box::new(future::err(Error::new(
+                    ErrorKind::Other,
+                    "Failed to parse conference invite packet"
+                ))),
+            }
+        }
+
+        match Packet::from_bytes(data) {
+            IResult::Done(_, Packet::OnlineConference(packet)) => self.handle_online(pk, packet),
+            IResult::Done(_, Packet::DirectConference(packet)) => self.handle_direct(pk, packet),
+            IResult::Done(_, Packet::MessageConference(packet)) => self.handle_message(pk, packet),
+            _ => Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                "Failed to parse conference packet"
+            ))),
+        }
+    }
+
+    /// Handle `ConferenceInvite` packet reporting the invite if it's
+    /// received from a friend.
+    fn handle_invite(&self, pk: PublicKey, invite: ConferenceInvite) -> IoFuture<()> {
+        if !self.messenger.has_friend(pk) {
+            return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                format!("Conference invite from unknown friend {:?}", pk)
+            )))
+        }
+
+        self.send_event(ConferenceEvent::Invite { pk, invite })
+    }
+
+    /// Handle `ConferenceInviteResponse` packet. The friend that accepted
+    /// our invite gets a peer number that is announced to the conference.
+    fn handle_invite_response(&self, pk: PublicKey, packet: ConferenceInviteResponse) -> IoFuture<()> {
+        let temp_pk = if let Some(temp_pk) = self.friend_connections.friend_dht_pk(pk) {
+            temp_pk
+        } else {
+            return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                format!("Invite response from unknown friend {:?}", pk)
+            )))
+        };
+
+        let mut conferences = self.conferences.write();
+        let conference_id = packet.conference_id_join;
+        let (peer_id, is_new, message_future) = {
+            let conference = match conferences.get_mut(&conference_id) {
+                Some(ref conference) if conference.unique_id != packet.unique_id ||
+                    conference.conference_type != packet.conference_type || !conference.is_connected() => None,
+                conference => conference,
+            };
+            let conference = if let Some(conference) = conference {
+                conference
+            } else {
+                return Box::new(future::err(Error::new(
+                    ErrorKind::Other,
+                    format!("Invite response for unknown conference {}", conference_id)
+                )))
+            };
+
+            let existing = conference.peer_id_by_pk(pk);
+            let peer_id = existing.unwrap_or_else(|| conference.free_peer_id());
+            if existing.is_none() {
+                conference.peers.insert(peer_id, Peer::new(pk, temp_pk));
+            }
+            conference.connections.insert(pk, Connection { remote_id: Some(packet.conference_id), introducer: true });
+
+            let message_future = self.broadcast_message(conference, MessagePayload::NewPeer { peer_id, real_pk: pk, temp_pk });
+            (peer_id, existing.is_none(), message_future)
+        };
+
+        let connections_future = self.update_connections(&mut conferences, conference_id);
+        let event_future = if is_new {
+            self.send_event(ConferenceEvent::PeerJoined { conference_id, peer_id, pk })
+        } else {
+            Box::new(future::ok(()))
+        };
+        Box::new(message_future.join3(connections_future, event_future).map(|_| ()))
+    }
+
+    /// Handle `OnlineConference` packet remembering the number of the
+    /// conference on the peer's side. We answer with our own
+    /// `OnlineConference` packet if the peer didn't know our number.
+    fn handle_online(&self, pk: PublicKey, packet: OnlineConference) -> IoFuture<()> {
+        let mut conferences = self.conferences.write();
+        let found = conferences.iter_mut().find(|&(_, ref conference)|
+            conference.unique_id == packet.unique_id && conference.conference_type == packet.conference_type
+        );
+        let (&conference_id, conference) = if let Some(found) = found {
+            found
+        } else {
+            return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                "OnlineConference packet for unknown conference"
+            )))
+        };
+
+        if !conference.connections.contains_key(&pk) && conference.peer_id_by_pk(pk).is_none() {
+            return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                format!("OnlineConference packet from unknown peer {:?}", pk)
+            )))
+        }
+
+        let connection = conference.connections
+            .entry(pk)
+            .or_insert(Connection { remote_id: None, introducer: false });
+        let was_known = connection.remote_id.is_some();
+        connection.remote_id = Some(packet.conference_id);
+
+        if was_known {
+            Box::new(future::ok(()))
+        } else {
+            self.send_online(pk, conference_id, conference)
+        }
+    }
+
+    /// Handle `DirectConference` packet received from one of connections of
+    /// the conference.
+    fn handle_direct(&self, pk: PublicKey, packet: DirectConference) -> IoFuture<()> {
+        let mut conferences = self.conferences.write();
+        let conference_id = packet.conference_id;
+        let remote_id = match conferences.get(&conference_id).and_then(|conference| conference.connections.get(&pk)) {
+            Some(connection) => connection.remote_id,
+            None => return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                format!("Direct conference packet from unknown connection {:?}", pk)
+            ))),
+        };
+
+        match packet.payload {
+            DirectPayload::PeerQuery => {
+                let remote_id = if let Some(remote_id) = remote_id {
+                    remote_id
+                } else {
+                    return Box::new(future::err(Error::new(
+                        ErrorKind::Other,
+                        "Peer query from connection without conference number"
+                    )))
+                };
+                self.send_peers(&conferences[&conference_id], pk, remote_id)
+            },
+            DirectPayload::PeerResponse(peers) => self.handle_peer_response(&mut conferences, conference_id, peers),
+            DirectPayload::Title(title) => {
+                let conference = conferences.get_mut(&conference_id).unwrap();
+                if conference.title == title {
+                    return Box::new(future::ok(()))
+                }
+                conference.title = title.clone();
+                self.send_event(ConferenceEvent::Title { conference_id, peer_id: None, title })
+            },
+            DirectPayload::PeerKill => {
+                conferences.get_mut(&conference_id).unwrap().connections.remove(&pk);
+                self.release(&conferences, pk)
+            },
+        }
+    }
+
+    /// Send the list of peers and the title of the conference to the peer
+    /// that queried them. The list is split into several packets if it
+    /// doesn't fit one.
+    fn send_peers(&self, conference: &Conference, pk: PublicKey, remote_id: u16) -> IoFuture<()> {
+        if !conference.is_connected() {
+            return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                "Peer query for conference we are not connected to"
+            )))
+        }
+
+        let mut chunks = vec![Vec::new()];
+        let mut chunk_size = DIRECT_CONFERENCE_HEADER_SIZE;
+        for info in self.peer_infos(conference) {
+            let info_size = 2 + PUBLICKEYBYTES * 2 + 1 + info.name.len();
+            if chunk_size + info_size > MAX_CRYPTO_DATA_SIZE {
+                chunks.push(Vec::new());
+                chunk_size = DIRECT_CONFERENCE_HEADER_SIZE;
+            }
+            chunk_size += info_size;
+            chunks.last_mut().unwrap().push(info);
+        }
+
+        let mut futures = chunks.into_iter()
+            .map(|peers| {
+                let packet = DirectConference::new(remote_id, DirectPayload::PeerResponse(peers));
+                self.send_packet(pk, &Packet::DirectConference(packet))
+            })
+            .collect::<Vec<_>>();
+        if !conference.title.is_empty() {
+            let packet = DirectConference::new(remote_id, DirectPayload::Title(conference.title.clone()));
+            futures.push(self.send_packet(pk, &Packet::DirectConference(packet)));
+        }
+        Box::new(future::join_all(futures).map(|_| ()))
+    }
+
+    /// Handle the list of peers of the conference. If it contains us we
+    /// become a member of the conference and send our name to it.
+    fn handle_peer_response(&self, conferences: &mut HashMap<u16, Conference>, conference_id: u16, peers: Vec<PeerInfo>) -> IoFuture<()> {
+        let mut futures = Vec::new();
+
+        {
+            let conference = conferences.get_mut(&conference_id).unwrap();
+            for info in peers {
+                if info.real_pk == self.real_pk {
+                    if conference.own_peer_id.is_none() {
+                        conference.own_peer_id = Some(info.peer_id);
+                        futures.push(self.send_event(ConferenceEvent::Connected { conference_id }));
+                        futures.push(self.broadcast_message(conference, MessagePayload::Name(self.messenger.name())));
+                    }
+                    continue
+                }
+
+                if conference.own_peer_id == Some(info.peer_id) {
+                    continue
+                }
+
+                let is_new = conference.peers.get(&info.peer_id).map_or(true, |peer| peer.real_pk != info.real_pk);
+                if is_new {
+                    conference.peers.insert(info.peer_id, Peer::new(info.real_pk, info.temp_pk));
+                    futures.push(self.send_event(ConferenceEvent::PeerJoined {
+                        conference_id,
+                        peer_id: info.peer_id,
+                        pk: info.real_pk,
+                    }));
+                }
+
+                let peer = conference.peers.get_mut(&info.peer_id).unwrap();
+                if peer.name != info.name {
+                    peer.name = info.name.clone();
+                    futures.push(self.send_event(ConferenceEvent::PeerName {
+                        conference_id,
+                        peer_id: info.peer_id,
+                        name: info.name,
+                    }));
+                }
+            }
+        }
+
+        futures.push(self.update_connections(conferences, conference_id));
+        Box::new(future::join_all(futures).map(|_| ()))
+    }
+
+    /// Handle `MessageConference` packet. New messages are relayed to other
+    /// connections of the conference. Messages from peers we don't know
+    /// make us query the list of peers from the connection that relayed
+    /// them.
+    fn handle_message(&self, pk: PublicKey, packet: MessageConference) -> IoFuture<()> {
+        let mut conferences = self.conferences.write();
+        let conference_id = packet.conference_id;
+        let peer_id = packet.peer_id;
+        let mut update_connections = false;
+
+        let future = {
+            let conference = match conferences.get_mut(&conference_id) {
+                Some(conference) => conference,
+                None => return Box::new(future::err(Error::new(
+                    ErrorKind::Other,
+                    format!("Message for unknown conference {}", conference_id)
+                ))),
+            };
+            let remote_id = match conference.connections.get(&pk) {
+                Some(connection) => connection.remote_id,
+                None => return Box::new(future::err(Error::new(
+                    ErrorKind::Other,
+                    format!("Message from unknown connection {:?}", pk)
+                ))),
+            };
+
+            if conference.own_peer_id == Some(peer_id) {
+                return Box::new(future::ok(()))
+            }
+
+            let real_pk = if let Some(peer) = conference.peers.get_mut(&peer_id) {
+                // numbers can jump forward when messages are lost, so only
+                // duplicates and messages older than the last one are dropped
+                let is_old = peer.last_message_number.map_or(false, |last|
+                    (packet.message_number.wrapping_sub(last) as i32) <= 0
+                );
+                if is_old {
+                    return Box::new(future::ok(()))
+                }
+                peer.last_message_number = Some(packet.message_number);
+                peer.last_active = clock_now();
+                peer.real_pk
+            } else {
+                // the connection wouldn't relay the message if it didn't
+                // know its author
+                return if let Some(remote_id) = remote_id {
+                    let query = DirectConference::new(remote_id, DirectPayload::PeerQuery);
+                    Box::new(self.send_packet(pk, &Packet::DirectConference(query)).then(|_| Ok(())))
+                } else {
+                    Box::new(future::ok(()))
+                }
+            };
+
+            let relay_future = self.relay_message(conference, &packet, Some(pk));
+            let handle_future: IoFuture<()> = match packet.payload {
+                MessagePayload::Ping => Box::new(future::ok(())),
+                MessagePayload::NewPeer { peer_id: new_peer_id, real_pk: new_pk, temp_pk } => {
+                    if new_pk == self.real_pk {
+                        if conference.own_peer_id.is_none() {
+                            conference.own_peer_id = Some(new_peer_id);
+                            update_connections = true;
+                            let name_future = self.broadcast_message(conference, MessagePayload::Name(self.messenger.name()));
+                            let event_future = self.send_event(ConferenceEvent::Connected { conference_id });
+                            Box::new(event_future.join(name_future).map(|_| ()))
+                        } else {
+                            Box::new(future::ok(()))
+                        }
+                    } else if conference.own_peer_id != Some(new_peer_id) && conference.peer_id_by_pk(new_pk).is_none() {
+                        conference.peers.insert(new_peer_id, Peer::new(new_pk, temp_pk));
+                        update_connections = true;
+                        self.send_event(ConferenceEvent::PeerJoined { conference_id, peer_id: new_peer_id, pk: new_pk })
+                    } else {
+                        Box::new(future::ok(()))
+                    }
+                },
+                MessagePayload::KillPeer { peer_id: killed_peer_id } => {
+                    // peers can remove only themselves
+                    if killed_peer_id == peer_id {
+                        conference.peers.remove(&peer_id);
+                        conference.forget_introducer(real_pk);
+                        update_connections = true;
+                        self.send_event(ConferenceEvent::PeerLeft { conference_id, peer_id, pk: real_pk })
+                    } else {
+                        Box::new(future::ok(()))
+                    }
+                },
+                MessagePayload::Name(name) => {
+                    let peer = conference.peers.get_mut(&peer_id).unwrap();
+                    if peer.name == name {
+                        Box::new(future::ok(()))
+                    } else {
+                        peer.name = name.clone();
+                        self.send_event(ConferenceEvent::PeerName { conference_id, peer_id, name })
+                    }
+                },
+                MessagePayload::Title(title) => {
+                    conference.title = title.clone();
+                    self.send_event(ConferenceEvent::Title { conference_id, peer_id: Some(peer_id), title })
+                },
+                MessagePayload::Message(message) =>
+                    self.send_event(ConferenceEvent::Message { conference_id, peer_id, kind: MessageKind::Normal, message }),
+                MessagePayload::Action(message) =>
+                    self.send_event(ConferenceEvent::Message { conference_id, peer_id, kind: MessageKind::Action, message }),
+            };
+
+            relay_future.join(handle_future).map(|_| ())
+        };
+
+        if update_connections {
+            let connections_future = self.update_connections(&mut conferences, conference_id);
+            Box::new(future.join(connections_future).map(|_| ()))
+        } else {
+            Box::new(future)
+        }
+    }
+
+    /// Handle lossy packet received via `net_crypto`. Lossy conference
+    /// packets are relayed like messages but only packets that are newer
+    /// than the last one from the same peer are accepted.
+    pub fn handle_lossy(&self, pk: PublicKey, data: &[u8]) -> IoFuture<()> {
+        let packet = match LossyConference::from_bytes(data) {
+            IResult::Done(_, packet) => packet,
+            _ => return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                "Failed to parse lossy conference packet"
+            ))),
+        };
+
+        let mut conferences = self.conferences.write();
+        let conference_id = packet.conference_id;
+        let conference = match conferences.get_mut(&conference_id) {
+            Some(ref conference) if !conference.connections.contains_key(&pk) => None,
+            conference => conference,
+        };
+        let conference = if let Some(conference) = conference {
+            conference
+        } else {
+            return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                format!("Lossy packet from unknown connection {:?}", pk)
+            )))
+        };
+
+        let peer_id = packet.peer_id;
+        if let Some(peer) = conference.peers.get_mut(&peer_id) {
+            let is_old = peer.last_lossy_number.map_or(false, |last|
+                (packet.message_number.wrapping_sub(last) as i16) <= 0
+            );
+            if is_old {
+                return Box::new(future::ok(()))
+            }
+            peer.last_lossy_number = Some(packet.message_number);
+            peer.last_active = clock_now();
+        } else {
+            return Box::new(future::ok(()))
+        }
+
+        let relay_future = self.relay_lossy(conference, &packet, Some(pk));
+        let event_future = self.send_event(ConferenceEvent::Lossy { conference_id, peer_id, data: packet.data });
+        Box::new(relay_future.join(event_future).map(|_| ()))
+    }
+
+    /// Send `Ping` messages, remove peers that timed out and resend peer
+    /// queries for conferences we are not connected to yet.
+    fn main_loop(&self) -> IoFuture<()> {
+        let mut conferences = self.conferences.write();
+        let mut futures: Vec<IoFuture<()>> = Vec::new();
+
+        let conference_ids = conferences.keys().cloned().collect::<Vec<_>>();
+        for conference_id in conference_ids {
+            let timed_out = {
+                let conference = conferences.get_mut(&conference_id).unwrap();
+                let ping_needed = clock_elapsed(conference.ping_time) >= Duration::from_secs(PING_INTERVAL);
+
+                if !conference.is_connected() {
+                    if ping_needed {
+                        conference.ping_time = clock_now();
+                        for (pk, remote_id) in conference.online_connections(&self.friend_connections) {
+                            let query = DirectConference::new(remote_id, DirectPayload::PeerQuery);
+                            futures.push(Box::new(self.send_packet(pk, &Packet::DirectConference(query)).then(|_| Ok(()))));
+                        }
+                    }
+                    continue
+                }
+
+                let timed_out = conference.peers.iter()
+                    .filter(|&(_, peer)| clock_elapsed(peer.last_active) >= Duration::from_secs(PEER_TIMEOUT))
+                    .map(|(&peer_id, _)| peer_id)
+                    .collect::<Vec<_>>();
+                for &peer_id in &timed_out {
+                    let peer = conference.peers.remove(&peer_id).unwrap();
+                    conference.forget_introducer(peer.real_pk);
+                    futures.push(self.send_event(ConferenceEvent::PeerLeft { conference_id, peer_id, pk: peer.real_pk }));
+                }
+
+                if ping_needed {
+                    conference.ping_time = clock_now();
+                    futures.push(self.broadcast_message(conference, MessagePayload::Ping));
+                }
+
+                timed_out
+            };
+
+            if !timed_out.is_empty() {
+                futures.push(self.update_connections(&mut conferences, conference_id));
+            }
+        }
+
+        Box::new(future::join_all(futures).map(|_| ()))
+    }
+
+    /// Run the main loop periodically.
+    pub fn run(self) -> IoFuture<()> {
+        let interval = Duration::from_secs(MAIN_LOOP_INTERVAL);
+        let wakeups = Interval::new(Instant::now(), interval);
+        let future = wakeups
+            .map_err(|e| Error::new(ErrorKind::Other, format!("Conferences timer error: {:?}", e)))
+            .for_each(move |_instant| {
+                trace!("Conferences wake up");
+                self.main_loop()
+            });
+
+        Box::new(future)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio_executor;
+    use tokio_timer::clock::*;
+
+    use toxcore::dht::precomputed_cache::PrecomputedCache;
+    use toxcore::dht::server::Server as DhtServer;
+    use toxcore::friend_requests::FriendRequests;
+    use toxcore::net_crypto::NetCryptoNewArgs;
+    use toxcore::onion::client::OnionClient;
+    use toxcore::tcp::connections::Connections;
+    use toxcore::toxid::NoSpam;
+
+    fn create_conferences() -> (Conferences, mpsc::UnboundedReceiver<Event>) {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx: udp_tx.clone(),
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            precomputed_keys,
+        });
+        let onion_client = OnionClient::new(DhtServer::new(udp_tx, dht_pk, dht_sk), real_pk, real_sk.clone());
+        let (incoming_tx, _incoming_rx) = mpsc::unbounded();
+        let tcp_connections = Connections::new(real_pk, real_sk, incoming_tx);
+        let friend_connections = FriendConnections::new(net_crypto.clone(), tcp_connections);
+        let friend_requests = FriendRequests::new(real_pk, NoSpam::new(), friend_connections.clone(), onion_client);
+        let messenger = Messenger::new(net_crypto.clone(), friend_connections.clone(), friend_requests);
+        let (event_tx, event_rx) = mpsc::unbounded();
+        let mut conferences = Conferences::new(real_pk, dht_pk, net_crypto, friend_connections, messenger);
+        conferences.set_event_sink(event_tx);
+        (conferences, event_rx)
+    }
+
+    /// Add an online peer to the connected conference and get its number.
+    fn add_online_peer(conferences: &Conferences, conference_id: u16, pk: PublicKey, remote_id: u16) -> u16 {
+        conferences.friend_connections.add_friend(pk);
+        conferences.friend_connections.handle_dht_pk(pk, gen_keypair().0).wait().unwrap();
+        conferences.friend_connections.handle_connection_status(pk, true).wait().unwrap();
+
+        let mut all_conferences = conferences.conferences.write();
+        let conference = all_conferences.get_mut(&conference_id).unwrap();
+        let peer_id = conference.free_peer_id();
+        conference.peers.insert(peer_id, Peer::new(pk, gen_keypair().0));
+        conference.connections.insert(pk, Connection { remote_id: Some(remote_id), introducer: true });
+        peer_id
+    }
+
+    fn encode<T: ToBytes>(packet: &T) -> Vec<u8> {
+        serialize_packet(packet).unwrap()
+    }
+
+    fn collect_events(conferences: Conferences, event_rx: mpsc::UnboundedReceiver<Event>) -> Vec<ConferenceEvent> {
+        drop(conferences);
+        event_rx.collect().wait().unwrap().into_iter()
+            .map(|event| match event {
+                Event::Conference(event) => event,
+                event => panic!("Unexpected event {:?}", event),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn create_leave_conference() {
+        let (conferences, _event_rx) = create_conferences();
+
+        let conference_id = conferences.create_conference(ConferenceType::Text).unwrap();
+        assert!(conferences.is_connected(conference_id));
+        assert_eq!(conferences.conferences(), vec![conference_id]);
+        assert_eq!(conferences.title(conference_id), Some(String::new()));
+
+        let peers = conferences.peers(conference_id).unwrap();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].real_pk, conferences.real_pk);
+        assert_eq!(Some(peers[0].peer_id), conferences.own_peer_id(conference_id));
+
+        conferences.set_title(conference_id, "Title".to_owned()).wait().unwrap();
+        assert_eq!(conferences.title(conference_id), Some("Title".to_owned()));
+
+        conferences.leave(conference_id).wait().unwrap();
+        assert!(conferences.conferences().is_empty());
+        assert!(conferences.leave(conference_id).wait().is_err());
+    }
+
+    #[test]
+    fn invite_join_offline_friend() {
+        let (conferences, _event_rx) = create_conferences();
+        let friend_pk = gen_keypair().0;
+        conferences.messenger.add_friend_norequest(friend_pk);
+
+        let conference_id = conferences.create_conference(ConferenceType::Text).unwrap();
+        assert!(conferences.invite(conference_id, friend_pk).wait().is_err());
+
+        let invite = ConferenceInvite::new(1, ConferenceType::Text, [42; CONFERENCE_UID_BYTES]);
+        assert!(conferences.join(friend_pk, &invite).wait().is_err());
+        assert_eq!(conferences.conferences(), vec![conference_id]);
+    }
+
+    #[test]
+    fn send_invalid() {
+        let (conferences, _event_rx) = create_conferences();
+        let conference_id = conferences.create_conference(ConferenceType::Text).unwrap();
+
+        assert!(conferences.send_message(conference_id, MessageKind::Normal, String::new()).wait().is_err());
+        assert!(conferences.send_message(conference_id + 1, MessageKind::Normal, "Hello".to_owned()).wait().is_err());
+        assert!(conferences.send_lossy(conference_id, Vec::new()).wait().is_err());
+        let title = String::from_utf8(vec![32; MAX_CONFERENCE_NAME_LENGTH + 1]).unwrap();
+        assert!(conferences.set_title(conference_id, title).wait().is_err());
+    }
+
+    #[test]
+    fn handle_invite() {
+        let (conferences, event_rx) = create_conferences();
+        let friend_pk = gen_keypair().0;
+        conferences.messenger.add_friend_norequest(friend_pk);
+
+        let invite = ConferenceInvite::new(1, ConferenceType::Text, [42; CONFERENCE_UID_BYTES]);
+        let data = encode(&MessengerPacket::ConferenceInvite(invite.clone()));
+        conferences.handle_lossless(friend_pk, &data).wait().unwrap();
+        // invites from strangers are not accepted
+        assert!(conferences.handle_lossless(gen_keypair().0, &data).wait().is_err());
+
+        let events = collect_events(conferences, event_rx);
+        assert_eq!(events, vec![ConferenceEvent::Invite { pk: friend_pk, invite }]);
+    }
+
+    #[test]
+    fn handle_invite_response() {
+        let (conferences, event_rx) = create_conferences();
+        let friend_pk = gen_keypair().0;
+        conferences.messenger.add_friend_norequest(friend_pk);
+        conferences.friend_connections.handle_dht_pk(friend_pk, gen_keypair().0).wait().unwrap();
+
+        let conference_id = conferences.create_conference(ConferenceType::Text).unwrap();
+        let unique_id = conferences.conferences.read()[&conference_id].unique_id;
+
+        // wrong unique id
+        let response = ConferenceInviteResponse::new(7, conference_id, ConferenceType::Text, [42; CONFERENCE_UID_BYTES]);
+        let data = encode(&MessengerPacket::ConferenceInviteResponse(response));
+        assert!(conferences.handle_lossless(friend_pk, &data).wait().is_err());
+
+        let response = ConferenceInviteResponse::new(7, conference_id, ConferenceType::Text, unique_id);
+        let data = encode(&MessengerPacket::ConferenceInviteResponse(response));
+        conferences.handle_lossless(friend_pk, &data).wait().unwrap();
+
+        let peer_id = {
+            let all_conferences = conferences.conferences.read();
+            let conference = &all_conferences[&conference_id];
+            let peer_id = conference.peer_id_by_pk(friend_pk).unwrap();
+            let connection = &conference.connections[&friend_pk];
+            assert_eq!(connection.remote_id, Some(7));
+            assert!(connection.introducer);
+            // `NewPeer` message was sent
+            assert_eq!(conference.message_number, 1);
+            peer_id
+        };
+
+        let events = collect_events(conferences, event_rx);
+        assert_eq!(events, vec![ConferenceEvent::PeerJoined { conference_id, peer_id, pk: friend_pk }]);
+    }
+
+    #[test]
+    fn handle_online() {
+        let (conferences, _event_rx) = create_conferences();
+        let conference_id = conferences.create_conference(ConferenceType::Text).unwrap();
+        let pk = gen_keypair().0;
+        let peer_id = add_online_peer(&conferences, conference_id, pk, 7);
+        conferences.conferences.write().get_mut(&conference_id).unwrap()
+            .connections.get_mut(&pk).unwrap().remote_id = None;
+        let unique_id = conferences.conferences.read()[&conference_id].unique_id;
+
+        let data = encode(&Packet::OnlineConference(OnlineConference::new(8, ConferenceType::Text, unique_id)));
+        // sending our `OnlineConference` packet back fails since there is
+        // no crypto connection
+        assert!(conferences.handle_lossless(pk, &data).wait().is_err());
+        assert_eq!(conferences.conferences.read()[&conference_id].connections[&pk].remote_id, Some(8));
+        assert!(conferences.conferences.read()[&conference_id].peers.contains_key(&peer_id));
+
+        // unknown peers are not accepted
+        assert!(conferences.handle_lossless(gen_keypair().0, &data).wait().is_err());
+        // unknown conferences are not accepted
+        let data = encode(&Packet::OnlineConference(OnlineConference::new(8, ConferenceType::Text, [42; CONFERENCE_UID_BYTES])));
+        assert!(conferences.handle_lossless(pk, &data).wait().is_err());
+    }
+
+    #[test]
+    fn handle_peer_query() {
+        let (conferences, _event_rx) = create_conferences();
+        let conference_id = conferences.create_conference(ConferenceType::Text).unwrap();
+        let pk = gen_keypair().0;
+        add_online_peer(&conferences, conference_id, pk, 7);
+
+        let data = encode(&Packet::DirectConference(DirectConference::new(conference_id, DirectPayload::PeerQuery)));
+        // the response can't be sent since there is no crypto connection
+        assert!(conferences.handle_lossless(pk, &data).wait().is_err());
+        // queries from unknown connections are not accepted
+        assert!(conferences.handle_lossless(gen_keypair().0, &data).wait().is_err());
+    }
+
+    #[test]
+    fn handle_peer_response() {
+        let (conferences, event_rx) = create_conferences();
+        let friend_pk = gen_keypair().0;
+        let conference_id = 0;
+        let mut conference = Conference::new(ConferenceType::Text, [42; CONFERENCE_UID_BYTES]);
+        conference.connections.insert(friend_pk, Connection { remote_id: Some(7), introducer: true });
+        conferences.conferences.write().insert(conference_id, conference);
+
+        let peers = vec![
+            PeerInfo { peer_id: 1, real_pk: friend_pk, temp_pk: gen_keypair().0, name: "Alice".to_owned() },
+            PeerInfo { peer_id: 2, real_pk: conferences.real_pk, temp_pk: conferences.dht_pk, name: String::new() },
+        ];
+        let data = encode(&Packet::DirectConference(DirectConference::new(conference_id, DirectPayload::PeerResponse(peers))));
+        conferences.handle_lossless(friend_pk, &data).wait().unwrap();
+        let data = encode(&Packet::DirectConference(DirectConference::new(conference_id, DirectPayload::Title("Title".to_owned()))));
+        conferences.handle_lossless(friend_pk, &data).wait().unwrap();
+
+        assert!(conferences.is_connected(conference_id));
+        assert_eq!(conferences.own_peer_id(conference_id), Some(2));
+        assert_eq!(conferences.title(conference_id), Some("Title".to_owned()));
+        assert!(conferences.conferences.read()[&conference_id].connections[&friend_pk].introducer);
+
+        let events = collect_events(conferences, event_rx);
+        assert_eq!(events, vec![
+            ConferenceEvent::PeerJoined { conference_id, peer_id: 1, pk: friend_pk },
+            ConferenceEvent::PeerName { conference_id, peer_id: 1, name: "Alice".to_owned() },
+            ConferenceEvent::Connected { conference_id },
+            ConferenceEvent::Title { conference_id, peer_id: None, title: "Title".to_owned() },
+        ]);
+    }
+
+    #[test]
+    fn handle_message() {
+        let (conferences, event_rx) = create_conferences();
+        let conference_id = conferences.create_conference(ConferenceType::Text).unwrap();
+        let pk = gen_keypair().0;
+        let peer_id = add_online_peer(&conferences, conference_id, pk, 7);
+
+        let send = |message_number, payload| {
+            let packet = MessageConference::new(conference_id, peer_id, message_number, payload);
+            conferences.handle_lossless(pk, &encode(&Packet::MessageConference(packet))).wait().unwrap();
+        };
+
+        send(1, MessagePayload::Name("Alice".to_owned()));
+        send(2, MessagePayload::Message("Hello".to_owned()));
+        // duplicate messages are dropped
+        send(2, MessagePayload::Message("Hello".to_owned()));
+        // messages with numbers older than the last one are dropped
+        send(1, MessagePayload::Message("Hello".to_owned()));
+        // numbers can jump forward when messages are lost
+        send(100, MessagePayload::Action("waves".to_owned()));
+        send(50, MessagePayload::Message("Hello".to_owned()));
+        send(101, MessagePayload::Title("Title".to_owned()));
+        let new_pk = gen_keypair().0;
+        send(102, MessagePayload::NewPeer { peer_id: 42, real_pk: new_pk, temp_pk: gen_keypair().0 });
+        // peers can't remove other peers
+        send(103, MessagePayload::KillPeer { peer_id: 42 });
+
+        // messages from unknown peers are dropped
+        let packet = MessageConference::new(conference_id, 43, 1, MessagePayload::Message("Hello".to_owned()));
+        conferences.handle_lossless(pk, &encode(&Packet::MessageConference(packet.clone()))).wait().unwrap();
+        // messages from unknown connections are not accepted
+        assert!(conferences.handle_lossless(gen_keypair().0, &encode(&Packet::MessageConference(packet))).wait().is_err());
+
+        send(104, MessagePayload::KillPeer { peer_id });
+
+        assert_eq!(conferences.title(conference_id), Some("Title".to_owned()));
+        {
+            let all_conferences = conferences.conferences.read();
+            let conference = &all_conferences[&conference_id];
+            assert!(conference.peers.contains_key(&42));
+            assert!(!conference.peers.contains_key(&peer_id));
+            // connection to the peer that left is dropped
+            assert!(!conference.connections.contains_key(&pk));
+        }
+
+        let events = collect_events(conferences, event_rx);
+        assert_eq!(events, vec![
+            ConferenceEvent::PeerName { conference_id, peer_id, name: "Alice".to_owned() },
+            ConferenceEvent::Message { conference_id, peer_id, kind: MessageKind::Normal, message: "Hello".to_owned() },
+            ConferenceEvent::Message { conference_id, peer_id, kind: MessageKind::Action, message: "waves".to_owned() },
+            ConferenceEvent::Title { conference_id, peer_id: Some(peer_id), title: "Title".to_owned() },
+            ConferenceEvent::PeerJoined { conference_id, peer_id: 42, pk: new_pk },
+            ConferenceEvent::PeerLeft { conference_id, peer_id, pk },
+        ]);
+    }
+
+    #[test]
+    fn handle_message_new_peer_self() {
+        let (conferences, event_rx) = create_conferences();
+        let friend_pk = gen_keypair().0;
+        let conference_id = 0;
+        let mut conference = Conference::new(ConferenceType::Text, [42; CONFERENCE_UID_BYTES]);
+        conference.peers.insert(1, Peer::new(friend_pk, gen_keypair().0));
+        conference.connections.insert(friend_pk, Connection { remote_id: Some(7), introducer: true });
+        conferences.conferences.write().insert(conference_id, conference);
+
+        let payload = MessagePayload::NewPeer { peer_id: 2, real_pk: conferences.real_pk, temp_pk: conferences.dht_pk };
+        let packet = MessageConference::new(conference_id, 1, 1, payload);
+        conferences.handle_lossless(friend_pk, &encode(&Packet::MessageConference(packet))).wait().unwrap();
+
+        assert_eq!(conferences.own_peer_id(conference_id), Some(2));
+        // the introducer connection is kept
+        assert!(conferences.has_connection(friend_pk));
+
+        let events = collect_events(conferences, event_rx);
+        assert_eq!(events, vec![ConferenceEvent::Connected { conference_id }]);
+    }
+
+    #[test]
+    fn handle_lossy() {
+        let (conferences, event_rx) = create_conferences();
+        let conference_id = conferences.create_conference(ConferenceType::Audio).unwrap();
+        let pk = gen_keypair().0;
+        let peer_id = add_online_peer(&conferences, conference_id, pk, 7);
+
+        let send = |message_number, data: Vec<u8>| {
+            let packet = LossyConference::new(conference_id, peer_id, message_number, data);
+            conferences.handle_lossy(pk, &encode(&packet)).wait().unwrap();
+        };
+
+        send(1, vec![192, 1]);
+        // old packets are dropped
+        send(1, vec![192, 2]);
+        send(3, vec![192, 3]);
+        send(2, vec![192, 4]);
+
+        assert!(conferences.handle_lossy(pk, &[0xc7, 1, 2]).wait().is_err());
+
+        let events = collect_events(conferences, event_rx);
+        assert_eq!(events, vec![
+            ConferenceEvent::Lossy { conference_id, peer_id, data: vec![192, 1] },
+            ConferenceEvent::Lossy { conference_id, peer_id, data: vec![192, 3] },
+        ]);
+    }
+
+    #[test]
+    fn close_peers() {
+        let mut conference = Conference::new(ConferenceType::Text, [42; CONFERENCE_UID_BYTES]);
+        let own_pk = PublicKey([128; PUBLICKEYBYTES]);
+        for (peer_id, &byte) in [1, 100, 126, 127, 129, 130, 200, 255].iter().enumerate() {
+            conference.peers.insert(peer_id as u16, Peer::new(PublicKey([byte; PUBLICKEYBYTES]), gen_keypair().0));
+        }
+
+        let mut close_peers = conference.close_peers(&own_pk);
+        close_peers.sort();
+        assert_eq!(close_peers, vec![
+            PublicKey([126; PUBLICKEYBYTES]),
+            PublicKey([127; PUBLICKEYBYTES]),
+            PublicKey([129; PUBLICKEYBYTES]),
+            PublicKey([130; PUBLICKEYBYTES]),
+        ]);
+    }
+
+    #[test]
+    fn main_loop_peer_timeout() {
+        let (conferences, event_rx) = create_conferences();
+        let conference_id = conferences.create_conference(ConferenceType::Text).unwrap();
+        let pk = gen_keypair().0;
+        let peer_id = add_online_peer(&conferences, conference_id, pk, 7);
+
+        let now = Instant::now() + Duration::from_secs(PEER_TIMEOUT + 1);
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(now));
+
+        with_default(&clock, &mut enter, |_| {
+            conferences.main_loop().wait().unwrap();
+        });
+
+        {
+            let all_conferences = conferences.conferences.read();
+            let conference = &all_conferences[&conference_id];
+            assert!(conference.peers.is_empty());
+            assert!(conference.connections.is_empty());
+            // `Ping` message was sent
+            assert_eq!(conference.message_number, 1);
+        }
+
+        let events = collect_events(conferences, event_rx);
+        assert_eq!(events, vec![ConferenceEvent::PeerLeft { conference_id, peer_id, pk }]);
+    }
+
+    #[test]
+    fn save_load() {
+        let (conferences, _event_rx) = create_conferences();
+        let conference_id = conferences.create_conference(ConferenceType::Text).unwrap();
+        conferences.set_title(conference_id, "Title".to_owned()).wait().unwrap();
+        let pk = gen_keypair().0;
+        let peer_id = add_online_peer(&conferences, conference_id, pk, 7);
+        // conferences we are not connected to are not saved
+        conferences.conferences.write().insert(42, Conference::new(ConferenceType::Text, [42; CONFERENCE_UID_BYTES]));
+
+        let state = conferences.save();
+        assert_eq!(state.0.len(), 1);
+        let saved = &state.0[0];
+        assert_eq!(saved.title, b"Title".to_vec());
+        assert_eq!(saved.message_number, 1);
+        assert_eq!(Some(saved.peer_number), conferences.own_peer_id(conference_id));
+        assert_eq!(saved.peers.len(), 1);
+        assert_eq!(saved.peers[0].real_pk, pk);
+        assert_eq!(saved.peers[0].peer_number, peer_id);
+
+        let (restored, _event_rx) = create_conferences();
+        restored.load(&state).wait().unwrap();
+        // already loaded conferences are skipped
+        restored.load(&state).wait().unwrap();
+
+        let conference_ids = restored.conferences();
+        assert_eq!(conference_ids.len(), 1);
+        let conference_id = conference_ids[0];
+        assert!(restored.is_connected(conference_id));
+        assert_eq!(restored.title(conference_id), Some("Title".to_owned()));
+        assert_eq!(restored.own_peer_id(conference_id), Some(saved.peer_number));
+        assert_eq!(restored.conferences.read()[&conference_id].message_number, 1);
+        // connection to the close peer is created
+        assert!(restored.has_connection(pk));
+        assert_eq!(restored.friend_connections.friend_dht_pk(pk), Some(saved.peers[0].temp_pk));
+    }
+}
@@ -0,0 +1,219 @@
+/*! DirectConference struct.
+*/
+
+use std::str;
+use nom::{be_u16, le_u8, rest};
+
+use toxcore::binary_io::*;
+use toxcore::crypto_core::*;
+use super::MAX_CONFERENCE_NAME_LENGTH;
+
+/** Info about a conference peer that is sent in response to a peer query.
+
+Serialized form:
+
+Length    | Content
+--------- | ------
+`2`       | Peer number
+`32`      | Long term `PublicKey` of the peer
+`32`      | DHT `PublicKey` of the peer
+`1`       | Length of the name
+`0..128`  | UTF8 byte string of the name
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PeerInfo {
+    /// Number of the peer in the conference
+    pub peer_id: u16,
+    /// Long term `PublicKey` of the peer
+    pub real_pk: PublicKey,
+    /// DHT `PublicKey` of the peer that is used to connect to it
+    pub temp_pk: PublicKey,
+    /// Name of the peer
+    pub name: String,
+}
+
+impl FromBytes for PeerInfo {
+    named!(from_bytes<PeerInfo>, do_parse!(
+        peer_id: be_u16 >>
+        real_pk: call!(PublicKey::from_bytes) >>
+        temp_pk: call!(PublicKey::from_bytes) >>
+        name: map_res!(verify!(length_data!(le_u8), |name: &[u8]| name.len() <= MAX_CONFERENCE_NAME_LENGTH),
+            str::from_utf8) >>
+        (PeerInfo {
+            peer_id,
+            real_pk,
+            temp_pk,
+            name: name.to_string(),
+        })
+    ));
+}
+
+impl ToBytes for PeerInfo {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u16!(self.peer_id) >>
+            gen_slice!(self.real_pk.as_ref()) >>
+            gen_slice!(self.temp_pk.as_ref()) >>
+            gen_cond!(self.name.len() > MAX_CONFERENCE_NAME_LENGTH, |buf| gen_error(buf, 0)) >>
+            gen_be_u8!(self.name.len() as u8) >>
+            gen_slice!(self.name.as_bytes())
+        )
+    }
+}
+
+/// Payload of a direct conference packet.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DirectPayload {
+    /// The sender closes its connection for the conference
+    PeerKill,
+    /// Request for the list of peers of the conference
+    PeerQuery,
+    /// List of peers of the conference
+    PeerResponse(Vec<PeerInfo>),
+    /// Title of the conference
+    Title(String),
+}
+
+impl FromBytes for DirectPayload {
+    named!(from_bytes<DirectPayload>, switch!(le_u8,
+        1 => value!(DirectPayload::PeerKill) |
+        8 => value!(DirectPayload::PeerQuery) |
+        9 => map!(many0!(PeerInfo::from_bytes), DirectPayload::PeerResponse) |
+        10 => map!(
+            map_res!(verify!(rest, |title: &[u8]| title.len() <= MAX_CONFERENCE_NAME_LENGTH), str::from_utf8),
+            |title| DirectPayload::Title(title.to_string())
+        )
+    ));
+}
+
+impl ToBytes for DirectPayload {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        match *self {
+            DirectPayload::PeerKill => gen_be_u8!(buf, 1),
+            DirectPayload::PeerQuery => gen_be_u8!(buf, 8),
+            DirectPayload::PeerResponse(ref peers) => do_gen!(buf,
+                gen_be_u8!(9) >>
+                gen_many_ref!(peers, |buf, peer| PeerInfo::to_bytes(peer, buf))
+            ),
+            DirectPayload::Title(ref title) => do_gen!(buf,
+                gen_be_u8!(10) >>
+                gen_cond!(title.len() > MAX_CONFERENCE_NAME_LENGTH, |buf| gen_error(buf, 0)) >>
+                gen_slice!(title.as_bytes())
+            ),
+        }
+    }
+}
+
+/** DirectConference is a struct that holds a conference packet that is sent
+to a single peer and is not relayed further.
+
+This packet is used to query the list of peers of the conference, to send
+this list and the title of the conference in response and to let a peer know
+that we close the connection to it.
+
+Serialized form:
+
+Length    | Content
+--------- | ------
+`1`       | `0x62`
+`2`       | Conference number of the receiver
+`1`       | Payload type (1 = peer kill, 8 = peer query, 9 = peer response, 10 = title)
+variable  | Payload
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DirectConference {
+    /// Number of the conference on the receiver's side
+    pub conference_id: u16,
+    /// Payload of the packet
+    pub payload: DirectPayload,
+}
+
+impl FromBytes for DirectConference {
+    named!(from_bytes<DirectConference>, do_parse!(
+        tag!("\x62") >>
+        conference_id: be_u16 >>
+        payload: call!(DirectPayload::from_bytes) >>
+        eof!() >>
+        (DirectConference { conference_id, payload })
+    ));
+}
+
+impl ToBytes for DirectConference {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(0x62) >>
+            gen_be_u16!(self.conference_id) >>
+            gen_call!(|buf, payload| DirectPayload::to_bytes(payload, buf), &self.payload)
+        )
+    }
+}
+
+impl DirectConference {
+    /// Create new DirectConference object.
+    pub fn new(conference_id: u16, payload: DirectPayload) -> Self {
+        DirectConference { conference_id, payload }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    encode_decode_test!(
+        peer_info_encode_decode,
+        PeerInfo {
+            peer_id: 1,
+            real_pk: gen_keypair().0,
+            temp_pk: gen_keypair().0,
+            name: "Alice".to_string(),
+        }
+    );
+
+    encode_decode_test!(
+        direct_conference_peer_kill_encode_decode,
+        DirectConference::new(1, DirectPayload::PeerKill)
+    );
+
+    encode_decode_test!(
+        direct_conference_peer_query_encode_decode,
+        DirectConference::new(1, DirectPayload::PeerQuery)
+    );
+
+    encode_decode_test!(
+        direct_conference_peer_response_encode_decode,
+        DirectConference::new(1, DirectPayload::PeerResponse(vec![
+            PeerInfo {
+                peer_id: 1,
+                real_pk: gen_keypair().0,
+                temp_pk: gen_keypair().0,
+                name: "Alice".to_string(),
+            },
+            PeerInfo {
+                peer_id: 2,
+                real_pk: gen_keypair().0,
+                temp_pk: gen_keypair().0,
+                name: String::new(),
+            },
+        ]))
+    );
+
+    encode_decode_test!(
+        direct_conference_title_encode_decode,
+        DirectConference::new(1, DirectPayload::Title("Title".to_string()))
+    );
+
+    #[test]
+    fn direct_conference_title_to_bytes_overflow() {
+        let title = String::from_utf8(vec![32; MAX_CONFERENCE_NAME_LENGTH + 1]).unwrap();
+        let packet = DirectConference::new(1, DirectPayload::Title(title));
+        let mut buf = [0; MAX_CONFERENCE_NAME_LENGTH + 5];
+        assert!(packet.to_bytes((&mut buf, 0)).is_err());
+    }
+
+    #[test]
+    fn direct_conference_from_bytes_unknown_payload() {
+        assert!(DirectConference::from_bytes(&[0x62, 0x00, 0x01, 0x02]).is_err());
+    }
+}
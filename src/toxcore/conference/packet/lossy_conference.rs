@@ -0,0 +1,82 @@
+/*! LossyConference struct.
+*/
+
+use nom::{be_u16, rest};
+
+use toxcore::binary_io::*;
+
+/** LossyConference is a struct that holds lossy data that is broadcasted to
+all peers of a conference.
+
+Lossy packets are relayed by peers like conference messages but they are not
+resent when lost. They are used for data like audio that becomes useless
+when it's late. The first byte of the data is the id of the lossy packet.
+
+Serialized form:
+
+Length    | Content
+--------- | ------
+`1`       | `0xc7`
+`2`       | Conference number of the receiver
+`2`       | Peer number of the author of the packet
+`2`       | Number of the lossy packet
+`1..`     | Data
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LossyConference {
+    /// Number of the conference on the receiver's side
+    pub conference_id: u16,
+    /// Peer number of the author of the packet
+    pub peer_id: u16,
+    /// Number of the lossy packet
+    pub message_number: u16,
+    /// Data of the packet starting with its id
+    pub data: Vec<u8>,
+}
+
+impl FromBytes for LossyConference {
+    named!(from_bytes<LossyConference>, do_parse!(
+        tag!(&[0xc7][..]) >>
+        conference_id: be_u16 >>
+        peer_id: be_u16 >>
+        message_number: be_u16 >>
+        data: verify!(rest, |data: &[u8]| !data.is_empty()) >>
+        (LossyConference { conference_id, peer_id, message_number, data: data.to_vec() })
+    ));
+}
+
+impl ToBytes for LossyConference {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(0xc7) >>
+            gen_be_u16!(self.conference_id) >>
+            gen_be_u16!(self.peer_id) >>
+            gen_be_u16!(self.message_number) >>
+            gen_cond!(self.data.is_empty(), |buf| gen_error(buf, 0)) >>
+            gen_slice!(self.data.as_slice())
+        )
+    }
+}
+
+impl LossyConference {
+    /// Create new LossyConference object.
+    pub fn new(conference_id: u16, peer_id: u16, message_number: u16, data: Vec<u8>) -> Self {
+        LossyConference { conference_id, peer_id, message_number, data }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    encode_decode_test!(
+        lossy_conference_encode_decode,
+        LossyConference::new(1, 2, 3, vec![192, 42, 42])
+    );
+
+    #[test]
+    fn lossy_conference_from_bytes_empty() {
+        assert!(LossyConference::from_bytes(&[0xc7, 0, 1, 0, 2, 0, 3]).is_err());
+    }
+}
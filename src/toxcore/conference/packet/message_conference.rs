@@ -0,0 +1,231 @@
+/*! MessageConference struct.
+*/
+
+use std::str;
+use nom::{be_u16, be_u32, le_u8, rest};
+
+use toxcore::binary_io::*;
+use toxcore::crypto_core::*;
+use toxcore::dht::packet::MAX_CRYPTO_DATA_SIZE;
+use super::MAX_CONFERENCE_NAME_LENGTH;
+
+/// Maximum size in bytes of message string of conference message and action
+/// payloads.
+pub const MAX_CONFERENCE_MESSAGE_DATA_SIZE: usize = MAX_CRYPTO_DATA_SIZE - 10;
+
+/// Payload of a conference message.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum MessagePayload {
+    /// Ping that lets peers know that the sender is still in the conference
+    Ping,
+    /// New peer joined the conference
+    NewPeer {
+        /// Number of the new peer in the conference
+        peer_id: u16,
+        /// Long term `PublicKey` of the new peer
+        real_pk: PublicKey,
+        /// DHT `PublicKey` of the new peer
+        temp_pk: PublicKey,
+    },
+    /// Peer left the conference
+    KillPeer {
+        /// Number of the peer in the conference
+        peer_id: u16,
+    },
+    /// The sender changed its name
+    Name(String),
+    /// The sender changed the title of the conference
+    Title(String),
+    /// Chat message
+    Message(String),
+    /// Action message
+    Action(String),
+}
+
+impl FromBytes for MessagePayload {
+    named!(from_bytes<MessagePayload>, switch!(le_u8,
+        0 => value!(MessagePayload::Ping) |
+        16 => do_parse!(
+            peer_id: be_u16 >>
+            real_pk: call!(PublicKey::from_bytes) >>
+            temp_pk: call!(PublicKey::from_bytes) >>
+            (MessagePayload::NewPeer { peer_id, real_pk, temp_pk })
+        ) |
+        17 => map!(be_u16, |peer_id| MessagePayload::KillPeer { peer_id }) |
+        48 => map!(
+            map_res!(verify!(rest, |name: &[u8]| name.len() <= MAX_CONFERENCE_NAME_LENGTH), str::from_utf8),
+            |name| MessagePayload::Name(name.to_string())
+        ) |
+        49 => map!(
+            map_res!(verify!(rest, |title: &[u8]| title.len() <= MAX_CONFERENCE_NAME_LENGTH), str::from_utf8),
+            |title| MessagePayload::Title(title.to_string())
+        ) |
+        64 => map!(
+            map_res!(verify!(rest, |message: &[u8]| !message.is_empty() && message.len() <= MAX_CONFERENCE_MESSAGE_DATA_SIZE),
+                str::from_utf8),
+            |message| MessagePayload::Message(message.to_string())
+        ) |
+        65 => map!(
+            map_res!(verify!(rest, |action: &[u8]| !action.is_empty() && action.len() <= MAX_CONFERENCE_MESSAGE_DATA_SIZE),
+                str::from_utf8),
+            |action| MessagePayload::Action(action.to_string())
+        )
+    ));
+}
+
+impl ToBytes for MessagePayload {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        match *self {
+            MessagePayload::Ping => gen_be_u8!(buf, 0),
+            MessagePayload::NewPeer { peer_id, ref real_pk, ref temp_pk } => do_gen!(buf,
+                gen_be_u8!(16) >>
+                gen_be_u16!(peer_id) >>
+                gen_slice!(real_pk.as_ref()) >>
+                gen_slice!(temp_pk.as_ref())
+            ),
+            MessagePayload::KillPeer { peer_id } => do_gen!(buf,
+                gen_be_u8!(17) >>
+                gen_be_u16!(peer_id)
+            ),
+            MessagePayload::Name(ref name) => do_gen!(buf,
+                gen_be_u8!(48) >>
+                gen_cond!(name.len() > MAX_CONFERENCE_NAME_LENGTH, |buf| gen_error(buf, 0)) >>
+                gen_slice!(name.as_bytes())
+            ),
+            MessagePayload::Title(ref title) => do_gen!(buf,
+                gen_be_u8!(49) >>
+                gen_cond!(title.len() > MAX_CONFERENCE_NAME_LENGTH, |buf| gen_error(buf, 0)) >>
+                gen_slice!(title.as_bytes())
+            ),
+            MessagePayload::Message(ref message) => do_gen!(buf,
+                gen_be_u8!(64) >>
+                gen_cond!(message.is_empty() || message.len() > MAX_CONFERENCE_MESSAGE_DATA_SIZE, |buf| gen_error(buf, 0)) >>
+                gen_slice!(message.as_bytes())
+            ),
+            MessagePayload::Action(ref action) => do_gen!(buf,
+                gen_be_u8!(65) >>
+                gen_cond!(action.is_empty() || action.len() > MAX_CONFERENCE_MESSAGE_DATA_SIZE, |buf| gen_error(buf, 0)) >>
+                gen_slice!(action.as_bytes())
+            ),
+        }
+    }
+}
+
+/** MessageConference is a struct that holds a message that is broadcasted to
+all peers of a conference.
+
+Every peer relays received messages to its other connections of the
+conference. Message numbers are increased by the author of the message for
+every new message so peers can drop messages they have already seen.
+
+Serialized form:
+
+Length    | Content
+--------- | ------
+`1`       | `0x63`
+`2`       | Conference number of the receiver
+`2`       | Peer number of the author of the message
+`4`       | Message number
+`1`       | Payload type (0 = ping, 16 = new peer, 17 = kill peer, 48 = name, 49 = title, 64 = message, 65 = action)
+variable  | Payload
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MessageConference {
+    /// Number of the conference on the receiver's side
+    pub conference_id: u16,
+    /// Peer number of the author of the message
+    pub peer_id: u16,
+    /// Number of the message
+    pub message_number: u32,
+    /// Payload of the message
+    pub payload: MessagePayload,
+}
+
+impl FromBytes for MessageConference {
+    named!(from_bytes<MessageConference>, do_parse!(
+        tag!("\x63") >>
+        conference_id: be_u16 >>
+        peer_id: be_u16 >>
+        message_number: be_u32 >>
+        payload: call!(MessagePayload::from_bytes) >>
+        eof!() >>
+        (MessageConference { conference_id, peer_id, message_number, payload })
+    ));
+}
+
+impl ToBytes for MessageConference {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(0x63) >>
+            gen_be_u16!(self.conference_id) >>
+            gen_be_u16!(self.peer_id) >>
+            gen_be_u32!(self.message_number) >>
+            gen_call!(|buf, payload| MessagePayload::to_bytes(payload, buf), &self.payload)
+        )
+    }
+}
+
+impl MessageConference {
+    /// Create new MessageConference object.
+    pub fn new(conference_id: u16, peer_id: u16, message_number: u32, payload: MessagePayload) -> Self {
+        MessageConference { conference_id, peer_id, message_number, payload }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    encode_decode_test!(
+        message_conference_ping_encode_decode,
+        MessageConference::new(1, 2, 3, MessagePayload::Ping)
+    );
+
+    encode_decode_test!(
+        message_conference_new_peer_encode_decode,
+        MessageConference::new(1, 2, 3, MessagePayload::NewPeer {
+            peer_id: 4,
+            real_pk: gen_keypair().0,
+            temp_pk: gen_keypair().0,
+        })
+    );
+
+    encode_decode_test!(
+        message_conference_kill_peer_encode_decode,
+        MessageConference::new(1, 2, 3, MessagePayload::KillPeer { peer_id: 2 })
+    );
+
+    encode_decode_test!(
+        message_conference_name_encode_decode,
+        MessageConference::new(1, 2, 3, MessagePayload::Name("Alice".to_string()))
+    );
+
+    encode_decode_test!(
+        message_conference_title_encode_decode,
+        MessageConference::new(1, 2, 3, MessagePayload::Title("Title".to_string()))
+    );
+
+    encode_decode_test!(
+        message_conference_message_encode_decode,
+        MessageConference::new(1, 2, 3, MessagePayload::Message("Hello".to_string()))
+    );
+
+    encode_decode_test!(
+        message_conference_action_encode_decode,
+        MessageConference::new(1, 2, 3, MessagePayload::Action("waves".to_string()))
+    );
+
+    #[test]
+    fn message_conference_from_bytes_empty_message() {
+        assert!(MessageConference::from_bytes(&[0x63, 0, 1, 0, 2, 0, 0, 0, 3, 64]).is_err());
+    }
+
+    #[test]
+    fn message_conference_to_bytes_overflow() {
+        let message = String::from_utf8(vec![32; MAX_CONFERENCE_MESSAGE_DATA_SIZE + 1]).unwrap();
+        let packet = MessageConference::new(1, 2, 3, MessagePayload::Message(message));
+        let mut buf = [0; MAX_CRYPTO_DATA_SIZE + 1];
+        assert!(packet.to_bytes((&mut buf, 0)).is_err());
+    }
+}
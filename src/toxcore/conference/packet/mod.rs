@@ -0,0 +1,77 @@
+/*! Top-level Conference Packets
+*/
+
+use toxcore::binary_io::*;
+
+mod online_conference;
+mod direct_conference;
+mod message_conference;
+mod lossy_conference;
+
+pub use self::online_conference::*;
+pub use self::direct_conference::*;
+pub use self::message_conference::*;
+pub use self::lossy_conference::*;
+
+/// Maximum size in bytes of peer name and conference title
+pub const MAX_CONFERENCE_NAME_LENGTH: usize = 128;
+
+/** Conference packet enum that encapsulates all types of Conference packets.
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Packet {
+    /// [`OnlineConference`](./struct.OnlineConference.html) structure.
+    OnlineConference(OnlineConference),
+    /// [`DirectConference`](./struct.DirectConference.html) structure.
+    DirectConference(DirectConference),
+    /// [`MessageConference`](./struct.MessageConference.html) structure.
+    MessageConference(MessageConference),
+    /// [`LossyConference`](./struct.LossyConference.html) structure.
+    LossyConference(LossyConference),
+}
+
+impl ToBytes for Packet {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        match *self {
+            Packet::OnlineConference(ref p) => p.to_bytes(buf),
+            Packet::DirectConference(ref p) => p.to_bytes(buf),
+            Packet::MessageConference(ref p) => p.to_bytes(buf),
+            Packet::LossyConference(ref p) => p.to_bytes(buf),
+        }
+    }
+}
+
+impl FromBytes for Packet {
+    named!(from_bytes<Packet>, alt!(
+        map!(OnlineConference::from_bytes, Packet::OnlineConference) |
+        map!(DirectConference::from_bytes, Packet::DirectConference) |
+        map!(MessageConference::from_bytes, Packet::MessageConference) |
+        map!(LossyConference::from_bytes, Packet::LossyConference)
+    ));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use toxcore::messenger::packet::{ConferenceType, CONFERENCE_UID_BYTES};
+
+    encode_decode_test!(
+        packet_online_conference_encode_decode,
+        Packet::OnlineConference(OnlineConference::new(1, ConferenceType::Text, [42; CONFERENCE_UID_BYTES]))
+    );
+
+    encode_decode_test!(
+        packet_direct_conference_encode_decode,
+        Packet::DirectConference(DirectConference::new(1, DirectPayload::PeerQuery))
+    );
+
+    encode_decode_test!(
+        packet_message_conference_encode_decode,
+        Packet::MessageConference(MessageConference::new(1, 2, 3, MessagePayload::Message("Hello".to_string())))
+    );
+
+    encode_decode_test!(
+        packet_lossy_conference_encode_decode,
+        Packet::LossyConference(LossyConference::new(1, 2, 3, vec![192, 42]))
+    );
+}
@@ -0,0 +1,81 @@
+/*! OnlineConference struct.
+*/
+
+use nom::{be_u16, le_u8};
+
+use toxcore::binary_io::*;
+use toxcore::messenger::packet::{ConferenceType, CONFERENCE_UID_BYTES};
+
+/** OnlineConference is a struct that holds info of a conference we are
+connected to.
+
+This packet is sent to a peer when the connection to it becomes established
+if the peer is one of the connections of a conference. It lets the peer know
+the number of the conference on our side so it can send conference packets
+to us.
+
+Serialized form:
+
+Length    | Content
+--------- | ------
+`1`       | `0x61`
+`2`       | Conference number of the sender
+`1`       | Conference type (0 = text, 1 = audio)
+`32`      | Unique conference id
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnlineConference {
+    /// Number of the conference on the sender's side
+    pub conference_id: u16,
+    /// Type of the conference
+    pub conference_type: ConferenceType,
+    /// Unique id of the conference
+    pub unique_id: [u8; CONFERENCE_UID_BYTES],
+}
+
+impl FromBytes for OnlineConference {
+    named!(from_bytes<OnlineConference>, do_parse!(
+        tag!("\x61") >>
+        conference_id: be_u16 >>
+        conference_type: call!(ConferenceType::from_bytes) >>
+        unique_id: count_fixed!(u8, le_u8, CONFERENCE_UID_BYTES) >>
+        eof!() >>
+        (OnlineConference { conference_id, conference_type, unique_id })
+    ));
+}
+
+impl ToBytes for OnlineConference {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(0x61) >>
+            gen_be_u16!(self.conference_id) >>
+            gen_be_u8!(self.conference_type as u8) >>
+            gen_slice!(&self.unique_id)
+        )
+    }
+}
+
+impl OnlineConference {
+    /// Create new OnlineConference object.
+    pub fn new(conference_id: u16, conference_type: ConferenceType, unique_id: [u8; CONFERENCE_UID_BYTES]) -> Self {
+        OnlineConference { conference_id, conference_type, unique_id }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    encode_decode_test!(
+        online_conference_encode_decode,
+        OnlineConference::new(1, ConferenceType::Text, [42; CONFERENCE_UID_BYTES])
+    );
+
+    #[test]
+    fn online_conference_from_bytes_wrong_type() {
+        let mut data = vec![0x61, 0x00, 0x01, 0x02];
+        data.extend_from_slice(&[42; CONFERENCE_UID_BYTES]);
+        assert!(OnlineConference::from_bytes(&data).is_err());
+    }
+}
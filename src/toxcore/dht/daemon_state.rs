@@ -14,6 +14,7 @@ use toxcore::state_format::old::*;
 use toxcore::binary_io::*;
 use toxcore::io_tokio::*;
 use toxcore::dht::kbucket::*;
+use toxcore::onion::onion_announce::OnionAnnounceSnapshot;
 
 /// Serialize or deserialize states of DHT close lists
 #[derive(Clone, Debug)]
@@ -63,6 +64,23 @@ impl DaemonState {
         let nodes_stream = stream::futures_unordered(nodes_sender).then(|_| Ok(()));
         Box::new(nodes_stream.for_each(|()| Ok(())))
     }
+
+    /// Serialize announced onion nodes and onion secret bytes
+    pub fn serialize_onion_announce(server: &Server) -> Vec<u8> {
+        server.onion_announce_snapshot().to_vec()
+    }
+
+    /// Deserialize announced onion nodes and onion secret bytes and restore
+    /// them in the server
+    pub fn deserialize_onion_announce(server: &Server, serialized_data: &[u8]) -> Result<(), Error> {
+        match OnionAnnounceSnapshot::from_bytes(serialized_data) {
+            IResult::Done(_, snapshot) => {
+                server.restore_onion_announce(&snapshot);
+                Ok(())
+            },
+            e => Err(Error::new(ErrorKind::Other, format!("Can't deserialize onion announce from serialized bytes {:?}", e))),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -118,4 +136,22 @@ mod tests {
         let serialized_vec = DaemonState::serialize_old(&alice);
         assert!(DaemonState::deserialize_old(&alice, &serialized_vec).wait().is_ok());
     }
+
+    #[test]
+    fn daemon_state_serialize_deserialize_onion_announce() {
+        let (pk, sk) = gen_keypair();
+        let (tx, _rx) = mpsc::unbounded::<(Packet, SocketAddr)>();
+        let alice = Server::new(tx.clone(), pk, sk.clone());
+
+        let serialized_vec = DaemonState::serialize_onion_announce(&alice);
+        let snapshot = alice.onion_announce_snapshot();
+
+        let bob = Server::new(tx, pk, sk);
+        DaemonState::deserialize_onion_announce(&bob, &serialized_vec).unwrap();
+
+        assert_eq!(bob.onion_announce_snapshot(), snapshot);
+
+        // test with incompleted serialized data
+        assert!(DaemonState::deserialize_onion_announce(&bob, &serialized_vec[..serialized_vec.len() - 1]).is_err());
+    }
 }
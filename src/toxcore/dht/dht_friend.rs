@@ -36,6 +36,11 @@ pub struct DhtFriend {
     pub nodes_to_bootstrap: NodesQueue,
     /// Struct for hole punching.
     pub hole_punch: HolePunching,
+    /// Addresses returned by close nodes that were reported to `net_crypto`
+    /// last time.
+    pub reported_addrs: Vec<SocketAddr>,
+    /// Address of the friend that was reported to `net_crypto` last time.
+    pub reported_addr: Option<SocketAddr>,
 }
 
 impl DhtFriend {
@@ -48,6 +53,8 @@ impl DhtFriend {
             random_requests_count: 0,
             nodes_to_bootstrap: NodesQueue::new(FRIEND_BOOTSTRAP_NODES_COUNT),
             hole_punch: HolePunching::new(),
+            reported_addrs: Vec::new(),
+            reported_addr: None,
         }
     }
 
@@ -82,6 +89,34 @@ impl DhtFriend {
         addrs
     }
 
+    /// Get addresses of friend returned by his close nodes if they changed
+    /// since the last call and remember them as reported.
+    pub fn update_reported_addrs(&mut self) -> Option<Vec<SocketAddr>> {
+        let mut addrs = self.get_returned_addrs();
+        addrs.sort();
+        if addrs == self.reported_addrs {
+            None
+        } else {
+            self.reported_addrs = addrs.clone();
+            Some(addrs)
+        }
+    }
+
+    /// Get address of the friend if it's known and changed since the last
+    /// call and remember it as reported.
+    pub fn update_reported_addr(&mut self) -> Option<SocketAddr> {
+        if !self.is_addr_known() {
+            return None
+        }
+        let addr = self.close_nodes.nodes.first().and_then(|node| node.get_socket_addr());
+        if addr.is_none() || addr == self.reported_addr {
+            None
+        } else {
+            self.reported_addr = addr;
+            addr
+        }
+    }
+
     /// Try to add a node to the friend's close nodes list.
     pub fn try_add_to_close(&mut self, node: &PackedNode) -> bool {
         self.close_nodes.try_add(&self.pk, node, /* evict */ true)
@@ -161,6 +196,41 @@ mod tests {
         assert_eq!(returned_addrs_set, addrs_set);
     }
 
+    #[test]
+    fn update_reported_addrs() {
+        let pk = gen_keypair().0;
+        let mut friend = DhtFriend::new(pk);
+
+        // nothing to report for a new friend
+        assert_eq!(friend.update_reported_addrs(), None);
+
+        let node = PackedNode::new("192.168.1.1:12345".parse().unwrap(), &gen_keypair().0);
+        let addr: SocketAddr = "192.168.2.1:12345".parse().unwrap();
+        friend.try_add_to_close(&node);
+        friend.close_nodes.get_node_mut(&pk, &node.pk).unwrap().update_returned_addr(addr);
+
+        assert_eq!(friend.update_reported_addrs(), Some(vec![addr]));
+        // the same addresses are reported only once
+        assert_eq!(friend.update_reported_addrs(), None);
+    }
+
+    #[test]
+    fn update_reported_addr() {
+        let pk = gen_keypair().0;
+        let mut friend = DhtFriend::new(pk);
+
+        // nothing to report while the address is unknown
+        assert!(friend.try_add_to_close(&PackedNode::new("192.168.1.1:12345".parse().unwrap(), &gen_keypair().0)));
+        assert_eq!(friend.update_reported_addr(), None);
+
+        let addr = "192.168.1.2:12345".parse().unwrap();
+        assert!(friend.try_add_to_close(&PackedNode::new(addr, &pk)));
+
+        assert_eq!(friend.update_reported_addr(), Some(addr));
+        // the same address is reported only once
+        assert_eq!(friend.update_reported_addr(), None);
+    }
+
     #[test]
     fn get_returned_addrs_timed_out() {
         let pk = gen_keypair().0;
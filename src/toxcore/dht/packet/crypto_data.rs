@@ -14,7 +14,7 @@ use toxcore::crypto_core::*;
 const MAX_CRYPTO_PACKET_SIZE: usize = 1400;
 
 /// The maximum size of data in packets.
-const MAX_CRYPTO_DATA_SIZE: usize = MAX_CRYPTO_PACKET_SIZE - MACBYTES - 11;
+pub const MAX_CRYPTO_DATA_SIZE: usize = MAX_CRYPTO_PACKET_SIZE - MACBYTES - 11;
 
 /// All packets will be padded a number of bytes based on this number.
 const CRYPTO_MAX_PADDING: usize = 8;
@@ -61,6 +61,11 @@ impl ToBytes for CryptoData {
 }
 
 impl CryptoData {
+    /// Size of serialized packet including packet kind byte and two bytes of
+    /// nonce.
+    pub fn size(&self) -> usize {
+        self.payload.len() + 3
+    }
     /// Get last two bytes of `Nonce` considering it as BigEndian number.
     pub fn nonce_last_bytes(nonce: Nonce) -> u16 {
         BigEndian::read_u16(&nonce.as_ref()[NONCEBYTES - 2..])
@@ -42,6 +42,14 @@ type Tx = mpsc::UnboundedSender<(Packet, SocketAddr)>;
 /// Shorthand for the transmit half of the TCP onion channel.
 type TcpOnionTx = mpsc::UnboundedSender<(InnerOnionResponse, SocketAddr)>;
 
+/// Shorthand for the transmit half of the channel for `OnionAnnounceResponse`
+/// packets that should be handled by onion client.
+type OnionAnnounceResponseTx = mpsc::UnboundedSender<(OnionAnnounceResponse, SocketAddr)>;
+
+/// Shorthand for the transmit half of the channel for `OnionDataResponse`
+/// packets that should be handled by onion client.
+type OnionDataResponseTx = mpsc::UnboundedSender<(OnionDataResponse, SocketAddr)>;
+
 /// Number of random `NodesRequest` packet to send every second one per second.
 /// After random requests count exceeds this number `NODES_REQ_INTERVAL` will be
 /// used.
@@ -151,6 +159,12 @@ pub struct Server {
     /// should be redirected to TCP sender trough this sink
     /// None if there is no TCP relay
     tcp_onion_sink: Option<TcpOnionTx>,
+    /// Sink for `OnionAnnounceResponse` packets that are handled by onion
+    /// client. None if there is no onion client.
+    onion_announce_response_sink: Option<OnionAnnounceResponseTx>,
+    /// Sink for `OnionDataResponse` packets that are handled by onion client.
+    /// None if there is no onion client.
+    onion_data_response_sink: Option<OnionDataResponseTx>,
     /// Net crypto module that handles `CookieRequest`, `CookieResponse`,
     /// `CryptoHandshake` and `CryptoData` packets. It can be `None` in case of
     /// pure bootstrap server when we don't have friends and therefore don't
@@ -213,6 +227,8 @@ impl Server {
             nodes_to_ping: Arc::new(RwLock::new(NodesQueue::new(MAX_TO_PING))),
             bootstrap_info: None,
             tcp_onion_sink: None,
+            onion_announce_response_sink: None,
+            onion_data_response_sink: None,
             net_crypto: None,
             lan_discovery_enabled: true,
             is_ipv6_enabled: false,
@@ -237,7 +253,7 @@ impl Server {
     }
 
     /// Get closest nodes from both close_nodes and friend's close_nodes
-    fn get_closest(&self, base_pk: &PublicKey, only_global: bool) -> NodesQueue {
+    pub fn get_closest(&self, base_pk: &PublicKey, only_global: bool) -> NodesQueue {
         let close_nodes = self.close_nodes.read();
         let friends = self.friends.read();
 
@@ -254,6 +270,34 @@ impl Server {
         queue
     }
 
+    /// Get up to `count` random nodes from both close_nodes and friend's
+    /// close_nodes. Bad nodes are skipped. These nodes are used by onion
+    /// client to build onion paths.
+    pub fn random_nodes(&self, count: usize) -> Vec<PackedNode> {
+        let close_nodes = self.close_nodes.read();
+        let friends = self.friends.read();
+
+        let mut nodes: Vec<PackedNode> = Vec::new();
+        let all_nodes = close_nodes.iter()
+            .chain(friends.iter().flat_map(|friend| friend.close_nodes.iter()))
+            .filter(|node| !node.is_bad())
+            .flat_map(|node| node.to_packed_node());
+        for node in all_nodes {
+            if nodes.iter().all(|n| n.pk != node.pk) {
+                nodes.push(node);
+            }
+        }
+
+        // partial Fisher-Yates shuffle
+        let count = count.min(nodes.len());
+        for i in 0 .. count {
+            let j = i + random_usize() % (nodes.len() - i);
+            nodes.swap(i, j);
+        }
+        nodes.truncate(count);
+        nodes
+    }
+
     /// Add a friend.
     /// `node_to_bootstrap` of new friend is filled with close nodes for fast bootstrapping.
     pub fn add_friend(&self, friend_pk: PublicKey) {
@@ -290,6 +334,7 @@ impl Server {
         let mut friends = self.friends.write();
 
         request_queue.clear_timed_out();
+        self.onion_announce.write().remove_timed_out();
 
         // Send NodesRequest packets to nodes from the Server
         let ping_nodes_to_bootstrap = self.ping_nodes_to_bootstrap(&mut request_queue, &mut nodes_to_bootstrap, self.pk);
@@ -314,6 +359,22 @@ impl Server {
 
         let send_nat_ping_req = self.send_nat_ping_req(&mut request_queue, &mut friends);
 
+        // Addresses returned by close nodes of friends help net_crypto to find
+        // connections when friends change their UDP addresses. Addresses of
+        // found friends are used by net_crypto to reach them directly. They
+        // are passed only when changed since net_crypto has to scan all its
+        // connections
+        if let Some(ref net_crypto) = self.net_crypto {
+            for friend in friends.iter_mut() {
+                if let Some(addrs) = friend.update_reported_addrs() {
+                    net_crypto.set_udp_addr_hints(friend.pk, addrs);
+                }
+                if let Some(addr) = friend.update_reported_addr() {
+                    net_crypto.set_dht_udp_addr(friend.pk, addr);
+                }
+            }
+        }
+
         let future = ping_nodes_to_bootstrap.join5(
             ping_close_nodes,
             send_nodes_req_random,
@@ -688,7 +749,7 @@ impl Server {
             },
             Packet::OnionDataRequest(packet) => {
                 debug!("Received OnionDataRequest");
-                self.handle_onion_data_request(packet)
+                self.handle_onion_data_request(packet, addr)
             },
             Packet::OnionResponse3(packet) => {
                 debug!("Received OnionResponse3");
@@ -706,29 +767,17 @@ impl Server {
                 debug!("Received BootstrapInfo");
                 self.handle_bootstrap_info(&packet, addr)
             },
-            // This packet should be handled in client only
             Packet::CryptoData(packet) => {
-                debug!("This packet should be handled in client only, {:?}", packet);
-                Box::new(future::err(
-                    Error::new(ErrorKind::Other,
-                               format!("Packet is not handled {:?}", packet)
-                    )))
+                debug!("Received CryptoData");
+                self.handle_crypto_data(&packet, addr)
             },
-            // This packet should be handled in client only
             Packet::OnionDataResponse(packet) => {
-                debug!("This packet should be handled in client only, {:?}", packet);
-                Box::new(future::err(
-                    Error::new(ErrorKind::Other,
-                               format!("Packet is not handled {:?}", packet)
-                    )))
+                debug!("Received OnionDataResponse");
+                self.handle_onion_data_response(packet, addr)
             },
-            // This packet should be handled in client only
             Packet::OnionAnnounceResponse(packet) => {
-                debug!("This packet should be handled in client only, {:?}", packet);
-                Box::new(future::err(
-                    Error::new(ErrorKind::Other,
-                               format!("Packet is not handled {:?}", packet)
-                    )))
+                debug!("Received OnionAnnounceResponse");
+                self.handle_onion_announce_response(packet, addr)
             },
         }
     }
@@ -946,6 +995,18 @@ impl Server {
         }
     }
 
+    /// Handle received `CryptoData` packet and pass it to `net_crypto`
+    /// module.
+    fn handle_crypto_data(&self, packet: &CryptoData, addr: SocketAddr) -> IoFuture<()> {
+        if let Some(ref net_crypto) = self.net_crypto {
+            net_crypto.handle_udp_crypto_data(packet, addr)
+        } else {
+            Box::new( future::err(
+                Error::new(ErrorKind::Other, "Net crypto is not initialised")
+            ))
+        }
+    }
+
     /// Handle received `DhtRequest` packet, redirect it if it's sent for
     /// someone else or parse it and handle the payload if it's sent for us.
     fn handle_dht_req(&self, packet: DhtRequest, addr: SocketAddr) -> IoFuture<()> {
@@ -1195,9 +1256,9 @@ impl Server {
     /// Handle received `OnionDataRequest` packet and send `OnionResponse3`
     /// packet with inner `OnionDataResponse` to destination node through its
     /// onion path.
-    fn handle_onion_data_request(&self, packet: OnionDataRequest) -> IoFuture<()> {
-        let onion_announce = self.onion_announce.read();
-        match onion_announce.handle_data_request(packet) {
+    fn handle_onion_data_request(&self, packet: OnionDataRequest, addr: SocketAddr) -> IoFuture<()> {
+        let mut onion_announce = self.onion_announce.write();
+        match onion_announce.handle_data_request(packet, addr) {
             Ok((response, addr)) => self.send_to_direct(addr, Packet::OnionResponse3(response)),
             Err(e) => Box::new(future::err(e))
         }
@@ -1316,6 +1377,32 @@ impl Server {
         }
     }
 
+    /// Handle received `OnionAnnounceResponse` packet by passing it to onion
+    /// client. This packet should be handled in client only.
+    fn handle_onion_announce_response(&self, packet: OnionAnnounceResponse, addr: SocketAddr) -> IoFuture<()> {
+        if let Some(ref onion_announce_response_sink) = self.onion_announce_response_sink {
+            send_to(onion_announce_response_sink, (packet, addr))
+        } else {
+            Box::new(future::err(
+                Error::new(ErrorKind::Other,
+                    format!("Packet is not handled {:?}", packet)
+            )))
+        }
+    }
+
+    /// Handle received `OnionDataResponse` packet by passing it to onion
+    /// client. This packet should be handled in client only.
+    fn handle_onion_data_response(&self, packet: OnionDataResponse, addr: SocketAddr) -> IoFuture<()> {
+        if let Some(ref onion_data_response_sink) = self.onion_data_response_sink {
+            send_to(onion_data_response_sink, (packet, addr))
+        } else {
+            Box::new(future::err(
+                Error::new(ErrorKind::Other,
+                    format!("Packet is not handled {:?}", packet)
+            )))
+        }
+    }
+
     /// Refresh onion symmetric key to enforce onion paths expiration.
     fn refresh_onion_key(&self) {
         *self.onion_symmetric_key.write() = secretbox::gen_key();
@@ -1390,6 +1477,18 @@ impl Server {
         self.tcp_onion_sink = Some(tcp_onion_sink)
     }
 
+    /// Set sink for `OnionAnnounceResponse` packets that are handled by onion
+    /// client.
+    pub fn set_onion_announce_response_sink(&mut self, onion_announce_response_sink: OnionAnnounceResponseTx) {
+        self.onion_announce_response_sink = Some(onion_announce_response_sink)
+    }
+
+    /// Set sink for `OnionDataResponse` packets that are handled by onion
+    /// client.
+    pub fn set_onion_data_response_sink(&mut self, onion_data_response_sink: OnionDataResponseTx) {
+        self.onion_data_response_sink = Some(onion_data_response_sink)
+    }
+
     /// Set `net_crypto` module.
     pub fn set_net_crypto(&mut self, net_crypto: NetCrypto) {
         self.net_crypto = Some(net_crypto);
@@ -1399,6 +1498,37 @@ impl Server {
     pub fn get_precomputed_keys(&self) -> PrecomputedCache {
         self.precomputed_keys.clone()
     }
+
+    /// Set maximum number of announced onion nodes we store.
+    pub fn set_onion_announce_max_entries(&mut self, max_entries: usize) {
+        self.onion_announce.write().set_max_entries(max_entries);
+    }
+
+    /// Get snapshot of announced onion nodes that can be restored after
+    /// restart with `restore_onion_announce`.
+    pub fn onion_announce_snapshot(&self) -> OnionAnnounceSnapshot {
+        self.onion_announce.read().snapshot()
+    }
+
+    /// Restore announced onion nodes from snapshot.
+    pub fn restore_onion_announce(&self, snapshot: &OnionAnnounceSnapshot) {
+        self.onion_announce.write().restore(snapshot);
+    }
+
+    /// Set limits of `OnionDataRequest`s forwarded to announced onion nodes.
+    pub fn set_onion_data_limits(&mut self, data_limits: OnionDataLimits) {
+        self.onion_announce.write().set_data_limits(data_limits);
+    }
+
+    /// Get information about announced onion nodes.
+    pub fn onion_announce_entries(&self) -> Vec<OnionAnnounceEntryInfo> {
+        self.onion_announce.read().entries_info()
+    }
+
+    /// Get counters of handled `OnionDataRequest`s.
+    pub fn onion_data_stats(&self) -> OnionDataStats {
+        self.onion_announce.read().data_stats()
+    }
 }
 
 #[cfg(test)]
@@ -2481,6 +2611,9 @@ mod tests {
         assert_eq!(response.nonce, nonce);
         assert_eq!(response.temporary_pk, temporary_pk);
         assert_eq!(response.payload, payload);
+
+        assert_eq!(alice.onion_data_stats().forwarded, 1);
+        assert_eq!(alice.onion_announce_entries()[0].forwards, 1);
     }
 
     // handle_onion_response_3
@@ -3418,6 +3551,24 @@ mod tests {
         assert!(alice.handle_packet(data, addr).wait().is_err());
     }
 
+    #[test]
+    fn handle_onion_data_response_with_sink() {
+        let (mut alice, _precomp, _bob_pk, _bob_sk, _rx, addr) = create_node();
+        let (onion_data_response_tx, onion_data_response_rx) = mpsc::unbounded();
+        alice.set_onion_data_response_sink(onion_data_response_tx);
+
+        let packet = OnionDataResponse {
+            nonce: gen_nonce(),
+            temporary_pk: gen_keypair().0,
+            payload: vec![42; 123]
+        };
+
+        alice.handle_packet(Packet::OnionDataResponse(packet.clone()), addr).wait().unwrap();
+
+        let (received, _onion_data_response_rx) = onion_data_response_rx.into_future().wait().unwrap();
+        assert_eq!(received, Some((packet, addr)));
+    }
+
     #[test]
     fn handle_onion_announce_response() {
         let (alice, precomp, _bob_pk, _bob_sk, _rx, addr) = create_node();
@@ -3434,4 +3585,45 @@ mod tests {
 
         assert!(alice.handle_packet(data, addr).wait().is_err());
     }
+
+    #[test]
+    fn handle_onion_announce_response_with_sink() {
+        let (mut alice, precomp, _bob_pk, _bob_sk, _rx, addr) = create_node();
+        let (onion_announce_response_tx, onion_announce_response_rx) = mpsc::unbounded();
+        alice.set_onion_announce_response_sink(onion_announce_response_tx);
+
+        let payload = OnionAnnounceResponsePayload {
+            announce_status: AnnounceStatus::Failed,
+            ping_id_or_pk: sha256::hash(&[1, 2, 3]),
+            nodes: Vec::new()
+        };
+        let packet = OnionAnnounceResponse::new(&precomp, 12345, &payload);
+
+        alice.handle_packet(Packet::OnionAnnounceResponse(packet.clone()), addr).wait().unwrap();
+
+        let (received, _onion_announce_response_rx) = onion_announce_response_rx.into_future().wait().unwrap();
+        assert_eq!(received, Some((packet, addr)));
+    }
+
+    #[test]
+    fn random_nodes() {
+        let (alice, _precomp, _bob_pk, _bob_sk, _rx, _addr) = create_node();
+
+        assert!(alice.random_nodes(3).is_empty());
+
+        let nodes = (0 .. 5).map(|i| {
+            let node = PackedNode::new(format!("1.2.3.{}:12345", i).parse().unwrap(), &gen_keypair().0);
+            assert!(alice.try_add_to_close_nodes(&node));
+            node
+        }).collect::<Vec<_>>();
+
+        let random_nodes = alice.random_nodes(3);
+        assert_eq!(random_nodes.len(), 3);
+        for node in &random_nodes {
+            assert!(nodes.contains(node));
+        }
+        assert!(random_nodes.iter().all(|node| random_nodes.iter().filter(|n| n.pk == node.pk).count() == 1));
+
+        assert_eq!(alice.random_nodes(10).len(), 5);
+    }
 }
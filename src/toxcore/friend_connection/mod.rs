@@ -1,4 +1,1016 @@
 /*! The implementation of Friend connection
+
+Friend connection module sits on top of `net_crypto` and TCP connections. It
+tracks friends by their long term `PublicKey` and creates crypto connection to
+a friend as soon as its DHT `PublicKey` becomes known. When the connection is
+established friends periodically exchange `Alive` packets to make sure that
+the connection is still alive and `ShareRelays` packets to let each other know
+TCP relays they can be reached by.
+
+Relays received in `ShareRelays` are added to TCP connections only if they
+can be linked to the friend. Their number is limited per friend and in total.
+They are removed when all friends that shared them are dropped or when they
+stay disconnected for `SHARED_RELAY_TIMEOUT` seconds.
+
 */
 
 pub mod packet;
+
+use std::collections::{HashMap, HashSet};
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::{future, Future, Stream};
+use futures::sync::mpsc;
+use parking_lot::RwLock;
+use tokio;
+use tokio::timer::Interval;
+
+use toxcore::binary_io::*;
+use toxcore::crypto_core::*;
+use toxcore::dht::packet::MAX_CRYPTO_DATA_SIZE;
+use toxcore::friend_connection::packet::*;
+use toxcore::io_tokio::*;
+use toxcore::net_crypto::NetCrypto;
+use toxcore::tcp::connections::Connections;
+use toxcore::time::*;
+
+/// Interval in seconds for sending `Alive` packets to a friend.
+pub const FRIEND_PING_INTERVAL: u64 = 8;
+
+/// Friend is considered offline if we don't receive `Alive` packets from it
+/// for this amount of seconds.
+pub const FRIEND_CONNECTION_TIMEOUT: u64 = FRIEND_PING_INTERVAL * 4;
+
+/// Interval in seconds for sending `ShareRelays` packets to a friend.
+pub const SHARE_RELAYS_INTERVAL: u64 = 5 * 60;
+
+/// Maximum number of relays that can be sent in `ShareRelays` packet.
+pub const MAX_SHARED_RELAYS: usize = 3;
+
+/// Maximum number of relays shared by a friend that can be linked to it.
+pub const MAX_FRIEND_SHARED_RELAYS: usize = MAX_SHARED_RELAYS;
+
+/// Maximum number of relays shared by all friends that can be added to TCP
+/// connections.
+pub const MAX_TOTAL_SHARED_RELAYS: usize = 32;
+
+/// Relay shared by a friend is removed if it stays disconnected for this
+/// amount of seconds.
+pub const SHARED_RELAY_TIMEOUT: u64 = 60;
+
+/// Interval in milliseconds for running the main loop.
+const MAIN_LOOP_INTERVAL: u64 = 1000;
+
+/// Shorthand for the transmit half of the message channel for sending friend
+/// connection status changes. The key is a long term public key of the
+/// friend, the flag is `true` when the friend becomes online and `false` when
+/// it becomes offline.
+type ConnectionStatusTx = mpsc::UnboundedSender<(PublicKey, bool)>;
+
+/// Shorthand for the transmit half of the message channel for sending
+/// `FriendRequests` packets received via `net_crypto`. The key is a long term
+/// public key of the sender.
+type FriendRequestTx = mpsc::UnboundedSender<(PublicKey, FriendRequests)>;
+
+/// Friend related data stored in the friend connections module.
+#[derive(Clone, Debug)]
+struct Friend {
+    /// Long term `PublicKey` of the friend.
+    real_pk: PublicKey,
+    /// DHT `PublicKey` of the friend if it's known.
+    dht_pk: Option<PublicKey>,
+    /// Whether crypto connection to the friend is established.
+    connected: bool,
+    /// Time when we sent the last `Alive` packet to the friend.
+    ping_sent_time: Option<Instant>,
+    /// Time when we received the last `Alive` packet from the friend.
+    ping_received_time: Option<Instant>,
+    /// Time when we sent the last `ShareRelays` packet to the friend.
+    share_relays_time: Option<Instant>,
+}
+
+/// Relay that was added to TCP connections because friends shared it.
+#[derive(Clone, Debug)]
+struct SharedRelay {
+    /// Long term `PublicKey`s of friends that shared the relay and are
+    /// linked to it.
+    friends: HashSet<PublicKey>,
+    /// Time when the relay was connected last time or when it was added.
+    connected_time: Instant,
+}
+
+impl Friend {
+    /// Create new `Friend` with unknown DHT `PublicKey`.
+    fn new(real_pk: PublicKey) -> Friend {
+        Friend {
+            real_pk,
+            dht_pk: None,
+            connected: false,
+            ping_sent_time: None,
+            ping_received_time: None,
+            share_relays_time: None,
+        }
+    }
+
+    /// Check if we didn't receive `Alive` packets from the friend for too
+    /// long.
+    fn is_timed_out(&self) -> bool {
+        self.ping_received_time.map_or(false, |time|
+            clock_elapsed(time) >= Duration::from_secs(FRIEND_CONNECTION_TIMEOUT)
+        )
+    }
+
+    /// Check if it's time to send `Alive` packet to the friend.
+    fn ping_should_be_sent(&self) -> bool {
+        self.ping_sent_time.map_or(true, |time|
+            clock_elapsed(time) >= Duration::from_secs(FRIEND_PING_INTERVAL)
+        )
+    }
+
+    /// Check if it's time to send `ShareRelays` packet to the friend.
+    fn share_relays_should_be_sent(&self) -> bool {
+        self.share_relays_time.map_or(true, |time|
+            clock_elapsed(time) >= Duration::from_secs(SHARE_RELAYS_INTERVAL)
+        )
+    }
+}
+
+/// Struct that manages connections to friends via `net_crypto` and reports
+/// when friends go online or offline.
+#[derive(Clone)]
+pub struct FriendConnections {
+    /// `NetCrypto` module that is used to create crypto connections.
+    net_crypto: NetCrypto,
+    /// TCP connections that are used to reach friends via relays.
+    tcp_connections: Connections,
+    /// Friends by their long term `PublicKey`.
+    friends: Arc<RwLock<HashMap<PublicKey, Friend>>>,
+    /// Relays added because friends shared them by relay `PublicKey`.
+    shared_relays: Arc<RwLock<HashMap<PublicKey, SharedRelay>>>,
+    /// Sink to send friend connection status changes.
+    connection_status_tx: Option<ConnectionStatusTx>,
+    /// Sink to send received `FriendRequests` packets.
+    friend_request_tx: Option<FriendRequestTx>,
+}
+
+impl FriendConnections {
+    /// Create new `FriendConnections` object.
+    pub fn new(net_crypto: NetCrypto, tcp_connections: Connections) -> FriendConnections {
+        FriendConnections {
+            net_crypto,
+            tcp_connections,
+            friends: Arc::new(RwLock::new(HashMap::new())),
+            shared_relays: Arc::new(RwLock::new(HashMap::new())),
+            connection_status_tx: None,
+            friend_request_tx: None,
+        }
+    }
+
+    /// Set sink to send friend connection status changes.
+    pub fn set_connection_status_sink(&mut self, connection_status_tx: ConnectionStatusTx) {
+        self.connection_status_tx = Some(connection_status_tx);
+    }
+
+    /// Set sink to send received `FriendRequests` packets.
+    pub fn set_friend_request_sink(&mut self, friend_request_tx: FriendRequestTx) {
+        self.friend_request_tx = Some(friend_request_tx);
+    }
+
+    /// Add a friend we want to connect to. Connection will be created when
+    /// its DHT `PublicKey` becomes known. If `net_crypto` already has
+    /// connection to the friend, e.g. because the friend sent us a friend
+    /// request, its DHT `PublicKey` is taken from this connection. Does
+    /// nothing if the friend already exists.
+    pub fn add_friend(&self, friend_pk: PublicKey) {
+        let mut friends = self.friends.write();
+        if friends.contains_key(&friend_pk) {
+            return
+        }
+
+        let mut friend = Friend::new(friend_pk);
+        if let Some(info) = self.net_crypto.connection_info(friend_pk) {
+            friend.dht_pk = Some(info.peer_dht_pk);
+            self.tcp_connections.add_connection(info.peer_dht_pk);
+        }
+        friends.insert(friend_pk, friend);
+    }
+
+    /// Remove a friend together with its crypto and TCP connections.
+    pub fn remove_friend(&self, friend_pk: PublicKey) -> IoFuture<()> {
+        let friend = if let Some(friend) = self.friends.write().remove(&friend_pk) {
+            friend
+        } else {
+            return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                format!("No friend with key {:?}", friend_pk)
+            )))
+        };
+
+        if let Some(dht_pk) = friend.dht_pk {
+            self.drop_connections(friend.real_pk, dht_pk)
+        } else {
+            Box::new(future::ok(()))
+        }
+    }
+
+    /// Check if the friend is online.
+    pub fn is_friend_online(&self, friend_pk: PublicKey) -> bool {
+        self.friends.read().get(&friend_pk).map_or(false, |friend| friend.connected)
+    }
+
+    /// Get DHT `PublicKey` of the friend if it's known.
+    pub fn friend_dht_pk(&self, friend_pk: PublicKey) -> Option<PublicKey> {
+        self.friends.read().get(&friend_pk).and_then(|friend| friend.dht_pk)
+    }
+
+    /// Send connection status change to the sink if it's set.
+    fn send_connection_status(&self, friend_pk: PublicKey, status: bool) -> IoFuture<()> {
+        if let Some(ref connection_status_tx) = self.connection_status_tx {
+            send_to(connection_status_tx, (friend_pk, status))
+        } else {
+            Box::new(future::ok(()))
+        }
+    }
+
+    /// Kill crypto connection to a friend and remove TCP connection to it
+    /// together with relays it shared. Errors are ignored since connections
+    /// might be already removed.
+    fn drop_connections(&self, friend_pk: PublicKey, dht_pk: PublicKey) -> IoFuture<()> {
+        self.release_shared_relays(friend_pk);
+        let kill_future = self.net_crypto.kill_connection(friend_pk)
+            .then(|_| Ok(()));
+        let tcp_future = self.tcp_connections.remove_connection(&dht_pk)
+            .then(|_| Ok(()));
+        Box::new(kill_future.join(tcp_future).map(|_| ()))
+    }
+
+    /// Handle DHT `PublicKey` of a friend. It's called when the key is found
+    /// via onion or received from `net_crypto`. If the key is changed the old
+    /// connections are dropped and new ones are created.
+    pub fn handle_dht_pk(&self, friend_pk: PublicKey, dht_pk: PublicKey) -> IoFuture<()> {
+        let mut friends = self.friends.write();
+        let friend = if let Some(friend) = friends.get_mut(&friend_pk) {
+            friend
+        } else {
+            return Box::new(future::ok(()))
+        };
+
+        if friend.dht_pk == Some(dht_pk) {
+            return Box::new(future::ok(()))
+        }
+
+        let mut futures = Vec::new();
+        if let Some(old_dht_pk) = friend.dht_pk {
+            futures.push(self.drop_connections(friend_pk, old_dht_pk));
+            if friend.connected {
+                friend.connected = false;
+                futures.push(self.send_connection_status(friend_pk, false));
+            }
+        }
+
+        friend.dht_pk = Some(dht_pk);
+        self.add_crypto_connection(friend_pk, dht_pk);
+        self.tcp_connections.add_connection(dht_pk);
+
+        Box::new(future::join_all(futures).map(|_| ()))
+    }
+
+    /// Handle status change of crypto connection to a friend. When the
+    /// connection becomes established `Alive` and `ShareRelays` packets are
+    /// sent immediately.
+    pub fn handle_connection_status(&self, friend_pk: PublicKey, status: bool) -> IoFuture<()> {
+        let mut friends = self.friends.write();
+        let friend = if let Some(friend) = friends.get_mut(&friend_pk) {
+            friend
+        } else {
+            return Box::new(future::ok(()))
+        };
+
+        if friend.connected == status {
+            return Box::new(future::ok(()))
+        }
+
+        if status {
+            self.set_friend_online(friend)
+        } else {
+            friend.connected = false;
+            friend.ping_sent_time = None;
+            friend.ping_received_time = None;
+            friend.share_relays_time = None;
+            self.send_connection_status(friend_pk, false)
+        }
+    }
+
+    /// Mark the friend as online and send `Alive` and `ShareRelays` packets
+    /// to it immediately.
+    fn set_friend_online(&self, friend: &mut Friend) -> IoFuture<()> {
+        friend.connected = true;
+        friend.ping_received_time = Some(clock_now());
+        let status_future = self.send_connection_status(friend.real_pk, true);
+        // sending errors are not fatal since the connection might be lost
+        // in the meantime
+        let ping_future = self.send_ping(friend).then(|_| Ok(()));
+        let share_relays_future = self.send_share_relays(friend).then(|_| Ok(()));
+        Box::new(status_future.join3(ping_future, share_relays_future).map(|_| ()))
+    }
+
+    /// Handle lossless packet received from a friend via `net_crypto`.
+    pub fn handle_lossless(&self, friend_pk: PublicKey, data: &[u8]) -> IoFuture<()> {
+        match Packet::from_bytes(data) {
+            IResult::Done(_, Packet::Alive(_)) => self.handle_alive(friend_pk),
+            IResult::Done(_, Packet::ShareRelays(packet)) => self.handle_share_relays(friend_pk, packet),
+            IResult::Done(_, Packet::FriendRequests(packet)) => self.handle_friend_requests(friend_pk, packet),
+            _ => Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                "Failed to parse friend connection packet"
+            ))),
+        }
+    }
+
+    /// Handle `Alive` packet updating the time when we heard from the friend
+    /// the last time.
+    fn handle_alive(&self, friend_pk: PublicKey) -> IoFuture<()> {
+        if let Some(friend) = self.friends.write().get_mut(&friend_pk) {
+            friend.ping_received_time = Some(clock_now());
+        }
+        Box::new(future::ok(()))
+    }
+
+    /// Handle `FriendRequests` packet passing it to the friend requests sink
+    /// if it's set.
+    fn handle_friend_requests(&self, friend_pk: PublicKey, packet: FriendRequests) -> IoFuture<()> {
+        if let Some(ref friend_request_tx) = self.friend_request_tx {
+            send_to(friend_request_tx, (friend_pk, packet))
+        } else {
+            Box::new(future::ok(()))
+        }
+    }
+
+    /// Handle `ShareRelays` packet adding received relays to TCP connections
+    /// and using them to reach the friend. This function uses `tokio::spawn`
+    /// inside so it should be executed via tokio to be able to spawn futures
+    /// that keep connections to new relays alive.
+    fn handle_share_relays(&self, friend_pk: PublicKey, packet: ShareRelays) -> IoFuture<()> {
+        let dht_pk = if let Some(dht_pk) = self.friend_dht_pk(friend_pk) {
+            dht_pk
+        } else {
+            return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                "ShareRelays packet from friend with unknown DHT key"
+            )))
+        };
+
+        let mut shared_relays = self.shared_relays.write();
+        let mut futures = Vec::with_capacity(packet.relays.len());
+        for relay in packet.relays.into_iter().take(MAX_SHARED_RELAYS) {
+            let friend_relays = shared_relays.values()
+                .filter(|shared_relay| shared_relay.friends.contains(&friend_pk))
+                .count();
+            if friend_relays >= MAX_FRIEND_SHARED_RELAYS {
+                break
+            }
+
+            let is_shared = shared_relays.contains_key(&relay.pk);
+            let is_added = is_shared || self.tcp_connections.has_relay(&relay.pk);
+            if !is_added && shared_relays.len() >= MAX_TOTAL_SHARED_RELAYS {
+                continue
+            }
+
+            // the relay is registered immediately so the connection to the
+            // friend can be linked to it before the relay future is polled
+            let relay_future = if is_added {
+                None
+            } else {
+                Some(self.tcp_connections.add_relay(relay.saddr, relay.pk))
+            };
+
+            match self.tcp_connections.add_relay_connection(&dht_pk, &relay.pk) {
+                Ok(future) => {
+                    // sending errors are not fatal since the relay will send
+                    // RouteRequest after connection
+                    futures.push(future.then(|_| Ok(())));
+                },
+                Err(e) => {
+                    debug!("Failed to link shared relay {:?}: {}", relay.pk, e);
+                    if relay_future.is_some() {
+                        let _ = self.tcp_connections.remove_relay(&relay.pk);
+                    }
+                    continue
+                },
+            }
+
+            if let Some(relay_future) = relay_future {
+                tokio::spawn(relay_future
+                    .map_err(|e| debug!("Shared relay failed: {}", e)));
+                shared_relays.insert(relay.pk, SharedRelay {
+                    friends: HashSet::new(),
+                    connected_time: clock_now(),
+                });
+            }
+            // relays that were added not because of sharing are not tracked
+            // since they shouldn't be removed with the friend
+            if let Some(shared_relay) = shared_relays.get_mut(&relay.pk) {
+                shared_relay.friends.insert(friend_pk);
+            }
+        }
+
+        Box::new(future::join_all(futures).map(|_: Vec<()>| ()))
+    }
+
+    /// Forget the friend in relays it shared and remove relays that are not
+    /// shared by other friends anymore.
+    fn release_shared_relays(&self, friend_pk: PublicKey) {
+        let mut shared_relays = self.shared_relays.write();
+        let tcp_connections = &self.tcp_connections;
+        shared_relays.retain(|relay_pk, shared_relay| {
+            shared_relay.friends.remove(&friend_pk);
+            if shared_relay.friends.is_empty() {
+                let _ = tcp_connections.remove_relay(relay_pk);
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    /// Remove shared relays that stay disconnected for too long so that
+    /// friends can share other relays.
+    fn remove_disconnected_shared_relays(&self) {
+        let connected_relays = self.tcp_connections.connected_relays();
+        let mut shared_relays = self.shared_relays.write();
+        let tcp_connections = &self.tcp_connections;
+        shared_relays.retain(|relay_pk, shared_relay| {
+            if connected_relays.iter().any(|node| node.pk == *relay_pk) {
+                shared_relay.connected_time = clock_now();
+                true
+            } else if clock_elapsed(shared_relay.connected_time) >= Duration::from_secs(SHARED_RELAY_TIMEOUT) {
+                debug!("Shared relay {:?} is timed out", relay_pk);
+                let _ = tcp_connections.remove_relay(relay_pk);
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    /// Add crypto connection to a friend and pass the address of the friend
+    /// found by DHT to `net_crypto` if it's known and the connection doesn't
+    /// have an address yet.
+    fn add_crypto_connection(&self, friend_pk: PublicKey, dht_pk: PublicKey) {
+        self.net_crypto.add_connection(friend_pk, dht_pk);
+        let has_addr = self.net_crypto.connection_info(friend_pk)
+            .map_or(false, |info| info.udp_addr.is_some());
+        if has_addr {
+            return
+        }
+        if let Some(&addr) = self.net_crypto.udp_addr_hints(dht_pk).first() {
+            self.net_crypto.set_friend_udp_addr(friend_pk, addr);
+        }
+    }
+
+    /// Serialize friend connection packet and send it to a friend via
+    /// `net_crypto`.
+    pub fn send_to_friend(&self, friend_pk: PublicKey, packet: &Packet) -> IoFuture<()> {
+        let mut buf = [0; MAX_CRYPTO_DATA_SIZE];
+        match packet.to_bytes((&mut buf, 0)) {
+            Ok((_, size)) => self.net_crypto.send_lossless(friend_pk, buf[..size].to_vec()),
+            Err(e) => Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                format!("Failed to serialize packet: {:?}", e)
+            ))),
+        }
+    }
+
+    /// Send `Alive` packet to a friend.
+    fn send_ping(&self, friend: &mut Friend) -> IoFuture<()> {
+        friend.ping_sent_time = Some(clock_now());
+        self.send_to_friend(friend.real_pk, &Packet::Alive(Alive))
+    }
+
+    /// Send `ShareRelays` packet with relays we are connected to to a friend.
+    fn send_share_relays(&self, friend: &mut Friend) -> IoFuture<()> {
+        friend.share_relays_time = Some(clock_now());
+        let mut relays = self.tcp_connections.connected_relays();
+        if relays.is_empty() {
+            return Box::new(future::ok(()))
+        }
+        relays.truncate(MAX_SHARED_RELAYS);
+        self.send_to_friend(friend.real_pk, &Packet::ShareRelays(ShareRelays::new(relays)))
+    }
+
+    /// The main loop that sends `Alive` and `ShareRelays` packets to online
+    /// friends, drops friends that stopped sending `Alive` packets and
+    /// restores crypto connections to offline friends with known DHT key.
+    fn main_loop(&self) -> IoFuture<()> {
+        let mut friends = self.friends.write();
+        let mut futures = Vec::new();
+
+        for friend in friends.values_mut() {
+            if friend.connected {
+                if friend.is_timed_out() {
+                    debug!("Friend {:?} is timed out", friend.real_pk);
+                    friend.connected = false;
+                    friend.ping_sent_time = None;
+                    friend.ping_received_time = None;
+                    friend.share_relays_time = None;
+                    futures.push(self.net_crypto.kill_connection(friend.real_pk));
+                    futures.push(self.send_connection_status(friend.real_pk, false));
+                    continue;
+                }
+
+                if friend.ping_should_be_sent() {
+                    futures.push(self.send_ping(friend));
+                }
+
+                if friend.share_relays_should_be_sent() {
+                    futures.push(self.send_share_relays(friend));
+                }
+            } else if let Some(dht_pk) = friend.dht_pk {
+                // net_crypto removes connections that are killed or timed out
+                self.add_crypto_connection(friend.real_pk, dht_pk);
+                // the connection could be established before the friend was
+                // added so its status change wasn't handled
+                let is_established = self.net_crypto.connection_info(friend.real_pk)
+                    .map_or(false, |info| info.is_established);
+                if is_established {
+                    futures.push(self.set_friend_online(friend));
+                }
+            }
+        }
+
+        self.remove_disconnected_shared_relays();
+
+        // sending errors are not fatal since the connection might be lost
+        // in the meantime
+        let futures = futures.into_iter()
+            .map(|future| future.then(|_| Ok(())));
+
+        Box::new(future::join_all(futures).map(|_: Vec<()>| ()))
+    }
+
+    /// Run the main loop periodically.
+    pub fn run(self) -> IoFuture<()> {
+        let interval = Duration::from_millis(MAIN_LOOP_INTERVAL);
+        let wakeups = Interval::new(Instant::now(), interval);
+        let future = wakeups
+            .map_err(|e| Error::new(ErrorKind::Other, format!("Friend connections timer error: {:?}", e)))
+            .for_each(move |_instant| {
+                trace!("Friend connections wake up");
+                self.main_loop()
+            });
+
+        Box::new(future)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::SocketAddr;
+
+    use tokio::runtime::Runtime;
+    use tokio_executor;
+    use tokio_timer::clock::*;
+
+    use toxcore::dht::packed_node::PackedNode;
+    use toxcore::dht::precomputed_cache::PrecomputedCache;
+    use toxcore::net_crypto::NetCryptoNewArgs;
+    use toxcore::tcp::connections::MAX_FRIEND_TCP_CONNECTIONS;
+    use toxcore::toxid::NoSpam;
+
+    fn create_friend_connections() -> (FriendConnections, mpsc::UnboundedReceiver<(PublicKey, bool)>) {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            precomputed_keys,
+        });
+        let (incoming_tx, _incoming_rx) = mpsc::unbounded();
+        let tcp_connections = Connections::new(real_pk, real_sk, incoming_tx);
+        let (connection_status_tx, connection_status_rx) = mpsc::unbounded();
+        let mut friend_connections = FriendConnections::new(net_crypto, tcp_connections);
+        friend_connections.set_connection_status_sink(connection_status_tx);
+        (friend_connections, connection_status_rx)
+    }
+
+    #[test]
+    fn add_remove_friend() {
+        let (friend_connections, _connection_status_rx) = create_friend_connections();
+        let friend_pk = gen_keypair().0;
+        let dht_pk = gen_keypair().0;
+
+        friend_connections.add_friend(friend_pk);
+        friend_connections.handle_dht_pk(friend_pk, dht_pk).wait().unwrap();
+        assert_eq!(friend_connections.friend_dht_pk(friend_pk), Some(dht_pk));
+
+        friend_connections.remove_friend(friend_pk).wait().unwrap();
+        assert_eq!(friend_connections.friend_dht_pk(friend_pk), None);
+        assert!(friend_connections.net_crypto.connection_info(friend_pk).is_none());
+        assert!(friend_connections.tcp_connections.connection_status(&dht_pk).is_none());
+        assert!(friend_connections.remove_friend(friend_pk).wait().is_err());
+    }
+
+    #[test]
+    fn add_friend_with_crypto_connection() {
+        let (friend_connections, _connection_status_rx) = create_friend_connections();
+        let friend_pk = gen_keypair().0;
+        let dht_pk = gen_keypair().0;
+
+        // e.g. the connection was created when the friend sent us a request
+        friend_connections.net_crypto.add_connection(friend_pk, dht_pk);
+
+        friend_connections.add_friend(friend_pk);
+        assert_eq!(friend_connections.friend_dht_pk(friend_pk), Some(dht_pk));
+        assert!(friend_connections.tcp_connections.connection_status(&dht_pk).is_some());
+    }
+
+    #[test]
+    fn handle_dht_pk() {
+        let (friend_connections, _connection_status_rx) = create_friend_connections();
+        let friend_pk = gen_keypair().0;
+        let dht_pk = gen_keypair().0;
+
+        friend_connections.add_friend(friend_pk);
+        friend_connections.handle_dht_pk(friend_pk, dht_pk).wait().unwrap();
+
+        let info = friend_connections.net_crypto.connection_info(friend_pk).unwrap();
+        assert_eq!(info.peer_dht_pk, dht_pk);
+        assert!(friend_connections.tcp_connections.connection_status(&dht_pk).is_some());
+    }
+
+    #[test]
+    fn handle_dht_pk_passes_udp_addr() {
+        let (friend_connections, _connection_status_rx) = create_friend_connections();
+        let friend_pk = gen_keypair().0;
+        let dht_pk = gen_keypair().0;
+        let addr = "127.0.0.1:33445".parse().unwrap();
+
+        friend_connections.net_crypto.set_udp_addr_hints(dht_pk, vec![addr]);
+
+        friend_connections.add_friend(friend_pk);
+        friend_connections.handle_dht_pk(friend_pk, dht_pk).wait().unwrap();
+
+        let info = friend_connections.net_crypto.connection_info(friend_pk).unwrap();
+        assert_eq!(info.udp_addr, Some(addr));
+    }
+
+    #[test]
+    fn handle_dht_pk_unknown_friend() {
+        let (friend_connections, _connection_status_rx) = create_friend_connections();
+        let friend_pk = gen_keypair().0;
+        let dht_pk = gen_keypair().0;
+
+        friend_connections.handle_dht_pk(friend_pk, dht_pk).wait().unwrap();
+
+        assert!(friend_connections.net_crypto.connection_info(friend_pk).is_none());
+        assert!(friend_connections.tcp_connections.connection_status(&dht_pk).is_none());
+    }
+
+    #[test]
+    fn handle_dht_pk_changed() {
+        let (friend_connections, connection_status_rx) = create_friend_connections();
+        let friend_pk = gen_keypair().0;
+        let old_dht_pk = gen_keypair().0;
+        let new_dht_pk = gen_keypair().0;
+
+        friend_connections.add_friend(friend_pk);
+        friend_connections.handle_dht_pk(friend_pk, old_dht_pk).wait().unwrap();
+        friend_connections.handle_connection_status(friend_pk, true).wait().unwrap();
+        friend_connections.handle_dht_pk(friend_pk, new_dht_pk).wait().unwrap();
+
+        let info = friend_connections.net_crypto.connection_info(friend_pk).unwrap();
+        assert_eq!(info.peer_dht_pk, new_dht_pk);
+        assert!(friend_connections.tcp_connections.connection_status(&old_dht_pk).is_none());
+        assert!(friend_connections.tcp_connections.connection_status(&new_dht_pk).is_some());
+        assert!(!friend_connections.is_friend_online(friend_pk));
+
+        drop(friend_connections);
+        let statuses = connection_status_rx.collect().wait().unwrap();
+        assert_eq!(statuses, vec![(friend_pk, true), (friend_pk, false)]);
+    }
+
+    #[test]
+    fn handle_connection_status() {
+        let (friend_connections, connection_status_rx) = create_friend_connections();
+        let friend_pk = gen_keypair().0;
+
+        friend_connections.add_friend(friend_pk);
+        friend_connections.handle_dht_pk(friend_pk, gen_keypair().0).wait().unwrap();
+
+        friend_connections.handle_connection_status(friend_pk, true).wait().unwrap();
+        assert!(friend_connections.is_friend_online(friend_pk));
+        {
+            let friends = friend_connections.friends.read();
+            let friend = &friends[&friend_pk];
+            assert!(friend.ping_sent_time.is_some());
+            assert!(friend.ping_received_time.is_some());
+            assert!(friend.share_relays_time.is_some());
+        }
+
+        // the same status shouldn't be reported twice
+        friend_connections.handle_connection_status(friend_pk, true).wait().unwrap();
+
+        friend_connections.handle_connection_status(friend_pk, false).wait().unwrap();
+        assert!(!friend_connections.is_friend_online(friend_pk));
+
+        // unknown friends are ignored
+        friend_connections.handle_connection_status(gen_keypair().0, true).wait().unwrap();
+
+        drop(friend_connections);
+        let statuses = connection_status_rx.collect().wait().unwrap();
+        assert_eq!(statuses, vec![(friend_pk, true), (friend_pk, false)]);
+    }
+
+    #[test]
+    fn handle_alive() {
+        let (friend_connections, _connection_status_rx) = create_friend_connections();
+        let friend_pk = gen_keypair().0;
+
+        friend_connections.add_friend(friend_pk);
+        friend_connections.handle_dht_pk(friend_pk, gen_keypair().0).wait().unwrap();
+        friend_connections.handle_connection_status(friend_pk, true).wait().unwrap();
+
+        let now = Instant::now() + Duration::from_secs(FRIEND_PING_INTERVAL);
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(now));
+
+        with_default(&clock, &mut enter, |_| {
+            friend_connections.handle_lossless(friend_pk, &[0x10]).wait().unwrap();
+        });
+
+        assert_eq!(friend_connections.friends.read()[&friend_pk].ping_received_time, Some(now));
+    }
+
+    #[test]
+    fn handle_lossless_invalid() {
+        let (friend_connections, _connection_status_rx) = create_friend_connections();
+        let friend_pk = gen_keypair().0;
+
+        friend_connections.add_friend(friend_pk);
+        assert!(friend_connections.handle_lossless(friend_pk, &[0x42, 1, 2, 3]).wait().is_err());
+    }
+
+    #[test]
+    fn handle_friend_requests() {
+        let (mut friend_connections, _connection_status_rx) = create_friend_connections();
+        let (friend_request_tx, friend_request_rx) = mpsc::unbounded();
+        friend_connections.set_friend_request_sink(friend_request_tx);
+        let friend_pk = gen_keypair().0;
+
+        let packet = FriendRequests::new(NoSpam([42; 4]), vec![1, 2, 3]);
+        let mut buf = [0; MAX_CRYPTO_DATA_SIZE];
+        let (_, size) = Packet::FriendRequests(packet.clone()).to_bytes((&mut buf, 0)).unwrap();
+
+        friend_connections.handle_lossless(friend_pk, &buf[..size]).wait().unwrap();
+
+        let (received, _friend_request_rx) = friend_request_rx.into_future().wait().unwrap();
+        assert_eq!(received, Some((friend_pk, packet)));
+    }
+
+    #[test]
+    fn handle_share_relays() {
+        let (friend_connections, _connection_status_rx) = create_friend_connections();
+        let friend_pk = gen_keypair().0;
+        let dht_pk = gen_keypair().0;
+        let relay_pk = gen_keypair().0;
+
+        friend_connections.add_friend(friend_pk);
+        friend_connections.handle_dht_pk(friend_pk, dht_pk).wait().unwrap();
+
+        let packet = Packet::ShareRelays(ShareRelays::new(vec![
+            PackedNode::new("127.0.0.1:12367".parse().unwrap(), &relay_pk),
+        ]));
+        let mut buf = [0; MAX_CRYPTO_DATA_SIZE];
+        let (_, size) = packet.to_bytes((&mut buf, 0)).unwrap();
+
+        let mut runtime = Runtime::new().unwrap();
+        let friend_connections_c = friend_connections.clone();
+        runtime.block_on(future::lazy(move ||
+            friend_connections_c.handle_lossless(friend_pk, &buf[..size])
+        )).unwrap();
+        runtime.shutdown_now().wait().unwrap();
+
+        let relays = friend_connections.tcp_connections.relay_connections(&dht_pk).unwrap();
+        assert_eq!(relays.len(), 1);
+        assert_eq!(relays[0].relay_pk(), relay_pk);
+    }
+
+    fn share_relays(friend_connections: &FriendConnections, friend_pk: PublicKey, relays: Vec<PackedNode>) {
+        let mut runtime = Runtime::new().unwrap();
+        let friend_connections_c = friend_connections.clone();
+        runtime.block_on(future::lazy(move ||
+            friend_connections_c.handle_share_relays(friend_pk, ShareRelays::new(relays))
+        )).unwrap();
+        runtime.shutdown_now().wait().unwrap();
+    }
+
+    fn random_relays(count: usize) -> Vec<PackedNode> {
+        (0 .. count)
+            .map(|i| PackedNode::new(SocketAddr::new("127.0.0.1".parse().unwrap(), 12400 + i as u16), &gen_keypair().0))
+            .collect()
+    }
+
+    #[test]
+    fn handle_share_relays_friend_limit() {
+        let (friend_connections, _connection_status_rx) = create_friend_connections();
+        let friend_pk = gen_keypair().0;
+        let dht_pk = gen_keypair().0;
+
+        friend_connections.add_friend(friend_pk);
+        friend_connections.handle_dht_pk(friend_pk, dht_pk).wait().unwrap();
+
+        share_relays(&friend_connections, friend_pk, random_relays(MAX_SHARED_RELAYS));
+        let relays = random_relays(MAX_SHARED_RELAYS);
+        share_relays(&friend_connections, friend_pk, relays.clone());
+
+        assert_eq!(friend_connections.shared_relays.read().len(), MAX_FRIEND_SHARED_RELAYS);
+        assert_eq!(friend_connections.tcp_connections.relay_connections(&dht_pk).unwrap().len(), MAX_FRIEND_SHARED_RELAYS);
+        assert!(!friend_connections.tcp_connections.has_relay(&relays[0].pk));
+    }
+
+    #[test]
+    fn handle_share_relays_total_limit() {
+        let (friend_connections, _connection_status_rx) = create_friend_connections();
+        let friend_pk = gen_keypair().0;
+        let dht_pk = gen_keypair().0;
+
+        friend_connections.add_friend(friend_pk);
+        friend_connections.handle_dht_pk(friend_pk, dht_pk).wait().unwrap();
+
+        {
+            let mut shared_relays = friend_connections.shared_relays.write();
+            for _ in 0 .. MAX_TOTAL_SHARED_RELAYS {
+                let mut friends = HashSet::new();
+                friends.insert(gen_keypair().0);
+                shared_relays.insert(gen_keypair().0, SharedRelay {
+                    friends,
+                    connected_time: clock_now(),
+                });
+            }
+        }
+
+        let relays = random_relays(1);
+        share_relays(&friend_connections, friend_pk, relays.clone());
+
+        assert!(!friend_connections.tcp_connections.has_relay(&relays[0].pk));
+        assert!(friend_connections.tcp_connections.relay_connections(&dht_pk).unwrap().is_empty());
+    }
+
+    #[test]
+    fn handle_share_relays_not_linked() {
+        let (friend_connections, _connection_status_rx) = create_friend_connections();
+        let friend_pk = gen_keypair().0;
+        let dht_pk = gen_keypair().0;
+
+        friend_connections.add_friend(friend_pk);
+        friend_connections.handle_dht_pk(friend_pk, dht_pk).wait().unwrap();
+
+        // the connection to the friend already has maximum number of relays
+        let mut runtime = Runtime::new().unwrap();
+        for relay in random_relays(MAX_FRIEND_TCP_CONNECTIONS) {
+            let _relay_future = friend_connections.tcp_connections.add_relay(relay.saddr, relay.pk);
+            runtime.block_on(friend_connections.tcp_connections.add_relay_connection(&dht_pk, &relay.pk).unwrap()).unwrap();
+        }
+        runtime.shutdown_now().wait().unwrap();
+
+        let relays = random_relays(1);
+        share_relays(&friend_connections, friend_pk, relays.clone());
+
+        assert!(friend_connections.shared_relays.read().is_empty());
+        assert!(!friend_connections.tcp_connections.has_relay(&relays[0].pk));
+    }
+
+    #[test]
+    fn remove_friend_removes_shared_relays() {
+        let (friend_connections, _connection_status_rx) = create_friend_connections();
+        let friend_pk_1 = gen_keypair().0;
+        let friend_pk_2 = gen_keypair().0;
+
+        friend_connections.add_friend(friend_pk_1);
+        friend_connections.add_friend(friend_pk_2);
+        friend_connections.handle_dht_pk(friend_pk_1, gen_keypair().0).wait().unwrap();
+        friend_connections.handle_dht_pk(friend_pk_2, gen_keypair().0).wait().unwrap();
+
+        let relays = random_relays(2);
+        share_relays(&friend_connections, friend_pk_1, relays.clone());
+        share_relays(&friend_connections, friend_pk_2, relays[1 ..].to_vec());
+
+        friend_connections.remove_friend(friend_pk_1).wait().unwrap();
+
+        // the relay shared by the other friend is kept
+        assert!(!friend_connections.tcp_connections.has_relay(&relays[0].pk));
+        assert!(friend_connections.tcp_connections.has_relay(&relays[1].pk));
+        assert_eq!(friend_connections.shared_relays.read().len(), 1);
+    }
+
+    #[test]
+    fn main_loop_removes_disconnected_shared_relays() {
+        let (friend_connections, _connection_status_rx) = create_friend_connections();
+        let friend_pk = gen_keypair().0;
+
+        friend_connections.add_friend(friend_pk);
+        friend_connections.handle_dht_pk(friend_pk, gen_keypair().0).wait().unwrap();
+
+        let relays = random_relays(1);
+        share_relays(&friend_connections, friend_pk, relays.clone());
+
+        friend_connections.main_loop().wait().unwrap();
+        assert!(friend_connections.tcp_connections.has_relay(&relays[0].pk));
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(
+            Instant::now() + Duration::from_secs(SHARED_RELAY_TIMEOUT + 1)
+        ));
+
+        with_default(&clock, &mut enter, |_| {
+            friend_connections.main_loop().wait().unwrap();
+        });
+
+        assert!(!friend_connections.tcp_connections.has_relay(&relays[0].pk));
+        assert!(friend_connections.shared_relays.read().is_empty());
+    }
+
+    #[test]
+    fn handle_share_relays_unknown_dht_pk() {
+        let (friend_connections, _connection_status_rx) = create_friend_connections();
+        let friend_pk = gen_keypair().0;
+
+        friend_connections.add_friend(friend_pk);
+
+        let packet = ShareRelays::new(vec![
+            PackedNode::new("127.0.0.1:12367".parse().unwrap(), &gen_keypair().0),
+        ]);
+        assert!(friend_connections.handle_share_relays(friend_pk, packet).wait().is_err());
+    }
+
+    #[test]
+    fn main_loop_sends_alive() {
+        let (friend_connections, _connection_status_rx) = create_friend_connections();
+        let friend_pk = gen_keypair().0;
+
+        friend_connections.add_friend(friend_pk);
+        friend_connections.handle_dht_pk(friend_pk, gen_keypair().0).wait().unwrap();
+        friend_connections.handle_connection_status(friend_pk, true).wait().unwrap();
+
+        let ping_sent_time = friend_connections.friends.read()[&friend_pk].ping_sent_time;
+        let now = Instant::now() + Duration::from_secs(FRIEND_PING_INTERVAL + 1);
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(now));
+
+        with_default(&clock, &mut enter, |_| {
+            friend_connections.main_loop().wait().unwrap();
+        });
+
+        let friends = friend_connections.friends.read();
+        let friend = &friends[&friend_pk];
+        assert_ne!(friend.ping_sent_time, ping_sent_time);
+        assert_eq!(friend.ping_sent_time, Some(now));
+        assert!(friend.connected);
+    }
+
+    #[test]
+    fn main_loop_timeout() {
+        let (friend_connections, connection_status_rx) = create_friend_connections();
+        let friend_pk = gen_keypair().0;
+
+        friend_connections.add_friend(friend_pk);
+        friend_connections.handle_dht_pk(friend_pk, gen_keypair().0).wait().unwrap();
+        friend_connections.handle_connection_status(friend_pk, true).wait().unwrap();
+
+        let now = Instant::now() + Duration::from_secs(FRIEND_CONNECTION_TIMEOUT + 1);
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(now));
+
+        with_default(&clock, &mut enter, |_| {
+            friend_connections.main_loop().wait().unwrap();
+        });
+
+        assert!(!friend_connections.is_friend_online(friend_pk));
+        assert!(friend_connections.net_crypto.connection_info(friend_pk).is_none());
+
+        // the next iteration should try to connect again
+        friend_connections.main_loop().wait().unwrap();
+        assert!(friend_connections.net_crypto.connection_info(friend_pk).is_some());
+
+        drop(friend_connections);
+        let statuses = connection_status_rx.collect().wait().unwrap();
+        assert_eq!(statuses, vec![(friend_pk, true), (friend_pk, false)]);
+    }
+}
@@ -18,7 +18,8 @@ variable  | 3 PackedNodes
 */
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct ShareRelays {
-    relays: Vec<PackedNode>,
+    /// Relays that are shared
+    pub relays: Vec<PackedNode>,
 }
 
 impl FromBytes for ShareRelays {
@@ -0,0 +1,520 @@
+/*! Friend requests module
+
+It allows to send friend requests to a `ToxId` and to receive friend requests
+from other nodes. Outgoing requests are sent via `net_crypto` when we are
+connected to the friend and via onion otherwise. Onion client searches the
+friend by its long term `PublicKey` and sends the request inside
+`OnionDataRequest` to the onion node the friend is announced at. Requests are
+resent periodically until the friend accepts them. Incoming requests can
+arrive both via `net_crypto` and inside `OnionDataResponse` packets. They are
+checked against our current `NoSpam`, duplicates and requests from blocked keys
+are dropped.
+
+*/
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::{future, Future, Stream};
+use futures::sync::mpsc;
+use parking_lot::RwLock;
+use tokio::timer::Interval;
+
+use toxcore::crypto_core::*;
+use toxcore::friend_connection::FriendConnections;
+use toxcore::friend_connection::packet::{self, MAX_ONION_CLIENT_DATA_SIZE};
+use toxcore::io_tokio::*;
+use toxcore::onion::client::OnionClient;
+use toxcore::time::*;
+use toxcore::toxid::{NoSpam, ToxId};
+
+/// Interval in seconds for resending friend requests that are not accepted
+/// yet.
+pub const FRIEND_REQUEST_RETRY_INTERVAL: u64 = 5;
+
+/// Maximum number of senders of received friend requests that are stored to
+/// drop duplicates.
+pub const MAX_RECEIVED_STORED: usize = 32;
+
+/// Interval in milliseconds for running the main loop.
+const MAIN_LOOP_INTERVAL: u64 = 1000;
+
+/// Shorthand for the transmit half of the message channel for sending
+/// received friend requests. The key is a long term public key of the sender,
+/// the second item is a greeting message.
+type FriendRequestTx = mpsc::UnboundedSender<(PublicKey, Vec<u8>)>;
+
+/// Error that can happen when sending a friend request
+#[derive(Debug, Eq, PartialEq, Fail)]
+pub enum SendFriendRequestError {
+    /// Error indicates that checksum of `ToxId` is invalid
+    #[fail(display = "Invalid checksum of ToxId")]
+    InvalidChecksumError,
+    /// Error indicates that `ToxId` contains our own `PublicKey`
+    #[fail(display = "Can't send friend request to ourselves")]
+    OwnKeyError,
+    /// Error indicates that greeting message is empty
+    #[fail(display = "Friend request message is empty")]
+    NoMessageError,
+    /// Error indicates that greeting message is too long
+    #[fail(display = "Friend request message is too long: {} bytes", len)]
+    TooLongError {
+        /// Length of the message
+        len: usize,
+    },
+}
+
+/// Friend request that is not accepted yet.
+#[derive(Clone, Debug)]
+struct OutgoingRequest {
+    /// `NoSpam` of the friend's `ToxId`.
+    nospam: NoSpam,
+    /// Greeting message.
+    message: Vec<u8>,
+    /// Time when the request was sent the last time.
+    sent_time: Option<Instant>,
+}
+
+impl OutgoingRequest {
+    /// Check if it's time to send the request again.
+    fn should_be_sent(&self) -> bool {
+        self.sent_time.map_or(true, |time|
+            clock_elapsed(time) >= Duration::from_secs(FRIEND_REQUEST_RETRY_INTERVAL)
+        )
+    }
+}
+
+/// Struct that sends friend requests until they are accepted and filters
+/// received friend requests.
+#[derive(Clone)]
+pub struct FriendRequests {
+    /// Our long term `PublicKey`.
+    real_pk: PublicKey,
+    /// Our current `NoSpam`. Received requests with another `NoSpam` are
+    /// dropped.
+    nospam: Arc<RwLock<NoSpam>>,
+    /// Friend connections that are used to send requests via `net_crypto`
+    /// to connected friends.
+    friend_connections: FriendConnections,
+    /// Onion client that is used to send requests to friends that are not
+    /// connected.
+    onion_client: OnionClient,
+    /// Requests that are not accepted yet by receiver's long term
+    /// `PublicKey`.
+    outgoing: Arc<RwLock<HashMap<PublicKey, OutgoingRequest>>>,
+    /// Senders of the last received requests.
+    received: Arc<RwLock<VecDeque<PublicKey>>>,
+    /// Keys requests from which are dropped.
+    blocked: Arc<RwLock<HashSet<PublicKey>>>,
+    /// Sink to send received friend requests.
+    friend_request_tx: Option<FriendRequestTx>,
+}
+
+impl FriendRequests {
+    /// Create new `FriendRequests` object.
+    pub fn new(
+        real_pk: PublicKey,
+        nospam: NoSpam,
+        friend_connections: FriendConnections,
+        onion_client: OnionClient
+    ) -> FriendRequests {
+        FriendRequests {
+            real_pk,
+            nospam: Arc::new(RwLock::new(nospam)),
+            friend_connections,
+            onion_client,
+            outgoing: Arc::new(RwLock::new(HashMap::new())),
+            received: Arc::new(RwLock::new(VecDeque::with_capacity(MAX_RECEIVED_STORED))),
+            blocked: Arc::new(RwLock::new(HashSet::new())),
+            friend_request_tx: None,
+        }
+    }
+
+    /// Set sink to send received friend requests.
+    pub fn set_friend_request_sink(&mut self, friend_request_tx: FriendRequestTx) {
+        self.friend_request_tx = Some(friend_request_tx);
+    }
+
+    /// Get our current `NoSpam`.
+    pub fn nospam(&self) -> NoSpam {
+        *self.nospam.read()
+    }
+
+    /// Set our `NoSpam`. Only requests with this `NoSpam` will be accepted
+    /// after this call.
+    pub fn set_nospam(&self, nospam: NoSpam) {
+        *self.nospam.write() = nospam;
+    }
+
+    /// Drop requests from the key.
+    pub fn block(&self, pk: PublicKey) {
+        self.blocked.write().insert(pk);
+    }
+
+    /// Stop dropping requests from the key.
+    pub fn unblock(&self, pk: PublicKey) {
+        self.blocked.write().remove(&pk);
+    }
+
+    /// Forget that we received a request from the key so that its next
+    /// request won't be considered as duplicate.
+    pub fn remove_received(&self, pk: PublicKey) {
+        self.received.write().retain(|received_pk| *received_pk != pk);
+    }
+
+    /// Send a friend request to the `ToxId`. The request is resent
+    /// periodically until it's accepted or canceled. If there is already a
+    /// request to the same key it's replaced.
+    pub fn send_friend_request(&self, tox_id: ToxId, message: Vec<u8>) -> Result<(), SendFriendRequestError> {
+        if !tox_id.is_valid() {
+            return Err(SendFriendRequestError::InvalidChecksumError)
+        }
+        if tox_id.pk == self.real_pk {
+            return Err(SendFriendRequestError::OwnKeyError)
+        }
+        if message.is_empty() {
+            return Err(SendFriendRequestError::NoMessageError)
+        }
+        if message.len() > MAX_ONION_CLIENT_DATA_SIZE {
+            return Err(SendFriendRequestError::TooLongError { len: message.len() })
+        }
+
+        self.outgoing.write().insert(tox_id.pk, OutgoingRequest {
+            nospam: tox_id.nospam(),
+            message,
+            sent_time: None,
+        });
+        self.friend_connections.add_friend(tox_id.pk);
+        self.onion_client.add_friend(tox_id.pk);
+
+        Ok(())
+    }
+
+    /// Stop sending the friend request to the key.
+    pub fn cancel_friend_request(&self, pk: PublicKey) -> bool {
+        self.outgoing.write().remove(&pk).is_some()
+    }
+
+    /// Check if there is a friend request to the key that is not accepted
+    /// yet.
+    pub fn is_pending(&self, pk: PublicKey) -> bool {
+        self.outgoing.read().contains_key(&pk)
+    }
+
+    /// Handle acceptance of our friend request. It should be called when we
+    /// receive the first packet from the friend confirming that it added us
+    /// as well.
+    pub fn handle_accepted(&self, pk: PublicKey) {
+        self.outgoing.write().remove(&pk);
+    }
+
+    /// Handle received friend request. It's dropped if it was sent to another
+    /// `NoSpam`, we already received the request from the same key or the key
+    /// is blocked. Requests received both via `net_crypto` and via onion
+    /// should be passed here.
+    pub fn handle_friend_request(&self, pk: PublicKey, packet: packet::FriendRequests) -> IoFuture<()> {
+        if packet.message.len() > MAX_ONION_CLIENT_DATA_SIZE {
+            return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                format!("Friend request message is too long: {} bytes", packet.message.len())
+            )))
+        }
+
+        if pk == self.real_pk || self.blocked.read().contains(&pk) {
+            debug!("Dropping friend request from blocked key {:?}", pk);
+            return Box::new(future::ok(()))
+        }
+
+        if packet.nospam != self.nospam() {
+            debug!("Dropping friend request from {:?} with invalid nospam", pk);
+            return Box::new(future::ok(()))
+        }
+
+        {
+            let mut received = self.received.write();
+            if received.contains(&pk) {
+                debug!("Dropping duplicate friend request from {:?}", pk);
+                return Box::new(future::ok(()))
+            }
+            if received.len() >= MAX_RECEIVED_STORED {
+                received.pop_front();
+            }
+            received.push_back(pk);
+        }
+
+        if let Some(ref friend_request_tx) = self.friend_request_tx {
+            send_to(friend_request_tx, (pk, packet.message))
+        } else {
+            Box::new(future::ok(()))
+        }
+    }
+
+    /// Send the friend request via `net_crypto` if the friend is connected
+    /// and via onion otherwise.
+    fn send_request(&self, pk: PublicKey, request: &OutgoingRequest) -> IoFuture<()> {
+        if self.friend_connections.is_friend_online(pk) {
+            let packet = packet::FriendRequests::new(request.nospam, request.message.clone());
+            self.friend_connections.send_to_friend(pk, &packet::Packet::FriendRequests(packet))
+        } else {
+            self.onion_client.send_friend_request(pk, request.nospam, request.message.clone())
+        }
+    }
+
+    /// The main loop that sends friend requests that are not accepted yet.
+    fn main_loop(&self) -> IoFuture<()> {
+        let mut outgoing = self.outgoing.write();
+        let mut futures = Vec::new();
+
+        for (&pk, request) in outgoing.iter_mut() {
+            if request.should_be_sent() {
+                request.sent_time = Some(clock_now());
+                // sending errors are not fatal since the request will be
+                // resent later
+                futures.push(self.send_request(pk, request).then(|_| Ok(())));
+            }
+        }
+
+        Box::new(future::join_all(futures).map(|_: Vec<()>| ()))
+    }
+
+    /// Run the main loop periodically.
+    pub fn run(self) -> IoFuture<()> {
+        let interval = Duration::from_millis(MAIN_LOOP_INTERVAL);
+        let wakeups = Interval::new(Instant::now(), interval);
+        let future = wakeups
+            .map_err(|e| Error::new(ErrorKind::Other, format!("Friend requests timer error: {:?}", e)))
+            .for_each(move |_instant| {
+                trace!("Friend requests wake up");
+                self.main_loop()
+            });
+
+        Box::new(future)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio_executor;
+    use tokio_timer::clock::*;
+
+    use toxcore::binary_io::*;
+    use toxcore::dht::precomputed_cache::PrecomputedCache;
+    use toxcore::dht::server::Server as DhtServer;
+    use toxcore::net_crypto::{NetCrypto, NetCryptoNewArgs};
+    use toxcore::onion::client::OnionClient;
+    use toxcore::tcp::connections::Connections;
+    use toxcore::toxid::TOXIDBYTES;
+
+    fn create_friend_requests() -> FriendRequests {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx: udp_tx.clone(),
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            precomputed_keys,
+        });
+        let onion_client = OnionClient::new(DhtServer::new(udp_tx, dht_pk, dht_sk), real_pk, real_sk.clone());
+        let (incoming_tx, _incoming_rx) = mpsc::unbounded();
+        let tcp_connections = Connections::new(real_pk, real_sk, incoming_tx);
+        let friend_connections = FriendConnections::new(net_crypto, tcp_connections);
+        FriendRequests::new(real_pk, NoSpam::new(), friend_connections, onion_client)
+    }
+
+    #[test]
+    fn send_friend_request_invalid() {
+        let friend_requests = create_friend_requests();
+        let tox_id = ToxId::new(gen_keypair().0);
+
+        let mut buf = [0; TOXIDBYTES];
+        tox_id.to_bytes((&mut buf, 0)).unwrap();
+        buf[TOXIDBYTES - 1] ^= 0xff;
+        let (_, invalid_tox_id) = ToxId::from_bytes(&buf).unwrap();
+        assert_eq!(
+            friend_requests.send_friend_request(invalid_tox_id, vec![42]),
+            Err(SendFriendRequestError::InvalidChecksumError)
+        );
+
+        assert_eq!(
+            friend_requests.send_friend_request(ToxId::new(friend_requests.real_pk), vec![42]),
+            Err(SendFriendRequestError::OwnKeyError)
+        );
+        assert_eq!(
+            friend_requests.send_friend_request(tox_id, Vec::new()),
+            Err(SendFriendRequestError::NoMessageError)
+        );
+        assert_eq!(
+            friend_requests.send_friend_request(tox_id, vec![42; MAX_ONION_CLIENT_DATA_SIZE + 1]),
+            Err(SendFriendRequestError::TooLongError { len: MAX_ONION_CLIENT_DATA_SIZE + 1 })
+        );
+
+        assert!(!friend_requests.is_pending(tox_id.pk));
+    }
+
+    #[test]
+    fn send_friend_request_offline_via_onion() {
+        let friend_requests = create_friend_requests();
+        let tox_id = ToxId::new(gen_keypair().0);
+
+        friend_requests.send_friend_request(tox_id, vec![42; 123]).unwrap();
+
+        // the friend is added to onion client but the request can't be sent
+        // until the friend is found
+        let request = friend_requests.outgoing.read()[&tox_id.pk].clone();
+        let error = friend_requests.send_request(tox_id.pk, &request).wait().unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Other);
+        assert_eq!(
+            error.to_string(),
+            format!("Onion node where friend {:?} is announced is not found yet", tox_id.pk)
+        );
+    }
+
+    #[test]
+    fn main_loop_resends_friend_request() {
+        let friend_requests = create_friend_requests();
+        let tox_id = ToxId::new(gen_keypair().0);
+
+        friend_requests.send_friend_request(tox_id, vec![42; 123]).unwrap();
+        assert!(friend_requests.is_pending(tox_id.pk));
+
+        // sending errors don't stop the main loop
+        friend_requests.main_loop().wait().unwrap();
+        let sent_time = friend_requests.outgoing.read()[&tox_id.pk].sent_time;
+        assert!(sent_time.is_some());
+
+        // the request shouldn't be resent before the interval
+        friend_requests.main_loop().wait().unwrap();
+        assert_eq!(friend_requests.outgoing.read()[&tox_id.pk].sent_time, sent_time);
+
+        let now = Instant::now() + Duration::from_secs(FRIEND_REQUEST_RETRY_INTERVAL + 1);
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(now));
+
+        with_default(&clock, &mut enter, |_| {
+            friend_requests.main_loop().wait().unwrap();
+        });
+        assert_eq!(friend_requests.outgoing.read()[&tox_id.pk].sent_time, Some(now));
+
+        friend_requests.handle_accepted(tox_id.pk);
+        assert!(!friend_requests.is_pending(tox_id.pk));
+    }
+
+    #[test]
+    fn cancel_friend_request() {
+        let friend_requests = create_friend_requests();
+        let tox_id = ToxId::new(gen_keypair().0);
+
+        friend_requests.send_friend_request(tox_id, vec![42]).unwrap();
+        assert!(friend_requests.cancel_friend_request(tox_id.pk));
+        assert!(!friend_requests.is_pending(tox_id.pk));
+        assert!(!friend_requests.cancel_friend_request(tox_id.pk));
+    }
+
+    #[test]
+    fn handle_friend_request() {
+        let mut friend_requests = create_friend_requests();
+        let (friend_request_tx, friend_request_rx) = mpsc::unbounded();
+        friend_requests.set_friend_request_sink(friend_request_tx);
+        let nospam = friend_requests.nospam();
+        let pk = gen_keypair().0;
+
+        let packet = packet::FriendRequests::new(nospam, vec![42; 123]);
+        friend_requests.handle_friend_request(pk, packet.clone()).wait().unwrap();
+        // duplicate is dropped
+        friend_requests.handle_friend_request(pk, packet.clone()).wait().unwrap();
+        // but it's accepted again after removal from received list
+        friend_requests.remove_received(pk);
+        friend_requests.handle_friend_request(pk, packet).wait().unwrap();
+
+        drop(friend_requests);
+        let requests = friend_request_rx.collect().wait().unwrap();
+        assert_eq!(requests, vec![(pk, vec![42; 123]), (pk, vec![42; 123])]);
+    }
+
+    #[test]
+    fn handle_friend_request_invalid_nospam() {
+        let mut friend_requests = create_friend_requests();
+        let (friend_request_tx, friend_request_rx) = mpsc::unbounded();
+        friend_requests.set_friend_request_sink(friend_request_tx);
+        let old_nospam = friend_requests.nospam();
+        let new_nospam = NoSpam([42; 4]);
+        friend_requests.set_nospam(new_nospam);
+        let pk = gen_keypair().0;
+
+        let packet = packet::FriendRequests::new(old_nospam, vec![42; 123]);
+        friend_requests.handle_friend_request(pk, packet).wait().unwrap();
+
+        let packet = packet::FriendRequests::new(new_nospam, vec![43; 123]);
+        friend_requests.handle_friend_request(pk, packet).wait().unwrap();
+
+        drop(friend_requests);
+        let requests = friend_request_rx.collect().wait().unwrap();
+        assert_eq!(requests, vec![(pk, vec![43; 123])]);
+    }
+
+    #[test]
+    fn handle_friend_request_blocked() {
+        let mut friend_requests = create_friend_requests();
+        let (friend_request_tx, friend_request_rx) = mpsc::unbounded();
+        friend_requests.set_friend_request_sink(friend_request_tx);
+        let nospam = friend_requests.nospam();
+        let pk = gen_keypair().0;
+
+        friend_requests.block(pk);
+        let packet = packet::FriendRequests::new(nospam, vec![42; 123]);
+        friend_requests.handle_friend_request(pk, packet).wait().unwrap();
+
+        friend_requests.unblock(pk);
+        let packet = packet::FriendRequests::new(nospam, vec![43; 123]);
+        friend_requests.handle_friend_request(pk, packet).wait().unwrap();
+
+        drop(friend_requests);
+        let requests = friend_request_rx.collect().wait().unwrap();
+        assert_eq!(requests, vec![(pk, vec![43; 123])]);
+    }
+
+    #[test]
+    fn handle_friend_request_too_long() {
+        let friend_requests = create_friend_requests();
+        let nospam = friend_requests.nospam();
+
+        let packet = packet::FriendRequests::new(nospam, vec![42; MAX_ONION_CLIENT_DATA_SIZE + 1]);
+        assert!(friend_requests.handle_friend_request(gen_keypair().0, packet).wait().is_err());
+    }
+
+    #[test]
+    fn handle_friend_request_overflow() {
+        let mut friend_requests = create_friend_requests();
+        let (friend_request_tx, friend_request_rx) = mpsc::unbounded();
+        friend_requests.set_friend_request_sink(friend_request_tx);
+        let nospam = friend_requests.nospam();
+
+        let first_pk = gen_keypair().0;
+        let packet = packet::FriendRequests::new(nospam, vec![42]);
+        friend_requests.handle_friend_request(first_pk, packet.clone()).wait().unwrap();
+        for _ in 0 .. MAX_RECEIVED_STORED {
+            friend_requests.handle_friend_request(gen_keypair().0, packet.clone()).wait().unwrap();
+        }
+        // the first key is forgotten so the request isn't considered as
+        // duplicate
+        friend_requests.handle_friend_request(first_pk, packet).wait().unwrap();
+
+        drop(friend_requests);
+        let requests = friend_request_rx.collect().wait().unwrap();
+        assert_eq!(requests.len(), MAX_RECEIVED_STORED + 2);
+    }
+}
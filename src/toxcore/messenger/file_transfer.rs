@@ -0,0 +1,1087 @@
+/*! File transfers between friends
+
+A file is offered with `FileSendRequest` packet. The receiver accepts it with
+`FileControl` packet, optionally sending `Seek` control before to resume a
+transfer that was interrupted. Then the file is sent in `FileData` chunks.
+Both sides can pause, resume or cancel the transfer at any time.
+
+File numbers are unique per friend and per direction, so a file we send and a
+file we receive can have the same number.
+
+Transfers are interrupted when the friend goes offline. To resume such a
+transfer the sender offers the file again with the same unique id and the
+receiver accepts it starting from the position it already has.
+
+File data is sent only while the sending buffer of the crypto connection to
+the friend is short enough, so chat messages and other packets sent over the
+same connection don't get stuck behind file chunks.
+
+*/
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::sync::Arc;
+use std::{cmp, u64};
+
+use futures::{future, Future};
+use parking_lot::Mutex;
+
+use toxcore::crypto_core::*;
+use toxcore::io_tokio::*;
+use toxcore::messenger::{serialize_packet, Event, EventTx};
+use toxcore::messenger::packet::*;
+use toxcore::net_crypto::{LosslessPacketId, NetCrypto};
+
+/// File size that means that the size of a file is unknown. Such transfers
+/// end with a chunk shorter than `MAX_FILE_DATA_SIZE` bytes.
+pub const UNKNOWN_FILE_SIZE: u64 = u64::MAX;
+
+/// Maximum number of files that can be sent to a friend at the same time.
+pub const MAX_CONCURRENT_FILES: usize = 256;
+
+/// File data is sent only when the sending buffer of the crypto connection
+/// has less packets than this value. The rest of the buffer is left for
+/// other packets.
+const MAX_FILE_QUEUE_LEN: u32 = 64;
+
+/// Maximum number of file chunks sent to a friend during one iteration of
+/// the main loop.
+const MAX_FILE_CHUNKS_PER_ITERATION: usize = 32;
+
+/// Source of a file we send.
+pub trait FileSource: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> FileSource for T {}
+
+/// Sink for a file we receive.
+pub trait FileSink: Write + Send {}
+
+impl<T: Write + Send> FileSink for T {}
+
+/// Event that happened with a file transfer.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FileEvent {
+    /// Friend accepted the file we offered.
+    Accepted {
+        /// Position in the file from which data will be sent
+        position: u64,
+    },
+    /// Friend paused the transfer.
+    Paused,
+    /// Friend resumed the transfer it paused before.
+    Resumed,
+    /// Friend cancelled the transfer.
+    Killed,
+    /// Part of the file is transferred.
+    Progress {
+        /// Number of bytes transferred counting from the start of the file
+        position: u64,
+    },
+    /// The file is transferred completely. For files we send it means that
+    /// the friend confirmed receiving the last chunk.
+    Finished,
+    /// The transfer is interrupted because the friend went offline or due to
+    /// I/O error. It can be resumed from the position with a new transfer
+    /// that has the same unique file id.
+    Broken {
+        /// Number of bytes transferred counting from the start of the file
+        position: u64,
+    },
+}
+
+/// Progress and pause state of a file transfer.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct FileInfo {
+    /// Size of the file or `UNKNOWN_FILE_SIZE`.
+    size: u64,
+    /// Number of bytes transferred counting from the start of the file.
+    position: u64,
+    /// Whether we paused the transfer.
+    paused_by_us: bool,
+    /// Whether the friend paused the transfer.
+    paused_by_friend: bool,
+}
+
+impl FileInfo {
+    /// Create new `FileInfo` for a transfer starting from the beginning.
+    fn new(size: u64) -> FileInfo {
+        FileInfo {
+            size,
+            position: 0,
+            paused_by_us: false,
+            paused_by_friend: false,
+        }
+    }
+
+    /// Check if the transfer is not paused by any side.
+    fn is_running(&self) -> bool {
+        !self.paused_by_us && !self.paused_by_friend
+    }
+}
+
+/// State of a file we send.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum SendingState {
+    /// The friend hasn't accepted the file yet.
+    NotAccepted,
+    /// File data is being sent.
+    Transferring,
+    /// All data is sent. The id of the lossless packet with the last chunk
+    /// is stored here when it's known so we can find out when the friend
+    /// receives it.
+    Finishing(Option<LosslessPacketId>),
+}
+
+/// File we send to a friend.
+struct OutgoingFile {
+    /// Progress and pause state of the transfer.
+    info: FileInfo,
+    /// State of the transfer.
+    state: SendingState,
+    /// Source of the file data.
+    source: Box<FileSource>,
+}
+
+impl OutgoingFile {
+    /// Check if the next chunk of the file can be sent.
+    fn can_send(&self) -> bool {
+        self.state == SendingState::Transferring && self.info.is_running()
+    }
+}
+
+/// File we receive from a friend.
+struct IncomingFile {
+    /// Progress and pause state of the transfer.
+    info: FileInfo,
+    /// Sink for the file data. It's set when we accept the file.
+    sink: Option<Box<FileSink>>,
+}
+
+/// File transfers with an online friend.
+#[derive(Default)]
+struct FriendFiles {
+    /// Files we send by their numbers.
+    sending: HashMap<u8, OutgoingFile>,
+    /// Files we receive by their numbers.
+    receiving: HashMap<u8, IncomingFile>,
+}
+
+impl FriendFiles {
+    /// Get progress and pause state of a file and whether it was accepted.
+    fn file_mut(&mut self, direction: TransferDirection, file_id: u8) -> Option<(&mut FileInfo, bool)> {
+        match direction {
+            TransferDirection::Send => self.sending.get_mut(&file_id).map(|file|
+                (&mut file.info, file.state != SendingState::NotAccepted)
+            ),
+            TransferDirection::Receive => self.receiving.get_mut(&file_id).map(|file|
+                (&mut file.info, file.sink.is_some())
+            ),
+        }
+    }
+
+    /// Remove a file returning its progress and pause state.
+    fn remove(&mut self, direction: TransferDirection, file_id: u8) -> Option<FileInfo> {
+        match direction {
+            TransferDirection::Send => self.sending.remove(&file_id).map(|file| file.info),
+            TransferDirection::Receive => self.receiving.remove(&file_id).map(|file| file.info),
+        }
+    }
+}
+
+/** Read the next chunk of a file starting from the position. Returns the
+chunk and whether it's the last one.
+
+The chunk is the last one when it's shorter than `MAX_FILE_DATA_SIZE` bytes
+or when it reaches the end of a file with known size.
+*/
+fn read_chunk(source: &mut FileSource, size: u64, position: u64) -> Result<(Vec<u8>, bool), Error> {
+    let len = cmp::min(MAX_FILE_DATA_SIZE as u64, size.saturating_sub(position));
+    let mut data = Vec::with_capacity(len as usize);
+    source.take(len).read_to_end(&mut data)?;
+    let last = data.len() < MAX_FILE_DATA_SIZE || position + data.len() as u64 >= size;
+    Ok((data, last))
+}
+
+/// Struct that sends and receives files for online friends.
+#[derive(Clone)]
+pub struct FileTransfers {
+    /// `NetCrypto` module that is used to send file packets.
+    net_crypto: NetCrypto,
+    /// File transfers by long term `PublicKey` of online friends.
+    friends: Arc<Mutex<HashMap<PublicKey, FriendFiles>>>,
+    /// Sink to send file transfer events.
+    event_tx: Option<EventTx>,
+}
+
+impl FileTransfers {
+    /// Create new `FileTransfers` object.
+    pub fn new(net_crypto: NetCrypto) -> FileTransfers {
+        FileTransfers {
+            net_crypto,
+            friends: Arc::new(Mutex::new(HashMap::new())),
+            event_tx: None,
+        }
+    }
+
+    /// Set sink to send file transfer events.
+    pub fn set_event_sink(&mut self, event_tx: EventTx) {
+        self.event_tx = Some(event_tx);
+    }
+
+    /// Send event to the sink if it's set.
+    fn send_event(&self, event: Event) -> IoFuture<()> {
+        if let Some(ref event_tx) = self.event_tx {
+            send_to(event_tx, event)
+        } else {
+            Box::new(future::ok(()))
+        }
+    }
+
+    /// Send event that happened with a file transfer.
+    fn send_file_event(&self, friend_pk: PublicKey, direction: TransferDirection, file_id: u8, event: FileEvent) -> IoFuture<()> {
+        self.send_event(Event::File { pk: friend_pk, direction, file_id, event })
+    }
+
+    /// Serialize file packet and send it to a friend via `net_crypto`.
+    fn send_packet(&self, friend_pk: PublicKey, packet: &Packet) -> IoFuture<()> {
+        match serialize_packet(packet) {
+            Ok(data) => self.net_crypto.send_lossless(friend_pk, data),
+            Err(e) => Box::new(future::err(e)),
+        }
+    }
+
+    /// Send `FileControl` packet to a friend.
+    fn send_control(&self, friend_pk: PublicKey, direction: TransferDirection, file_id: u8, control_type: ControlType) -> IoFuture<()> {
+        self.send_packet(friend_pk, &Packet::FileControl(FileControl::new(direction, file_id, control_type)))
+    }
+
+    /// Check if file data can be sent to a friend without filling the sending
+    /// buffer of the crypto connection.
+    fn can_send_file_data(&self, friend_pk: PublicKey) -> bool {
+        self.net_crypto.connection_info(friend_pk).map_or(false, |info|
+            info.is_established && info.send_array_len < MAX_FILE_QUEUE_LEN
+        )
+    }
+
+    /// Offer a file to an online friend and get the number of the transfer.
+    /// Data is read from the source when the friend accepts the file. The
+    /// source is moved to the position requested by the friend, so to resume
+    /// an interrupted transfer it's enough to use the unique id of that
+    /// transfer. If the unique id is not specified a random one is generated.
+    pub fn send_file(
+        &self,
+        friend_pk: PublicKey,
+        file_type: FileType,
+        file_size: u64,
+        file_name: String,
+        file_unique_id: Option<[u8; FILE_UID_BYTES]>,
+        source: Box<FileSource>
+    ) -> IoFuture<u8> {
+        if file_name.len() > MAX_FILENAME_LENGTH {
+            return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                "File name is too long"
+            )))
+        }
+
+        let file_unique_id = file_unique_id.unwrap_or_else(|| {
+            let mut file_unique_id = [0; FILE_UID_BYTES];
+            randombytes_into(&mut file_unique_id);
+            file_unique_id
+        });
+
+        let file_id = {
+            let mut friends = self.friends.lock();
+            let files = if let Some(files) = friends.get_mut(&friend_pk) {
+                files
+            } else {
+                return Box::new(future::err(Error::new(
+                    ErrorKind::Other,
+                    format!("Friend {:?} is not online", friend_pk)
+                )))
+            };
+            let file_id = (0 .. MAX_CONCURRENT_FILES)
+                .map(|file_id| file_id as u8)
+                .find(|file_id| !files.sending.contains_key(file_id));
+            let file_id = if let Some(file_id) = file_id {
+                file_id
+            } else {
+                return Box::new(future::err(Error::new(
+                    ErrorKind::Other,
+                    "Too many files are being sent to the friend"
+                )))
+            };
+            files.sending.insert(file_id, OutgoingFile {
+                info: FileInfo::new(file_size),
+                state: SendingState::NotAccepted,
+                source,
+            });
+            file_id
+        };
+
+        let packet = Packet::FileSendRequest(FileSendRequest::new(file_id, file_type, file_size, file_unique_id, file_name));
+        let friends = self.friends.clone();
+        let future = self.send_packet(friend_pk, &packet).then(move |result| match result {
+            Ok(()) => Ok(file_id),
+            Err(e) => {
+                if let Some(files) = friends.lock().get_mut(&friend_pk) {
+                    files.sending.remove(&file_id);
+                }
+                Err(e)
+            },
+        });
+        Box::new(future)
+    }
+
+    /// Accept a file offered by a friend. The file data will be written to
+    /// the sink.
+    pub fn accept_file(&self, friend_pk: PublicKey, file_id: u8, sink: Box<FileSink>) -> IoFuture<()> {
+        self.accept_file_from(friend_pk, file_id, 0, sink)
+    }
+
+    /// Accept a file offered by a friend asking it to send data starting from
+    /// the position. It's used to resume interrupted transfers, the sink
+    /// should already contain the first `position` bytes of the file.
+    pub fn accept_file_from(&self, friend_pk: PublicKey, file_id: u8, position: u64, sink: Box<FileSink>) -> IoFuture<()> {
+        {
+            let mut friends = self.friends.lock();
+            let file = if let Some(file) = friends.get_mut(&friend_pk).and_then(|files| files.receiving.get_mut(&file_id)) {
+                file
+            } else {
+                return Box::new(future::err(Error::new(
+                    ErrorKind::Other,
+                    format!("No incoming file {} from friend {:?}", file_id, friend_pk)
+                )))
+            };
+            if file.sink.is_some() {
+                return Box::new(future::err(Error::new(
+                    ErrorKind::Other,
+                    "File is already accepted"
+                )))
+            }
+            if position > 0 && position >= file.info.size {
+                return Box::new(future::err(Error::new(
+                    ErrorKind::Other,
+                    "Position is beyond the end of the file"
+                )))
+            }
+            file.info.position = position;
+            file.sink = Some(sink);
+        }
+
+        let mut controls = Vec::new();
+        if position > 0 {
+            controls.push(ControlType::Seek(position));
+        }
+        controls.push(ControlType::Accept);
+        // packets are queued in net_crypto in the order of these calls
+        let futures = controls.into_iter()
+            .map(|control_type| self.send_control(friend_pk, TransferDirection::Receive, file_id, control_type))
+            .collect::<Vec<_>>();
+        Box::new(future::join_all(futures).map(|_| ()))
+    }
+
+    /// Pause a file transfer that was accepted.
+    pub fn pause_file(&self, friend_pk: PublicKey, direction: TransferDirection, file_id: u8) -> IoFuture<()> {
+        self.set_paused(friend_pk, direction, file_id, true)
+    }
+
+    /// Resume a file transfer that we paused before.
+    pub fn resume_file(&self, friend_pk: PublicKey, direction: TransferDirection, file_id: u8) -> IoFuture<()> {
+        self.set_paused(friend_pk, direction, file_id, false)
+    }
+
+    /// Pause or resume a file transfer sending `Pause` or `Accept` control
+    /// to the friend.
+    fn set_paused(&self, friend_pk: PublicKey, direction: TransferDirection, file_id: u8, paused: bool) -> IoFuture<()> {
+        {
+            let mut friends = self.friends.lock();
+            let (info, accepted) = if let Some(file) = friends.get_mut(&friend_pk).and_then(|files| files.file_mut(direction, file_id)) {
+                file
+            } else {
+                return Box::new(future::err(Error::new(
+                    ErrorKind::Other,
+                    format!("No file {} for friend {:?}", file_id, friend_pk)
+                )))
+            };
+            if !accepted || info.paused_by_us == paused {
+                return Box::new(future::err(Error::new(
+                    ErrorKind::Other,
+                    "File can't be paused or resumed in its current state"
+                )))
+            }
+            info.paused_by_us = paused;
+        }
+
+        let control_type = if paused { ControlType::Pause } else { ControlType::Accept };
+        self.send_control(friend_pk, direction, file_id, control_type)
+    }
+
+    /// Cancel a file transfer.
+    pub fn kill_file(&self, friend_pk: PublicKey, direction: TransferDirection, file_id: u8) -> IoFuture<()> {
+        let removed = self.friends.lock().get_mut(&friend_pk).and_then(|files| files.remove(direction, file_id));
+        if removed.is_some() {
+            self.send_control(friend_pk, direction, file_id, ControlType::Kill)
+        } else {
+            Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                format!("No file {} for friend {:?}", file_id, friend_pk)
+            )))
+        }
+    }
+
+    /// Get number of bytes of a file that were transferred.
+    pub fn file_position(&self, friend_pk: PublicKey, direction: TransferDirection, file_id: u8) -> Option<u64> {
+        self.friends.lock().get_mut(&friend_pk)
+            .and_then(|files| files.file_mut(direction, file_id))
+            .map(|(info, _)| info.position)
+    }
+
+    /// Start tracking file transfers with a friend that became online.
+    pub fn handle_friend_online(&self, friend_pk: PublicKey) {
+        self.friends.lock().entry(friend_pk).or_insert_with(FriendFiles::default);
+    }
+
+    /// Drop all file transfers with a friend that went offline reporting
+    /// them as broken.
+    pub fn handle_friend_offline(&self, friend_pk: PublicKey) -> IoFuture<()> {
+        let files = if let Some(files) = self.friends.lock().remove(&friend_pk) {
+            files
+        } else {
+            return Box::new(future::ok(()))
+        };
+
+        let sending = files.sending.into_iter()
+            .map(|(file_id, file)| (TransferDirection::Send, file_id, file.info.position));
+        let receiving = files.receiving.into_iter()
+            .map(|(file_id, file)| (TransferDirection::Receive, file_id, file.info.position));
+        let futures = sending.chain(receiving)
+            .map(|(direction, file_id, position)|
+                self.send_file_event(friend_pk, direction, file_id, FileEvent::Broken { position })
+            )
+            .collect::<Vec<_>>();
+        Box::new(future::join_all(futures).map(|_| ()))
+    }
+
+    /// Handle `FileSendRequest` packet reporting the offered file.
+    pub fn handle_file_send_request(&self, friend_pk: PublicKey, packet: FileSendRequest) -> IoFuture<()> {
+        {
+            let mut friends = self.friends.lock();
+            let files = if let Some(files) = friends.get_mut(&friend_pk) {
+                files
+            } else {
+                return Box::new(future::err(Error::new(
+                    ErrorKind::Other,
+                    format!("File request from offline friend {:?}", friend_pk)
+                )))
+            };
+            if files.receiving.contains_key(&packet.file_id) {
+                return Box::new(future::err(Error::new(
+                    ErrorKind::Other,
+                    format!("File number {} is already used by friend {:?}", packet.file_id, friend_pk)
+                )))
+            }
+            files.receiving.insert(packet.file_id, IncomingFile {
+                info: FileInfo::new(packet.file_size),
+                sink: None,
+            });
+        }
+
+        self.send_event(Event::FileRequest {
+            pk: friend_pk,
+            file_id: packet.file_id,
+            file_type: packet.file_type,
+            file_size: packet.file_size,
+            file_name: packet.file_name,
+            file_unique_id: packet.file_unique_id,
+        })
+    }
+
+    /// Handle `FileControl` packet changing the state of a transfer.
+    pub fn handle_file_control(&self, friend_pk: PublicKey, packet: FileControl) -> IoFuture<()> {
+        // the direction in the packet is from the point of view of the friend
+        let direction = match packet.transfer_direction {
+            TransferDirection::Send => TransferDirection::Receive,
+            TransferDirection::Receive => TransferDirection::Send,
+        };
+
+        let event = {
+            let mut friends = self.friends.lock();
+            let files = if let Some(files) = friends.get_mut(&friend_pk) {
+                files
+            } else {
+                return Box::new(future::err(Error::new(
+                    ErrorKind::Other,
+                    format!("File control from offline friend {:?}", friend_pk)
+                )))
+            };
+
+            match packet.control_type {
+                ControlType::Kill => files.remove(direction, packet.file_id).map(|_| FileEvent::Killed),
+                ControlType::Pause => files.file_mut(direction, packet.file_id).map(|(info, _)| {
+                    info.paused_by_friend = true;
+                    FileEvent::Paused
+                }),
+                ControlType::Accept => {
+                    let accepted = if direction == TransferDirection::Send {
+                        files.sending.get_mut(&packet.file_id).and_then(|file|
+                            if file.state == SendingState::NotAccepted {
+                                file.state = SendingState::Transferring;
+                                Some(FileEvent::Accepted { position: file.info.position })
+                            } else {
+                                None
+                            }
+                        )
+                    } else {
+                        None
+                    };
+                    accepted.or_else(|| files.file_mut(direction, packet.file_id).and_then(|(info, _)|
+                        if info.paused_by_friend {
+                            info.paused_by_friend = false;
+                            Some(FileEvent::Resumed)
+                        } else {
+                            None
+                        }
+                    ))
+                },
+                ControlType::Seek(position) => {
+                    let file = if direction == TransferDirection::Send {
+                        files.sending.get_mut(&packet.file_id).filter(|file| file.state == SendingState::NotAccepted)
+                    } else {
+                        None
+                    };
+                    let file = if let Some(file) = file {
+                        file
+                    } else {
+                        return Box::new(future::err(Error::new(
+                            ErrorKind::Other,
+                            format!("Unexpected seek for file {} from friend {:?}", packet.file_id, friend_pk)
+                        )))
+                    };
+                    if position >= file.info.size {
+                        return Box::new(future::err(Error::new(
+                            ErrorKind::Other,
+                            "Seek position is beyond the end of the file"
+                        )))
+                    }
+                    if let Err(e) = file.source.seek(SeekFrom::Start(position)) {
+                        return Box::new(future::err(e))
+                    }
+                    file.info.position = position;
+                    None
+                },
+            }
+        };
+
+        if let Some(event) = event {
+            self.send_file_event(friend_pk, direction, packet.file_id, event)
+        } else {
+            Box::new(future::ok(()))
+        }
+    }
+
+    /// Handle `FileData` packet writing the chunk to the sink of the file.
+    /// The transfer is cancelled if writing fails.
+    pub fn handle_file_data(&self, friend_pk: PublicKey, packet: FileData) -> IoFuture<()> {
+        let direction = TransferDirection::Receive;
+
+        let result = {
+            let mut friends = self.friends.lock();
+            let files = if let Some(files) = friends.get_mut(&friend_pk) {
+                files
+            } else {
+                return Box::new(future::err(Error::new(
+                    ErrorKind::Other,
+                    format!("File data from offline friend {:?}", friend_pk)
+                )))
+            };
+            let (result, finished) = {
+                let file = if let Some(file) = files.receiving.get_mut(&packet.file_id) {
+                    file
+                } else {
+                    return Box::new(future::err(Error::new(
+                        ErrorKind::Other,
+                        format!("No incoming file {} from friend {:?}", packet.file_id, friend_pk)
+                    )))
+                };
+                let info = &mut file.info;
+                let sink = if let Some(ref mut sink) = file.sink {
+                    sink
+                } else {
+                    return Box::new(future::err(Error::new(
+                        ErrorKind::Other,
+                        format!("File data for not accepted file {} from friend {:?}", packet.file_id, friend_pk)
+                    )))
+                };
+
+                // the friend shouldn't send more data than the size of the file
+                let len = cmp::min(packet.data.len() as u64, info.size - info.position) as usize;
+                let finished = packet.data.len() < MAX_FILE_DATA_SIZE || info.position + len as u64 >= info.size;
+                let position = info.position;
+                let result = sink.write_all(&packet.data[..len])
+                    .and_then(|()| if finished { sink.flush() } else { Ok(()) })
+                    .map(|()| {
+                        info.position += len as u64;
+                        info.position
+                    })
+                    .map_err(|e| (e, position));
+                (result, finished)
+            };
+            if result.is_err() || finished {
+                files.receiving.remove(&packet.file_id);
+            }
+            result.map(|position| if finished { FileEvent::Finished } else { FileEvent::Progress { position } })
+        };
+
+        match result {
+            Ok(event) => self.send_file_event(friend_pk, direction, packet.file_id, event),
+            Err((e, position)) => {
+                debug!("Failed to write file {} from {:?}: {}", packet.file_id, friend_pk, e);
+                let kill_future = self.send_control(friend_pk, direction, packet.file_id, ControlType::Kill)
+                    .then(|_| Ok(()));
+                let event_future = self.send_file_event(friend_pk, direction, packet.file_id, FileEvent::Broken { position });
+                Box::new(kill_future.join(event_future).map(|_| ()))
+            },
+        }
+    }
+
+    /// Read the next chunk of a file and send it to the friend. If sending
+    /// fails the transfer is reported as broken.
+    fn send_chunk(&self, friend_pk: PublicKey, file_id: u8, file: &mut OutgoingFile) -> Result<IoFuture<()>, Error> {
+        let position = file.info.position;
+        let (data, last) = read_chunk(&mut *file.source, file.info.size, position)?;
+        file.info.position += data.len() as u64;
+
+        let packet = Packet::FileData(FileData::new(file_id, data));
+        let data = serialize_packet(&packet)?;
+
+        let transfers = self.clone();
+        let on_error = move |e: Error| -> IoFuture<()> {
+            debug!("Failed to send file {} to {:?}: {}", file_id, friend_pk, e);
+            let removed = transfers.friends.lock().get_mut(&friend_pk).and_then(|files| files.sending.remove(&file_id));
+            if removed.is_some() {
+                transfers.send_file_event(friend_pk, TransferDirection::Send, file_id, FileEvent::Broken { position })
+            } else {
+                Box::new(future::ok(()))
+            }
+        };
+
+        let future: IoFuture<()> = if last {
+            file.state = SendingState::Finishing(None);
+            let friends = self.friends.clone();
+            Box::new(self.net_crypto.send_lossless_numbered(friend_pk, data).then(move |result| -> IoFuture<()> { match result {
+                Ok(packet_id) => {
+                    if let Some(file) = friends.lock().get_mut(&friend_pk).and_then(|files| files.sending.get_mut(&file_id)) {
+                        file.state = SendingState::Finishing(Some(packet_id));
+                    }
+                    Box::new(future::ok(()))
+                },
+                Err(e) => on_error(e),
+            } }))
+        } else {
+            Box::new(self.net_crypto.send_lossless(friend_pk, data).or_else(on_error))
+        };
+        Ok(future)
+    }
+
+    /** Send file data to online friends and report finished transfers.
+
+    Transfers to the same friend take turns sending one chunk at a time. At
+    most `MAX_FILE_CHUNKS_PER_ITERATION` chunks are sent to a friend per
+    iteration and only while the sending buffer of the crypto connection is
+    shorter than `MAX_FILE_QUEUE_LEN` packets.
+    */
+    pub fn main_loop(&self) -> IoFuture<()> {
+        let mut friends = self.friends.lock();
+        let mut futures = Vec::new();
+
+        for (&friend_pk, files) in friends.iter_mut() {
+            let net_crypto = &self.net_crypto;
+            let finished = files.sending.iter()
+                .filter(|&(_, file)| match file.state {
+                    SendingState::Finishing(Some(packet_id)) =>
+                        net_crypto.is_lossless_delivered(friend_pk, packet_id),
+                    _ => false,
+                })
+                .map(|(&file_id, _)| file_id)
+                .collect::<Vec<_>>();
+            for file_id in finished {
+                files.sending.remove(&file_id);
+                futures.push(self.send_file_event(friend_pk, TransferDirection::Send, file_id, FileEvent::Finished));
+            }
+
+            let mut positions = files.sending.iter()
+                .filter(|&(_, file)| file.can_send())
+                .map(|(&file_id, file)| (file_id, file.info.position))
+                .collect::<Vec<_>>();
+            positions.sort();
+
+            let mut broken = Vec::new();
+            let mut chunks = 0;
+            'sending: loop {
+                let mut sent = false;
+                for &(file_id, _) in &positions {
+                    if chunks == MAX_FILE_CHUNKS_PER_ITERATION || !self.can_send_file_data(friend_pk) {
+                        break 'sending
+                    }
+                    let file = match files.sending.get_mut(&file_id) {
+                        Some(file) => file,
+                        None => continue,
+                    };
+                    if !file.can_send() {
+                        continue
+                    }
+                    match self.send_chunk(friend_pk, file_id, file) {
+                        Ok(future) => futures.push(future),
+                        Err(e) => {
+                            debug!("Failed to read file {} for {:?}: {}", file_id, friend_pk, e);
+                            broken.push(file_id);
+                        },
+                    }
+                    chunks += 1;
+                    sent = true;
+                }
+                if !sent {
+                    break
+                }
+            }
+
+            for file_id in broken {
+                if let Some(file) = files.sending.remove(&file_id) {
+                    futures.push(Box::new(self.send_control(friend_pk, TransferDirection::Send, file_id, ControlType::Kill).then(|_| Ok(()))));
+                    futures.push(self.send_file_event(friend_pk, TransferDirection::Send, file_id, FileEvent::Broken { position: file.info.position }));
+                }
+            }
+
+            for (file_id, old_position) in positions {
+                if let Some(file) = files.sending.get(&file_id) {
+                    if file.info.position != old_position {
+                        futures.push(self.send_file_event(friend_pk, TransferDirection::Send, file_id, FileEvent::Progress { position: file.info.position }));
+                    }
+                }
+            }
+        }
+
+        Box::new(future::join_all(futures).map(|_| ()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use futures::Stream;
+    use futures::sync::mpsc;
+
+    use toxcore::dht::precomputed_cache::PrecomputedCache;
+    use toxcore::net_crypto::NetCryptoNewArgs;
+
+    /// Sink that allows to check written data after it's moved to a transfer.
+    struct SharedSink(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedSink {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+            self.0.lock().write(buf)
+        }
+
+        fn flush(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    fn create_file_transfers() -> (FileTransfers, mpsc::UnboundedReceiver<Event>) {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            precomputed_keys,
+        });
+        let (event_tx, event_rx) = mpsc::unbounded();
+        let mut file_transfers = FileTransfers::new(net_crypto);
+        file_transfers.set_event_sink(event_tx);
+        (file_transfers, event_rx)
+    }
+
+    fn add_outgoing_file(file_transfers: &FileTransfers, friend_pk: PublicKey, file_id: u8, state: SendingState, data: Vec<u8>) {
+        let file = OutgoingFile {
+            info: FileInfo::new(data.len() as u64),
+            state,
+            source: Box::new(Cursor::new(data)),
+        };
+        file_transfers.friends.lock().get_mut(&friend_pk).unwrap().sending.insert(file_id, file);
+    }
+
+    fn add_incoming_file(file_transfers: &FileTransfers, friend_pk: PublicKey, file_id: u8, size: u64) -> Arc<Mutex<Vec<u8>>> {
+        let data = Arc::new(Mutex::new(Vec::new()));
+        let file = IncomingFile {
+            info: FileInfo::new(size),
+            sink: Some(Box::new(SharedSink(data.clone()))),
+        };
+        file_transfers.friends.lock().get_mut(&friend_pk).unwrap().receiving.insert(file_id, file);
+        data
+    }
+
+    #[test]
+    fn read_chunk_known_size() {
+        let data = vec![42; MAX_FILE_DATA_SIZE + 10];
+        let mut source = Cursor::new(data.clone());
+
+        let (chunk, last) = read_chunk(&mut source, data.len() as u64, 0).unwrap();
+        assert_eq!(chunk.len(), MAX_FILE_DATA_SIZE);
+        assert!(!last);
+        let (chunk, last) = read_chunk(&mut source, data.len() as u64, MAX_FILE_DATA_SIZE as u64).unwrap();
+        assert_eq!(chunk.len(), 10);
+        assert!(last);
+    }
+
+    #[test]
+    fn read_chunk_known_size_full_chunk() {
+        let mut source = Cursor::new(vec![42; MAX_FILE_DATA_SIZE * 2]);
+
+        // the rest of the source is beyond the size of the file
+        let (chunk, last) = read_chunk(&mut source, MAX_FILE_DATA_SIZE as u64, 0).unwrap();
+        assert_eq!(chunk.len(), MAX_FILE_DATA_SIZE);
+        assert!(last);
+    }
+
+    #[test]
+    fn read_chunk_unknown_size() {
+        let mut source = Cursor::new(vec![42; MAX_FILE_DATA_SIZE]);
+
+        let (chunk, last) = read_chunk(&mut source, UNKNOWN_FILE_SIZE, 0).unwrap();
+        assert_eq!(chunk.len(), MAX_FILE_DATA_SIZE);
+        assert!(!last);
+        let (chunk, last) = read_chunk(&mut source, UNKNOWN_FILE_SIZE, MAX_FILE_DATA_SIZE as u64).unwrap();
+        assert!(chunk.is_empty());
+        assert!(last);
+    }
+
+    #[test]
+    fn send_file_offline() {
+        let (file_transfers, _event_rx) = create_file_transfers();
+        let friend_pk = gen_keypair().0;
+
+        let source = Box::new(Cursor::new(vec![42; 10]));
+        let result = file_transfers.send_file(friend_pk, FileType::Data, 10, "file.txt".to_owned(), None, source).wait();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn send_file_without_connection() {
+        let (file_transfers, _event_rx) = create_file_transfers();
+        let friend_pk = gen_keypair().0;
+        file_transfers.handle_friend_online(friend_pk);
+
+        let source = Box::new(Cursor::new(vec![42; 10]));
+        let result = file_transfers.send_file(friend_pk, FileType::Data, 10, "file.txt".to_owned(), None, source).wait();
+        assert!(result.is_err());
+        // the transfer is dropped when the request can't be sent
+        assert_eq!(file_transfers.file_position(friend_pk, TransferDirection::Send, 0), None);
+    }
+
+    #[test]
+    fn handle_file_send_request() {
+        let (file_transfers, event_rx) = create_file_transfers();
+        let friend_pk = gen_keypair().0;
+        file_transfers.handle_friend_online(friend_pk);
+
+        let packet = FileSendRequest::new(1, FileType::Data, 4096, [42; FILE_UID_BYTES], "file.txt".to_owned());
+        file_transfers.handle_file_send_request(friend_pk, packet.clone()).wait().unwrap();
+        assert_eq!(file_transfers.file_position(friend_pk, TransferDirection::Receive, 1), Some(0));
+
+        // the number is already used
+        assert!(file_transfers.handle_file_send_request(friend_pk, packet).wait().is_err());
+        // data for not accepted file
+        assert!(file_transfers.handle_file_data(friend_pk, FileData::new(1, vec![42; 10])).wait().is_err());
+
+        drop(file_transfers);
+        let events = event_rx.collect().wait().unwrap();
+        assert_eq!(events, vec![Event::FileRequest {
+            pk: friend_pk,
+            file_id: 1,
+            file_type: FileType::Data,
+            file_size: 4096,
+            file_name: "file.txt".to_owned(),
+            file_unique_id: [42; FILE_UID_BYTES],
+        }]);
+    }
+
+    #[test]
+    fn handle_file_send_request_offline() {
+        let (file_transfers, _event_rx) = create_file_transfers();
+        let friend_pk = gen_keypair().0;
+
+        let packet = FileSendRequest::new(1, FileType::Data, 4096, [42; FILE_UID_BYTES], "file.txt".to_owned());
+        assert!(file_transfers.handle_file_send_request(friend_pk, packet).wait().is_err());
+    }
+
+    #[test]
+    fn handle_file_data() {
+        let (file_transfers, event_rx) = create_file_transfers();
+        let friend_pk = gen_keypair().0;
+        file_transfers.handle_friend_online(friend_pk);
+        let size = MAX_FILE_DATA_SIZE as u64 + 10;
+        let data = add_incoming_file(&file_transfers, friend_pk, 1, size);
+
+        file_transfers.handle_file_data(friend_pk, FileData::new(1, vec![1; MAX_FILE_DATA_SIZE])).wait().unwrap();
+        assert_eq!(file_transfers.file_position(friend_pk, TransferDirection::Receive, 1), Some(MAX_FILE_DATA_SIZE as u64));
+        // data beyond the size of the file is dropped
+        file_transfers.handle_file_data(friend_pk, FileData::new(1, vec![2; 20])).wait().unwrap();
+        assert_eq!(file_transfers.file_position(friend_pk, TransferDirection::Receive, 1), None);
+
+        let mut expected = vec![1; MAX_FILE_DATA_SIZE];
+        expected.extend_from_slice(&[2; 10]);
+        assert_eq!(*data.lock(), expected);
+
+        drop(file_transfers);
+        let events = event_rx.collect().wait().unwrap();
+        assert_eq!(events, vec![
+            Event::File {
+                pk: friend_pk,
+                direction: TransferDirection::Receive,
+                file_id: 1,
+                event: FileEvent::Progress { position: MAX_FILE_DATA_SIZE as u64 },
+            },
+            Event::File {
+                pk: friend_pk,
+                direction: TransferDirection::Receive,
+                file_id: 1,
+                event: FileEvent::Finished,
+            },
+        ]);
+    }
+
+    #[test]
+    fn handle_file_data_unknown_size() {
+        let (file_transfers, event_rx) = create_file_transfers();
+        let friend_pk = gen_keypair().0;
+        file_transfers.handle_friend_online(friend_pk);
+        let data = add_incoming_file(&file_transfers, friend_pk, 1, UNKNOWN_FILE_SIZE);
+
+        // empty chunk means the end of the file
+        file_transfers.handle_file_data(friend_pk, FileData::new(1, Vec::new())).wait().unwrap();
+        assert_eq!(file_transfers.file_position(friend_pk, TransferDirection::Receive, 1), None);
+        assert!(data.lock().is_empty());
+
+        drop(file_transfers);
+        let events = event_rx.collect().wait().unwrap();
+        assert_eq!(events, vec![Event::File {
+            pk: friend_pk,
+            direction: TransferDirection::Receive,
+            file_id: 1,
+            event: FileEvent::Finished,
+        }]);
+    }
+
+    #[test]
+    fn handle_file_control_seek_accept_pause() {
+        let (file_transfers, event_rx) = create_file_transfers();
+        let friend_pk = gen_keypair().0;
+        file_transfers.handle_friend_online(friend_pk);
+        add_outgoing_file(&file_transfers, friend_pk, 0, SendingState::NotAccepted, vec![42; 1000]);
+
+        let control = |control_type| FileControl::new(TransferDirection::Receive, 0, control_type);
+
+        // seek beyond the end of the file
+        assert!(file_transfers.handle_file_control(friend_pk, control(ControlType::Seek(1000))).wait().is_err());
+        file_transfers.handle_file_control(friend_pk, control(ControlType::Seek(100))).wait().unwrap();
+        assert_eq!(file_transfers.file_position(friend_pk, TransferDirection::Send, 0), Some(100));
+        file_transfers.handle_file_control(friend_pk, control(ControlType::Accept)).wait().unwrap();
+        // seek is not allowed after accepting
+        assert!(file_transfers.handle_file_control(friend_pk, control(ControlType::Seek(200))).wait().is_err());
+        file_transfers.handle_file_control(friend_pk, control(ControlType::Pause)).wait().unwrap();
+        file_transfers.handle_file_control(friend_pk, control(ControlType::Accept)).wait().unwrap();
+
+        drop(file_transfers);
+        let events = event_rx.collect().wait().unwrap();
+        let file_event = |event| Event::File {
+            pk: friend_pk,
+            direction: TransferDirection::Send,
+            file_id: 0,
+            event,
+        };
+        assert_eq!(events, vec![
+            file_event(FileEvent::Accepted { position: 100 }),
+            file_event(FileEvent::Paused),
+            file_event(FileEvent::Resumed),
+        ]);
+    }
+
+    #[test]
+    fn handle_file_control_kill() {
+        let (file_transfers, event_rx) = create_file_transfers();
+        let friend_pk = gen_keypair().0;
+        file_transfers.handle_friend_online(friend_pk);
+        add_incoming_file(&file_transfers, friend_pk, 3, 1000);
+
+        let packet = FileControl::new(TransferDirection::Send, 3, ControlType::Kill);
+        file_transfers.handle_file_control(friend_pk, packet).wait().unwrap();
+        assert_eq!(file_transfers.file_position(friend_pk, TransferDirection::Receive, 3), None);
+
+        drop(file_transfers);
+        let events = event_rx.collect().wait().unwrap();
+        assert_eq!(events, vec![Event::File {
+            pk: friend_pk,
+            direction: TransferDirection::Receive,
+            file_id: 3,
+            event: FileEvent::Killed,
+        }]);
+    }
+
+    #[test]
+    fn handle_friend_offline() {
+        let (file_transfers, event_rx) = create_file_transfers();
+        let friend_pk = gen_keypair().0;
+        file_transfers.handle_friend_online(friend_pk);
+        add_outgoing_file(&file_transfers, friend_pk, 0, SendingState::Transferring, vec![42; 1000]);
+        file_transfers.friends.lock().get_mut(&friend_pk).unwrap().sending.get_mut(&0).unwrap().info.position = 500;
+
+        file_transfers.handle_friend_offline(friend_pk).wait().unwrap();
+        assert_eq!(file_transfers.file_position(friend_pk, TransferDirection::Send, 0), None);
+
+        drop(file_transfers);
+        let events = event_rx.collect().wait().unwrap();
+        assert_eq!(events, vec![Event::File {
+            pk: friend_pk,
+            direction: TransferDirection::Send,
+            file_id: 0,
+            event: FileEvent::Broken { position: 500 },
+        }]);
+    }
+
+    #[test]
+    fn main_loop_without_connection() {
+        let (file_transfers, _event_rx) = create_file_transfers();
+        let friend_pk = gen_keypair().0;
+        file_transfers.handle_friend_online(friend_pk);
+        add_outgoing_file(&file_transfers, friend_pk, 0, SendingState::Transferring, vec![42; 1000]);
+
+        // no data is sent when there is no room for it in crypto connection
+        file_transfers.main_loop().wait().unwrap();
+        assert_eq!(file_transfers.file_position(friend_pk, TransferDirection::Send, 0), Some(0));
+    }
+
+    #[test]
+    fn pause_not_accepted() {
+        let (file_transfers, _event_rx) = create_file_transfers();
+        let friend_pk = gen_keypair().0;
+        file_transfers.handle_friend_online(friend_pk);
+        add_outgoing_file(&file_transfers, friend_pk, 0, SendingState::NotAccepted, vec![42; 1000]);
+
+        assert!(file_transfers.pause_file(friend_pk, TransferDirection::Send, 0).wait().is_err());
+        assert!(file_transfers.resume_file(friend_pk, TransferDirection::Send, 0).wait().is_err());
+    }
+}
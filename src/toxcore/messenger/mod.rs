@@ -1,4 +1,1127 @@
 /*! The implementation of Messenger
+
+Messenger module sits on top of friend connections and friend requests. It
+keeps our own name, status message and status, exchanges them with friends
+when they become online and turns received messenger packets into a single
+stream of typed events.
+
+A friend is considered online by messenger only after it sent us `Online`
+packet. This packet also confirms that the friend accepted our friend
+request.
+
+Chat messages are queued in the outbox of the friend and sent from the main
+loop while the friend is online. When the friend confirms that it received
+all chunks of a message `MessageReceipt` event is sent. Not delivered
+messages are resent after reconnection and can be saved with
+`Messenger::outbox_snapshot` to be restored after restart.
+
+File transfers are handled by `FileTransfers` module that is owned by the
+messenger and reports its events to the same stream. Audio/video call
+signalling is handled by `Msi` module in the same way. Conferences report
+their events to this stream as well.
+
 */
 
 pub mod packet;
+pub mod file_transfer;
+pub mod msi;
+mod outbox;
+
+pub use self::outbox::{split_message, MessageKind, OutboxSnapshot, OutboxSnapshotEntry};
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::{future, Future, Stream};
+use futures::sync::mpsc;
+use parking_lot::RwLock;
+use tokio::timer::Interval;
+
+use toxcore::binary_io::*;
+use toxcore::conference::ConferenceEvent;
+use toxcore::crypto_core::*;
+use toxcore::dht::packet::MAX_CRYPTO_DATA_SIZE;
+use toxcore::friend_connection::FriendConnections;
+use toxcore::friend_requests::{FriendRequests, SendFriendRequestError};
+use toxcore::io_tokio::*;
+use toxcore::messenger::file_transfer::{FileEvent, FileTransfers};
+use toxcore::messenger::msi::{CallEvent, Msi};
+use toxcore::messenger::outbox::Outbox;
+use toxcore::messenger::packet::*;
+use toxcore::net_crypto::{LosslessPacketId, NetCrypto};
+use toxcore::toxid::ToxId;
+
+/// Interval in milliseconds for running the main loop. Queued messages are
+/// sent and delivery receipts are checked on every iteration.
+const MAIN_LOOP_INTERVAL: u64 = 50;
+
+/// Shorthand for the transmit half of the message channel for sending
+/// messenger events.
+type EventTx = mpsc::UnboundedSender<Event>;
+
+/// Serialize messenger packet to bytes that can be sent as a lossless
+/// packet via `net_crypto`.
+fn serialize_packet(packet: &Packet) -> Result<Vec<u8>, Error> {
+    let mut buf = [0; MAX_CRYPTO_DATA_SIZE];
+    match packet.to_bytes((&mut buf, 0)) {
+        Ok((_, size)) => Ok(buf[..size].to_vec()),
+        Err(e) => Err(Error::new(
+            ErrorKind::Other,
+            format!("Failed to serialize packet: {:?}", e)
+        )),
+    }
+}
+
+/// Error that can happen when sending a chat message
+#[derive(Debug, Eq, PartialEq, Fail)]
+pub enum SendMessageError {
+    /// Error indicates that the friend doesn't exist
+    #[fail(display = "No friend with such key")]
+    NoFriendError,
+    /// Error indicates that the message is empty
+    #[fail(display = "Message is empty")]
+    NoMessageError,
+}
+
+/// Event that happened with one of our friends.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Event {
+    /// Friend request is received.
+    FriendRequest {
+        /// Long term `PublicKey` of the sender
+        pk: PublicKey,
+        /// Greeting message
+        message: String,
+    },
+    /// Friend became online or offline.
+    FriendConnectionStatus {
+        /// Long term `PublicKey` of the friend
+        pk: PublicKey,
+        /// `true` if the friend became online
+        online: bool,
+    },
+    /// Friend changed its name.
+    FriendName {
+        /// Long term `PublicKey` of the friend
+        pk: PublicKey,
+        /// New name
+        name: String,
+    },
+    /// Friend changed its status message.
+    FriendStatusMessage {
+        /// Long term `PublicKey` of the friend
+        pk: PublicKey,
+        /// New status message
+        message: String,
+    },
+    /// Friend changed its status.
+    FriendStatus {
+        /// Long term `PublicKey` of the friend
+        pk: PublicKey,
+        /// New status
+        status: PeerStatus,
+    },
+    /// Friend started or stopped typing.
+    FriendTyping {
+        /// Long term `PublicKey` of the friend
+        pk: PublicKey,
+        /// `true` if the friend is typing
+        is_typing: bool,
+    },
+    /// Chat message is received from a friend.
+    FriendMessage {
+        /// Long term `PublicKey` of the friend
+        pk: PublicKey,
+        /// Chat message
+        message: String,
+    },
+    /// Action message is received from a friend.
+    FriendAction {
+        /// Long term `PublicKey` of the friend
+        pk: PublicKey,
+        /// Action message
+        action: String,
+    },
+    /// Friend received all chunks of the message we sent.
+    MessageReceipt {
+        /// Long term `PublicKey` of the friend
+        pk: PublicKey,
+        /// Id of the message returned by `send_message`
+        message_id: u32,
+    },
+    /// Friend offers us a file.
+    FileRequest {
+        /// Long term `PublicKey` of the friend
+        pk: PublicKey,
+        /// Number of the file transfer
+        file_id: u8,
+        /// Type of the file
+        file_type: FileType,
+        /// Size of the file or `UNKNOWN_FILE_SIZE`
+        file_size: u64,
+        /// Name of the file
+        file_name: String,
+        /// Unique id of the file that can be used to resume transfers
+        file_unique_id: [u8; FILE_UID_BYTES],
+    },
+    /// Something happened with a file transfer.
+    File {
+        /// Long term `PublicKey` of the friend
+        pk: PublicKey,
+        /// `Send` for files we send and `Receive` for files we receive
+        direction: TransferDirection,
+        /// Number of the file transfer
+        file_id: u8,
+        /// What happened with the transfer
+        event: FileEvent,
+    },
+    /// Something happened with an audio/video call.
+    Call {
+        /// Long term `PublicKey` of the friend
+        pk: PublicKey,
+        /// What happened with the call
+        event: CallEvent,
+    },
+    /// Something happened in one of conferences.
+    Conference(ConferenceEvent),
+}
+
+/// Friend related data stored in the messenger module.
+#[derive(Clone, Debug)]
+struct Friend {
+    /// Whether the friend sent us `Online` packet since the connection was
+    /// established.
+    online: bool,
+    /// Name of the friend.
+    name: String,
+    /// Status message of the friend.
+    status_message: String,
+    /// Status of the friend.
+    status: PeerStatus,
+    /// Whether the friend is typing a message to us.
+    is_typing: bool,
+    /// Chat messages that are not delivered to the friend yet.
+    outbox: Outbox,
+}
+
+impl Friend {
+    /// Create new offline `Friend` without a name.
+    fn new() -> Friend {
+        Friend {
+            online: false,
+            name: String::new(),
+            status_message: String::new(),
+            status: PeerStatus::Online,
+            is_typing: false,
+            outbox: Outbox::new(),
+        }
+    }
+}
+
+/// Struct that keeps our own info, exchanges it with friends and reports
+/// everything that happens with friends as events.
+#[derive(Clone)]
+pub struct Messenger {
+    /// `NetCrypto` module that is used to send messenger packets.
+    net_crypto: NetCrypto,
+    /// Friend connections that track when friends go online or offline.
+    friend_connections: FriendConnections,
+    /// Friend requests module that sends requests to friends we added.
+    friend_requests: FriendRequests,
+    /// File transfers with online friends.
+    file_transfers: FileTransfers,
+    /// Audio/video calls with online friends.
+    msi: Msi,
+    /// Our name.
+    name: Arc<RwLock<String>>,
+    /// Our status message.
+    status_message: Arc<RwLock<String>>,
+    /// Our status.
+    status: Arc<RwLock<PeerStatus>>,
+    /// Friends by their long term `PublicKey`.
+    friends: Arc<RwLock<HashMap<PublicKey, Friend>>>,
+    /// Id that will be assigned to the next chat message.
+    next_message_id: Arc<RwLock<u32>>,
+    /// Sink to send messenger events.
+    event_tx: Option<EventTx>,
+}
+
+impl Messenger {
+    /// Create new `Messenger` object.
+    pub fn new(net_crypto: NetCrypto, friend_connections: FriendConnections, friend_requests: FriendRequests) -> Messenger {
+        Messenger {
+            file_transfers: FileTransfers::new(net_crypto.clone()),
+            msi: Msi::new(net_crypto.clone()),
+            net_crypto,
+            friend_connections,
+            friend_requests,
+            name: Arc::new(RwLock::new(String::new())),
+            status_message: Arc::new(RwLock::new(String::new())),
+            status: Arc::new(RwLock::new(PeerStatus::Online)),
+            friends: Arc::new(RwLock::new(HashMap::new())),
+            next_message_id: Arc::new(RwLock::new(1)),
+            event_tx: None,
+        }
+    }
+
+    /// Set sink to send messenger events.
+    pub fn set_event_sink(&mut self, event_tx: EventTx) {
+        self.file_transfers.set_event_sink(event_tx.clone());
+        self.msi.set_event_sink(event_tx.clone());
+        self.event_tx = Some(event_tx);
+    }
+
+    /// Get file transfers module to send and receive files.
+    pub fn file_transfers(&self) -> &FileTransfers {
+        &self.file_transfers
+    }
+
+    /// Get msi module to make and answer audio/video calls.
+    pub fn msi(&self) -> &Msi {
+        &self.msi
+    }
+
+    /// Send event to the sink if it's set.
+    fn send_event(&self, event: Event) -> IoFuture<()> {
+        if let Some(ref event_tx) = self.event_tx {
+            send_to(event_tx, event)
+        } else {
+            Box::new(future::ok(()))
+        }
+    }
+
+    /// Add a friend by its `ToxId` and send a friend request to it. The
+    /// request is resent until the friend accepts it.
+    pub fn add_friend(&self, tox_id: ToxId, message: String) -> Result<(), SendFriendRequestError> {
+        self.friend_requests.send_friend_request(tox_id, message.into_bytes())?;
+        self.friends.write()
+            .entry(tox_id.pk)
+            .or_insert_with(Friend::new);
+        Ok(())
+    }
+
+    /// Add a friend without sending a friend request. It's used to accept
+    /// received friend requests. Does nothing if the friend already exists.
+    pub fn add_friend_norequest(&self, friend_pk: PublicKey) {
+        self.friend_connections.add_friend(friend_pk);
+        self.friends.write()
+            .entry(friend_pk)
+            .or_insert_with(Friend::new);
+    }
+
+    /// Remove a friend. `Offline` packet is sent to the friend if it's online
+    /// so it can mark us as offline immediately.
+    pub fn remove_friend(&self, friend_pk: PublicKey) -> IoFuture<()> {
+        let friend = if let Some(friend) = self.friends.write().remove(&friend_pk) {
+            friend
+        } else {
+            return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                format!("No friend with key {:?}", friend_pk)
+            )))
+        };
+
+        self.friend_requests.cancel_friend_request(friend_pk);
+
+        let offline_future: IoFuture<()> = if friend.online {
+            // sending errors are not fatal since the friend is removed anyway
+            Box::new(self.send_packet(friend_pk, &Packet::Offline(Offline)).then(|_| Ok(())))
+        } else {
+            Box::new(future::ok(()))
+        };
+        let files_future = self.file_transfers.handle_friend_offline(friend_pk);
+        let calls_future = self.msi.handle_friend_offline(friend_pk);
+        let friend_connections = self.friend_connections.clone();
+        Box::new(offline_future
+            .join3(files_future, calls_future)
+            .and_then(move |_| friend_connections.remove_friend(friend_pk)))
+    }
+
+    /// Get long term `PublicKey`s of all friends.
+    pub fn friends(&self) -> Vec<PublicKey> {
+        self.friends.read().keys().cloned().collect()
+    }
+
+    /// Check if the friend exists.
+    pub fn has_friend(&self, friend_pk: PublicKey) -> bool {
+        self.friends.read().contains_key(&friend_pk)
+    }
+
+    /// Check if the friend is online.
+    pub fn is_friend_online(&self, friend_pk: PublicKey) -> bool {
+        self.friends.read().get(&friend_pk).map_or(false, |friend| friend.online)
+    }
+
+    /// Get name of the friend.
+    pub fn friend_name(&self, friend_pk: PublicKey) -> Option<String> {
+        self.friends.read().get(&friend_pk).map(|friend| friend.name.clone())
+    }
+
+    /// Get status message of the friend.
+    pub fn friend_status_message(&self, friend_pk: PublicKey) -> Option<String> {
+        self.friends.read().get(&friend_pk).map(|friend| friend.status_message.clone())
+    }
+
+    /// Get status of the friend.
+    pub fn friend_status(&self, friend_pk: PublicKey) -> Option<PeerStatus> {
+        self.friends.read().get(&friend_pk).map(|friend| friend.status)
+    }
+
+    /// Check if the friend is typing a message to us.
+    pub fn is_friend_typing(&self, friend_pk: PublicKey) -> bool {
+        self.friends.read().get(&friend_pk).map_or(false, |friend| friend.is_typing)
+    }
+
+    /// Get our name.
+    pub fn name(&self) -> String {
+        self.name.read().clone()
+    }
+
+    /// Get our status message.
+    pub fn status_message(&self) -> String {
+        self.status_message.read().clone()
+    }
+
+    /// Get our status.
+    pub fn status(&self) -> PeerStatus {
+        *self.status.read()
+    }
+
+    /// Set our name and send it to all online friends.
+    pub fn set_name(&self, name: String) -> IoFuture<()> {
+        if name.len() > MAX_NICKNAME_DATA_SIZE {
+            return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                format!("Name is too long: {} bytes", name.len())
+            )))
+        }
+
+        *self.name.write() = name.clone();
+        self.send_to_online_friends(&Packet::Nickname(Nickname::new(name)))
+    }
+
+    /// Set our status message and send it to all online friends.
+    pub fn set_status_message(&self, message: String) -> IoFuture<()> {
+        if message.len() > MAX_STATUS_MESSAGE_DATA_SIZE {
+            return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                format!("Status message is too long: {} bytes", message.len())
+            )))
+        }
+
+        *self.status_message.write() = message.clone();
+        self.send_to_online_friends(&Packet::StatusMessage(StatusMessage::new(message)))
+    }
+
+    /// Set our status and send it to all online friends.
+    pub fn set_status(&self, status: PeerStatus) -> IoFuture<()> {
+        *self.status.write() = status;
+        self.send_to_online_friends(&Packet::UserStatus(UserStatus::new(status)))
+    }
+
+    /// Let the friend know whether we are typing a message to it.
+    pub fn set_typing(&self, friend_pk: PublicKey, is_typing: bool) -> IoFuture<()> {
+        self.send_to_online_friend(friend_pk, &Packet::Typing(Typing::new(is_typing)))
+    }
+
+    /** Queue chat message to a friend and get its id. Messages longer than
+    `MAX_MESSAGE_DATA_SIZE` are split into several packets. The message is
+    sent when the friend is online and resent after reconnection until the
+    friend confirms that it received the message. Then `MessageReceipt`
+    event with the returned id is sent.
+    */
+    pub fn send_message(&self, friend_pk: PublicKey, kind: MessageKind, message: String) -> Result<u32, SendMessageError> {
+        if message.is_empty() {
+            return Err(SendMessageError::NoMessageError)
+        }
+
+        let mut friends = self.friends.write();
+        let friend = friends.get_mut(&friend_pk).ok_or(SendMessageError::NoFriendError)?;
+
+        let mut next_message_id = self.next_message_id.write();
+        let message_id = *next_message_id;
+        *next_message_id = next_message_id.wrapping_add(1);
+
+        friend.outbox.push(message_id, kind, &message);
+        Ok(message_id)
+    }
+
+    /// Get snapshot of all not delivered chat messages that can be restored
+    /// with `restore_outbox` after restart.
+    pub fn outbox_snapshot(&self) -> OutboxSnapshot {
+        let entries = self.friends.read().iter()
+            .flat_map(|(&pk, friend)| friend.outbox.snapshot_entries(pk))
+            .collect();
+        OutboxSnapshot { entries }
+    }
+
+    /// Restore not delivered chat messages from snapshot. Friends should be
+    /// added before this call, messages to unknown friends are skipped.
+    pub fn restore_outbox(&self, snapshot: &OutboxSnapshot) {
+        let mut friends = self.friends.write();
+        let mut next_message_id = self.next_message_id.write();
+        for entry in &snapshot.entries {
+            if let Some(friend) = friends.get_mut(&entry.pk) {
+                friend.outbox.restore_entry(entry);
+                if entry.message_id >= *next_message_id {
+                    *next_message_id = entry.message_id.wrapping_add(1);
+                }
+            }
+        }
+    }
+
+    /// Serialize messenger packet and send it to a friend via `net_crypto`.
+    pub fn send_packet(&self, friend_pk: PublicKey, packet: &Packet) -> IoFuture<()> {
+        match serialize_packet(packet) {
+            Ok(data) => self.net_crypto.send_lossless(friend_pk, data),
+            Err(e) => Box::new(future::err(e)),
+        }
+    }
+
+    /// Serialize messenger packet and send it to a friend via `net_crypto`
+    /// getting the id of the lossless packet.
+    fn send_packet_numbered(&self, friend_pk: PublicKey, packet: &Packet) -> IoFuture<LosslessPacketId> {
+        match serialize_packet(packet) {
+            Ok(data) => self.net_crypto.send_lossless_numbered(friend_pk, data),
+            Err(e) => Box::new(future::err(e)),
+        }
+    }
+
+    /// Send messenger packet to a friend if it's online.
+    fn send_to_online_friend(&self, friend_pk: PublicKey, packet: &Packet) -> IoFuture<()> {
+        if self.is_friend_online(friend_pk) {
+            self.send_packet(friend_pk, packet)
+        } else {
+            Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                format!("Friend {:?} is not online", friend_pk)
+            )))
+        }
+    }
+
+    /// Send messenger packet to all online friends. Sending errors are
+    /// ignored since friends might go offline in the meantime.
+    fn send_to_online_friends(&self, packet: &Packet) -> IoFuture<()> {
+        let futures = self.friends.read().iter()
+            .filter(|&(_, friend)| friend.online)
+            .map(|(&friend_pk, _)| self.send_packet(friend_pk, packet).then(|_| Ok(())))
+            .collect::<Vec<_>>();
+        Box::new(future::join_all(futures).map(|_: Vec<()>| ()))
+    }
+
+    /// Handle friend request that passed all checks of friend requests
+    /// module.
+    pub fn handle_friend_request(&self, pk: PublicKey, message: Vec<u8>) -> IoFuture<()> {
+        if self.has_friend(pk) {
+            debug!("Dropping friend request from existing friend {:?}", pk);
+            return Box::new(future::ok(()))
+        }
+
+        let message = String::from_utf8_lossy(&message).into_owned();
+        self.send_event(Event::FriendRequest { pk, message })
+    }
+
+    /// Handle status change of friend connection. When the connection becomes
+    /// established we send `Online` packet and our info to the friend. The
+    /// friend is considered online only when we receive `Online` packet from
+    /// it.
+    pub fn handle_connection_status(&self, friend_pk: PublicKey, status: bool) -> IoFuture<()> {
+        if !self.has_friend(friend_pk) {
+            return Box::new(future::ok(()))
+        }
+
+        if status {
+            self.send_online(friend_pk)
+        } else {
+            self.set_friend_offline(friend_pk)
+        }
+    }
+
+    /// Send `Online` packet and our info to the friend.
+    fn send_online(&self, friend_pk: PublicKey) -> IoFuture<()> {
+        let packets = vec![
+            Packet::Online(Online),
+            Packet::Nickname(Nickname::new(self.name())),
+            Packet::StatusMessage(StatusMessage::new(self.status_message())),
+            Packet::UserStatus(UserStatus::new(self.status())),
+        ];
+        // sending errors are not fatal since the connection might be lost
+        // in the meantime
+        let futures = packets.iter()
+            .map(|packet| self.send_packet(friend_pk, packet).then(|_| Ok(())))
+            .collect::<Vec<_>>();
+        Box::new(future::join_all(futures).map(|_: Vec<()>| ()))
+    }
+
+    /// Mark the friend as offline and report it if it was online.
+    fn set_friend_offline(&self, friend_pk: PublicKey) -> IoFuture<()> {
+        let was_online = if let Some(friend) = self.friends.write().get_mut(&friend_pk) {
+            let was_online = friend.online;
+            friend.online = false;
+            friend.is_typing = false;
+            // packet numbers are valid only for the lost connection
+            friend.outbox.reset();
+            was_online
+        } else {
+            false
+        };
+
+        let files_future = self.file_transfers.handle_friend_offline(friend_pk);
+        let calls_future = self.msi.handle_friend_offline(friend_pk);
+        if was_online {
+            let event_future = self.send_event(Event::FriendConnectionStatus { pk: friend_pk, online: false });
+            Box::new(event_future.join3(files_future, calls_future).map(|_| ()))
+        } else {
+            Box::new(files_future.join(calls_future).map(|_| ()))
+        }
+    }
+
+    /// Handle lossless packet received from a friend via `net_crypto`.
+    pub fn handle_lossless(&self, friend_pk: PublicKey, data: &[u8]) -> IoFuture<()> {
+        if !self.has_friend(friend_pk) {
+            return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                format!("Messenger packet from unknown friend {:?}", friend_pk)
+            )))
+        }
+
+        match Packet::from_bytes(data) {
+            IResult::Done(_, Packet::Online(_)) => self.handle_online(friend_pk),
+            IResult::Done(_, Packet::Offline(_)) => self.set_friend_offline(friend_pk),
+            IResult::Done(_, Packet::Nickname(packet)) => self.handle_nickname(friend_pk, packet),
+            IResult::Done(_, Packet::StatusMessage(packet)) => self.handle_status_message(friend_pk, packet),
+            IResult::Done(_, Packet::UserStatus(packet)) => self.handle_user_status(friend_pk, packet),
+            IResult::Done(_, Packet::Typing(packet)) => self.handle_typing(friend_pk, packet),
+            IResult::Done(_, Packet::Message(packet)) =>
+                self.send_event(Event::FriendMessage { pk: friend_pk, message: packet.message }),
+            IResult::Done(_, Packet::Action(packet)) =>
+                self.send_event(Event::FriendAction { pk: friend_pk, action: packet.action }),
+            IResult::Done(_, Packet::FileSendRequest(packet)) =>
+                self.file_transfers.handle_file_send_request(friend_pk, packet),
+            IResult::Done(_, Packet::FileControl(packet)) =>
+                self.file_transfers.handle_file_control(friend_pk, packet),
+            IResult::Done(_, Packet::FileData(packet)) =>
+                self.file_transfers.handle_file_data(friend_pk, packet),
+            IResult::Done(_, Packet::MsiPacket(packet)) =>
+                self.msi.handle_msi_packet(friend_pk, packet),
+            IResult::Done(_, packet) => {
+                debug!("Unhandled messenger packet from {:?}: {:?}", friend_pk, packet);
+                Box::new(future::ok(()))
+            },
+            _ => Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                "Failed to parse messenger packet"
+            ))),
+        }
+    }
+
+    /// Handle `Online` packet marking the friend as online. It also means
+    /// that the friend accepted our friend request. Not delivered messages
+    /// will be sent by the main loop. Our `Online` packet and info are sent
+    /// again since the friend could drop them if it added us after the
+    /// connection was established.
+    fn handle_online(&self, friend_pk: PublicKey) -> IoFuture<()> {
+        let was_online = if let Some(friend) = self.friends.write().get_mut(&friend_pk) {
+            let was_online = friend.online;
+            friend.online = true;
+            if !was_online {
+                friend.outbox.reset();
+                self.file_transfers.handle_friend_online(friend_pk);
+            }
+            was_online
+        } else {
+            true
+        };
+
+        self.friend_requests.handle_accepted(friend_pk);
+
+        if was_online {
+            Box::new(future::ok(()))
+        } else {
+            let event_future = self.send_event(Event::FriendConnectionStatus { pk: friend_pk, online: true });
+            Box::new(event_future.join(self.send_online(friend_pk)).map(|_| ()))
+        }
+    }
+
+    /// Handle `Nickname` packet reporting the name if it was changed.
+    fn handle_nickname(&self, friend_pk: PublicKey, packet: Nickname) -> IoFuture<()> {
+        let changed = self.friends.write().get_mut(&friend_pk).map_or(false, |friend| {
+            let changed = friend.name != packet.nickname;
+            friend.name = packet.nickname.clone();
+            changed
+        });
+
+        if changed {
+            self.send_event(Event::FriendName { pk: friend_pk, name: packet.nickname })
+        } else {
+            Box::new(future::ok(()))
+        }
+    }
+
+    /// Handle `StatusMessage` packet reporting the status message if it was
+    /// changed.
+    fn handle_status_message(&self, friend_pk: PublicKey, packet: StatusMessage) -> IoFuture<()> {
+        let changed = self.friends.write().get_mut(&friend_pk).map_or(false, |friend| {
+            let changed = friend.status_message != packet.message;
+            friend.status_message = packet.message.clone();
+            changed
+        });
+
+        if changed {
+            self.send_event(Event::FriendStatusMessage { pk: friend_pk, message: packet.message })
+        } else {
+            Box::new(future::ok(()))
+        }
+    }
+
+    /// Handle `UserStatus` packet reporting the status if it was changed.
+    fn handle_user_status(&self, friend_pk: PublicKey, packet: UserStatus) -> IoFuture<()> {
+        let changed = self.friends.write().get_mut(&friend_pk).map_or(false, |friend| {
+            let changed = friend.status != packet.status;
+            friend.status = packet.status;
+            changed
+        });
+
+        if changed {
+            self.send_event(Event::FriendStatus { pk: friend_pk, status: packet.status })
+        } else {
+            Box::new(future::ok(()))
+        }
+    }
+
+    /// Handle `Typing` packet reporting the typing state if it was changed.
+    fn handle_typing(&self, friend_pk: PublicKey, packet: Typing) -> IoFuture<()> {
+        let changed = self.friends.write().get_mut(&friend_pk).map_or(false, |friend| {
+            let changed = friend.is_typing != packet.is_typing;
+            friend.is_typing = packet.is_typing;
+            changed
+        });
+
+        if changed {
+            self.send_event(Event::FriendTyping { pk: friend_pk, is_typing: packet.is_typing })
+        } else {
+            Box::new(future::ok(()))
+        }
+    }
+
+    /// Send queued chat messages to online friends and report messages that
+    /// were delivered completely. Then file data is sent if there is room for
+    /// it in crypto connections.
+    fn main_loop(&self) -> IoFuture<()> {
+        let mut friends = self.friends.write();
+        let mut futures = Vec::new();
+
+        for (&friend_pk, friend) in friends.iter_mut() {
+            if !friend.online || friend.outbox.is_empty() {
+                continue
+            }
+
+            let net_crypto = &self.net_crypto;
+            let delivered = friend.outbox.pop_delivered(|packet_id|
+                net_crypto.is_lossless_delivered(friend_pk, packet_id)
+            );
+            for message_id in delivered {
+                futures.push(self.send_event(Event::MessageReceipt { pk: friend_pk, message_id }));
+            }
+
+            for (message_id, part, packet) in friend.outbox.take_unsent() {
+                let friends = self.friends.clone();
+                let future = self.send_packet_numbered(friend_pk, &packet).then(move |result| {
+                    if let Some(friend) = friends.write().get_mut(&friend_pk) {
+                        match result {
+                            Ok(packet_id) => friend.outbox.set_sent(message_id, part, packet_id),
+                            Err(e) => {
+                                debug!("Failed to send message to {:?}: {}", friend_pk, e);
+                                friend.outbox.set_unsent(message_id, part);
+                            },
+                        }
+                    }
+                    Ok(())
+                });
+                futures.push(Box::new(future));
+            }
+        }
+
+        // chat messages are queued first so file data can't take their place
+        futures.push(self.file_transfers.main_loop());
+        futures.push(self.msi.main_loop());
+
+        Box::new(future::join_all(futures).map(|_| ()))
+    }
+
+    /// Run the main loop periodically.
+    pub fn run(self) -> IoFuture<()> {
+        let interval = Duration::from_millis(MAIN_LOOP_INTERVAL);
+        let wakeups = Interval::new(Instant::now(), interval);
+        let future = wakeups
+            .map_err(|e| Error::new(ErrorKind::Other, format!("Messenger timer error: {:?}", e)))
+            .for_each(move |_instant| {
+                trace!("Messenger wake up");
+                self.main_loop()
+            });
+
+        Box::new(future)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::Stream;
+
+    use toxcore::dht::precomputed_cache::PrecomputedCache;
+    use toxcore::dht::server::Server as DhtServer;
+    use toxcore::net_crypto::NetCryptoNewArgs;
+    use toxcore::onion::client::OnionClient;
+    use toxcore::tcp::connections::Connections;
+    use toxcore::toxid::NoSpam;
+
+    fn create_messenger() -> (Messenger, mpsc::UnboundedReceiver<Event>) {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx: udp_tx.clone(),
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            precomputed_keys,
+        });
+        let onion_client = OnionClient::new(DhtServer::new(udp_tx, dht_pk, dht_sk), real_pk, real_sk.clone());
+        let (incoming_tx, _incoming_rx) = mpsc::unbounded();
+        let tcp_connections = Connections::new(real_pk, real_sk, incoming_tx);
+        let friend_connections = FriendConnections::new(net_crypto.clone(), tcp_connections);
+        let friend_requests = FriendRequests::new(real_pk, NoSpam::new(), friend_connections.clone(), onion_client);
+        let (event_tx, event_rx) = mpsc::unbounded();
+        let mut messenger = Messenger::new(net_crypto, friend_connections, friend_requests);
+        messenger.set_event_sink(event_tx);
+        (messenger, event_rx)
+    }
+
+    fn encode(packet: &Packet) -> Vec<u8> {
+        let mut buf = [0; MAX_CRYPTO_DATA_SIZE];
+        let (_, size) = packet.to_bytes((&mut buf, 0)).unwrap();
+        buf[..size].to_vec()
+    }
+
+    #[test]
+    fn add_remove_friend() {
+        let (messenger, _event_rx) = create_messenger();
+        let tox_id = ToxId::new(gen_keypair().0);
+
+        messenger.add_friend(tox_id, "Hello".to_owned()).unwrap();
+        assert!(messenger.has_friend(tox_id.pk));
+        assert!(messenger.friend_requests.is_pending(tox_id.pk));
+        assert_eq!(messenger.friends(), vec![tox_id.pk]);
+
+        messenger.remove_friend(tox_id.pk).wait().unwrap();
+        assert!(!messenger.has_friend(tox_id.pk));
+        assert!(!messenger.friend_requests.is_pending(tox_id.pk));
+        assert!(messenger.remove_friend(tox_id.pk).wait().is_err());
+    }
+
+    #[test]
+    fn add_friend_invalid() {
+        let (messenger, _event_rx) = create_messenger();
+        let tox_id = ToxId::new(gen_keypair().0);
+
+        assert_eq!(
+            messenger.add_friend(tox_id, String::new()),
+            Err(SendFriendRequestError::NoMessageError)
+        );
+        assert!(!messenger.has_friend(tox_id.pk));
+    }
+
+    #[test]
+    fn handle_friend_request() {
+        let (messenger, event_rx) = create_messenger();
+        let pk = gen_keypair().0;
+        let friend_pk = gen_keypair().0;
+        messenger.add_friend_norequest(friend_pk);
+
+        messenger.handle_friend_request(pk, b"Hello".to_vec()).wait().unwrap();
+        // requests from existing friends are dropped
+        messenger.handle_friend_request(friend_pk, b"Hello".to_vec()).wait().unwrap();
+
+        drop(messenger);
+        let events = event_rx.collect().wait().unwrap();
+        assert_eq!(events, vec![Event::FriendRequest { pk, message: "Hello".to_owned() }]);
+    }
+
+    #[test]
+    fn handle_online_offline() {
+        let (messenger, event_rx) = create_messenger();
+        let tox_id = ToxId::new(gen_keypair().0);
+        messenger.add_friend(tox_id, "Hello".to_owned()).unwrap();
+
+        messenger.handle_connection_status(tox_id.pk, true).wait().unwrap();
+        // friend isn't online until it sends Online packet
+        assert!(!messenger.is_friend_online(tox_id.pk));
+
+        messenger.handle_lossless(tox_id.pk, &encode(&Packet::Online(Online))).wait().unwrap();
+        assert!(messenger.is_friend_online(tox_id.pk));
+        assert!(!messenger.friend_requests.is_pending(tox_id.pk));
+        // the same status shouldn't be reported twice
+        messenger.handle_lossless(tox_id.pk, &encode(&Packet::Online(Online))).wait().unwrap();
+
+        messenger.handle_lossless(tox_id.pk, &encode(&Packet::Offline(Offline))).wait().unwrap();
+        assert!(!messenger.is_friend_online(tox_id.pk));
+
+        messenger.handle_lossless(tox_id.pk, &encode(&Packet::Online(Online))).wait().unwrap();
+        messenger.handle_connection_status(tox_id.pk, false).wait().unwrap();
+        assert!(!messenger.is_friend_online(tox_id.pk));
+
+        drop(messenger);
+        let events = event_rx.collect().wait().unwrap();
+        assert_eq!(events, vec![
+            Event::FriendConnectionStatus { pk: tox_id.pk, online: true },
+            Event::FriendConnectionStatus { pk: tox_id.pk, online: false },
+            Event::FriendConnectionStatus { pk: tox_id.pk, online: true },
+            Event::FriendConnectionStatus { pk: tox_id.pk, online: false },
+        ]);
+    }
+
+    #[test]
+    fn handle_friend_info() {
+        let (messenger, event_rx) = create_messenger();
+        let friend_pk = gen_keypair().0;
+        messenger.add_friend_norequest(friend_pk);
+
+        let packets = vec![
+            Packet::Nickname(Nickname::new("Alice".to_owned())),
+            // unchanged values aren't reported
+            Packet::Nickname(Nickname::new("Alice".to_owned())),
+            Packet::StatusMessage(StatusMessage::new("Working".to_owned())),
+            Packet::UserStatus(UserStatus::new(PeerStatus::Busy)),
+            Packet::Typing(Typing::new(true)),
+        ];
+        for packet in &packets {
+            messenger.handle_lossless(friend_pk, &encode(packet)).wait().unwrap();
+        }
+
+        assert_eq!(messenger.friend_name(friend_pk), Some("Alice".to_owned()));
+        assert_eq!(messenger.friend_status_message(friend_pk), Some("Working".to_owned()));
+        assert_eq!(messenger.friend_status(friend_pk), Some(PeerStatus::Busy));
+        assert!(messenger.is_friend_typing(friend_pk));
+
+        drop(messenger);
+        let events = event_rx.collect().wait().unwrap();
+        assert_eq!(events, vec![
+            Event::FriendName { pk: friend_pk, name: "Alice".to_owned() },
+            Event::FriendStatusMessage { pk: friend_pk, message: "Working".to_owned() },
+            Event::FriendStatus { pk: friend_pk, status: PeerStatus::Busy },
+            Event::FriendTyping { pk: friend_pk, is_typing: true },
+        ]);
+    }
+
+    #[test]
+    fn handle_message_and_action() {
+        let (messenger, event_rx) = create_messenger();
+        let friend_pk = gen_keypair().0;
+        messenger.add_friend_norequest(friend_pk);
+
+        let packet = Packet::Message(Message::new("Hi".to_owned()));
+        messenger.handle_lossless(friend_pk, &encode(&packet)).wait().unwrap();
+        let packet = Packet::Action(Action::new("waves".to_owned()));
+        messenger.handle_lossless(friend_pk, &encode(&packet)).wait().unwrap();
+
+        drop(messenger);
+        let events = event_rx.collect().wait().unwrap();
+        assert_eq!(events, vec![
+            Event::FriendMessage { pk: friend_pk, message: "Hi".to_owned() },
+            Event::FriendAction { pk: friend_pk, action: "waves".to_owned() },
+        ]);
+    }
+
+    #[test]
+    fn handle_lossless_invalid() {
+        let (messenger, _event_rx) = create_messenger();
+        let friend_pk = gen_keypair().0;
+
+        let data = encode(&Packet::Online(Online));
+        // packets from unknown friends are rejected
+        assert!(messenger.handle_lossless(friend_pk, &data).wait().is_err());
+
+        messenger.add_friend_norequest(friend_pk);
+        assert!(messenger.handle_lossless(friend_pk, &[0xff]).wait().is_err());
+    }
+
+    #[test]
+    fn handle_lossless_file_transfer() {
+        let (messenger, event_rx) = create_messenger();
+        let friend_pk = gen_keypair().0;
+        messenger.add_friend_norequest(friend_pk);
+
+        let request = FileSendRequest::new(1, FileType::Data, 4096, [42; FILE_UID_BYTES], "file.txt".to_owned());
+        let data = encode(&Packet::FileSendRequest(request));
+        // file requests are accepted only from online friends
+        assert!(messenger.handle_lossless(friend_pk, &data).wait().is_err());
+
+        messenger.handle_lossless(friend_pk, &encode(&Packet::Online(Online))).wait().unwrap();
+        messenger.handle_lossless(friend_pk, &data).wait().unwrap();
+        // transfers are broken when the friend goes offline
+        messenger.handle_lossless(friend_pk, &encode(&Packet::Offline(Offline))).wait().unwrap();
+
+        drop(messenger);
+        let events = event_rx.collect().wait().unwrap();
+        assert_eq!(events, vec![
+            Event::FriendConnectionStatus { pk: friend_pk, online: true },
+            Event::FileRequest {
+                pk: friend_pk,
+                file_id: 1,
+                file_type: FileType::Data,
+                file_size: 4096,
+                file_name: "file.txt".to_owned(),
+                file_unique_id: [42; FILE_UID_BYTES],
+            },
+            Event::FriendConnectionStatus { pk: friend_pk, online: false },
+            Event::File {
+                pk: friend_pk,
+                direction: TransferDirection::Receive,
+                file_id: 1,
+                event: FileEvent::Broken { position: 0 },
+            },
+        ]);
+    }
+
+    #[test]
+    fn handle_lossless_msi() {
+        let (messenger, event_rx) = create_messenger();
+        let friend_pk = gen_keypair().0;
+        messenger.add_friend_norequest(friend_pk);
+
+        messenger.handle_lossless(friend_pk, &encode(&Packet::Online(Online))).wait().unwrap();
+        let packet = MsiPacket::new(RequestKind::Init, None, Some(msi::CAPABILITY_SEND_AUDIO));
+        messenger.handle_lossless(friend_pk, &encode(&Packet::MsiPacket(packet))).wait().unwrap();
+        assert_eq!(messenger.msi().call_state(friend_pk), Some(msi::CallState::Requested));
+        // calls are ended when the friend goes offline
+        messenger.handle_lossless(friend_pk, &encode(&Packet::Offline(Offline))).wait().unwrap();
+        assert_eq!(messenger.msi().call_state(friend_pk), None);
+
+        drop(messenger);
+        let events = event_rx.collect().wait().unwrap();
+        assert_eq!(events, vec![
+            Event::FriendConnectionStatus { pk: friend_pk, online: true },
+            Event::Call { pk: friend_pk, event: CallEvent::Invite { capabilities: msi::CAPABILITY_SEND_AUDIO } },
+            Event::FriendConnectionStatus { pk: friend_pk, online: false },
+            Event::Call { pk: friend_pk, event: CallEvent::Ended },
+        ]);
+    }
+
+    #[test]
+    fn set_own_info() {
+        let (messenger, _event_rx) = create_messenger();
+
+        messenger.set_name("Bob".to_owned()).wait().unwrap();
+        messenger.set_status_message("Away from keyboard".to_owned()).wait().unwrap();
+        messenger.set_status(PeerStatus::Away).wait().unwrap();
+        assert_eq!(messenger.name(), "Bob");
+        assert_eq!(messenger.status_message(), "Away from keyboard");
+        assert_eq!(messenger.status(), PeerStatus::Away);
+
+        let long_name = String::from_utf8(vec![32; MAX_NICKNAME_DATA_SIZE + 1]).unwrap();
+        assert!(messenger.set_name(long_name).wait().is_err());
+        assert_eq!(messenger.name(), "Bob");
+        let long_message = String::from_utf8(vec![32; MAX_STATUS_MESSAGE_DATA_SIZE + 1]).unwrap();
+        assert!(messenger.set_status_message(long_message).wait().is_err());
+    }
+
+    #[test]
+    fn send_message_errors() {
+        let (messenger, _event_rx) = create_messenger();
+        let friend_pk = gen_keypair().0;
+
+        assert_eq!(messenger.send_message(friend_pk, MessageKind::Normal, "Hi".to_owned()), Err(SendMessageError::NoFriendError));
+        messenger.add_friend_norequest(friend_pk);
+        assert_eq!(messenger.send_message(friend_pk, MessageKind::Normal, String::new()), Err(SendMessageError::NoMessageError));
+        assert!(messenger.outbox_snapshot().entries.is_empty());
+    }
+
+    #[test]
+    fn send_message_offline() {
+        let (messenger, _event_rx) = create_messenger();
+        let friend_pk = gen_keypair().0;
+        messenger.add_friend_norequest(friend_pk);
+
+        let message_id = messenger.send_message(friend_pk, MessageKind::Normal, "Hi".to_owned()).unwrap();
+        let action_id = messenger.send_message(friend_pk, MessageKind::Action, "waves".to_owned()).unwrap();
+        assert_ne!(message_id, action_id);
+
+        // friend is offline so messages stay in the outbox
+        messenger.main_loop().wait().unwrap();
+
+        let snapshot = messenger.outbox_snapshot();
+        assert_eq!(snapshot.entries.len(), 2);
+        assert_eq!(snapshot.entries[0].message_id, message_id);
+        assert_eq!(snapshot.entries[0].kind, MessageKind::Normal);
+        assert_eq!(snapshot.entries[1].message_id, action_id);
+        assert_eq!(snapshot.entries[1].kind, MessageKind::Action);
+    }
+
+    #[test]
+    fn send_message_without_connection() {
+        let (messenger, event_rx) = create_messenger();
+        let friend_pk = gen_keypair().0;
+        messenger.add_friend_norequest(friend_pk);
+        messenger.handle_lossless(friend_pk, &encode(&Packet::Online(Online))).wait().unwrap();
+
+        let message_id = messenger.send_message(friend_pk, MessageKind::Normal, "Hi".to_owned()).unwrap();
+
+        // there is no crypto connection so sending fails and the message
+        // will be resent later
+        messenger.main_loop().wait().unwrap();
+
+        let snapshot = messenger.outbox_snapshot();
+        assert_eq!(snapshot.entries.len(), 1);
+        assert_eq!(snapshot.entries[0].message_id, message_id);
+
+        drop(messenger);
+        let events = event_rx.collect().wait().unwrap();
+        assert!(!events.contains(&Event::MessageReceipt { pk: friend_pk, message_id }));
+    }
+
+    #[test]
+    fn restore_outbox() {
+        let (messenger, _event_rx) = create_messenger();
+        let friend_pk = gen_keypair().0;
+        let unknown_pk = gen_keypair().0;
+        messenger.add_friend_norequest(friend_pk);
+
+        let snapshot = OutboxSnapshot {
+            entries: vec![
+                OutboxSnapshotEntry {
+                    pk: friend_pk,
+                    message_id: 7,
+                    kind: MessageKind::Normal,
+                    last: true,
+                    text: "Hi".to_owned(),
+                },
+                OutboxSnapshotEntry {
+                    pk: unknown_pk,
+                    message_id: 8,
+                    kind: MessageKind::Normal,
+                    last: true,
+                    text: "Hello".to_owned(),
+                },
+            ],
+        };
+        messenger.restore_outbox(&snapshot);
+
+        let restored = messenger.outbox_snapshot();
+        assert_eq!(restored.entries, vec![snapshot.entries[0].clone()]);
+
+        // new messages get ids after restored ones
+        let message_id = messenger.send_message(friend_pk, MessageKind::Normal, "Bye".to_owned()).unwrap();
+        assert_eq!(message_id, 8);
+    }
+}
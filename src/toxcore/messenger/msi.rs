@@ -0,0 +1,641 @@
+/*! MSI (Media Session Interface) call signalling
+
+MSI protocol is used to start, answer, change and end audio/video calls. It
+doesn't carry any media, it only lets friends agree on the state of a call
+and on capabilities of each side, so the application can drive its own media
+pipeline.
+
+The caller sends `Init` request with its capabilities. The callee answers
+with `Push` request with its own capabilities and the call becomes active.
+`Push` request is also used by both sides to change capabilities of an
+active call. Any side ends the call or rejects it with `Pop` request. `Pop`
+request with error header reports that the last request was not valid and
+ends the call as well.
+
+There can be only one call with a friend at a time. Calls that are not
+answered for `CALL_TIMEOUT` seconds are ended by the main loop. Calls are
+ended as well when the friend goes offline.
+
+*/
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::{future, Future};
+use parking_lot::RwLock;
+
+use toxcore::crypto_core::*;
+use toxcore::io_tokio::*;
+use toxcore::messenger::{serialize_packet, Event, EventTx};
+use toxcore::messenger::packet::*;
+use toxcore::net_crypto::NetCrypto;
+use toxcore::time::*;
+
+/// Capability flag: the side sends audio.
+pub const CAPABILITY_SEND_AUDIO: u8 = 4;
+
+/// Capability flag: the side sends video.
+pub const CAPABILITY_SEND_VIDEO: u8 = 8;
+
+/// Capability flag: the side accepts audio.
+pub const CAPABILITY_RECEIVE_AUDIO: u8 = 16;
+
+/// Capability flag: the side accepts video.
+pub const CAPABILITY_RECEIVE_VIDEO: u8 = 32;
+
+/// Calls that are not answered for this amount of seconds are ended.
+pub const CALL_TIMEOUT: u64 = 30;
+
+/// State of a call with a friend.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CallState {
+    /// We called the friend and wait for the answer.
+    Requesting,
+    /// The friend called us and waits for our answer.
+    Requested,
+    /// The call is answered and media can be exchanged.
+    Active,
+}
+
+/// Event that happened with a call.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CallEvent {
+    /// Friend calls us. The call can be answered with `Msi::answer` or
+    /// rejected with `Msi::hang_up`.
+    Invite {
+        /// Capabilities of the friend
+        capabilities: u8,
+    },
+    /// Friend answered our call.
+    Started {
+        /// Capabilities of the friend
+        capabilities: u8,
+    },
+    /// Friend changed its capabilities during the call.
+    Capabilities {
+        /// New capabilities of the friend
+        capabilities: u8,
+    },
+    /// Friend ended or rejected the call or went offline.
+    Ended,
+    /// Friend reported an error and the call is ended.
+    Error {
+        /// The reported error
+        error: MsiErrorKind,
+    },
+    /// The call was not answered in time and is ended.
+    TimedOut,
+}
+
+/// Call with a friend.
+#[derive(Clone, Debug)]
+struct Call {
+    /// State of the call.
+    state: CallState,
+    /// Our capabilities.
+    self_capabilities: u8,
+    /// Capabilities of the friend.
+    peer_capabilities: u8,
+    /// Time when the call was started or requested.
+    start_time: Instant,
+}
+
+impl Call {
+    /// Create new `Call` in the given state.
+    fn new(state: CallState, self_capabilities: u8, peer_capabilities: u8) -> Call {
+        Call {
+            state,
+            self_capabilities,
+            peer_capabilities,
+            start_time: clock_now(),
+        }
+    }
+
+    /// Check if the call was not answered in time.
+    fn is_timed_out(&self) -> bool {
+        self.state != CallState::Active &&
+            clock_elapsed(self.start_time) >= Duration::from_secs(CALL_TIMEOUT)
+    }
+}
+
+/// What should be done after handling received msi packet.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+struct Outcome {
+    /// Packet that should be sent to the friend in reply.
+    reply: Option<MsiPacket>,
+    /// Event that should be reported.
+    event: Option<CallEvent>,
+}
+
+impl Outcome {
+    /// Reply with `Pop` request carrying the error.
+    fn error(error: MsiErrorKind) -> Outcome {
+        Outcome {
+            reply: Some(MsiPacket::new(RequestKind::Pop, Some(error), None)),
+            event: None,
+        }
+    }
+
+    /// Report the event without replying.
+    fn event(event: CallEvent) -> Outcome {
+        Outcome {
+            reply: None,
+            event: Some(event),
+        }
+    }
+}
+
+/// Struct that keeps calls with friends and drives their state machines.
+#[derive(Clone)]
+pub struct Msi {
+    /// `NetCrypto` module that is used to send msi packets.
+    net_crypto: NetCrypto,
+    /// Calls by long term `PublicKey` of friends.
+    calls: Arc<RwLock<HashMap<PublicKey, Call>>>,
+    /// Sink to send call events.
+    event_tx: Option<EventTx>,
+}
+
+impl Msi {
+    /// Create new `Msi` object.
+    pub fn new(net_crypto: NetCrypto) -> Msi {
+        Msi {
+            net_crypto,
+            calls: Arc::new(RwLock::new(HashMap::new())),
+            event_tx: None,
+        }
+    }
+
+    /// Set sink to send call events.
+    pub fn set_event_sink(&mut self, event_tx: EventTx) {
+        self.event_tx = Some(event_tx);
+    }
+
+    /// Send call event to the sink if it's set.
+    fn send_event(&self, friend_pk: PublicKey, event: CallEvent) -> IoFuture<()> {
+        if let Some(ref event_tx) = self.event_tx {
+            send_to(event_tx, Event::Call { pk: friend_pk, event })
+        } else {
+            Box::new(future::ok(()))
+        }
+    }
+
+    /// Serialize msi packet and send it to a friend via `net_crypto`.
+    fn send_packet(&self, friend_pk: PublicKey, packet: MsiPacket) -> IoFuture<()> {
+        match serialize_packet(&Packet::MsiPacket(packet)) {
+            Ok(data) => self.net_crypto.send_lossless(friend_pk, data),
+            Err(e) => Box::new(future::err(e)),
+        }
+    }
+
+    /// Send request to a friend. The call is removed if the request can't be
+    /// sent since the friend can't receive further requests anyway.
+    fn send_request(&self, friend_pk: PublicKey, packet: MsiPacket) -> IoFuture<()> {
+        let calls = self.calls.clone();
+        let future = self.send_packet(friend_pk, packet).map_err(move |e| {
+            calls.write().remove(&friend_pk);
+            e
+        });
+        Box::new(future)
+    }
+
+    /// Get state of the call with a friend.
+    pub fn call_state(&self, friend_pk: PublicKey) -> Option<CallState> {
+        self.calls.read().get(&friend_pk).map(|call| call.state)
+    }
+
+    /// Get capabilities of the friend we have a call with.
+    pub fn peer_capabilities(&self, friend_pk: PublicKey) -> Option<u8> {
+        self.calls.read().get(&friend_pk).map(|call| call.peer_capabilities)
+    }
+
+    /// Get our capabilities in the call with a friend.
+    pub fn self_capabilities(&self, friend_pk: PublicKey) -> Option<u8> {
+        self.calls.read().get(&friend_pk).map(|call| call.self_capabilities)
+    }
+
+    /// Call a friend. `Started` event is sent when the friend answers.
+    pub fn call(&self, friend_pk: PublicKey, capabilities: u8) -> IoFuture<()> {
+        {
+            let mut calls = self.calls.write();
+            if calls.contains_key(&friend_pk) {
+                return Box::new(future::err(Error::new(
+                    ErrorKind::Other,
+                    format!("Already in a call with {:?}", friend_pk)
+                )))
+            }
+            calls.insert(friend_pk, Call::new(CallState::Requesting, capabilities, 0));
+        }
+
+        self.send_request(friend_pk, MsiPacket::new(RequestKind::Init, None, Some(capabilities)))
+    }
+
+    /// Answer a call from a friend.
+    pub fn answer(&self, friend_pk: PublicKey, capabilities: u8) -> IoFuture<()> {
+        match self.calls.write().get_mut(&friend_pk) {
+            Some(ref mut call) if call.state == CallState::Requested => {
+                call.state = CallState::Active;
+                call.self_capabilities = capabilities;
+            },
+            _ => return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                format!("No incoming call from {:?}", friend_pk)
+            ))),
+        }
+
+        self.send_request(friend_pk, MsiPacket::new(RequestKind::Push, None, Some(capabilities)))
+    }
+
+    /// End the call with a friend or reject the call from it.
+    pub fn hang_up(&self, friend_pk: PublicKey) -> IoFuture<()> {
+        if self.calls.write().remove(&friend_pk).is_none() {
+            return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                format!("No call with {:?}", friend_pk)
+            )))
+        }
+
+        self.send_packet(friend_pk, MsiPacket::new(RequestKind::Pop, None, None))
+    }
+
+    /// Change our capabilities in the active call with a friend, e.g. to
+    /// stop sending video.
+    pub fn change_capabilities(&self, friend_pk: PublicKey, capabilities: u8) -> IoFuture<()> {
+        match self.calls.write().get_mut(&friend_pk) {
+            Some(ref mut call) if call.state == CallState::Active => call.self_capabilities = capabilities,
+            _ => return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                format!("No active call with {:?}", friend_pk)
+            ))),
+        }
+
+        self.send_request(friend_pk, MsiPacket::new(RequestKind::Push, None, Some(capabilities)))
+    }
+
+    /// Handle `MsiPacket` received from a friend. Invalid requests are
+    /// answered with errors and end the call.
+    pub fn handle_msi_packet(&self, friend_pk: PublicKey, packet: MsiPacket) -> IoFuture<()> {
+        let outcome = self.update_call(friend_pk, &packet);
+
+        let reply_future: IoFuture<()> = if let Some(reply) = outcome.reply {
+            // sending errors are not fatal since the friend might go offline
+            // in the meantime
+            Box::new(self.send_packet(friend_pk, reply).then(|_| Ok(())))
+        } else {
+            Box::new(future::ok(()))
+        };
+        let event_future = if let Some(event) = outcome.event {
+            self.send_event(friend_pk, event)
+        } else {
+            Box::new(future::ok(()))
+        };
+        Box::new(reply_future.join(event_future).map(|_| ()))
+    }
+
+    /// Update the call with a friend according to received packet.
+    fn update_call(&self, friend_pk: PublicKey, packet: &MsiPacket) -> Outcome {
+        let mut calls = self.calls.write();
+
+        if let Some(error) = packet.error {
+            return if calls.remove(&friend_pk).is_some() {
+                Outcome::event(CallEvent::Error { error })
+            } else {
+                Outcome::default()
+            }
+        }
+
+        let state = calls.get(&friend_pk).map(|call| call.state);
+        match (packet.request, packet.capabilities, state) {
+            // `Pop` for unknown call is ignored so that two sides hanging up
+            // at the same time don't report errors to each other
+            (RequestKind::Pop, _, None) => Outcome::default(),
+            (RequestKind::Pop, _, Some(_)) => {
+                calls.remove(&friend_pk);
+                Outcome::event(CallEvent::Ended)
+            },
+            (_, None, _) => {
+                let had_call = calls.remove(&friend_pk).is_some();
+                let mut outcome = Outcome::error(MsiErrorKind::InvalidMessage);
+                if had_call {
+                    outcome.event = Some(CallEvent::Error { error: MsiErrorKind::InvalidMessage });
+                }
+                outcome
+            },
+            (RequestKind::Init, Some(capabilities), None) => {
+                calls.insert(friend_pk, Call::new(CallState::Requested, 0, capabilities));
+                Outcome::event(CallEvent::Invite { capabilities })
+            },
+            // the friend calls us again while the call is still active on
+            // our side, probably after reconnection, so we answer
+            // automatically
+            (RequestKind::Init, Some(capabilities), Some(CallState::Active)) => {
+                let call = calls.get_mut(&friend_pk).unwrap();
+                let changed = call.peer_capabilities != capabilities;
+                call.peer_capabilities = capabilities;
+                Outcome {
+                    reply: Some(MsiPacket::new(RequestKind::Push, None, Some(call.self_capabilities))),
+                    event: if changed { Some(CallEvent::Capabilities { capabilities }) } else { None },
+                }
+            },
+            (RequestKind::Push, Some(_), None) => Outcome::error(MsiErrorKind::StrayMessage),
+            (RequestKind::Push, Some(capabilities), Some(CallState::Requesting)) => {
+                let call = calls.get_mut(&friend_pk).unwrap();
+                call.state = CallState::Active;
+                call.peer_capabilities = capabilities;
+                Outcome::event(CallEvent::Started { capabilities })
+            },
+            (RequestKind::Push, Some(capabilities), Some(CallState::Active)) => {
+                let call = calls.get_mut(&friend_pk).unwrap();
+                if call.peer_capabilities == capabilities {
+                    Outcome::default()
+                } else {
+                    call.peer_capabilities = capabilities;
+                    Outcome::event(CallEvent::Capabilities { capabilities })
+                }
+            },
+            (RequestKind::Init, Some(_), Some(_)) | (RequestKind::Push, Some(_), Some(_)) => {
+                calls.remove(&friend_pk);
+                let mut outcome = Outcome::error(MsiErrorKind::InvalidState);
+                outcome.event = Some(CallEvent::Error { error: MsiErrorKind::InvalidState });
+                outcome
+            },
+        }
+    }
+
+    /// Handle going offline of a friend. The call with it is ended.
+    pub fn handle_friend_offline(&self, friend_pk: PublicKey) -> IoFuture<()> {
+        if self.calls.write().remove(&friend_pk).is_some() {
+            self.send_event(friend_pk, CallEvent::Ended)
+        } else {
+            Box::new(future::ok(()))
+        }
+    }
+
+    /// End calls that were not answered in time letting friends know about
+    /// it.
+    pub fn main_loop(&self) -> IoFuture<()> {
+        let mut calls = self.calls.write();
+
+        let timed_out = calls.iter()
+            .filter(|&(_, call)| call.is_timed_out())
+            .map(|(&friend_pk, _)| friend_pk)
+            .collect::<Vec<_>>();
+
+        let futures = timed_out.into_iter()
+            .map(|friend_pk| {
+                calls.remove(&friend_pk);
+                let pop_future = self.send_packet(friend_pk, MsiPacket::new(RequestKind::Pop, None, None))
+                    .then(|_| Ok(()));
+                let event_future = self.send_event(friend_pk, CallEvent::TimedOut);
+                pop_future.join(event_future).map(|_| ())
+            })
+            .collect::<Vec<_>>();
+
+        Box::new(future::join_all(futures).map(|_| ()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::Stream;
+    use futures::sync::mpsc;
+    use tokio_executor;
+    use tokio_timer::clock::*;
+
+    use toxcore::dht::precomputed_cache::PrecomputedCache;
+    use toxcore::net_crypto::NetCryptoNewArgs;
+
+    const AUDIO: u8 = CAPABILITY_SEND_AUDIO | CAPABILITY_RECEIVE_AUDIO;
+    const VIDEO: u8 = CAPABILITY_SEND_VIDEO | CAPABILITY_RECEIVE_VIDEO;
+
+    fn create_msi() -> (Msi, mpsc::UnboundedReceiver<Event>) {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            precomputed_keys,
+        });
+        let (event_tx, event_rx) = mpsc::unbounded();
+        let mut msi = Msi::new(net_crypto);
+        msi.set_event_sink(event_tx);
+        (msi, event_rx)
+    }
+
+    fn add_call(msi: &Msi, friend_pk: PublicKey, state: CallState) {
+        msi.calls.write().insert(friend_pk, Call::new(state, AUDIO, AUDIO));
+    }
+
+    fn collect_events(msi: Msi, event_rx: mpsc::UnboundedReceiver<Event>) -> Vec<Event> {
+        drop(msi);
+        event_rx.collect().wait().unwrap()
+    }
+
+    #[test]
+    fn call_without_connection() {
+        let (msi, _event_rx) = create_msi();
+        let friend_pk = gen_keypair().0;
+
+        assert!(msi.call(friend_pk, AUDIO).wait().is_err());
+        // the call is removed since the request can't be sent
+        assert_eq!(msi.call_state(friend_pk), None);
+    }
+
+    #[test]
+    fn call_invalid_state() {
+        let (msi, _event_rx) = create_msi();
+        let friend_pk = gen_keypair().0;
+
+        assert!(msi.answer(friend_pk, AUDIO).wait().is_err());
+        assert!(msi.hang_up(friend_pk).wait().is_err());
+        assert!(msi.change_capabilities(friend_pk, AUDIO).wait().is_err());
+
+        add_call(&msi, friend_pk, CallState::Requesting);
+        assert!(msi.call(friend_pk, AUDIO).wait().is_err());
+        assert!(msi.answer(friend_pk, AUDIO).wait().is_err());
+        assert!(msi.change_capabilities(friend_pk, VIDEO).wait().is_err());
+        assert_eq!(msi.call_state(friend_pk), Some(CallState::Requesting));
+        assert_eq!(msi.self_capabilities(friend_pk), Some(AUDIO));
+    }
+
+    #[test]
+    fn hang_up() {
+        let (msi, _event_rx) = create_msi();
+        let friend_pk = gen_keypair().0;
+        add_call(&msi, friend_pk, CallState::Active);
+
+        // `Pop` request can't be sent but the call is ended anyway
+        assert!(msi.hang_up(friend_pk).wait().is_err());
+        assert_eq!(msi.call_state(friend_pk), None);
+    }
+
+    #[test]
+    fn handle_incoming_call() {
+        let (msi, event_rx) = create_msi();
+        let friend_pk = gen_keypair().0;
+
+        msi.handle_msi_packet(friend_pk, MsiPacket::new(RequestKind::Init, None, Some(AUDIO))).wait().unwrap();
+        assert_eq!(msi.call_state(friend_pk), Some(CallState::Requested));
+        assert_eq!(msi.peer_capabilities(friend_pk), Some(AUDIO));
+
+        // the friend cancels the call
+        msi.handle_msi_packet(friend_pk, MsiPacket::new(RequestKind::Pop, None, None)).wait().unwrap();
+        assert_eq!(msi.call_state(friend_pk), None);
+        // `Pop` for unknown call is ignored
+        msi.handle_msi_packet(friend_pk, MsiPacket::new(RequestKind::Pop, None, None)).wait().unwrap();
+
+        assert_eq!(collect_events(msi, event_rx), vec![
+            Event::Call { pk: friend_pk, event: CallEvent::Invite { capabilities: AUDIO } },
+            Event::Call { pk: friend_pk, event: CallEvent::Ended },
+        ]);
+    }
+
+    #[test]
+    fn handle_answer() {
+        let (msi, event_rx) = create_msi();
+        let friend_pk = gen_keypair().0;
+        add_call(&msi, friend_pk, CallState::Requesting);
+
+        msi.handle_msi_packet(friend_pk, MsiPacket::new(RequestKind::Push, None, Some(AUDIO | VIDEO))).wait().unwrap();
+        assert_eq!(msi.call_state(friend_pk), Some(CallState::Active));
+        assert_eq!(msi.peer_capabilities(friend_pk), Some(AUDIO | VIDEO));
+
+        // the friend stops sending video
+        msi.handle_msi_packet(friend_pk, MsiPacket::new(RequestKind::Push, None, Some(AUDIO))).wait().unwrap();
+        // the same capabilities are not reported twice
+        msi.handle_msi_packet(friend_pk, MsiPacket::new(RequestKind::Push, None, Some(AUDIO))).wait().unwrap();
+        assert_eq!(msi.peer_capabilities(friend_pk), Some(AUDIO));
+
+        // the friend calls us again after reconnection
+        msi.handle_msi_packet(friend_pk, MsiPacket::new(RequestKind::Init, None, Some(AUDIO))).wait().unwrap();
+        assert_eq!(msi.call_state(friend_pk), Some(CallState::Active));
+
+        assert_eq!(collect_events(msi, event_rx), vec![
+            Event::Call { pk: friend_pk, event: CallEvent::Started { capabilities: AUDIO | VIDEO } },
+            Event::Call { pk: friend_pk, event: CallEvent::Capabilities { capabilities: AUDIO } },
+        ]);
+    }
+
+    #[test]
+    fn handle_invalid_state() {
+        let (msi, event_rx) = create_msi();
+        let friend_pk = gen_keypair().0;
+        add_call(&msi, friend_pk, CallState::Requested);
+
+        msi.handle_msi_packet(friend_pk, MsiPacket::new(RequestKind::Push, None, Some(AUDIO))).wait().unwrap();
+        assert_eq!(msi.call_state(friend_pk), None);
+
+        // stray messages don't create calls
+        msi.handle_msi_packet(friend_pk, MsiPacket::new(RequestKind::Push, None, Some(AUDIO))).wait().unwrap();
+        assert_eq!(msi.call_state(friend_pk), None);
+
+        assert_eq!(collect_events(msi, event_rx), vec![
+            Event::Call { pk: friend_pk, event: CallEvent::Error { error: MsiErrorKind::InvalidState } },
+        ]);
+    }
+
+    #[test]
+    fn handle_invalid_message() {
+        let (msi, event_rx) = create_msi();
+        let friend_pk = gen_keypair().0;
+
+        // `Init` request without capabilities doesn't create a call
+        msi.handle_msi_packet(friend_pk, MsiPacket::new(RequestKind::Init, None, None)).wait().unwrap();
+        assert_eq!(msi.call_state(friend_pk), None);
+
+        add_call(&msi, friend_pk, CallState::Active);
+        msi.handle_msi_packet(friend_pk, MsiPacket::new(RequestKind::Push, None, None)).wait().unwrap();
+        assert_eq!(msi.call_state(friend_pk), None);
+
+        assert_eq!(collect_events(msi, event_rx), vec![
+            Event::Call { pk: friend_pk, event: CallEvent::Error { error: MsiErrorKind::InvalidMessage } },
+        ]);
+    }
+
+    #[test]
+    fn handle_error() {
+        let (msi, event_rx) = create_msi();
+        let friend_pk = gen_keypair().0;
+        add_call(&msi, friend_pk, CallState::Requesting);
+
+        let packet = MsiPacket::new(RequestKind::Pop, Some(MsiErrorKind::InvalidState), None);
+        msi.handle_msi_packet(friend_pk, packet.clone()).wait().unwrap();
+        assert_eq!(msi.call_state(friend_pk), None);
+        // errors for unknown calls are ignored
+        msi.handle_msi_packet(friend_pk, packet).wait().unwrap();
+
+        assert_eq!(collect_events(msi, event_rx), vec![
+            Event::Call { pk: friend_pk, event: CallEvent::Error { error: MsiErrorKind::InvalidState } },
+        ]);
+    }
+
+    #[test]
+    fn handle_friend_offline() {
+        let (msi, event_rx) = create_msi();
+        let friend_pk = gen_keypair().0;
+        add_call(&msi, friend_pk, CallState::Active);
+
+        msi.handle_friend_offline(friend_pk).wait().unwrap();
+        assert_eq!(msi.call_state(friend_pk), None);
+        msi.handle_friend_offline(friend_pk).wait().unwrap();
+
+        assert_eq!(collect_events(msi, event_rx), vec![
+            Event::Call { pk: friend_pk, event: CallEvent::Ended },
+        ]);
+    }
+
+    #[test]
+    fn main_loop_timeout() {
+        let (msi, event_rx) = create_msi();
+        let requesting_pk = gen_keypair().0;
+        let requested_pk = gen_keypair().0;
+        let active_pk = gen_keypair().0;
+        add_call(&msi, requesting_pk, CallState::Requesting);
+        add_call(&msi, requested_pk, CallState::Requested);
+        add_call(&msi, active_pk, CallState::Active);
+
+        msi.main_loop().wait().unwrap();
+        assert_eq!(msi.calls.read().len(), 3);
+
+        let now = Instant::now() + Duration::from_secs(CALL_TIMEOUT);
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(now));
+
+        with_default(&clock, &mut enter, |_| {
+            msi.main_loop().wait().unwrap();
+        });
+
+        assert_eq!(msi.call_state(requesting_pk), None);
+        assert_eq!(msi.call_state(requested_pk), None);
+        assert_eq!(msi.call_state(active_pk), Some(CallState::Active));
+
+        let mut events = collect_events(msi, event_rx);
+        events.sort_by_key(|event| match *event {
+            Event::Call { pk, .. } => pk,
+            _ => unreachable!(),
+        });
+        let mut expected = vec![
+            Event::Call { pk: requesting_pk, event: CallEvent::TimedOut },
+            Event::Call { pk: requested_pk, event: CallEvent::TimedOut },
+        ];
+        expected.sort_by_key(|event| match *event {
+            Event::Call { pk, .. } => pk,
+            _ => unreachable!(),
+        });
+        assert_eq!(events, expected);
+    }
+}
@@ -0,0 +1,477 @@
+/*! Outbox of chat messages that are not delivered yet
+
+Messages longer than `MAX_MESSAGE_DATA_SIZE` are split into chunks, every
+chunk is sent as a separate `Message` or `Action` packet. Chunks stay in the
+outbox until the friend confirms that it received them. When the connection
+to the friend is lost all chunks are sent again after reconnection.
+
+*/
+
+use std::collections::VecDeque;
+use std::str;
+
+use nom::{be_u16, be_u32, le_u8};
+
+use toxcore::binary_io::*;
+use toxcore::crypto_core::*;
+use toxcore::messenger::packet::*;
+use toxcore::net_crypto::LosslessPacketId;
+
+/// Kind of a chat message.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MessageKind {
+    /// Normal chat message
+    Normal = 0,
+    /// Action message, e.g. `/me waves`
+    Action = 1,
+}
+
+impl FromBytes for MessageKind {
+    named!(from_bytes<MessageKind>, switch!(le_u8,
+        0 => value!(MessageKind::Normal) |
+        1 => value!(MessageKind::Action)
+    ));
+}
+
+impl ToBytes for MessageKind {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        gen_be_u8!(buf, *self as u8)
+    }
+}
+
+/** Split message into chunks that fit into `Message` packet.
+
+Chunks are split after the last space or newline when it's possible and never
+in the middle of UTF-8 character.
+
+*/
+pub fn split_message(message: &str) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut rest = message;
+
+    while rest.len() > MAX_MESSAGE_DATA_SIZE {
+        let mut end = MAX_MESSAGE_DATA_SIZE;
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        let split = rest[..end].rfind(|c| c == ' ' || c == '\n')
+            .filter(|&i| i > 0)
+            .map_or(end, |i| i + 1);
+        chunks.push(rest[..split].to_owned());
+        rest = &rest[split..];
+    }
+
+    chunks.push(rest.to_owned());
+    chunks
+}
+
+/// Sending state of a chunk.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum ChunkState {
+    /// Chunk should be sent.
+    Unsent,
+    /// Chunk is passed to `net_crypto` but its packet id is not known yet.
+    Sending,
+    /// Chunk is sent with the packet id.
+    Sent(LosslessPacketId),
+}
+
+/// Chunk of a chat message that is not delivered yet.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct OutgoingChunk {
+    /// Id of the message the chunk belongs to.
+    message_id: u32,
+    /// Index of the chunk in the message.
+    part: usize,
+    /// Kind of the message.
+    kind: MessageKind,
+    /// Text of the chunk.
+    text: String,
+    /// Whether it's the last chunk of the message.
+    last: bool,
+    /// Sending state of the chunk.
+    state: ChunkState,
+}
+
+/// Queue of not delivered chunks of chat messages to a friend.
+#[derive(Clone, Debug, Default)]
+pub struct Outbox {
+    /// Chunks in the order they should be sent.
+    chunks: VecDeque<OutgoingChunk>,
+}
+
+impl Outbox {
+    /// Create new empty `Outbox`.
+    pub fn new() -> Outbox {
+        Outbox {
+            chunks: VecDeque::new(),
+        }
+    }
+
+    /// Check if all messages are delivered.
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    /// Split the message into chunks and add them to the end of the queue.
+    pub fn push(&mut self, message_id: u32, kind: MessageKind, message: &str) {
+        let chunks = split_message(message);
+        let len = chunks.len();
+        for (part, text) in chunks.into_iter().enumerate() {
+            self.push_chunk(message_id, kind, text, part + 1 == len);
+        }
+    }
+
+    /// Add a single chunk to the end of the queue.
+    fn push_chunk(&mut self, message_id: u32, kind: MessageKind, text: String, last: bool) {
+        let part = self.chunks.iter()
+            .filter(|chunk| chunk.message_id == message_id)
+            .count();
+        self.chunks.push_back(OutgoingChunk {
+            message_id,
+            part,
+            kind,
+            text,
+            last,
+            state: ChunkState::Unsent,
+        });
+    }
+
+    /// Mark all chunks as unsent. It should be called when the connection to
+    /// the friend changes since packet numbers are valid only for the
+    /// connection they were sent with.
+    pub fn reset(&mut self) {
+        for chunk in &mut self.chunks {
+            chunk.state = ChunkState::Unsent;
+        }
+    }
+
+    /// Get packets for unsent chunks together with message id and part of
+    /// the chunk and mark these chunks as being sent.
+    pub fn take_unsent(&mut self) -> Vec<(u32, usize, Packet)> {
+        self.chunks.iter_mut()
+            .filter(|chunk| chunk.state == ChunkState::Unsent)
+            .map(|chunk| {
+                chunk.state = ChunkState::Sending;
+                let packet = match chunk.kind {
+                    MessageKind::Normal => Packet::Message(Message::new(chunk.text.clone())),
+                    MessageKind::Action => Packet::Action(Action::new(chunk.text.clone())),
+                };
+                (chunk.message_id, chunk.part, packet)
+            })
+            .collect()
+    }
+
+    /// Set packet id of the chunk that is being sent. Does nothing if the
+    /// outbox was reset in the meantime.
+    pub fn set_sent(&mut self, message_id: u32, part: usize, packet_id: LosslessPacketId) {
+        self.set_state(message_id, part, ChunkState::Sent(packet_id));
+    }
+
+    /// Mark the chunk that failed to be sent as unsent so it's sent again
+    /// later.
+    pub fn set_unsent(&mut self, message_id: u32, part: usize) {
+        self.set_state(message_id, part, ChunkState::Unsent);
+    }
+
+    /// Change state of the chunk if it's being sent.
+    fn set_state(&mut self, message_id: u32, part: usize, state: ChunkState) {
+        let chunk = self.chunks.iter_mut().find(|chunk|
+            chunk.message_id == message_id && chunk.part == part && chunk.state == ChunkState::Sending
+        );
+        if let Some(chunk) = chunk {
+            chunk.state = state;
+        }
+    }
+
+    /// Remove delivered chunks from the front of the queue and get ids of
+    /// messages that were delivered completely. Chunks are delivered in the
+    /// same order they were sent so checking stops on the first chunk that
+    /// is not delivered.
+    pub fn pop_delivered<F>(&mut self, is_delivered: F) -> Vec<u32> where F: Fn(LosslessPacketId) -> bool {
+        let mut delivered = Vec::new();
+        while let Some(ChunkState::Sent(packet_id)) = self.chunks.front().map(|chunk| chunk.state) {
+            if !is_delivered(packet_id) {
+                break
+            }
+            if let Some(chunk) = self.chunks.pop_front() {
+                if chunk.last {
+                    delivered.push(chunk.message_id);
+                }
+            }
+        }
+        delivered
+    }
+
+    /// Get snapshot entries for all not delivered chunks.
+    pub fn snapshot_entries(&self, pk: PublicKey) -> Vec<OutboxSnapshotEntry> {
+        self.chunks.iter()
+            .map(|chunk| OutboxSnapshotEntry {
+                pk,
+                message_id: chunk.message_id,
+                kind: chunk.kind,
+                last: chunk.last,
+                text: chunk.text.clone(),
+            })
+            .collect()
+    }
+
+    /// Add a chunk restored from snapshot to the end of the queue.
+    pub fn restore_entry(&mut self, entry: &OutboxSnapshotEntry) {
+        self.push_chunk(entry.message_id, entry.kind, entry.text.clone(), entry.last);
+    }
+}
+
+/** Not delivered chunk of a chat message stored in `OutboxSnapshot`.
+
+Serialized form:
+
+Length   | Content
+-------- | ------
+`32`     | Long term `PublicKey` of the friend
+`4`      | Message id
+`1`      | Message kind (0 = normal, 1 = action)
+`1`      | Whether it's the last chunk of the message (0 or 1)
+`2`      | Length of the text
+variable | UTF8 text of the chunk
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OutboxSnapshotEntry {
+    /// Long term `PublicKey` of the friend
+    pub pk: PublicKey,
+    /// Id of the message the chunk belongs to
+    pub message_id: u32,
+    /// Kind of the message
+    pub kind: MessageKind,
+    /// Whether it's the last chunk of the message
+    pub last: bool,
+    /// Text of the chunk
+    pub text: String,
+}
+
+impl FromBytes for OutboxSnapshotEntry {
+    named!(from_bytes<OutboxSnapshotEntry>, do_parse!(
+        pk: call!(PublicKey::from_bytes) >>
+        message_id: be_u32 >>
+        kind: call!(MessageKind::from_bytes) >>
+        last: map!(verify!(le_u8, |last| last <= 1), |last| last == 1) >>
+        text: map_res!(length_data!(be_u16), str::from_utf8) >>
+        (OutboxSnapshotEntry {
+            pk,
+            message_id,
+            kind,
+            last,
+            text: text.to_string(),
+        })
+    ));
+}
+
+impl ToBytes for OutboxSnapshotEntry {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_slice!(self.pk.as_ref()) >>
+            gen_be_u32!(self.message_id) >>
+            gen_call!(|buf, kind| MessageKind::to_bytes(kind, buf), &self.kind) >>
+            gen_be_u8!(self.last as u8) >>
+            gen_be_u16!(self.text.len() as u16) >>
+            gen_slice!(self.text.as_bytes())
+        )
+    }
+}
+
+impl OutboxSnapshotEntry {
+    /// Size of serialized entry without the text.
+    const BASE_SIZE: usize = PUBLICKEYBYTES + 4 + 1 + 1 + 2;
+}
+
+/** Snapshot of not delivered chat messages that can be persisted to send
+them after restart.
+
+Serialized form:
+
+Length   | Content
+-------- | ------
+variable | Chunks as `OutboxSnapshotEntry`s
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OutboxSnapshot {
+    /// Not delivered chunks in the order they should be sent
+    pub entries: Vec<OutboxSnapshotEntry>,
+}
+
+impl FromBytes for OutboxSnapshot {
+    named!(from_bytes<OutboxSnapshot>, do_parse!(
+        entries: many0!(OutboxSnapshotEntry::from_bytes) >>
+        eof!() >>
+        (OutboxSnapshot { entries })
+    ));
+}
+
+impl ToBytes for OutboxSnapshot {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_many_ref!(&self.entries, |buf, entry| OutboxSnapshotEntry::to_bytes(entry, buf))
+        )
+    }
+}
+
+impl OutboxSnapshot {
+    /// Serialize snapshot to a new buffer of sufficient size.
+    pub fn to_vec(&self) -> Vec<u8> {
+        let size = self.entries.iter()
+            .map(|entry| OutboxSnapshotEntry::BASE_SIZE + entry.text.len())
+            .sum::<usize>();
+        let mut buf = vec![0; size];
+        // can not fail since buf has enough length
+        let (_, len) = self.to_bytes((&mut buf, 0)).unwrap();
+        buf.truncate(len);
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    encode_decode_test!(
+        outbox_snapshot_encode_decode,
+        OutboxSnapshot {
+            entries: vec![
+                OutboxSnapshotEntry {
+                    pk: gen_keypair().0,
+                    message_id: 1,
+                    kind: MessageKind::Normal,
+                    last: false,
+                    text: "Hello".to_owned(),
+                },
+                OutboxSnapshotEntry {
+                    pk: gen_keypair().0,
+                    message_id: 2,
+                    kind: MessageKind::Action,
+                    last: true,
+                    text: "waves".to_owned(),
+                },
+            ],
+        }
+    );
+
+    #[test]
+    fn outbox_snapshot_to_vec() {
+        let snapshot = OutboxSnapshot {
+            entries: vec![OutboxSnapshotEntry {
+                pk: gen_keypair().0,
+                message_id: 1,
+                kind: MessageKind::Normal,
+                last: true,
+                text: "Hello".to_owned(),
+            }],
+        };
+
+        let buf = snapshot.to_vec();
+        assert_eq!(buf.len(), OutboxSnapshotEntry::BASE_SIZE + 5);
+        assert_eq!(OutboxSnapshot::from_bytes(&buf).unwrap().1, snapshot);
+    }
+
+    #[test]
+    fn split_message_short() {
+        assert_eq!(split_message("Hello"), vec!["Hello".to_owned()]);
+        assert_eq!(split_message(""), vec![String::new()]);
+    }
+
+    #[test]
+    fn split_message_on_space() {
+        let first = "a".repeat(MAX_MESSAGE_DATA_SIZE - 10);
+        let second = "b".repeat(20);
+        let message = format!("{} {}", first, second);
+
+        let chunks = split_message(&message);
+        assert_eq!(chunks, vec![format!("{} ", first), second]);
+    }
+
+    #[test]
+    fn split_message_without_spaces() {
+        let message = "a".repeat(MAX_MESSAGE_DATA_SIZE * 2 + 1);
+
+        let chunks = split_message(&message);
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[0].len(), MAX_MESSAGE_DATA_SIZE);
+        assert_eq!(chunks[1].len(), MAX_MESSAGE_DATA_SIZE);
+        assert_eq!(chunks[2].len(), 1);
+        assert_eq!(chunks.concat(), message);
+    }
+
+    #[test]
+    fn split_message_on_char_boundary() {
+        // 'я' takes 2 bytes so the limit falls in the middle of a character
+        let message = format!("a{}", "я".repeat(MAX_MESSAGE_DATA_SIZE));
+
+        let chunks = split_message(&message);
+        assert!(chunks.iter().all(|chunk| chunk.len() <= MAX_MESSAGE_DATA_SIZE));
+        assert_eq!(chunks[0].len(), MAX_MESSAGE_DATA_SIZE - 1);
+        assert_eq!(chunks.concat(), message);
+    }
+
+    #[test]
+    fn outbox_receipts() {
+        let mut outbox = Outbox::new();
+        outbox.push(1, MessageKind::Normal, &"a".repeat(MAX_MESSAGE_DATA_SIZE + 1));
+        outbox.push(2, MessageKind::Action, "waves");
+
+        let unsent = outbox.take_unsent();
+        assert_eq!(unsent.len(), 3);
+        assert_eq!(unsent[2], (2, 0, Packet::Action(Action::new("waves".to_owned()))));
+        // chunks that are being sent aren't returned again
+        assert!(outbox.take_unsent().is_empty());
+
+        let packet_id = |packet_number| LosslessPacketId { connection_id: 42, packet_number };
+        outbox.set_sent(1, 0, packet_id(10));
+        outbox.set_sent(1, 1, packet_id(11));
+        outbox.set_sent(2, 0, packet_id(12));
+
+        // only the first chunk of the first message is delivered
+        assert!(outbox.pop_delivered(|packet_id| packet_id.packet_number < 11).is_empty());
+        assert_eq!(outbox.pop_delivered(|packet_id| packet_id.packet_number < 12), vec![1]);
+        assert_eq!(outbox.pop_delivered(|_| true), vec![2]);
+        assert!(outbox.is_empty());
+    }
+
+    #[test]
+    fn outbox_reset() {
+        let mut outbox = Outbox::new();
+        outbox.push(1, MessageKind::Normal, "Hello");
+
+        assert_eq!(outbox.take_unsent().len(), 1);
+        outbox.reset();
+        // packet id of the old connection is ignored
+        outbox.set_sent(1, 0, LosslessPacketId { connection_id: 42, packet_number: 10 });
+        assert!(outbox.pop_delivered(|_| true).is_empty());
+
+        assert_eq!(outbox.take_unsent().len(), 1);
+        outbox.set_unsent(1, 0);
+        assert_eq!(outbox.take_unsent().len(), 1);
+    }
+
+    #[test]
+    fn outbox_snapshot_restore() {
+        let pk = gen_keypair().0;
+        let mut outbox = Outbox::new();
+        outbox.push(1, MessageKind::Normal, &"a".repeat(MAX_MESSAGE_DATA_SIZE + 1));
+        outbox.take_unsent();
+        outbox.set_sent(1, 0, LosslessPacketId { connection_id: 42, packet_number: 0 });
+
+        let entries = outbox.snapshot_entries(pk);
+        assert_eq!(entries.len(), 2);
+        assert!(!entries[0].last);
+        assert!(entries[1].last);
+
+        let mut restored = Outbox::new();
+        for entry in &entries {
+            restored.restore_entry(entry);
+        }
+        // restored chunks are sent again
+        assert_eq!(restored.take_unsent().len(), 2);
+        assert_eq!(restored.snapshot_entries(pk), entries);
+    }
+}
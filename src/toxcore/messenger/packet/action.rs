@@ -0,0 +1,84 @@
+/*! Action struct.
+*/
+
+use std::str;
+use nom::rest;
+
+use toxcore::binary_io::*;
+use super::MAX_MESSAGE_DATA_SIZE;
+
+/** Action is a struct that holds string of an action message.
+
+This packet is used to send an action message (like `/me` in IRC) to a
+friend. It has the same size limit as [`Message`](./struct.Message.html).
+
+Serialized form:
+
+Length    | Content
+--------- | ------
+`1`       | `0x41`
+`1..1372` | UTF8 byte string
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Action {
+    /// Action message
+    pub action: String,
+}
+
+impl FromBytes for Action {
+    named!(from_bytes<Action>, do_parse!(
+        tag!("\x41") >>
+        action: map_res!(verify!(rest, |action: &[u8]| !action.is_empty() && action.len() <= MAX_MESSAGE_DATA_SIZE),
+            str::from_utf8) >>
+        (Action { action: action.to_string() })
+    ));
+}
+
+impl ToBytes for Action {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(0x41) >>
+            gen_cond!(self.action.is_empty() || self.action.len() > MAX_MESSAGE_DATA_SIZE, |buf| gen_error(buf, 0)) >>
+            gen_slice!(self.action.as_bytes())
+        )
+    }
+}
+
+impl Action {
+    /// Create new Action object.
+    pub fn new(action: String) -> Self {
+        Action { action }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    encode_decode_test!(
+        action_encode_decode,
+        Action::new("waves".to_string())
+    );
+
+    #[test]
+    fn action_from_bytes_encoding_error() {
+        let err_string = vec![0x41, 0, 159, 146, 150]; // not UTF8 bytes.
+        assert!(Action::from_bytes(&err_string).is_err());
+    }
+
+    #[test]
+    fn action_from_bytes_overflow() {
+        let mut large_string = vec![0x41];
+        large_string.extend_from_slice(&[32; MAX_MESSAGE_DATA_SIZE + 1]);
+        assert!(Action::from_bytes(&large_string).is_err());
+    }
+
+    #[test]
+    fn action_to_bytes_overflow() {
+        let large_string = String::from_utf8(vec![32u8; MAX_MESSAGE_DATA_SIZE + 1]).unwrap();
+        let large_action = Action::new(large_string);
+        let mut buf = [0; MAX_MESSAGE_DATA_SIZE + 2]; // `1` is for packet_id.
+        assert!(large_action.to_bytes((&mut buf, 0)).is_err());
+    }
+}
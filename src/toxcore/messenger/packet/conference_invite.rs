@@ -0,0 +1,113 @@
+/*! ConferenceInvite struct.
+*/
+
+use nom::{be_u16, le_u8};
+
+use toxcore::binary_io::*;
+
+/// Size in bytes of unique id of a conference
+pub const CONFERENCE_UID_BYTES: usize = 32;
+
+/// Type of a conference.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ConferenceType {
+    /// Text-only conference
+    Text = 0,
+    /// Conference that supports audio
+    Audio = 1,
+}
+
+impl FromBytes for ConferenceType {
+    named!(from_bytes<ConferenceType>, switch!(le_u8,
+        0 => value!(ConferenceType::Text) |
+        1 => value!(ConferenceType::Audio)
+    ));
+}
+
+/** ConferenceInvite is a struct that holds info to invite a friend to a
+conference.
+
+This packet is used to invite a friend to a conference. The friend answers
+with [`ConferenceInviteResponse`](./struct.ConferenceInviteResponse.html)
+packet if it wants to join.
+
+Serialized form:
+
+Length    | Content
+--------- | ------
+`1`       | `0x60`
+`1`       | `0x00`
+`2`       | Conference number of the inviter
+`1`       | Conference type (0 = text, 1 = audio)
+`32`      | Unique conference id
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ConferenceInvite {
+    /// Number of the conference on the inviter's side
+    pub conference_id: u16,
+    /// Type of the conference
+    pub conference_type: ConferenceType,
+    /// Unique id of the conference
+    pub unique_id: [u8; CONFERENCE_UID_BYTES],
+}
+
+impl FromBytes for ConferenceInvite {
+    named!(from_bytes<ConferenceInvite>, do_parse!(
+        tag!("\x60") >>
+        tag!("\x00") >>
+        conference_id: be_u16 >>
+        conference_type: call!(ConferenceType::from_bytes) >>
+        unique_id: count_fixed!(u8, le_u8, CONFERENCE_UID_BYTES) >>
+        eof!() >>
+        (ConferenceInvite { conference_id, conference_type, unique_id })
+    ));
+}
+
+impl ToBytes for ConferenceInvite {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(0x60) >>
+            gen_be_u8!(0x00) >>
+            gen_be_u16!(self.conference_id) >>
+            gen_be_u8!(self.conference_type as u8) >>
+            gen_slice!(&self.unique_id)
+        )
+    }
+}
+
+impl ConferenceInvite {
+    /// Create new ConferenceInvite object.
+    pub fn new(conference_id: u16, conference_type: ConferenceType, unique_id: [u8; CONFERENCE_UID_BYTES]) -> Self {
+        ConferenceInvite { conference_id, conference_type, unique_id }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    encode_decode_test!(
+        conference_invite_encode_decode,
+        ConferenceInvite::new(1, ConferenceType::Text, [42; CONFERENCE_UID_BYTES])
+    );
+
+    encode_decode_test!(
+        conference_invite_audio_encode_decode,
+        ConferenceInvite::new(2, ConferenceType::Audio, [43; CONFERENCE_UID_BYTES])
+    );
+
+    #[test]
+    fn conference_invite_from_bytes_invalid_type() {
+        let mut bytes = vec![0x60, 0x00, 0, 1, 2];
+        bytes.extend_from_slice(&[42; CONFERENCE_UID_BYTES]);
+        assert!(ConferenceInvite::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn conference_invite_from_bytes_trailing_data() {
+        let mut bytes = vec![0x60, 0x00, 0, 1, 0];
+        bytes.extend_from_slice(&[42; CONFERENCE_UID_BYTES + 1]);
+        assert!(ConferenceInvite::from_bytes(&bytes).is_err());
+    }
+}
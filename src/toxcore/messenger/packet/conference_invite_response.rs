@@ -0,0 +1,88 @@
+/*! ConferenceInviteResponse struct.
+*/
+
+use nom::{be_u16, le_u8};
+
+use toxcore::binary_io::*;
+use super::{ConferenceType, CONFERENCE_UID_BYTES};
+
+/** ConferenceInviteResponse is a struct that holds info to join a
+conference.
+
+This packet is sent in response to
+[`ConferenceInvite`](./struct.ConferenceInvite.html) packet when a friend
+accepts the invitation.
+
+Serialized form:
+
+Length    | Content
+--------- | ------
+`1`       | `0x60`
+`1`       | `0x01`
+`2`       | Conference number of the invited peer
+`2`       | Conference number of the inviter
+`1`       | Conference type (0 = text, 1 = audio)
+`32`      | Unique conference id
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ConferenceInviteResponse {
+    /// Number of the conference on the invited peer's side
+    pub conference_id: u16,
+    /// Number of the conference on the inviter's side
+    pub conference_id_join: u16,
+    /// Type of the conference
+    pub conference_type: ConferenceType,
+    /// Unique id of the conference
+    pub unique_id: [u8; CONFERENCE_UID_BYTES],
+}
+
+impl FromBytes for ConferenceInviteResponse {
+    named!(from_bytes<ConferenceInviteResponse>, do_parse!(
+        tag!("\x60") >>
+        tag!("\x01") >>
+        conference_id: be_u16 >>
+        conference_id_join: be_u16 >>
+        conference_type: call!(ConferenceType::from_bytes) >>
+        unique_id: count_fixed!(u8, le_u8, CONFERENCE_UID_BYTES) >>
+        eof!() >>
+        (ConferenceInviteResponse { conference_id, conference_id_join, conference_type, unique_id })
+    ));
+}
+
+impl ToBytes for ConferenceInviteResponse {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(0x60) >>
+            gen_be_u8!(0x01) >>
+            gen_be_u16!(self.conference_id) >>
+            gen_be_u16!(self.conference_id_join) >>
+            gen_be_u8!(self.conference_type as u8) >>
+            gen_slice!(&self.unique_id)
+        )
+    }
+}
+
+impl ConferenceInviteResponse {
+    /// Create new ConferenceInviteResponse object.
+    pub fn new(conference_id: u16, conference_id_join: u16, conference_type: ConferenceType, unique_id: [u8; CONFERENCE_UID_BYTES]) -> Self {
+        ConferenceInviteResponse { conference_id, conference_id_join, conference_type, unique_id }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    encode_decode_test!(
+        conference_invite_response_encode_decode,
+        ConferenceInviteResponse::new(1, 2, ConferenceType::Text, [42; CONFERENCE_UID_BYTES])
+    );
+
+    #[test]
+    fn conference_invite_response_from_bytes_invite() {
+        let mut bytes = vec![0x60, 0x00, 0, 1, 0, 2, 0];
+        bytes.extend_from_slice(&[42; CONFERENCE_UID_BYTES]);
+        assert!(ConferenceInviteResponse::from_bytes(&bytes).is_err());
+    }
+}
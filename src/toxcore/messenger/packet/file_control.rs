@@ -0,0 +1,154 @@
+/*! FileControl struct.
+*/
+
+use nom::{be_u64, le_u8};
+
+use toxcore::binary_io::*;
+
+/// Direction of a file transfer from the point of view of the packet sender.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TransferDirection {
+    /// The sender of the packet sends the file
+    Send = 0,
+    /// The sender of the packet receives the file
+    Receive = 1,
+}
+
+impl FromBytes for TransferDirection {
+    named!(from_bytes<TransferDirection>, switch!(le_u8,
+        0 => value!(TransferDirection::Send) |
+        1 => value!(TransferDirection::Receive)
+    ));
+}
+
+/// Control command for a file transfer.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ControlType {
+    /// Accept the transfer or resume it after pause
+    Accept,
+    /// Pause the transfer
+    Pause,
+    /// Cancel the transfer
+    Kill,
+    /// Start the transfer from the given position. Can be sent only by the
+    /// receiver before accepting the transfer
+    Seek(u64),
+}
+
+impl FromBytes for ControlType {
+    named!(from_bytes<ControlType>, switch!(le_u8,
+        0 => value!(ControlType::Accept) |
+        1 => value!(ControlType::Pause) |
+        2 => value!(ControlType::Kill) |
+        3 => map!(be_u64, ControlType::Seek)
+    ));
+}
+
+impl ToBytes for ControlType {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        match *self {
+            ControlType::Accept => gen_be_u8!(buf, 0),
+            ControlType::Pause => gen_be_u8!(buf, 1),
+            ControlType::Kill => gen_be_u8!(buf, 2),
+            ControlType::Seek(position) => do_gen!(buf,
+                gen_be_u8!(3) >>
+                gen_be_u64!(position)
+            ),
+        }
+    }
+}
+
+/** FileControl is a struct that holds a control command for a file transfer.
+
+This packet is used to accept, pause, resume, cancel or seek a file
+transfer.
+
+Serialized form:
+
+Length    | Content
+--------- | ------
+`1`       | `0x51`
+`1`       | Transfer direction (0 = sending, 1 = receiving)
+`1`       | File id
+`1`       | Control type (0 = accept, 1 = pause, 2 = kill, 3 = seek)
+`0` or `8`| Seek position
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FileControl {
+    /// Direction of the transfer
+    pub transfer_direction: TransferDirection,
+    /// Number of the file transfer
+    pub file_id: u8,
+    /// Control command
+    pub control_type: ControlType,
+}
+
+impl FromBytes for FileControl {
+    named!(from_bytes<FileControl>, do_parse!(
+        tag!("\x51") >>
+        transfer_direction: call!(TransferDirection::from_bytes) >>
+        file_id: le_u8 >>
+        control_type: call!(ControlType::from_bytes) >>
+        eof!() >>
+        (FileControl { transfer_direction, file_id, control_type })
+    ));
+}
+
+impl ToBytes for FileControl {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(0x51) >>
+            gen_be_u8!(self.transfer_direction as u8) >>
+            gen_be_u8!(self.file_id) >>
+            gen_call!(|buf, control_type| ControlType::to_bytes(control_type, buf), &self.control_type)
+        )
+    }
+}
+
+impl FileControl {
+    /// Create new FileControl object.
+    pub fn new(transfer_direction: TransferDirection, file_id: u8, control_type: ControlType) -> Self {
+        FileControl { transfer_direction, file_id, control_type }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    encode_decode_test!(
+        file_control_accept_encode_decode,
+        FileControl::new(TransferDirection::Send, 1, ControlType::Accept)
+    );
+
+    encode_decode_test!(
+        file_control_pause_encode_decode,
+        FileControl::new(TransferDirection::Receive, 2, ControlType::Pause)
+    );
+
+    encode_decode_test!(
+        file_control_kill_encode_decode,
+        FileControl::new(TransferDirection::Send, 3, ControlType::Kill)
+    );
+
+    encode_decode_test!(
+        file_control_seek_encode_decode,
+        FileControl::new(TransferDirection::Receive, 4, ControlType::Seek(100))
+    );
+
+    #[test]
+    fn file_control_from_bytes_invalid_control_type() {
+        assert!(FileControl::from_bytes(&[0x51, 0, 1, 4]).is_err());
+    }
+
+    #[test]
+    fn file_control_from_bytes_invalid_direction() {
+        assert!(FileControl::from_bytes(&[0x51, 2, 1, 0]).is_err());
+    }
+
+    #[test]
+    fn file_control_from_bytes_trailing_data() {
+        assert!(FileControl::from_bytes(&[0x51, 0, 1, 0, 42]).is_err());
+    }
+}
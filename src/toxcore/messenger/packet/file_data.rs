@@ -0,0 +1,88 @@
+/*! FileData struct.
+*/
+
+use nom::{le_u8, rest};
+
+use toxcore::binary_io::*;
+
+/// Maximum size in bytes of data chunk of file data packet
+pub const MAX_FILE_DATA_SIZE: usize = 1371;
+
+/** FileData is a struct that holds a chunk of a file.
+
+This packet is used to transfer file data after the transfer was accepted.
+Chunks are sent in order, a chunk smaller than `MAX_FILE_DATA_SIZE` bytes
+means the end of a file with unknown size.
+
+Serialized form:
+
+Length    | Content
+--------- | ------
+`1`       | `0x52`
+`1`       | File id
+`0..1371` | Data chunk
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FileData {
+    /// Number of the file transfer
+    pub file_id: u8,
+    /// Chunk of the file
+    pub data: Vec<u8>,
+}
+
+impl FromBytes for FileData {
+    named!(from_bytes<FileData>, do_parse!(
+        tag!("\x52") >>
+        file_id: le_u8 >>
+        data: verify!(rest, |data: &[u8]| data.len() <= MAX_FILE_DATA_SIZE) >>
+        (FileData { file_id, data: data.to_vec() })
+    ));
+}
+
+impl ToBytes for FileData {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(0x52) >>
+            gen_be_u8!(self.file_id) >>
+            gen_cond!(self.data.len() > MAX_FILE_DATA_SIZE, |buf| gen_error(buf, 0)) >>
+            gen_slice!(self.data.as_slice())
+        )
+    }
+}
+
+impl FileData {
+    /// Create new FileData object.
+    pub fn new(file_id: u8, data: Vec<u8>) -> Self {
+        FileData { file_id, data }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    encode_decode_test!(
+        file_data_encode_decode,
+        FileData::new(1, vec![42; 123])
+    );
+
+    encode_decode_test!(
+        file_data_empty_encode_decode,
+        FileData::new(1, Vec::new())
+    );
+
+    #[test]
+    fn file_data_from_bytes_overflow() {
+        let mut bytes = vec![0x52, 1];
+        bytes.extend_from_slice(&[42; MAX_FILE_DATA_SIZE + 1]);
+        assert!(FileData::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn file_data_to_bytes_overflow() {
+        let packet = FileData::new(1, vec![42; MAX_FILE_DATA_SIZE + 1]);
+        let mut buf = [0; MAX_FILE_DATA_SIZE + 3];
+        assert!(packet.to_bytes((&mut buf, 0)).is_err());
+    }
+}
@@ -0,0 +1,138 @@
+/*! FileSendRequest struct.
+*/
+
+use std::str;
+use nom::{be_u32, be_u64, le_u8, rest};
+
+use toxcore::binary_io::*;
+
+/// Maximum size in bytes of file name of file send request packet
+pub const MAX_FILENAME_LENGTH: usize = 255;
+
+/// Size in bytes of unique id of a file
+pub const FILE_UID_BYTES: usize = 32;
+
+/// Type of a file that is sent.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FileType {
+    /// Arbitrary data
+    Data = 0,
+    /// Avatar of the sender
+    Avatar = 1,
+}
+
+impl FromBytes for FileType {
+    named!(from_bytes<FileType>, switch!(be_u32,
+        0 => value!(FileType::Data) |
+        1 => value!(FileType::Avatar)
+    ));
+}
+
+/** FileSendRequest is a struct that holds info of a file we want to send.
+
+This packet is used to offer a file to a friend. The friend answers with
+[`FileControl`](./struct.FileControl.html) packet to accept or reject it.
+
+Serialized form:
+
+Length    | Content
+--------- | ------
+`1`       | `0x50`
+`1`       | File id
+`4`       | File type
+`8`       | File size
+`32`      | Unique file id
+`0..255`  | UTF8 byte string of file name
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FileSendRequest {
+    /// Number of the file transfer that is unique per friend
+    pub file_id: u8,
+    /// Type of the file
+    pub file_type: FileType,
+    /// Size of the file in bytes, `u64::MAX` means unknown size
+    pub file_size: u64,
+    /// Unique id of the file that can be used to resume transfers
+    pub file_unique_id: [u8; FILE_UID_BYTES],
+    /// Name of the file
+    pub file_name: String,
+}
+
+impl FromBytes for FileSendRequest {
+    named!(from_bytes<FileSendRequest>, do_parse!(
+        tag!("\x50") >>
+        file_id: le_u8 >>
+        file_type: call!(FileType::from_bytes) >>
+        file_size: be_u64 >>
+        file_unique_id: count_fixed!(u8, le_u8, FILE_UID_BYTES) >>
+        file_name: map_res!(verify!(rest, |file_name: &[u8]| file_name.len() <= MAX_FILENAME_LENGTH),
+            str::from_utf8) >>
+        (FileSendRequest {
+            file_id,
+            file_type,
+            file_size,
+            file_unique_id,
+            file_name: file_name.to_string(),
+        })
+    ));
+}
+
+impl ToBytes for FileSendRequest {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(0x50) >>
+            gen_be_u8!(self.file_id) >>
+            gen_be_u32!(self.file_type as u32) >>
+            gen_be_u64!(self.file_size) >>
+            gen_slice!(&self.file_unique_id) >>
+            gen_cond!(self.file_name.len() > MAX_FILENAME_LENGTH, |buf| gen_error(buf, 0)) >>
+            gen_slice!(self.file_name.as_bytes())
+        )
+    }
+}
+
+impl FileSendRequest {
+    /// Create new FileSendRequest object.
+    pub fn new(file_id: u8, file_type: FileType, file_size: u64, file_unique_id: [u8; FILE_UID_BYTES], file_name: String) -> Self {
+        FileSendRequest { file_id, file_type, file_size, file_unique_id, file_name }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    encode_decode_test!(
+        file_send_request_encode_decode,
+        FileSendRequest::new(1, FileType::Data, 4096, [42; FILE_UID_BYTES], "file.txt".to_string())
+    );
+
+    encode_decode_test!(
+        file_send_request_avatar_encode_decode,
+        FileSendRequest::new(2, FileType::Avatar, 0, [43; FILE_UID_BYTES], String::new())
+    );
+
+    #[test]
+    fn file_send_request_from_bytes_invalid_type() {
+        let mut bytes = vec![0x50, 1, 0, 0, 0, 2];
+        bytes.extend_from_slice(&[0; 8 + FILE_UID_BYTES]);
+        assert!(FileSendRequest::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn file_send_request_from_bytes_overflow() {
+        let mut bytes = vec![0x50, 1, 0, 0, 0, 0];
+        bytes.extend_from_slice(&[0; 8 + FILE_UID_BYTES]);
+        bytes.extend_from_slice(&[32; MAX_FILENAME_LENGTH + 1]);
+        assert!(FileSendRequest::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn file_send_request_to_bytes_overflow() {
+        let large_name = String::from_utf8(vec![32u8; MAX_FILENAME_LENGTH + 1]).unwrap();
+        let packet = FileSendRequest::new(1, FileType::Data, 1, [42; FILE_UID_BYTES], large_name);
+        let mut buf = [0; 1024];
+        assert!(packet.to_bytes((&mut buf, 0)).is_err());
+    }
+}
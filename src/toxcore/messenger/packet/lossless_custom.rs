@@ -0,0 +1,114 @@
+/*! LosslessCustom struct.
+*/
+
+use nom::{le_u8, rest};
+
+use toxcore::binary_io::*;
+
+/// The first packet id of lossless custom packets range
+pub const PACKET_ID_LOSSLESS_CUSTOM_START: u8 = 160;
+
+/// The last packet id of lossless custom packets range. Ids 190 and 191 that
+/// follow it are reserved for `CryptoStream` packets of `net_crypto`.
+pub const PACKET_ID_LOSSLESS_CUSTOM_END: u8 = 189;
+
+/// Maximum size in bytes of data of custom packets
+pub const MAX_CUSTOM_DATA_SIZE: usize = 1372;
+
+/** LosslessCustom is a struct that holds data of a lossless custom packet.
+
+Lossless custom packets are used by clients to implement their own
+protocols on top of reliable `net_crypto` connection. They are passed to
+clients as is.
+
+Serialized form:
+
+Length    | Content
+--------- | ------
+`1`       | Packet id in `160..=189` range
+`0..1372` | Data
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LosslessCustom {
+    /// Id of the packet
+    pub id: u8,
+    /// Data of the packet
+    pub data: Vec<u8>,
+}
+
+impl FromBytes for LosslessCustom {
+    named!(from_bytes<LosslessCustom>, do_parse!(
+        id: verify!(le_u8, |id| id >= PACKET_ID_LOSSLESS_CUSTOM_START && id <= PACKET_ID_LOSSLESS_CUSTOM_END) >>
+        data: verify!(rest, |data: &[u8]| data.len() <= MAX_CUSTOM_DATA_SIZE) >>
+        (LosslessCustom { id, data: data.to_vec() })
+    ));
+}
+
+impl ToBytes for LosslessCustom {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_cond!(self.id < PACKET_ID_LOSSLESS_CUSTOM_START || self.id > PACKET_ID_LOSSLESS_CUSTOM_END, |buf| gen_error(buf, 0)) >>
+            gen_be_u8!(self.id) >>
+            gen_cond!(self.data.len() > MAX_CUSTOM_DATA_SIZE, |buf| gen_error(buf, 0)) >>
+            gen_slice!(self.data.as_slice())
+        )
+    }
+}
+
+impl LosslessCustom {
+    /// Create new LosslessCustom object.
+    pub fn new(id: u8, data: Vec<u8>) -> Self {
+        LosslessCustom { id, data }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use toxcore::net_crypto::{PACKET_ID_STREAM_DATA, PACKET_ID_STREAM_CLOSE};
+
+    encode_decode_test!(
+        lossless_custom_encode_decode,
+        LosslessCustom::new(PACKET_ID_LOSSLESS_CUSTOM_START, vec![42; 123])
+    );
+
+    encode_decode_test!(
+        lossless_custom_last_id_encode_decode,
+        LosslessCustom::new(PACKET_ID_LOSSLESS_CUSTOM_END, Vec::new())
+    );
+
+    #[test]
+    fn lossless_custom_from_bytes_invalid_id() {
+        assert!(LosslessCustom::from_bytes(&[PACKET_ID_LOSSLESS_CUSTOM_END + 1, 42]).is_err());
+        assert!(LosslessCustom::from_bytes(&[PACKET_ID_LOSSLESS_CUSTOM_START - 1, 42]).is_err());
+    }
+
+    #[test]
+    fn lossless_custom_from_bytes_stream_id() {
+        assert!(LosslessCustom::from_bytes(&[PACKET_ID_STREAM_DATA, 42]).is_err());
+        assert!(LosslessCustom::from_bytes(&[PACKET_ID_STREAM_CLOSE, 42]).is_err());
+    }
+
+    #[test]
+    fn lossless_custom_from_bytes_overflow() {
+        let mut bytes = vec![PACKET_ID_LOSSLESS_CUSTOM_START];
+        bytes.extend_from_slice(&[42; MAX_CUSTOM_DATA_SIZE + 1]);
+        assert!(LosslessCustom::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn lossless_custom_to_bytes_invalid_id() {
+        let packet = LosslessCustom::new(PACKET_ID_LOSSLESS_CUSTOM_END + 1, vec![42]);
+        let mut buf = [0; 2];
+        assert!(packet.to_bytes((&mut buf, 0)).is_err());
+    }
+
+    #[test]
+    fn lossless_custom_to_bytes_overflow() {
+        let packet = LosslessCustom::new(PACKET_ID_LOSSLESS_CUSTOM_START, vec![42; MAX_CUSTOM_DATA_SIZE + 1]);
+        let mut buf = [0; MAX_CUSTOM_DATA_SIZE + 2];
+        assert!(packet.to_bytes((&mut buf, 0)).is_err());
+    }
+}
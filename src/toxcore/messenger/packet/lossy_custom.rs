@@ -0,0 +1,96 @@
+/*! LossyCustom struct.
+*/
+
+use nom::{le_u8, rest};
+
+use toxcore::binary_io::*;
+use super::MAX_CUSTOM_DATA_SIZE;
+
+/// The first packet id of lossy custom packets range
+pub const PACKET_ID_LOSSY_CUSTOM_START: u8 = 200;
+
+/// The last packet id of lossy custom packets range
+pub const PACKET_ID_LOSSY_CUSTOM_END: u8 = 254;
+
+/** LossyCustom is a struct that holds data of a lossy custom packet.
+
+Lossy custom packets are used by clients to implement their own protocols
+that don't need reliable delivery. They are sent as `net_crypto` lossy
+packets and passed to clients as is.
+
+Serialized form:
+
+Length    | Content
+--------- | ------
+`1`       | Packet id in `200..=254` range
+`0..1372` | Data
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LossyCustom {
+    /// Id of the packet
+    pub id: u8,
+    /// Data of the packet
+    pub data: Vec<u8>,
+}
+
+impl FromBytes for LossyCustom {
+    named!(from_bytes<LossyCustom>, do_parse!(
+        id: verify!(le_u8, |id| id >= PACKET_ID_LOSSY_CUSTOM_START && id <= PACKET_ID_LOSSY_CUSTOM_END) >>
+        data: verify!(rest, |data: &[u8]| data.len() <= MAX_CUSTOM_DATA_SIZE) >>
+        (LossyCustom { id, data: data.to_vec() })
+    ));
+}
+
+impl ToBytes for LossyCustom {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_cond!(self.id < PACKET_ID_LOSSY_CUSTOM_START || self.id > PACKET_ID_LOSSY_CUSTOM_END, |buf| gen_error(buf, 0)) >>
+            gen_be_u8!(self.id) >>
+            gen_cond!(self.data.len() > MAX_CUSTOM_DATA_SIZE, |buf| gen_error(buf, 0)) >>
+            gen_slice!(self.data.as_slice())
+        )
+    }
+}
+
+impl LossyCustom {
+    /// Create new LossyCustom object.
+    pub fn new(id: u8, data: Vec<u8>) -> Self {
+        LossyCustom { id, data }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    encode_decode_test!(
+        lossy_custom_encode_decode,
+        LossyCustom::new(PACKET_ID_LOSSY_CUSTOM_START, vec![42; 123])
+    );
+
+    encode_decode_test!(
+        lossy_custom_last_id_encode_decode,
+        LossyCustom::new(PACKET_ID_LOSSY_CUSTOM_END, Vec::new())
+    );
+
+    #[test]
+    fn lossy_custom_from_bytes_invalid_id() {
+        assert!(LossyCustom::from_bytes(&[PACKET_ID_LOSSY_CUSTOM_END + 1, 42]).is_err());
+        assert!(LossyCustom::from_bytes(&[PACKET_ID_LOSSY_CUSTOM_START - 1, 42]).is_err());
+    }
+
+    #[test]
+    fn lossy_custom_from_bytes_overflow() {
+        let mut bytes = vec![PACKET_ID_LOSSY_CUSTOM_START];
+        bytes.extend_from_slice(&[42; MAX_CUSTOM_DATA_SIZE + 1]);
+        assert!(LossyCustom::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn lossy_custom_to_bytes_invalid_id() {
+        let packet = LossyCustom::new(PACKET_ID_LOSSY_CUSTOM_START - 1, vec![42]);
+        let mut buf = [0; 2];
+        assert!(packet.to_bytes((&mut buf, 0)).is_err());
+    }
+}
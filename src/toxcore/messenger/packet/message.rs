@@ -0,0 +1,98 @@
+/*! Message struct.
+*/
+
+use std::str;
+use nom::rest;
+
+use toxcore::binary_io::*;
+
+/// Maximum size in bytes of message string of message and action packets
+pub const MAX_MESSAGE_DATA_SIZE: usize = 1372;
+
+/** Message is a struct that holds string of a chat message.
+
+This packet is used to send a normal chat message to a friend. Long
+messages should be split by the sender since the size of the packet is
+limited by the size of `net_crypto` data packet.
+
+Serialized form:
+
+Length    | Content
+--------- | ------
+`1`       | `0x40`
+`1..1372` | UTF8 byte string
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Message {
+    /// Chat message
+    pub message: String,
+}
+
+impl FromBytes for Message {
+    named!(from_bytes<Message>, do_parse!(
+        tag!("\x40") >>
+        message: map_res!(verify!(rest, |message: &[u8]| !message.is_empty() && message.len() <= MAX_MESSAGE_DATA_SIZE),
+            str::from_utf8) >>
+        (Message { message: message.to_string() })
+    ));
+}
+
+impl ToBytes for Message {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(0x40) >>
+            gen_cond!(self.message.is_empty() || self.message.len() > MAX_MESSAGE_DATA_SIZE, |buf| gen_error(buf, 0)) >>
+            gen_slice!(self.message.as_bytes())
+        )
+    }
+}
+
+impl Message {
+    /// Create new Message object.
+    pub fn new(message: String) -> Self {
+        Message { message }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    encode_decode_test!(
+        message_encode_decode,
+        Message::new("Hello".to_string())
+    );
+
+    #[test]
+    fn message_from_bytes_encoding_error() {
+        let err_string = vec![0x40, 0, 159, 146, 150]; // not UTF8 bytes.
+        assert!(Message::from_bytes(&err_string).is_err());
+    }
+
+    #[test]
+    fn message_from_bytes_empty() {
+        assert!(Message::from_bytes(&[0x40]).is_err());
+    }
+
+    #[test]
+    fn message_from_bytes_overflow() {
+        let mut large_string = vec![0x40];
+        large_string.extend_from_slice(&[32; MAX_MESSAGE_DATA_SIZE + 1]);
+        assert!(Message::from_bytes(&large_string).is_err());
+    }
+
+    #[test]
+    fn message_to_bytes_overflow() {
+        let large_string = String::from_utf8(vec![32u8; MAX_MESSAGE_DATA_SIZE + 1]).unwrap();
+        let large_message = Message::new(large_string);
+        let mut buf = [0; MAX_MESSAGE_DATA_SIZE + 2]; // `1` is for packet_id.
+        assert!(large_message.to_bytes((&mut buf, 0)).is_err());
+    }
+
+    #[test]
+    fn message_to_bytes_empty() {
+        let mut buf = [0; 1];
+        assert!(Message::new(String::new()).to_bytes((&mut buf, 0)).is_err());
+    }
+}
@@ -6,10 +6,36 @@ use toxcore::binary_io::*;
 mod online;
 mod offline;
 mod nickname;
+mod status_message;
+mod user_status;
+mod typing;
+mod message;
+mod action;
+mod msi;
+mod file_send_request;
+mod file_control;
+mod file_data;
+mod conference_invite;
+mod conference_invite_response;
+mod lossless_custom;
+mod lossy_custom;
 
 pub use self::online::*;
 pub use self::offline::*;
 pub use self::nickname::*;
+pub use self::status_message::*;
+pub use self::user_status::*;
+pub use self::typing::*;
+pub use self::message::*;
+pub use self::action::*;
+pub use self::msi::*;
+pub use self::file_send_request::*;
+pub use self::file_control::*;
+pub use self::file_data::*;
+pub use self::conference_invite::*;
+pub use self::conference_invite_response::*;
+pub use self::lossless_custom::*;
+pub use self::lossy_custom::*;
 
 /** Messenger packet enum that encapsulates all types of Messenger packets.
 */
@@ -21,6 +47,32 @@ pub enum Packet {
     Offline(Offline),
     /// [`Nickname`](./struct.Nickname.html) structure.
     Nickname(Nickname),
+    /// [`StatusMessage`](./struct.StatusMessage.html) structure.
+    StatusMessage(StatusMessage),
+    /// [`UserStatus`](./struct.UserStatus.html) structure.
+    UserStatus(UserStatus),
+    /// [`Typing`](./struct.Typing.html) structure.
+    Typing(Typing),
+    /// [`Message`](./struct.Message.html) structure.
+    Message(Message),
+    /// [`Action`](./struct.Action.html) structure.
+    Action(Action),
+    /// [`MsiPacket`](./struct.MsiPacket.html) structure.
+    MsiPacket(MsiPacket),
+    /// [`FileSendRequest`](./struct.FileSendRequest.html) structure.
+    FileSendRequest(FileSendRequest),
+    /// [`FileControl`](./struct.FileControl.html) structure.
+    FileControl(FileControl),
+    /// [`FileData`](./struct.FileData.html) structure.
+    FileData(FileData),
+    /// [`ConferenceInvite`](./struct.ConferenceInvite.html) structure.
+    ConferenceInvite(ConferenceInvite),
+    /// [`ConferenceInviteResponse`](./struct.ConferenceInviteResponse.html) structure.
+    ConferenceInviteResponse(ConferenceInviteResponse),
+    /// [`LosslessCustom`](./struct.LosslessCustom.html) structure.
+    LosslessCustom(LosslessCustom),
+    /// [`LossyCustom`](./struct.LossyCustom.html) structure.
+    LossyCustom(LossyCustom),
 }
 
 impl ToBytes for Packet {
@@ -29,6 +81,19 @@ impl ToBytes for Packet {
             Packet::Online(ref p) => p.to_bytes(buf),
             Packet::Offline(ref p) => p.to_bytes(buf),
             Packet::Nickname(ref p) => p.to_bytes(buf),
+            Packet::StatusMessage(ref p) => p.to_bytes(buf),
+            Packet::UserStatus(ref p) => p.to_bytes(buf),
+            Packet::Typing(ref p) => p.to_bytes(buf),
+            Packet::Message(ref p) => p.to_bytes(buf),
+            Packet::Action(ref p) => p.to_bytes(buf),
+            Packet::MsiPacket(ref p) => p.to_bytes(buf),
+            Packet::FileSendRequest(ref p) => p.to_bytes(buf),
+            Packet::FileControl(ref p) => p.to_bytes(buf),
+            Packet::FileData(ref p) => p.to_bytes(buf),
+            Packet::ConferenceInvite(ref p) => p.to_bytes(buf),
+            Packet::ConferenceInviteResponse(ref p) => p.to_bytes(buf),
+            Packet::LosslessCustom(ref p) => p.to_bytes(buf),
+            Packet::LossyCustom(ref p) => p.to_bytes(buf),
         }
     }
 }
@@ -37,7 +102,20 @@ impl FromBytes for Packet {
     named!(from_bytes<Packet>, alt!(
         map!(Online::from_bytes, Packet::Online) |
         map!(Offline::from_bytes, Packet::Offline) |
-        map!(Nickname::from_bytes, Packet::Nickname)
+        map!(Nickname::from_bytes, Packet::Nickname) |
+        map!(StatusMessage::from_bytes, Packet::StatusMessage) |
+        map!(UserStatus::from_bytes, Packet::UserStatus) |
+        map!(Typing::from_bytes, Packet::Typing) |
+        map!(Message::from_bytes, Packet::Message) |
+        map!(Action::from_bytes, Packet::Action) |
+        map!(MsiPacket::from_bytes, Packet::MsiPacket) |
+        map!(FileSendRequest::from_bytes, Packet::FileSendRequest) |
+        map!(FileControl::from_bytes, Packet::FileControl) |
+        map!(FileData::from_bytes, Packet::FileData) |
+        map!(ConferenceInvite::from_bytes, Packet::ConferenceInvite) |
+        map!(ConferenceInviteResponse::from_bytes, Packet::ConferenceInviteResponse) |
+        map!(LosslessCustom::from_bytes, Packet::LosslessCustom) |
+        map!(LossyCustom::from_bytes, Packet::LossyCustom)
     ));
 }
 
@@ -59,4 +137,69 @@ mod tests {
         packet_nickname_encode_decode,
         Packet::Nickname(Nickname::new("1234".to_string()))
     );
+
+    encode_decode_test!(
+        packet_status_message_encode_decode,
+        Packet::StatusMessage(StatusMessage::new("Working".to_string()))
+    );
+
+    encode_decode_test!(
+        packet_user_status_encode_decode,
+        Packet::UserStatus(UserStatus::new(PeerStatus::Away))
+    );
+
+    encode_decode_test!(
+        packet_typing_encode_decode,
+        Packet::Typing(Typing::new(true))
+    );
+
+    encode_decode_test!(
+        packet_message_encode_decode,
+        Packet::Message(Message::new("Hello".to_string()))
+    );
+
+    encode_decode_test!(
+        packet_action_encode_decode,
+        Packet::Action(Action::new("waves".to_string()))
+    );
+
+    encode_decode_test!(
+        packet_msi_encode_decode,
+        Packet::MsiPacket(MsiPacket::new(RequestKind::Init, Some(MsiErrorKind::None), Some(0x0f)))
+    );
+
+    encode_decode_test!(
+        packet_file_send_request_encode_decode,
+        Packet::FileSendRequest(FileSendRequest::new(1, FileType::Data, 4096, [42; FILE_UID_BYTES], "file.txt".to_string()))
+    );
+
+    encode_decode_test!(
+        packet_file_control_encode_decode,
+        Packet::FileControl(FileControl::new(TransferDirection::Receive, 1, ControlType::Seek(100)))
+    );
+
+    encode_decode_test!(
+        packet_file_data_encode_decode,
+        Packet::FileData(FileData::new(1, vec![42; 123]))
+    );
+
+    encode_decode_test!(
+        packet_conference_invite_encode_decode,
+        Packet::ConferenceInvite(ConferenceInvite::new(1, ConferenceType::Text, [42; CONFERENCE_UID_BYTES]))
+    );
+
+    encode_decode_test!(
+        packet_conference_invite_response_encode_decode,
+        Packet::ConferenceInviteResponse(ConferenceInviteResponse::new(1, 2, ConferenceType::Audio, [42; CONFERENCE_UID_BYTES]))
+    );
+
+    encode_decode_test!(
+        packet_lossless_custom_encode_decode,
+        Packet::LosslessCustom(LosslessCustom::new(PACKET_ID_LOSSLESS_CUSTOM_START, vec![42; 123]))
+    );
+
+    encode_decode_test!(
+        packet_lossy_custom_encode_decode,
+        Packet::LossyCustom(LossyCustom::new(PACKET_ID_LOSSY_CUSTOM_START, vec![42; 123]))
+    );
 }
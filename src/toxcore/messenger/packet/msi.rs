@@ -0,0 +1,235 @@
+/*! MsiPacket struct.
+*/
+
+use nom::le_u8;
+
+use toxcore::binary_io::*;
+
+/// Maximum size in bytes of msi packet
+pub const MAX_MSI_PACKET_SIZE: usize = 256;
+
+/// Id of request header
+const MSI_HEADER_REQUEST: u8 = 1;
+
+/// Id of error header
+const MSI_HEADER_ERROR: u8 = 2;
+
+/// Id of capabilities header
+const MSI_HEADER_CAPABILITIES: u8 = 3;
+
+/// Kind of request of msi packet.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RequestKind {
+    /// Request to start a call or to change its capabilities
+    Init = 0,
+    /// Answer to a call or change of capabilities
+    Push = 1,
+    /// Request to end a call
+    Pop = 2,
+}
+
+impl FromBytes for RequestKind {
+    named!(from_bytes<RequestKind>, switch!(le_u8,
+        0 => value!(RequestKind::Init) |
+        1 => value!(RequestKind::Push) |
+        2 => value!(RequestKind::Pop)
+    ));
+}
+
+/// Kind of error of msi packet.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MsiErrorKind {
+    /// No error
+    None = 0,
+    /// Received message is invalid
+    InvalidMessage = 1,
+    /// Received message has invalid parameter
+    InvalidParam = 2,
+    /// Received message is not valid in the current call state
+    InvalidState = 3,
+    /// Received message is not related to any call
+    StrayMessage = 4,
+    /// System error
+    SystemError = 5,
+    /// Failed to handle the message
+    Handle = 6,
+    /// Error is not disclosed
+    Undisclosed = 7,
+}
+
+impl FromBytes for MsiErrorKind {
+    named!(from_bytes<MsiErrorKind>, switch!(le_u8,
+        0 => value!(MsiErrorKind::None) |
+        1 => value!(MsiErrorKind::InvalidMessage) |
+        2 => value!(MsiErrorKind::InvalidParam) |
+        3 => value!(MsiErrorKind::InvalidState) |
+        4 => value!(MsiErrorKind::StrayMessage) |
+        5 => value!(MsiErrorKind::SystemError) |
+        6 => value!(MsiErrorKind::Handle) |
+        7 => value!(MsiErrorKind::Undisclosed)
+    ));
+}
+
+/// Header of msi packet.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum MsiHeader {
+    Request(RequestKind),
+    Error(MsiErrorKind),
+    Capabilities(u8),
+}
+
+impl FromBytes for MsiHeader {
+    named!(from_bytes<MsiHeader>, switch!(le_u8,
+        MSI_HEADER_REQUEST => do_parse!(
+            tag!("\x01") >>
+            request: call!(RequestKind::from_bytes) >>
+            (MsiHeader::Request(request))
+        ) |
+        MSI_HEADER_ERROR => do_parse!(
+            tag!("\x01") >>
+            error: call!(MsiErrorKind::from_bytes) >>
+            (MsiHeader::Error(error))
+        ) |
+        MSI_HEADER_CAPABILITIES => do_parse!(
+            tag!("\x01") >>
+            capabilities: le_u8 >>
+            (MsiHeader::Capabilities(capabilities))
+        )
+    ));
+}
+
+/** MsiPacket is a struct that holds signalling data of audio/video calls.
+
+This packet is used by MSI (Media Session Interface) protocol to start, answer,
+change and end audio/video calls.
+
+Serialized form:
+
+Length    | Content
+--------- | ------
+`1`       | `0x45`
+`3`       | Request header: `0x01`, `0x01`, request kind
+`0` or `3`| Error header: `0x02`, `0x01`, error kind
+`0` or `3`| Capabilities header: `0x03`, `0x01`, capabilities
+`1`       | `0x00`
+
+Headers can be placed in any order but each of them can appear only once.
+Request header is mandatory.
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MsiPacket {
+    /// Kind of the request
+    pub request: RequestKind,
+    /// Error that happened on the sender side
+    pub error: Option<MsiErrorKind>,
+    /// Bit flags of capabilities of the sender
+    pub capabilities: Option<u8>,
+}
+
+impl MsiPacket {
+    /// Create new MsiPacket object.
+    pub fn new(request: RequestKind, error: Option<MsiErrorKind>, capabilities: Option<u8>) -> Self {
+        MsiPacket { request, error, capabilities }
+    }
+
+    /// Build MsiPacket from parsed headers. Returns `None` if request header
+    /// is missing or some header is duplicated.
+    fn from_headers(headers: Vec<MsiHeader>) -> Option<MsiPacket> {
+        let mut request = None;
+        let mut error = None;
+        let mut capabilities = None;
+        for header in headers {
+            match header {
+                MsiHeader::Request(value) if request.is_none() => request = Some(value),
+                MsiHeader::Error(value) if error.is_none() => error = Some(value),
+                MsiHeader::Capabilities(value) if capabilities.is_none() => capabilities = Some(value),
+                _ => return None,
+            }
+        }
+        request.map(|request| MsiPacket { request, error, capabilities })
+    }
+}
+
+/// Serialize a header of msi packet.
+fn gen_msi_header(buf: (&mut [u8], usize), id: u8, value: u8) -> Result<(&mut [u8], usize), GenError> {
+    do_gen!(buf,
+        gen_be_u8!(id) >>
+        gen_be_u8!(1) >>
+        gen_be_u8!(value)
+    )
+}
+
+impl FromBytes for MsiPacket {
+    named!(from_bytes<MsiPacket>, do_parse!(
+        verify!(rest_len, |len| len <= MAX_MSI_PACKET_SIZE) >>
+        tag!("\x45") >>
+        packet: map_opt!(
+            many_till!(call!(MsiHeader::from_bytes), tag!("\x00")),
+            |(headers, _)| MsiPacket::from_headers(headers)
+        ) >>
+        (packet)
+    ));
+}
+
+impl ToBytes for MsiPacket {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(0x45) >>
+            gen_call!(|buf, request: &RequestKind| gen_msi_header(buf, MSI_HEADER_REQUEST, *request as u8), &self.request) >>
+            gen_cond!(self.error.is_some(), |buf| gen_msi_header(buf, MSI_HEADER_ERROR, self.error.map_or(0, |error| error as u8))) >>
+            gen_cond!(self.capabilities.is_some(), |buf| gen_msi_header(buf, MSI_HEADER_CAPABILITIES, self.capabilities.unwrap_or(0))) >>
+            gen_be_u8!(0) >>
+            gen_len_limit(MAX_MSI_PACKET_SIZE)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    encode_decode_test!(
+        msi_packet_encode_decode,
+        MsiPacket::new(RequestKind::Init, None, Some(0x0f))
+    );
+
+    encode_decode_test!(
+        msi_packet_with_error_encode_decode,
+        MsiPacket::new(RequestKind::Pop, Some(MsiErrorKind::InvalidState), None)
+    );
+
+    encode_decode_test!(
+        msi_packet_request_only_encode_decode,
+        MsiPacket::new(RequestKind::Push, None, None)
+    );
+
+    #[test]
+    fn msi_packet_any_headers_order() {
+        let bytes = [0x45, 3, 1, 0x0f, 1, 1, 1, 0];
+        let (_, packet) = MsiPacket::from_bytes(&bytes).unwrap();
+        assert_eq!(packet, MsiPacket::new(RequestKind::Push, None, Some(0x0f)));
+    }
+
+    #[test]
+    fn msi_packet_from_bytes_no_request() {
+        assert!(MsiPacket::from_bytes(&[0x45, 3, 1, 0x0f, 0]).is_err());
+    }
+
+    #[test]
+    fn msi_packet_from_bytes_duplicated_header() {
+        assert!(MsiPacket::from_bytes(&[0x45, 1, 1, 0, 1, 1, 2, 0]).is_err());
+    }
+
+    #[test]
+    fn msi_packet_from_bytes_invalid_header_size() {
+        assert!(MsiPacket::from_bytes(&[0x45, 1, 2, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn msi_packet_from_bytes_overflow() {
+        let mut bytes = vec![0x45, 1, 1, 0, 0];
+        bytes.extend_from_slice(&[0; MAX_MSI_PACKET_SIZE]);
+        assert!(MsiPacket::from_bytes(&bytes).is_err());
+    }
+}
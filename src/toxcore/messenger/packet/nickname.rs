@@ -7,7 +7,7 @@ use nom::rest;
 use toxcore::binary_io::*;
 
 /// Maximum size in bytes of nickname string of nickname packet
-const MAX_NICKNAME_DATA_SIZE: usize = 128;
+pub const MAX_NICKNAME_DATA_SIZE: usize = 128;
 
 /** Nickname is a struct that holds string of my nickname.
 
@@ -25,7 +25,8 @@ Length    | Content
 */
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Nickname {
-    nickname: String,
+    /// Nickname
+    pub nickname: String,
 }
 
 impl FromBytes for Nickname {
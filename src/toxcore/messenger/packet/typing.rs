@@ -0,0 +1,72 @@
+/*! Typing struct.
+*/
+
+use nom::le_u8;
+
+use toxcore::binary_io::*;
+
+/** Typing is a struct that holds typing status of the user.
+
+This packet is used to notify a friend that the user started or stopped
+typing a message to it.
+
+Serialized form:
+
+Length    | Content
+--------- | ------
+`1`       | `0x33`
+`1`       | Typing status (0 = not typing, 1 = typing)
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Typing {
+    /// Whether the user is typing
+    pub is_typing: bool,
+}
+
+impl FromBytes for Typing {
+    named!(from_bytes<Typing>, do_parse!(
+        tag!("\x33") >>
+        is_typing: switch!(le_u8,
+            0 => value!(false) |
+            1 => value!(true)
+        ) >>
+        (Typing { is_typing })
+    ));
+}
+
+impl ToBytes for Typing {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(0x33) >>
+            gen_be_u8!(self.is_typing as u8)
+        )
+    }
+}
+
+impl Typing {
+    /// Create new Typing object.
+    pub fn new(is_typing: bool) -> Self {
+        Typing { is_typing }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    encode_decode_test!(
+        typing_encode_decode,
+        Typing::new(true)
+    );
+
+    encode_decode_test!(
+        not_typing_encode_decode,
+        Typing::new(false)
+    );
+
+    #[test]
+    fn typing_from_bytes_invalid_status() {
+        assert!(Typing::from_bytes(&[0x33, 2]).is_err());
+    }
+}
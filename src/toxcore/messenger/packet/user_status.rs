@@ -0,0 +1,100 @@
+/*! UserStatus struct.
+*/
+
+use nom::le_u8;
+
+use toxcore::binary_io::*;
+
+/// Status of a peer that it sets by itself.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PeerStatus {
+    /// Peer is online and available
+    Online = 0,
+    /// Peer is away
+    Away = 1,
+    /// Peer is busy
+    Busy = 2,
+}
+
+impl FromBytes for PeerStatus {
+    named!(from_bytes<PeerStatus>, switch!(le_u8,
+        0 => value!(PeerStatus::Online) |
+        1 => value!(PeerStatus::Away) |
+        2 => value!(PeerStatus::Busy)
+    ));
+}
+
+impl ToBytes for PeerStatus {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        gen_be_u8!(buf, *self as u8)
+    }
+}
+
+/** UserStatus is a struct that holds status of the user.
+
+This packet is used to transmit sender's status to a friend.
+Every time a friend become online or my status is changed,
+this packet is sent to the friend or to all friends of mine.
+
+Serialized form:
+
+Length    | Content
+--------- | ------
+`1`       | `0x32`
+`1`       | Status (0 = online, 1 = away, 2 = busy)
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct UserStatus {
+    /// Status of the user
+    pub status: PeerStatus,
+}
+
+impl FromBytes for UserStatus {
+    named!(from_bytes<UserStatus>, do_parse!(
+        tag!("\x32") >>
+        status: call!(PeerStatus::from_bytes) >>
+        (UserStatus { status })
+    ));
+}
+
+impl ToBytes for UserStatus {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(0x32) >>
+            gen_call!(|buf, status| PeerStatus::to_bytes(status, buf), &self.status)
+        )
+    }
+}
+
+impl UserStatus {
+    /// Create new UserStatus object.
+    pub fn new(status: PeerStatus) -> Self {
+        UserStatus { status }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    encode_decode_test!(
+        user_status_online_encode_decode,
+        UserStatus::new(PeerStatus::Online)
+    );
+
+    encode_decode_test!(
+        user_status_away_encode_decode,
+        UserStatus::new(PeerStatus::Away)
+    );
+
+    encode_decode_test!(
+        user_status_busy_encode_decode,
+        UserStatus::new(PeerStatus::Busy)
+    );
+
+    #[test]
+    fn user_status_from_bytes_invalid_status() {
+        assert!(UserStatus::from_bytes(&[0x32, 3]).is_err());
+    }
+}
@@ -27,6 +27,19 @@ pub const DEFAULT_RTT: u64 = 1000;
 /// rtt in milliseconds for TCP connections
 pub const TCP_RTT: u64 = 500;
 
+/// How often in milliseconds request packets should be sent to the peer for an
+/// established connection
+pub const REQUEST_PACKET_INTERVAL: u64 = 1000;
+
+/// If we didn't send any data packet to an established connection for this
+/// amount of time in seconds then empty request packet is sent to keep the
+/// connection alive
+pub const CRYPTO_KEEPALIVE_INTERVAL: u64 = 8;
+
+/// If we don't receive data packets from an established connection for this
+/// amount of time in seconds then it's considered dead
+pub const CRYPTO_IDLE_TIMEOUT: u64 = 32;
+
 /// Packet that should be sent every second. Depending on `ConnectionStatus` it
 /// can be `CookieRequest` or `CryptoHandshake`
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -201,6 +214,107 @@ impl RecvPacket {
     }
 }
 
+/// Counters of data packets sent and received over one transport protocol
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct TransportStats {
+    /// Number of sent data packets
+    pub packets_sent: u64,
+    /// Number of bytes in sent data packets
+    pub bytes_sent: u64,
+    /// Number of received data packets
+    pub packets_received: u64,
+    /// Number of bytes in received data packets
+    pub bytes_received: u64,
+}
+
+impl TransportStats {
+    /// Count sent data packet of the given size
+    pub fn add_sent(&mut self, size: usize) {
+        self.packets_sent += 1;
+        self.bytes_sent += size as u64;
+    }
+
+    /// Count received data packet of the given size
+    pub fn add_received(&mut self, size: usize) {
+        self.packets_received += 1;
+        self.bytes_received += size as u64;
+    }
+}
+
+/// Path to the peer through a TCP relay. Packets received via TCP relays come
+/// with it and replies are sent back the same way.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TcpOrigin {
+    /// Out of band packet from the peer connected to the relay
+    Oob {
+        /// `PublicKey` of the TCP relay
+        relay_pk: PublicKey,
+        /// DHT `PublicKey` of the peer
+        sender_pk: PublicKey,
+    },
+    /// Data packet routed by the relay
+    Data {
+        /// `PublicKey` of the TCP relay
+        relay_pk: PublicKey,
+        /// Id of the routed connection to the peer
+        connection_id: u8,
+    },
+}
+
+/// Transport protocol that is currently used to send packets to the peer
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ConnectionTransport {
+    /// Packets are sent directly to the peer's UDP address
+    Udp(SocketAddr),
+    /// Packets are sent via TCP relay the same way as they were received
+    Tcp(TcpOrigin),
+    /// There is no working path to the peer: UDP address is unknown or not
+    /// alive and no packets were received via TCP relays
+    Unreachable,
+}
+
+/// Identifier of lossless packet sent via crypto connection. Besides the
+/// number of the packet in the sending buffer it contains id of the connection
+/// so numbers of packets sent via previous connections to the same peer are
+/// never considered delivered.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct LosslessPacketId {
+    /// Id of the crypto connection the packet was sent via
+    pub connection_id: u64,
+    /// Number of the packet in the sending buffer
+    pub packet_number: u32,
+}
+
+/// Snapshot of crypto connection state and statistics
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ConnectionInfo {
+    /// Long term `PublicKey` of the peer
+    pub peer_real_pk: PublicKey,
+    /// DHT `PublicKey` of the peer
+    pub peer_dht_pk: PublicKey,
+    /// Whether connection is fully established
+    pub is_established: bool,
+    /// Current round trip time
+    pub rtt: Duration,
+    /// Transport protocol that is currently used to send packets
+    pub transport: ConnectionTransport,
+    /// Known UDP address of the peer
+    pub udp_addr: Option<SocketAddr>,
+    /// Counters of data packets sent and received via UDP
+    pub udp_stats: TransportStats,
+    /// Counters of data packets sent and received via TCP relays
+    pub tcp_stats: TransportStats,
+    /// Number of lossless packets that were resent because the peer requested
+    /// them
+    pub retransmissions: u64,
+    /// Number of packets in the sending buffer that are not confirmed yet
+    pub send_array_len: u32,
+    /// Number of packets in the receiving buffer that are not handled yet
+    pub recv_array_len: u32,
+    /// Time elapsed since the connection was created
+    pub age: Duration,
+}
+
 /** Secure connection to send data between two friends that provides encryption,
 ordered delivery, and perfect forward secrecy.
 
@@ -210,6 +324,9 @@ can switch between them without the peers needing to disconnect and reconnect.
 */
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct CryptoConnection {
+    /// Random id that distinguishes this connection from previous and next
+    /// connections to the same peer
+    pub id: u64,
     /// Precomputed key of our DHT `SecretKey` and peer's DHT `PublicKey`
     pub dht_precomputed_key: PrecomputedKey,
     /// Long term `PublicKey` of the peer we are connected to
@@ -228,6 +345,9 @@ pub struct CryptoConnection {
     pub udp_received_time: Option<Instant>,
     /// Time when we made an attempt to send UDP packet
     pub udp_send_attempt_time: Option<Instant>,
+    /// Path through TCP relay that is used to send packets to the peer when
+    /// UDP is not available
+    pub tcp_origin: Option<TcpOrigin>,
     /// Buffer of sent packets
     pub send_array: PacketsArray<SentPacket>,
     /// Buffer of received packets
@@ -235,6 +355,28 @@ pub struct CryptoConnection {
     /// Round trip time - the lowest (for all packets) difference between time
     /// when a packet was sent and time when we received the confirmation
     pub rtt: Duration,
+    /// Time when we sent request packet last time
+    pub request_packet_sent_time: Option<Instant>,
+    /// Time when we sent any data packet last time
+    pub data_sent_time: Option<Instant>,
+    /// Start of the receiving buffer that we sent to the peer last time. When
+    /// it differs from the current one the peer doesn't know about received
+    /// packets yet
+    pub acked_buffer_start: u32,
+    /// Time when we received valid data packet last time
+    pub data_received_time: Option<Instant>,
+    /// The highest nonce of received valid data packets. It's used to reject
+    /// replayed packets coming from unknown addresses
+    pub last_received_nonce: Option<Nonce>,
+    /// Time when this connection was created
+    pub created_time: Instant,
+    /// Counters of data packets sent and received via UDP
+    pub udp_stats: TransportStats,
+    /// Counters of data packets sent and received via TCP relays
+    pub tcp_stats: TransportStats,
+    /// Number of lossless packets that were resent because the peer requested
+    /// them
+    pub retransmissions: u64,
 }
 
 impl CryptoConnection {
@@ -256,6 +398,7 @@ impl CryptoConnection {
         };
 
         CryptoConnection {
+            id: random_u64(),
             dht_precomputed_key,
             peer_real_pk,
             peer_dht_pk,
@@ -265,9 +408,19 @@ impl CryptoConnection {
             udp_addr: None,
             udp_received_time: None,
             udp_send_attempt_time: None,
+            tcp_origin: None,
             send_array: PacketsArray::new(),
             recv_array: PacketsArray::new(),
             rtt: Duration::from_millis(DEFAULT_RTT),
+            request_packet_sent_time: None,
+            data_sent_time: None,
+            acked_buffer_start: 0,
+            data_received_time: None,
+            last_received_nonce: None,
+            created_time: clock_now(),
+            udp_stats: TransportStats::default(),
+            tcp_stats: TransportStats::default(),
+            retransmissions: 0,
         }
     }
 
@@ -305,6 +458,7 @@ impl CryptoConnection {
         };
 
         CryptoConnection {
+            id: random_u64(),
             dht_precomputed_key,
             peer_real_pk,
             peer_dht_pk,
@@ -314,9 +468,19 @@ impl CryptoConnection {
             udp_addr: None,
             udp_received_time: None,
             udp_send_attempt_time: None,
+            tcp_origin: None,
             send_array: PacketsArray::new(),
             recv_array: PacketsArray::new(),
             rtt: Duration::from_millis(DEFAULT_RTT),
+            request_packet_sent_time: None,
+            data_sent_time: None,
+            acked_buffer_start: 0,
+            data_received_time: None,
+            last_received_nonce: None,
+            created_time: clock_now(),
+            udp_stats: TransportStats::default(),
+            tcp_stats: TransportStats::default(),
+            retransmissions: 0,
         }
     }
 
@@ -340,13 +504,83 @@ impl CryptoConnection {
     }
 
     /// Check if this connection is timed out, i.e. we didn't receive expected
-    /// packet in time
-    pub fn is_timed_out(&self) -> bool {
+    /// packet in time. Established connection is timed out when we didn't
+    /// receive data packets for `idle_timeout`. If we never received data
+    /// packets the time of connection creation is used instead.
+    pub fn is_timed_out(&self, idle_timeout: Duration) -> bool {
         match self.status {
             ConnectionStatus::CookieRequesting { ref packet, .. }
             | ConnectionStatus::HandshakeSending { ref packet, .. }
             | ConnectionStatus::NotConfirmed { ref packet, .. } => packet.is_timed_out(),
-            ConnectionStatus::Established { .. } => false, // TODO: timeout?
+            ConnectionStatus::Established { .. } => {
+                let time = self.data_received_time.unwrap_or(self.created_time);
+                clock_elapsed(time) > idle_timeout
+            },
+        }
+    }
+
+    /// Check if this connection is established, i.e. we received at least one
+    /// valid `CryptoData` packet from the peer
+    pub fn is_established(&self) -> bool {
+        match self.status {
+            ConnectionStatus::Established { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Check if request packet should be sent to the peer. It's sent not
+    /// often than once per `REQUEST_PACKET_INTERVAL` and only when it's
+    /// needed: for not confirmed connections it's the first data packet that
+    /// makes connection established, for established connections it requests
+    /// missing packets or acknowledges received ones. Idle connections rely
+    /// on keepalive packets instead
+    pub fn request_packet_should_be_sent(&self) -> bool {
+        let needed = match self.status {
+            ConnectionStatus::NotConfirmed { .. } => true,
+            ConnectionStatus::Established { .. } =>
+                self.recv_array.len() > 0 || self.recv_array.buffer_start != self.acked_buffer_start,
+            _ => false,
+        };
+        needed && self.request_packet_sent_time
+            .map(|time| clock_elapsed(time) >= Duration::from_millis(REQUEST_PACKET_INTERVAL))
+            .unwrap_or(true)
+    }
+
+    /// Check if keepalive packet should be sent to the peer, i.e. connection
+    /// is established and we didn't send anything for
+    /// `CRYPTO_KEEPALIVE_INTERVAL`
+    pub fn keepalive_should_be_sent(&self) -> bool {
+        self.is_established() && self.data_sent_time
+            .map(|time| clock_elapsed(time) >= Duration::from_secs(CRYPTO_KEEPALIVE_INTERVAL))
+            .unwrap_or(true)
+    }
+
+    /// Transport protocol that is currently used to send packets to the peer.
+    /// UDP is used only when the peer's address is known and it's alive,
+    /// otherwise packets go via TCP relay they were received from.
+    pub fn transport(&self) -> ConnectionTransport {
+        match (self.udp_addr, self.tcp_origin) {
+            (Some(addr), _) if self.is_udp_alive() => ConnectionTransport::Udp(addr),
+            (_, Some(origin)) => ConnectionTransport::Tcp(origin),
+            _ => ConnectionTransport::Unreachable,
+        }
+    }
+
+    /// Get snapshot of connection state and statistics
+    pub fn info(&self) -> ConnectionInfo {
+        ConnectionInfo {
+            peer_real_pk: self.peer_real_pk,
+            peer_dht_pk: self.peer_dht_pk,
+            is_established: self.is_established(),
+            rtt: self.rtt,
+            transport: self.transport(),
+            udp_addr: self.udp_addr,
+            udp_stats: self.udp_stats,
+            tcp_stats: self.tcp_stats,
+            retransmissions: self.retransmissions,
+            send_array_len: self.send_array.len(),
+            recv_array_len: self.recv_array.len(),
+            age: clock_elapsed(self.created_time),
         }
     }
 
@@ -453,6 +687,223 @@ mod tests {
         assert_eq!(recv_packet_c, recv_packet);
     }
 
+    #[test]
+    fn established_connection_is_timed_out() {
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(&dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        connection.status = ConnectionStatus::Established {
+            sent_nonce: gen_nonce(),
+            received_nonce: gen_nonce(),
+            peer_session_pk,
+            session_precomputed_key: precompute(&peer_session_pk, &session_sk),
+        };
+
+        let idle_timeout = Duration::from_secs(CRYPTO_IDLE_TIMEOUT);
+        let now = clock_now();
+        connection.data_received_time = Some(now);
+
+        assert!(!connection.is_timed_out(idle_timeout));
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(now + idle_timeout + Duration::from_secs(1)));
+
+        with_default(&clock, &mut enter, |_| {
+            assert!(connection.is_timed_out(idle_timeout));
+        });
+    }
+
+    #[test]
+    fn established_connection_without_data_is_timed_out() {
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(&dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        connection.status = ConnectionStatus::Established {
+            sent_nonce: gen_nonce(),
+            received_nonce: gen_nonce(),
+            peer_session_pk,
+            session_precomputed_key: precompute(&peer_session_pk, &session_sk),
+        };
+
+        let idle_timeout = Duration::from_secs(CRYPTO_IDLE_TIMEOUT);
+        assert!(connection.data_received_time.is_none());
+        assert!(!connection.is_timed_out(idle_timeout));
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(connection.created_time + idle_timeout + Duration::from_secs(1)));
+
+        with_default(&clock, &mut enter, |_| {
+            assert!(connection.is_timed_out(idle_timeout));
+        });
+    }
+
+    #[test]
+    fn request_packet_should_be_sent() {
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(&dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        // connection without session keys doesn't send request packets
+        assert!(!connection.request_packet_should_be_sent());
+
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let crypto_handshake = CryptoHandshake {
+            cookie: EncryptedCookie {
+                nonce: secretbox::gen_nonce(),
+                payload: vec![42; 88]
+            },
+            nonce: gen_nonce(),
+            payload: vec![42; 248]
+        };
+        connection.status = ConnectionStatus::NotConfirmed {
+            sent_nonce: gen_nonce(),
+            received_nonce: gen_nonce(),
+            peer_session_pk,
+            session_precomputed_key: precompute(&peer_session_pk, &session_sk),
+            packet: StatusPacket::new_crypto_handshake(crypto_handshake),
+        };
+
+        // not confirmed connection sends request packets so that the peer
+        // receives data packet and considers the connection established
+        assert!(connection.request_packet_should_be_sent());
+
+        connection.status = ConnectionStatus::Established {
+            sent_nonce: gen_nonce(),
+            received_nonce: gen_nonce(),
+            peer_session_pk,
+            session_precomputed_key: precompute(&peer_session_pk, &session_sk),
+        };
+
+        // idle established connection doesn't send request packets
+        assert_eq!(connection.recv_array.len(), 0);
+        assert!(!connection.request_packet_should_be_sent());
+
+        // received packets that the peer doesn't know about should be
+        // acknowledged
+        connection.recv_array.buffer_start = 1;
+        connection.recv_array.buffer_end = 1;
+        assert!(connection.request_packet_should_be_sent());
+
+        connection.acked_buffer_start = 1;
+        assert!(!connection.request_packet_should_be_sent());
+
+        // missing packets should be requested
+        connection.recv_array.buffer_end = 2;
+        assert!(connection.request_packet_should_be_sent());
+
+        let now = clock_now();
+        connection.request_packet_sent_time = Some(now);
+        assert!(!connection.request_packet_should_be_sent());
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(now + Duration::from_millis(REQUEST_PACKET_INTERVAL)));
+
+        with_default(&clock, &mut enter, |_| {
+            assert!(connection.request_packet_should_be_sent());
+        });
+    }
+
+    #[test]
+    fn keepalive_should_be_sent() {
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(&dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        // not established connection doesn't need keepalive
+        assert!(!connection.keepalive_should_be_sent());
+
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        connection.status = ConnectionStatus::Established {
+            sent_nonce: gen_nonce(),
+            received_nonce: gen_nonce(),
+            peer_session_pk,
+            session_precomputed_key: precompute(&peer_session_pk, &session_sk),
+        };
+
+        let now = clock_now();
+        connection.data_sent_time = Some(now);
+
+        assert!(!connection.keepalive_should_be_sent());
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(now + Duration::from_secs(CRYPTO_KEEPALIVE_INTERVAL)));
+
+        with_default(&clock, &mut enter, |_| {
+            assert!(connection.keepalive_should_be_sent());
+        });
+    }
+
+    #[test]
+    fn transport_stats() {
+        let mut stats = TransportStats::default();
+        stats.add_sent(100);
+        stats.add_sent(50);
+        stats.add_received(42);
+
+        assert_eq!(stats, TransportStats {
+            packets_sent: 2,
+            bytes_sent: 150,
+            packets_received: 1,
+            bytes_received: 42,
+        });
+    }
+
+    #[test]
+    fn connection_info() {
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(&dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        assert!(connection.send_array.push_back(SentPacket::new(vec![42; 123])).is_ok());
+        assert!(connection.recv_array.insert(2, RecvPacket::new(vec![42; 123])).is_ok());
+        connection.udp_stats.add_sent(123);
+        connection.retransmissions = 7;
+
+        let info = connection.info();
+        assert_eq!(info.peer_real_pk, peer_real_pk);
+        assert_eq!(info.peer_dht_pk, peer_dht_pk);
+        assert!(!info.is_established);
+        assert_eq!(info.rtt, Duration::from_millis(DEFAULT_RTT));
+        assert_eq!(info.transport, ConnectionTransport::Unreachable);
+        assert_eq!(info.udp_stats.packets_sent, 1);
+        assert_eq!(info.tcp_stats, TransportStats::default());
+        assert_eq!(info.retransmissions, 7);
+        assert_eq!(info.send_array_len, 1);
+        assert_eq!(info.recv_array_len, 3);
+
+        // TCP relay is used when UDP is not alive
+        let origin = TcpOrigin::Data {
+            relay_pk: gen_keypair().0,
+            connection_id: 42,
+        };
+        connection.tcp_origin = Some(origin);
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.udp_addr = Some(addr);
+        assert_eq!(connection.info().transport, ConnectionTransport::Tcp(origin));
+
+        // UDP is used when it's alive
+        connection.update_udp_received_time();
+        assert_eq!(connection.info().transport, ConnectionTransport::Udp(addr));
+    }
+
     #[test]
     fn crypto_connection_clone() {
         let (dht_pk, dht_sk) = gen_keypair();
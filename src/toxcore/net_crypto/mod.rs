@@ -12,16 +12,18 @@ TCP relays.
 
 mod crypto_connection;
 mod packets_array;
+mod stream;
 
 pub use self::crypto_connection::*;
 use self::packets_array::*;
+pub use self::stream::*;
 
 use std::collections::HashMap;
 use std::io::{ErrorKind, Error};
 use std::net::{SocketAddr, IpAddr};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
-use std::u16;
+use std::{u16, u32};
 
 use futures::Future;
 use futures::future;
@@ -33,6 +35,8 @@ use toxcore::crypto_core::*;
 use toxcore::dht::packet::*;
 use toxcore::dht::precomputed_cache::*;
 use toxcore::io_tokio::*;
+use toxcore::tcp::connections::{IncomingPacket, OutgoingPacket};
+use toxcore::tcp::packet::{Data, OobSend, MAX_TCP_PACKET_SIZE};
 use toxcore::time::*;
 
 /// Maximum size of `Packet` when we try to send it to UDP address even if
@@ -51,6 +55,11 @@ const PACKET_ID_REQUEST: u8 = 1;
 /// Packet with this ID means that this crypto connection should be killed.
 const PACKET_ID_KILL: u8 = 2;
 
+/// Kill packet is resent this number of times after the connection is killed
+/// since the peer drops the connection when it receives the packet and can't
+/// acknowledge it.
+const MAX_KILL_PACKET_RESENDS: u32 = 3;
+
 /// Packets with ID from 0 to `PACKET_ID_CRYPTO_RANGE_END` are reserved for
 /// `net_crypto`.
 const PACKET_ID_CRYPTO_RANGE_END: u8 = 15;
@@ -63,10 +72,18 @@ const PACKET_ID_LOSSY_RANGE_START: u8 = 192;
 /// `PACKET_ID_LOSSY_RANGE_END` are considered lossy packets.
 const PACKET_ID_LOSSY_RANGE_END: u8 = 254;
 
+/// Maximum number of connections we try to decrypt `CryptoData` packet with
+/// when it's received from unknown UDP address.
+const MAX_ROAMING_CANDIDATES: usize = 8;
+
 /// Shorthand for the transmit half of the message channel for sending DHT
 /// packets.
 type UdpTx = mpsc::UnboundedSender<(Packet, SocketAddr)>;
 
+/// Shorthand for the transmit half of the message channel for sending packets
+/// to TCP relays. The key is a `PublicKey` of the relay.
+type TcpTx = mpsc::UnboundedSender<(OutgoingPacket, PublicKey)>;
+
 /// Shorthand for the transmit half of the message channel for sending DHT
 /// `PublicKey` when it gets known. The first key is a long term key, the second
 /// key is a DHT key.
@@ -82,6 +99,12 @@ type LosslessTx = mpsc::UnboundedSender<(PublicKey, Vec<u8>)>;
 /// packet.
 type LossyTx = mpsc::UnboundedSender<(PublicKey, Vec<u8>)>;
 
+/// Shorthand for the transmit half of the message channel for sending
+/// connection status changes. The key is a long term public key of the peer,
+/// the flag is `true` when connection becomes established and `false` when it
+/// is killed or timed out.
+type ConnectionStatusTx = mpsc::UnboundedSender<(PublicKey, bool)>;
+
 /// Arguments for creating new `NetCrypto`.
 #[derive(Clone)]
 pub struct NetCryptoNewArgs {
@@ -111,6 +134,18 @@ pub struct NetCryptoNewArgs {
     pub precomputed_keys: PrecomputedCache,
 }
 
+/// Connection killed by us. It's kept for some time to resend the kill packet
+/// in case it was lost.
+#[derive(Clone, Debug)]
+struct KilledConnection {
+    /// The connection with the kill packet in its sending buffer
+    connection: CryptoConnection,
+    /// Number of the kill packet in the sending buffer
+    packet_number: u32,
+    /// How many times the kill packet was resent
+    resends: u32,
+}
+
 /// Struct that manages crypto connections to friends and handles net crypto
 /// packets from both UDP and TCP connections.
 #[derive(Clone)]
@@ -143,9 +178,32 @@ pub struct NetCrypto {
     /// Long term keys by IP address of DHT node map. `SocketAddr` can't be used
     /// as a key since it contains additional info for `IPv6` address.
     keys_by_addr: Arc<RwLock<HashMap<(IpAddr, /*port*/ u16), PublicKey>>>,
+    /// Addresses where peers might be reachable via UDP by their DHT
+    /// `PublicKey`, e.g. returned by their DHT close nodes. They are used to
+    /// find the connection when the peer's UDP address changes
+    udp_addr_hints: Arc<RwLock<HashMap<PublicKey, Vec<SocketAddr>>>>,
+    /// UDP addresses of peers found by DHT by their DHT `PublicKey`. They are
+    /// given to connections created after the address was found
+    dht_udp_addrs: Arc<RwLock<HashMap<PublicKey, SocketAddr>>>,
+    /// DHT `PublicKey`s of peers routed through TCP relays by relay
+    /// `PublicKey` and connection id. They are learned from `RouteResponse`
+    /// packets and used to find connections by routed data packets
+    tcp_routes: Arc<RwLock<HashMap<(PublicKey, u8), PublicKey>>>,
     /// Lru cache for precomputed keys. It stores precomputed keys to avoid
     /// redundant calculations.
     precomputed_keys: PrecomputedCache,
+    /// Handles of opened `CryptoStream`s by long term public key of the peer
+    streams: Arc<RwLock<HashMap<PublicKey, StreamHandle>>>,
+    /// Connections killed by us that resend the kill packet
+    killed_connections: Arc<RwLock<Vec<KilledConnection>>>,
+    /// Sink to send connection status changes
+    connection_status_tx: Arc<RwLock<Option<ConnectionStatusTx>>>,
+    /// Sink to send packets to TCP relays. It can be `None` in case of pure
+    /// UDP usage
+    tcp_tx: Arc<RwLock<Option<TcpTx>>>,
+    /// Established connection is considered dead if we don't receive data
+    /// packets from it for this amount of time
+    idle_timeout: Arc<RwLock<Duration>>,
 }
 
 impl NetCrypto {
@@ -162,8 +220,168 @@ impl NetCrypto {
             symmetric_key: secretbox::gen_key(),
             connections: Arc::new(RwLock::new(HashMap::new())),
             keys_by_addr: Arc::new(RwLock::new(HashMap::new())),
+            udp_addr_hints: Arc::new(RwLock::new(HashMap::new())),
+            dht_udp_addrs: Arc::new(RwLock::new(HashMap::new())),
+            tcp_routes: Arc::new(RwLock::new(HashMap::new())),
             precomputed_keys: args.precomputed_keys,
+            streams: Arc::new(RwLock::new(HashMap::new())),
+            killed_connections: Arc::new(RwLock::new(Vec::new())),
+            connection_status_tx: Arc::new(RwLock::new(None)),
+            tcp_tx: Arc::new(RwLock::new(None)),
+            idle_timeout: Arc::new(RwLock::new(Duration::from_secs(CRYPTO_IDLE_TIMEOUT))),
+        }
+    }
+
+    /// Set sink to send connection status changes.
+    pub fn set_connection_status_sink(&self, connection_status_tx: ConnectionStatusTx) {
+        *self.connection_status_tx.write() = Some(connection_status_tx);
+    }
+
+    /// Set sink to send packets to TCP relays.
+    pub fn set_tcp_sink(&self, tcp_tx: TcpTx) {
+        *self.tcp_tx.write() = Some(tcp_tx);
+    }
+
+    /// Set timeout after which established connection is considered dead if
+    /// we don't receive data packets from it. Default value is
+    /// `CRYPTO_IDLE_TIMEOUT` seconds.
+    pub fn set_idle_timeout(&self, idle_timeout: Duration) {
+        *self.idle_timeout.write() = idle_timeout;
+    }
+
+    /// Send connection status change to the sink if it's set
+    fn send_connection_status(&self, peer_real_pk: PublicKey, status: bool) -> IoFuture<()> {
+        if let Some(ref connection_status_tx) = *self.connection_status_tx.read() {
+            send_to(connection_status_tx, (peer_real_pk, status))
+        } else {
+            Box::new(future::ok(()))
+        }
+    }
+
+    /// Remove crypto connection together with its UDP address and opened
+    /// stream and report that it went offline.
+    fn drop_connection(&self, peer_real_pk: PublicKey, udp_addr: Option<SocketAddr>) -> IoFuture<()> {
+        self.connections.write().remove(&peer_real_pk);
+        if let Some(addr) = udp_addr {
+            let mut keys_by_addr = self.keys_by_addr.write();
+            // The address might be taken by another connection already
+            if keys_by_addr.get(&(addr.ip(), addr.port())) == Some(&peer_real_pk) {
+                keys_by_addr.remove(&(addr.ip(), addr.port()));
+            }
+        }
+        self.close_stream(peer_real_pk);
+        self.send_connection_status(peer_real_pk, false)
+    }
+
+    /// Kill crypto connection to a friend. The kill packet is sent to the peer
+    /// so it can remove the connection immediately instead of waiting for the
+    /// timeout. It's stored in the sending buffer like lossless packets and
+    /// resent by the main loop a few times since the peer can't acknowledge
+    /// it.
+    pub fn kill_connection(&self, peer_real_pk: PublicKey) -> IoFuture<()> {
+        let connection = match self.connection_by_key(peer_real_pk) {
+            Some(connection) => connection,
+            None => return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                format!("No crypto connection for key {:?}", peer_real_pk)
+            ))),
+        };
+
+        let (kill_future, udp_addr) = {
+            let mut connection = connection.write();
+            let kill_future = match connection.status {
+                ConnectionStatus::NotConfirmed { .. } | ConnectionStatus::Established { .. } => {
+                    let packet_number = connection.send_array.buffer_end;
+                    let kill_future = self.store_and_send_packet(&mut connection, vec![PACKET_ID_KILL]);
+                    self.killed_connections.write().push(KilledConnection {
+                        connection: connection.clone(),
+                        packet_number,
+                        resends: 0,
+                    });
+                    kill_future
+                },
+                // The peer doesn't have session keys yet and can't handle kill packet
+                _ => Box::new(future::ok(())),
+            };
+            (kill_future, connection.udp_addr)
+        };
+        let drop_future = self.drop_connection(peer_real_pk, udp_addr);
+
+        Box::new(kill_future.join(drop_future).map(|_| ()))
+    }
+
+    /// Add new crypto connection to a friend and start sending `CookieRequest`
+    /// packets to it. Does nothing if the connection already exists. If DHT
+    /// has found UDP address of the friend it's used for the new connection.
+    pub fn add_connection(&self, peer_real_pk: PublicKey, peer_dht_pk: PublicKey) {
+        let mut connections = self.connections.write();
+        if connections.contains_key(&peer_real_pk) {
+            return
+        }
+        let mut connection = CryptoConnection::new(&self.dht_sk, self.dht_pk, self.real_pk, peer_real_pk, peer_dht_pk);
+        if let Some(&addr) = self.dht_udp_addrs.read().get(&peer_dht_pk) {
+            self.set_connection_udp_addr(&mut connection, addr);
+        }
+        connections.insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+    }
+
+    /// Set UDP address of a friend that will be used to send packets to it
+    /// directly.
+    pub fn set_friend_udp_addr(&self, peer_real_pk: PublicKey, addr: SocketAddr) {
+        if let Some(connection) = self.connection_by_key(peer_real_pk) {
+            self.set_connection_udp_addr(&mut connection.write(), addr);
+        }
+    }
+
+    /// Set addresses where a friend with the given DHT `PublicKey` might be
+    /// reachable. When `CryptoData` packet is received from unknown address
+    /// connections having a hint with the same IP are checked first.
+    pub fn set_udp_addr_hints(&self, peer_dht_pk: PublicKey, addrs: Vec<SocketAddr>) {
+        let mut udp_addr_hints = self.udp_addr_hints.write();
+        if addrs.is_empty() {
+            udp_addr_hints.remove(&peer_dht_pk);
+        } else {
+            udp_addr_hints.insert(peer_dht_pk, addrs);
+        }
+    }
+
+    /// Set UDP address of a friend with the given DHT `PublicKey` found by
+    /// DHT. It's passed to existing connections to the friend and remembered
+    /// for connections that will be created later.
+    pub fn set_dht_udp_addr(&self, peer_dht_pk: PublicKey, addr: SocketAddr) {
+        self.dht_udp_addrs.write().insert(peer_dht_pk, addr);
+        let peers = self.connections.read().values()
+            .map(|connection| connection.read())
+            .filter(|connection| connection.peer_dht_pk == peer_dht_pk)
+            .map(|connection| connection.peer_real_pk)
+            .collect::<Vec<_>>();
+        for peer_real_pk in peers {
+            self.set_friend_udp_addr(peer_real_pk, addr);
+        }
+    }
+
+    /// Get addresses where a friend with the given DHT `PublicKey` might be
+    /// reachable.
+    pub fn udp_addr_hints(&self, peer_dht_pk: PublicKey) -> Vec<SocketAddr> {
+        self.udp_addr_hints.read().get(&peer_dht_pk).cloned().unwrap_or_default()
+    }
+
+    /// Set path through TCP relay that will be used to send packets to a
+    /// friend when UDP is not available.
+    pub fn set_friend_tcp_origin(&self, peer_real_pk: PublicKey, origin: TcpOrigin) {
+        if let Some(connection) = self.connection_by_key(peer_real_pk) {
+            connection.write().tcp_origin = Some(origin);
+        }
+    }
+
+    /// Set UDP address of the connection replacing the old one
+    fn set_connection_udp_addr(&self, connection: &mut CryptoConnection, addr: SocketAddr) {
+        let mut keys_by_addr = self.keys_by_addr.write();
+        if let Some(old_addr) = connection.udp_addr {
+            keys_by_addr.remove(&(old_addr.ip(), old_addr.port()));
         }
+        connection.udp_addr = Some(addr);
+        keys_by_addr.insert((addr.ip(), addr.port()), connection.peer_real_pk);
     }
 
     /// Send `Packet` packet to UDP socket
@@ -171,6 +389,50 @@ impl NetCrypto {
         send_to(&self.udp_tx, (packet, addr))
     }
 
+    /// Send `Packet` packet to TCP relay so that it's delivered to the peer the
+    /// same way as packets with the given origin are received from it
+    fn send_to_tcp(&self, origin: TcpOrigin, packet: Packet) -> IoFuture<()> {
+        let mut buf = [0; MAX_TCP_PACKET_SIZE];
+        let data = match packet.to_bytes((&mut buf, 0)) {
+            Ok((_, size)) => buf[..size].to_vec(),
+            Err(e) => return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                format!("Failed to serialize packet: {:?}", e)
+            ))),
+        };
+
+        let (packet, relay_pk) = match origin {
+            TcpOrigin::Oob { relay_pk, sender_pk } =>
+                (OutgoingPacket::OobSend(OobSend { destination_pk: sender_pk, data }), relay_pk),
+            TcpOrigin::Data { relay_pk, connection_id } =>
+                (OutgoingPacket::Data(Data { connection_id, data }), relay_pk),
+        };
+        if let Some(ref tcp_tx) = *self.tcp_tx.read() {
+            send_to(tcp_tx, (packet, relay_pk))
+        } else {
+            Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                "TCP sink is not set"
+            )))
+        }
+    }
+
+    /// Get crypto connection by the origin of a packet received via TCP relay.
+    /// Out of band packets are matched by DHT `PublicKey` of the sender, data
+    /// packets by DHT `PublicKey` of the peer the relay routes them from or by
+    /// the path set for the connection if the route is unknown.
+    fn connection_by_tcp_origin(&self, origin: TcpOrigin) -> Option<Arc<RwLock<CryptoConnection>>> {
+        let peer_dht_pk = match origin {
+            TcpOrigin::Oob { sender_pk, .. } => Some(sender_pk),
+            TcpOrigin::Data { relay_pk, connection_id } =>
+                self.tcp_routes.read().get(&(relay_pk, connection_id)).cloned(),
+        };
+        self.connections.read().values().find(|connection| {
+            let connection = connection.read();
+            connection.tcp_origin == Some(origin) || Some(connection.peer_dht_pk) == peer_dht_pk
+        }).cloned()
+    }
+
     /// Get long term `PublicKey` of the peer by its UDP address
     fn key_by_addr(&self, addr: SocketAddr) -> Option<PublicKey> {
         self.keys_by_addr.read().get(&(addr.ip(), addr.port())).cloned()
@@ -206,8 +468,20 @@ impl NetCrypto {
         }
     }
 
-    /// Handle `CookieResponse` and if it's correct change connection status to `HandshakeSending`.
-    pub fn handle_cookie_response(&self, connection: &mut CryptoConnection, packet: &CookieResponse) -> IoFuture<()> {
+    /// Handle `CookieRequest` packet received from TCP relay. `CookieResponse`
+    /// is sent back through the same relay.
+    pub fn handle_tcp_cookie_request(&self, packet: &CookieRequest, origin: TcpOrigin) -> IoFuture<()> {
+        match self.handle_cookie_request(packet) {
+            Ok(response) => self.send_to_tcp(origin, Packet::CookieResponse(response)),
+            Err(e) => Box::new(future::err(e))
+        }
+    }
+
+    /// Handle `CookieResponse` and if it's correct change connection status to
+    /// `HandshakeSending`. `tcp_origin` is the path through TCP relay the
+    /// packet was received from or `None` if it was received via UDP. It's
+    /// remembered only if the packet is valid.
+    pub fn handle_cookie_response(&self, connection: &mut CryptoConnection, packet: &CookieResponse, tcp_origin: Option<TcpOrigin>) -> IoFuture<()> {
         let cookie_request_id = if let ConnectionStatus::CookieRequesting { cookie_request_id, .. } = connection.status {
             cookie_request_id
         } else {
@@ -229,6 +503,10 @@ impl NetCrypto {
             )))
         }
 
+        if tcp_origin.is_some() {
+            connection.tcp_origin = tcp_origin;
+        }
+
         let sent_nonce = gen_nonce();
         let our_cookie = Cookie::new(connection.peer_real_pk, connection.peer_dht_pk);
         let our_encrypted_cookie = EncryptedCookie::new(&self.symmetric_key, &our_cookie);
@@ -254,7 +532,7 @@ impl NetCrypto {
         if let Some(connection) = connection {
             let mut connection = connection.write();
             connection.update_udp_received_time();
-            self.handle_cookie_response(&mut connection, packet)
+            self.handle_cookie_response(&mut connection, packet, None)
         } else {
             Box::new(future::err(
                 Error::new(
@@ -265,8 +543,25 @@ impl NetCrypto {
         }
     }
 
-    /// Handle `CryptoHandshake` and if it's correct change connection status to `NotConfirmed`.
-    pub fn handle_crypto_handshake(&self, connection: &mut CryptoConnection, packet: &CryptoHandshake) -> IoFuture<()> {
+    /// Handle `CookieResponse` packet received from TCP relay
+    pub fn handle_tcp_cookie_response(&self, packet: &CookieResponse, origin: TcpOrigin) -> IoFuture<()> {
+        if let Some(connection) = self.connection_by_tcp_origin(origin) {
+            self.handle_cookie_response(&mut connection.write(), packet, Some(origin))
+        } else {
+            Box::new(future::err(
+                Error::new(
+                    ErrorKind::Other,
+                    format!("No crypto connection for TCP origin {:?}", origin)
+                )
+            ))
+        }
+    }
+
+    /// Handle `CryptoHandshake` and if it's correct change connection status to
+    /// `NotConfirmed`. `tcp_origin` is the path through TCP relay the packet
+    /// was received from or `None` if it was received via UDP. It's remembered
+    /// only if the packet is valid.
+    pub fn handle_crypto_handshake(&self, connection: &mut CryptoConnection, packet: &CryptoHandshake, tcp_origin: Option<TcpOrigin>) -> IoFuture<()> {
         if let ConnectionStatus::Established { .. } = connection.status {
             return Box::new(future::err(Error::new(
                 ErrorKind::Other,
@@ -313,6 +608,10 @@ impl NetCrypto {
             )
         }
 
+        if tcp_origin.is_some() {
+            connection.tcp_origin = tcp_origin;
+        }
+
         connection.status = match connection.status {
             ConnectionStatus::CookieRequesting { .. } => {
                 let sent_nonce = gen_nonce();
@@ -353,15 +652,86 @@ impl NetCrypto {
         if let Some(connection) = connection {
             let mut connection = connection.write();
             connection.update_udp_received_time();
-            self.handle_crypto_handshake(&mut connection, packet)
+            self.handle_crypto_handshake(&mut connection, packet, None)
         } else {
-            Box::new(future::err( // TODO: create crypto connection
-                Error::new(
-                    ErrorKind::Other,
-                    format!("No crypto connection for address {}", addr)
-                )
-            ))
+            self.handle_new_crypto_handshake(packet, Some(addr), None)
+        }
+    }
+
+    /// Handle `CryptoHandshake` packet received from TCP relay
+    pub fn handle_tcp_crypto_handshake(&self, packet: &CryptoHandshake, origin: TcpOrigin) -> IoFuture<()> {
+        if let Some(connection) = self.connection_by_tcp_origin(origin) {
+            self.handle_crypto_handshake(&mut connection.write(), packet, Some(origin))
+        } else {
+            self.handle_new_crypto_handshake(packet, None, Some(origin))
+        }
+    }
+
+    /// Handle `CryptoHandshake` packet received from UDP address or TCP origin
+    /// that doesn't belong to any crypto connection. The peer is identified
+    /// by the `Cookie` we gave it. If we already have connection to it the
+    /// packet is handled by this connection, otherwise a new connection with
+    /// `NotConfirmed` status is created and DHT `PublicKey` of the peer is
+    /// sent to the sink.
+    fn handle_new_crypto_handshake(&self, packet: &CryptoHandshake, udp_addr: Option<SocketAddr>, tcp_origin: Option<TcpOrigin>) -> IoFuture<()> {
+        let cookie = match packet.cookie.get_payload(&self.symmetric_key) {
+            Ok(cookie) => cookie,
+            Err(e) => return Box::new(future::err(e)),
+        };
+
+        if cookie.is_timed_out() {
+            return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                "Cookie is timed out"
+            )))
+        }
+
+        let payload = match packet.get_payload(&self.precomputed_keys.get(cookie.dht_pk)) {
+            Ok(payload) => payload,
+            Err(e) => return Box::new(future::err(e)),
+        };
+
+        if packet.cookie.hash() != payload.cookie_hash {
+            return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                "Invalid SHA512 hash of cookie"
+            )))
+        }
+
+        // The handshake is valid so the address belongs to the peer
+        if let Some(connection) = self.connection_by_key(cookie.real_pk) {
+            let mut connection = connection.write();
+            if let Some(addr) = udp_addr {
+                if connection.peer_dht_pk == cookie.dht_pk {
+                    self.set_connection_udp_addr(&mut connection, addr);
+                    connection.update_udp_received_time();
+                }
+            }
+            return self.handle_crypto_handshake(&mut connection, packet, tcp_origin)
+        }
+
+        let mut connection = CryptoConnection::new_not_confirmed(
+            &self.dht_sk,
+            cookie.real_pk,
+            cookie.dht_pk,
+            payload.base_nonce,
+            payload.session_pk,
+            payload.cookie,
+            &self.symmetric_key
+        );
+        if let Some(addr) = udp_addr {
+            self.set_connection_udp_addr(&mut connection, addr);
+            connection.update_udp_received_time();
         }
+        connection.tcp_origin = tcp_origin;
+
+        let status_future = self.send_status_packet(&mut connection);
+        self.connections.write()
+            .entry(cookie.real_pk)
+            .or_insert_with(|| Arc::new(RwLock::new(connection)));
+        let dht_pk_future = send_to(&self.dht_pk_tx, (cookie.real_pk, cookie.dht_pk));
+
+        Box::new(status_future.join(dht_pk_future).map(|_| ()))
     }
 
     /** Handle request packet marking requested packets if rtt is elapsed since
@@ -415,9 +785,14 @@ impl NetCrypto {
     /// Send received lossless packets from the beginning of the receiving
     /// buffer to lossless sink and delete them
     fn process_ready_lossless_packets(&self, recv_array: &mut PacketsArray<RecvPacket>, pk: PublicKey) -> IoFuture<()> {
+        let stream = self.streams.read().get(&pk).cloned();
         let mut futures = Vec::new();
         while let Some(packet) = recv_array.pop_front() {
-            let future = send_to(&self.lossless_tx, (pk, packet.data));
+            let future = match stream {
+                // packets of opened stream are passed directly to it
+                Some(ref stream) if is_stream_packet(&packet.data) => stream.send_packet(packet.data),
+                _ => send_to(&self.lossless_tx, (pk, packet.data)),
+            };
             futures.push(future);
         }
         Box::new(future::join_all(futures).map(|_| ()))
@@ -450,8 +825,12 @@ impl NetCrypto {
     - lossless type: add packet to the received packets buffer and process
       packets from the beginning of this buffer
     - lossy type: just process the packet
+
+    `tcp_origin` is the path through TCP relay the packet was received from or
+    `None` if it was received via UDP. It's remembered only if the packet is
+    valid.
     */
-    fn handle_crypto_data(&self, connection: &mut CryptoConnection, packet: &CryptoData, udp: bool) -> IoFuture<()> {
+    fn handle_crypto_data(&self, connection: &mut CryptoConnection, packet: &CryptoData, tcp_origin: Option<TcpOrigin>) -> IoFuture<()> {
         let (sent_nonce, mut received_nonce, peer_session_pk, session_precomputed_key) = match connection.status {
             ConnectionStatus::NotConfirmed { sent_nonce, received_nonce, peer_session_pk, ref session_precomputed_key, .. }
             | ConnectionStatus::Established { sent_nonce, received_nonce, peer_session_pk, ref session_precomputed_key } => {
@@ -465,16 +844,28 @@ impl NetCrypto {
             }
         };
 
-        let cur_last_bytes = CryptoData::nonce_last_bytes(received_nonce);
-        let (diff, _) = packet.nonce_last_bytes.overflowing_sub(cur_last_bytes);
-        let mut packet_nonce = received_nonce;
-        increment_nonce_number(&mut packet_nonce, diff as usize);
+        let (packet_nonce, diff) = NetCrypto::packet_nonce(received_nonce, packet);
 
         let payload = match packet.get_payload(&session_precomputed_key, &packet_nonce) {
             Ok(payload) => payload,
             Err(e) => return Box::new(future::err(e))
         };
 
+        let udp = tcp_origin.is_none();
+        if tcp_origin.is_some() {
+            connection.tcp_origin = tcp_origin;
+        }
+
+        if connection.last_received_nonce.map_or(true, |nonce| nonce < packet_nonce) {
+            connection.last_received_nonce = Some(packet_nonce);
+        }
+
+        if udp {
+            connection.udp_stats.add_received(packet.size());
+        } else {
+            connection.tcp_stats.add_received(packet.size());
+        }
+
         // Find the time when the last acknowledged packet was sent
         let mut last_sent_time = NetCrypto::last_sent_time(&connection.send_array, payload.buffer_start);
 
@@ -494,11 +885,7 @@ impl NetCrypto {
 
         if packet_id == PACKET_ID_KILL {
             // Kill the connection
-            self.connections.write().remove(&connection.peer_real_pk);
-            if let Some(addr) = connection.udp_addr {
-                self.keys_by_addr.write().remove(&(addr.ip(), addr.port()));
-            }
-            return Box::new(future::ok(()));
+            return self.drop_connection(connection.peer_real_pk, connection.udp_addr);
         }
 
         // Update nonce if diff is big enough
@@ -506,7 +893,13 @@ impl NetCrypto {
             increment_nonce_number(&mut received_nonce, NONCE_DIFF_THRESHOLD as usize);
         }
 
-        // TODO: connection status notification
+        connection.data_received_time = Some(clock_now());
+
+        let status_future = if connection.is_established() {
+            Box::new(future::ok(()))
+        } else {
+            self.send_connection_status(connection.peer_real_pk, true)
+        };
 
         connection.status = ConnectionStatus::Established {
             sent_nonce,
@@ -549,16 +942,84 @@ impl NetCrypto {
             }
         }
 
-        result
+        // Sending buffer might be freed so opened stream can write more data
+        self.notify_stream(connection.peer_real_pk);
+
+        Box::new(result.join(status_future).map(|_| ()))
+    }
+
+    /// Calculate full `Nonce` of `CryptoData` packet using the last received
+    /// `Nonce` of the connection. Also returns the difference between last
+    /// bytes of these nonces.
+    fn packet_nonce(received_nonce: Nonce, packet: &CryptoData) -> (Nonce, u16) {
+        let cur_last_bytes = CryptoData::nonce_last_bytes(received_nonce);
+        let (diff, _) = packet.nonce_last_bytes.overflowing_sub(cur_last_bytes);
+        let mut packet_nonce = received_nonce;
+        increment_nonce_number(&mut packet_nonce, diff as usize);
+        (packet_nonce, diff)
+    }
+
+    /// Check if `CryptoData` packet was sent by the peer of the established
+    /// connection and it's newer than all packets received before. The
+    /// connection is not modified.
+    fn is_fresh_crypto_data(connection: &CryptoConnection, packet: &CryptoData) -> bool {
+        if let ConnectionStatus::Established { received_nonce, ref session_precomputed_key, .. } = connection.status {
+            let (packet_nonce, _) = NetCrypto::packet_nonce(received_nonce, packet);
+            connection.last_received_nonce.map_or(true, |nonce| nonce < packet_nonce)
+                && packet.get_payload(session_precomputed_key, &packet_nonce).is_ok()
+        } else {
+            false
+        }
+    }
+
+    /// Find established connection that `CryptoData` packet received from
+    /// unknown address belongs to. Connections having a hint with the same IP
+    /// are checked first, then connections which don't receive UDP packets
+    /// from their current address. The number of checked connections is
+    /// limited so that junk packets can't make us try decrypting them with
+    /// every key.
+    fn find_roaming_connection(&self, packet: &CryptoData, addr: SocketAddr) -> Option<Arc<RwLock<CryptoConnection>>> {
+        let connections = self.connections.read();
+        let udp_addr_hints = self.udp_addr_hints.read();
+        let is_hinted = |connection: &CryptoConnection| udp_addr_hints
+            .get(&connection.peer_dht_pk)
+            .map_or(false, |hints| hints.iter().any(|hint| hint.ip() == addr.ip()));
+        let hinted = connections.values().filter(|connection| {
+            let connection = connection.read();
+            connection.is_established() && is_hinted(&connection)
+        });
+        let silent = connections.values().filter(|connection| {
+            let connection = connection.read();
+            connection.is_established() && !is_hinted(&connection) && !connection.is_udp_alive()
+        });
+        hinted.chain(silent)
+            .take(MAX_ROAMING_CANDIDATES)
+            .find(|connection| NetCrypto::is_fresh_crypto_data(&connection.read(), packet))
+            .cloned()
     }
 
-    /// Handle `CryptoData` packet received from UDP socket
+    /// Handle `CryptoData` packet received from UDP socket. If the address is
+    /// unknown the packet is authenticated against candidate connections and
+    /// on success the connection is moved to the new address.
     pub fn handle_udp_crypto_data(&self, packet: &CryptoData, addr: SocketAddr) -> IoFuture<()> {
         let connection = self.key_by_addr(addr).and_then(|pk| self.connection_by_key(pk));
         if let Some(connection) = connection {
             let mut connection = connection.write();
             connection.update_udp_received_time();
-            self.handle_crypto_data(&mut connection, packet, /* udp */ true)
+            self.handle_crypto_data(&mut connection, packet, None)
+        } else if let Some(connection) = self.find_roaming_connection(packet, addr) {
+            let mut connection = connection.write();
+            // Check again since the connection could be changed while it
+            // wasn't locked
+            if !NetCrypto::is_fresh_crypto_data(&connection, packet) {
+                return Box::new(future::err(Error::new(
+                    ErrorKind::Other,
+                    format!("Stale CryptoData packet from address {}", addr)
+                )))
+            }
+            self.set_connection_udp_addr(&mut connection, addr);
+            connection.update_udp_received_time();
+            self.handle_crypto_data(&mut connection, packet, None)
         } else {
             Box::new(future::err(
                 Error::new(
@@ -569,14 +1030,102 @@ impl NetCrypto {
         }
     }
 
-    /// Send packet to crypto connection choosing TCP or UDP protocol
+    /// Handle `CryptoData` packet received from TCP relay
+    pub fn handle_tcp_crypto_data(&self, packet: &CryptoData, origin: TcpOrigin) -> IoFuture<()> {
+        if let Some(connection) = self.connection_by_tcp_origin(origin) {
+            self.handle_crypto_data(&mut connection.write(), packet, Some(origin))
+        } else {
+            Box::new(future::err(
+                Error::new(
+                    ErrorKind::Other,
+                    format!("No crypto connection for TCP origin {:?}", origin)
+                )
+            ))
+        }
+    }
+
+    /// Handle `ConnectNotification` from TCP relay. If the relay routes a
+    /// peer we have connection to this route is used to send packets to the
+    /// peer unless the connection already uses another routed connection.
+    fn handle_tcp_connect_notification(&self, relay_pk: PublicKey, connection_id: u8) {
+        let peer_dht_pk = if let Some(&peer_dht_pk) = self.tcp_routes.read().get(&(relay_pk, connection_id)) {
+            peer_dht_pk
+        } else {
+            return
+        };
+
+        for connection in self.connections.read().values() {
+            let mut connection = connection.write();
+            if connection.peer_dht_pk != peer_dht_pk {
+                continue;
+            }
+            match connection.tcp_origin {
+                Some(TcpOrigin::Data { .. }) => {},
+                _ => connection.tcp_origin = Some(TcpOrigin::Data { relay_pk, connection_id }),
+            }
+        }
+    }
+
+    /// Handle packet received from TCP relay with the given `PublicKey`. Net
+    /// crypto packets can be received either as out of band packets or as
+    /// data of routed connections. Routes to peers are remembered from
+    /// `RouteResponse` packets to find connections by routed data and used
+    /// for connections when relays send `ConnectNotification`. Other packets
+    /// are handled by TCP connections module so they are ignored here.
+    pub fn handle_tcp_packet(&self, packet: IncomingPacket, relay_pk: PublicKey) -> IoFuture<()> {
+        let (data, origin) = match packet {
+            IncomingPacket::OobReceive(packet) =>
+                (packet.data, TcpOrigin::Oob { relay_pk, sender_pk: packet.sender_pk }),
+            IncomingPacket::Data(packet) =>
+                (packet.data, TcpOrigin::Data { relay_pk, connection_id: packet.connection_id }),
+            IncomingPacket::RouteResponse(packet) => {
+                if packet.connection_id != 0 {
+                    self.tcp_routes.write().insert((relay_pk, packet.connection_id), packet.pk);
+                }
+                return Box::new(future::ok(()))
+            },
+            IncomingPacket::ConnectNotification(packet) => {
+                self.handle_tcp_connect_notification(relay_pk, packet.connection_id);
+                return Box::new(future::ok(()))
+            },
+            _ => return Box::new(future::ok(())),
+        };
+
+        match Packet::from_bytes(&data) {
+            IResult::Done(_, Packet::CookieRequest(packet)) => self.handle_tcp_cookie_request(&packet, origin),
+            IResult::Done(_, Packet::CookieResponse(packet)) => self.handle_tcp_cookie_response(&packet, origin),
+            IResult::Done(_, Packet::CryptoHandshake(packet)) => self.handle_tcp_crypto_handshake(&packet, origin),
+            IResult::Done(_, Packet::CryptoData(packet)) => self.handle_tcp_crypto_data(&packet, origin),
+            IResult::Done(_, packet) => Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                format!("Unexpected packet from TCP relay: {:?}", packet)
+            ))),
+            _ => Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                "Failed to parse packet from TCP relay"
+            ))),
+        }
+    }
+
+    /// Send packet to crypto connection choosing TCP or UDP protocol. When
+    /// UDP is not alive the packet is sent via TCP relay and sometimes also
+    /// via UDP to check if it works again. `CookieRequest` and
+    /// `CryptoHandshake` packets are always sent via UDP too since otherwise
+    /// UDP connection can't be established.
     fn send_packet(&self, packet: Packet, connection: &mut CryptoConnection) -> IoFuture<()> {
-        if let Some(addr) = connection.udp_addr {
+        let udp_future: IoFuture<()> = if let Some(addr) = connection.udp_addr {
             if connection.is_udp_alive() {
+                if let Packet::CryptoData(ref packet) = packet {
+                    connection.udp_stats.add_sent(packet.size());
+                }
                 return self.send_to_udp(addr, packet)
             }
 
-            let udp_attempt_should_be_made = connection.udp_attempt_should_be_made() && {
+            let is_status_packet = match packet {
+                Packet::CookieRequest(_) | Packet::CryptoHandshake(_) => true,
+                _ => false,
+            };
+            let udp_attempt_should_be_made = is_status_packet || connection.udp_attempt_should_be_made() && {
                 // check if the packet is not too big
                 let mut buf = [0; DHT_ATTEMPT_MAX_PACKET_LENGTH];
                 packet.to_bytes((&mut buf, 0)).is_ok()
@@ -584,15 +1133,28 @@ impl NetCrypto {
 
             if udp_attempt_should_be_made {
                 connection.update_udp_send_attempt_time();
-                self.send_to_udp(addr, packet)
+                if let Packet::CryptoData(ref packet) = packet {
+                    connection.udp_stats.add_sent(packet.size());
+                }
+                self.send_to_udp(addr, packet.clone())
             } else {
                 Box::new(future::ok(()))
             }
         } else {
             Box::new(future::ok(()))
-        }
+        };
+
+        let tcp_future = match connection.tcp_origin {
+            Some(origin) if self.tcp_tx.read().is_some() => {
+                if let Packet::CryptoData(ref packet) = packet {
+                    connection.tcp_stats.add_sent(packet.size());
+                }
+                self.send_to_tcp(origin, packet)
+            },
+            _ => Box::new(future::ok(())),
+        };
 
-        // TODO: send via TCP relay here
+        Box::new(udp_future.join(tcp_future).map(|_| ()))
     }
 
     /// Send `CookieRequest` or `CryptoHandshake` packet if needed depending on
@@ -604,49 +1166,319 @@ impl NetCrypto {
         }
     }
 
-    /// The main loop that should be run at least 20 times per second
-    pub fn main_loop(&self) -> IoFuture<()> {
-        let connections = self.connections.read();
-        let len = connections.len();
-        let mut send_futures = Vec::with_capacity(len);
-        let mut timed_out = Vec::with_capacity(len);
-        // Only one cycle over all connections to prevent many lock acquirements
-        for (&pk, connection) in connections.iter() {
-            let mut connection = connection.write();
-
-            if connection.is_timed_out() {
-                timed_out.push((pk, connection.udp_addr));
-                continue;
+    /// Encrypt data with the session key and send it as `CryptoData` packet
+    /// with the specified packet number.
+    fn send_data_packet(&self, connection: &mut CryptoConnection, data: Vec<u8>, packet_number: u32) -> IoFuture<()> {
+        let packet = match connection.status {
+            ConnectionStatus::NotConfirmed { ref mut sent_nonce, ref session_precomputed_key, .. }
+            | ConnectionStatus::Established { ref mut sent_nonce, ref session_precomputed_key, .. } => {
+                let payload = CryptoDataPayload {
+                    buffer_start: connection.recv_array.buffer_start,
+                    packet_number,
+                    data
+                };
+                let packet = CryptoData::new(session_precomputed_key, *sent_nonce, &payload);
+                increment_nonce(sent_nonce);
+                packet
+            },
+            _ => {
+                return Box::new(future::err(Error::new(
+                    ErrorKind::Other,
+                    "Can't send data packet in current connection state"
+                )))
             }
+        };
 
-            let send_future = self.send_status_packet(&mut connection);
-            send_futures.push(send_future);
+        connection.data_sent_time = Some(clock_now());
+        connection.acked_buffer_start = connection.recv_array.buffer_start;
+
+        self.send_packet(Packet::CryptoData(packet), connection)
+    }
+
+    /// Store lossless packet in the sending buffer and send it to the peer.
+    /// It will be resent if the peer requests it.
+    fn send_lossless_packet(&self, connection: &mut CryptoConnection, packet: Vec<u8>) -> IoFuture<()> {
+        match packet.first() {
+            Some(&packet_id) if packet_id > PACKET_ID_CRYPTO_RANGE_END && packet_id < PACKET_ID_LOSSY_RANGE_START => {},
+            _ => return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                "Lossless packet should have id in lossless range"
+            ))),
         }
-        // release read lock and acquire write lock if we have to delete some connections
-        drop(connections);
-        if !timed_out.is_empty() {
-            let mut connections = self.connections.write();
-            let mut keys_by_addr = self.keys_by_addr.write();
-            for (pk, addr) in timed_out {
-                connections.remove(&pk);
-                if let Some(addr) = addr {
-                    keys_by_addr.remove(&(addr.ip(), addr.port()));
-                }
-            }
+
+        if packet.len() > MAX_CRYPTO_DATA_SIZE {
+            return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                format!("Lossless packet is too big: {} bytes", packet.len())
+            )))
         }
-        Box::new(future::join_all(send_futures).map(|_| ()))
-    }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        self.store_and_send_packet(connection, packet)
+    }
 
-    use futures::Stream;
-    use tokio_executor;
-    use tokio_timer::clock::*;
+    /// Put packet to the sending buffer so it can be retransmitted when
+    /// requested and send it via crypto connection.
+    fn store_and_send_packet(&self, connection: &mut CryptoConnection, packet: Vec<u8>) -> IoFuture<()> {
+        let packet_number = connection.send_array.buffer_end;
+        if let Err(e) = connection.send_array.push_back(SentPacket::new(packet.clone())) {
+            return Box::new(future::err(e))
+        }
 
-    use toxcore::time::ConstNow;
+        self.send_data_packet(connection, packet, packet_number)
+    }
+
+    /// Resend kill packets of killed connections if they were sent more than
+    /// rtt ago and forget connections which resent them enough times.
+    fn resend_kill_packets(&self) -> IoFuture<()> {
+        let mut killed_connections = self.killed_connections.write();
+        let mut send_futures = Vec::with_capacity(killed_connections.len());
+        for killed in killed_connections.iter_mut() {
+            let rtt = killed.connection.rtt;
+            let resend = match killed.connection.send_array.get_mut(killed.packet_number) {
+                Some(packet) => {
+                    packet.requested = clock_elapsed(packet.sent_time) > rtt;
+                    packet.requested
+                },
+                None => false,
+            };
+            if resend {
+                killed.resends += 1;
+                send_futures.push(self.send_requested_packets(&mut killed.connection));
+            }
+        }
+        killed_connections.retain(|killed| killed.resends < MAX_KILL_PACKET_RESENDS);
+        Box::new(future::join_all(send_futures).map(|_| ()))
+    }
+
+    /// Send lossless packet to a friend via established crypto connection.
+    /// First byte of the packet is its id and it should be in lossless range.
+    pub fn send_lossless(&self, peer_real_pk: PublicKey, packet: Vec<u8>) -> IoFuture<()> {
+        if let Some(connection) = self.connection_by_key(peer_real_pk) {
+            let mut connection = connection.write();
+            if !connection.is_established() {
+                return Box::new(future::err(Error::new(
+                    ErrorKind::Other,
+                    "Crypto connection is not established"
+                )))
+            }
+            self.send_lossless_packet(&mut connection, packet)
+        } else {
+            Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                format!("No crypto connection for key {:?}", peer_real_pk)
+            )))
+        }
+    }
+
+    /// Send lossless packet to a friend via established crypto connection and
+    /// get its id consisting of the connection id and the number it was
+    /// stored with in the sending buffer. This id can be used to check whether
+    /// the peer received the packet with `is_lossless_delivered`.
+    pub fn send_lossless_numbered(&self, peer_real_pk: PublicKey, packet: Vec<u8>) -> IoFuture<LosslessPacketId> {
+        if let Some(connection) = self.connection_by_key(peer_real_pk) {
+            let mut connection = connection.write();
+            if !connection.is_established() {
+                return Box::new(future::err(Error::new(
+                    ErrorKind::Other,
+                    "Crypto connection is not established"
+                )))
+            }
+            let packet_id = LosslessPacketId {
+                connection_id: connection.id,
+                packet_number: connection.send_array.buffer_end,
+            };
+            Box::new(self.send_lossless_packet(&mut connection, packet).map(move |()| packet_id))
+        } else {
+            Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                format!("No crypto connection for key {:?}", peer_real_pk)
+            )))
+        }
+    }
+
+    /// Check if the peer confirmed that it received lossless packet with the
+    /// id returned by `send_lossless_numbered`. Packets with numbers lower
+    /// than the start of the sending buffer are delivered while packets in the
+    /// buffer and numbers at or after its end are not. Returns `false` if
+    /// there is no crypto connection to the peer or the packet was sent via
+    /// another connection.
+    pub fn is_lossless_delivered(&self, peer_real_pk: PublicKey, packet_id: LosslessPacketId) -> bool {
+        self.connection_by_key(peer_real_pk).map_or(false, |connection| {
+            let connection = connection.read();
+            if connection.id != packet_id.connection_id {
+                return false
+            }
+            // Numbers wrap around so the packet is lower than the start of the
+            // buffer if it's in the half of numbers space preceding it
+            let distance = connection.send_array.buffer_start.wrapping_sub(packet_id.packet_number);
+            distance > 0 && distance <= u32::MAX / 2
+        })
+    }
+
+    /// Send lossy packet to a friend via established crypto connection. First
+    /// byte of the packet is its id and it should be in lossy range. Lossy
+    /// packets are not stored in the sending buffer so they are never resent.
+    pub fn send_lossy(&self, peer_real_pk: PublicKey, packet: Vec<u8>) -> IoFuture<()> {
+        match packet.first() {
+            Some(&packet_id) if packet_id >= PACKET_ID_LOSSY_RANGE_START && packet_id <= PACKET_ID_LOSSY_RANGE_END => {},
+            _ => return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                "Lossy packet should have id in lossy range"
+            ))),
+        }
+
+        if packet.len() > MAX_CRYPTO_DATA_SIZE {
+            return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                format!("Lossy packet is too big: {} bytes", packet.len())
+            )))
+        }
+
+        if let Some(connection) = self.connection_by_key(peer_real_pk) {
+            let mut connection = connection.write();
+            if !connection.is_established() {
+                return Box::new(future::err(Error::new(
+                    ErrorKind::Other,
+                    "Crypto connection is not established"
+                )))
+            }
+            let packet_number = connection.send_array.buffer_end;
+            self.send_data_packet(&mut connection, packet, packet_number)
+        } else {
+            Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                format!("No crypto connection for key {:?}", peer_real_pk)
+            )))
+        }
+    }
+
+    /** Generate request packet data that contains indices of lossless packets
+    we haven't received yet.
+
+    The encoding is described in `handle_request_packet` function.
+
+    */
+    fn generate_request_packet(recv_array: &PacketsArray<RecvPacket>) -> Vec<u8> {
+        let mut data = Vec::with_capacity(MAX_CRYPTO_DATA_SIZE);
+        data.push(PACKET_ID_REQUEST);
+
+        // n is a packet number offset from the previous requested packet
+        let mut n = 1;
+
+        for i in recv_array.buffer_start .. recv_array.buffer_end {
+            if data.len() >= MAX_CRYPTO_DATA_SIZE {
+                break
+            }
+
+            if recv_array.get(i).is_none() {
+                data.push(n);
+                n = 0;
+            }
+
+            if n == 255 {
+                data.push(0);
+                n = 1;
+            } else {
+                n += 1;
+            }
+        }
+
+        data
+    }
+
+    /// Send request packet with indices of not received lossless packets. The
+    /// peer will resend them and it will also know that all other packets were
+    /// delivered. Request packet without indices is used as keepalive.
+    fn send_request_packet(&self, connection: &mut CryptoConnection) -> IoFuture<()> {
+        let data = NetCrypto::generate_request_packet(&connection.recv_array);
+        let packet_number = connection.send_array.buffer_end;
+        connection.request_packet_sent_time = Some(clock_now());
+        self.send_data_packet(connection, data, packet_number)
+    }
+
+    /// Resend lossless packets that were requested by the peer.
+    fn send_requested_packets(&self, connection: &mut CryptoConnection) -> IoFuture<()> {
+        let now = clock_now();
+        let mut packets = Vec::new();
+        for i in connection.send_array.buffer_start .. connection.send_array.buffer_end {
+            if let Some(packet) = connection.send_array.get_mut(i) {
+                if packet.requested {
+                    packet.requested = false;
+                    packet.sent_time = now;
+                    packets.push((i, packet.data.clone()));
+                }
+            }
+        }
+        connection.retransmissions += packets.len() as u64;
+        let futures = packets.into_iter()
+            .map(|(i, data)| self.send_data_packet(connection, data, i))
+            .collect::<Vec<_>>();
+        Box::new(future::join_all(futures).map(|_| ()))
+    }
+
+    /// Get snapshot of state and statistics of crypto connection to a friend
+    pub fn connection_info(&self, peer_real_pk: PublicKey) -> Option<ConnectionInfo> {
+        self.connection_by_key(peer_real_pk).map(|connection| connection.read().info())
+    }
+
+    /// Get snapshots of state and statistics of all crypto connections
+    pub fn connections_info(&self) -> impl Iterator<Item = ConnectionInfo> {
+        self.connections.read()
+            .values()
+            .map(|connection| connection.read().info())
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    /// The main loop that should be run at least 20 times per second
+    pub fn main_loop(&self) -> IoFuture<()> {
+        let idle_timeout = *self.idle_timeout.read();
+        let connections = self.connections.read();
+        let len = connections.len();
+        let mut send_futures = Vec::with_capacity(len);
+        let mut timed_out = Vec::with_capacity(len);
+        // Only one cycle over all connections to prevent many lock acquirements
+        for (&pk, connection) in connections.iter() {
+            let mut connection = connection.write();
+
+            if connection.is_timed_out(idle_timeout) {
+                timed_out.push((pk, connection.udp_addr));
+                continue;
+            }
+
+            let send_future = self.send_status_packet(&mut connection);
+            send_futures.push(send_future);
+
+            if connection.request_packet_should_be_sent() || connection.keepalive_should_be_sent() {
+                let send_future = self.send_request_packet(&mut connection);
+                send_futures.push(send_future);
+            }
+
+            if connection.is_established() {
+                let send_future = self.send_requested_packets(&mut connection);
+                send_futures.push(send_future);
+            }
+        }
+        // release read lock before deleting timed out connections
+        drop(connections);
+        for (pk, addr) in timed_out {
+            send_futures.push(self.drop_connection(pk, addr));
+        }
+        send_futures.push(self.resend_kill_packets());
+        Box::new(future::join_all(send_futures).map(|_| ()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::Stream;
+    use tokio_executor;
+    use tokio_timer::clock::*;
+
+    use toxcore::time::ConstNow;
+    use toxcore::tcp::packet::{ConnectNotification, OobReceive, RouteResponse};
 
     #[test]
     fn net_crypto_clone() {
@@ -858,7 +1690,7 @@ mod tests {
         };
         let cookie_response = CookieResponse::new(&connection.dht_precomputed_key, &cookie_response_payload);
 
-        assert!(net_crypto.handle_cookie_response(&mut connection, &cookie_response).wait().is_ok());
+        assert!(net_crypto.handle_cookie_response(&mut connection, &cookie_response, None).wait().is_ok());
 
         let packet = unpack!(connection.status, ConnectionStatus::HandshakeSending, packet);
         let packet = unpack!(packet.dht_packet(), Packet::CryptoHandshake);
@@ -913,7 +1745,7 @@ mod tests {
         };
         let cookie_response = CookieResponse::new(&connection.dht_precomputed_key, &cookie_response_payload);
 
-        assert!(net_crypto.handle_cookie_response(&mut connection, &cookie_response).wait().is_err());
+        assert!(net_crypto.handle_cookie_response(&mut connection, &cookie_response, None).wait().is_err());
     }
 
     #[test]
@@ -952,7 +1784,7 @@ mod tests {
         };
         let cookie_response = CookieResponse::new(&connection.dht_precomputed_key, &cookie_response_payload);
 
-        assert!(net_crypto.handle_cookie_response(&mut connection, &cookie_response).wait().is_err());
+        assert!(net_crypto.handle_cookie_response(&mut connection, &cookie_response, None).wait().is_err());
     }
 
 
@@ -1090,7 +1922,7 @@ mod tests {
         };
         let crypto_handshake = CryptoHandshake::new(&connection.dht_precomputed_key, &crypto_handshake_payload, our_encrypted_cookie);
 
-        assert!(net_crypto.handle_crypto_handshake(&mut connection, &crypto_handshake).wait().is_ok());
+        assert!(net_crypto.handle_crypto_handshake(&mut connection, &crypto_handshake, None).wait().is_ok());
 
         let received_nonce = unpack!(connection.status, ConnectionStatus::NotConfirmed, received_nonce);
         let peer_session_pk = unpack!(connection.status, ConnectionStatus::NotConfirmed, peer_session_pk);
@@ -1158,7 +1990,7 @@ mod tests {
         };
         let crypto_handshake = CryptoHandshake::new(&connection.dht_precomputed_key, &crypto_handshake_payload, our_encrypted_cookie);
 
-        assert!(net_crypto.handle_crypto_handshake(&mut connection, &crypto_handshake).wait().is_ok());
+        assert!(net_crypto.handle_crypto_handshake(&mut connection, &crypto_handshake, None).wait().is_ok());
 
         // Nonce and session pk should be taken from the packet
         let received_nonce = unpack!(connection.status, ConnectionStatus::NotConfirmed, received_nonce);
@@ -1226,7 +2058,7 @@ mod tests {
         };
         let crypto_handshake = CryptoHandshake::new(&connection.dht_precomputed_key, &crypto_handshake_payload, our_encrypted_cookie);
 
-        assert!(net_crypto.handle_crypto_handshake(&mut connection, &crypto_handshake).wait().is_err());
+        assert!(net_crypto.handle_crypto_handshake(&mut connection, &crypto_handshake, None).wait().is_err());
     }
 
     #[test]
@@ -1269,7 +2101,7 @@ mod tests {
         };
         let crypto_handshake = CryptoHandshake::new(&connection.dht_precomputed_key, &crypto_handshake_payload, our_encrypted_cookie);
 
-        assert!(net_crypto.handle_crypto_handshake(&mut connection, &crypto_handshake).wait().is_err());
+        assert!(net_crypto.handle_crypto_handshake(&mut connection, &crypto_handshake, None).wait().is_err());
     }
 
     #[test]
@@ -1313,7 +2145,7 @@ mod tests {
         };
         let crypto_handshake = CryptoHandshake::new(&connection.dht_precomputed_key, &crypto_handshake_payload, our_encrypted_cookie);
 
-        assert!(net_crypto.handle_crypto_handshake(&mut connection, &crypto_handshake).wait().is_err());
+        assert!(net_crypto.handle_crypto_handshake(&mut connection, &crypto_handshake, None).wait().is_err());
     }
 
     #[test]
@@ -1356,7 +2188,7 @@ mod tests {
         };
         let crypto_handshake = CryptoHandshake::new(&connection.dht_precomputed_key, &crypto_handshake_payload, our_encrypted_cookie);
 
-        assert!(net_crypto.handle_crypto_handshake(&mut connection, &crypto_handshake).wait().is_err());
+        assert!(net_crypto.handle_crypto_handshake(&mut connection, &crypto_handshake, None).wait().is_err());
     }
 
     #[test]
@@ -1401,7 +2233,7 @@ mod tests {
         };
         let crypto_handshake = CryptoHandshake::new(&connection.dht_precomputed_key, &crypto_handshake_payload, our_encrypted_cookie);
 
-        assert!(net_crypto.handle_crypto_handshake(&mut connection, &crypto_handshake).wait().is_err());
+        assert!(net_crypto.handle_crypto_handshake(&mut connection, &crypto_handshake, None).wait().is_err());
 
         let (keys, _dht_pk_rx) = dht_pk_rx.into_future().wait().unwrap();
         let (received_real_pk, received_dht_pk) = keys.unwrap();
@@ -1477,6 +2309,124 @@ mod tests {
         assert_eq!(payload.cookie_hash, cookie.hash());
     }
 
+    #[test]
+    fn handle_udp_crypto_handshake_new_connection() {
+        let (udp_tx, udp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&dht_pk, &peer_dht_sk);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+
+        let base_nonce = gen_nonce();
+        let session_pk = gen_keypair().0;
+        let our_cookie = Cookie::new(peer_real_pk, peer_dht_pk);
+        let our_encrypted_cookie = EncryptedCookie::new(&net_crypto.symmetric_key, &our_cookie);
+        let cookie = EncryptedCookie {
+            nonce: secretbox::gen_nonce(),
+            payload: vec![43; 88]
+        };
+        let crypto_handshake_payload = CryptoHandshakePayload {
+            base_nonce,
+            session_pk,
+            cookie_hash: our_encrypted_cookie.hash(),
+            cookie: cookie.clone()
+        };
+        let crypto_handshake = CryptoHandshake::new(&dht_precomputed_key, &crypto_handshake_payload, our_encrypted_cookie);
+
+        net_crypto.handle_udp_crypto_handshake(&crypto_handshake, addr).wait().unwrap();
+
+        let connection = net_crypto.connection_by_key(peer_real_pk).unwrap().read().clone();
+
+        assert_eq!(connection.peer_dht_pk, peer_dht_pk);
+        assert_eq!(connection.udp_addr, Some(addr));
+        assert_eq!(net_crypto.key_by_addr(addr), Some(peer_real_pk));
+
+        let received_nonce = unpack!(connection.status, ConnectionStatus::NotConfirmed, received_nonce);
+        let peer_session_pk = unpack!(connection.status, ConnectionStatus::NotConfirmed, peer_session_pk);
+
+        assert_eq!(received_nonce, base_nonce);
+        assert_eq!(peer_session_pk, session_pk);
+
+        // our handshake is sent back
+        let (received, _udp_rx) = udp_rx.into_future().wait().unwrap();
+        let (packet, addr_to_send) = received.unwrap();
+
+        assert_eq!(addr_to_send, addr);
+
+        let packet = unpack!(packet, Packet::CryptoHandshake);
+        assert_eq!(packet.cookie, cookie);
+
+        let payload = packet.get_payload(&dht_precomputed_key).unwrap();
+        assert_eq!(payload.cookie_hash, cookie.hash());
+
+        // DHT key of the new peer is reported
+        let (received, _dht_pk_rx) = dht_pk_rx.into_future().wait().unwrap();
+        assert_eq!(received.unwrap(), (peer_real_pk, peer_dht_pk));
+    }
+
+    #[test]
+    fn handle_udp_crypto_handshake_new_connection_invalid_hash() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&dht_pk, &peer_dht_sk);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+
+        let our_cookie = Cookie::new(peer_real_pk, peer_dht_pk);
+        let our_encrypted_cookie = EncryptedCookie::new(&net_crypto.symmetric_key, &our_cookie);
+        let cookie = EncryptedCookie {
+            nonce: secretbox::gen_nonce(),
+            payload: vec![43; 88]
+        };
+        let crypto_handshake_payload = CryptoHandshakePayload {
+            base_nonce: gen_nonce(),
+            session_pk: gen_keypair().0,
+            cookie_hash: cookie.hash(),
+            cookie
+        };
+        let crypto_handshake = CryptoHandshake::new(&dht_precomputed_key, &crypto_handshake_payload, our_encrypted_cookie);
+
+        assert!(net_crypto.handle_udp_crypto_handshake(&crypto_handshake, addr).wait().is_err());
+        assert!(net_crypto.connection_by_key(peer_real_pk).is_none());
+        assert_eq!(net_crypto.key_by_addr(addr), None);
+    }
+
     #[test]
     fn handle_crypto_data_lossy() {
         let (udp_tx, _udp_rx) = mpsc::unbounded();
@@ -1519,7 +2469,7 @@ mod tests {
         };
         let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload);
 
-        assert!(net_crypto.handle_crypto_data(&mut connection, &crypto_data, /* udp */ true).wait().is_ok());
+        assert!(net_crypto.handle_crypto_data(&mut connection, &crypto_data, None).wait().is_ok());
 
         // The diff between nonces is not bigger than the threshold so received
         // nonce shouldn't be changed
@@ -1582,7 +2532,7 @@ mod tests {
         };
         let crypto_data = CryptoData::new(&session_precomputed_key, packet_nonce, &crypto_data_payload);
 
-        assert!(net_crypto.handle_crypto_data(&mut connection, &crypto_data, /* udp */ true).wait().is_ok());
+        assert!(net_crypto.handle_crypto_data(&mut connection, &crypto_data, None).wait().is_ok());
 
         // The diff between nonces is bigger than the threshold so received
         // nonce should be changed increased
@@ -1658,7 +2608,7 @@ mod tests {
         let clock = Clock::new_with_now(ConstNow(now + Duration::from_millis(250)));
 
         with_default(&clock, &mut enter, |_| {
-            assert!(net_crypto.handle_crypto_data(&mut connection, &crypto_data, /* udp */ true).wait().is_ok());
+            assert!(net_crypto.handle_crypto_data(&mut connection, &crypto_data, None).wait().is_ok());
         });
 
         // The diff between nonces is not bigger than the threshold so received
@@ -1724,7 +2674,7 @@ mod tests {
         };
         let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload);
 
-        assert!(net_crypto.handle_crypto_data(&mut connection, &crypto_data, /* udp */ true).wait().is_err());
+        assert!(net_crypto.handle_crypto_data(&mut connection, &crypto_data, None).wait().is_err());
 
         assert_eq!(unpack!(connection.status, ConnectionStatus::Established, received_nonce), received_nonce);
 
@@ -1791,9 +2741,9 @@ mod tests {
         let crypto_data_3 = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload_3);
 
         // Send packets in random order
-        assert!(net_crypto.handle_crypto_data(&mut connection, &crypto_data_2, /* udp */ true).wait().is_ok());
-        assert!(net_crypto.handle_crypto_data(&mut connection, &crypto_data_3, /* udp */ true).wait().is_ok());
-        assert!(net_crypto.handle_crypto_data(&mut connection, &crypto_data_1, /* udp */ true).wait().is_ok());
+        assert!(net_crypto.handle_crypto_data(&mut connection, &crypto_data_2, None).wait().is_ok());
+        assert!(net_crypto.handle_crypto_data(&mut connection, &crypto_data_3, None).wait().is_ok());
+        assert!(net_crypto.handle_crypto_data(&mut connection, &crypto_data_1, None).wait().is_ok());
 
         // The diff between nonces is not bigger than the threshold so received
         // nonce shouldn't be changed
@@ -1864,7 +2814,7 @@ mod tests {
         };
         let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload);
 
-        assert!(net_crypto.handle_crypto_data(&mut connection, &crypto_data, /* udp */ true).wait().is_err());
+        assert!(net_crypto.handle_crypto_data(&mut connection, &crypto_data, None).wait().is_err());
 
         assert_eq!(unpack!(connection.status, ConnectionStatus::Established, received_nonce), received_nonce);
 
@@ -1923,7 +2873,7 @@ mod tests {
         };
         let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload);
 
-        assert!(net_crypto.handle_crypto_data(&mut connection.write(), &crypto_data, /* udp */ true).wait().is_ok());
+        assert!(net_crypto.handle_crypto_data(&mut connection.write(), &crypto_data, None).wait().is_ok());
 
         assert!(net_crypto.connections.read().is_empty());
         assert!(net_crypto.keys_by_addr.read().is_empty());
@@ -1998,7 +2948,7 @@ mod tests {
         let clock = Clock::new_with_now(ConstNow(now + Duration::from_secs(1)));
 
         with_default(&clock, &mut enter, |_| {
-            assert!(net_crypto.handle_crypto_data(&mut connection, &crypto_data, /* udp */ true).wait().is_ok());
+            assert!(net_crypto.handle_crypto_data(&mut connection, &crypto_data, None).wait().is_ok());
         });
 
         assert!(connection.send_array.get(0).unwrap().requested);
@@ -2062,7 +3012,7 @@ mod tests {
         };
         let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload);
 
-        assert!(net_crypto.handle_crypto_data(&mut connection, &crypto_data, /* udp */ true).wait().is_ok());
+        assert!(net_crypto.handle_crypto_data(&mut connection, &crypto_data, None).wait().is_ok());
 
         assert!(!connection.send_array.get(0).unwrap().requested);
         assert!(!connection.send_array.get(1).unwrap().requested);
@@ -2113,7 +3063,7 @@ mod tests {
         };
         let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload);
 
-        assert!(net_crypto.handle_crypto_data(&mut connection, &crypto_data, /* udp */ true).wait().is_err());
+        assert!(net_crypto.handle_crypto_data(&mut connection, &crypto_data, None).wait().is_err());
 
         assert_eq!(unpack!(connection.status, ConnectionStatus::Established, received_nonce), received_nonce);
 
@@ -2165,7 +3115,7 @@ mod tests {
         };
         let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload);
 
-        assert!(net_crypto.handle_crypto_data(&mut connection, &crypto_data, /* udp */ true).wait().is_err());
+        assert!(net_crypto.handle_crypto_data(&mut connection, &crypto_data, None).wait().is_err());
 
         assert_eq!(unpack!(connection.status, ConnectionStatus::Established, received_nonce), received_nonce);
 
@@ -2210,7 +3160,7 @@ mod tests {
         };
         let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload);
 
-        assert!(net_crypto.handle_crypto_data(&mut connection, &crypto_data, /* udp */ true).wait().is_err());
+        assert!(net_crypto.handle_crypto_data(&mut connection, &crypto_data, None).wait().is_err());
     }
 
     #[test]
@@ -2282,11 +3232,11 @@ mod tests {
     }
 
     #[test]
-    fn send_status_packet() {
-        let (udp_tx, udp_rx) = mpsc::unbounded();
+    fn handle_udp_crypto_data_roaming() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
         let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
-        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (lossy_tx, lossy_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
         let (real_pk, _real_sk) = gen_keypair();
         let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
@@ -2305,31 +3255,1522 @@ mod tests {
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
         let mut connection = CryptoConnection::new(&dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
 
-        let addr = "127.0.0.1:12345".parse().unwrap();
-        connection.udp_addr = Some(addr);
-        connection.update_udp_received_time();
+        let received_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce: gen_nonce(),
+            received_nonce,
+            peer_session_pk,
+            session_precomputed_key: session_precomputed_key.clone(),
+        };
 
-        // send status packet first time - it should be sent
-        assert!(net_crypto.send_status_packet(&mut connection).wait().is_ok());
+        let old_addr = "127.0.0.1:12345".parse().unwrap();
+        connection.udp_addr = Some(old_addr);
 
-        let packet = unpack!(connection.status.clone(), ConnectionStatus::CookieRequesting, packet);
-        assert_eq!(packet.num_sent, 1);
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+        net_crypto.keys_by_addr.write().insert((old_addr.ip(), old_addr.port()), peer_real_pk);
 
-        let (received, _udp_rx) = udp_rx.into_future().wait().unwrap();
-        let (received, addr_to_send) = received.unwrap();
+        let crypto_data_payload = CryptoDataPayload {
+            buffer_start: 0,
+            packet_number: 0,
+            data: vec![0, 0, PACKET_ID_LOSSY_RANGE_START, 1, 2, 3]
+        };
+        let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload);
 
-        assert_eq!(received, packet.dht_packet());
-        assert_eq!(addr_to_send, addr);
+        let new_addr = "127.0.0.2:54321".parse().unwrap();
+        assert!(net_crypto.handle_udp_crypto_data(&crypto_data, new_addr).wait().is_ok());
 
-        // send status packet again - it shouldn't be sent
-        assert!(net_crypto.send_status_packet(&mut connection).wait().is_ok());
+        let connection = net_crypto.connection_by_key(peer_real_pk).unwrap().read().clone();
+        assert_eq!(connection.udp_addr, Some(new_addr));
+        assert!(connection.is_udp_alive());
+        assert_eq!(net_crypto.key_by_addr(new_addr), Some(peer_real_pk));
+        assert_eq!(net_crypto.key_by_addr(old_addr), None);
 
-        let packet = unpack!(connection.status, ConnectionStatus::CookieRequesting, packet);
-        assert_eq!(packet.num_sent, 1);
-    }
+        let (received, _lossy_rx) = lossy_rx.into_future().wait().unwrap();
+        let (received_peer_real_pk, received_data) = received.unwrap();
+        assert_eq!(received_peer_real_pk, peer_real_pk);
+        assert_eq!(received_data, vec![PACKET_ID_LOSSY_RANGE_START, 1, 2, 3]);
+    }
+
+    #[test]
+    fn handle_udp_crypto_data_roaming_replayed() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(&dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let received_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce: gen_nonce(),
+            received_nonce,
+            peer_session_pk,
+            session_precomputed_key: session_precomputed_key.clone(),
+        };
+
+        let old_addr = "127.0.0.1:12345".parse().unwrap();
+        connection.udp_addr = Some(old_addr);
+
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+        net_crypto.keys_by_addr.write().insert((old_addr.ip(), old_addr.port()), peer_real_pk);
+
+        let crypto_data_payload = CryptoDataPayload {
+            buffer_start: 0,
+            packet_number: 0,
+            data: vec![0, 0, PACKET_ID_LOSSY_RANGE_START, 1, 2, 3]
+        };
+        let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload);
+
+        assert!(net_crypto.handle_udp_crypto_data(&crypto_data, old_addr).wait().is_ok());
+
+        // the same packet can't move the connection to another address
+        let new_addr = "127.0.0.2:54321".parse().unwrap();
+        assert!(net_crypto.handle_udp_crypto_data(&crypto_data, new_addr).wait().is_err());
+
+        let connection = net_crypto.connection_by_key(peer_real_pk).unwrap().read().clone();
+        assert_eq!(connection.udp_addr, Some(old_addr));
+        assert_eq!(net_crypto.key_by_addr(new_addr), None);
+    }
+
+    #[test]
+    fn handle_udp_crypto_data_roaming_hinted() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(&dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let received_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce: gen_nonce(),
+            received_nonce,
+            peer_session_pk,
+            session_precomputed_key: session_precomputed_key.clone(),
+        };
+
+        // UDP is alive so the connection is checked only because of the hint
+        let old_addr = "127.0.0.1:12345".parse().unwrap();
+        connection.udp_addr = Some(old_addr);
+        connection.update_udp_received_time();
+
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+        net_crypto.keys_by_addr.write().insert((old_addr.ip(), old_addr.port()), peer_real_pk);
+
+        let crypto_data_payload = CryptoDataPayload {
+            buffer_start: 0,
+            packet_number: 0,
+            data: vec![0, 0, PACKET_ID_LOSSY_RANGE_START, 1, 2, 3]
+        };
+        let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload);
+
+        let new_addr = "127.0.0.2:54321".parse().unwrap();
+        assert!(net_crypto.handle_udp_crypto_data(&crypto_data, new_addr).wait().is_err());
+
+        net_crypto.set_udp_addr_hints(peer_dht_pk, vec!["127.0.0.2:33445".parse().unwrap()]);
+        assert!(net_crypto.handle_udp_crypto_data(&crypto_data, new_addr).wait().is_ok());
+
+        let connection = net_crypto.connection_by_key(peer_real_pk).unwrap().read().clone();
+        assert_eq!(connection.udp_addr, Some(new_addr));
+        assert_eq!(net_crypto.key_by_addr(new_addr), Some(peer_real_pk));
+        assert_eq!(net_crypto.key_by_addr(old_addr), None);
+    }
+
+    #[test]
+    fn handle_tcp_cookie_request() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (tcp_tx, tcp_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            precomputed_keys,
+        });
+        net_crypto.set_tcp_sink(tcp_tx);
+
+        let cookie_request_id = 12345;
+
+        let cookie_request_payload = CookieRequestPayload {
+            pk: peer_real_pk,
+            id: cookie_request_id,
+        };
+        let cookie_request = CookieRequest::new(&precomputed_key, &peer_dht_pk, &cookie_request_payload);
+
+        let relay_pk = gen_keypair().0;
+        let origin = TcpOrigin::Oob { relay_pk, sender_pk: peer_dht_pk };
+
+        assert!(net_crypto.handle_tcp_cookie_request(&cookie_request, origin).wait().is_ok());
+
+        let (received, _tcp_rx) = tcp_rx.into_future().wait().unwrap();
+        let (packet, relay_pk_to_send) = received.unwrap();
+        let packet = unpack!(packet, OutgoingPacket::OobSend);
+
+        assert_eq!(relay_pk_to_send, relay_pk);
+        assert_eq!(packet.destination_pk, peer_dht_pk);
+
+        let cookie_response = unpack!(Packet::from_bytes(&packet.data).unwrap().1, Packet::CookieResponse);
+        let cookie_response_payload = cookie_response.get_payload(&precomputed_key).unwrap();
+
+        assert_eq!(cookie_response_payload.id, cookie_request_id);
+    }
+
+    #[test]
+    fn handle_tcp_cookie_response() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (tcp_tx, tcp_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            precomputed_keys,
+        });
+        net_crypto.set_tcp_sink(tcp_tx);
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let connection = CryptoConnection::new(&dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let dht_precomputed_key = connection.dht_precomputed_key.clone();
+        let cookie_request_id = unpack!(connection.status, ConnectionStatus::CookieRequesting, cookie_request_id);
+
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+
+        let relay_pk = gen_keypair().0;
+        let origin = TcpOrigin::Data { relay_pk, connection_id: 42 };
+        net_crypto.set_friend_tcp_origin(peer_real_pk, origin);
+
+        let cookie = EncryptedCookie {
+            nonce: secretbox::gen_nonce(),
+            payload: vec![43; 88]
+        };
+        let cookie_response_payload = CookieResponsePayload {
+            cookie: cookie.clone(),
+            id: cookie_request_id
+        };
+        let cookie_response = CookieResponse::new(&dht_precomputed_key, &cookie_response_payload);
+
+        assert!(net_crypto.handle_tcp_cookie_response(&cookie_response, origin).wait().is_ok());
+
+        let connection = net_crypto.connection_by_key(peer_real_pk).unwrap().read().clone();
+        let packet = unpack!(connection.status, ConnectionStatus::HandshakeSending, packet);
+        let packet = unpack!(packet.dht_packet(), Packet::CryptoHandshake);
+        assert_eq!(packet.cookie, cookie);
+
+        // handshake is sent back through the same relay
+        let (received, _tcp_rx) = tcp_rx.into_future().wait().unwrap();
+        let (received_packet, relay_pk_to_send) = received.unwrap();
+        let received_packet = unpack!(received_packet, OutgoingPacket::Data);
+
+        assert_eq!(relay_pk_to_send, relay_pk);
+        assert_eq!(received_packet.connection_id, 42);
+        assert_eq!(Packet::from_bytes(&received_packet.data).unwrap().1, Packet::CryptoHandshake(packet));
+    }
+
+    #[test]
+    fn handle_tcp_cookie_response_no_connection() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            precomputed_keys,
+        });
+
+        let cookie_response_payload = CookieResponsePayload {
+            cookie: EncryptedCookie {
+                nonce: secretbox::gen_nonce(),
+                payload: vec![43; 88]
+            },
+            id: 12345
+        };
+        let cookie_response = CookieResponse::new(&precompute(&gen_keypair().0, &dht_sk), &cookie_response_payload);
+
+        let origin = TcpOrigin::Data { relay_pk: gen_keypair().0, connection_id: 42 };
+
+        assert!(net_crypto.handle_tcp_cookie_response(&cookie_response, origin).wait().is_err());
+    }
+
+    #[test]
+    fn handle_tcp_packet_crypto_data() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(&dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let received_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce: gen_nonce(),
+            received_nonce,
+            peer_session_pk,
+            session_precomputed_key: session_precomputed_key.clone(),
+        };
+
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+
+        let crypto_data_payload = CryptoDataPayload {
+            buffer_start: 0,
+            packet_number: 0,
+            data: vec![0, 0, PACKET_ID_LOSSY_RANGE_START, 1, 2, 3]
+        };
+        let crypto_data = Packet::CryptoData(CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload));
+        let mut buf = [0; MAX_TCP_PACKET_SIZE];
+        let (_, size) = crypto_data.to_bytes((&mut buf, 0)).unwrap();
+
+        let relay_pk = gen_keypair().0;
+        let packet = IncomingPacket::OobReceive(OobReceive {
+            sender_pk: peer_dht_pk,
+            data: buf[..size].to_vec()
+        });
+
+        assert!(net_crypto.handle_tcp_packet(packet, relay_pk).wait().is_ok());
+
+        let connection = net_crypto.connection_by_key(peer_real_pk).unwrap().read().clone();
+        assert_eq!(connection.tcp_origin, Some(TcpOrigin::Oob { relay_pk, sender_pk: peer_dht_pk }));
+        assert_eq!(connection.tcp_stats.packets_received, 1);
+
+        let (received, _lossy_rx) = lossy_rx.into_future().wait().unwrap();
+        let (received_peer_real_pk, received_data) = received.unwrap();
+        assert_eq!(received_peer_real_pk, peer_real_pk);
+        assert_eq!(received_data, vec![PACKET_ID_LOSSY_RANGE_START, 1, 2, 3]);
+    }
+
+    #[test]
+    fn handle_tcp_packet_routed_crypto_data() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(&dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let received_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce: gen_nonce(),
+            received_nonce,
+            peer_session_pk,
+            session_precomputed_key: session_precomputed_key.clone(),
+        };
+
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+
+        let crypto_data_payload = CryptoDataPayload {
+            buffer_start: 0,
+            packet_number: 0,
+            data: vec![0, 0, PACKET_ID_LOSSY_RANGE_START, 1, 2, 3]
+        };
+        let crypto_data = Packet::CryptoData(CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload));
+        let mut buf = [0; MAX_TCP_PACKET_SIZE];
+        let (_, size) = crypto_data.to_bytes((&mut buf, 0)).unwrap();
+
+        let relay_pk = gen_keypair().0;
+        let packet = IncomingPacket::Data(Data {
+            connection_id: 42,
+            data: buf[..size].to_vec()
+        });
+
+        // the route to the peer is unknown
+        assert!(net_crypto.handle_tcp_packet(packet.clone(), relay_pk).wait().is_err());
+
+        let route_response = IncomingPacket::RouteResponse(RouteResponse {
+            connection_id: 42,
+            pk: peer_dht_pk,
+        });
+        assert!(net_crypto.handle_tcp_packet(route_response, relay_pk).wait().is_ok());
+
+        // data is matched by the DHT key of the routed peer
+        assert!(net_crypto.handle_tcp_packet(packet, relay_pk).wait().is_ok());
+
+        let connection = net_crypto.connection_by_key(peer_real_pk).unwrap().read().clone();
+        assert_eq!(connection.tcp_origin, Some(TcpOrigin::Data { relay_pk, connection_id: 42 }));
+
+        let (received, _lossy_rx) = lossy_rx.into_future().wait().unwrap();
+        let (received_peer_real_pk, received_data) = received.unwrap();
+        assert_eq!(received_peer_real_pk, peer_real_pk);
+        assert_eq!(received_data, vec![PACKET_ID_LOSSY_RANGE_START, 1, 2, 3]);
+    }
+
+    #[test]
+    fn handle_tcp_packet_crypto_handshake_new_connection() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (tcp_tx, tcp_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            precomputed_keys,
+        });
+        net_crypto.set_tcp_sink(tcp_tx);
+
+        let (peer_dht_pk, peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&dht_pk, &peer_dht_sk);
+
+        let base_nonce = gen_nonce();
+        let session_pk = gen_keypair().0;
+        let our_cookie = Cookie::new(peer_real_pk, peer_dht_pk);
+        let our_encrypted_cookie = EncryptedCookie::new(&net_crypto.symmetric_key, &our_cookie);
+        let cookie = EncryptedCookie {
+            nonce: secretbox::gen_nonce(),
+            payload: vec![43; 88]
+        };
+        let crypto_handshake_payload = CryptoHandshakePayload {
+            base_nonce,
+            session_pk,
+            cookie_hash: our_encrypted_cookie.hash(),
+            cookie: cookie.clone()
+        };
+        let crypto_handshake = Packet::CryptoHandshake(CryptoHandshake::new(&dht_precomputed_key, &crypto_handshake_payload, our_encrypted_cookie));
+        let mut buf = [0; MAX_TCP_PACKET_SIZE];
+        let (_, size) = crypto_handshake.to_bytes((&mut buf, 0)).unwrap();
+
+        let relay_pk = gen_keypair().0;
+        let packet = IncomingPacket::OobReceive(OobReceive {
+            sender_pk: peer_dht_pk,
+            data: buf[..size].to_vec()
+        });
+
+        assert!(net_crypto.handle_tcp_packet(packet, relay_pk).wait().is_ok());
+
+        let connection = net_crypto.connection_by_key(peer_real_pk).unwrap().read().clone();
+        assert_eq!(connection.peer_dht_pk, peer_dht_pk);
+        assert_eq!(connection.tcp_origin, Some(TcpOrigin::Oob { relay_pk, sender_pk: peer_dht_pk }));
+        assert_eq!(unpack!(connection.status, ConnectionStatus::NotConfirmed, peer_session_pk), session_pk);
+
+        // our handshake is sent back through the same relay
+        let (received, _tcp_rx) = tcp_rx.into_future().wait().unwrap();
+        let (packet, received_relay_pk) = received.unwrap();
+        assert_eq!(received_relay_pk, relay_pk);
+        let packet = unpack!(packet, OutgoingPacket::OobSend);
+        assert_eq!(packet.destination_pk, peer_dht_pk);
+        let (_, packet) = Packet::from_bytes(&packet.data).unwrap();
+        let packet = unpack!(packet, Packet::CryptoHandshake);
+        assert_eq!(packet.cookie, cookie);
+
+        // DHT key of the new peer is reported
+        let (received, _dht_pk_rx) = dht_pk_rx.into_future().wait().unwrap();
+        assert_eq!(received.unwrap(), (peer_real_pk, peer_dht_pk));
+    }
+
+    #[test]
+    fn handle_tcp_packet_connect_notification() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        net_crypto.add_connection(peer_real_pk, peer_dht_pk);
+
+        let relay_pk = gen_keypair().0;
+
+        // notification about unknown route is ignored
+        let packet = IncomingPacket::ConnectNotification(ConnectNotification { connection_id: 42 });
+        net_crypto.handle_tcp_packet(packet, relay_pk).wait().unwrap();
+        assert_eq!(net_crypto.connection_by_key(peer_real_pk).unwrap().read().tcp_origin, None);
+
+        let packet = IncomingPacket::RouteResponse(RouteResponse { pk: peer_dht_pk, connection_id: 42 });
+        net_crypto.handle_tcp_packet(packet, relay_pk).wait().unwrap();
+        let packet = IncomingPacket::ConnectNotification(ConnectNotification { connection_id: 42 });
+        net_crypto.handle_tcp_packet(packet, relay_pk).wait().unwrap();
+
+        let origin = TcpOrigin::Data { relay_pk, connection_id: 42 };
+        assert_eq!(net_crypto.connection_by_key(peer_real_pk).unwrap().read().tcp_origin, Some(origin));
+
+        // routed connection is not replaced by another one
+        let another_relay_pk = gen_keypair().0;
+        let packet = IncomingPacket::RouteResponse(RouteResponse { pk: peer_dht_pk, connection_id: 43 });
+        net_crypto.handle_tcp_packet(packet, another_relay_pk).wait().unwrap();
+        let packet = IncomingPacket::ConnectNotification(ConnectNotification { connection_id: 43 });
+        net_crypto.handle_tcp_packet(packet, another_relay_pk).wait().unwrap();
+
+        assert_eq!(net_crypto.connection_by_key(peer_real_pk).unwrap().read().tcp_origin, Some(origin));
+    }
+
+    #[test]
+    fn set_dht_udp_addr() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        net_crypto.add_connection(peer_real_pk, peer_dht_pk);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        net_crypto.set_dht_udp_addr(peer_dht_pk, addr);
+
+        assert_eq!(net_crypto.connection_info(peer_real_pk).unwrap().udp_addr, Some(addr));
+        assert_eq!(net_crypto.key_by_addr(addr), Some(peer_real_pk));
+
+        // the address is used for the connection created later
+        net_crypto.kill_connection(peer_real_pk).wait().unwrap();
+        net_crypto.add_connection(peer_real_pk, peer_dht_pk);
+
+        assert_eq!(net_crypto.connection_info(peer_real_pk).unwrap().udp_addr, Some(addr));
+        assert_eq!(net_crypto.key_by_addr(addr), Some(peer_real_pk));
+    }
+
+    #[test]
+    fn handle_tcp_packet_forged_does_not_change_origin() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(&dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let received_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        connection.status = ConnectionStatus::Established {
+            sent_nonce: gen_nonce(),
+            received_nonce,
+            peer_session_pk,
+            session_precomputed_key: precompute(&peer_session_pk, &session_sk),
+        };
+        let origin = TcpOrigin::Data { relay_pk: gen_keypair().0, connection_id: 42 };
+        connection.tcp_origin = Some(origin);
+
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+
+        let relay_pk = gen_keypair().0;
+        let forged_origin = TcpOrigin::Oob { relay_pk, sender_pk: peer_dht_pk };
+
+        // data packet encrypted with a wrong key
+        let crypto_data_payload = CryptoDataPayload {
+            buffer_start: 0,
+            packet_number: 0,
+            data: vec![PACKET_ID_LOSSY_RANGE_START, 1, 2, 3]
+        };
+        let crypto_data = CryptoData::new(&precompute(&gen_keypair().0, &session_sk), received_nonce, &crypto_data_payload);
+        assert!(net_crypto.handle_tcp_crypto_data(&crypto_data, forged_origin).wait().is_err());
+
+        // handshake encrypted with a wrong key
+        let crypto_handshake_payload = CryptoHandshakePayload {
+            base_nonce: gen_nonce(),
+            session_pk: gen_keypair().0,
+            cookie_hash: EncryptedCookie {
+                nonce: secretbox::gen_nonce(),
+                payload: vec![42; 88]
+            }.hash(),
+            cookie: EncryptedCookie {
+                nonce: secretbox::gen_nonce(),
+                payload: vec![43; 88]
+            },
+        };
+        let crypto_handshake = CryptoHandshake::new(&precompute(&gen_keypair().0, &dht_sk), &crypto_handshake_payload, EncryptedCookie {
+            nonce: secretbox::gen_nonce(),
+            payload: vec![42; 88]
+        });
+        assert!(net_crypto.handle_tcp_crypto_handshake(&crypto_handshake, forged_origin).wait().is_err());
+
+        let connection = net_crypto.connection_by_key(peer_real_pk).unwrap().read().clone();
+        assert_eq!(connection.tcp_origin, Some(origin));
+    }
+
+    #[test]
+    fn handle_tcp_packet_invalid() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            precomputed_keys,
+        });
+
+        let relay_pk = gen_keypair().0;
+
+        // garbage can't be parsed
+        let packet = IncomingPacket::Data(Data { connection_id: 42, data: vec![42; 123] });
+        assert!(net_crypto.handle_tcp_packet(packet, relay_pk).wait().is_err());
+
+        // packets that are not related to net_crypto are ignored
+        let packet = IncomingPacket::ConnectNotification(ConnectNotification { connection_id: 42 });
+        assert!(net_crypto.handle_tcp_packet(packet, relay_pk).wait().is_ok());
+    }
+
+    #[test]
+    fn send_status_packet() {
+        let (udp_tx, udp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(&dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.udp_addr = Some(addr);
+        connection.update_udp_received_time();
+
+        // send status packet first time - it should be sent
+        assert!(net_crypto.send_status_packet(&mut connection).wait().is_ok());
+
+        let packet = unpack!(connection.status.clone(), ConnectionStatus::CookieRequesting, packet);
+        assert_eq!(packet.num_sent, 1);
+
+        let (received, _udp_rx) = udp_rx.into_future().wait().unwrap();
+        let (received, addr_to_send) = received.unwrap();
+
+        assert_eq!(received, packet.dht_packet());
+        assert_eq!(addr_to_send, addr);
+
+        // send status packet again - it shouldn't be sent
+        assert!(net_crypto.send_status_packet(&mut connection).wait().is_ok());
+
+        let packet = unpack!(connection.status, ConnectionStatus::CookieRequesting, packet);
+        assert_eq!(packet.num_sent, 1);
+    }
+
+    #[test]
+    fn send_packet_udp() {
+        let (udp_tx, udp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(&dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.udp_addr = Some(addr);
+        connection.update_udp_received_time();
+
+        let packet = Packet::CryptoData(CryptoData {
+            nonce_last_bytes: 123,
+            payload: vec![42; DHT_ATTEMPT_MAX_PACKET_LENGTH]
+        });
+
+        assert!(net_crypto.send_packet(packet.clone(), &mut connection).wait().is_ok());
+
+        let (received, _udp_rx) = udp_rx.into_future().wait().unwrap();
+        let (received, addr_to_send) = received.unwrap();
+
+        assert_eq!(addr_to_send, addr);
+        assert_eq!(received, packet);
+    }
+
+    #[test]
+    fn send_packet_udp_attempt() {
+        let (udp_tx, udp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(&dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.udp_addr = Some(addr);
+
+        let packet = Packet::CryptoData(CryptoData {
+            nonce_last_bytes: 123,
+            payload: vec![42; DHT_ATTEMPT_MAX_PACKET_LENGTH - 3] // 1 byte of packet kind and 2 bytes of nonce
+        });
+
+        assert!(net_crypto.send_packet(packet.clone(), &mut connection).wait().is_ok());
+
+        let (received, _udp_rx) = udp_rx.into_future().wait().unwrap();
+        let (received, addr_to_send) = received.unwrap();
+
+        assert_eq!(addr_to_send, addr);
+        assert_eq!(received, packet);
+
+        // TODO: check that TCP received the packet
+    }
+
+    #[test]
+    fn send_packet_udp_status_packet() {
+        let (udp_tx, udp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(&dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.udp_addr = Some(addr);
+        // UDP attempt was made recently
+        connection.update_udp_send_attempt_time();
+
+        // cookie request is bigger than packets that are sent as attempts
+        let packet = connection.packet_to_send().unwrap();
+
+        assert!(net_crypto.send_packet(packet.clone(), &mut connection).wait().is_ok());
+
+        let (received, _udp_rx) = udp_rx.into_future().wait().unwrap();
+        let (received, addr_to_send) = received.unwrap();
+
+        assert_eq!(addr_to_send, addr);
+        assert_eq!(received, packet);
+    }
+
+    #[test]
+    fn send_packet_no_udp_attempt() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(&dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.udp_addr = Some(addr);
+
+        let packet = Packet::CryptoData(CryptoData {
+            nonce_last_bytes: 123,
+            payload: vec![42; DHT_ATTEMPT_MAX_PACKET_LENGTH]
+        });
+
+        assert!(net_crypto.send_packet(packet.clone(), &mut connection).wait().is_ok());
+
+        // TODO: check that TCP received the packet
+    }
+
+    #[test]
+    fn send_packet_tcp() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (tcp_tx, tcp_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            precomputed_keys,
+        });
+        net_crypto.set_tcp_sink(tcp_tx);
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(&dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let relay_pk = gen_keypair().0;
+        connection.tcp_origin = Some(TcpOrigin::Oob { relay_pk, sender_pk: peer_dht_pk });
+
+        let packet = Packet::CryptoData(CryptoData {
+            nonce_last_bytes: 123,
+            payload: vec![42; DHT_ATTEMPT_MAX_PACKET_LENGTH]
+        });
+
+        assert!(net_crypto.send_packet(packet.clone(), &mut connection).wait().is_ok());
+
+        let (received, _tcp_rx) = tcp_rx.into_future().wait().unwrap();
+        let (received_packet, relay_pk_to_send) = received.unwrap();
+        let received_packet = unpack!(received_packet, OutgoingPacket::OobSend);
+
+        assert_eq!(relay_pk_to_send, relay_pk);
+        assert_eq!(received_packet.destination_pk, peer_dht_pk);
+        assert_eq!(Packet::from_bytes(&received_packet.data).unwrap().1, packet);
+        assert_eq!(connection.tcp_stats.packets_sent, 1);
+    }
+
+    #[test]
+    fn main_loop_sends_status_packets() {
+        let (udp_tx, udp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(&dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let packet = unpack!(connection.status.clone(), ConnectionStatus::CookieRequesting, packet).dht_packet();
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.udp_addr = Some(addr);
+        connection.update_udp_received_time();
+
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+
+        assert!(net_crypto.main_loop().wait().is_ok());
+
+        let (received, _udp_rx) = udp_rx.into_future().wait().unwrap();
+        let (received, addr_to_send) = received.unwrap();
+
+        assert_eq!(addr_to_send, addr);
+        assert_eq!(received, packet);
+    }
+
+    #[test]
+    fn main_loop_removes_timed_out_connections() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(&dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.udp_addr = Some(addr);
+
+        // make the connection timed out
+        let cookie_request_id = unpack!(connection.status.clone(), ConnectionStatus::CookieRequesting, cookie_request_id);
+        let mut packet = unpack!(connection.status.clone(), ConnectionStatus::CookieRequesting, packet);
+        packet.num_sent = MAX_NUM_SENDPACKET_TRIES;
+        packet.sent_time -= Duration::from_secs(CRYPTO_SEND_PACKET_INTERVAL + 1);
+        connection.status = ConnectionStatus::CookieRequesting {
+            cookie_request_id,
+            packet
+        };
+
+        assert!(connection.is_timed_out(Duration::from_secs(CRYPTO_IDLE_TIMEOUT)));
+
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+        net_crypto.keys_by_addr.write().insert((addr.ip(), addr.port()), peer_real_pk);
+
+        assert!(net_crypto.main_loop().wait().is_ok());
+
+        assert!(net_crypto.connections.read().is_empty());
+        assert!(net_crypto.keys_by_addr.read().is_empty());
+    }
+
+    #[test]
+    fn send_status_packet_established() {
+        let (udp_tx, udp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(&dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let received_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce: gen_nonce(),
+            received_nonce,
+            peer_session_pk,
+            session_precomputed_key,
+        };
+
+        // send status packet with connection.status is Established
+        assert!(net_crypto.send_status_packet(&mut connection).wait().is_ok());
+
+        // Necessary to drop udp_tx so that udp_rx.collect() can be finished
+        drop(net_crypto.udp_tx);
+
+        assert!(udp_rx.collect().wait().unwrap().is_empty());
+    }
+
+    #[test]
+    fn add_connection() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        net_crypto.add_connection(peer_real_pk, peer_dht_pk);
+
+        let connection = net_crypto.connection_by_key(peer_real_pk).unwrap();
+        assert_eq!(connection.read().peer_dht_pk, peer_dht_pk);
+        let _cookie_request_id = unpack!(connection.read().status.clone(), ConnectionStatus::CookieRequesting, cookie_request_id);
+
+        // adding the same connection again shouldn't replace it
+        net_crypto.add_connection(peer_real_pk, gen_keypair().0);
+        assert_eq!(net_crypto.connection_by_key(peer_real_pk).unwrap().read().peer_dht_pk, peer_dht_pk);
+    }
+
+    #[test]
+    fn set_friend_udp_addr() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        net_crypto.add_connection(peer_real_pk, peer_dht_pk);
+
+        let addr_1 = "127.0.0.1:12345".parse().unwrap();
+        let addr_2 = "127.0.0.1:12346".parse().unwrap();
+
+        net_crypto.set_friend_udp_addr(peer_real_pk, addr_1);
+        assert_eq!(net_crypto.key_by_addr(addr_1), Some(peer_real_pk));
+
+        net_crypto.set_friend_udp_addr(peer_real_pk, addr_2);
+        assert_eq!(net_crypto.key_by_addr(addr_1), None);
+        assert_eq!(net_crypto.key_by_addr(addr_2), Some(peer_real_pk));
+        assert_eq!(net_crypto.connection_by_key(peer_real_pk).unwrap().read().udp_addr, Some(addr_2));
+    }
+
+    #[test]
+    fn send_lossless() {
+        let (udp_tx, udp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(&dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.udp_addr = Some(addr);
+        connection.update_udp_received_time();
+
+        let sent_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce,
+            received_nonce: gen_nonce(),
+            peer_session_pk,
+            session_precomputed_key: session_precomputed_key.clone(),
+        };
+
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+
+        let data = vec![PACKET_ID_CRYPTO_RANGE_END + 1, 1, 2, 3];
+        assert!(net_crypto.send_lossless(peer_real_pk, data.clone()).wait().is_ok());
+
+        let connection = net_crypto.connection_by_key(peer_real_pk).unwrap();
+        let connection = connection.read();
+        assert_eq!(connection.send_array.buffer_end, 1);
+        assert_eq!(connection.send_array.get(0).unwrap().data, data);
+
+        let mut next_nonce = sent_nonce;
+        increment_nonce(&mut next_nonce);
+        assert_eq!(unpack!(connection.status.clone(), ConnectionStatus::Established, sent_nonce), next_nonce);
+
+        let (received, _udp_rx) = udp_rx.into_future().wait().unwrap();
+        let (received, addr_to_send) = received.unwrap();
+        assert_eq!(addr_to_send, addr);
+
+        let packet = unpack!(received, Packet::CryptoData);
+        let payload = packet.get_payload(&session_precomputed_key, &sent_nonce).unwrap();
+        assert_eq!(payload.packet_number, 0);
+        assert_eq!(payload.buffer_start, 0);
+        assert_eq!(payload.data, data);
+    }
+
+    #[test]
+    fn send_lossy() {
+        let (udp_tx, udp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(&dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.udp_addr = Some(addr);
+        connection.update_udp_received_time();
+
+        let sent_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce,
+            received_nonce: gen_nonce(),
+            peer_session_pk,
+            session_precomputed_key: session_precomputed_key.clone(),
+        };
+
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+
+        // lossless packets can't be sent as lossy
+        assert!(net_crypto.send_lossy(peer_real_pk, vec![PACKET_ID_CRYPTO_RANGE_END + 1, 1, 2, 3]).wait().is_err());
+
+        let data = vec![PACKET_ID_LOSSY_RANGE_START, 1, 2, 3];
+        assert!(net_crypto.send_lossy(peer_real_pk, data.clone()).wait().is_ok());
+
+        // lossy packets are not stored in the sending buffer
+        let connection = net_crypto.connection_by_key(peer_real_pk).unwrap();
+        let connection = connection.read();
+        assert_eq!(connection.send_array.buffer_end, 0);
+
+        let mut next_nonce = sent_nonce;
+        increment_nonce(&mut next_nonce);
+        assert_eq!(unpack!(connection.status.clone(), ConnectionStatus::Established, sent_nonce), next_nonce);
+
+        let (received, _udp_rx) = udp_rx.into_future().wait().unwrap();
+        let (received, addr_to_send) = received.unwrap();
+        assert_eq!(addr_to_send, addr);
+
+        let packet = unpack!(received, Packet::CryptoData);
+        let payload = packet.get_payload(&session_precomputed_key, &sent_nonce).unwrap();
+        assert_eq!(payload.packet_number, 0);
+        assert_eq!(payload.buffer_start, 0);
+        assert_eq!(payload.data, data);
+    }
+
+    #[test]
+    fn send_lossless_numbered() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let data = vec![PACKET_ID_CRYPTO_RANGE_END + 1, 1, 2, 3];
+
+        // no connection
+        assert!(net_crypto.send_lossless_numbered(peer_real_pk, data.clone()).wait().is_err());
+        let packet_id = LosslessPacketId {
+            connection_id: 42,
+            packet_number: 0,
+        };
+        assert!(!net_crypto.is_lossless_delivered(peer_real_pk, packet_id));
+
+        let mut connection = CryptoConnection::new(&dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+        let connection_id = connection.id;
+
+        connection.udp_addr = Some("127.0.0.1:12345".parse().unwrap());
+        connection.update_udp_received_time();
+
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        connection.status = ConnectionStatus::Established {
+            sent_nonce: gen_nonce(),
+            received_nonce: gen_nonce(),
+            peer_session_pk,
+            session_precomputed_key: precompute(&peer_session_pk, &session_sk),
+        };
+
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+
+        let first_id = net_crypto.send_lossless_numbered(peer_real_pk, data.clone()).wait().unwrap();
+        assert_eq!(first_id, LosslessPacketId { connection_id, packet_number: 0 });
+        let second_id = net_crypto.send_lossless_numbered(peer_real_pk, data.clone()).wait().unwrap();
+        assert_eq!(second_id, LosslessPacketId { connection_id, packet_number: 1 });
+        assert!(!net_crypto.is_lossless_delivered(peer_real_pk, first_id));
+        assert!(!net_crypto.is_lossless_delivered(peer_real_pk, second_id));
+
+        // the peer confirmed the first packet
+        let connection = net_crypto.connection_by_key(peer_real_pk).unwrap();
+        connection.write().send_array.set_buffer_start(1).unwrap();
+
+        assert!(net_crypto.is_lossless_delivered(peer_real_pk, first_id));
+        assert!(!net_crypto.is_lossless_delivered(peer_real_pk, second_id));
+
+        // numbers that were never sent are not delivered
+        let unsent_id = LosslessPacketId { connection_id, packet_number: 7 };
+        assert!(!net_crypto.is_lossless_delivered(peer_real_pk, unsent_id));
+
+        // numbers of another connection to the same peer are not delivered
+        let stale_id = LosslessPacketId { connection_id: connection_id.wrapping_add(1), packet_number: 0 };
+        assert!(!net_crypto.is_lossless_delivered(peer_real_pk, stale_id));
+    }
+
+    #[test]
+    fn send_lossless_invalid() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+
+        // no connection
+        assert!(net_crypto.send_lossless(peer_real_pk, vec![PACKET_ID_CRYPTO_RANGE_END + 1]).wait().is_err());
+
+        // connection is not established
+        net_crypto.add_connection(peer_real_pk, peer_dht_pk);
+        assert!(net_crypto.send_lossless(peer_real_pk, vec![PACKET_ID_CRYPTO_RANGE_END + 1]).wait().is_err());
+
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        net_crypto.connection_by_key(peer_real_pk).unwrap().write().status = ConnectionStatus::Established {
+            sent_nonce: gen_nonce(),
+            received_nonce: gen_nonce(),
+            peer_session_pk,
+            session_precomputed_key: precompute(&peer_session_pk, &session_sk),
+        };
+
+        // packet id is not in lossless range
+        assert!(net_crypto.send_lossless(peer_real_pk, vec![PACKET_ID_LOSSY_RANGE_START]).wait().is_err());
+        assert!(net_crypto.send_lossless(peer_real_pk, Vec::new()).wait().is_err());
+        // packet is too big
+        assert!(net_crypto.send_lossless(peer_real_pk, vec![PACKET_ID_CRYPTO_RANGE_END + 1; MAX_CRYPTO_DATA_SIZE + 1]).wait().is_err());
+
+        assert_eq!(net_crypto.connection_by_key(peer_real_pk).unwrap().read().send_array.len(), 0);
+    }
+
+    #[test]
+    fn generate_request_packet() {
+        let mut recv_array = PacketsArray::new();
+        assert!(recv_array.insert(1, RecvPacket::new(vec![42; 123])).is_ok());
+        assert!(recv_array.insert(2, RecvPacket::new(vec![42; 123])).is_ok());
+        assert!(recv_array.insert(3, RecvPacket::new(vec![42; 123])).is_ok());
+        assert!(recv_array.insert(4, RecvPacket::new(vec![42; 123])).is_ok());
+        assert!(recv_array.insert(6, RecvPacket::new(vec![42; 123])).is_ok());
+        assert!(recv_array.insert(1025, RecvPacket::new(vec![42; 123])).is_ok());
+        for i in 7 .. 1024 {
+            assert!(recv_array.insert(i, RecvPacket::new(vec![42; 123])).is_ok());
+        }
+
+        // packets 0, 5 and 1024 are missing
+        let data = NetCrypto::generate_request_packet(&recv_array);
+        assert_eq!(data, vec![PACKET_ID_REQUEST, 1, 5, 0, 0, 0, 254]);
+
+        // generated packet can be handled by the other side
+        let mut send_array = PacketsArray::new();
+        for _ in 0 .. 1026 {
+            assert!(send_array.push_back(SentPacket {
+                data: vec![42; 123],
+                sent_time: Instant::now() - Duration::from_secs(1),
+                requested: false,
+            }).is_ok());
+        }
+        let mut last_sent_time = None;
+        NetCrypto::handle_request_packet(&mut send_array, &data[1..], Duration::from_millis(0), &mut last_sent_time);
+        for i in 0 .. 1026 {
+            if i == 0 || i == 5 || i == 1024 {
+                assert!(send_array.get(i).unwrap().requested);
+            } else if i < 1024 {
+                assert!(send_array.get(i).is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn main_loop_sends_request_packets() {
+        let (udp_tx, udp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let mut connection = CryptoConnection::new(&dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.udp_addr = Some(addr);
+        connection.update_udp_received_time();
+
+        let sent_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce,
+            received_nonce: gen_nonce(),
+            peer_session_pk,
+            session_precomputed_key: session_precomputed_key.clone(),
+        };
+
+        // packet 0 is missing
+        assert!(connection.recv_array.insert(1, RecvPacket::new(vec![42; 123])).is_ok());
+
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+
+        assert!(net_crypto.main_loop().wait().is_ok());
+
+        let (received, udp_rx) = udp_rx.into_future().wait().unwrap();
+        let (received, addr_to_send) = received.unwrap();
+        assert_eq!(addr_to_send, addr);
+
+        let packet = unpack!(received, Packet::CryptoData);
+        let payload = packet.get_payload(&session_precomputed_key, &sent_nonce).unwrap();
+        assert_eq!(payload.data, vec![PACKET_ID_REQUEST, 1]);
+
+        // request packet shouldn't be sent again too early
+        assert!(net_crypto.main_loop().wait().is_ok());
+
+        drop(net_crypto);
+        assert!(udp_rx.collect().wait().unwrap().is_empty());
+    }
 
     #[test]
-    fn send_packet_udp() {
+    fn main_loop_resends_requested_packets() {
         let (udp_tx, udp_rx) = mpsc::unbounded();
         let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
@@ -2355,27 +4796,53 @@ mod tests {
         let addr = "127.0.0.1:12345".parse().unwrap();
         connection.udp_addr = Some(addr);
         connection.update_udp_received_time();
+        // don't send keepalive and request packets in this test
+        connection.data_sent_time = Some(clock_now());
+        connection.request_packet_sent_time = Some(clock_now());
 
-        let packet = Packet::CryptoData(CryptoData {
-            nonce_last_bytes: 123,
-            payload: vec![42; DHT_ATTEMPT_MAX_PACKET_LENGTH]
-        });
+        let sent_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce,
+            received_nonce: gen_nonce(),
+            peer_session_pk,
+            session_precomputed_key: session_precomputed_key.clone(),
+        };
 
-        assert!(net_crypto.send_packet(packet.clone(), &mut connection).wait().is_ok());
+        assert!(connection.send_array.push_back(SentPacket::new(vec![42; 123])).is_ok());
+        let mut requested = SentPacket::new(vec![43; 123]);
+        requested.requested = true;
+        assert!(connection.send_array.push_back(requested).is_ok());
 
-        let (received, _udp_rx) = udp_rx.into_future().wait().unwrap();
-        let (received, addr_to_send) = received.unwrap();
+        let connection = Arc::new(RwLock::new(connection));
+        net_crypto.connections.write().insert(peer_real_pk, connection.clone());
 
-        assert_eq!(addr_to_send, addr);
-        assert_eq!(received, packet);
+        assert!(net_crypto.main_loop().wait().is_ok());
+
+        assert!(!connection.read().send_array.get(1).unwrap().requested);
+        assert_eq!(connection.read().retransmissions, 1);
+
+        let (received, udp_rx) = udp_rx.into_future().wait().unwrap();
+        let (received, _addr_to_send) = received.unwrap();
+
+        let packet = unpack!(received, Packet::CryptoData);
+        let payload = packet.get_payload(&session_precomputed_key, &sent_nonce).unwrap();
+        assert_eq!(payload.packet_number, 1);
+        assert_eq!(payload.data, vec![43; 123]);
+
+        drop(net_crypto);
+        assert!(udp_rx.collect().wait().unwrap().is_empty());
     }
 
     #[test]
-    fn send_packet_udp_attempt() {
+    fn kill_connection() {
         let (udp_tx, udp_rx) = mpsc::unbounded();
         let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (connection_status_tx, connection_status_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
         let (real_pk, _real_sk) = gen_keypair();
         let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
@@ -2389,6 +4856,7 @@ mod tests {
             real_pk,
             precomputed_keys,
         });
+        net_crypto.set_connection_status_sink(connection_status_tx);
 
         let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
@@ -2396,26 +4864,51 @@ mod tests {
 
         let addr = "127.0.0.1:12345".parse().unwrap();
         connection.udp_addr = Some(addr);
+        connection.update_udp_received_time();
 
-        let packet = Packet::CryptoData(CryptoData {
-            nonce_last_bytes: 123,
-            payload: vec![42; DHT_ATTEMPT_MAX_PACKET_LENGTH - 3] // 1 byte of packet kind and 2 bytes of nonce
-        });
+        let sent_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce,
+            received_nonce: gen_nonce(),
+            peer_session_pk,
+            session_precomputed_key: session_precomputed_key.clone(),
+        };
 
-        assert!(net_crypto.send_packet(packet.clone(), &mut connection).wait().is_ok());
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+        net_crypto.keys_by_addr.write().insert((addr.ip(), addr.port()), peer_real_pk);
+
+        assert!(net_crypto.kill_connection(peer_real_pk).wait().is_ok());
+
+        assert!(net_crypto.connections.read().is_empty());
+        assert!(net_crypto.keys_by_addr.read().is_empty());
+
+        // kill packet is stored in the sending buffer to be resent
+        let killed_connections = net_crypto.killed_connections.read();
+        assert_eq!(killed_connections.len(), 1);
+        let killed = &killed_connections[0];
+        assert_eq!(killed.packet_number, 0);
+        assert_eq!(killed.connection.send_array.get(0).unwrap().data, vec![PACKET_ID_KILL]);
+        drop(killed_connections);
 
         let (received, _udp_rx) = udp_rx.into_future().wait().unwrap();
         let (received, addr_to_send) = received.unwrap();
-
         assert_eq!(addr_to_send, addr);
-        assert_eq!(received, packet);
 
-        // TODO: check that TCP received the packet
+        let packet = unpack!(received, Packet::CryptoData);
+        let payload = packet.get_payload(&session_precomputed_key, &sent_nonce).unwrap();
+        assert_eq!(payload.data, vec![PACKET_ID_KILL]);
+        assert_eq!(payload.packet_number, 0);
+
+        let (received, _connection_status_rx) = connection_status_rx.into_future().wait().unwrap();
+        assert_eq!(received.unwrap(), (peer_real_pk, false));
     }
 
     #[test]
-    fn send_packet_no_udp_attempt() {
-        let (udp_tx, _udp_rx) = mpsc::unbounded();
+    fn main_loop_resends_kill_packet() {
+        let (udp_tx, udp_rx) = mpsc::unbounded();
         let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
@@ -2439,23 +4932,121 @@ mod tests {
 
         let addr = "127.0.0.1:12345".parse().unwrap();
         connection.udp_addr = Some(addr);
+        connection.update_udp_received_time();
 
-        let packet = Packet::CryptoData(CryptoData {
-            nonce_last_bytes: 123,
-            payload: vec![42; DHT_ATTEMPT_MAX_PACKET_LENGTH]
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        let mut sent_nonce = gen_nonce();
+        connection.status = ConnectionStatus::Established {
+            sent_nonce,
+            received_nonce: gen_nonce(),
+            peer_session_pk,
+            session_precomputed_key: session_precomputed_key.clone(),
+        };
+        let rtt = connection.rtt;
+
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+
+        let now = clock_now();
+        assert!(net_crypto.kill_connection(peer_real_pk).wait().is_ok());
+
+        // kill packet is not resent until rtt is elapsed
+        assert!(net_crypto.main_loop().wait().is_ok());
+
+        let mut enter = tokio_executor::enter().unwrap();
+        for i in 1 ..= MAX_KILL_PACKET_RESENDS {
+            let clock = Clock::new_with_now(ConstNow(now + (rtt + Duration::from_millis(1)) * i));
+            with_default(&clock, &mut enter, |_| {
+                assert!(net_crypto.main_loop().wait().is_ok());
+            });
+        }
+
+        // connection is forgotten after the last resend
+        assert!(net_crypto.killed_connections.read().is_empty());
+
+        drop(net_crypto);
+        let packets = udp_rx.collect().wait().unwrap();
+        assert_eq!(packets.len(), 1 + MAX_KILL_PACKET_RESENDS as usize);
+
+        for (received, addr_to_send) in packets {
+            assert_eq!(addr_to_send, addr);
+
+            let packet = unpack!(received, Packet::CryptoData);
+            let payload = packet.get_payload(&session_precomputed_key, &sent_nonce).unwrap();
+            assert_eq!(payload.data, vec![PACKET_ID_KILL]);
+            assert_eq!(payload.packet_number, 0);
+            increment_nonce(&mut sent_nonce);
+        }
+    }
+
+    #[test]
+    fn kill_connection_no_connection() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            precomputed_keys,
         });
 
-        assert!(net_crypto.send_packet(packet.clone(), &mut connection).wait().is_ok());
+        assert!(net_crypto.kill_connection(gen_keypair().0).wait().is_err());
+    }
 
-        // TODO: check that TCP received the packet
+    #[test]
+    fn kill_connection_not_confirmed() {
+        let (udp_tx, udp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        net_crypto.add_connection(peer_real_pk, peer_dht_pk);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        net_crypto.set_friend_udp_addr(peer_real_pk, addr);
+
+        assert!(net_crypto.kill_connection(peer_real_pk).wait().is_ok());
+
+        assert!(net_crypto.connections.read().is_empty());
+        assert!(net_crypto.keys_by_addr.read().is_empty());
+
+        // nothing should be sent since the peer doesn't have session keys
+        drop(net_crypto);
+        assert!(udp_rx.collect().wait().unwrap().is_empty());
     }
 
     #[test]
-    fn send_packet_tcp() {
+    fn handle_crypto_data_reports_established() {
         let (udp_tx, _udp_rx) = mpsc::unbounded();
         let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (connection_status_tx, connection_status_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
         let (real_pk, _real_sk) = gen_keypair();
         let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
@@ -2469,23 +5060,55 @@ mod tests {
             real_pk,
             precomputed_keys,
         });
+        net_crypto.set_connection_status_sink(connection_status_tx);
 
         let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
         let mut connection = CryptoConnection::new(&dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
 
-        let packet = Packet::CryptoData(CryptoData {
-            nonce_last_bytes: 123,
-            payload: vec![42; DHT_ATTEMPT_MAX_PACKET_LENGTH]
-        });
+        let received_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::NotConfirmed {
+            sent_nonce: gen_nonce(),
+            received_nonce,
+            peer_session_pk,
+            session_precomputed_key: session_precomputed_key.clone(),
+            packet: StatusPacket::new_crypto_handshake(CryptoHandshake {
+                cookie: EncryptedCookie {
+                    nonce: secretbox::gen_nonce(),
+                    payload: vec![42; 88]
+                },
+                nonce: gen_nonce(),
+                payload: vec![42; 248]
+            })
+        };
 
-        assert!(net_crypto.send_packet(packet.clone(), &mut connection).wait().is_ok());
+        let crypto_data_payload = CryptoDataPayload {
+            buffer_start: 0,
+            packet_number: 0,
+            data: vec![PACKET_ID_LOSSY_RANGE_START, 1, 2, 3]
+        };
+        let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload);
 
-        // TODO: check that TCP received the packet
+        assert!(net_crypto.handle_crypto_data(&mut connection, &crypto_data, None).wait().is_ok());
+        assert!(connection.is_established());
+        assert!(connection.data_received_time.is_some());
+
+        // the second packet shouldn't trigger status change
+        let mut next_nonce = received_nonce;
+        increment_nonce(&mut next_nonce);
+        let crypto_data = CryptoData::new(&session_precomputed_key, next_nonce, &crypto_data_payload);
+        assert!(net_crypto.handle_crypto_data(&mut connection, &crypto_data, None).wait().is_ok());
+
+        drop(net_crypto);
+        let statuses = connection_status_rx.collect().wait().unwrap();
+        assert_eq!(statuses, vec![(peer_real_pk, true)]);
     }
 
     #[test]
-    fn main_loop_sends_status_packets() {
+    fn main_loop_sends_keepalive() {
         let (udp_tx, udp_rx) = mpsc::unbounded();
         let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
@@ -2508,29 +5131,64 @@ mod tests {
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
         let mut connection = CryptoConnection::new(&dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
 
-        let packet = unpack!(connection.status.clone(), ConnectionStatus::CookieRequesting, packet).dht_packet();
-
         let addr = "127.0.0.1:12345".parse().unwrap();
         connection.udp_addr = Some(addr);
         connection.update_udp_received_time();
 
+        let sent_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce,
+            received_nonce: gen_nonce(),
+            peer_session_pk,
+            session_precomputed_key: session_precomputed_key.clone(),
+        };
+
+        let now = clock_now();
+        connection.data_sent_time = Some(now);
+        connection.request_packet_sent_time = Some(now);
+
         net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
 
+        // nothing should be sent since we've just sent a packet
         assert!(net_crypto.main_loop().wait().is_ok());
 
-        let (received, _udp_rx) = udp_rx.into_future().wait().unwrap();
-        let (received, addr_to_send) = received.unwrap();
+        let mut enter = tokio_executor::enter().unwrap();
+
+        // idle connection doesn't send request packets
+        let clock = Clock::new_with_now(ConstNow(now + Duration::from_millis(REQUEST_PACKET_INTERVAL)));
+
+        with_default(&clock, &mut enter, |_| {
+            assert!(net_crypto.main_loop().wait().is_ok());
+        });
+
+        let clock = Clock::new_with_now(ConstNow(now + Duration::from_secs(CRYPTO_KEEPALIVE_INTERVAL)));
+
+        with_default(&clock, &mut enter, |_| {
+            assert!(net_crypto.main_loop().wait().is_ok());
+        });
 
+        drop(net_crypto);
+        let packets = udp_rx.collect().wait().unwrap();
+        assert_eq!(packets.len(), 1);
+
+        let (received, addr_to_send) = packets[0].clone();
         assert_eq!(addr_to_send, addr);
-        assert_eq!(received, packet);
+
+        let packet = unpack!(received, Packet::CryptoData);
+        let payload = packet.get_payload(&session_precomputed_key, &sent_nonce).unwrap();
+        assert_eq!(payload.data, vec![PACKET_ID_REQUEST]);
     }
 
     #[test]
-    fn main_loop_removes_timed_out_connections() {
+    fn main_loop_removes_idle_connections() {
         let (udp_tx, _udp_rx) = mpsc::unbounded();
         let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (connection_status_tx, connection_status_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
         let (real_pk, _real_sk) = gen_keypair();
         let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
@@ -2544,6 +5202,8 @@ mod tests {
             real_pk,
             precomputed_keys,
         });
+        net_crypto.set_connection_status_sink(connection_status_tx);
+        net_crypto.set_idle_timeout(Duration::from_secs(10));
 
         let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
@@ -2552,30 +5212,38 @@ mod tests {
         let addr = "127.0.0.1:12345".parse().unwrap();
         connection.udp_addr = Some(addr);
 
-        // make the connection timed out
-        let cookie_request_id = unpack!(connection.status.clone(), ConnectionStatus::CookieRequesting, cookie_request_id);
-        let mut packet = unpack!(connection.status.clone(), ConnectionStatus::CookieRequesting, packet);
-        packet.num_sent = MAX_NUM_SENDPACKET_TRIES;
-        packet.sent_time -= Duration::from_secs(CRYPTO_SEND_PACKET_INTERVAL + 1);
-        connection.status = ConnectionStatus::CookieRequesting {
-            cookie_request_id,
-            packet
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        connection.status = ConnectionStatus::Established {
+            sent_nonce: gen_nonce(),
+            received_nonce: gen_nonce(),
+            peer_session_pk,
+            session_precomputed_key: precompute(&peer_session_pk, &session_sk),
         };
 
-        assert!(connection.is_timed_out());
+        let now = clock_now();
+        connection.data_received_time = Some(now);
 
         net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
         net_crypto.keys_by_addr.write().insert((addr.ip(), addr.port()), peer_real_pk);
 
-        assert!(net_crypto.main_loop().wait().is_ok());
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(now + Duration::from_secs(11)));
+
+        with_default(&clock, &mut enter, |_| {
+            assert!(net_crypto.main_loop().wait().is_ok());
+        });
 
         assert!(net_crypto.connections.read().is_empty());
         assert!(net_crypto.keys_by_addr.read().is_empty());
+
+        let (received, _connection_status_rx) = connection_status_rx.into_future().wait().unwrap();
+        assert_eq!(received.unwrap(), (peer_real_pk, false));
     }
 
     #[test]
-    fn send_status_packet_established() {
-        let (udp_tx, udp_rx) = mpsc::unbounded();
+    fn connection_info() {
+        let (udp_tx, _udp_rx) = mpsc::unbounded();
         let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
@@ -2597,6 +5265,10 @@ mod tests {
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
         let mut connection = CryptoConnection::new(&dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
 
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.udp_addr = Some(addr);
+        connection.update_udp_received_time();
+
         let received_nonce = gen_nonce();
         let (peer_session_pk, _peer_session_sk) = gen_keypair();
         let (_session_pk, session_sk) = gen_keypair();
@@ -2605,15 +5277,40 @@ mod tests {
             sent_nonce: gen_nonce(),
             received_nonce,
             peer_session_pk,
-            session_precomputed_key,
+            session_precomputed_key: session_precomputed_key.clone(),
         };
 
-        // send status packet with connection.status is Established
-        assert!(net_crypto.send_status_packet(&mut connection).wait().is_ok());
+        let connection = Arc::new(RwLock::new(connection));
+        net_crypto.connections.write().insert(peer_real_pk, connection.clone());
 
-        // Necessary to drop udp_tx so that udp_rx.collect() can be finished
-        drop(net_crypto.udp_tx);
+        assert!(net_crypto.send_lossless(peer_real_pk, vec![PACKET_ID_CRYPTO_RANGE_END + 1, 1, 2, 3]).wait().is_ok());
 
-        assert!(udp_rx.collect().wait().unwrap().is_empty());
+        let crypto_data_payload = CryptoDataPayload {
+            buffer_start: 0,
+            packet_number: 0,
+            data: vec![PACKET_ID_LOSSY_RANGE_START, 1, 2, 3]
+        };
+        let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload);
+        assert!(net_crypto.handle_crypto_data(&mut connection.write(), &crypto_data, Some(TcpOrigin::Data { relay_pk: gen_keypair().0, connection_id: 16 })).wait().is_ok());
+
+        let info = net_crypto.connection_info(peer_real_pk).unwrap();
+        assert!(info.is_established);
+        assert_eq!(info.transport, ConnectionTransport::Udp(addr));
+        assert_eq!(info.udp_stats.packets_sent, 1);
+        assert_eq!(info.udp_stats.packets_received, 0);
+        assert_eq!(info.tcp_stats.packets_received, 1);
+        assert_eq!(info.tcp_stats.bytes_received, crypto_data.size() as u64);
+        assert_eq!(info.send_array_len, 1);
+
+        assert!(net_crypto.connection_info(gen_keypair().0).is_none());
+
+        let (another_peer_real_pk, _another_peer_real_sk) = gen_keypair();
+        net_crypto.add_connection(another_peer_real_pk, gen_keypair().0);
+
+        let mut keys = net_crypto.connections_info().map(|info| info.peer_real_pk).collect::<Vec<_>>();
+        keys.sort();
+        let mut expected_keys = vec![peer_real_pk, another_peer_real_pk];
+        expected_keys.sort();
+        assert_eq!(keys, expected_keys);
     }
 }
@@ -0,0 +1,535 @@
+/*! Byte stream over an established crypto connection.
+
+`CryptoStream` implements `AsyncRead` and `AsyncWrite` so any codec (e.g.
+`tokio_codec::Framed`) can run on top of a crypto connection between two
+friends. Written bytes are split into lossless packets with
+`PACKET_ID_STREAM_DATA` id which are delivered to the other side in order.
+Closing the write half sends `PACKET_ID_STREAM_CLOSE` packet that is seen as EOF
+by the reader on the other side.
+
+*/
+
+use std::cmp;
+use std::io::{self, Read, Write, ErrorKind};
+use std::sync::Arc;
+
+use bytes::BytesMut;
+use futures::{Async, Future, Poll, Stream};
+use futures::sync::mpsc;
+use futures::task::AtomicTask;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use super::*;
+
+/// Lossless packet with this ID contains chunk of stream data. Stream packets
+/// take the last two ids of the lossless range toxcore leaves for custom
+/// packets (160-191). They are excluded from `LosslessCustom` packets of the
+/// messenger so applications can't use them.
+pub const PACKET_ID_STREAM_DATA: u8 = 190;
+
+/// Lossless packet with this ID means that the other side closed its write half
+/// of the stream.
+pub const PACKET_ID_STREAM_CLOSE: u8 = 191;
+
+/// Maximum number of stream bytes that can be sent in one lossless packet.
+pub const MAX_STREAM_CHUNK_SIZE: usize = MAX_CRYPTO_DATA_SIZE - 1;
+
+/// Stream won't add new packets to the sending buffer of the connection when
+/// it already contains this number of not acknowledged packets. Writing will
+/// be resumed when the peer confirms their delivery.
+pub const STREAM_SEND_WINDOW: u32 = 1024;
+
+/// Check if lossless packet belongs to a stream.
+pub(super) fn is_stream_packet(packet: &[u8]) -> bool {
+    match packet.first() {
+        Some(&PACKET_ID_STREAM_DATA) | Some(&PACKET_ID_STREAM_CLOSE) => true,
+        _ => false,
+    }
+}
+
+/// Handle that `NetCrypto` uses to pass received stream packets to the
+/// `CryptoStream` and to wake it up when more data can be written.
+#[derive(Clone, Debug)]
+pub(super) struct StreamHandle {
+    /// Sink to send received stream packets
+    packets_tx: mpsc::UnboundedSender<Vec<u8>>,
+    /// Task of the `CryptoStream` that waits for free space in the sending
+    /// buffer
+    write_task: Arc<AtomicTask>,
+}
+
+impl StreamHandle {
+    /// Pass received stream packet to the `CryptoStream`.
+    pub(super) fn send_packet(&self, packet: Vec<u8>) -> IoFuture<()> {
+        send_to(&self.packets_tx, packet)
+    }
+
+    /// Wake up the `CryptoStream` waiting for free space in the sending buffer.
+    pub(super) fn notify(&self) {
+        self.write_task.notify()
+    }
+}
+
+/** Byte stream over an established crypto connection with a friend.
+
+Only one stream can be opened per friend at a time. Lossless packets of the
+stream are not passed to the lossless sink of `NetCrypto` while the stream is
+opened. The stream is closed when it's dropped or when the crypto connection
+is killed or timed out.
+
+*/
+pub struct CryptoStream {
+    /// `NetCrypto` that owns the crypto connection
+    net_crypto: NetCrypto,
+    /// Long term `PublicKey` of the peer
+    peer_real_pk: PublicKey,
+    /// Stream of received stream packets
+    packets_rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    /// Task that is notified when the sending buffer gets free space
+    write_task: Arc<AtomicTask>,
+    /// Received bytes that weren't read yet
+    read_buf: BytesMut,
+    /// True if the peer closed its write half or the connection is gone
+    read_eof: bool,
+    /// True if we closed our write half
+    write_closed: bool,
+    /// Sending of packets that was started but isn't completed yet
+    pending: Option<IoFuture<()>>,
+}
+
+impl NetCrypto {
+    /// Open `CryptoStream` to a friend. The crypto connection should be
+    /// already added but it doesn't have to be established - writing will be
+    /// postponed until it becomes established.
+    pub fn open_stream(&self, peer_real_pk: PublicKey) -> Result<CryptoStream, io::Error> {
+        if self.connection_by_key(peer_real_pk).is_none() {
+            return Err(io::Error::new(
+                ErrorKind::NotConnected,
+                format!("No crypto connection for key {:?}", peer_real_pk)
+            ))
+        }
+
+        let mut streams = self.streams.write();
+        if streams.contains_key(&peer_real_pk) {
+            return Err(io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!("Stream for key {:?} is already opened", peer_real_pk)
+            ))
+        }
+
+        let (packets_tx, packets_rx) = mpsc::unbounded();
+        let write_task = Arc::new(AtomicTask::new());
+        streams.insert(peer_real_pk, StreamHandle {
+            packets_tx,
+            write_task: write_task.clone(),
+        });
+
+        Ok(CryptoStream {
+            net_crypto: self.clone(),
+            peer_real_pk,
+            packets_rx,
+            write_task,
+            read_buf: BytesMut::new(),
+            read_eof: false,
+            write_closed: false,
+            pending: None,
+        })
+    }
+
+    /// Wake up opened stream if it waits for free space in the sending buffer.
+    pub(super) fn notify_stream(&self, peer_real_pk: PublicKey) {
+        if let Some(stream) = self.streams.read().get(&peer_real_pk) {
+            stream.notify();
+        }
+    }
+
+    /// Forget about opened stream when its crypto connection is gone. The
+    /// stream will see EOF on reading and an error on writing.
+    pub(super) fn close_stream(&self, peer_real_pk: PublicKey) {
+        if let Some(stream) = self.streams.write().remove(&peer_real_pk) {
+            stream.notify();
+        }
+    }
+}
+
+impl CryptoStream {
+    /// Long term `PublicKey` of the peer this stream is opened to.
+    pub fn peer_real_pk(&self) -> PublicKey {
+        self.peer_real_pk
+    }
+
+    /// Drive sending of packets that was started earlier.
+    fn poll_pending(&mut self) -> Poll<(), io::Error> {
+        if let Some(mut pending) = self.pending.take() {
+            if let Async::NotReady = pending.poll()? {
+                self.pending = Some(pending);
+                return Ok(Async::NotReady)
+            }
+        }
+        Ok(Async::Ready(()))
+    }
+
+    /// Add packets to the sending buffer of the connection and start sending
+    /// them. Returns number of stream bytes that were taken from `buf`.
+    fn start_write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let connection = self.net_crypto.connection_by_key(self.peer_real_pk)
+            .ok_or_else(|| io::Error::new(ErrorKind::NotConnected, "Crypto connection is gone"))?;
+        let mut connection = connection.write();
+
+        // Registering before checking is not racy because `NetCrypto` can
+        // notify us only while holding the connection lock
+        self.write_task.register();
+
+        if !connection.is_established() {
+            return Err(ErrorKind::WouldBlock.into())
+        }
+
+        let window = STREAM_SEND_WINDOW.saturating_sub(connection.send_array.len()) as usize;
+        if window == 0 {
+            return Err(ErrorKind::WouldBlock.into())
+        }
+
+        let mut written = 0;
+        let mut futures = Vec::new();
+        for chunk in buf.chunks(MAX_STREAM_CHUNK_SIZE).take(window) {
+            let mut packet = Vec::with_capacity(chunk.len() + 1);
+            packet.push(PACKET_ID_STREAM_DATA);
+            packet.extend_from_slice(chunk);
+            futures.push(self.net_crypto.send_lossless_packet(&mut connection, packet));
+            written += chunk.len();
+        }
+        self.pending = Some(Box::new(future::join_all(futures).map(|_| ())));
+
+        Ok(written)
+    }
+}
+
+impl Drop for CryptoStream {
+    fn drop(&mut self) {
+        let mut streams = self.net_crypto.streams.write();
+        // The handle might belong to a stream opened after this one was closed
+        let is_own = streams.get(&self.peer_real_pk)
+            .map_or(false, |stream| Arc::ptr_eq(&stream.write_task, &self.write_task));
+        if is_own {
+            streams.remove(&self.peer_real_pk);
+        }
+    }
+}
+
+impl Read for CryptoStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if !self.read_buf.is_empty() {
+                let len = cmp::min(buf.len(), self.read_buf.len());
+                buf[..len].copy_from_slice(&self.read_buf.split_to(len));
+                return Ok(len)
+            }
+
+            if self.read_eof {
+                return Ok(0)
+            }
+
+            match self.packets_rx.poll() {
+                Ok(Async::Ready(Some(packet))) => match packet.split_first() {
+                    Some((&PACKET_ID_STREAM_DATA, data)) => self.read_buf.extend_from_slice(data),
+                    Some((&PACKET_ID_STREAM_CLOSE, _)) => self.read_eof = true,
+                    _ => unreachable!("Only stream packets are passed to stream"),
+                },
+                // connection was killed or timed out
+                Ok(Async::Ready(None)) => self.read_eof = true,
+                Ok(Async::NotReady) => return Err(ErrorKind::WouldBlock.into()),
+                Err(()) => unreachable!("Unbounded receiver never fails"),
+            }
+        }
+    }
+}
+
+impl Write for CryptoStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Async::NotReady = self.poll_pending()? {
+            return Err(ErrorKind::WouldBlock.into())
+        }
+
+        if self.write_closed {
+            return Err(io::Error::new(ErrorKind::BrokenPipe, "Stream is closed for writing"))
+        }
+
+        if buf.is_empty() {
+            return Ok(0)
+        }
+
+        self.start_write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.poll_pending()? {
+            Async::Ready(()) => Ok(()),
+            Async::NotReady => Err(ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+impl AsyncRead for CryptoStream {}
+
+impl AsyncWrite for CryptoStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        if let Async::NotReady = self.poll_pending()? {
+            return Ok(Async::NotReady)
+        }
+
+        if !self.write_closed {
+            let connection = self.net_crypto.connection_by_key(self.peer_real_pk)
+                .ok_or_else(|| io::Error::new(ErrorKind::NotConnected, "Crypto connection is gone"))?;
+            let mut connection = connection.write();
+            self.write_task.register();
+            if connection.send_array.len() >= STREAM_SEND_WINDOW {
+                return Ok(Async::NotReady)
+            }
+            self.pending = Some(self.net_crypto.send_lossless_packet(&mut connection, vec![PACKET_ID_STREAM_CLOSE]));
+            self.write_closed = true;
+        }
+
+        self.poll_pending()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio_codec::{Framed, LinesCodec};
+    use futures::Sink;
+
+    fn create_net_crypto() -> (NetCrypto, mpsc::UnboundedReceiver<(Packet, SocketAddr)>, mpsc::UnboundedReceiver<(PublicKey, Vec<u8>)>) {
+        let (udp_tx, udp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            precomputed_keys,
+        });
+        (net_crypto, udp_rx, lossless_rx)
+    }
+
+    /// Create two `NetCrypto` with established connection between them
+    fn create_connected_pair() -> (
+        (NetCrypto, mpsc::UnboundedReceiver<(Packet, SocketAddr)>, mpsc::UnboundedReceiver<(PublicKey, Vec<u8>)>),
+        (NetCrypto, mpsc::UnboundedReceiver<(Packet, SocketAddr)>, mpsc::UnboundedReceiver<(PublicKey, Vec<u8>)>),
+    ) {
+        let (alice, alice_udp_rx, alice_lossless_rx) = create_net_crypto();
+        let (bob, bob_udp_rx, bob_lossless_rx) = create_net_crypto();
+
+        let alice_addr = "127.0.0.1:12345".parse().unwrap();
+        let bob_addr = "127.0.0.1:12346".parse().unwrap();
+
+        let (alice_session_pk, alice_session_sk) = gen_keypair();
+        let (bob_session_pk, bob_session_sk) = gen_keypair();
+        let alice_nonce = gen_nonce();
+        let bob_nonce = gen_nonce();
+
+        let mut alice_connection = CryptoConnection::new(&alice.dht_sk, alice.dht_pk, alice.real_pk, bob.real_pk, bob.dht_pk);
+        alice_connection.status = ConnectionStatus::Established {
+            sent_nonce: alice_nonce,
+            received_nonce: bob_nonce,
+            peer_session_pk: bob_session_pk,
+            session_precomputed_key: precompute(&bob_session_pk, &alice_session_sk),
+        };
+        alice_connection.udp_addr = Some(bob_addr);
+        alice_connection.update_udp_received_time();
+        alice.connections.write().insert(bob.real_pk, Arc::new(RwLock::new(alice_connection)));
+        alice.keys_by_addr.write().insert((bob_addr.ip(), bob_addr.port()), bob.real_pk);
+
+        let mut bob_connection = CryptoConnection::new(&bob.dht_sk, bob.dht_pk, bob.real_pk, alice.real_pk, alice.dht_pk);
+        bob_connection.status = ConnectionStatus::Established {
+            sent_nonce: bob_nonce,
+            received_nonce: alice_nonce,
+            peer_session_pk: alice_session_pk,
+            session_precomputed_key: precompute(&alice_session_pk, &bob_session_sk),
+        };
+        bob_connection.udp_addr = Some(alice_addr);
+        bob_connection.update_udp_received_time();
+        bob.connections.write().insert(alice.real_pk, Arc::new(RwLock::new(bob_connection)));
+        bob.keys_by_addr.write().insert((alice_addr.ip(), alice_addr.port()), alice.real_pk);
+
+        ((alice, alice_udp_rx, alice_lossless_rx), (bob, bob_udp_rx, bob_lossless_rx))
+    }
+
+    /// Pass all packets sent by one `NetCrypto` to another one
+    fn transfer(udp_rx: &mut mpsc::UnboundedReceiver<(Packet, SocketAddr)>, to: &NetCrypto, from_addr: SocketAddr) {
+        future::poll_fn(|| {
+            while let Async::Ready(Some((packet, _addr))) = udp_rx.poll()? {
+                let packet = unpack!(packet, Packet::CryptoData);
+                to.handle_udp_crypto_data(&packet, from_addr).wait().unwrap();
+            }
+            Ok::<_, ()>(Async::Ready(()))
+        }).wait().unwrap();
+    }
+
+    #[test]
+    fn open_stream_no_connection() {
+        let (net_crypto, _udp_rx, _lossless_rx) = create_net_crypto();
+        let error = net_crypto.open_stream(gen_keypair().0).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::NotConnected);
+    }
+
+    #[test]
+    fn open_stream_twice() {
+        let ((alice, _alice_udp_rx, _alice_lossless_rx), (bob, _bob_udp_rx, _bob_lossless_rx)) = create_connected_pair();
+
+        let stream = alice.open_stream(bob.real_pk).unwrap();
+        let error = alice.open_stream(bob.real_pk).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::AlreadyExists);
+
+        // dropped stream releases its handle
+        drop(stream);
+        assert!(alice.open_stream(bob.real_pk).is_ok());
+    }
+
+    #[test]
+    fn write_chunks_and_read_in_order() {
+        let ((alice, mut alice_udp_rx, _alice_lossless_rx), (bob, _bob_udp_rx, bob_lossless_rx)) = create_connected_pair();
+        let alice_addr = "127.0.0.1:12345".parse().unwrap();
+
+        let alice_stream = alice.open_stream(bob.real_pk).unwrap();
+        let bob_stream = bob.open_stream(alice.real_pk).unwrap();
+
+        let data = (0 .. MAX_STREAM_CHUNK_SIZE * 2 + 42).map(|i| i as u8).collect::<Vec<_>>();
+
+        let alice_stream = future::lazy(|| {
+            let mut alice_stream = alice_stream;
+            assert_eq!(alice_stream.write(&data).unwrap(), data.len());
+            alice_stream.flush().unwrap();
+            Ok::<_, ()>(alice_stream)
+        }).wait().unwrap();
+
+        // three lossless packets should be sent
+        let connection = alice.connection_by_key(bob.real_pk).unwrap();
+        assert_eq!(connection.read().send_array.len(), 3);
+
+        transfer(&mut alice_udp_rx, &bob, alice_addr);
+
+        let (bob_stream, received) = tokio::io::read_exact(bob_stream, vec![0; data.len()]).wait().unwrap();
+        assert_eq!(received, data);
+
+        // stream packets should not get to the lossless sink
+        drop(bob);
+        drop(bob_stream);
+        drop(alice_stream);
+        assert!(bob_lossless_rx.collect().wait().unwrap().is_empty());
+    }
+
+    #[test]
+    fn shutdown_is_eof() {
+        let ((alice, mut alice_udp_rx, _alice_lossless_rx), (bob, _bob_udp_rx, _bob_lossless_rx)) = create_connected_pair();
+        let alice_addr = "127.0.0.1:12345".parse().unwrap();
+
+        let alice_stream = alice.open_stream(bob.real_pk).unwrap();
+        let bob_stream = bob.open_stream(alice.real_pk).unwrap();
+
+        let _alice_stream = tokio::io::write_all(alice_stream, b"bye".to_vec())
+            .and_then(|(stream, _)| tokio::io::shutdown(stream))
+            .wait()
+            .unwrap();
+
+        transfer(&mut alice_udp_rx, &bob, alice_addr);
+
+        let (_bob_stream, received) = tokio::io::read_to_end(bob_stream, Vec::new()).wait().unwrap();
+        assert_eq!(received, b"bye".to_vec());
+    }
+
+    #[test]
+    fn write_after_shutdown() {
+        let ((alice, _alice_udp_rx, _alice_lossless_rx), (bob, _bob_udp_rx, _bob_lossless_rx)) = create_connected_pair();
+
+        let alice_stream = alice.open_stream(bob.real_pk).unwrap();
+        let mut alice_stream = tokio::io::shutdown(alice_stream).wait().unwrap();
+
+        let error = future::lazy(|| Ok::<_, ()>(alice_stream.write(b"data"))).wait().unwrap().err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn write_backpressure() {
+        let ((alice, _alice_udp_rx, _alice_lossless_rx), (bob, _bob_udp_rx, _bob_lossless_rx)) = create_connected_pair();
+
+        let mut alice_stream = alice.open_stream(bob.real_pk).unwrap();
+
+        let connection = alice.connection_by_key(bob.real_pk).unwrap();
+        connection.write().send_array.buffer_end = STREAM_SEND_WINDOW - 1;
+
+        future::lazy(|| {
+            // only one chunk fits into the window
+            let data = vec![42; MAX_STREAM_CHUNK_SIZE * 2];
+            assert_eq!(alice_stream.write(&data).unwrap(), MAX_STREAM_CHUNK_SIZE);
+            alice_stream.flush().unwrap();
+
+            // window is full
+            let error = alice_stream.write(&data).err().unwrap();
+            assert_eq!(error.kind(), ErrorKind::WouldBlock);
+
+            // the peer acknowledged packets
+            connection.write().send_array.set_buffer_start(STREAM_SEND_WINDOW).unwrap();
+            assert!(alice_stream.write(&data).is_ok());
+
+            Ok::<_, ()>(())
+        }).wait().unwrap();
+    }
+
+    #[test]
+    fn write_not_established() {
+        let (alice, _alice_udp_rx, _alice_lossless_rx) = create_net_crypto();
+        let (bob_pk, _bob_sk) = gen_keypair();
+        alice.add_connection(bob_pk, gen_keypair().0);
+
+        let mut alice_stream = alice.open_stream(bob_pk).unwrap();
+
+        let error = future::lazy(|| Ok::<_, ()>(alice_stream.write(b"data"))).wait().unwrap().err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn killed_connection_is_eof() {
+        let ((alice, _alice_udp_rx, _alice_lossless_rx), (bob, _bob_udp_rx, _bob_lossless_rx)) = create_connected_pair();
+
+        let alice_stream = alice.open_stream(bob.real_pk).unwrap();
+
+        alice.connections.write().remove(&bob.real_pk);
+        alice.close_stream(bob.real_pk);
+
+        let (mut alice_stream, received) = tokio::io::read_to_end(alice_stream, Vec::new()).wait().unwrap();
+        assert!(received.is_empty());
+
+        let error = future::lazy(|| Ok::<_, ()>(alice_stream.write(b"data"))).wait().unwrap().err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::NotConnected);
+    }
+
+    #[test]
+    fn framed_lines() {
+        let ((alice, mut alice_udp_rx, _alice_lossless_rx), (bob, _bob_udp_rx, _bob_lossless_rx)) = create_connected_pair();
+        let alice_addr = "127.0.0.1:12345".parse().unwrap();
+
+        let alice_framed = Framed::new(alice.open_stream(bob.real_pk).unwrap(), LinesCodec::new());
+        let bob_framed = Framed::new(bob.open_stream(alice.real_pk).unwrap(), LinesCodec::new());
+
+        let _alice_framed = alice_framed
+            .send("hello".to_owned())
+            .and_then(|framed| framed.send("world".to_owned()))
+            .wait()
+            .unwrap();
+
+        transfer(&mut alice_udp_rx, &bob, alice_addr);
+
+        let lines = bob_framed.take(2).collect().wait().unwrap();
+        assert_eq!(lines, vec!["hello".to_owned(), "world".to_owned()]);
+    }
+}
@@ -0,0 +1,1182 @@
+/*! Onion client.
+
+Onion client announces our long term `PublicKey` to onion nodes that are
+closest to it so that friends can find us, searches for onion nodes where
+friends are announced and sends data to friends through these nodes. All
+requests are sent through onion paths that consist of three random DHT nodes
+so that onion nodes don't learn our IP address.
+
+Two kinds of data are sent to friends via onion: friend requests and our DHT
+`PublicKey` along with DHT nodes close to us. When a friend receives our DHT
+`PublicKey` it can find us in DHT and connect to us via `net_crypto`.
+
+Onion requests are sent only via UDP.
+
+*/
+
+mod onion_path;
+
+pub use self::onion_path::*;
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use futures::{future, Future, Stream};
+use futures::sync::mpsc;
+use parking_lot::Mutex;
+use tokio::timer::Interval;
+
+use toxcore::crypto_core::*;
+use toxcore::dht::kbucket::Distance;
+use toxcore::dht::packed_node::PackedNode;
+use toxcore::dht::packet::Packet as DhtPacket;
+use toxcore::dht::server::Server as DhtServer;
+use toxcore::friend_connection::packet::FriendRequests;
+use toxcore::io_tokio::*;
+use toxcore::onion::onion_announce::initial_ping_id;
+use toxcore::onion::packet::*;
+use toxcore::time::*;
+use toxcore::toxid::NoSpam;
+
+/// Number of onion paths that are used to send requests.
+pub const NUMBER_ONION_PATHS: usize = 3;
+
+/// Maximum number of onion nodes we announce ourselves to and maximum number
+/// of onion nodes we search every friend at.
+pub const MAX_ONION_NODES: usize = 8;
+
+/// Interval in seconds for sending `OnionAnnounceRequest` to onion nodes we
+/// are not announced at yet and for searching friends whose data
+/// `PublicKey` is not known yet.
+pub const ANNOUNCE_INTERVAL_NOT_ANNOUNCED: u64 = 3;
+
+/// Interval in seconds for sending `OnionAnnounceRequest` to onion nodes we
+/// are announced at or a friend was found at.
+pub const ONION_NODE_PING_INTERVAL: u64 = 15;
+
+/// Onion node is removed from the list if it doesn't respond for this number
+/// of seconds.
+pub const ONION_NODE_TIMEOUT: u64 = ONION_NODE_PING_INTERVAL * 3;
+
+/// Interval in seconds for sending `OnionAnnounceRequest` to the closest DHT
+/// nodes while the list of onion nodes is not full.
+pub const NODES_SEARCH_INTERVAL: u64 = 3;
+
+/// Interval in seconds for sending our DHT `PublicKey` to friends that are
+/// not connected to us.
+pub const DHT_PK_ANNOUNCE_INTERVAL: u64 = 30;
+
+/// `OnionAnnounceResponse` is accepted only during this number of seconds
+/// after the request was sent.
+pub const ANNOUNCE_REQUEST_TIMEOUT: u64 = 10;
+
+/// Interval in milliseconds for running the main loop.
+const MAIN_LOOP_INTERVAL: u64 = 1000;
+
+/// Shorthand for the transmit half of the message channel for sending DHT
+/// `PublicKey`s of friends. The first key is a long term key, the second key
+/// is a DHT key.
+type DhtPkTx = mpsc::UnboundedSender<(PublicKey, PublicKey)>;
+
+/// Shorthand for the transmit half of the message channel for sending friend
+/// requests received via onion.
+type FriendRequestTx = mpsc::UnboundedSender<(PublicKey, FriendRequests)>;
+
+/// Onion node we sent `OnionAnnounceRequest` to and received a response.
+#[derive(Clone, Debug)]
+struct OnionNode {
+    /// DHT `PublicKey` of the node.
+    pk: PublicKey,
+    /// Address of the node.
+    saddr: SocketAddr,
+    /// Id of the onion path the response from the node was received
+    /// through. Ping id we get from the node is valid only for this path.
+    path_id: u64,
+    /// Ping id that should be sent to the node to announce ourselves.
+    ping_id: sha256::Digest,
+    /// Whether we are announced at this node or the friend we search is
+    /// found at this node.
+    is_stored: bool,
+    /// Time when we sent the last request to the node.
+    ping_time: Instant,
+    /// Time when we received the last response from the node.
+    response_time: Instant,
+}
+
+impl OnionNode {
+    /// Check if the node doesn't respond for too long.
+    fn is_timed_out(&self) -> bool {
+        clock_elapsed(self.response_time) >= Duration::from_secs(ONION_NODE_TIMEOUT)
+    }
+
+    /// Convert `OnionNode` to `PackedNode`.
+    fn to_packed_node(&self) -> PackedNode {
+        PackedNode::new(self.saddr, &self.pk)
+    }
+}
+
+/// List of onion nodes that are closest to some `PublicKey` sorted by
+/// distance to it.
+#[derive(Clone, Debug)]
+struct OnionNodes {
+    /// `PublicKey` the nodes are close to.
+    base_pk: PublicKey,
+    /// Nodes sorted by distance to `base_pk`.
+    nodes: Vec<OnionNode>,
+    /// Time when we sent requests to the closest DHT nodes the last time.
+    search_time: Option<Instant>,
+}
+
+impl OnionNodes {
+    /// Create new empty `OnionNodes` list.
+    fn new(base_pk: PublicKey) -> OnionNodes {
+        OnionNodes {
+            base_pk,
+            nodes: Vec::with_capacity(MAX_ONION_NODES),
+            search_time: None,
+        }
+    }
+
+    /// Check if the node is in the list.
+    fn contains(&self, pk: &PublicKey) -> bool {
+        self.nodes.iter().any(|node| node.pk == *pk)
+    }
+
+    /// Check if the node is not in the list and can be added there.
+    fn can_add(&self, pk: &PublicKey) -> bool {
+        if self.contains(pk) {
+            return false
+        }
+        match self.nodes.last() {
+            Some(farthest) if self.nodes.len() >= MAX_ONION_NODES =>
+                self.base_pk.distance(pk, &farthest.pk) == Ordering::Less,
+            _ => true,
+        }
+    }
+
+    /// Add the node to the list or update it if it's already there. The
+    /// farthest node is removed if the list is full.
+    fn add(&mut self, node: OnionNode) {
+        if let Some(old_node) = self.nodes.iter_mut().find(|old_node| old_node.pk == node.pk) {
+            *old_node = node;
+            return
+        }
+        if !self.can_add(&node.pk) {
+            return
+        }
+        self.nodes.push(node);
+        let base_pk = self.base_pk;
+        self.nodes.sort_by(|node_1, node_2| base_pk.distance(&node_1.pk, &node_2.pk));
+        self.nodes.truncate(MAX_ONION_NODES);
+    }
+
+    /// Remove nodes that don't respond for too long.
+    fn remove_timed_out(&mut self) {
+        self.nodes.retain(|node| !node.is_timed_out());
+    }
+
+    /// Check if the list has the maximum number of nodes.
+    fn is_full(&self) -> bool {
+        self.nodes.len() >= MAX_ONION_NODES
+    }
+
+    /// Check if it's time to send requests to the closest DHT nodes.
+    fn should_search(&self) -> bool {
+        !self.is_full() && self.search_time.map_or(true, |time|
+            clock_elapsed(time) >= Duration::from_secs(NODES_SEARCH_INTERVAL)
+        )
+    }
+}
+
+/// Friend we search via onion.
+#[derive(Clone, Debug)]
+struct OnionFriend {
+    /// Long term `PublicKey` of the friend.
+    real_pk: PublicKey,
+    /// Temporary `PublicKey` that is used to search the friend.
+    temporary_pk: PublicKey,
+    /// Temporary `SecretKey` that is used to search the friend.
+    temporary_sk: SecretKey,
+    /// `PublicKey` the friend announced to onion nodes. Data sent to the
+    /// friend should be encrypted with it.
+    data_pk: Option<PublicKey>,
+    /// Onion nodes close to the friend's long term `PublicKey`.
+    nodes: OnionNodes,
+    /// Number from the last accepted `DhtPkAnnouncePayload` of the friend.
+    last_no_reply: u64,
+    /// Time when we sent our DHT `PublicKey` to the friend the last time.
+    dht_pk_announce_time: Option<Instant>,
+    /// Whether the friend is connected to us via `net_crypto`. Connected
+    /// friends are not searched.
+    connected: bool,
+}
+
+impl OnionFriend {
+    /// Create new `OnionFriend`.
+    fn new(real_pk: PublicKey) -> OnionFriend {
+        let (temporary_pk, temporary_sk) = gen_keypair();
+        OnionFriend {
+            real_pk,
+            temporary_pk,
+            temporary_sk,
+            data_pk: None,
+            nodes: OnionNodes::new(real_pk),
+            last_no_reply: 0,
+            dht_pk_announce_time: None,
+            connected: false,
+        }
+    }
+
+    /// Check if it's time to send our DHT `PublicKey` to the friend.
+    fn should_announce_dht_pk(&self) -> bool {
+        self.data_pk.is_some() && self.dht_pk_announce_time.map_or(true, |time|
+            clock_elapsed(time) >= Duration::from_secs(DHT_PK_ANNOUNCE_INTERVAL)
+        )
+    }
+}
+
+/// `OnionAnnounceRequest` we are waiting a response to.
+#[derive(Clone, Debug)]
+struct AnnounceRequest {
+    /// Long term `PublicKey` of the friend we search or `None` if we announce
+    /// ourselves.
+    friend_pk: Option<PublicKey>,
+    /// Node the request was sent to.
+    node: PackedNode,
+    /// Id of the onion path the request was sent through.
+    path_id: u64,
+    /// Time when the request was sent.
+    time: Instant,
+}
+
+impl AnnounceRequest {
+    /// Check if it's too late to accept a response to this request.
+    fn is_timed_out(&self) -> bool {
+        clock_elapsed(self.time) >= Duration::from_secs(ANNOUNCE_REQUEST_TIMEOUT)
+    }
+}
+
+/// Mutable state of `OnionClient`.
+struct OnionClientState {
+    /// Onion paths requests are sent through.
+    paths: Vec<OnionPath>,
+    /// Onion nodes close to our long term `PublicKey` we announce ourselves
+    /// to.
+    announce_nodes: OnionNodes,
+    /// Friends we search by their long term `PublicKey`s.
+    friends: HashMap<PublicKey, OnionFriend>,
+    /// `OnionAnnounceRequest`s we are waiting responses to by their
+    /// `sendback_data`.
+    announce_requests: HashMap<u64, AnnounceRequest>,
+}
+
+impl OnionClientState {
+    /// Find index of the path with the given id or index of a random path if
+    /// there is no such path.
+    fn path_index(&self, path_id: Option<u64>) -> Option<usize> {
+        path_id
+            .and_then(|path_id| self.paths.iter().position(|path| path.id == path_id))
+            .or_else(|| if self.paths.is_empty() {
+                None
+            } else {
+                Some(random_usize() % self.paths.len())
+            })
+    }
+
+    /// Check if we are already waiting a response from the node.
+    fn is_request_pending(&self, friend_pk: Option<PublicKey>, pk: &PublicKey) -> bool {
+        self.announce_requests.values().any(|request|
+            request.friend_pk == friend_pk && request.node.pk == *pk
+        )
+    }
+}
+
+/// Onion client that announces us and searches friends via onion.
+#[derive(Clone)]
+pub struct OnionClient {
+    /// DHT server that is used to get nodes for onion paths and to send
+    /// packets.
+    dht: DhtServer,
+    /// Our long term `PublicKey`.
+    real_pk: PublicKey,
+    /// Our long term `SecretKey`.
+    real_sk: SecretKey,
+    /// `PublicKey` we announce to onion nodes. Friends encrypt data they send
+    /// to us via onion with it.
+    data_pk: PublicKey,
+    /// `SecretKey` for `data_pk`.
+    data_sk: SecretKey,
+    /// Mutable state of the client.
+    state: Arc<Mutex<OnionClientState>>,
+    /// Sink to send DHT `PublicKey`s of friends received via onion.
+    dht_pk_tx: Option<DhtPkTx>,
+    /// Sink to send friend requests received via onion.
+    friend_request_tx: Option<FriendRequestTx>,
+}
+
+impl OnionClient {
+    /// Create new `OnionClient`.
+    pub fn new(dht: DhtServer, real_pk: PublicKey, real_sk: SecretKey) -> OnionClient {
+        let (data_pk, data_sk) = gen_keypair();
+        OnionClient {
+            dht,
+            real_pk,
+            real_sk,
+            data_pk,
+            data_sk,
+            state: Arc::new(Mutex::new(OnionClientState {
+                paths: Vec::with_capacity(NUMBER_ONION_PATHS),
+                announce_nodes: OnionNodes::new(real_pk),
+                friends: HashMap::new(),
+                announce_requests: HashMap::new(),
+            })),
+            dht_pk_tx: None,
+            friend_request_tx: None,
+        }
+    }
+
+    /// Set sink to send DHT `PublicKey`s of friends received via onion.
+    pub fn set_dht_pk_sink(&mut self, dht_pk_tx: DhtPkTx) {
+        self.dht_pk_tx = Some(dht_pk_tx);
+    }
+
+    /// Set sink to send friend requests received via onion.
+    pub fn set_friend_request_sink(&mut self, friend_request_tx: FriendRequestTx) {
+        self.friend_request_tx = Some(friend_request_tx);
+    }
+
+    /// Start searching the friend by its long term `PublicKey`.
+    pub fn add_friend(&self, friend_pk: PublicKey) {
+        self.state.lock().friends
+            .entry(friend_pk)
+            .or_insert_with(|| OnionFriend::new(friend_pk));
+    }
+
+    /// Stop searching the friend.
+    pub fn remove_friend(&self, friend_pk: PublicKey) {
+        let mut state = self.state.lock();
+        state.friends.remove(&friend_pk);
+        state.announce_requests.retain(|_, request| request.friend_pk != Some(friend_pk));
+    }
+
+    /// Set whether the friend is connected to us via `net_crypto`. We don't
+    /// search connected friends and don't send our DHT `PublicKey` to them.
+    pub fn set_friend_connected(&self, friend_pk: PublicKey, connected: bool) {
+        if let Some(friend) = self.state.lock().friends.get_mut(&friend_pk) {
+            if friend.connected && !connected {
+                // let the friend know our DHT PublicKey as soon as possible
+                friend.dht_pk_announce_time = None;
+            }
+            friend.connected = connected;
+        }
+    }
+
+    /// Check if we are announced at least at one onion node.
+    pub fn is_announced(&self) -> bool {
+        self.state.lock().announce_nodes.nodes.iter().any(|node| node.is_stored)
+    }
+
+    /// Check if we found an onion node the friend is announced at so we can
+    /// send data to it.
+    pub fn is_friend_found(&self, friend_pk: PublicKey) -> bool {
+        self.state.lock().friends.get(&friend_pk).map_or(false, |friend|
+            friend.data_pk.is_some() && friend.nodes.nodes.iter().any(|node| node.is_stored)
+        )
+    }
+
+    /// Send `OnionAnnounceRequest` to the node through the path with the
+    /// given id or through a random path. If `friend` is `None` we announce
+    /// ourselves, otherwise we search the friend.
+    fn send_announce_request(
+        &self,
+        state: &mut OnionClientState,
+        node: PackedNode,
+        path_id: Option<u64>,
+        friend: Option<(PublicKey, SecretKey)>,
+        ping_id: sha256::Digest
+    ) -> IoFuture<()> {
+        let path_index = match state.path_index(path_id) {
+            Some(path_index) => path_index,
+            None => return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                "No onion paths to send OnionAnnounceRequest"
+            ))),
+        };
+
+        let (request_pk, request_sk, search_pk, data_pk, friend_pk) = match friend {
+            // announce ourselves with our data PublicKey
+            None => (self.real_pk, self.real_sk.clone(), self.real_pk, self.data_pk, None),
+            // search the friend using temporary keys
+            Some((friend_pk, temporary_sk)) => {
+                let temporary_pk = state.friends[&friend_pk].temporary_pk;
+                (temporary_pk, temporary_sk, friend_pk, PublicKey([0; PUBLICKEYBYTES]), Some(friend_pk))
+            },
+        };
+
+        let path = &mut state.paths[path_index];
+        path.request_sent();
+        let path_id = path.id;
+
+        let sendback_data = random_u64();
+        let payload = OnionAnnounceRequestPayload {
+            ping_id,
+            search_pk,
+            data_pk,
+            sendback_data,
+        };
+        let inner = InnerOnionAnnounceRequest::new(&encrypt_precompute(&node.pk, &request_sk), &request_pk, &payload);
+        let request = path.create_udp_onion_request(node.saddr, InnerOnionRequest::InnerOnionAnnounceRequest(inner));
+        let first_node_saddr = path.nodes[0].saddr;
+
+        state.announce_requests.insert(sendback_data, AnnounceRequest {
+            friend_pk,
+            node,
+            path_id,
+            time: clock_now(),
+        });
+
+        send_to(&self.dht.tx, (DhtPacket::OnionRequest0(request), first_node_saddr))
+    }
+
+    /// Send `OnionAnnounceRequest`s to nodes that can be added to the list of
+    /// onion nodes.
+    fn send_requests_to_new_nodes(
+        &self,
+        state: &mut OnionClientState,
+        nodes: &[PackedNode],
+        friend: Option<(PublicKey, SecretKey)>
+    ) -> Vec<IoFuture<()>> {
+        let friend_pk = friend.as_ref().map(|&(friend_pk, _)| friend_pk);
+        let mut futures = Vec::new();
+        for node in nodes {
+            let can_add = match friend_pk {
+                None => state.announce_nodes.can_add(&node.pk),
+                Some(friend_pk) => state.friends[&friend_pk].nodes.can_add(&node.pk),
+            };
+            if can_add && !state.is_request_pending(friend_pk, &node.pk) {
+                futures.push(self.send_announce_request(state, *node, None, friend.clone(), initial_ping_id()));
+            }
+        }
+        futures
+    }
+
+    /// Handle `OnionAnnounceResponse` packet. The node that sent the response
+    /// is added to the list of onion nodes and requests are sent to returned
+    /// nodes that are closer than nodes from the list.
+    pub fn handle_announce_response(&self, packet: &OnionAnnounceResponse) -> IoFuture<()> {
+        let mut state = self.state.lock();
+
+        let request = match state.announce_requests.remove(&packet.sendback_data) {
+            Some(request) => request,
+            None => return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                "OnionAnnounceResponse with unknown sendback_data"
+            ))),
+        };
+        if request.is_timed_out() {
+            return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                "OnionAnnounceResponse is received too late"
+            )))
+        }
+
+        let friend = match request.friend_pk {
+            Some(friend_pk) => match state.friends.get(&friend_pk) {
+                Some(friend) => Some((friend_pk, friend.temporary_sk.clone())),
+                // the friend was removed
+                None => return Box::new(future::ok(())),
+            },
+            None => None,
+        };
+        let request_sk = friend.as_ref().map_or(&self.real_sk, |&(_, ref temporary_sk)| temporary_sk);
+        let payload = match packet.get_payload(&encrypt_precompute(&request.node.pk, request_sk)) {
+            Ok(payload) => payload,
+            Err(e) => return Box::new(future::err(e)),
+        };
+
+        if let Some(path) = state.paths.iter_mut().find(|path| path.id == request.path_id) {
+            path.response_received();
+        }
+
+        let mut node = OnionNode {
+            pk: request.node.pk,
+            saddr: request.node.saddr,
+            path_id: request.path_id,
+            ping_id: initial_ping_id(),
+            is_stored: false,
+            ping_time: request.time,
+            response_time: clock_now(),
+        };
+        match request.friend_pk {
+            None => {
+                node.is_stored = payload.announce_status == AnnounceStatus::Announced;
+                node.ping_id = payload.ping_id_or_pk;
+                state.announce_nodes.add(node);
+            },
+            Some(friend_pk) => {
+                let friend = state.friends.get_mut(&friend_pk).unwrap();
+                if payload.announce_status == AnnounceStatus::Found {
+                    node.is_stored = true;
+                    friend.data_pk = Some(digest_as_pk(payload.ping_id_or_pk));
+                }
+                friend.nodes.add(node);
+            },
+        }
+
+        let futures = self.send_requests_to_new_nodes(&mut state, &payload.nodes, friend);
+        Box::new(future::join_all(futures).map(|_| ()))
+    }
+
+    /// Send data to the friend through all onion nodes it's announced at.
+    fn send_data(&self, state: &OnionClientState, friend_pk: PublicKey, data: &OnionDataResponseInnerPayload) -> IoFuture<()> {
+        let friend = match state.friends.get(&friend_pk) {
+            Some(friend) => friend,
+            None => return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                format!("Friend {:?} is not added to onion client", friend_pk)
+            ))),
+        };
+        let data_pk = match friend.data_pk {
+            Some(data_pk) => data_pk,
+            None => return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                format!("Onion node where friend {:?} is announced is not found yet", friend_pk)
+            ))),
+        };
+
+        let nonce = gen_nonce();
+        let (temporary_pk, temporary_sk) = gen_keypair();
+        let payload = OnionDataResponsePayload::new(&encrypt_precompute(&friend_pk, &self.real_sk), self.real_pk, &nonce, data);
+        let inner = InnerOnionDataRequest::new(&encrypt_precompute(&data_pk, &temporary_sk), friend_pk, temporary_pk, nonce, &payload);
+
+        let futures = friend.nodes.nodes.iter()
+            .filter(|node| node.is_stored)
+            .flat_map(|node| state.path_index(Some(node.path_id)).map(|path_index| (node, path_index)))
+            .map(|(node, path_index)| {
+                let path = &state.paths[path_index];
+                let request = path.create_udp_onion_request(node.saddr, InnerOnionRequest::InnerOnionDataRequest(inner.clone()));
+                send_to(&self.dht.tx, (DhtPacket::OnionRequest0(request), path.nodes[0].saddr))
+            })
+            .collect::<Vec<_>>();
+
+        if futures.is_empty() {
+            return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                format!("Onion node where friend {:?} is announced is not found yet", friend_pk)
+            )))
+        }
+
+        Box::new(future::join_all(futures).map(|_| ()))
+    }
+
+    /// Send friend request to the friend via onion. The friend should be
+    /// added to onion client before and the onion node where the friend is
+    /// announced should be found.
+    pub fn send_friend_request(&self, friend_pk: PublicKey, nospam: NoSpam, message: Vec<u8>) -> IoFuture<()> {
+        let state = self.state.lock();
+        let data = OnionDataResponseInnerPayload::FriendRequest(FriendRequest::new(nospam, message));
+        self.send_data(&state, friend_pk, &data)
+    }
+
+    /// Send our DHT `PublicKey` with DHT nodes close to us to the friend via
+    /// onion.
+    fn send_dht_pk(&self, state: &OnionClientState, friend_pk: PublicKey) -> IoFuture<()> {
+        let nodes: Vec<PackedNode> = self.dht.get_closest(&self.dht.pk, false).into();
+        let data = OnionDataResponseInnerPayload::DhtPkAnnounce(DhtPkAnnouncePayload {
+            no_reply: unix_time(SystemTime::now()),
+            dht_pk: self.dht.pk,
+            nodes: nodes.into_iter().take(MAX_DHT_PK_ANNOUNCE_NODES).collect(),
+        });
+        self.send_data(state, friend_pk, &data)
+    }
+
+    /// Handle `OnionDataResponse` packet that contains data sent to us by
+    /// another node.
+    pub fn handle_data_response(&self, packet: &OnionDataResponse) -> IoFuture<()> {
+        let payload = match packet.get_payload(&encrypt_precompute(&packet.temporary_pk, &self.data_sk)) {
+            Ok(payload) => payload,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let inner = match payload.get_payload(&packet.nonce, &encrypt_precompute(&payload.real_pk, &self.real_sk)) {
+            Ok(inner) => inner,
+            Err(e) => return Box::new(future::err(e)),
+        };
+
+        match inner {
+            OnionDataResponseInnerPayload::FriendRequest(request) => {
+                if let Some(ref friend_request_tx) = self.friend_request_tx {
+                    let packet = FriendRequests::new(request.nospam, request.message);
+                    send_to(friend_request_tx, (payload.real_pk, packet))
+                } else {
+                    Box::new(future::ok(()))
+                }
+            },
+            OnionDataResponseInnerPayload::DhtPkAnnounce(announce) =>
+                self.handle_dht_pk_announce(payload.real_pk, announce),
+        }
+    }
+
+    /// Handle DHT `PublicKey` of the friend. DHT server is asked to search the
+    /// friend's DHT `PublicKey` at the nodes the friend sent us.
+    fn handle_dht_pk_announce(&self, friend_pk: PublicKey, announce: DhtPkAnnouncePayload) -> IoFuture<()> {
+        {
+            let mut state = self.state.lock();
+            let friend = match state.friends.get_mut(&friend_pk) {
+                Some(friend) => friend,
+                None => return Box::new(future::err(Error::new(
+                    ErrorKind::Other,
+                    format!("DhtPkAnnounce from unknown friend {:?}", friend_pk)
+                ))),
+            };
+            if announce.no_reply <= friend.last_no_reply {
+                return Box::new(future::err(Error::new(
+                    ErrorKind::Other,
+                    format!("Replayed DhtPkAnnounce from friend {:?}", friend_pk)
+                )))
+            }
+            friend.last_no_reply = announce.no_reply;
+        }
+
+        let mut futures = {
+            let mut request_queue = self.dht.request_queue.write();
+            announce.nodes.iter()
+                .map(|node| self.dht.send_nodes_req(node, &mut request_queue, announce.dht_pk))
+                .collect::<Vec<_>>()
+        };
+        if let Some(ref dht_pk_tx) = self.dht_pk_tx {
+            futures.push(send_to(dht_pk_tx, (friend_pk, announce.dht_pk)));
+        }
+
+        Box::new(future::join_all(futures).map(|_| ()))
+    }
+
+    /// Remove expired paths and create new paths from random DHT nodes.
+    fn refresh_paths(&self, state: &mut OnionClientState) {
+        state.paths.retain(|path| !path.is_expired());
+        while state.paths.len() < NUMBER_ONION_PATHS {
+            let nodes = self.dht.random_nodes(3);
+            if nodes.len() < 3 {
+                break
+            }
+            state.paths.push(OnionPath::new([nodes[0], nodes[1], nodes[2]]));
+        }
+    }
+
+    /// Send `OnionAnnounceRequest`s to announce ourselves.
+    fn announce(&self, state: &mut OnionClientState) -> Vec<IoFuture<()>> {
+        state.announce_nodes.remove_timed_out();
+
+        let mut futures = Vec::new();
+        let nodes = state.announce_nodes.nodes.clone();
+        for node in nodes {
+            let interval = if node.is_stored {
+                ONION_NODE_PING_INTERVAL
+            } else {
+                ANNOUNCE_INTERVAL_NOT_ANNOUNCED
+            };
+            if clock_elapsed(node.ping_time) >= Duration::from_secs(interval) &&
+                !state.is_request_pending(None, &node.pk) {
+                // ping id is valid only for the path we received it through
+                let ping_id = if state.paths.iter().any(|path| path.id == node.path_id) {
+                    node.ping_id
+                } else {
+                    initial_ping_id()
+                };
+                futures.push(self.send_announce_request(state, node.to_packed_node(), Some(node.path_id), None, ping_id));
+                if let Some(node) = state.announce_nodes.nodes.iter_mut().find(|n| n.pk == node.pk) {
+                    node.ping_time = clock_now();
+                }
+            }
+        }
+
+        if state.announce_nodes.should_search() {
+            state.announce_nodes.search_time = Some(clock_now());
+            let nodes: Vec<PackedNode> = self.dht.get_closest(&self.real_pk, false).into();
+            futures.extend(self.send_requests_to_new_nodes(state, &nodes, None));
+        }
+
+        futures
+    }
+
+    /// Send `OnionAnnounceRequest`s to search the friend and send our DHT
+    /// `PublicKey` to it if it's found.
+    fn search_friend(&self, state: &mut OnionClientState, friend_pk: PublicKey) -> Vec<IoFuture<()>> {
+        let (friend, nodes, should_search, should_announce_dht_pk) = {
+            let friend = state.friends.get_mut(&friend_pk).unwrap();
+            friend.nodes.remove_timed_out();
+            let should_search = friend.nodes.should_search();
+            if should_search {
+                friend.nodes.search_time = Some(clock_now());
+            }
+            let should_announce_dht_pk = friend.should_announce_dht_pk();
+            ((friend_pk, friend.temporary_sk.clone()), friend.nodes.nodes.clone(), should_search, should_announce_dht_pk)
+        };
+        let interval = if state.friends[&friend_pk].data_pk.is_some() {
+            ONION_NODE_PING_INTERVAL
+        } else {
+            ANNOUNCE_INTERVAL_NOT_ANNOUNCED
+        };
+
+        let mut futures = Vec::new();
+        for node in nodes {
+            if clock_elapsed(node.ping_time) >= Duration::from_secs(interval) &&
+                !state.is_request_pending(Some(friend_pk), &node.pk) {
+                futures.push(self.send_announce_request(state, node.to_packed_node(), Some(node.path_id), Some(friend.clone()), initial_ping_id()));
+                let friend = state.friends.get_mut(&friend_pk).unwrap();
+                if let Some(node) = friend.nodes.nodes.iter_mut().find(|n| n.pk == node.pk) {
+                    node.ping_time = clock_now();
+                }
+            }
+        }
+
+        if should_search {
+            let nodes: Vec<PackedNode> = self.dht.get_closest(&friend_pk, false).into();
+            futures.extend(self.send_requests_to_new_nodes(state, &nodes, Some(friend)));
+        }
+
+        if should_announce_dht_pk {
+            state.friends.get_mut(&friend_pk).unwrap().dht_pk_announce_time = Some(clock_now());
+            // errors are not fatal since the DHT PublicKey will be sent again
+            futures.push(Box::new(self.send_dht_pk(state, friend_pk).then(|_| Ok(()))));
+        }
+
+        futures
+    }
+
+    /// The main loop that refreshes onion paths, announces us and searches
+    /// friends that are not connected.
+    fn main_loop(&self) -> IoFuture<()> {
+        let mut state = self.state.lock();
+
+        state.announce_requests.retain(|_, request| !request.is_timed_out());
+        self.refresh_paths(&mut state);
+        if state.paths.is_empty() {
+            trace!("Not enough DHT nodes to create onion paths");
+            return Box::new(future::ok(()))
+        }
+
+        let mut futures = self.announce(&mut state);
+        let friends = state.friends.values()
+            .filter(|friend| !friend.connected)
+            .map(|friend| friend.real_pk)
+            .collect::<Vec<_>>();
+        for friend_pk in friends {
+            futures.extend(self.search_friend(&mut state, friend_pk));
+        }
+
+        Box::new(future::join_all(futures).map(|_| ()))
+    }
+
+    /// Run the main loop periodically.
+    pub fn run(self) -> IoFuture<()> {
+        let interval = Duration::from_millis(MAIN_LOOP_INTERVAL);
+        let wakeups = Interval::new(Instant::now(), interval);
+        let future = wakeups
+            .map_err(|e| Error::new(ErrorKind::Other, format!("Onion client timer error: {:?}", e)))
+            .for_each(move |_instant| {
+                trace!("Onion client wake up");
+                self.main_loop()
+            });
+
+        Box::new(future)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio_executor;
+    use tokio_timer::clock::*;
+
+    type UdpRx = mpsc::UnboundedReceiver<(DhtPacket, SocketAddr)>;
+
+    fn create_client() -> (OnionClient, UdpRx) {
+        crypto_init();
+        let (udp_tx, udp_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let dht = DhtServer::new(udp_tx, dht_pk, dht_sk);
+        let (real_pk, real_sk) = gen_keypair();
+        (OnionClient::new(dht, real_pk, real_sk), udp_rx)
+    }
+
+    /// Add nodes to close nodes list of DHT server and return their secret
+    /// keys by their addresses.
+    fn add_dht_nodes(client: &OnionClient, count: usize) -> HashMap<SocketAddr, (PublicKey, SecretKey)> {
+        (0 .. count).map(|i| {
+            let (pk, sk) = gen_keypair();
+            let saddr = format!("127.0.0.{}:12345", i + 1).parse().unwrap();
+            assert!(client.dht.close_nodes.write().try_add(&PackedNode::new(saddr, &pk)));
+            (saddr, (pk, sk))
+        }).collect()
+    }
+
+    /// Receive `OnionRequest0` and decrypt it as path nodes do. Returns the
+    /// inner request and the address of the destination node.
+    fn unpack_onion_request(udp_rx: UdpRx, nodes: &HashMap<SocketAddr, (PublicKey, SecretKey)>) -> (InnerOnionRequest, SocketAddr, UdpRx) {
+        let (received, udp_rx) = udp_rx.into_future().wait().unwrap();
+        let (packet, saddr) = received.unwrap();
+        let request_0 = unpack!(packet, DhtPacket::OnionRequest0);
+        let onion_return = OnionReturn {
+            nonce: secretbox::gen_nonce(),
+            payload: vec![42; 42]
+        };
+
+        let payload_0 = request_0.get_payload(&encrypt_precompute(&request_0.temporary_pk, &nodes[&saddr].1)).unwrap();
+        let saddr = payload_0.ip_port.to_saddr();
+        let request_1 = OnionRequest1 {
+            nonce: request_0.nonce,
+            temporary_pk: payload_0.temporary_pk,
+            payload: payload_0.inner,
+            onion_return: onion_return.clone()
+        };
+        let payload_1 = request_1.get_payload(&encrypt_precompute(&request_1.temporary_pk, &nodes[&saddr].1)).unwrap();
+        let saddr = payload_1.ip_port.to_saddr();
+        let request_2 = OnionRequest2 {
+            nonce: request_1.nonce,
+            temporary_pk: payload_1.temporary_pk,
+            payload: payload_1.inner,
+            onion_return
+        };
+        let payload_2 = request_2.get_payload(&encrypt_precompute(&request_2.temporary_pk, &nodes[&saddr].1)).unwrap();
+
+        (payload_2.inner, payload_2.ip_port.to_saddr(), udp_rx)
+    }
+
+    /// Skip all packets that were sent by the client.
+    fn skip_sent_packets(mut udp_rx: UdpRx, count: usize) -> UdpRx {
+        for _ in 0 .. count {
+            let (_received, rx) = udp_rx.into_future().wait().unwrap();
+            udp_rx = rx;
+        }
+        udp_rx
+    }
+
+    /// Pretend that the friend is found at one of the nodes we sent
+    /// `OnionAnnounceRequest` to.
+    fn find_friend(client: &OnionClient, friend_pk: PublicKey, friend_data_pk: PublicKey, nodes: &HashMap<SocketAddr, (PublicKey, SecretKey)>) {
+        let (sendback_data, request) = client.state.lock().announce_requests.iter()
+            .find(|&(_, request)| request.friend_pk == Some(friend_pk))
+            .map(|(&sendback_data, request)| (sendback_data, request.clone()))
+            .unwrap();
+        let temporary_pk = client.state.lock().friends[&friend_pk].temporary_pk;
+        let payload = OnionAnnounceResponsePayload {
+            announce_status: AnnounceStatus::Found,
+            ping_id_or_pk: pk_as_digest(friend_data_pk),
+            nodes: Vec::new()
+        };
+        let shared_secret = encrypt_precompute(&temporary_pk, &nodes[&request.node.saddr].1);
+        let packet = OnionAnnounceResponse::new(&shared_secret, sendback_data, &payload);
+        client.handle_announce_response(&packet).wait().unwrap();
+    }
+
+    #[test]
+    fn main_loop_without_dht_nodes() {
+        let (client, _udp_rx) = create_client();
+        client.add_friend(gen_keypair().0);
+
+        client.main_loop().wait().unwrap();
+
+        let state = client.state.lock();
+        assert!(state.paths.is_empty());
+        assert!(state.announce_requests.is_empty());
+    }
+
+    #[test]
+    fn main_loop_announces_us() {
+        let (client, udp_rx) = create_client();
+        let nodes = add_dht_nodes(&client, 5);
+
+        client.main_loop().wait().unwrap();
+
+        {
+            let state = client.state.lock();
+            assert_eq!(state.paths.len(), NUMBER_ONION_PATHS);
+            // requests are sent to 4 closest nodes
+            assert_eq!(state.announce_requests.len(), 4);
+            assert!(state.announce_requests.values().all(|request| request.friend_pk.is_none()));
+        }
+
+        let (inner, saddr, _udp_rx) = unpack_onion_request(udp_rx, &nodes);
+        let inner = unpack!(inner, InnerOnionRequest::InnerOnionAnnounceRequest);
+        assert_eq!(inner.pk, client.real_pk);
+        let payload = inner.get_payload(&encrypt_precompute(&inner.pk, &nodes[&saddr].1)).unwrap();
+        assert_eq!(payload.ping_id, initial_ping_id());
+        assert_eq!(payload.search_pk, client.real_pk);
+        assert_eq!(payload.data_pk, client.data_pk);
+        assert!(client.state.lock().announce_requests.contains_key(&payload.sendback_data));
+
+        // requests are not sent again before the response or the timeout
+        client.main_loop().wait().unwrap();
+        assert_eq!(client.state.lock().announce_requests.len(), 4);
+    }
+
+    #[test]
+    fn handle_announce_response_announced() {
+        let (client, _udp_rx) = create_client();
+        let nodes = add_dht_nodes(&client, 5);
+        client.main_loop().wait().unwrap();
+        assert!(!client.is_announced());
+
+        let (sendback_data, request) = client.state.lock().announce_requests.iter()
+            .map(|(&sendback_data, request)| (sendback_data, request.clone()))
+            .next()
+            .unwrap();
+        let ping_id = sha256::hash(&[1, 2, 3]);
+        let payload = OnionAnnounceResponsePayload {
+            announce_status: AnnounceStatus::Announced,
+            ping_id_or_pk: ping_id,
+            nodes: Vec::new()
+        };
+        let shared_secret = encrypt_precompute(&client.real_pk, &nodes[&request.node.saddr].1);
+        let packet = OnionAnnounceResponse::new(&shared_secret, sendback_data, &payload);
+        client.handle_announce_response(&packet).wait().unwrap();
+
+        assert!(client.is_announced());
+        {
+            let state = client.state.lock();
+            let node = &state.announce_nodes.nodes[0];
+            assert_eq!(node.pk, request.node.pk);
+            assert_eq!(node.ping_id, ping_id);
+            assert_eq!(node.path_id, request.path_id);
+        }
+
+        // the same response can't be handled twice
+        assert!(client.handle_announce_response(&packet).wait().is_err());
+    }
+
+    #[test]
+    fn handle_announce_response_sends_requests_to_returned_nodes() {
+        let (client, _udp_rx) = create_client();
+        let nodes = add_dht_nodes(&client, 5);
+        client.main_loop().wait().unwrap();
+
+        let (sendback_data, request) = client.state.lock().announce_requests.iter()
+            .map(|(&sendback_data, request)| (sendback_data, request.clone()))
+            .next()
+            .unwrap();
+        let new_node = PackedNode::new("127.0.0.42:12345".parse().unwrap(), &gen_keypair().0);
+        let payload = OnionAnnounceResponsePayload {
+            announce_status: AnnounceStatus::Failed,
+            ping_id_or_pk: sha256::hash(&[1, 2, 3]),
+            // the node that is already requested is ignored
+            nodes: vec![new_node, request.node]
+        };
+        let shared_secret = encrypt_precompute(&client.real_pk, &nodes[&request.node.saddr].1);
+        let packet = OnionAnnounceResponse::new(&shared_secret, sendback_data, &payload);
+        client.handle_announce_response(&packet).wait().unwrap();
+
+        let state = client.state.lock();
+        assert!(!state.announce_nodes.nodes[0].is_stored);
+        assert_eq!(state.announce_requests.len(), 4);
+        assert!(state.announce_requests.values().any(|request| request.node == new_node));
+    }
+
+    #[test]
+    fn handle_announce_response_invalid_key() {
+        let (client, _udp_rx) = create_client();
+        add_dht_nodes(&client, 5);
+        client.main_loop().wait().unwrap();
+
+        let sendback_data = *client.state.lock().announce_requests.keys().next().unwrap();
+        let payload = OnionAnnounceResponsePayload {
+            announce_status: AnnounceStatus::Announced,
+            ping_id_or_pk: sha256::hash(&[1, 2, 3]),
+            nodes: Vec::new()
+        };
+        let shared_secret = encrypt_precompute(&client.real_pk, &gen_keypair().1);
+        let packet = OnionAnnounceResponse::new(&shared_secret, sendback_data, &payload);
+        assert!(client.handle_announce_response(&packet).wait().is_err());
+        assert!(!client.is_announced());
+    }
+
+    #[test]
+    fn handle_announce_response_timed_out() {
+        let (client, _udp_rx) = create_client();
+        let nodes = add_dht_nodes(&client, 5);
+        client.main_loop().wait().unwrap();
+
+        let (sendback_data, request) = client.state.lock().announce_requests.iter()
+            .map(|(&sendback_data, request)| (sendback_data, request.clone()))
+            .next()
+            .unwrap();
+        let payload = OnionAnnounceResponsePayload {
+            announce_status: AnnounceStatus::Announced,
+            ping_id_or_pk: sha256::hash(&[1, 2, 3]),
+            nodes: Vec::new()
+        };
+        let shared_secret = encrypt_precompute(&client.real_pk, &nodes[&request.node.saddr].1);
+        let packet = OnionAnnounceResponse::new(&shared_secret, sendback_data, &payload);
+
+        let now = Instant::now() + Duration::from_secs(ANNOUNCE_REQUEST_TIMEOUT + 1);
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(now));
+
+        with_default(&clock, &mut enter, |_| {
+            assert!(client.handle_announce_response(&packet).wait().is_err());
+        });
+        assert!(!client.is_announced());
+    }
+
+    #[test]
+    fn search_friend_and_send_friend_request() {
+        let (alice, alice_udp_rx) = create_client();
+        let (mut bob, _bob_udp_rx) = create_client();
+        let (friend_request_tx, friend_request_rx) = mpsc::unbounded();
+        bob.set_friend_request_sink(friend_request_tx);
+        let nodes = add_dht_nodes(&alice, 5);
+
+        alice.add_friend(bob.real_pk);
+        assert!(alice.send_friend_request(bob.real_pk, NoSpam([42; 4]), vec![42; 123]).wait().is_err());
+
+        alice.main_loop().wait().unwrap();
+        let requests_count = alice.state.lock().announce_requests.len();
+        // we search the friend at 4 closest nodes as well as announce ourselves
+        assert_eq!(requests_count, 8);
+        let alice_udp_rx = skip_sent_packets(alice_udp_rx, requests_count);
+
+        find_friend(&alice, bob.real_pk, bob.data_pk, &nodes);
+        assert!(alice.is_friend_found(bob.real_pk));
+
+        alice.send_friend_request(bob.real_pk, NoSpam([42; 4]), vec![42; 123]).wait().unwrap();
+
+        let (inner, _saddr, _alice_udp_rx) = unpack_onion_request(alice_udp_rx, &nodes);
+        let inner = unpack!(inner, InnerOnionRequest::InnerOnionDataRequest);
+        assert_eq!(inner.destination_pk, bob.real_pk);
+        // onion node forwards the payload to the friend as is
+        let response = OnionDataResponse {
+            nonce: inner.nonce,
+            temporary_pk: inner.temporary_pk,
+            payload: inner.payload
+        };
+        bob.handle_data_response(&response).wait().unwrap();
+
+        let alice_pk = alice.real_pk;
+        drop(bob);
+        let requests = friend_request_rx.collect().wait().unwrap();
+        assert_eq!(requests, vec![(alice_pk, FriendRequests::new(NoSpam([42; 4]), vec![42; 123]))]);
+    }
+
+    #[test]
+    fn main_loop_sends_dht_pk_to_found_friend() {
+        let (alice, alice_udp_rx) = create_client();
+        let (mut bob, bob_udp_rx) = create_client();
+        let (dht_pk_tx, dht_pk_rx) = mpsc::unbounded();
+        bob.set_dht_pk_sink(dht_pk_tx);
+        let nodes = add_dht_nodes(&alice, 5);
+
+        alice.add_friend(bob.real_pk);
+        bob.add_friend(alice.real_pk);
+
+        alice.main_loop().wait().unwrap();
+        let alice_udp_rx = skip_sent_packets(alice_udp_rx, alice.state.lock().announce_requests.len());
+        find_friend(&alice, bob.real_pk, bob.data_pk, &nodes);
+
+        // requests are not sent again yet so the only packet is DhtPkAnnounce
+        alice.main_loop().wait().unwrap();
+        assert!(alice.state.lock().friends[&bob.real_pk].dht_pk_announce_time.is_some());
+
+        let (inner, _saddr, _alice_udp_rx) = unpack_onion_request(alice_udp_rx, &nodes);
+        let inner = unpack!(inner, InnerOnionRequest::InnerOnionDataRequest);
+        let response = OnionDataResponse {
+            nonce: inner.nonce,
+            temporary_pk: inner.temporary_pk,
+            payload: inner.payload
+        };
+        bob.handle_data_response(&response).wait().unwrap();
+        // replayed packet is dropped
+        assert!(bob.handle_data_response(&response).wait().is_err());
+
+        // bob searches alice's DHT PublicKey at nodes close to alice
+        let (received, _bob_udp_rx) = bob_udp_rx.into_future().wait().unwrap();
+        let (packet, saddr) = received.unwrap();
+        assert!(nodes.contains_key(&saddr));
+        let nodes_request = unpack!(packet, DhtPacket::NodesRequest);
+        let shared_secret = encrypt_precompute(&bob.dht.pk, &nodes[&saddr].1);
+        let nodes_request_payload = nodes_request.get_payload(&shared_secret).unwrap();
+        assert_eq!(nodes_request_payload.pk, alice.dht.pk);
+
+        let alice_pk = alice.real_pk;
+        let alice_dht_pk = alice.dht.pk;
+        drop(bob);
+        let dht_pks = dht_pk_rx.collect().wait().unwrap();
+        assert_eq!(dht_pks, vec![(alice_pk, alice_dht_pk)]);
+    }
+
+    #[test]
+    fn handle_dht_pk_from_unknown_friend() {
+        let (alice, alice_udp_rx) = create_client();
+        let (bob, _bob_udp_rx) = create_client();
+        let nodes = add_dht_nodes(&alice, 5);
+
+        alice.add_friend(bob.real_pk);
+
+        alice.main_loop().wait().unwrap();
+        let alice_udp_rx = skip_sent_packets(alice_udp_rx, alice.state.lock().announce_requests.len());
+        find_friend(&alice, bob.real_pk, bob.data_pk, &nodes);
+        alice.main_loop().wait().unwrap();
+
+        let (inner, _saddr, _alice_udp_rx) = unpack_onion_request(alice_udp_rx, &nodes);
+        let inner = unpack!(inner, InnerOnionRequest::InnerOnionDataRequest);
+        let response = OnionDataResponse {
+            nonce: inner.nonce,
+            temporary_pk: inner.temporary_pk,
+            payload: inner.payload
+        };
+        assert!(bob.handle_data_response(&response).wait().is_err());
+    }
+
+    #[test]
+    fn connected_friend_is_not_searched() {
+        let (client, _udp_rx) = create_client();
+        add_dht_nodes(&client, 5);
+        let friend_pk = gen_keypair().0;
+
+        client.add_friend(friend_pk);
+        client.set_friend_connected(friend_pk, true);
+        client.main_loop().wait().unwrap();
+        assert!(client.state.lock().announce_requests.values().all(|request| request.friend_pk.is_none()));
+
+        client.set_friend_connected(friend_pk, false);
+        client.main_loop().wait().unwrap();
+        assert!(client.state.lock().announce_requests.values().any(|request| request.friend_pk == Some(friend_pk)));
+
+        client.remove_friend(friend_pk);
+        assert!(client.state.lock().announce_requests.values().all(|request| request.friend_pk.is_none()));
+    }
+
+    #[test]
+    fn onion_nodes_keep_closest() {
+        let base_pk = gen_keypair().0;
+        let mut onion_nodes = OnionNodes::new(base_pk);
+        let create_node = |pk| OnionNode {
+            pk,
+            saddr: "127.0.0.1:12345".parse().unwrap(),
+            path_id: 0,
+            ping_id: initial_ping_id(),
+            is_stored: false,
+            ping_time: clock_now(),
+            response_time: clock_now(),
+        };
+
+        let mut pks = (0 .. MAX_ONION_NODES + 1).map(|_| gen_keypair().0).collect::<Vec<_>>();
+        pks.sort_by(|pk_1, pk_2| base_pk.distance(pk_1, pk_2));
+        let farthest_pk = pks.pop().unwrap();
+
+        for &pk in &pks {
+            assert!(onion_nodes.can_add(&pk));
+            onion_nodes.add(create_node(pk));
+        }
+        assert!(onion_nodes.is_full());
+        assert!(!onion_nodes.can_add(&pks[0]));
+        assert!(!onion_nodes.can_add(&farthest_pk));
+        onion_nodes.add(create_node(farthest_pk));
+        assert!(!onion_nodes.contains(&farthest_pk));
+        assert_eq!(onion_nodes.nodes.iter().map(|node| node.pk).collect::<Vec<_>>(), pks);
+    }
+}
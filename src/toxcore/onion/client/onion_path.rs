@@ -0,0 +1,246 @@
+/*! Onion path definition.
+*/
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use toxcore::binary_io::*;
+use toxcore::crypto_core::*;
+use toxcore::dht::packed_node::PackedNode;
+use toxcore::onion::packet::*;
+use toxcore::time::*;
+
+/// Onion path can be used for this number of seconds after its creation.
+/// After that a new path should be created to prevent tracking us by paths.
+pub const ONION_PATH_MAX_LIFETIME: u64 = 1200;
+
+/// Onion path is considered dead when this number of `OnionAnnounceRequest`
+/// packets were sent through it in a row without any response.
+pub const ONION_PATH_MAX_NO_RESPONSE_USES: u32 = 4;
+
+/// Node of onion path with temporary keys that are used to encrypt the onion
+/// layer for this node.
+#[derive(Clone)]
+pub struct OnionPathNode {
+    /// DHT `PublicKey` of the node.
+    pub public_key: PublicKey,
+    /// Address of the node.
+    pub saddr: SocketAddr,
+    /// Temporary `PublicKey` that is sent to the node along with the onion
+    /// layer encrypted for it.
+    pub temporary_pk: PublicKey,
+    /// `PrecomputedKey` of the node's DHT `PublicKey` and our temporary
+    /// `SecretKey`.
+    temporary_precomputed_key: PrecomputedKey,
+}
+
+impl OnionPathNode {
+    /// Create new `OnionPathNode` with random temporary keys.
+    pub fn new(node: PackedNode) -> OnionPathNode {
+        let (temporary_pk, temporary_sk) = gen_keypair();
+        OnionPathNode {
+            public_key: node.pk,
+            saddr: node.saddr,
+            temporary_pk,
+            temporary_precomputed_key: encrypt_precompute(&node.pk, &temporary_sk),
+        }
+    }
+}
+
+/** Onion path that consists of three random DHT nodes. Every onion request
+is encrypted three times so that each node of the path can decrypt only its
+own layer and knows only the previous and the next hop.
+*/
+#[derive(Clone)]
+pub struct OnionPath {
+    /// Random id of the path that is used to find the path the response was
+    /// received through.
+    pub id: u64,
+    /// Nodes of the path.
+    pub nodes: [OnionPathNode; 3],
+    /// Time when the path was created.
+    creation_time: Instant,
+    /// Number of `OnionAnnounceRequest` packets sent through the path since
+    /// the last received response.
+    requests_without_response: u32,
+}
+
+impl OnionPath {
+    /// Create new `OnionPath` from three DHT nodes.
+    pub fn new(nodes: [PackedNode; 3]) -> OnionPath {
+        OnionPath {
+            id: random_u64(),
+            nodes: [
+                OnionPathNode::new(nodes[0]),
+                OnionPathNode::new(nodes[1]),
+                OnionPathNode::new(nodes[2]),
+            ],
+            creation_time: clock_now(),
+            requests_without_response: 0,
+        }
+    }
+
+    /// Check if the node with this DHT `PublicKey` belongs to the path.
+    pub fn contains(&self, pk: &PublicKey) -> bool {
+        self.nodes.iter().any(|node| node.public_key == *pk)
+    }
+
+    /// Check if the path is too old or doesn't respond so it should be
+    /// replaced with a new one.
+    pub fn is_expired(&self) -> bool {
+        clock_elapsed(self.creation_time) >= Duration::from_secs(ONION_PATH_MAX_LIFETIME) ||
+            self.requests_without_response >= ONION_PATH_MAX_NO_RESPONSE_USES
+    }
+
+    /// Remember that a request that expects a response was sent through the
+    /// path.
+    pub fn request_sent(&mut self) {
+        self.requests_without_response += 1;
+    }
+
+    /// Remember that a response was received through the path.
+    pub fn response_received(&mut self) {
+        self.requests_without_response = 0;
+    }
+
+    /// Encrypt the inner request for every node of the path and create
+    /// `OnionRequest0` packet that should be sent to the first node via UDP.
+    /// All layers are encrypted with the same nonce since nodes pass it as is
+    /// to the next hop.
+    pub fn create_udp_onion_request(&self, destination: SocketAddr, inner: InnerOnionRequest) -> OnionRequest0 {
+        let nonce = gen_nonce();
+        let mut buf = [0; ONION_MAX_PACKET_SIZE];
+
+        let payload = OnionRequest2Payload {
+            ip_port: IpPort::from_udp_saddr(destination),
+            inner,
+        };
+        let (_, size) = payload.to_bytes((&mut buf, 0)).unwrap();
+        let encrypted = seal_precomputed(&buf[..size], &nonce, &self.nodes[2].temporary_precomputed_key);
+
+        let payload = OnionRequest1Payload {
+            ip_port: IpPort::from_udp_saddr(self.nodes[2].saddr),
+            temporary_pk: self.nodes[2].temporary_pk,
+            inner: encrypted,
+        };
+        let (_, size) = payload.to_bytes((&mut buf, 0)).unwrap();
+        let encrypted = seal_precomputed(&buf[..size], &nonce, &self.nodes[1].temporary_precomputed_key);
+
+        let payload = OnionRequest0Payload {
+            ip_port: IpPort::from_udp_saddr(self.nodes[1].saddr),
+            temporary_pk: self.nodes[1].temporary_pk,
+            inner: encrypted,
+        };
+        let (_, size) = payload.to_bytes((&mut buf, 0)).unwrap();
+        let encrypted = seal_precomputed(&buf[..size], &nonce, &self.nodes[0].temporary_precomputed_key);
+
+        OnionRequest0 {
+            nonce,
+            temporary_pk: self.nodes[0].temporary_pk,
+            payload: encrypted,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio_executor;
+    use tokio_timer::clock::*;
+
+    fn create_path() -> (OnionPath, [(PackedNode, SecretKey); 3]) {
+        let create_node = |addr: &str| {
+            let (pk, sk) = gen_keypair();
+            (PackedNode::new(addr.parse().unwrap(), &pk), sk)
+        };
+        let nodes = [
+            create_node("1.2.3.4:12345"),
+            create_node("1.2.3.5:12345"),
+            create_node("1.2.3.6:12345"),
+        ];
+        let path = OnionPath::new([nodes[0].0, nodes[1].0, nodes[2].0]);
+        (path, nodes)
+    }
+
+    #[test]
+    fn create_udp_onion_request() {
+        let (path, nodes) = create_path();
+        let destination = "1.2.3.7:12345".parse().unwrap();
+        let inner = InnerOnionRequest::InnerOnionAnnounceRequest(InnerOnionAnnounceRequest {
+            nonce: gen_nonce(),
+            pk: gen_keypair().0,
+            payload: vec![42; 123]
+        });
+        let onion_return = OnionReturn {
+            nonce: secretbox::gen_nonce(),
+            payload: vec![42; 42]
+        };
+
+        let request_0 = path.create_udp_onion_request(destination, inner.clone());
+        let payload_0 = request_0.get_payload(&encrypt_precompute(&request_0.temporary_pk, &nodes[0].1)).unwrap();
+        assert_eq!(payload_0.ip_port.to_saddr(), nodes[1].0.saddr);
+
+        let request_1 = OnionRequest1 {
+            nonce: request_0.nonce,
+            temporary_pk: payload_0.temporary_pk,
+            payload: payload_0.inner,
+            onion_return: onion_return.clone()
+        };
+        let payload_1 = request_1.get_payload(&encrypt_precompute(&request_1.temporary_pk, &nodes[1].1)).unwrap();
+        assert_eq!(payload_1.ip_port.to_saddr(), nodes[2].0.saddr);
+
+        let request_2 = OnionRequest2 {
+            nonce: request_1.nonce,
+            temporary_pk: payload_1.temporary_pk,
+            payload: payload_1.inner,
+            onion_return
+        };
+        let payload_2 = request_2.get_payload(&encrypt_precompute(&request_2.temporary_pk, &nodes[2].1)).unwrap();
+        assert_eq!(payload_2.ip_port.to_saddr(), destination);
+        assert_eq!(payload_2.inner, inner);
+    }
+
+    #[test]
+    fn path_contains() {
+        let (path, nodes) = create_path();
+        for &(node, _) in &nodes {
+            assert!(path.contains(&node.pk));
+        }
+        assert!(!path.contains(&gen_keypair().0));
+    }
+
+    #[test]
+    fn path_expires_without_responses() {
+        let (mut path, _nodes) = create_path();
+
+        for _ in 0 .. ONION_PATH_MAX_NO_RESPONSE_USES - 1 {
+            path.request_sent();
+        }
+        assert!(!path.is_expired());
+
+        path.response_received();
+        for _ in 0 .. ONION_PATH_MAX_NO_RESPONSE_USES - 1 {
+            path.request_sent();
+        }
+        assert!(!path.is_expired());
+
+        path.request_sent();
+        assert!(path.is_expired());
+    }
+
+    #[test]
+    fn path_expires_after_lifetime() {
+        let (path, _nodes) = create_path();
+        assert!(!path.is_expired());
+
+        let now = Instant::now() + Duration::from_secs(ONION_PATH_MAX_LIFETIME + 1);
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(now));
+
+        with_default(&clock, &mut enter, |_| {
+            assert!(path.is_expired());
+        });
+    }
+}
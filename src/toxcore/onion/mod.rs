@@ -39,7 +39,11 @@ packet to another destination node by its long term public key. That means that
 when onion node should store long term public keys of announced node along with
 onion return addresses.
 
+Onion client uses onion paths to announce our long term public key and to find
+friends by their long term public keys.
+
 */
 
+pub mod client;
 pub mod onion_announce;
 pub mod packet;
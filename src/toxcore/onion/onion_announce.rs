@@ -1,21 +1,28 @@
 /*! The implementation of onion announce
 */
 
+use std::collections::BTreeMap;
+use std::fmt;
 use std::io::{ErrorKind, Error};
 use std::net::{IpAddr, SocketAddr};
 use std::time::{Duration, Instant, SystemTime};
 
+use lru::LruCache;
+use nom::{be_u16, be_u64, le_u8};
+
 use toxcore::binary_io::*;
 use toxcore::crypto_core::*;
 use toxcore::time::*;
 use toxcore::onion::packet::*;
-use toxcore::dht::kbucket::Distance;
+use toxcore::dht::packed_node::PackedNode;
+use toxcore::utils::{RateLimit, TokenBucket};
 
 /// Number of secret random bytes to make onion ping id unique for each node.
 pub const SECRET_BYTES_SIZE: usize = 32;
 
-/// Maximum number of entries in onion announce list. When number of entries
-/// exceeds this value farthest nodes are dropped using DHT distance function.
+/// Default maximum number of entries in onion announce list. When number of
+/// entries exceeds this value farthest nodes are dropped using DHT distance
+/// function. Can be changed with `OnionAnnounce::set_max_entries`.
 pub const ONION_ANNOUNCE_MAX_ENTRIES: usize = 160;
 
 /// Number of seconds when onion ping id is valid after it was generated.
@@ -27,6 +34,10 @@ pub const PING_ID_TIMEOUT: u64 = 300;
 /// without re-announcing.
 pub const ONION_ANNOUNCE_TIMEOUT: u64 = 300;
 
+/// Maximum number of source IP addresses for which rate limiters of
+/// `OnionDataRequest`s are kept.
+pub const ONION_DATA_MAX_SOURCES: usize = 4096;
+
 /// Create onion ping id filled with zeros.
 pub fn initial_ping_id() -> sha256::Digest {
     // can not fail since slice has enough length
@@ -52,7 +63,13 @@ struct OnionAnnounceEntry {
     /// PublicKey that should be used to encrypt data packets for announced node
     pub data_pk: PublicKey,
     /// Time when this entry was added to the list of announced nodes
-    pub time: Instant
+    pub time: Instant,
+    /// Number of `OnionDataRequest`s forwarded to this node
+    pub forwards: u64,
+    /// Number of `OnionDataRequest`s to this node rejected by limits
+    pub rejected_forwards: u64,
+    /// Rate limiter of `OnionDataRequest`s forwarded to this node
+    pub data_bucket: Option<TokenBucket>,
 }
 
 impl OnionAnnounceEntry {
@@ -64,7 +81,10 @@ impl OnionAnnounceEntry {
             port,
             onion_return,
             data_pk,
-            time: clock_now()
+            time: clock_now(),
+            forwards: 0,
+            rejected_forwards: 0,
+            data_bucket: None,
         }
     }
 
@@ -148,14 +168,226 @@ impl OnionPingData {
     }
 }
 
+/** Limits of `OnionDataRequest`s forwarded to announced nodes. `None` means
+that there is no limit. By default nothing is limited.
+
+Requests that exceed limits are dropped so announced nodes can't be flooded
+with e.g. spam friend requests through our node.
+*/
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct OnionDataLimits {
+    /// Rate limit of requests forwarded to each announced node
+    pub per_destination: Option<RateLimit>,
+    /// Rate limit of requests received from each IP address
+    pub per_source_ip: Option<RateLimit>,
+}
+
+/// Counters of handled `OnionDataRequest`s
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct OnionDataStats {
+    /// Number of forwarded requests
+    pub forwarded: u64,
+    /// Number of requests dropped because the destination is not announced
+    pub unknown_destination: u64,
+    /// Number of requests dropped because of `per_destination` limit
+    pub destination_rate: u64,
+    /// Number of requests dropped because of `per_source_ip` limit
+    pub source_ip_rate: u64,
+}
+
+/// Information about announced onion node
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionAnnounceEntryInfo {
+    /// Long term `PublicKey` of announced node
+    pub pk: PublicKey,
+    /// Address of announced node
+    pub saddr: SocketAddr,
+    /// `PublicKey` that should be used to encrypt data packets for announced
+    /// node
+    pub data_pk: PublicKey,
+    /// Time elapsed since the node announced itself last time
+    pub age: Duration,
+    /// Number of `OnionDataRequest`s forwarded to this node
+    pub forwards: u64,
+    /// Number of `OnionDataRequest`s to this node rejected by limits
+    pub rejected_forwards: u64,
+}
+
+/** Rate limiters of `OnionDataRequest`s per source IP address together with
+the time they were used last time.
+
+The number of tracked addresses is bounded by `ONION_DATA_MAX_SOURCES` so that
+requests from many addresses can't exhaust memory. The least recently used
+limiter is dropped first.
+*/
+struct SourceBuckets(LruCache<IpAddr, (TokenBucket, Instant)>);
+
+impl SourceBuckets {
+    fn new() -> SourceBuckets {
+        SourceBuckets(LruCache::new(ONION_DATA_MAX_SOURCES))
+    }
+
+    /// Remove limiters that weren't used for the given time. They are full
+    /// anyway so nothing is lost.
+    fn remove_idle(&mut self, timeout: Duration) {
+        while self.0.peek_lru().map_or(false, |(_, &(_, last_used))| clock_elapsed(last_used) >= timeout) {
+            self.0.pop_lru();
+        }
+    }
+}
+
+impl Clone for SourceBuckets {
+    fn clone(&self) -> SourceBuckets {
+        let mut buckets = LruCache::new(self.0.cap());
+        // insert from the least recently used limiter to keep the order
+        for (ip, bucket) in self.0.iter().rev() {
+            buckets.put(*ip, bucket.clone());
+        }
+        SourceBuckets(buckets)
+    }
+}
+
+impl fmt::Debug for SourceBuckets {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.0.iter()).finish()
+    }
+}
+
+/** Announced onion node stored in `OnionAnnounceSnapshot`.
+
+Serialized form:
+
+Length   | Content
+-------- | ------
+`[39, 51]` | `PackedNode` with `PublicKey` and address of announced node
+`32`     | `PublicKey` that should be used to encrypt data packets
+`8`      | Unix time in seconds when the node was announced
+`2`      | Length of `OnionReturn`
+variable | `OnionReturn`
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionAnnounceSnapshotEntry {
+    /// Long term `PublicKey` of announced node
+    pub pk: PublicKey,
+    /// Address of announced node
+    pub saddr: SocketAddr,
+    /// Onion return that should be used to send data packets to announced node
+    pub onion_return: OnionReturn,
+    /// `PublicKey` that should be used to encrypt data packets for announced
+    /// node
+    pub data_pk: PublicKey,
+    /// Unix time in seconds when the node was announced
+    pub time: u64,
+}
+
+impl FromBytes for OnionAnnounceSnapshotEntry {
+    named!(from_bytes<OnionAnnounceSnapshotEntry>, do_parse!(
+        node: call!(PackedNode::from_bytes) >>
+        data_pk: call!(PublicKey::from_bytes) >>
+        time: be_u64 >>
+        onion_return: length_value!(be_u16, OnionReturn::from_bytes) >>
+        (OnionAnnounceSnapshotEntry {
+            pk: node.pk,
+            saddr: node.saddr,
+            onion_return,
+            data_pk,
+            time,
+        })
+    ));
+}
+
+impl ToBytes for OnionAnnounceSnapshotEntry {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_call!(|buf, node| PackedNode::to_bytes(node, buf), &PackedNode::new(self.saddr, &self.pk)) >>
+            gen_slice!(self.data_pk.as_ref()) >>
+            gen_be_u64!(self.time) >>
+            gen_be_u16!((secretbox::NONCEBYTES + self.onion_return.payload.len()) as u16) >>
+            gen_call!(|buf, onion_return| OnionReturn::to_bytes(onion_return, buf), &self.onion_return)
+        )
+    }
+}
+
+impl OnionAnnounceSnapshotEntry {
+    /// Maximum size of serialized entry without `OnionReturn` payload.
+    const BASE_SIZE: usize = 1 + 16 + 2 + PUBLICKEYBYTES + PUBLICKEYBYTES + 8 + 2 + secretbox::NONCEBYTES;
+}
+
+/** Snapshot of `OnionAnnounce` state that can be persisted to restore
+announced nodes after restart.
+
+Serialized form:
+
+Length   | Content
+-------- | ------
+`32`     | Secret bytes of onion node
+variable | Announced nodes as `OnionAnnounceSnapshotEntry`s
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionAnnounceSnapshot {
+    /// Secret bytes of onion node to make onion ping id unique
+    pub secret_bytes: [u8; SECRET_BYTES_SIZE],
+    /// Announced nodes
+    pub entries: Vec<OnionAnnounceSnapshotEntry>,
+}
+
+impl FromBytes for OnionAnnounceSnapshot {
+    named!(from_bytes<OnionAnnounceSnapshot>, do_parse!(
+        secret_bytes: count_fixed!(u8, le_u8, SECRET_BYTES_SIZE) >>
+        entries: many0!(OnionAnnounceSnapshotEntry::from_bytes) >>
+        eof!() >>
+        (OnionAnnounceSnapshot {
+            secret_bytes,
+            entries,
+        })
+    ));
+}
+
+impl ToBytes for OnionAnnounceSnapshot {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_slice!(&self.secret_bytes) >>
+            gen_many_ref!(&self.entries, |buf, entry| OnionAnnounceSnapshotEntry::to_bytes(entry, buf))
+        )
+    }
+}
+
+impl OnionAnnounceSnapshot {
+    /// Serialize snapshot to a new buffer of sufficient size.
+    pub fn to_vec(&self) -> Vec<u8> {
+        let size = SECRET_BYTES_SIZE + self.entries.iter()
+            .map(|entry| OnionAnnounceSnapshotEntry::BASE_SIZE + entry.onion_return.payload.len())
+            .sum::<usize>();
+        let mut buf = vec![0; size];
+        // can not fail since buf has enough length
+        let (_, len) = self.to_bytes((&mut buf, 0)).unwrap();
+        buf.truncate(len);
+        buf
+    }
+}
+
 /** Holds list of announced onion nodes and process announce requests.
+
+Entries are indexed by their distance to our DHT `PublicKey` so that lookups,
+insertions and dropping the farthest entry take logarithmic time even with
+large capacity.
 */
 #[derive(Clone, Debug)]
 pub struct OnionAnnounce {
     /// Secret bytes of onion node to make onion ping id unique
     secret_bytes: [u8; SECRET_BYTES_SIZE],
-    /// List of announced onion nodes
-    entries: Vec<OnionAnnounceEntry>,
+    /// Announced onion nodes indexed by distance to DHT `PublicKey`
+    entries: BTreeMap<[u8; PUBLICKEYBYTES], OnionAnnounceEntry>,
+    /// Maximum number of entries in announce list
+    max_entries: usize,
+    /// Limits of forwarded `OnionDataRequest`s
+    data_limits: OnionDataLimits,
+    /// Rate limiters of `OnionDataRequest`s per source IP address
+    source_buckets: SourceBuckets,
+    /// Counters of handled `OnionDataRequest`s
+    data_stats: OnionDataStats,
     /// Short term DHT `PublicKey`
     dht_pk: PublicKey
 }
@@ -167,11 +399,105 @@ impl OnionAnnounce {
         randombytes_into(&mut secret_bytes);
         OnionAnnounce {
             secret_bytes,
-            entries: Vec::with_capacity(ONION_ANNOUNCE_MAX_ENTRIES),
+            entries: BTreeMap::new(),
+            max_entries: ONION_ANNOUNCE_MAX_ENTRIES,
+            data_limits: OnionDataLimits::default(),
+            source_buckets: SourceBuckets::new(),
+            data_stats: OnionDataStats::default(),
             dht_pk
         }
     }
 
+    /// Set limits of forwarded `OnionDataRequest`s. Rate limiters are reset.
+    pub fn set_data_limits(&mut self, data_limits: OnionDataLimits) {
+        self.data_limits = data_limits;
+        self.source_buckets.0.clear();
+        for entry in self.entries.values_mut() {
+            entry.data_bucket = data_limits.per_destination.map(TokenBucket::new);
+        }
+    }
+
+    /// Limits of forwarded `OnionDataRequest`s.
+    pub fn data_limits(&self) -> OnionDataLimits {
+        self.data_limits
+    }
+
+    /// Counters of handled `OnionDataRequest`s.
+    pub fn data_stats(&self) -> OnionDataStats {
+        self.data_stats
+    }
+
+    /// Information about all announced nodes that are not timed out sorted
+    /// by distance to DHT `PublicKey`.
+    pub fn entries_info(&self) -> Vec<OnionAnnounceEntryInfo> {
+        self.entries.values()
+            .filter(|entry| !entry.is_timed_out())
+            .map(|entry| OnionAnnounceEntryInfo {
+                pk: entry.pk,
+                saddr: SocketAddr::new(entry.ip_addr, entry.port),
+                data_pk: entry.data_pk,
+                age: clock_elapsed(entry.time),
+                forwards: entry.forwards,
+                rejected_forwards: entry.rejected_forwards,
+            })
+            .collect()
+    }
+
+    /// Set maximum number of entries in announce list. If the list contains
+    /// more entries the farthest ones are dropped.
+    pub fn set_max_entries(&mut self, max_entries: usize) {
+        self.max_entries = max_entries;
+        while self.entries.len() > max_entries {
+            self.remove_farthest();
+        }
+    }
+
+    /// Maximum number of entries in announce list.
+    pub fn max_entries(&self) -> usize {
+        self.max_entries
+    }
+
+    /// Number of entries in announce list including timed out ones that are
+    /// not removed yet.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Check if announce list is empty.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Key of entry with given `PublicKey` in the index. It's the XOR of the
+    /// `PublicKey` and DHT `PublicKey` so entries are sorted by distance.
+    fn distance_key(&self, pk: &PublicKey) -> [u8; PUBLICKEYBYTES] {
+        let mut key = [0; PUBLICKEYBYTES];
+        for (k, (a, b)) in key.iter_mut().zip(self.dht_pk.as_ref().iter().zip(pk.as_ref().iter())) {
+            *k = a ^ b;
+        }
+        key
+    }
+
+    /// Remove the farthest entry from DHT `PublicKey`.
+    fn remove_farthest(&mut self) {
+        let farthest = self.entries.keys().next_back().cloned();
+        if let Some(key) = farthest {
+            self.entries.remove(&key);
+        }
+    }
+
+    /// Remove all timed out entries. Should be called periodically.
+    pub fn remove_timed_out(&mut self) {
+        let timed_out = self.entries.iter()
+            .filter(|&(_, entry)| entry.is_timed_out())
+            .map(|(&key, _)| key)
+            .collect::<Vec<_>>();
+        for key in timed_out {
+            self.entries.remove(&key);
+        }
+        self.source_buckets.remove_idle(Duration::from_secs(ONION_ANNOUNCE_TIMEOUT));
+    }
+
     /** Calculate onion ping id using sha256 hash of arguments together with
     secret bytes stored in this struct.
 
@@ -192,51 +518,96 @@ impl OnionAnnounce {
 
     /// Find entry by its `PublicKey` ignoring timed out entries
     fn find_in_entries(&self, pk: PublicKey) -> Option<&OnionAnnounceEntry> {
-        match self.entries.binary_search_by(|e| self.dht_pk.distance(&e.pk, &pk)) {
-            //TODO: use Option::filter when it's stabilized
-            Ok(idx) => if self.entries[idx].is_timed_out() { None } else { self.entries.get(idx) },
-            Err(_) => None
-        }
+        self.entries.get(&self.distance_key(&pk))
+            .and_then(|entry| if entry.is_timed_out() { None } else { Some(entry) })
     }
 
     /** Try to add announce entry to onion announce list.
 
-    Firstly we remove all timed out entries. Then if:
+    If:
     - announce list already contains entry with such `PublicKey` then update
       entry and return it
-    - announce list with new entry does not exceed `ONION_ANNOUNCE_MAX_ENTRIES`
-      length add entry to the list and return it
+    - announce list with new entry does not exceed `max_entries` length add
+      entry to the list and return it
     - the farthest entry from DHT `PublicKey` is farther than new entry then
       replace it with new entry
 
-    Also we keep onion announce list sorted by distance to DHT `PublicKey` so
-    we can easily find the farthest entry.
+    Timed out entries are removed by `remove_timed_out` periodically, but
+    when the list is full they are removed before replacing the farthest
+    entry.
 
     */
-    fn add_to_entries(&mut self, entry: OnionAnnounceEntry) -> Option<&OnionAnnounceEntry> {
-        //TODO: remove timed out entries by timer?
-        self.entries.retain(|e| !e.is_timed_out());
-        match self.entries.binary_search_by(|e| self.dht_pk.distance(&e.pk, &entry.pk)) {
-            Ok(idx) => {
-                // node with such pk already announced - just update the entry
-                self.entries[idx].clone_from(&entry);
-                self.entries.get(idx)
-            },
-            Err(idx) => {
-                if self.entries.len() < ONION_ANNOUNCE_MAX_ENTRIES {
-                    // adding new entry does not exceed the limit - just add it
-                    self.entries.insert(idx, entry);
-                    self.entries.get(idx)
-                } else if idx < ONION_ANNOUNCE_MAX_ENTRIES {
+    fn add_to_entries(&mut self, mut entry: OnionAnnounceEntry) -> Option<&OnionAnnounceEntry> {
+        let key = self.distance_key(&entry.pk);
+        if let Some(old_entry) = self.entries.get(&key) {
+            // keep forwarding counters when node re-announces itself
+            entry.forwards = old_entry.forwards;
+            entry.rejected_forwards = old_entry.rejected_forwards;
+            entry.data_bucket = old_entry.data_bucket.clone();
+        } else {
+            entry.data_bucket = self.data_limits.per_destination.map(TokenBucket::new);
+        }
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.max_entries {
+            self.remove_timed_out();
+            if self.entries.len() >= self.max_entries {
+                match self.entries.keys().next_back() {
                     // the farthest entry is farther than new entry - replace it
-                    self.entries.pop();
-                    self.entries.insert(idx, entry);
-                    self.entries.get(idx)
-                } else {
-                    None
+                    Some(farthest) if *farthest > key => self.remove_farthest(),
+                    _ => return None,
                 }
             }
         }
+        // node with such pk can be already announced - just update the entry
+        self.entries.insert(key, entry);
+        self.entries.get(&key)
+    }
+
+    /** Create snapshot of announce list and secret bytes that can be
+    restored with `restore` after restart. Timed out entries are skipped.
+    */
+    pub fn snapshot(&self) -> OnionAnnounceSnapshot {
+        let now = unix_time(SystemTime::now());
+        let entries = self.entries.values()
+            .filter(|entry| !entry.is_timed_out())
+            .map(|entry| OnionAnnounceSnapshotEntry {
+                pk: entry.pk,
+                saddr: SocketAddr::new(entry.ip_addr, entry.port),
+                onion_return: entry.onion_return.clone(),
+                data_pk: entry.data_pk,
+                time: now.saturating_sub(clock_elapsed(entry.time).as_secs()),
+            })
+            .collect();
+        OnionAnnounceSnapshot {
+            secret_bytes: self.secret_bytes,
+            entries,
+        }
+    }
+
+    /** Restore secret bytes and announce list from snapshot. Entries that
+    timed out while the node was down are skipped. Ping ids generated before
+    the snapshot was made remain valid.
+    */
+    pub fn restore(&mut self, snapshot: &OnionAnnounceSnapshot) {
+        self.secret_bytes = snapshot.secret_bytes;
+        let now = unix_time(SystemTime::now());
+        for entry in &snapshot.entries {
+            let age = Duration::from_secs(now.saturating_sub(entry.time));
+            if age >= Duration::from_secs(ONION_ANNOUNCE_TIMEOUT) {
+                continue
+            }
+            let time = clock_now().checked_sub(age).unwrap_or_else(clock_now);
+            self.add_to_entries(OnionAnnounceEntry {
+                pk: entry.pk,
+                ip_addr: entry.saddr.ip(),
+                port: entry.saddr.port(),
+                onion_return: entry.onion_return.clone(),
+                data_pk: entry.data_pk,
+                time,
+                forwards: 0,
+                rejected_forwards: 0,
+                data_bucket: None,
+            });
+        }
     }
 
     /** Handle payload `OnionAnnounceRequest` packet and return `AnnounceStatus`
@@ -309,28 +680,78 @@ impl OnionAnnounce {
 
     When onion node handles `OnionDataRequest` it checks if onion entry list
     contains destination node and when entry exists sends `OnionDataResponse`
-    to this node through its onion path.
+    to this node through its onion path. Requests that exceed
+    `OnionDataLimits` for the destination or for the source IP address `addr`
+    are rejected.
 
     */
-    pub fn handle_data_request(&self, request: OnionDataRequest) -> Result<(OnionResponse3, SocketAddr), Error> {
-        if let Some(entry) = self.find_in_entries(request.inner.destination_pk) {
-            let response_payload = OnionDataResponse {
-                nonce: request.inner.nonce,
-                temporary_pk: request.inner.temporary_pk,
-                payload: request.inner.payload
-            };
-            let response = OnionResponse3 {
-                onion_return: entry.onion_return.clone(),
-                payload: InnerOnionResponse::OnionDataResponse(response_payload)
-            };
-            let saddr = SocketAddr::new(entry.ip_addr, entry.port);
-            Ok((response, saddr))
-        } else {
-            Err(Error::new(
+    pub fn handle_data_request(&mut self, request: OnionDataRequest, addr: SocketAddr) -> Result<(OnionResponse3, SocketAddr), Error> {
+        let key = self.distance_key(&request.inner.destination_pk);
+        let entry = match self.entries.get_mut(&key) {
+            Some(ref entry) if entry.is_timed_out() => None,
+            entry => entry,
+        };
+        let entry = match entry {
+            Some(entry) => entry,
+            None => {
+                self.data_stats.unknown_destination += 1;
+                return Err(Error::new(
+                    ErrorKind::Other,
+                    format!("No announced node with public key {:?}", request.inner.destination_pk)
+                ))
+            },
+        };
+
+        // a token is taken from each limiter only when the request passes
+        // all of them so that rejected requests don't affect other limits
+        let ip = addr.ip();
+        let source_buckets = &mut self.source_buckets;
+        let mut source_bucket = self.data_limits.per_source_ip.map(|limit|
+            source_buckets.0.pop(&ip)
+                .map_or_else(|| TokenBucket::new(limit), |(bucket, _last_used)| bucket)
+        );
+        let source_allowed = source_bucket.as_mut().map_or(true, |bucket| bucket.can_take(1));
+        let destination_allowed = source_allowed &&
+            entry.data_bucket.as_mut().map_or(true, |bucket| bucket.try_take(1));
+        if let Some(mut bucket) = source_bucket {
+            if destination_allowed {
+                bucket.try_take(1);
+            }
+            source_buckets.0.put(ip, (bucket, clock_now()));
+        }
+
+        if !source_allowed {
+            self.data_stats.source_ip_rate += 1;
+            entry.rejected_forwards += 1;
+            return Err(Error::new(
                 ErrorKind::Other,
-                format!("No announced node with public key {:?}", request.inner.destination_pk)
+                format!("Too many onion data requests from {}", ip)
             ))
         }
+
+        if !destination_allowed {
+            self.data_stats.destination_rate += 1;
+            entry.rejected_forwards += 1;
+            return Err(Error::new(
+                ErrorKind::Other,
+                format!("Too many onion data requests to {:?}", request.inner.destination_pk)
+            ))
+        }
+
+        self.data_stats.forwarded += 1;
+        entry.forwards += 1;
+
+        let response_payload = OnionDataResponse {
+            nonce: request.inner.nonce,
+            temporary_pk: request.inner.temporary_pk,
+            payload: request.inner.payload
+        };
+        let response = OnionResponse3 {
+            onion_return: entry.onion_return.clone(),
+            payload: InnerOnionResponse::OnionDataResponse(response_payload)
+        };
+        let saddr = SocketAddr::new(entry.ip_addr, entry.port);
+        Ok((response, saddr))
     }
 }
 
@@ -338,6 +759,8 @@ impl OnionAnnounce {
 mod tests {
     use super::*;
 
+    use std::net::Ipv4Addr;
+
     use tokio_executor;
     use tokio_timer::clock::*;
 
@@ -464,7 +887,8 @@ mod tests {
         let entry_pk = entry.pk;
         let entry_time = entry.time;
 
-        onion_announce.entries.push(entry);
+        let key = onion_announce.distance_key(&entry_pk);
+        onion_announce.entries.insert(key, entry);
 
         let mut enter = tokio_executor::enter().unwrap();
         // time when entry is timed out
@@ -516,7 +940,7 @@ mod tests {
         }
 
         // choose one of entries to update
-        let mut entry_to_update = onion_announce.entries[ONION_ANNOUNCE_MAX_ENTRIES / 2].clone();
+        let mut entry_to_update = onion_announce.entries.values().nth(ONION_ANNOUNCE_MAX_ENTRIES / 2).unwrap().clone();
 
         entry_to_update.time += Duration::from_secs(7);
         entry_to_update.ip_addr = "1.2.3.4".parse().unwrap();
@@ -570,8 +994,11 @@ mod tests {
         });
 
         // make one of entries timed out
-        let timed_out_pk = onion_announce.entries[ONION_ANNOUNCE_MAX_ENTRIES / 2].pk;
-        onion_announce.entries[ONION_ANNOUNCE_MAX_ENTRIES / 2].time = now;
+        let timed_out_pk = {
+            let entry = onion_announce.entries.values_mut().nth(ONION_ANNOUNCE_MAX_ENTRIES / 2).unwrap();
+            entry.time = now;
+            entry.pk
+        };
 
         with_default(&clock_2, &mut enter, |_| {
             let entry = create_random_entry("1.2.3.5:12345".parse().unwrap());
@@ -656,6 +1083,145 @@ mod tests {
         assert_eq!(onion_announce.entries.len(), ONION_ANNOUNCE_MAX_ENTRIES);
     }
 
+    #[test]
+    fn add_to_entries_with_increased_capacity() {
+        let dht_pk = gen_keypair().0;
+        let mut onion_announce = OnionAnnounce::new(dht_pk);
+        onion_announce.set_max_entries(ONION_ANNOUNCE_MAX_ENTRIES * 4);
+
+        let mut pks = Vec::new();
+
+        for i in 0..ONION_ANNOUNCE_MAX_ENTRIES * 4 {
+            let saddr = SocketAddr::new("1.2.3.4".parse().unwrap(), 12345 + i as u16);
+            let entry = create_random_entry(saddr);
+            pks.push(entry.pk);
+            assert!(onion_announce.add_to_entries(entry).is_some());
+        }
+
+        for pk in pks {
+            assert!(onion_announce.find_in_entries(pk).is_some());
+        }
+
+        assert_eq!(onion_announce.len(), ONION_ANNOUNCE_MAX_ENTRIES * 4);
+    }
+
+    #[test]
+    fn set_max_entries_should_drop_the_farthest_entries() {
+        let dht_pk = PublicKey::from_slice(&[0; 32]).unwrap();
+        let mut onion_announce = OnionAnnounce::new(dht_pk);
+
+        let mut pks = Vec::new();
+
+        for i in 0..10 {
+            let mut entry = create_random_entry("1.2.3.4:12345".parse().unwrap());
+            entry.pk = PublicKey::from_slice(&[i; 32]).unwrap();
+            pks.push(entry.pk);
+            assert!(onion_announce.add_to_entries(entry).is_some());
+        }
+
+        onion_announce.set_max_entries(4);
+
+        assert_eq!(onion_announce.max_entries(), 4);
+        assert_eq!(onion_announce.len(), 4);
+        for (i, pk) in pks.into_iter().enumerate() {
+            assert_eq!(onion_announce.find_in_entries(pk).is_some(), i < 4);
+        }
+    }
+
+    #[test]
+    fn remove_timed_out() {
+        let dht_pk = gen_keypair().0;
+        let mut onion_announce = OnionAnnounce::new(dht_pk);
+
+        let entry_1 = create_random_entry("1.2.3.4:12345".parse().unwrap());
+        let mut entry_2 = create_random_entry("1.2.3.4:12346".parse().unwrap());
+        let entry_1_pk = entry_1.pk;
+        let entry_2_pk = entry_2.pk;
+        let now = entry_1.time;
+        entry_2.time = now + Duration::from_secs(ONION_ANNOUNCE_TIMEOUT / 2);
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(
+            now + Duration::from_secs(ONION_ANNOUNCE_TIMEOUT + 1)
+        ));
+
+        with_default(&clock, &mut enter, |_| {
+            assert!(onion_announce.add_to_entries(entry_1).is_some());
+            assert!(onion_announce.add_to_entries(entry_2).is_some());
+            assert_eq!(onion_announce.len(), 2);
+
+            onion_announce.remove_timed_out();
+
+            assert_eq!(onion_announce.len(), 1);
+            assert!(onion_announce.find_in_entries(entry_1_pk).is_none());
+            assert!(onion_announce.find_in_entries(entry_2_pk).is_some());
+        });
+    }
+
+    #[test]
+    fn snapshot_encode_decode() {
+        let dht_pk = gen_keypair().0;
+        let mut onion_announce = OnionAnnounce::new(dht_pk);
+
+        assert!(onion_announce.add_to_entries(create_random_entry("1.2.3.4:12345".parse().unwrap())).is_some());
+        assert!(onion_announce.add_to_entries(create_random_entry("[1:2:3:4:5:6:7:8]:12345".parse().unwrap())).is_some());
+
+        let snapshot = onion_announce.snapshot();
+        assert_eq!(snapshot.entries.len(), 2);
+
+        let bytes = snapshot.to_vec();
+        let decoded = OnionAnnounceSnapshot::from_bytes(&bytes).to_full_result().unwrap();
+        assert_eq!(decoded, snapshot);
+
+        // truncated snapshot is invalid
+        assert!(OnionAnnounceSnapshot::from_bytes(&bytes[..bytes.len() - 1]).to_full_result().is_err());
+    }
+
+    #[test]
+    fn snapshot_restore() {
+        let dht_pk = gen_keypair().0;
+        let mut onion_announce = OnionAnnounce::new(dht_pk);
+
+        let entry = create_random_entry("1.2.3.4:12345".parse().unwrap());
+        let entry_pk = entry.pk;
+        let entry_data_pk = entry.data_pk;
+        assert!(onion_announce.add_to_entries(entry).is_some());
+
+        let time = SystemTime::now();
+        let pk = gen_keypair().0;
+        let ip_addr = "1.2.3.4".parse().unwrap();
+        let ping_id = onion_announce.ping_id(time, pk, ip_addr, 12345);
+
+        let snapshot = onion_announce.snapshot();
+
+        let mut restored = OnionAnnounce::new(dht_pk);
+        restored.restore(&snapshot);
+
+        let restored_entry = restored.find_in_entries(entry_pk).unwrap();
+        assert_eq!(restored_entry.data_pk, entry_data_pk);
+        // ping ids remain valid after restore
+        assert_eq!(restored.ping_id(time, pk, ip_addr, 12345), ping_id);
+    }
+
+    #[test]
+    fn restore_should_skip_timed_out_entries() {
+        let dht_pk = gen_keypair().0;
+        let mut onion_announce = OnionAnnounce::new(dht_pk);
+
+        let entry = create_random_entry("1.2.3.4:12345".parse().unwrap());
+        let entry_pk = entry.pk;
+        assert!(onion_announce.add_to_entries(entry).is_some());
+
+        let mut snapshot = onion_announce.snapshot();
+        snapshot.entries[0].time -= ONION_ANNOUNCE_TIMEOUT;
+
+        let mut restored = OnionAnnounce::new(dht_pk);
+        restored.restore(&snapshot);
+
+        assert!(restored.find_in_entries(entry_pk).is_none());
+        assert!(restored.is_empty());
+    }
+
     ////////////////////////////////////////////////////////////////////////////////////////
     // Tests for OnionAnnounce::handle_onion_announce_request
     #[test]
@@ -846,7 +1412,7 @@ mod tests {
             onion_return
         };
 
-        let (response, saddr) = onion_announce.handle_data_request(request).unwrap();
+        let (response, saddr) = onion_announce.handle_data_request(request, "1.2.3.5:12345".parse().unwrap()).unwrap();
 
         assert_eq!(saddr.ip(), entry_addr);
         assert_eq!(saddr.port(), entry_port);
@@ -862,7 +1428,7 @@ mod tests {
     fn handle_data_request_unknown_destination() {
         let (dht_pk, _dht_sk) = gen_keypair();
 
-        let onion_announce = OnionAnnounce::new(dht_pk);
+        let mut onion_announce = OnionAnnounce::new(dht_pk);
 
         let onion_return = OnionReturn {
             nonce: secretbox::gen_nonce(),
@@ -879,6 +1445,209 @@ mod tests {
             onion_return
         };
 
-        assert!(onion_announce.handle_data_request(request).is_err());
+        assert!(onion_announce.handle_data_request(request, "1.2.3.5:12345".parse().unwrap()).is_err());
+        assert_eq!(onion_announce.data_stats().unknown_destination, 1);
+    }
+
+    fn create_data_request(destination_pk: PublicKey) -> OnionDataRequest {
+        OnionDataRequest {
+            inner: InnerOnionDataRequest {
+                destination_pk,
+                nonce: gen_nonce(),
+                temporary_pk: gen_keypair().0,
+                payload: vec![42; 123]
+            },
+            onion_return: OnionReturn {
+                nonce: secretbox::gen_nonce(),
+                payload: vec![42; ONION_RETURN_3_PAYLOAD_SIZE]
+            }
+        }
+    }
+
+    #[test]
+    fn handle_data_request_destination_limit() {
+        let mut onion_announce = OnionAnnounce::new(gen_keypair().0);
+        onion_announce.set_data_limits(OnionDataLimits {
+            per_destination: Some(RateLimit { rate: 1, burst: 2 }),
+            per_source_ip: None,
+        });
+
+        let entry_1 = create_random_entry("1.2.3.4:12345".parse().unwrap());
+        let entry_2 = create_random_entry("1.2.3.4:12346".parse().unwrap());
+        let (entry_1_pk, entry_2_pk) = (entry_1.pk, entry_2.pk);
+        assert!(onion_announce.add_to_entries(entry_1).is_some());
+        assert!(onion_announce.add_to_entries(entry_2).is_some());
+
+        let addr = "1.2.3.5:12345".parse().unwrap();
+        assert!(onion_announce.handle_data_request(create_data_request(entry_1_pk), addr).is_ok());
+        assert!(onion_announce.handle_data_request(create_data_request(entry_1_pk), addr).is_ok());
+        assert!(onion_announce.handle_data_request(create_data_request(entry_1_pk), addr).is_err());
+        // other destinations are not affected
+        assert!(onion_announce.handle_data_request(create_data_request(entry_2_pk), addr).is_ok());
+
+        assert_eq!(onion_announce.data_stats(), OnionDataStats {
+            forwarded: 3,
+            unknown_destination: 0,
+            destination_rate: 1,
+            source_ip_rate: 0,
+        });
+
+        let info = onion_announce.entries_info();
+        let info_1 = info.iter().find(|info| info.pk == entry_1_pk).unwrap();
+        assert_eq!(info_1.forwards, 2);
+        assert_eq!(info_1.rejected_forwards, 1);
+    }
+
+    #[test]
+    fn handle_data_request_source_ip_limit() {
+        let mut onion_announce = OnionAnnounce::new(gen_keypair().0);
+        onion_announce.set_data_limits(OnionDataLimits {
+            per_destination: None,
+            per_source_ip: Some(RateLimit { rate: 1, burst: 1 }),
+        });
+
+        let entry_1 = create_random_entry("1.2.3.4:12345".parse().unwrap());
+        let entry_2 = create_random_entry("1.2.3.4:12346".parse().unwrap());
+        let (entry_1_pk, entry_2_pk) = (entry_1.pk, entry_2.pk);
+        assert!(onion_announce.add_to_entries(entry_1).is_some());
+        assert!(onion_announce.add_to_entries(entry_2).is_some());
+
+        let addr_1 = "1.2.3.5:12345".parse().unwrap();
+        let addr_2 = "1.2.3.5:12346".parse().unwrap();
+        let addr_3 = "1.2.3.6:12345".parse().unwrap();
+        assert!(onion_announce.handle_data_request(create_data_request(entry_1_pk), addr_1).is_ok());
+        // the same IP address with different port is limited
+        assert!(onion_announce.handle_data_request(create_data_request(entry_2_pk), addr_2).is_err());
+        // other IP addresses are not affected
+        assert!(onion_announce.handle_data_request(create_data_request(entry_2_pk), addr_3).is_ok());
+
+        assert_eq!(onion_announce.data_stats().forwarded, 2);
+        assert_eq!(onion_announce.data_stats().source_ip_rate, 1);
+    }
+
+    #[test]
+    fn handle_data_request_destination_limit_keeps_source_tokens() {
+        let mut onion_announce = OnionAnnounce::new(gen_keypair().0);
+        onion_announce.set_data_limits(OnionDataLimits {
+            per_destination: Some(RateLimit { rate: 1, burst: 1 }),
+            per_source_ip: Some(RateLimit { rate: 1, burst: 2 }),
+        });
+
+        let entry_1 = create_random_entry("1.2.3.4:12345".parse().unwrap());
+        let entry_2 = create_random_entry("1.2.3.4:12346".parse().unwrap());
+        let (entry_1_pk, entry_2_pk) = (entry_1.pk, entry_2.pk);
+        assert!(onion_announce.add_to_entries(entry_1).is_some());
+        assert!(onion_announce.add_to_entries(entry_2).is_some());
+
+        let addr = "1.2.3.5:12345".parse().unwrap();
+        assert!(onion_announce.handle_data_request(create_data_request(entry_1_pk), addr).is_ok());
+        // rejected by the destination limit
+        assert!(onion_announce.handle_data_request(create_data_request(entry_1_pk), addr).is_err());
+        // the source still has a token for another destination
+        assert!(onion_announce.handle_data_request(create_data_request(entry_2_pk), addr).is_ok());
+
+        assert_eq!(onion_announce.data_stats(), OnionDataStats {
+            forwarded: 2,
+            unknown_destination: 0,
+            destination_rate: 1,
+            source_ip_rate: 0,
+        });
+    }
+
+    #[test]
+    fn handle_data_request_source_buckets_are_bounded() {
+        let mut onion_announce = OnionAnnounce::new(gen_keypair().0);
+        onion_announce.set_data_limits(OnionDataLimits {
+            per_destination: None,
+            per_source_ip: Some(RateLimit { rate: 1, burst: 1 }),
+        });
+
+        let entry = create_random_entry("1.2.3.4:12345".parse().unwrap());
+        let entry_pk = entry.pk;
+        assert!(onion_announce.add_to_entries(entry).is_some());
+
+        let first_addr = "10.0.0.0:12345".parse().unwrap();
+        assert!(onion_announce.handle_data_request(create_data_request(entry_pk), first_addr).is_ok());
+        assert!(onion_announce.handle_data_request(create_data_request(entry_pk), first_addr).is_err());
+
+        for i in 1 .. ONION_DATA_MAX_SOURCES as u32 + 1 {
+            let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::from((10u32 << 24) + i)), 12345);
+            assert!(onion_announce.handle_data_request(create_data_request(entry_pk), addr).is_ok());
+        }
+        assert_eq!(onion_announce.source_buckets.0.len(), ONION_DATA_MAX_SOURCES);
+
+        // the limiter of the least recently used source was dropped
+        assert!(onion_announce.handle_data_request(create_data_request(entry_pk), first_addr).is_ok());
+    }
+
+    #[test]
+    fn remove_timed_out_source_buckets() {
+        let mut onion_announce = OnionAnnounce::new(gen_keypair().0);
+        onion_announce.set_data_limits(OnionDataLimits {
+            per_destination: None,
+            per_source_ip: Some(RateLimit { rate: 1, burst: 1 }),
+        });
+
+        let entry = create_random_entry("1.2.3.4:12345".parse().unwrap());
+        let entry_pk = entry.pk;
+        assert!(onion_announce.add_to_entries(entry).is_some());
+
+        let addr = "1.2.3.5:12345".parse().unwrap();
+        assert!(onion_announce.handle_data_request(create_data_request(entry_pk), addr).is_ok());
+        assert_eq!(onion_announce.source_buckets.0.len(), 1);
+
+        let _cloned = onion_announce.clone();
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(
+            Instant::now() + Duration::from_secs(ONION_ANNOUNCE_TIMEOUT + 1)
+        ));
+
+        with_default(&clock, &mut enter, |_| onion_announce.remove_timed_out());
+        assert!(onion_announce.source_buckets.0.is_empty());
+    }
+
+    #[test]
+    fn add_to_entries_should_keep_forwards_counters() {
+        let mut onion_announce = OnionAnnounce::new(gen_keypair().0);
+
+        let entry = create_random_entry("1.2.3.4:12345".parse().unwrap());
+        let entry_pk = entry.pk;
+        assert!(onion_announce.add_to_entries(entry.clone()).is_some());
+
+        let addr = "1.2.3.5:12345".parse().unwrap();
+        assert!(onion_announce.handle_data_request(create_data_request(entry_pk), addr).is_ok());
+
+        // node re-announces itself
+        assert!(onion_announce.add_to_entries(entry).is_some());
+
+        assert_eq!(onion_announce.entries_info()[0].forwards, 1);
+    }
+
+    #[test]
+    fn entries_info() {
+        let mut onion_announce = OnionAnnounce::new(gen_keypair().0);
+
+        let entry = create_random_entry("1.2.3.4:12345".parse().unwrap());
+        let entry_pk = entry.pk;
+        let entry_data_pk = entry.data_pk;
+        let entry_time = entry.time;
+        assert!(onion_announce.add_to_entries(entry).is_some());
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(
+            entry_time + Duration::from_secs(42)
+        ));
+
+        with_default(&clock, &mut enter, |_| {
+            assert_eq!(onion_announce.entries_info(), vec![OnionAnnounceEntryInfo {
+                pk: entry_pk,
+                saddr: "1.2.3.4:12345".parse().unwrap(),
+                data_pk: entry_data_pk,
+                age: Duration::from_secs(42),
+                forwards: 0,
+                rejected_forwards: 0,
+            }]);
+        });
     }
 }
@@ -0,0 +1,119 @@
+/*! DhtPkAnnouncePayload packet
+*/
+
+use super::*;
+
+use toxcore::binary_io::*;
+use toxcore::crypto_core::*;
+
+use nom::be_u64;
+
+/// Maximum number of nodes that can be sent in `DhtPkAnnouncePayload`.
+pub const MAX_DHT_PK_ANNOUNCE_NODES: usize = 4;
+
+/** Packet that is used to let a friend know our DHT `PublicKey` so that it
+can find us in DHT and connect to us via `net_crypto`. It's sent to the
+friend's announced onion node inside `OnionDataRequest`.
+
+`no_reply` is a number that should be increased with every sent packet. The
+receiver drops packets with number that is not greater than the number of
+the last accepted packet so that they can't be replayed. Nodes are DHT nodes
+close to us that the receiver can ask about our DHT `PublicKey`. TCP relays
+that can be sent by other implementations in the same list are skipped.
+
+Serialized form:
+
+Length     | Content
+---------- | ------
+`1`        | `0x9c`
+`8`        | `no_reply`
+`32`       | DHT `PublicKey`
+`[0, 204]` | Nodes in packed format
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DhtPkAnnouncePayload {
+    /// Number used to prevent replay attacks
+    pub no_reply: u64,
+    /// Our DHT `PublicKey`
+    pub dht_pk: PublicKey,
+    /// Up to 4 DHT nodes close to us
+    pub nodes: Vec<PackedNode>,
+}
+
+impl FromBytes for DhtPkAnnouncePayload {
+    named!(from_bytes<DhtPkAnnouncePayload>, do_parse!(
+        tag!(&[0x9c][..]) >>
+        no_reply: be_u64 >>
+        dht_pk: call!(PublicKey::from_bytes) >>
+        nodes: many0!(alt!(
+            map!(PackedNode::from_bytes, Some) |
+            map!(PackedNode::from_tcp_bytes, |_| None)
+        )) >>
+        cond_reduce!(nodes.len() <= MAX_DHT_PK_ANNOUNCE_NODES, eof!()) >>
+        (DhtPkAnnouncePayload {
+            no_reply,
+            dht_pk,
+            nodes: nodes.into_iter().flat_map(|node| node).collect()
+        })
+    ));
+}
+
+impl ToBytes for DhtPkAnnouncePayload {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(0x9c) >>
+            gen_be_u64!(self.no_reply) >>
+            gen_slice!(self.dht_pk.as_ref()) >>
+            gen_cond!(self.nodes.len() > MAX_DHT_PK_ANNOUNCE_NODES, |buf| gen_error(buf, 0)) >>
+            gen_many_ref!(&self.nodes, |buf, node| PackedNode::to_bytes(node, buf))
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    encode_decode_test!(
+        dht_pk_announce_payload_encode_decode,
+        DhtPkAnnouncePayload {
+            no_reply: 42,
+            dht_pk: gen_keypair().0,
+            nodes: vec![
+                PackedNode::new("1.2.3.4:12345".parse().unwrap(), &gen_keypair().0),
+                PackedNode::new("[FF::01]:12345".parse().unwrap(), &gen_keypair().0),
+            ]
+        }
+    );
+
+    #[test]
+    fn dht_pk_announce_payload_skips_tcp_relays() {
+        let udp_node = PackedNode::new("1.2.3.4:12345".parse().unwrap(), &gen_keypair().0);
+        let tcp_node = PackedNode::new("5.6.7.8:33445".parse().unwrap(), &gen_keypair().0);
+        let payload = DhtPkAnnouncePayload {
+            no_reply: 42,
+            dht_pk: gen_keypair().0,
+            nodes: vec![udp_node],
+        };
+
+        let mut buf = [0; 1024];
+        let (_, size) = payload.to_bytes((&mut buf, 0)).unwrap();
+        let (_, size) = tcp_node.to_tcp_bytes((&mut buf, size)).unwrap();
+
+        let (_, decoded) = DhtPkAnnouncePayload::from_bytes(&buf[..size]).unwrap();
+        assert_eq!(decoded, payload);
+    }
+
+    #[test]
+    fn dht_pk_announce_payload_too_many_nodes() {
+        let payload = DhtPkAnnouncePayload {
+            no_reply: 42,
+            dht_pk: gen_keypair().0,
+            nodes: vec![PackedNode::new("1.2.3.4:12345".parse().unwrap(), &gen_keypair().0); MAX_DHT_PK_ANNOUNCE_NODES + 1],
+        };
+
+        let mut buf = [0; 1024];
+        assert!(payload.to_bytes((&mut buf, 0)).is_err());
+    }
+}
@@ -0,0 +1,74 @@
+/*! FriendRequest packet
+*/
+
+use toxcore::binary_io::*;
+use toxcore::friend_connection::packet::MAX_ONION_CLIENT_DATA_SIZE;
+use toxcore::toxid::NoSpam;
+
+use nom::rest;
+
+/** Friend request that is sent to the friend's announced onion node inside
+`OnionDataRequest` when we are not connected to the friend via `net_crypto`.
+Long term `PublicKey` of the sender is contained in the enclosing
+`OnionDataResponsePayload`.
+
+Serialized form:
+
+Length   | Content
+-------- | ------
+`1`      | `0x20`
+`4`      | `NoSpam`
+variable | Message
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FriendRequest {
+    /// `NoSpam` of the receiver's `ToxId`
+    pub nospam: NoSpam,
+    /// Greeting message
+    pub message: Vec<u8>,
+}
+
+impl FromBytes for FriendRequest {
+    named!(from_bytes<FriendRequest>, do_parse!(
+        tag!("\x20") >>
+        nospam: call!(NoSpam::from_bytes) >>
+        message: verify!(rest, |message: &[u8]| message.len() <= MAX_ONION_CLIENT_DATA_SIZE) >>
+        (FriendRequest { nospam, message: message.to_vec() })
+    ));
+}
+
+impl ToBytes for FriendRequest {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(0x20) >>
+            gen_slice!(self.nospam.0) >>
+            gen_cond!(self.message.len() > MAX_ONION_CLIENT_DATA_SIZE, |buf| gen_error(buf, 0)) >>
+            gen_slice!(self.message.as_slice())
+        )
+    }
+}
+
+impl FriendRequest {
+    /// Create new `FriendRequest` object.
+    pub fn new(nospam: NoSpam, message: Vec<u8>) -> FriendRequest {
+        FriendRequest { nospam, message }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    encode_decode_test!(
+        friend_request_encode_decode,
+        FriendRequest::new(NoSpam([42; 4]), vec![1, 2, 3, 4])
+    );
+
+    #[test]
+    fn friend_request_too_long() {
+        let mut buf = [0; 2048];
+        let request = FriendRequest::new(NoSpam([42; 4]), vec![42; MAX_ONION_CLIENT_DATA_SIZE + 1]);
+        assert!(request.to_bytes((&mut buf, 0)).is_err());
+    }
+}
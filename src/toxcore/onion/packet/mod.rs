@@ -13,6 +13,8 @@ mod onion_request_2;
 mod onion_response_1;
 mod onion_response_2;
 mod onion_response_3;
+mod friend_request;
+mod dht_pk_announce_payload;
 
 pub use self::onion_announce_request::*;
 pub use self::onion_announce_response::*;
@@ -26,6 +28,8 @@ pub use self::onion_request_2::*;
 pub use self::onion_response_1::*;
 pub use self::onion_response_2::*;
 pub use self::onion_response_3::*;
+pub use self::friend_request::*;
+pub use self::dht_pk_announce_payload::*;
 
 use toxcore::binary_io::*;
 use toxcore::crypto_core::*;
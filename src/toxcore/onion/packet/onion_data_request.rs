@@ -67,6 +67,19 @@ impl ToBytes for InnerOnionDataRequest {
     }
 }
 
+impl InnerOnionDataRequest {
+    /// Create new `InnerOnionDataRequest` object. The payload is sent to the
+    /// destination node as is so it has to be encrypted with the data
+    /// `PublicKey` the destination node announced.
+    pub fn new(shared_secret: &PrecomputedKey, destination_pk: PublicKey, temporary_pk: PublicKey, nonce: Nonce, payload: &OnionDataResponsePayload) -> InnerOnionDataRequest {
+        let mut buf = [0; ONION_MAX_PACKET_SIZE];
+        let (_, size) = payload.to_bytes((&mut buf, 0)).unwrap();
+        let payload = seal_precomputed(&buf[..size], &nonce, shared_secret);
+
+        InnerOnionDataRequest { destination_pk, nonce, temporary_pk, payload }
+    }
+}
+
 /** Same as `InnerOnionDataRequest` but with `OnionReturn` addresses. It's sent
 from the third node from onion chain to the destination node.
 
@@ -1,4 +1,4 @@
-/*! OnionDataResponse packet
+/*! OnionDataResponse packet with OnionDataResponsePayload
 */
 
 use super::*;
@@ -7,6 +7,7 @@ use toxcore::binary_io::*;
 use toxcore::crypto_core::*;
 
 use nom::rest;
+use std::io::{Error, ErrorKind};
 
 /** When onion node receives `OnionDataRequest` packet it converts it to
 `OnionDataResponse` and sends to destination node if it announced itself
@@ -21,6 +22,8 @@ Length   | Content
 `32`     | Temporary `PublicKey`
 variable | Payload
 
+where payload is encrypted [`OnionDataResponsePayload`](./struct.OnionDataResponsePayload.html)
+
 */
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct OnionDataResponse {
@@ -59,10 +62,164 @@ impl ToBytes for OnionDataResponse {
     }
 }
 
+impl OnionDataResponse {
+    /// Create new `OnionDataResponse` object.
+    pub fn new(shared_secret: &PrecomputedKey, temporary_pk: PublicKey, nonce: Nonce, payload: &OnionDataResponsePayload) -> OnionDataResponse {
+        let mut buf = [0; ONION_MAX_PACKET_SIZE];
+        let (_, size) = payload.to_bytes((&mut buf, 0)).unwrap();
+        let payload = seal_precomputed(&buf[..size], &nonce, shared_secret);
+
+        OnionDataResponse { nonce, temporary_pk, payload }
+    }
+
+    /** Decrypt payload and try to parse it as `OnionDataResponsePayload`.
+
+    Returns `Error` in case of failure:
+
+    - fails to decrypt
+    - fails to parse as `OnionDataResponsePayload`
+    */
+    pub fn get_payload(&self, shared_secret: &PrecomputedKey) -> Result<OnionDataResponsePayload, Error> {
+        let decrypted = open_precomputed(&self.payload, &self.nonce, shared_secret)
+            .map_err(|()| {
+                debug!("Decrypting OnionDataResponse failed!");
+                Error::new(ErrorKind::Other, "OnionDataResponse decrypt error.")
+            })?;
+        match OnionDataResponsePayload::from_bytes(&decrypted) {
+            IResult::Incomplete(e) => {
+                debug!(target: "Onion", "OnionDataResponsePayload deserialize error: {:?}", e);
+                Err(Error::new(ErrorKind::Other,
+                    format!("OnionDataResponsePayload deserialize error: {:?}", e)))
+            },
+            IResult::Error(e) => {
+                debug!(target: "Onion", "OnionDataResponsePayload deserialize error: {:?}", e);
+                Err(Error::new(ErrorKind::Other,
+                    format!("OnionDataResponsePayload deserialize error: {:?}", e)))
+            },
+            IResult::Done(_, inner) => {
+                Ok(inner)
+            }
+        }
+    }
+}
+
+/** Unencrypted payload of `OnionDataResponse` packet. It's encrypted with
+temporary `SecretKey` of the sender and data `PublicKey` the receiver
+announced to onion nodes.
+
+Serialized form:
+
+Length   | Content
+-------- | ------
+`32`     | Long term `PublicKey` of the sender
+variable | Payload
+
+where payload is [`OnionDataResponseInnerPayload`](./enum.OnionDataResponseInnerPayload.html)
+encrypted with long term keys of the sender and the receiver using the same
+nonce as the enclosing `OnionDataResponse`.
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionDataResponsePayload {
+    /// Long term `PublicKey` of the sender
+    pub real_pk: PublicKey,
+    /// Encrypted payload
+    pub payload: Vec<u8>
+}
+
+impl FromBytes for OnionDataResponsePayload {
+    named!(from_bytes<OnionDataResponsePayload>, do_parse!(
+        real_pk: call!(PublicKey::from_bytes) >>
+        payload: rest >>
+        (OnionDataResponsePayload {
+            real_pk,
+            payload: payload.to_vec()
+        })
+    ));
+}
+
+impl ToBytes for OnionDataResponsePayload {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_slice!(self.real_pk.as_ref()) >>
+            gen_slice!(self.payload)
+        )
+    }
+}
+
+impl OnionDataResponsePayload {
+    /// Create new `OnionDataResponsePayload` object.
+    pub fn new(shared_secret: &PrecomputedKey, real_pk: PublicKey, nonce: &Nonce, payload: &OnionDataResponseInnerPayload) -> OnionDataResponsePayload {
+        let mut buf = [0; ONION_MAX_PACKET_SIZE];
+        let (_, size) = payload.to_bytes((&mut buf, 0)).unwrap();
+        let payload = seal_precomputed(&buf[..size], nonce, shared_secret);
+
+        OnionDataResponsePayload { real_pk, payload }
+    }
+
+    /** Decrypt payload and try to parse it as `OnionDataResponseInnerPayload`.
+
+    Returns `Error` in case of failure:
+
+    - fails to decrypt
+    - fails to parse as `OnionDataResponseInnerPayload`
+    */
+    pub fn get_payload(&self, nonce: &Nonce, shared_secret: &PrecomputedKey) -> Result<OnionDataResponseInnerPayload, Error> {
+        let decrypted = open_precomputed(&self.payload, nonce, shared_secret)
+            .map_err(|()| {
+                debug!("Decrypting OnionDataResponsePayload failed!");
+                Error::new(ErrorKind::Other, "OnionDataResponsePayload decrypt error.")
+            })?;
+        match OnionDataResponseInnerPayload::from_bytes(&decrypted) {
+            IResult::Incomplete(e) => {
+                debug!(target: "Onion", "OnionDataResponseInnerPayload deserialize error: {:?}", e);
+                Err(Error::new(ErrorKind::Other,
+                    format!("OnionDataResponseInnerPayload deserialize error: {:?}", e)))
+            },
+            IResult::Error(e) => {
+                debug!(target: "Onion", "OnionDataResponseInnerPayload deserialize error: {:?}", e);
+                Err(Error::new(ErrorKind::Other,
+                    format!("OnionDataResponseInnerPayload deserialize error: {:?}", e)))
+            },
+            IResult::Done(_, inner) => {
+                Ok(inner)
+            }
+        }
+    }
+}
+
+/** Data that onion client sends to its friends through onion paths.
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum OnionDataResponseInnerPayload {
+    /// [`DhtPkAnnouncePayload`](./struct.DhtPkAnnouncePayload.html) structure.
+    DhtPkAnnounce(DhtPkAnnouncePayload),
+    /// [`FriendRequest`](./struct.FriendRequest.html) structure.
+    FriendRequest(FriendRequest)
+}
+
+impl ToBytes for OnionDataResponseInnerPayload {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        match *self {
+            OnionDataResponseInnerPayload::DhtPkAnnounce(ref inner) => inner.to_bytes(buf),
+            OnionDataResponseInnerPayload::FriendRequest(ref inner) => inner.to_bytes(buf),
+        }
+    }
+}
+
+impl FromBytes for OnionDataResponseInnerPayload {
+    named!(from_bytes<OnionDataResponseInnerPayload>, alt!(
+        map!(DhtPkAnnouncePayload::from_bytes, OnionDataResponseInnerPayload::DhtPkAnnounce) |
+        map!(FriendRequest::from_bytes, OnionDataResponseInnerPayload::FriendRequest)
+    ));
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use toxcore::toxid::NoSpam;
+
     encode_decode_test!(
         onion_data_response_encode_decode,
         OnionDataResponse {
@@ -71,4 +228,66 @@ mod tests {
             payload: vec![42; 123]
         }
     );
+
+    encode_decode_test!(
+        onion_data_response_payload_encode_decode,
+        OnionDataResponsePayload {
+            real_pk: gen_keypair().0,
+            payload: vec![42; 123]
+        }
+    );
+
+    encode_decode_test!(
+        onion_data_response_inner_payload_dht_pk_announce_encode_decode,
+        OnionDataResponseInnerPayload::DhtPkAnnounce(DhtPkAnnouncePayload {
+            no_reply: 42,
+            dht_pk: gen_keypair().0,
+            nodes: vec![
+                PackedNode::new("1.2.3.4:12345".parse().unwrap(), &gen_keypair().0)
+            ]
+        })
+    );
+
+    encode_decode_test!(
+        onion_data_response_inner_payload_friend_request_encode_decode,
+        OnionDataResponseInnerPayload::FriendRequest(FriendRequest::new(NoSpam([42; 4]), vec![1, 2, 3]))
+    );
+
+    #[test]
+    fn onion_data_response_payload_encrypt_decrypt() {
+        let (alice_real_pk, alice_real_sk) = gen_keypair();
+        let (bob_real_pk, bob_real_sk) = gen_keypair();
+        let (temporary_pk, temporary_sk) = gen_keypair();
+        let (data_pk, data_sk) = gen_keypair();
+        let nonce = gen_nonce();
+        let inner_payload = OnionDataResponseInnerPayload::FriendRequest(FriendRequest::new(NoSpam([42; 4]), vec![1, 2, 3]));
+        // encode payload with alice's keys
+        let real_shared_secret = encrypt_precompute(&bob_real_pk, &alice_real_sk);
+        let payload = OnionDataResponsePayload::new(&real_shared_secret, alice_real_pk, &nonce, &inner_payload);
+        let data_shared_secret = encrypt_precompute(&data_pk, &temporary_sk);
+        let packet = OnionDataResponse::new(&data_shared_secret, temporary_pk, nonce, &payload);
+        // decode payload with bob's keys
+        let data_shared_secret = encrypt_precompute(&packet.temporary_pk, &data_sk);
+        let decoded_payload = packet.get_payload(&data_shared_secret).unwrap();
+        assert_eq!(decoded_payload, payload);
+        let real_shared_secret = encrypt_precompute(&decoded_payload.real_pk, &bob_real_sk);
+        let decoded_inner_payload = decoded_payload.get_payload(&packet.nonce, &real_shared_secret).unwrap();
+        assert_eq!(decoded_inner_payload, inner_payload);
+    }
+
+    #[test]
+    fn onion_data_response_payload_encrypt_decrypt_invalid_key() {
+        let (temporary_pk, temporary_sk) = gen_keypair();
+        let (data_pk, _data_sk) = gen_keypair();
+        let (_eve_pk, eve_sk) = gen_keypair();
+        let payload = OnionDataResponsePayload {
+            real_pk: gen_keypair().0,
+            payload: vec![42; 123]
+        };
+        let data_shared_secret = encrypt_precompute(&data_pk, &temporary_sk);
+        let packet = OnionDataResponse::new(&data_shared_secret, temporary_pk, gen_nonce(), &payload);
+        // try to decode payload with eve's secret key
+        let eve_shared_secret = encrypt_precompute(&temporary_pk, &eve_sk);
+        assert!(packet.get_payload(&eve_shared_secret).is_err());
+    }
 }
@@ -14,6 +14,7 @@ use toxcore::binary_io::*;
 use toxcore::crypto_core::*;
 use toxcore::dht::packed_node::*;
 use toxcore::toxid::{NoSpam, NOSPAMBYTES};
+use toxcore::messenger::packet::{ConferenceType, CONFERENCE_UID_BYTES};
 use toxcore::dht::daemon_state::*;
 use toxcore::onion::packet::*;
 
@@ -577,6 +578,184 @@ impl ToBytes for Friends {
     }
 }
 
+/** Peer of a conference that is saved to restore the conference after
+restart.
+
+Serialized form:
+
+Length    | Content
+--------- | ------
+`32`      | Long term `PublicKey` of the peer
+`32`      | DHT `PublicKey` of the peer
+`2`       | Peer number in LE
+`8`       | Time when the peer was active for the last time in LE
+`1`       | Length of the name
+`0..128`  | Name of the peer
+
+https://zetok.github.io/tox-spec/#conferences-0x14
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ConferencePeerState {
+    /// Long term `PublicKey` of the peer.
+    pub real_pk: PublicKey,
+    /// DHT `PublicKey` of the peer.
+    pub temp_pk: PublicKey,
+    /// Number of the peer in the conference.
+    pub peer_number: u16,
+    /// Unix time when the peer was active for the last time.
+    pub last_active: u64,
+    /// Name of the peer.
+    pub name: Vec<u8>,
+}
+
+impl FromBytes for ConferencePeerState {
+    named!(from_bytes<ConferencePeerState>, do_parse!(
+        real_pk: call!(PublicKey::from_bytes) >>
+        temp_pk: call!(PublicKey::from_bytes) >>
+        peer_number: le_u16 >>
+        last_active: le_u64 >>
+        name: verify!(length_data!(le_u8), |name: &[u8]| name.len() <= NAME_LEN) >>
+        (ConferencePeerState {
+            real_pk,
+            temp_pk,
+            peer_number,
+            last_active,
+            name: name.to_vec(),
+        })
+    ));
+}
+
+impl ToBytes for ConferencePeerState {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_slice!(self.real_pk.as_ref()) >>
+            gen_slice!(self.temp_pk.as_ref()) >>
+            gen_le_u16!(self.peer_number) >>
+            gen_le_u64!(self.last_active) >>
+            gen_cond!(self.name.len() > NAME_LEN, |buf| gen_error(buf, 0)) >>
+            gen_le_u8!(self.name.len() as u8) >>
+            gen_slice!(self.name.as_slice())
+        )
+    }
+}
+
+impl ConferencePeerState {
+    /// Number of bytes of serialized `ConferencePeerState`.
+    fn size(&self) -> usize {
+        PUBLICKEYBYTES * 2 + 2 + 8 + 1 + self.name.len()
+    }
+}
+
+/** Conference that is saved to restore it after restart.
+
+Serialized form:
+
+Length    | Content
+--------- | ------
+`1`       | Conference type (0 = text, 1 = audio)
+`32`      | Unique conference id
+`4`       | Number of the last message we sent in LE
+`2`       | Number of the last lossy packet we sent in LE
+`2`       | Our peer number in LE
+`4`       | Number of saved peers in LE
+`1`       | Length of the title
+`0..128`  | Title of the conference
+variable  | Saved peers
+
+https://zetok.github.io/tox-spec/#conferences-0x14
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ConferenceState {
+    /// Type of the conference.
+    pub conference_type: ConferenceType,
+    /// Unique id of the conference.
+    pub unique_id: [u8; CONFERENCE_UID_BYTES],
+    /// Number of the last message we sent.
+    pub message_number: u32,
+    /// Number of the last lossy packet we sent.
+    pub lossy_message_number: u16,
+    /// Our peer number in the conference.
+    pub peer_number: u16,
+    /// Title of the conference.
+    pub title: Vec<u8>,
+    /// Peers of the conference except us.
+    pub peers: Vec<ConferencePeerState>,
+}
+
+impl FromBytes for ConferenceState {
+    named!(from_bytes<ConferenceState>, do_parse!(
+        conference_type: call!(ConferenceType::from_bytes) >>
+        unique_id: count_fixed!(u8, le_u8, CONFERENCE_UID_BYTES) >>
+        message_number: le_u32 >>
+        lossy_message_number: le_u16 >>
+        peer_number: le_u16 >>
+        peers_count: le_u32 >>
+        title: verify!(length_data!(le_u8), |title: &[u8]| title.len() <= NAME_LEN) >>
+        peers: count!(ConferencePeerState::from_bytes, peers_count as usize) >>
+        (ConferenceState {
+            conference_type,
+            unique_id,
+            message_number,
+            lossy_message_number,
+            peer_number,
+            title: title.to_vec(),
+            peers,
+        })
+    ));
+}
+
+impl ToBytes for ConferenceState {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_le_u8!(self.conference_type as u8) >>
+            gen_slice!(&self.unique_id) >>
+            gen_le_u32!(self.message_number) >>
+            gen_le_u16!(self.lossy_message_number) >>
+            gen_le_u16!(self.peer_number) >>
+            gen_le_u32!(self.peers.len() as u32) >>
+            gen_cond!(self.title.len() > NAME_LEN, |buf| gen_error(buf, 0)) >>
+            gen_le_u8!(self.title.len() as u8) >>
+            gen_slice!(self.title.as_slice()) >>
+            gen_many_ref!(&self.peers, |buf, peer| ConferencePeerState::to_bytes(peer, buf))
+        )
+    }
+}
+
+impl ConferenceState {
+    /// Number of bytes of serialized `ConferenceState`.
+    fn size(&self) -> usize {
+        1 + CONFERENCE_UID_BYTES + 4 + 2 + 2 + 4 + 1 + self.title.len()
+            + self.peers.iter().map(ConferencePeerState::size).sum::<usize>()
+    }
+}
+
+/** Wrapper struct for `Vec<ConferenceState>` to ease working with
+conferences.
+
+https://zetok.github.io/tox-spec/#conferences-0x14
+*/
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Conferences(pub Vec<ConferenceState>);
+
+impl FromBytes for Conferences {
+    named!(from_bytes<Conferences>, do_parse!(
+        tag!([0x14, 0x00]) >>
+        tag!(SECTION_MAGIC) >>
+        conferences: many0!(ConferenceState::from_bytes) >>
+        (Conferences(conferences))
+    ));
+}
+
+impl ToBytes for Conferences {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_le_u16!(0x0014) >>
+            gen_slice!(SECTION_MAGIC) >>
+            gen_many_ref!(&self.0, |buf, conference| ConferenceState::to_bytes(conference, buf))
+        )
+    }
+}
+
 /// End of the state format data.
 #[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
 pub struct Eof;
@@ -647,6 +826,11 @@ pub enum Section {
     https://zetok.github.io/tox-spec/#path-nodes-0x0b
     */
     PathNodes(PathNodes),
+    /** Section for a list of [`Conferences`](./struct.Conferences.html).
+
+    https://zetok.github.io/tox-spec/#conferences-0x14
+    */
+    Conferences(Conferences),
     /// End of file. https://zetok.github.io/tox-spec/#eof-0xff
     Eof(Eof),
 }
@@ -661,6 +845,7 @@ impl FromBytes for Section {
         map!(UserStatus::from_bytes, Section::UserStatus) |
         map!(TcpRelays::from_bytes, Section::TcpRelays) |
         map!(PathNodes::from_bytes, Section::PathNodes) |
+        map!(Conferences::from_bytes, Section::Conferences) |
         map!(Eof::from_bytes, Section::Eof)
     ));
 }
@@ -745,6 +930,13 @@ impl ToBytes for Section {
                     gen_call!(|buf, data| PathNodes::to_bytes(data, buf), p)
                 )
             },
+            Section::Conferences(ref p) => {
+                let conferences_bytes: usize = p.0.iter().map(ConferenceState::size).sum();
+                do_gen!(buf,
+                    gen_le_u32!(conferences_bytes as u32) >>
+                    gen_call!(|buf, data| Conferences::to_bytes(data, buf), p)
+                )
+            },
             Section::Eof(ref p) => {
                 do_gen!(buf,
                     gen_le_u32!(0x00) >>
@@ -901,6 +1093,38 @@ mod tests {
         ])
     );
 
+    encode_decode_test!(
+        conferences_encode_decode,
+        Conferences(vec![
+            ConferenceState {
+                conference_type: ConferenceType::Text,
+                unique_id: [42; CONFERENCE_UID_BYTES],
+                message_number: 1234,
+                lossy_message_number: 12,
+                peer_number: 1,
+                title: b"test title".to_vec(),
+                peers: vec![
+                    ConferencePeerState {
+                        real_pk: gen_keypair().0,
+                        temp_pk: gen_keypair().0,
+                        peer_number: 2,
+                        last_active: 1234,
+                        name: b"test name".to_vec(),
+                    },
+                ],
+            },
+            ConferenceState {
+                conference_type: ConferenceType::Audio,
+                unique_id: [43; CONFERENCE_UID_BYTES],
+                message_number: 1235,
+                lossy_message_number: 13,
+                peer_number: 3,
+                title: Vec::new(),
+                peers: Vec::new(),
+            },
+        ])
+    );
+
     encode_decode_test!(
         state_encode_decode,
         State {
@@ -977,6 +1201,25 @@ mod tests {
                         },
                     },
                 ])),
+                Section::Conferences(Conferences(vec![
+                    ConferenceState {
+                        conference_type: ConferenceType::Text,
+                        unique_id: [42; CONFERENCE_UID_BYTES],
+                        message_number: 1234,
+                        lossy_message_number: 12,
+                        peer_number: 1,
+                        title: b"test title".to_vec(),
+                        peers: vec![
+                            ConferencePeerState {
+                                real_pk: gen_keypair().0,
+                                temp_pk: gen_keypair().0,
+                                peer_number: 2,
+                                last_active: 1234,
+                                name: b"test name".to_vec(),
+                            },
+                        ],
+                    },
+                ])),
                 Section::Eof(Eof),
             ],
         }
@@ -23,7 +23,9 @@ pub enum IncomingPacket {
     /// oob from other client with sender pk
     OobReceive(OobReceive),
     /// data from connected client
-    Data(Data)
+    Data(Data),
+    /// onion response from UDP socket of the server
+    OnionResponse(OnionResponse)
 }
 
 /** A packet should be sent to server.
@@ -38,7 +40,9 @@ pub enum OutgoingPacket {
     /// send oob by pk
     OobSend(OobSend),
     /// send data to connected client by id
-    Data(Data)
+    Data(Data),
+    /// ask server to send onion request to UDP socket
+    OnionRequest(OnionRequest)
 }
 
 /** Connection between server and client
@@ -82,7 +86,8 @@ impl Connection {
                 ))
             },
             Packet::PongResponse(_packet) => {
-                // TODO check ping_id
+                // pings are sent and their ids are verified by `RelayClient`
+                // before packets get here, so nothing is left to check
                 Box::new( future::ok(()) )
             },
             Packet::OobSend(_packet) => {
@@ -97,7 +102,15 @@ impl Connection {
             Packet::Data(packet) => {
                 self.send_to_client( IncomingPacket::Data(packet) )
             },
-            _ => unimplemented!() // TODO onion
+            Packet::OnionRequest(_packet) => {
+                Box::new( future::err(
+                    Error::new(ErrorKind::Other,
+                        "Server must not send OnionRequest to client"
+                )))
+            },
+            Packet::OnionResponse(packet) => {
+                self.send_to_client( IncomingPacket::OnionResponse(packet) )
+            },
         }
     }
     /** Handle packet from client
@@ -117,6 +130,9 @@ impl Connection {
             OutgoingPacket::Data(packet) => {
                 self.send_to_server( Packet::Data(packet) )
             },
+            OutgoingPacket::OnionRequest(packet) => {
+                self.send_to_server( Packet::OnionRequest(packet) )
+            },
         }
     }
     /// Send packet to server
@@ -132,10 +148,13 @@ impl Connection {
 #[cfg(test)]
 mod tests {
     use toxcore::crypto_core::*;
+    use toxcore::onion::packet::*;
     use toxcore::tcp::client::connection::*;
     use futures::prelude::*;
     use futures::sync::mpsc;
 
+    use std::net::{IpAddr, Ipv4Addr};
+
     fn create_connection_channels()
         -> (Connection, mpsc::UnboundedReceiver<Packet>, mpsc::UnboundedReceiver<IncomingPacket>) {
         let (server_tx, server_rx) = mpsc::unbounded();
@@ -212,6 +231,27 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn client_onion_request() {
+        let (connection, server_rx, _callback_rx) = create_connection_channels();
+
+        let request = OnionRequest {
+            nonce: gen_nonce(),
+            ip_port: IpPort {
+                protocol: ProtocolType::TCP,
+                ip_addr: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                port: 12345,
+            },
+            temporary_pk: gen_keypair().0,
+            payload: vec![13; 170]
+        };
+        let outgoing_packet = OutgoingPacket::OnionRequest(request.clone());
+        connection.handle_from_client(outgoing_packet).wait().unwrap();
+
+        let (incoming_packet, _tail) = server_rx.into_future().wait().unwrap();
+        assert_eq!(incoming_packet.unwrap(), Packet::OnionRequest(request));
+    }
+
     // server tests
     #[test]
     fn server_route_request() {
@@ -332,6 +372,40 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn server_onion_request() {
+        let (connection, _server_rx, _callback_rx) = create_connection_channels();
+
+        let packet = Packet::OnionRequest(OnionRequest {
+            nonce: gen_nonce(),
+            ip_port: IpPort {
+                protocol: ProtocolType::TCP,
+                ip_addr: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                port: 12345,
+            },
+            temporary_pk: gen_keypair().0,
+            payload: vec![13; 170]
+        });
+        let handle_res = connection.handle_from_server(packet).wait();
+        assert!(handle_res.is_err());
+    }
+    #[test]
+    fn server_onion_response() {
+        let (connection, _server_rx, callback_rx) = create_connection_channels();
+
+        let response = OnionResponse {
+            payload: InnerOnionResponse::OnionAnnounceResponse(OnionAnnounceResponse {
+                sendback_data: 12345,
+                nonce: gen_nonce(),
+                payload: vec![42; 123]
+            })
+        };
+        let packet = Packet::OnionResponse(response.clone());
+        connection.handle_from_server(packet).wait().unwrap();
+        let (incoming_packet, _tail) = callback_rx.into_future().wait().unwrap();
+        assert_eq!(incoming_packet.unwrap(), IncomingPacket::OnionResponse(response));
+    }
+
     // test lost rx parts
     #[test]
     fn server_disconnected() {
@@ -3,8 +3,10 @@
 
 mod connection;
 mod processor;
+mod relay;
 
 pub use self::connection::Connection;
 pub use self::connection::IncomingPacket;
 pub use self::connection::OutgoingPacket;
 pub use self::processor::ClientProcessor;
+pub use self::relay::{RelayClient, RelayStatus};
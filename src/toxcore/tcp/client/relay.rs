@@ -0,0 +1,769 @@
+/*! The implementation of managed TCP relay client that keeps connection to a
+relay alive.
+
+`RelayClient` connects to the relay, sends `PingRequest`s and verifies
+`PongResponse`s. When the relay doesn't respond to pings or the connection is
+lost it reconnects with exponential backoff and re-issues `RouteRequest`s for
+all keys that were requested before. Changes of the relay status are reported
+on the status channel.
+*/
+
+use std::collections::{HashMap, HashSet};
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::{future, stream, Future, Sink, Stream};
+use futures::future::Loop;
+use failure::Fail;
+use futures::sync::mpsc;
+use parking_lot::RwLock;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::timer::{Delay, Interval, Timeout};
+use tokio::timer::timeout::{Error as TimeoutError};
+use tokio_codec::Framed;
+
+use toxcore::crypto_core::*;
+use toxcore::io_tokio::*;
+use toxcore::tcp::client::connection::*;
+use toxcore::tcp::codec::Codec;
+use toxcore::tcp::handshake::make_client_handshake;
+use toxcore::tcp::packet::*;
+use toxcore::tcp::proxy::{self, Proxy};
+use toxcore::tcp::websocket;
+use toxcore::time::*;
+use toxcore::utils::gen_ping_id;
+
+/// Interval in seconds for sending TCP `PingRequest` to the relay
+pub const TCP_PING_FREQUENCY: u64 = 30;
+/// Timeout in seconds for waiting `PongResponse` from the relay
+pub const TCP_PING_TIMEOUT: u64 = 10;
+/// Initial delay in seconds before reconnection to the relay
+pub const TCP_RECONNECT_BASE_DELAY: u64 = 1;
+/// Maximum delay in seconds before reconnection to the relay
+pub const TCP_RECONNECT_MAX_DELAY: u64 = 120;
+/// Default timeout in seconds for establishing connection to the relay
+/// including proxy negotiation, WebSocket upgrade and the handshake
+pub const TCP_CONNECT_TIMEOUT: u64 = 10;
+/// Time in seconds the connection to the relay should stay alive to be
+/// considered stable so that reconnection starts again with the base delay
+pub const TCP_STABLE_CONNECTION_TIME: u64 = 60;
+
+/// Interval in seconds for checking whether ping should be sent or the relay
+/// is timed out
+const TCP_PING_CHECK_INTERVAL: u64 = 1;
+
+/// Status of connection to the relay
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RelayStatus {
+    /// Connection to the relay is being established
+    Connecting,
+    /// Handshake is completed and the relay responds to pings
+    Connected,
+    /// Connection is lost, reconnection will be made after a delay
+    Disconnected,
+}
+
+/// Mutable state of `RelayClient`
+struct RelayState {
+    /// Current status of connection to the relay
+    status: RelayStatus,
+    /// The channel side to send packets to the relay. `None` if we are not
+    /// connected
+    to_server_tx: Option<mpsc::UnboundedSender<Packet>>,
+    /// Keys that we requested routes to with `RouteRequest`
+    routes: HashSet<PublicKey>,
+    /// Connection ids received in `RouteResponse`s
+    connection_ids: HashMap<u8, PublicKey>,
+    /// Id of the last sent `PingRequest` or 0 if we don't wait for response
+    ping_id: u64,
+    /// Last time sent `PingRequest`
+    last_pinged: Instant,
+    /// Last time received correct `PongResponse` or connected to the relay
+    last_pong_resp: Instant,
+    /// Number of failed connection attempts in a row
+    reconnect_attempts: u32,
+    /// Time when the handshake with the relay was completed. `None` if we
+    /// are not connected
+    connected_time: Option<Instant>,
+}
+
+/** Managed connection to a TCP relay that pings the relay and reconnects
+when the connection is lost.
+*/
+#[derive(Clone)]
+pub struct RelayClient {
+    /// Address of the relay
+    addr: SocketAddr,
+    /// `PublicKey` of the relay
+    relay_pk: PublicKey,
+    /// Our DHT `PublicKey`
+    pk: PublicKey,
+    /// Our DHT `SecretKey`
+    sk: SecretKey,
+    /// The channel side to send packets received from the relay to handle
+    /// them in callbacks
+    incoming_tx: mpsc::UnboundedSender<IncomingPacket>,
+    /// The channel side to report status changes
+    status_tx: mpsc::UnboundedSender<RelayStatus>,
+    /// Proxy that is used to connect to the relay
+    proxy: Option<Proxy>,
+    /// Request path if the relay is connected via WebSocket
+    websocket_path: Option<String>,
+    /// Timeout for establishing connection to the relay
+    connect_timeout: Duration,
+    state: Arc<RwLock<RelayState>>,
+}
+
+impl RelayClient {
+    /** Create new `RelayClient`. It doesn't connect until the future
+    returned by `run` is polled.
+    */
+    pub fn new(addr: SocketAddr,
+               relay_pk: PublicKey,
+               pk: PublicKey,
+               sk: SecretKey,
+               incoming_tx: mpsc::UnboundedSender<IncomingPacket>,
+               status_tx: mpsc::UnboundedSender<RelayStatus>) -> RelayClient {
+        let state = RelayState {
+            status: RelayStatus::Disconnected,
+            to_server_tx: None,
+            routes: HashSet::new(),
+            connection_ids: HashMap::new(),
+            ping_id: 0,
+            last_pinged: clock_now(),
+            last_pong_resp: clock_now(),
+            reconnect_attempts: 0,
+            connected_time: None,
+        };
+        RelayClient {
+            addr,
+            relay_pk,
+            pk,
+            sk,
+            incoming_tx,
+            status_tx,
+            proxy: None,
+            websocket_path: None,
+            connect_timeout: Duration::from_secs(TCP_CONNECT_TIMEOUT),
+            state: Arc::new(RwLock::new(state)),
+        }
+    }
+
+    /// Set proxy that will be used for all subsequent connections to the
+    /// relay
+    pub fn set_proxy(&mut self, proxy: Option<Proxy>) {
+        self.proxy = proxy;
+    }
+
+    /// Connect to the relay via WebSocket with the given request path
+    /// instead of raw TCP. `None` switches back to raw TCP.
+    pub fn set_websocket_path(&mut self, websocket_path: Option<String>) {
+        self.websocket_path = websocket_path;
+    }
+
+    /// Set timeout for establishing connection to the relay including proxy
+    /// negotiation, WebSocket upgrade and the handshake
+    pub fn set_connect_timeout(&mut self, connect_timeout: Duration) {
+        self.connect_timeout = connect_timeout;
+    }
+
+    /// Address of the relay
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// `PublicKey` of the relay
+    pub fn relay_pk(&self) -> PublicKey {
+        self.relay_pk
+    }
+
+    /// Current status of connection to the relay
+    pub fn status(&self) -> RelayStatus {
+        self.state.read().status
+    }
+
+    /// Check if we have connection to the relay
+    pub fn is_connected(&self) -> bool {
+        self.status() == RelayStatus::Connected
+    }
+
+    /** Send the packet to the relay. `RouteRequest`s are remembered and sent
+    again after reconnection. Sending fails if we are not connected.
+    */
+    pub fn send_packet(&self, packet: OutgoingPacket) -> IoFuture<()> {
+        let mut state = self.state.write();
+
+        match packet {
+            OutgoingPacket::RouteRequest(ref packet) => {
+                state.routes.insert(packet.pk);
+            },
+            OutgoingPacket::DisconnectNotification(ref packet) => {
+                if let Some(pk) = state.connection_ids.remove(&packet.connection_id) {
+                    state.routes.remove(&pk);
+                }
+            },
+            _ => {},
+        }
+
+        if let Some(ref to_server_tx) = state.to_server_tx {
+            Connection::new(to_server_tx.clone(), self.incoming_tx.clone())
+                .handle_from_client(packet)
+        } else {
+            Box::new(future::err(
+                Error::new(ErrorKind::Other, "Relay is not connected")
+            ))
+        }
+    }
+
+    /** Run the client. It connects to the relay and reconnects with
+    exponential backoff every time the connection is lost. The future never
+    resolves so it should be dropped to stop the client.
+    */
+    pub fn run(self) -> IoFuture<()> {
+        let future = future::loop_fn(self, |client| {
+            client.run_connection().then(move |result| {
+                if let Err(ref e) = result {
+                    debug!("Connection to TCP relay {} is lost: {}", client.addr, e);
+                }
+                let delay = client.disconnected();
+                Delay::new(clock_now() + delay)
+                    .map_err(|e| Error::new(ErrorKind::Other, format!("Reconnect timer error: {:?}", e)))
+                    .map(move |()| Loop::Continue(client))
+            })
+        });
+
+        Box::new(future)
+    }
+
+    /// Change the status and report it on the status channel
+    fn set_status(&self, state: &mut RelayState, status: RelayStatus) {
+        if state.status != status {
+            state.status = status;
+            // ignore error since nobody may be interested in status
+            let _ = self.status_tx.unbounded_send(status);
+        }
+    }
+
+    /** Register the connection to the relay. Reset ping state and send
+    `RouteRequest`s for all previously requested keys.
+    */
+    fn connected(&self, to_server_tx: mpsc::UnboundedSender<Packet>) -> IoFuture<()> {
+        let mut state = self.state.write();
+
+        state.ping_id = 0;
+        state.last_pinged = clock_now();
+        state.last_pong_resp = clock_now();
+        state.connected_time = Some(clock_now());
+        state.connection_ids.clear();
+        state.to_server_tx = Some(to_server_tx.clone());
+        self.set_status(&mut state, RelayStatus::Connected);
+
+        let route_requests = state.routes.iter()
+            .map(|&pk| Packet::RouteRequest(RouteRequest { pk }))
+            .collect::<Vec<_>>();
+
+        send_all_to(&to_server_tx, stream::iter_ok(route_requests))
+    }
+
+    /** Unregister the connection to the relay and return the delay before
+    the next connection attempt. The backoff is reset only if the connection
+    was stable so that a relay that drops us right after the handshake isn't
+    reconnected to with the base delay over and over.
+    */
+    fn disconnected(&self) -> Duration {
+        let mut state = self.state.write();
+
+        let stable = state.connected_time.take().map_or(false, |connected_time|
+            clock_elapsed(connected_time) >= Duration::from_secs(TCP_STABLE_CONNECTION_TIME)
+        );
+        if stable {
+            state.reconnect_attempts = 0;
+        }
+
+        state.to_server_tx = None;
+        state.connection_ids.clear();
+        self.set_status(&mut state, RelayStatus::Disconnected);
+
+        let delay = reconnect_delay(state.reconnect_attempts);
+        state.reconnect_attempts = state.reconnect_attempts.saturating_add(1);
+        delay
+    }
+
+    /// Connect to the relay, make the handshake and process packets until
+    /// the connection is lost
+    fn run_connection(&self) -> IoFuture<()> {
+        {
+            let mut state = self.state.write();
+            self.set_status(&mut state, RelayStatus::Connecting);
+        }
+
+        // all steps before the handshake is completed share the same
+        // deadline so that a stalled relay or proxy can't hang the client
+        let deadline = clock_now() + self.connect_timeout;
+
+        let self_c = self.clone();
+        // keep `ProxyError` as the inner error so that it can be inspected
+        let socket = proxy::connect(self.proxy.as_ref(), &self.addr)
+            .map_err(|e| Error::new(ErrorKind::Other, e.compat()));
+
+        if let Some(ref path) = self.websocket_path {
+            let (host, path) = (self.addr.to_string(), path.clone());
+            let socket = socket
+                .and_then(move |socket| websocket::connect(socket, &host, &path));
+            Box::new(Timeout::new_at(socket, deadline)
+                .map_err(connect_timeout_error)
+                .and_then(move |socket| self_c.run_stream(socket, deadline)))
+        } else {
+            Box::new(Timeout::new_at(socket, deadline)
+                .map_err(connect_timeout_error)
+                .and_then(move |socket| self_c.run_stream(socket, deadline)))
+        }
+    }
+
+    /// Make the handshake on the connected stream and process packets until
+    /// the connection is lost. The handshake should be completed before the
+    /// deadline.
+    fn run_stream<S>(&self, socket: S, deadline: Instant) -> IoFuture<()>
+        where S: AsyncRead + AsyncWrite + Send + 'static
+    {
+        let (pk, sk, relay_pk) = (self.pk, self.sk.clone(), self.relay_pk);
+        let self_c = self.clone();
+
+        let handshake = make_client_handshake(socket, &pk, &sk, &relay_pk);
+        let future = Timeout::new_at(handshake, deadline)
+            .map_err(connect_timeout_error)
+            .and_then(move |(socket, channel)| {
+                debug!("Handshake with TCP relay {} is completed", self_c.addr);
+                let secure_socket = Framed::new(socket, Codec::new(channel));
+                let (to_server, from_server) = secure_socket.split();
+                let (to_server_tx, to_server_rx) = mpsc::unbounded();
+
+                let connection = Connection::new(to_server_tx.clone(), self_c.incoming_tx.clone());
+                let route_requests = self_c.connected(to_server_tx);
+
+                let self_c_c = self_c.clone();
+                let reader = from_server
+                    .map_err(|e| Error::new(ErrorKind::Other, format!("Decode packet error: {:?}", e)))
+                    .for_each(move |packet| self_c_c.handle_packet(&connection, packet));
+
+                let writer = to_server_rx
+                    .map_err(|()| unreachable!("rx can't fail"))
+                    .fold(to_server, |to_server, packet|
+                        to_server.send(packet)
+                            .map_err(|e| Error::new(ErrorKind::Other, format!("Encode packet error: {:?}", e)))
+                    )
+                    .map(|_to_server| ());
+
+                let pinger = Interval::new(Instant::now(), Duration::from_secs(TCP_PING_CHECK_INTERVAL))
+                    .map_err(|e| Error::new(ErrorKind::Other, format!("Ping timer error: {:?}", e)))
+                    .for_each(move |_instant| self_c.send_ping());
+
+                route_requests
+                    .and_then(|()|
+                        reader
+                            .select(writer).map(|_| ()).map_err(|(e, _)| e)
+                            .select(pinger).map(|_| ()).map_err(|(e, _)| e)
+                    )
+            });
+
+        Box::new(future)
+    }
+
+    /// Handle packet received from the relay
+    fn handle_packet(&self, connection: &Connection, packet: Packet) -> IoFuture<()> {
+        match packet {
+            Packet::PongResponse(ref packet) => {
+                self.handle_pong_response(packet);
+                return Box::new(future::ok(()))
+            },
+            Packet::RouteResponse(ref packet) => {
+                let mut state = self.state.write();
+                if packet.connection_id != 0 && state.routes.contains(&packet.pk) {
+                    state.connection_ids.insert(packet.connection_id, packet.pk);
+                }
+            },
+            _ => {},
+        }
+        connection.handle_from_server(packet)
+    }
+
+    /// Check that `PongResponse` corresponds to the last `PingRequest`
+    fn handle_pong_response(&self, packet: &PongResponse) {
+        let mut state = self.state.write();
+        if state.ping_id != 0 && packet.ping_id == state.ping_id {
+            state.ping_id = 0;
+            state.last_pong_resp = clock_now();
+        } else {
+            debug!("Unexpected PongResponse from TCP relay {}: {}", self.addr, packet.ping_id);
+        }
+    }
+
+    /** Send `PingRequest` to the relay if ping interval is passed. Return an
+    error if the relay didn't respond to the last ping in time.
+    */
+    fn send_ping(&self) -> IoFuture<()> {
+        let mut state = self.state.write();
+
+        if state.ping_id != 0 && clock_elapsed(state.last_pinged) > Duration::from_secs(TCP_PING_TIMEOUT) {
+            return Box::new(future::err(
+                Error::new(ErrorKind::Other, "Relay didn't respond to PingRequest")
+            ))
+        }
+
+        if state.ping_id != 0 || clock_elapsed(state.last_pong_resp) < Duration::from_secs(TCP_PING_FREQUENCY) {
+            return Box::new(future::ok(()))
+        }
+
+        let to_server_tx = if let Some(ref to_server_tx) = state.to_server_tx {
+            to_server_tx.clone()
+        } else {
+            return Box::new(future::ok(()))
+        };
+
+        let ping_id = gen_ping_id();
+        state.ping_id = ping_id;
+        state.last_pinged = clock_now();
+
+        send_to(&to_server_tx, Packet::PingRequest(PingRequest { ping_id }))
+    }
+}
+
+/// Convert error of a connection step limited by the connect timeout
+fn connect_timeout_error(error: TimeoutError<Error>) -> Error {
+    if error.is_elapsed() {
+        Error::new(ErrorKind::TimedOut, "Connection to the relay timed out")
+    } else if error.is_inner() {
+        error.into_inner().unwrap()
+    } else {
+        Error::new(ErrorKind::Other, format!("Connection timer error: {:?}", error))
+    }
+}
+
+/** Get delay before the connection attempt with the given number. The delay
+grows exponentially up to `TCP_RECONNECT_MAX_DELAY` and is randomized in the
+range from half of its value to its full value so that clients don't
+reconnect to a restarted relay simultaneously.
+*/
+pub fn reconnect_delay(attempt: u32) -> Duration {
+    let max_delay_ms = TCP_RECONNECT_MAX_DELAY * 1000;
+    let delay_ms = (TCP_RECONNECT_BASE_DELAY * 1000)
+        .checked_shl(attempt.min(32))
+        .map_or(max_delay_ms, |delay_ms| delay_ms.min(max_delay_ms));
+    let jitter_ms = random_u64() % (delay_ms / 2 + 1);
+    Duration::from_millis(delay_ms - jitter_ms)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use failure::Compat;
+    use tokio;
+    use tokio::net::TcpListener;
+    use tokio_executor;
+    use tokio_timer::clock::*;
+
+    use toxcore::tcp::proxy::ProxyError;
+    use toxcore::tcp::server::{Server, ServerExt, ShutdownHandle};
+
+    fn create_client() -> (RelayClient, mpsc::UnboundedReceiver<IncomingPacket>, mpsc::UnboundedReceiver<RelayStatus>) {
+        let (relay_pk, _relay_sk) = gen_keypair();
+        let (pk, sk) = gen_keypair();
+        let (incoming_tx, incoming_rx) = mpsc::unbounded();
+        let (status_tx, status_rx) = mpsc::unbounded();
+        let client = RelayClient::new("127.0.0.1:12345".parse().unwrap(), relay_pk, pk, sk, incoming_tx, status_tx);
+        (client, incoming_rx, status_rx)
+    }
+
+    #[test]
+    fn reconnect_delay_grows() {
+        for attempt in 0 .. 40 {
+            let delay = reconnect_delay(attempt);
+            let expected = Duration::from_secs((TCP_RECONNECT_BASE_DELAY << attempt.min(32)).min(TCP_RECONNECT_MAX_DELAY));
+            assert!(delay <= expected);
+            assert!(delay >= expected / 2);
+        }
+    }
+
+    #[test]
+    fn send_packet_not_connected() {
+        let (client, _incoming_rx, _status_rx) = create_client();
+
+        let pk = gen_keypair().0;
+        let res = client.send_packet(OutgoingPacket::RouteRequest(RouteRequest { pk })).wait();
+        assert!(res.is_err());
+
+        // route is remembered to be requested after connection
+        assert!(client.state.read().routes.contains(&pk));
+    }
+
+    #[test]
+    fn connected_resends_route_requests() {
+        let (client, _incoming_rx, status_rx) = create_client();
+
+        let pk = gen_keypair().0;
+        client.send_packet(OutgoingPacket::RouteRequest(RouteRequest { pk })).wait().unwrap_err();
+
+        let (to_server_tx, to_server_rx) = mpsc::unbounded();
+        client.connected(to_server_tx).wait().unwrap();
+
+        assert!(client.is_connected());
+        let (status, _status_rx) = status_rx.into_future().wait().unwrap();
+        assert_eq!(status.unwrap(), RelayStatus::Connected);
+
+        let (packet, _to_server_rx) = to_server_rx.into_future().wait().unwrap();
+        assert_eq!(packet.unwrap(), Packet::RouteRequest(RouteRequest { pk }));
+    }
+
+    #[test]
+    fn disconnect_notification_forgets_route() {
+        let (client, _incoming_rx, _status_rx) = create_client();
+
+        let (to_server_tx, _to_server_rx) = mpsc::unbounded();
+        client.connected(to_server_tx).wait().unwrap();
+
+        let pk = gen_keypair().0;
+        client.send_packet(OutgoingPacket::RouteRequest(RouteRequest { pk })).wait().unwrap();
+
+        let (incoming_tx, _incoming_rx) = mpsc::unbounded();
+        let (server_tx, _server_rx) = mpsc::unbounded();
+        let connection = Connection::new(server_tx, incoming_tx);
+        client.handle_packet(&connection, Packet::RouteResponse(RouteResponse { pk, connection_id: 16 })).wait().unwrap();
+
+        client.send_packet(OutgoingPacket::DisconnectNotification(DisconnectNotification { connection_id: 16 })).wait().unwrap();
+
+        assert!(!client.state.read().routes.contains(&pk));
+    }
+
+    #[test]
+    fn send_ping_and_check_pong() {
+        let (client, _incoming_rx, _status_rx) = create_client();
+
+        let (to_server_tx, to_server_rx) = mpsc::unbounded();
+        client.connected(to_server_tx).wait().unwrap();
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(
+            Instant::now() + Duration::from_secs(TCP_PING_FREQUENCY + 1)
+        ));
+
+        let ping_id = with_default(&clock, &mut enter, |_| {
+            client.send_ping().wait().unwrap();
+            let (packet, _to_server_rx) = to_server_rx.into_future().wait().unwrap();
+            let ping_id = unpack!(packet.unwrap(), Packet::PingRequest).ping_id;
+
+            // wrong pong is ignored
+            client.handle_pong_response(&PongResponse { ping_id: ping_id + 1 });
+            assert_eq!(client.state.read().ping_id, ping_id);
+
+            client.handle_pong_response(&PongResponse { ping_id });
+            assert_eq!(client.state.read().ping_id, 0);
+
+            ping_id
+        });
+        assert!(ping_id != 0);
+    }
+
+    #[test]
+    fn send_ping_timed_out() {
+        let (client, _incoming_rx, _status_rx) = create_client();
+
+        let (to_server_tx, _to_server_rx) = mpsc::unbounded();
+        client.connected(to_server_tx).wait().unwrap();
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(
+            Instant::now() + Duration::from_secs(TCP_PING_FREQUENCY + 1)
+        ));
+
+        with_default(&clock, &mut enter, |_| {
+            client.send_ping().wait().unwrap();
+        });
+
+        let clock = Clock::new_with_now(ConstNow(
+            Instant::now() + Duration::from_secs(TCP_PING_FREQUENCY + TCP_PING_TIMEOUT + 2)
+        ));
+
+        with_default(&clock, &mut enter, |_| {
+            assert!(client.send_ping().wait().is_err());
+        });
+    }
+
+    #[test]
+    fn disconnected_reports_status() {
+        let (client, _incoming_rx, status_rx) = create_client();
+
+        let (to_server_tx, _to_server_rx) = mpsc::unbounded();
+        client.connected(to_server_tx).wait().unwrap();
+
+        let delay = client.disconnected();
+        assert!(delay <= Duration::from_secs(TCP_RECONNECT_BASE_DELAY));
+        assert_eq!(client.state.read().reconnect_attempts, 1);
+        assert!(client.send_packet(OutgoingPacket::Data(Data { connection_id: 16, data: vec![42; 123] })).wait().is_err());
+
+        let statuses = status_rx.take(2).collect().wait().unwrap();
+        assert_eq!(statuses, vec![RelayStatus::Connected, RelayStatus::Disconnected]);
+    }
+
+    #[test]
+    fn disconnected_after_stable_connection_resets_backoff() {
+        let (client, _incoming_rx, _status_rx) = create_client();
+        client.state.write().reconnect_attempts = 5;
+
+        let (to_server_tx, _to_server_rx) = mpsc::unbounded();
+        client.connected(to_server_tx).wait().unwrap();
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(
+            Instant::now() + Duration::from_secs(TCP_STABLE_CONNECTION_TIME + 1)
+        ));
+
+        let delay = with_default(&clock, &mut enter, |_| client.disconnected());
+        assert!(delay <= Duration::from_secs(TCP_RECONNECT_BASE_DELAY));
+        assert_eq!(client.state.read().reconnect_attempts, 1);
+    }
+
+    #[test]
+    fn disconnected_after_unstable_connection_keeps_backoff() {
+        let (client, _incoming_rx, _status_rx) = create_client();
+        client.state.write().reconnect_attempts = 5;
+
+        let (to_server_tx, _to_server_rx) = mpsc::unbounded();
+        client.connected(to_server_tx).wait().unwrap();
+
+        let delay = client.disconnected();
+        assert!(delay >= Duration::from_secs(TCP_RECONNECT_BASE_DELAY << 5) / 2);
+        assert_eq!(client.state.read().reconnect_attempts, 6);
+    }
+
+    #[test]
+    fn run_connection_proxy_error() {
+        let (server_pk, _server_sk) = gen_keypair();
+        let (client_pk, client_sk) = gen_keypair();
+
+        // nobody listens on this port
+        let proxy = Proxy::Socks5 { addr: "127.0.0.1:12362".parse().unwrap(), auth: None };
+
+        let (incoming_tx, _incoming_rx) = mpsc::unbounded();
+        let (status_tx, _status_rx) = mpsc::unbounded();
+        let mut client = RelayClient::new("1.2.3.4:33445".parse().unwrap(), server_pk, client_pk, client_sk, incoming_tx, status_tx);
+        client.set_proxy(Some(proxy));
+
+        let future = client.run_connection().then(|res| {
+            let error = res.unwrap_err();
+            let proxy_error = error.get_ref()
+                .and_then(|e| e.downcast_ref::<Compat<ProxyError>>())
+                .map(|e| e.get_ref());
+            match proxy_error {
+                Some(ProxyError::ConnectError { .. }) => {},
+                _ => panic!("Unexpected error: {:?}", error),
+            }
+            Ok(())
+        });
+
+        tokio::run(future);
+    }
+
+    #[test]
+    fn run_connection_timed_out() {
+        let (server_pk, _server_sk) = gen_keypair();
+        let (client_pk, client_sk) = gen_keypair();
+
+        // the relay accepts the connection but never responds to the handshake
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server_future = listener.incoming()
+            .into_future()
+            .map_err(|(e, _)| e)
+            .map(|(socket, _incoming)| socket);
+
+        let (incoming_tx, _incoming_rx) = mpsc::unbounded();
+        let (status_tx, _status_rx) = mpsc::unbounded();
+        let mut client = RelayClient::new(addr, server_pk, client_pk, client_sk, incoming_tx, status_tx);
+        client.set_connect_timeout(Duration::from_millis(100));
+
+        let future = server_future
+            .join(client.run_connection().then(|res| {
+                assert_eq!(res.unwrap_err().kind(), ErrorKind::TimedOut);
+                Ok::<(), Error>(())
+            }))
+            .map(|_| ()).map_err(|e| panic!("Unexpected error: {:?}", e));
+
+        tokio::run(future);
+    }
+
+    #[test]
+    fn run_reconnect() {
+        let (server_pk, server_sk) = gen_keypair();
+        let (client_pk, client_sk) = gen_keypair();
+        let friend_pk = gen_keypair().0;
+
+        let addr = "127.0.0.1:12352".parse().unwrap();
+
+        let (shutdown_handle, shutdown_signal) = ShutdownHandle::new();
+
+        let server = Server::new();
+        let server_c = server.clone();
+        let listener = TcpListener::bind(&addr).unwrap();
+        let server_future = server.run_with_shutdown(listener, server_sk, shutdown_signal, Duration::from_secs(1))
+            .map_err(|e| Error::new(ErrorKind::Other, format!("{:?}", e)));
+
+        let (incoming_tx, incoming_rx) = mpsc::unbounded();
+        let (status_tx, status_rx) = mpsc::unbounded();
+        let client = RelayClient::new(addr, server_pk, client_pk, client_sk, incoming_tx, status_tx);
+        let client_c = client.clone();
+
+        let statuses = status_rx
+            .map_err(|()| unreachable!("rx can't fail"))
+            .filter(|status| *status == RelayStatus::Connected);
+        let incoming_rx = incoming_rx
+            .map_err(|()| unreachable!("rx can't fail"));
+        let route_response = Packet::RouteResponse(RouteResponse { pk: friend_pk, connection_id: 16 });
+        let route_response_c = route_response.clone();
+
+        let test_future = statuses.into_future()
+            .map_err(|(e, _)| e)
+            .and_then(move |(_status, statuses)| {
+                client_c.send_packet(OutgoingPacket::RouteRequest(RouteRequest { pk: friend_pk }))
+                    .map(move |()| statuses)
+            })
+            .and_then(|statuses|
+                incoming_rx.into_future()
+                    .map_err(|(e, _)| e)
+                    .map(move |(packet, incoming_rx)| {
+                        assert_eq!(Packet::RouteResponse(unpack!(packet.unwrap(), IncomingPacket::RouteResponse)), route_response);
+                        (statuses, incoming_rx)
+                    })
+            )
+            .and_then(move |(statuses, incoming_rx)| {
+                // the relay drops us
+                server_c.disconnect_client(&client_pk)
+                    .map(move |()| (statuses, incoming_rx))
+            })
+            .and_then(|(statuses, incoming_rx)|
+                // wait for reconnection
+                statuses.into_future()
+                    .map_err(|(e, _)| e)
+                    .map(move |(status, _statuses)| {
+                        assert_eq!(status.unwrap(), RelayStatus::Connected);
+                        incoming_rx
+                    })
+            )
+            .and_then(|incoming_rx|
+                // route is requested again after reconnection
+                incoming_rx.into_future().map_err(|(e, _)| e)
+            )
+            .map(move |(packet, _incoming_rx)| {
+                assert_eq!(Packet::RouteResponse(unpack!(packet.unwrap(), IncomingPacket::RouteResponse)), route_response_c);
+                shutdown_handle.shutdown();
+            });
+
+        let future = server_future
+            .join(client.run().select(test_future).map(|_| ()).map_err(|(e, _)| e))
+            .map(|_| ()).map_err(|e| panic!("Unexpected error: {:?}", e));
+
+        tokio::run(future);
+    }
+}
@@ -24,7 +24,9 @@ pub enum IncomingPacket {
     /// oob from other client with sender pk
     OobReceive(OobReceive),
     /// data from connected client
-    Data(Data)
+    Data(Data),
+    /// onion response from UDP socket of the server
+    OnionResponse(OnionResponse)
 }
 
 /** A packet should be sent to server.
@@ -39,7 +41,9 @@ pub enum OutgoingPacket {
     /// send oob by pk
     OobSend(OobSend),
     /// send data to connected client by id
-    Data(Data)
+    Data(Data),
+    /// ask server to send onion request to UDP socket
+    OnionRequest(OnionRequest)
 }
 
 /** Connection between server and net_crypto
@@ -84,7 +88,8 @@ impl Connection {
                 ), connection_id)
             },
             Packet::PongResponse(_packet) => {
-                // TODO check ping_id
+                // this connection never sends `PingRequest`s so the response
+                // is unsolicited and is ignored
                 Box::new( future::ok(()) )
             },
             Packet::OobSend(_packet) => {
@@ -99,7 +104,15 @@ impl Connection {
             Packet::Data(packet) => {
                 self.send_to_net_crypto( IncomingPacket::Data(packet), connection_id )
             },
-            _ => unimplemented!() // TODO onion
+            Packet::OnionRequest(_packet) => {
+                Box::new( future::err(
+                    Error::new(ErrorKind::Other,
+                               "Server must not send OnionRequest to client"
+                    )))
+            },
+            Packet::OnionResponse(packet) => {
+                self.send_to_net_crypto( IncomingPacket::OnionResponse(packet), connection_id )
+            },
         }
     }
     /** Handle packet from net_crypto
@@ -119,6 +132,9 @@ impl Connection {
             OutgoingPacket::Data(packet) => {
                 self.send_to_server( Packet::Data(packet), connection_id )
             },
+            OutgoingPacket::OnionRequest(packet) => {
+                self.send_to_server( Packet::OnionRequest(packet), connection_id )
+            },
         }
     }
     /// Send packet to server
@@ -135,8 +151,11 @@ impl Connection {
 mod tests {
     use super::*;
     use toxcore::crypto_core::*;
+    use toxcore::onion::packet::*;
     use futures::prelude::*;
 
+    use std::net::{IpAddr, Ipv4Addr};
+
     fn create_connection_channels()
         -> (Connection, mpsc::UnboundedReceiver<(Packet, PublicKey)>, mpsc::UnboundedReceiver<(IncomingPacket, PublicKey)>) {
         let (server_tx, server_rx) = mpsc::unbounded();
@@ -229,6 +248,30 @@ mod tests {
     }
 
     // server tests
+    #[test]
+    fn net_crypto_onion_request() {
+        let (connection, server_rx, _callback_rx) = create_connection_channels();
+
+        let request = OnionRequest {
+            nonce: gen_nonce(),
+            ip_port: IpPort {
+                protocol: ProtocolType::TCP,
+                ip_addr: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                port: 12345,
+            },
+            temporary_pk: gen_keypair().0,
+            payload: vec![13; 170]
+        };
+        let outgoing_packet = OutgoingPacket::OnionRequest(request.clone());
+
+        let connection_id = gen_keypair().0;
+
+        connection.handle_from_net_crypto(outgoing_packet, connection_id.clone()).wait().unwrap();
+
+        let (incoming_packet, _tail) = server_rx.into_future().wait().unwrap();
+        assert_eq!(incoming_packet.unwrap(), (Packet::OnionRequest(request), connection_id));
+    }
+
     #[test]
     fn server_route_request() {
         let (connection, _server_rx, _callback_rx) = create_connection_channels();
@@ -378,6 +421,47 @@ mod tests {
         ), connection_id));
     }
 
+    #[test]
+    fn server_onion_request() {
+        let (connection, _server_rx, _callback_rx) = create_connection_channels();
+
+        let packet = Packet::OnionRequest(OnionRequest {
+            nonce: gen_nonce(),
+            ip_port: IpPort {
+                protocol: ProtocolType::TCP,
+                ip_addr: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                port: 12345,
+            },
+            temporary_pk: gen_keypair().0,
+            payload: vec![13; 170]
+        });
+
+        let connection_id = gen_keypair().0;
+
+        let handle_res = connection.handle_from_server(packet, connection_id).wait();
+        assert!(handle_res.is_err());
+    }
+
+    #[test]
+    fn server_onion_response() {
+        let (connection, _server_rx, callback_rx) = create_connection_channels();
+
+        let response = OnionResponse {
+            payload: InnerOnionResponse::OnionAnnounceResponse(OnionAnnounceResponse {
+                sendback_data: 12345,
+                nonce: gen_nonce(),
+                payload: vec![42; 123]
+            })
+        };
+        let packet = Packet::OnionResponse(response.clone());
+
+        let connection_id = gen_keypair().0;
+
+        connection.handle_from_server(packet, connection_id.clone()).wait().unwrap();
+        let (incoming_packet, _tail) = callback_rx.into_future().wait().unwrap();
+        assert_eq!(incoming_packet.unwrap(), (IncomingPacket::OnionResponse(response), connection_id));
+    }
+
     // test lost rx parts
     #[test]
     fn server_disconnected() {
@@ -17,34 +17,44 @@ Defintion of struct names.
          set of ClientConnection(HashMap)
          set of Connection(HashMap)
      ClientConnection has
-         object of TcpClient processor
-         IP, port, PK : to save for sleeping status
+         object of RelayClient that keeps connection to a relay alive
+         sender that stops the relay when dropped
      Connection has
          object of 3 to 6 ConnToRelay connections for redundancy
      ConnToRelay has
-         id_of_client as a key to ClientConnection hashmap
+         PK of the relay as a key to ClientConnection hashmap
          connection_id of Routing Response packet
 
+Connections periodically checks every Connection. If it has less than
+`RECOMMENDED_FRIEND_TCP_CONNECTIONS` online relays, RouteRequests are sent via
+other connected relays up to `MAX_FRIEND_TCP_CONNECTIONS`. If it has enough
+online relays, extra relays are dropped. When all slots are taken, relays
+that refused to make a route or on which the friend didn't get online in
+`RELAY_LINK_TIMEOUT` are replaced with other connected relays. Data is sent via
+the relay that has been online for the longest time. When a relay is
+disconnected, its connections are reset and another relay is used.
+
 */
 
 use parking_lot::RwLock;
 
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use std::collections::HashMap;
-use std::net::{SocketAddr, IpAddr, Ipv4Addr};
+use std::net::SocketAddr;
 
-use futures::sync::mpsc;
+use futures::sync::{mpsc, oneshot};
 use futures::{future, Future, Sink, Stream};
 use failure::{Error, err_msg};
-use tokio_codec::Framed;
-use tokio::net::TcpStream;
+use tokio::timer::Interval;
 
 use toxcore::time::*;
 use toxcore::crypto_core::*;
-use toxcore::tcp::codec::*;
-use toxcore::tcp::handshake::make_client_handshake;
+use toxcore::dht::packed_node::PackedNode;
+use toxcore::tcp::client::{self, RelayClient, RelayStatus};
+use toxcore::tcp::connections::connection::{IncomingPacket, OutgoingPacket};
 use toxcore::tcp::packet::*;
+use toxcore::tcp::proxy::Proxy;
 
 /// The amount of maximum connections for each friend.
 pub const MAX_FRIEND_TCP_CONNECTIONS: usize =  6;
@@ -53,19 +63,28 @@ pub const MAX_FRIEND_TCP_CONNECTIONS: usize =  6;
 ///   NOTE: Must be at most (MAX_FRIEND_TCP_CONNECTIONS / 2).
 pub const RECOMMENDED_FRIEND_TCP_CONNECTIONS: usize =  MAX_FRIEND_TCP_CONNECTIONS / 2;
 
+/// Interval in seconds for checking connections to friends.
+const CONNECTIONS_CHECK_INTERVAL: u64 = 1;
+
+/// Time in seconds after which a relay on which the friend is not online can
+/// be replaced with another relay.
+pub const RELAY_LINK_TIMEOUT: u64 = 10;
+
 /// Status of connection to TcpRelay.
-#[derive(PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum ConnectionStatus {
-    /// Uninitialized state.
+    /// RouteRequest is sent but RouteResponse is not received yet.
     None,
-    /// Connection is registered internally.
+    /// RouteResponse is received, the friend is not connected to the relay.
     Registered,
-    /// Connection is online.
+    /// The friend is connected to the relay.
     Online,
+    /// The relay refused to make a route to the friend.
+    Refused,
 }
 
 /// Status of connection to a friend.
-#[derive(PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum ClientConnectionStatus {
     /// Uninitialized state.
     None,
@@ -79,175 +98,595 @@ pub enum ClientConnectionStatus {
 
 /// Main struct for TCP connections.
 /// Holds key_pair for TCP connections, set of TCP connections to relay,
-/// set of connections of TcpClient.
+/// set of connections to friends.
 /// PublicKey is used as a key of HashMap to avoid ABA problem.
 #[derive(Clone)]
 pub struct Connections {
     pk: PublicKey,
     sk: SecretKey,
+    /// The channel side to send packets received from relays to net_crypto
+    incoming_tx: mpsc::UnboundedSender<(IncomingPacket, PublicKey)>,
+    /// Proxy that is used for all connections to relays
+    proxy: Option<Proxy>,
     conns_of_client: Arc<RwLock<HashMap<PublicKey, ClientConnection>>>,
     connections: Arc<RwLock<HashMap<PublicKey, Connection>>>,
 }
 
-/// Connection of a TcpClient processor object.
-#[derive(Clone)]
+/// Connection to a relay.
 pub struct ClientConnection {
-    status: ClientConnectionStatus,
-    to_local_tx: Option<mpsc::UnboundedSender<Packet>>,
-    connected_time: Instant,
-    lock_count: u32,
-    sleep_count: u32,
-    onion: bool,
-    /// used when sleep.
-    ip: IpAddr,
-    /// used when sleep.
-    port: u16,
-    /// used when sleep.
-    relay_pk: PublicKey,
-    wakeup: bool,
+    client: RelayClient,
+    /// Relay is stopped when this sender is dropped.
+    _stop_tx: oneshot::Sender<()>,
 }
 
 /// Connection to a friend.
 /// It has 3 to 6 redundant connection to TcpRelays.
-#[derive(Clone, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Connection {
     status: ClientConnectionStatus,
     friend_dht_pk: PublicKey,
     conn_to_relay: Vec<ConnToRelay>,
 }
 
-/// Connection to a relay.
-#[derive(Clone, PartialEq)]
+/// Connection to a friend via a relay.
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct ConnToRelay {
     status: ConnectionStatus,
     /// Public key is used for id to avoid ABA problem.
-    id_of_client: PublicKey,
+    relay_pk: PublicKey,
     connection_id: u8,
+    /// Time when the friend became online on this relay.
+    online_since: Option<Instant>,
+    /// Time since the friend is not online on this relay.
+    offline_since: Instant,
+}
+
+impl ConnToRelay {
+    fn new(relay_pk: PublicKey) -> Self {
+        ConnToRelay {
+            status: ConnectionStatus::None,
+            relay_pk,
+            connection_id: 0,
+            online_since: None,
+            offline_since: clock_now(),
+        }
+    }
+
+    /// Status of connection to the friend via the relay.
+    pub fn status(&self) -> ConnectionStatus {
+        self.status
+    }
+
+    /// PK of the relay.
+    pub fn relay_pk(&self) -> PublicKey {
+        self.relay_pk
+    }
+
+    /// Id of connection to the friend on the relay or 0 if the relay didn't
+    /// respond to RouteRequest yet.
+    pub fn connection_id(&self) -> u8 {
+        self.connection_id
+    }
+
+    /// Reset the connection when the relay is disconnected.
+    fn reset(&mut self) {
+        self.status = ConnectionStatus::None;
+        self.connection_id = 0;
+        self.online_since = None;
+        self.offline_since = clock_now();
+    }
+
+    /// Check if the relay can be replaced with another one since it refused
+    /// to make a route or the friend didn't get online on it for too long.
+    fn is_failed(&self) -> bool {
+        match self.status {
+            ConnectionStatus::Online => false,
+            ConnectionStatus::Refused => true,
+            _ => clock_elapsed(self.offline_since) > Duration::from_secs(RELAY_LINK_TIMEOUT),
+        }
+    }
+}
+
+impl Connection {
+    fn new(friend_dht_pk: PublicKey) -> Self {
+        Connection {
+            status: ClientConnectionStatus::Valid,
+            friend_dht_pk,
+            conn_to_relay: Vec::new(),
+        }
+    }
+
+    /// Status of connection to the friend.
+    pub fn status(&self) -> ClientConnectionStatus {
+        self.status
+    }
+
+    /// DHT PK of the friend.
+    pub fn friend_dht_pk(&self) -> PublicKey {
+        self.friend_dht_pk
+    }
+
+    /// Connections to the friend via relays.
+    pub fn conn_to_relay(&self) -> &[ConnToRelay] {
+        &self.conn_to_relay
+    }
+
+    /// Number of relays on which the friend is online.
+    fn online_count(&self) -> usize {
+        self.conn_to_relay.iter()
+            .filter(|relay| relay.status == ConnectionStatus::Online)
+            .count()
+    }
+
+    /// Update status of the connection according to statuses of relays.
+    fn update_status(&mut self) {
+        if self.status == ClientConnectionStatus::Sleeping {
+            return
+        }
+        self.status = if self.online_count() > 0 {
+            ClientConnectionStatus::Connected
+        } else {
+            ClientConnectionStatus::Valid
+        };
+    }
+
+    /// Online relays sorted from the best one which is the relay that has
+    /// been online for the longest time.
+    fn online_relays(&self) -> Vec<ConnToRelay> {
+        let mut relays = self.conn_to_relay.iter()
+            .filter(|relay| relay.status == ConnectionStatus::Online)
+            .cloned()
+            .collect::<Vec<_>>();
+        relays.sort_by_key(|relay| relay.online_since);
+        relays
+    }
+
+    /// Remove extra relays when the friend is online on enough relays.
+    /// Return removed relays.
+    fn remove_extra_relays(&mut self) -> Vec<ConnToRelay> {
+        if self.online_count() < RECOMMENDED_FRIEND_TCP_CONNECTIONS {
+            return Vec::new()
+        }
+        let keep = self.online_relays().into_iter()
+            .take(RECOMMENDED_FRIEND_TCP_CONNECTIONS)
+            .map(|relay| relay.relay_pk)
+            .collect::<Vec<_>>();
+        let (kept, removed) = self.conn_to_relay.iter()
+            .partition(|relay| keep.contains(&relay.relay_pk));
+        self.conn_to_relay = kept;
+        removed
+    }
+
+    /// Remove relays that refused to make a route or on which the friend
+    /// didn't get online for too long. Return removed relays.
+    fn remove_failed_relays(&mut self) -> Vec<ConnToRelay> {
+        let (removed, kept) = self.conn_to_relay.iter()
+            .partition(|relay| relay.is_failed());
+        self.conn_to_relay = kept;
+        removed
+    }
+
+    /// Check if the friend is linked to the relay with the given connection
+    /// id.
+    fn has_route(&self, relay_pk: &PublicKey, connection_id: u8) -> bool {
+        self.conn_to_relay.iter()
+            .any(|relay| relay.relay_pk == *relay_pk && relay.connection_id != 0 && relay.connection_id == connection_id)
+    }
 }
 
 impl Connections {
     /// Create new TCP connections object.
-    pub fn new(pk: PublicKey, sk: SecretKey) -> Self {
+    pub fn new(pk: PublicKey, sk: SecretKey, incoming_tx: mpsc::UnboundedSender<(IncomingPacket, PublicKey)>) -> Self {
         Connections {
             pk,
             sk,
+            incoming_tx,
+            proxy: None,
             conns_of_client: Arc::new(RwLock::new(HashMap::new())),
             connections: Arc::new(RwLock::new(HashMap::new())),
         }
     }
 
-    fn add_entry(&self, socket: Framed<TcpStream, Codec>, server_pk: &PublicKey,
-                 from_server_tx: mpsc::UnboundedSender<(Packet, PublicKey)>) -> Box<Future<Item = (), Error = Error> + Send> {
-        let (to_server, from_server) = socket.split();
-        let (to_local_tx, to_local_rx) = mpsc::unbounded();
-        let mut connections = self.connections.write();
-        let mut conns_of_client = self.conns_of_client.write();
+    /// Set proxy that will be used for connections to relays added after
+    /// this call.
+    pub fn set_proxy(&mut self, proxy: Option<Proxy>) {
+        self.proxy = proxy;
+    }
 
-        let relay = ConnToRelay {
-            status: ConnectionStatus::None,
-            id_of_client: gen_keypair().0,
-            connection_id: 0,
-        };
+    /// Add a new relay. The returned future keeps connection to the relay
+    /// alive and should be run until the relay is removed. It resolves when
+    /// the relay is removed with `remove_relay`.
+    pub fn add_relay(&self, addr: SocketAddr, relay_pk: PublicKey) -> Box<Future<Item = (), Error = Error> + Send> {
+        self.add_relay_inner(addr, relay_pk, None)
+    }
 
-        let connection = connections.entry(*server_pk).or_insert(
-            Connection {
-                status: ClientConnectionStatus::None,
-                friend_dht_pk: server_pk.clone(),
-                conn_to_relay: vec![relay.clone()],
-            }
-        );
+    /// Add a new relay that is connected via WebSocket with the given request
+    /// path. It's handled the same way as relays added with `add_relay`.
+    pub fn add_websocket_relay(&self, addr: SocketAddr, relay_pk: PublicKey, path: String) -> Box<Future<Item = (), Error = Error> + Send> {
+        self.add_relay_inner(addr, relay_pk, Some(path))
+    }
 
-        if connection.conn_to_relay.contains(&relay) {
-            // TODO: return with proper value
-            return Box::new(future::ok(()))
-        } else {
-            connection.conn_to_relay.push(relay.clone());
+    /// Add a new relay that is connected via WebSocket if `websocket_path` is
+    /// specified or via raw TCP otherwise.
+    fn add_relay_inner(&self, addr: SocketAddr, relay_pk: PublicKey, websocket_path: Option<String>) -> Box<Future<Item = (), Error = Error> + Send> {
+        let (incoming_tx, incoming_rx) = mpsc::unbounded();
+        let (status_tx, status_rx) = mpsc::unbounded();
+        let (stop_tx, stop_rx) = oneshot::channel();
+
+        let mut client = RelayClient::new(addr, relay_pk, self.pk, self.sk.clone(), incoming_tx, status_tx);
+        client.set_proxy(self.proxy.clone());
+        client.set_websocket_path(websocket_path);
+
+        {
+            let mut conns_of_client = self.conns_of_client.write();
+            if conns_of_client.contains_key(&relay_pk) {
+                return Box::new(future::err(err_msg("Relay is already added")))
+            }
+            conns_of_client.insert(relay_pk, ClientConnection {
+                client: client.clone(),
+                _stop_tx: stop_tx,
+            });
         }
 
-        let mut conn_of_client = ClientConnection::new();
-        conn_of_client.to_local_tx = Some(to_local_tx);
+        let self_c = self.clone();
+        let incomings = incoming_rx
+            .map_err(|()| unreachable!("rx can't fail"))
+            .for_each(move |packet| self_c.handle_relay_packet(relay_pk, packet));
 
-        conns_of_client.insert(relay.id_of_client, conn_of_client);
+        let self_c = self.clone();
+        let statuses = status_rx
+            .map_err(|()| unreachable!("rx can't fail"))
+            .for_each(move |status| {
+                self_c.handle_relay_status(relay_pk, status);
+                Ok(())
+            });
+
+        // stop_rx is canceled when the relay is removed
+        let stop = stop_rx.then(|_| Ok(()));
 
-        let reader = from_server
+        let future = client.run()
             .map_err(Error::from)
-            .map(move |packet| (packet, relay.clone().id_of_client))
-            .forward(from_server_tx
-                .sink_map_err(|e| {
-                    err_msg(format!("Could not forward message from server to connection {:?}", e))
-                })
-            )
-            .map(|_| ());
+            .select(incomings).map(|_| ()).map_err(|(e, _)| e)
+            .select(statuses).map(|_| ()).map_err(|(e, _)| e)
+            .select(stop).map(|_| ()).map_err(|(e, _)| e);
 
-        let writer = to_local_rx
-            .map_err(|()|  unreachable!("rx can't fail"))
-            .forward(to_server)
-            .map(|_| ());
+        Box::new(future)
+    }
 
-        let network = reader.select(writer)
-            .map(|_| ())
-            .map_err(|(err, _select_next)| err);
+    /// Remove the relay and all connections to friends via it.
+    pub fn remove_relay(&self, relay_pk: &PublicKey) -> Result<(), Error> {
+        if self.conns_of_client.write().remove(relay_pk).is_none() {
+            return Err(err_msg("Cannot find relay to remove"))
+        }
 
-        Box::new(network)
+        for connection in self.connections.write().values_mut() {
+            connection.conn_to_relay.retain(|relay| relay.relay_pk != *relay_pk);
+            connection.update_status();
+        }
+
+        Ok(())
     }
 
-    /// Add a new connection of client to tcp server.
-    pub fn add_relay(&self, addr: &SocketAddr, server_pk: &PublicKey,
-                     from_server_tx: mpsc::UnboundedSender<(Packet, PublicKey)>) -> Box<Future<Item = (), Error = Error> + Send> {
-        let (pk, sk, server_pk, from_server_tx) =
-            (self.pk.clone(), self.sk.clone(), server_pk.clone(), from_server_tx.clone());
+    /// Add a new connection to a friend. Relays for the connection will be
+    /// chosen during the next check.
+    pub fn add_connection(&self, friend_dht_pk: PublicKey) {
+        self.connections.write()
+            .entry(friend_dht_pk)
+            .or_insert_with(|| Connection::new(friend_dht_pk));
+    }
 
-        let self_c = self.clone();
+    /// Remove connection to a friend and notify relays that we don't need
+    /// routes to the friend anymore.
+    pub fn remove_connection(&self, friend_dht_pk: &PublicKey) -> Box<Future<Item = (), Error = Error> + Send> {
+        let conns_of_client = self.conns_of_client.read();
+        let connection = if let Some(connection) = self.connections.write().remove(friend_dht_pk) {
+            connection
+        } else {
+            return Box::new(future::err(err_msg("Cannot find connection to remove")))
+        };
 
-        let conn = TcpStream::connect(addr)
-            .map_err(Error::from)
-            .and_then(move |socket| {
-                make_client_handshake(socket, &pk, &sk, &server_pk)
-                    .map_err(Error::from)
-            })
-            .and_then(move |(socket, channel)| {
-                let secure_socket = Framed::new(socket, Codec::new(channel));
-                self_c.add_entry(secure_socket, &server_pk, from_server_tx)
-            });
+        let notifications = connection.conn_to_relay.iter()
+            .map(|relay| Self::send_disconnect_notification(&conns_of_client, relay))
+            .collect::<Vec<_>>();
+
+        Box::new(future::join_all(notifications).map(|_| ()))
+    }
+
+    /// Add the relay to the connection to a friend. It's used when we know
+    /// that the friend is connected to the relay. An error is returned
+    /// immediately if the relay can't be linked to the connection, otherwise
+    /// the returned future sends `RouteRequest` to the relay.
+    pub fn add_relay_connection(&self, friend_dht_pk: &PublicKey, relay_pk: &PublicKey) -> Result<Box<Future<Item = (), Error = Error> + Send>, Error> {
+        let conns_of_client = self.conns_of_client.read();
+        let mut connections = self.connections.write();
+
+        let client_connection = if let Some(client_connection) = conns_of_client.get(relay_pk) {
+            client_connection
+        } else {
+            return Err(err_msg("Cannot find relay"))
+        };
+        let connection = if let Some(connection) = connections.get_mut(friend_dht_pk) {
+            connection
+        } else {
+            return Err(err_msg("Cannot find connection"))
+        };
+
+        if connection.conn_to_relay.iter().any(|relay| relay.relay_pk == *relay_pk) {
+            return Ok(Box::new(future::ok(())))
+        }
+        if connection.conn_to_relay.len() >= MAX_FRIEND_TCP_CONNECTIONS {
+            return Err(err_msg("Too many relays for the connection"))
+        }
+
+        Ok(Self::link_relay(&client_connection.client, connection))
+    }
 
-        Box::new(conn)
+    /// Check if the relay is added.
+    pub fn has_relay(&self, relay_pk: &PublicKey) -> bool {
+        self.conns_of_client.read().contains_key(relay_pk)
     }
 
-    /// Send a packet to the tcp server
-    pub fn send_packet(&self, connection_id: &PublicKey, packet: Packet) -> Box<Future<Item = (), Error = Error> + Send> {
+    /// Get status of connection to a friend.
+    pub fn connection_status(&self, friend_dht_pk: &PublicKey) -> Option<ClientConnectionStatus> {
+        self.connections.read().get(friend_dht_pk).map(|connection| connection.status)
+    }
+
+    /// Get connections to a friend via relays.
+    pub fn relay_connections(&self, friend_dht_pk: &PublicKey) -> Option<Vec<ConnToRelay>> {
+        self.connections.read().get(friend_dht_pk).map(|connection| connection.conn_to_relay.clone())
+    }
+
+    /// Get addresses and keys of relays we are connected to.
+    pub fn connected_relays(&self) -> Vec<PackedNode> {
+        self.conns_of_client.read()
+            .values()
+            .filter(|client_connection| client_connection.client.is_connected())
+            .map(|client_connection| PackedNode::new(client_connection.client.addr(), &client_connection.client.relay_pk()))
+            .collect()
+    }
+
+    /// Send data to a friend via the best relay on which the friend is
+    /// online. Other relays are tried if the best one is not connected.
+    pub fn send_data(&self, friend_dht_pk: &PublicKey, data: Vec<u8>) -> Box<Future<Item = (), Error = Error> + Send> {
+        let conns_of_client = self.conns_of_client.read();
         let connections = self.connections.read();
-        let conns_of_connection = self.conns_of_client.read();
-
-        if let Some(conn) = connections.get(connection_id) {
-            for relay in &conn.conn_to_relay {
-                if let Some(client) = conns_of_connection.get(&relay.id_of_client) {
-                    if let Some(ref to_local_tx) = client.to_local_tx {
-                        return Box::new(to_local_tx.clone().send(packet)
-                            .map(|_| ())
-                            .map_err(Error::from))
-                    }
+
+        let relays = if let Some(connection) = connections.get(friend_dht_pk) {
+            connection.online_relays()
+        } else {
+            return Box::new(future::err(err_msg("Cannot find connection")))
+        };
+
+        let best_relay = relays.iter().filter_map(|relay|
+            conns_of_client.get(&relay.relay_pk)
+                .filter(|client_connection| client_connection.client.is_connected())
+                .map(|client_connection| (client_connection, relay.connection_id))
+        ).next();
+
+        if let Some((client_connection, connection_id)) = best_relay {
+            Box::new(client_connection.client
+                .send_packet(client::OutgoingPacket::Data(Data { connection_id, data }))
+                .map_err(Error::from))
+        } else {
+            Box::new(future::err(err_msg("The friend is not online on any relay")))
+        }
+    }
+
+    /// Send a packet to the relay. Data packets to a friend are sent via the
+    /// best relay on which the friend is online using `send_data` so that
+    /// another relay is used when the given one is not available.
+    pub fn send_to_relay(&self, relay_pk: &PublicKey, packet: OutgoingPacket) -> Box<Future<Item = (), Error = Error> + Send> {
+        if let OutgoingPacket::Data(ref packet) = packet {
+            let friend_dht_pk = self.connections.read().values()
+                .find(|connection| connection.has_route(relay_pk, packet.connection_id))
+                .map(|connection| connection.friend_dht_pk);
+            if let Some(friend_dht_pk) = friend_dht_pk {
+                return self.send_data(&friend_dht_pk, packet.data.clone())
+            }
+        }
+
+        let conns_of_client = self.conns_of_client.read();
+
+        let client_connection = if let Some(client_connection) = conns_of_client.get(relay_pk) {
+            client_connection
+        } else {
+            return Box::new(future::err(err_msg("Cannot find relay")))
+        };
+
+        let packet = match packet {
+            OutgoingPacket::RouteRequest(packet) => client::OutgoingPacket::RouteRequest(packet),
+            OutgoingPacket::DisconnectNotification(packet) => client::OutgoingPacket::DisconnectNotification(packet),
+            OutgoingPacket::OobSend(packet) => client::OutgoingPacket::OobSend(packet),
+            OutgoingPacket::Data(packet) => client::OutgoingPacket::Data(packet),
+            OutgoingPacket::OnionRequest(packet) => client::OutgoingPacket::OnionRequest(packet),
+        };
+
+        Box::new(client_connection.client.send_packet(packet).map_err(Error::from))
+    }
+
+    /// Check connections to friends: add relays to connections that don't
+    /// have enough online relays and remove extra relays.
+    pub fn check_connections(&self) -> Box<Future<Item = (), Error = Error> + Send> {
+        let conns_of_client = self.conns_of_client.read();
+        let mut connections = self.connections.write();
+
+        let mut futures = Vec::new();
+
+        for connection in connections.values_mut() {
+            if connection.status == ClientConnectionStatus::Sleeping {
+                continue
+            }
+
+            let removed = connection.remove_extra_relays();
+            futures.extend(removed.iter()
+                .map(|relay| Self::send_disconnect_notification(&conns_of_client, relay)));
+
+            if connection.online_count() >= RECOMMENDED_FRIEND_TCP_CONNECTIONS {
+                continue
+            }
+
+            let candidates = conns_of_client.values()
+                .filter(|client_connection| client_connection.client.is_connected())
+                .filter(|client_connection| !connection.conn_to_relay.iter()
+                    .any(|relay| relay.relay_pk == client_connection.client.relay_pk()))
+                .collect::<Vec<_>>();
+
+            // free slots taken by failed relays only when there are other
+            // relays to try so that the same relays aren't re-linked over
+            // and over
+            if connection.conn_to_relay.len() >= MAX_FRIEND_TCP_CONNECTIONS && !candidates.is_empty() {
+                let failed = connection.remove_failed_relays();
+                futures.extend(failed.iter()
+                    .map(|relay| Self::send_disconnect_notification(&conns_of_client, relay)));
+            }
+
+            for client_connection in candidates {
+                if connection.conn_to_relay.len() >= MAX_FRIEND_TCP_CONNECTIONS {
+                    break
                 }
+                futures.push(Self::link_relay(&client_connection.client, connection));
             }
         }
 
-        Box::new(future::ok(()))
+        Box::new(future::join_all(futures).map(|_| ()))
     }
-}
 
-impl ClientConnection {
-    /// Create new ClientConnection object.
-    pub fn new() -> Self {
-        ClientConnection {
-            status: ClientConnectionStatus::None,
-            to_local_tx: None,
-            connected_time: clock_now(),
-            lock_count: 0,
-            sleep_count: 0,
-            onion: false,
-            ip: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
-            port: 33445,
-            relay_pk: gen_keypair().0,
-            wakeup: false,
+    /// Run periodical check of connections to friends.
+    pub fn run(self) -> Box<Future<Item = (), Error = Error> + Send> {
+        let interval = Duration::from_secs(CONNECTIONS_CHECK_INTERVAL);
+        let wakeups = Interval::new(Instant::now(), interval);
+        let future = wakeups
+            .map_err(|e| err_msg(format!("Connections timer error: {:?}", e)))
+            .for_each(move |_instant| {
+                trace!("Tcp connections wake up");
+                self.check_connections()
+            });
+
+        Box::new(future)
+    }
+
+    /// Add the relay to the connection and send RouteRequest to it.
+    fn link_relay(client: &RelayClient, connection: &mut Connection) -> Box<Future<Item = (), Error = Error> + Send> {
+        connection.conn_to_relay.push(ConnToRelay::new(client.relay_pk()));
+        let route_request = client::OutgoingPacket::RouteRequest(RouteRequest {
+            pk: connection.friend_dht_pk,
+        });
+        // the request will be sent after reconnection if the relay is not
+        // connected now
+        Box::new(client.send_packet(route_request).then(|_| Ok(())))
+    }
+
+    /// Notify the relay that we don't need route to the friend anymore.
+    fn send_disconnect_notification(conns_of_client: &HashMap<PublicKey, ClientConnection>, relay: &ConnToRelay)
+        -> Box<Future<Item = (), Error = Error> + Send> {
+        match conns_of_client.get(&relay.relay_pk) {
+            Some(client_connection) if relay.connection_id != 0 => {
+                let notification = client::OutgoingPacket::DisconnectNotification(DisconnectNotification {
+                    connection_id: relay.connection_id,
+                });
+                // ignore errors since the relay can be disconnected
+                Box::new(client_connection.client.send_packet(notification).then(|_| Ok(())))
+            },
+            _ => Box::new(future::ok(())),
+        }
+    }
+
+    /// Handle status change of the relay.
+    fn handle_relay_status(&self, relay_pk: PublicKey, status: RelayStatus) {
+        debug!("TCP relay {:?} status is changed to {:?}", relay_pk, status);
+
+        if status == RelayStatus::Connected {
+            return
+        }
+
+        // RelayClient will send RouteRequests again after reconnection
+        for connection in self.connections.write().values_mut() {
+            for relay in connection.conn_to_relay.iter_mut().filter(|relay| relay.relay_pk == relay_pk) {
+                relay.reset();
+            }
+            connection.update_status();
+        }
+    }
+
+    /// Handle packet received from the relay and send it to net_crypto.
+    fn handle_relay_packet(&self, relay_pk: PublicKey, packet: client::IncomingPacket) -> Box<Future<Item = (), Error = Error> + Send> {
+        let packet = match packet {
+            client::IncomingPacket::RouteResponse(packet) => {
+                if let Some(future) = self.handle_route_response(relay_pk, &packet) {
+                    return future
+                }
+                IncomingPacket::RouteResponse(packet)
+            },
+            client::IncomingPacket::ConnectNotification(packet) => {
+                self.set_relay_status(relay_pk, packet.connection_id, ConnectionStatus::Online);
+                IncomingPacket::ConnectNotification(packet)
+            },
+            client::IncomingPacket::DisconnectNotification(packet) => {
+                self.set_relay_status(relay_pk, packet.connection_id, ConnectionStatus::Registered);
+                IncomingPacket::DisconnectNotification(packet)
+            },
+            client::IncomingPacket::OobReceive(packet) => IncomingPacket::OobReceive(packet),
+            client::IncomingPacket::Data(packet) => IncomingPacket::Data(packet),
+            client::IncomingPacket::OnionResponse(packet) => IncomingPacket::OnionResponse(packet),
+        };
+
+        Box::new(self.incoming_tx.clone().send((packet, relay_pk))
+            .map(|_| ())
+            .map_err(Error::from))
+    }
+
+    /// Register connection id from RouteResponse. If we don't need the route
+    /// anymore return future that notifies the relay about it.
+    fn handle_route_response(&self, relay_pk: PublicKey, packet: &RouteResponse) -> Option<Box<Future<Item = (), Error = Error> + Send>> {
+        let conns_of_client = self.conns_of_client.read();
+        let mut connections = self.connections.write();
+
+        let relay = connections.get_mut(&packet.pk).and_then(|connection|
+            connection.conn_to_relay.iter_mut().find(|relay| relay.relay_pk == relay_pk)
+        );
+
+        match relay {
+            Some(relay) => {
+                if packet.connection_id == 0 {
+                    // the relay refused to make a route so it will be
+                    // replaced during the next check
+                    relay.status = ConnectionStatus::Refused;
+                } else {
+                    relay.connection_id = packet.connection_id;
+                    relay.status = ConnectionStatus::Registered;
+                }
+                None
+            },
+            None if packet.connection_id != 0 => {
+                // the connection or the relay was removed before response
+                let mut relay = ConnToRelay::new(relay_pk);
+                relay.connection_id = packet.connection_id;
+                Some(Self::send_disconnect_notification(&conns_of_client, &relay))
+            },
+            None => None,
+        }
+    }
+
+    /// Set status of connection to a friend via the relay by connection id.
+    fn set_relay_status(&self, relay_pk: PublicKey, connection_id: u8, status: ConnectionStatus) {
+        for connection in self.connections.write().values_mut() {
+            let found = if let Some(relay) = connection.conn_to_relay.iter_mut()
+                .find(|relay| relay.relay_pk == relay_pk && relay.connection_id == connection_id) {
+                relay.status = status;
+                if status == ConnectionStatus::Online {
+                    relay.online_since = Some(clock_now());
+                } else {
+                    relay.online_since = None;
+                    relay.offline_since = clock_now();
+                }
+                true
+            } else {
+                false
+            };
+            if found {
+                connection.update_status();
+                return
+            }
         }
     }
 }
@@ -255,64 +694,318 @@ impl ClientConnection {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use toxcore::tcp::connections::*;
+    use tokio;
     use tokio::net::TcpListener;
-    use tokio::runtime::Runtime;
     use tokio_executor;
     use tokio_timer::clock::*;
     use toxcore::tcp::server::*;
 
+    fn create_connections() -> (Connections, mpsc::UnboundedReceiver<(IncomingPacket, PublicKey)>) {
+        let (pk, sk) = gen_keypair();
+        let (incoming_tx, incoming_rx) = mpsc::unbounded();
+        (Connections::new(pk, sk, incoming_tx), incoming_rx)
+    }
+
+    fn online_relay(connection_id: u8, online_since: Instant) -> ConnToRelay {
+        ConnToRelay {
+            status: ConnectionStatus::Online,
+            relay_pk: gen_keypair().0,
+            connection_id,
+            online_since: Some(online_since),
+            offline_since: online_since,
+        }
+    }
+
     #[test]
-    #[ignore]
-    fn connections_send_packet() {
-        let (client_pk, client_sk) = gen_keypair();
-        let addr = "0.0.0.0:12347".parse().unwrap();
-        let (server_pk, server_sk) = gen_keypair();
-        let listener = TcpListener::bind(&addr).unwrap();
+    fn connection_online_relays() {
+        let now = Instant::now();
+        let mut connection = Connection::new(gen_keypair().0);
+        let relay_1 = online_relay(16, now + Duration::from_secs(2));
+        let relay_2 = online_relay(17, now);
+        let mut relay_3 = ConnToRelay::new(gen_keypair().0);
+        relay_3.status = ConnectionStatus::Registered;
+        connection.conn_to_relay = vec![relay_1, relay_2, relay_3];
+
+        // the relay that has been online for the longest time is the best
+        assert_eq!(connection.online_relays(), vec![relay_2, relay_1]);
+
+        connection.update_status();
+        assert_eq!(connection.status(), ClientConnectionStatus::Connected);
+    }
 
-        let server = Server::new();
-        let server_future = server.run(listener, server_sk);
+    #[test]
+    fn connection_remove_extra_relays() {
+        let now = Instant::now();
+        let mut connection = Connection::new(gen_keypair().0);
+        for i in 0 .. 4 {
+            connection.conn_to_relay.push(online_relay(16 + i, now + Duration::from_secs(u64::from(i))));
+        }
+        let pending = ConnToRelay::new(gen_keypair().0);
+        connection.conn_to_relay.push(pending);
 
-        let connections = Connections::new(client_pk.clone(), client_sk.clone());
+        let removed = connection.remove_extra_relays();
 
-        // Create ConnectionsProcessor
-        let ConnectionsProcessor {
-            from_net_crypto_tx: _,
-            to_net_crypto_rx: _,
-            from_server_tx,
-            to_server_rx: _,
-            processor: _
-        } = ConnectionsProcessor::new();
+        assert_eq!(connection.conn_to_relay.len(), RECOMMENDED_FRIEND_TCP_CONNECTIONS);
+        assert_eq!(removed.len(), 2);
+        assert!(removed.contains(&pending));
+        // the newest online relay is removed
+        assert!(removed.iter().any(|relay| relay.connection_id == 19));
+    }
 
-        let add_relay_future1 = connections.add_relay(&addr, &server_pk, from_server_tx.clone());
+    #[test]
+    fn connection_remove_extra_relays_not_enough_online() {
+        let mut connection = Connection::new(gen_keypair().0);
+        connection.conn_to_relay.push(online_relay(16, Instant::now()));
+        connection.conn_to_relay.push(ConnToRelay::new(gen_keypair().0));
 
-        let add_relay_future2 = connections.add_relay(&addr, &server_pk, from_server_tx.clone());
+        assert!(connection.remove_extra_relays().is_empty());
+        assert_eq!(connection.conn_to_relay.len(), 2);
+    }
 
-        let connection_id = gen_keypair().0;
+    #[test]
+    fn connection_remove_failed_relays() {
+        let mut connection = Connection::new(gen_keypair().0);
+        let online = online_relay(16, Instant::now());
+        let pending = ConnToRelay::new(gen_keypair().0);
+        let mut refused = ConnToRelay::new(gen_keypair().0);
+        refused.status = ConnectionStatus::Refused;
+        connection.conn_to_relay = vec![online, pending, refused];
+
+        let removed = connection.remove_failed_relays();
+        assert_eq!(removed, vec![refused]);
+        assert_eq!(connection.conn_to_relay, vec![online, pending]);
 
-        let packet = Packet::DisconnectNotification(
-            DisconnectNotification { connection_id: 42 }
-        );
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(
+            Instant::now() + Duration::from_secs(RELAY_LINK_TIMEOUT + 1)
+        ));
+
+        // the friend didn't get online on the pending relay in time
+        let removed = with_default(&clock, &mut enter, |_| connection.remove_failed_relays());
+        assert_eq!(removed, vec![pending]);
+        assert_eq!(connection.conn_to_relay, vec![online]);
+    }
 
-        let send_packet_future = connections.send_packet(&connection_id, packet);
+    #[test]
+    fn handle_route_response_refused() {
+        let (connections, _incoming_rx) = create_connections();
+        let friend_pk = gen_keypair().0;
+        let relay_pk = gen_keypair().0;
 
-        let send_future = send_packet_future.join3(add_relay_future1, add_relay_future2)
-            .map(|_| ());
+        connections.add_connection(friend_pk);
+        connections.connections.write().get_mut(&friend_pk).unwrap()
+            .conn_to_relay.push(ConnToRelay::new(relay_pk));
 
-        let both = server_future
-            .map_err(Error::from)
-            .select(send_future)
-            .map(|_| ())
-            .map_err(|_| ());
+        connections.handle_relay_packet(relay_pk, client::IncomingPacket::RouteResponse(RouteResponse {
+            pk: friend_pk,
+            connection_id: 0,
+        })).wait().unwrap();
 
-        let now = Instant::now();
-        let mut_now = MutNow::new(now);
-        let mut enter = tokio_executor::enter().unwrap();
-        let clock = Clock::new_with_now(mut_now);
-        with_default(&clock, &mut enter, |enter| {
-            let mut runtime = Runtime::new().unwrap();
-            runtime.spawn(both);
-            enter.block_on(runtime.shutdown_on_idle()).unwrap();
-        });
+        let relays = connections.relay_connections(&friend_pk).unwrap();
+        assert_eq!(relays[0].status(), ConnectionStatus::Refused);
+        assert!(relays[0].is_failed());
+    }
+
+    #[test]
+    fn handle_relay_packets() {
+        let (connections, incoming_rx) = create_connections();
+        let friend_pk = gen_keypair().0;
+        let relay_pk = gen_keypair().0;
+
+        connections.add_connection(friend_pk);
+        connections.connections.write().get_mut(&friend_pk).unwrap()
+            .conn_to_relay.push(ConnToRelay::new(relay_pk));
+
+        connections.handle_relay_packet(relay_pk, client::IncomingPacket::RouteResponse(RouteResponse {
+            pk: friend_pk,
+            connection_id: 16,
+        })).wait().unwrap();
+        let relays = connections.relay_connections(&friend_pk).unwrap();
+        assert_eq!(relays[0].status(), ConnectionStatus::Registered);
+        assert_eq!(relays[0].connection_id(), 16);
+
+        connections.handle_relay_packet(relay_pk, client::IncomingPacket::ConnectNotification(ConnectNotification {
+            connection_id: 16,
+        })).wait().unwrap();
+        assert_eq!(connections.relay_connections(&friend_pk).unwrap()[0].status(), ConnectionStatus::Online);
+        assert_eq!(connections.connection_status(&friend_pk), Some(ClientConnectionStatus::Connected));
+
+        connections.handle_relay_packet(relay_pk, client::IncomingPacket::DisconnectNotification(DisconnectNotification {
+            connection_id: 16,
+        })).wait().unwrap();
+        assert_eq!(connections.relay_connections(&friend_pk).unwrap()[0].status(), ConnectionStatus::Registered);
+        assert_eq!(connections.connection_status(&friend_pk), Some(ClientConnectionStatus::Valid));
+
+        // packets are forwarded to net_crypto
+        let packets = incoming_rx.take(3).collect().wait().unwrap();
+        assert_eq!(packets[1], (IncomingPacket::ConnectNotification(ConnectNotification {
+            connection_id: 16,
+        }), relay_pk));
+    }
+
+    #[test]
+    fn handle_relay_status_disconnected() {
+        let (connections, _incoming_rx) = create_connections();
+        let friend_pk = gen_keypair().0;
+        let relay = online_relay(16, Instant::now());
+
+        connections.add_connection(friend_pk);
+        connections.connections.write().get_mut(&friend_pk).unwrap()
+            .conn_to_relay.push(relay);
+        connections.connections.write().get_mut(&friend_pk).unwrap()
+            .update_status();
+
+        connections.handle_relay_status(relay.relay_pk, RelayStatus::Disconnected);
+
+        let relays = connections.relay_connections(&friend_pk).unwrap();
+        assert_eq!(relays[0].status(), ConnectionStatus::None);
+        assert_eq!(relays[0].connection_id(), 0);
+        assert_eq!(connections.connection_status(&friend_pk), Some(ClientConnectionStatus::Valid));
+    }
+
+    #[test]
+    fn send_data_no_online_relays() {
+        let (connections, _incoming_rx) = create_connections();
+        let friend_pk = gen_keypair().0;
+
+        assert!(connections.send_data(&friend_pk, vec![42; 123]).wait().is_err());
+
+        connections.add_connection(friend_pk);
+        assert!(connections.send_data(&friend_pk, vec![42; 123]).wait().is_err());
+    }
+
+    #[test]
+    fn remove_relay() {
+        let (connections, _incoming_rx) = create_connections();
+        let friend_pk = gen_keypair().0;
+        let relay_pk = gen_keypair().0;
+
+        let _relay_future = connections.add_relay("127.0.0.1:12345".parse().unwrap(), relay_pk);
+        assert!(connections.add_relay("127.0.0.1:12345".parse().unwrap(), relay_pk).wait().is_err());
+        // the relay is not connected yet
+        assert!(connections.connected_relays().is_empty());
+
+        connections.add_connection(friend_pk);
+        connections.add_relay_connection(&friend_pk, &relay_pk).unwrap().wait().unwrap();
+        assert!(connections.has_relay(&relay_pk));
+        assert_eq!(connections.relay_connections(&friend_pk).unwrap().len(), 1);
+
+        connections.remove_relay(&relay_pk).unwrap();
+        assert!(connections.relay_connections(&friend_pk).unwrap().is_empty());
+        assert!(!connections.has_relay(&relay_pk));
+        assert!(connections.remove_relay(&relay_pk).is_err());
+    }
+
+    #[test]
+    fn connections_send_data() {
+        let (server_pk, server_sk) = gen_keypair();
+        let addr = "127.0.0.1:12353".parse().unwrap();
+
+        let (shutdown_handle, shutdown_signal) = ShutdownHandle::new();
+
+        let server = Server::new();
+        let listener = TcpListener::bind(&addr).unwrap();
+        let server_future = server.run_with_shutdown(listener, server_sk, shutdown_signal, Duration::from_secs(1))
+            .map_err(Error::from);
+
+        let (connections_1, incoming_rx_1) = create_connections();
+        let (connections_2, incoming_rx_2) = create_connections();
+        let (pk_1, pk_2) = (connections_1.pk, connections_2.pk);
+
+        connections_1.add_connection(pk_2);
+        connections_2.add_connection(pk_1);
+
+        let background = connections_1.add_relay(addr, server_pk)
+            .select(connections_2.add_relay(addr, server_pk)).map(|_| ()).map_err(|(e, _)| e)
+            .select(connections_1.clone().run()).map(|_| ()).map_err(|(e, _)| e)
+            .select(connections_2.clone().run()).map(|_| ()).map_err(|(e, _)| e);
+
+        let connections_1_c = connections_1.clone();
+        let test_future = incoming_rx_1
+            .map_err(|()| unreachable!("rx can't fail"))
+            .filter(|(packet, _)| matches!(packet, IncomingPacket::ConnectNotification(_)))
+            .into_future()
+            .map_err(|(e, _)| e)
+            .and_then(move |_| {
+                assert_eq!(connections_1_c.connection_status(&pk_2), Some(ClientConnectionStatus::Connected));
+                assert_eq!(connections_1_c.connected_relays(), vec![PackedNode::new(addr, &server_pk)]);
+                connections_1_c.send_data(&pk_2, vec![42; 123])
+            })
+            .and_then(|()|
+                incoming_rx_2
+                    .map_err(|()| unreachable!("rx can't fail"))
+                    .filter_map(|(packet, _)| match packet {
+                        IncomingPacket::Data(packet) => Some(packet),
+                        _ => None,
+                    })
+                    .into_future()
+                    .map_err(|(e, _)| e)
+            )
+            .map(move |(packet, _)| {
+                assert_eq!(packet.unwrap().data, vec![42; 123]);
+                shutdown_handle.shutdown();
+            });
+
+        let future = server_future
+            .join(test_future.select(background).map(|_| ()).map_err(|(e, _)| e))
+            .map(|_| ()).map_err(|e| panic!("Unexpected error: {:?}", e));
+
+        tokio::run(future);
+    }
+
+    #[test]
+    fn connections_send_data_websocket() {
+        let (server_pk, server_sk) = gen_keypair();
+        let addr = "127.0.0.1:12365".parse().unwrap();
+        let websocket_addr = "127.0.0.1:12366".parse().unwrap();
+
+        let (shutdown_handle, shutdown_signal) = ShutdownHandle::new();
+
+        // TCP and WebSocket clients are connected to the same server
+        let server = Server::new();
+        let listener = TcpListener::bind(&addr).unwrap();
+        let websocket_listener = TcpListener::bind(&websocket_addr).unwrap();
+        let server_future = server.run_websocket_listeners(vec![listener], vec![websocket_listener], server_sk, shutdown_signal, Duration::from_secs(1))
+            .map_err(Error::from);
+
+        let (connections_1, incoming_rx_1) = create_connections();
+        let (connections_2, incoming_rx_2) = create_connections();
+        let (pk_1, pk_2) = (connections_1.pk, connections_2.pk);
+
+        connections_1.add_connection(pk_2);
+        connections_2.add_connection(pk_1);
+
+        let background = connections_1.add_relay(addr, server_pk)
+            .select(connections_2.add_websocket_relay(websocket_addr, server_pk, "/".to_owned())).map(|_| ()).map_err(|(e, _)| e)
+            .select(connections_1.clone().run()).map(|_| ()).map_err(|(e, _)| e)
+            .select(connections_2.clone().run()).map(|_| ()).map_err(|(e, _)| e);
+
+        let connections_2_c = connections_2.clone();
+        let test_future = incoming_rx_2
+            .map_err(|()| unreachable!("rx can't fail"))
+            .filter(|(packet, _)| matches!(packet, IncomingPacket::ConnectNotification(_)))
+            .into_future()
+            .map_err(|(e, _)| e)
+            .and_then(move |_| connections_2_c.send_data(&pk_1, vec![42; 123]))
+            .and_then(|()|
+                incoming_rx_1
+                    .map_err(|()| unreachable!("rx can't fail"))
+                    .filter_map(|(packet, _)| match packet {
+                        IncomingPacket::Data(packet) => Some(packet),
+                        _ => None,
+                    })
+                    .into_future()
+                    .map_err(|(e, _)| e)
+            )
+            .map(move |(packet, _)| {
+                assert_eq!(packet.unwrap().data, vec![42; 123]);
+                shutdown_handle.shutdown();
+            });
+
+        let future = server_future
+            .join(test_future.select(background).map(|_| ()).map_err(|(e, _)| e))
+            .map(|_| ()).map_err(|e| panic!("Unexpected error: {:?}", e));
+
+        tokio::run(future);
     }
 }
@@ -17,7 +17,7 @@ use toxcore::io_tokio::IoFuture;
 use futures::{self, Stream, Sink, Future};
 use std::io::{Error, ErrorKind};
 use tokio_codec::Framed;
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncWrite};
 
 /// Create a handshake from client to server
 pub fn create_client_handshake(client_pk: &PublicKey,
@@ -96,11 +96,12 @@ pub fn handle_server_handshake(common_key: &PrecomputedKey,
 
 /// Sends handshake to the server, receives handshake from the server
 /// and processes it
-pub fn make_client_handshake(socket: TcpStream,
+pub fn make_client_handshake<S>(socket: S,
                             client_pk: &PublicKey,
                             client_sk: &SecretKey,
                             server_pk: &PublicKey)
-    -> IoFuture<(TcpStream, secure::Channel)> {
+    -> IoFuture<(S, secure::Channel)>
+    where S: AsyncRead + AsyncWrite + Send + 'static {
     let res = futures::done(create_client_handshake(client_pk, client_sk, server_pk))
         .and_then(|(session, common_key, handshake)| {
             // send handshake
@@ -146,9 +147,10 @@ pub fn make_client_handshake(socket: TcpStream,
 
 /// Receives handshake from the client, processes it and
 /// sends handshake to the client
-pub fn make_server_handshake(socket: TcpStream,
+pub fn make_server_handshake<S>(socket: S,
                             server_sk: SecretKey)
-    -> IoFuture<(TcpStream, secure::Channel, PublicKey)> {
+    -> IoFuture<(S, secure::Channel, PublicKey)>
+    where S: AsyncRead + AsyncWrite + Send + 'static {
     let res = Framed::new(socket, ClientHandshakeCodec)
         .into_future() // receive handshake from client
         .map_err(|(e, _socket)| {
@@ -6,6 +6,8 @@ pub mod handshake;
 pub mod secure;
 pub mod packet;
 pub mod codec;
+pub mod proxy;
+pub mod websocket;
 pub mod server;
 pub mod client;
 pub mod connections;
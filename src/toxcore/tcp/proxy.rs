@@ -0,0 +1,579 @@
+/*! Proxy support for outgoing TCP connections to relays
+
+Two types of proxies are supported: SOCKS5 (RFC 1928) with optional
+username/password authentication (RFC 1929) and HTTP proxies that support
+`CONNECT` method. The proxy negotiation is made before the handshake with the
+relay so the returned `TcpStream` can be used the same way as a direct
+connection.
+*/
+
+use std::io::{Error as IoError};
+use std::net::{IpAddr, SocketAddr};
+
+use futures::{future, Future};
+use futures::future::Loop;
+use tokio::io::{read_exact, write_all};
+use tokio::net::TcpStream;
+
+/// SOCKS protocol version
+const SOCKS5_VERSION: u8 = 0x05;
+/// SOCKS5 authentication method that doesn't require authentication
+const SOCKS5_AUTH_NONE: u8 = 0x00;
+/// SOCKS5 username/password authentication method
+const SOCKS5_AUTH_PASSWORD: u8 = 0x02;
+/// SOCKS5 response when none of the offered methods are acceptable
+const SOCKS5_AUTH_NO_ACCEPTABLE: u8 = 0xff;
+/// Version of SOCKS5 username/password authentication
+const SOCKS5_PASSWORD_VERSION: u8 = 0x01;
+/// SOCKS5 `CONNECT` command
+const SOCKS5_CMD_CONNECT: u8 = 0x01;
+/// SOCKS5 address type for IPv4 address
+const SOCKS5_ATYP_IPV4: u8 = 0x01;
+/// SOCKS5 address type for domain name
+const SOCKS5_ATYP_DOMAIN: u8 = 0x03;
+/// SOCKS5 address type for IPv6 address
+const SOCKS5_ATYP_IPV6: u8 = 0x04;
+/// SOCKS5 reply that indicates success
+const SOCKS5_REPLY_SUCCEEDED: u8 = 0x00;
+
+/// Maximum size of HTTP proxy response headers
+const HTTP_MAX_RESPONSE_SIZE: usize = 4096;
+
+/// Credentials for SOCKS5 username/password authentication
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Socks5Auth {
+    /// Username, can't be longer than 255 bytes
+    pub username: String,
+    /// Password, can't be longer than 255 bytes
+    pub password: String,
+}
+
+/// Proxy that is used for outgoing TCP connections
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Proxy {
+    /// SOCKS5 proxy
+    Socks5 {
+        /// Address of the proxy
+        addr: SocketAddr,
+        /// Credentials if the proxy requires authentication
+        auth: Option<Socks5Auth>,
+    },
+    /// HTTP proxy that supports `CONNECT` method
+    Http {
+        /// Address of the proxy
+        addr: SocketAddr,
+    },
+}
+
+/// Error that can happen when connecting via proxy
+#[derive(Debug, Fail)]
+pub enum ProxyError {
+    /// Error indicates that we couldn't connect to the proxy or to the
+    /// destination directly
+    #[fail(display = "Failed to connect to {}: {}", addr, error)]
+    ConnectError {
+        /// Address we tried to connect to
+        addr: SocketAddr,
+        /// Connection error
+        #[fail(cause)]
+        error: IoError,
+    },
+    /// IO error during negotiation with the proxy
+    #[fail(display = "Proxy IO error: {}", error)]
+    IoError {
+        /// IO error
+        #[fail(cause)]
+        error: IoError,
+    },
+    /// Error indicates that the proxy doesn't accept offered authentication
+    /// methods
+    #[fail(display = "SOCKS5 proxy doesn't accept offered authentication methods")]
+    UnsupportedAuthError,
+    /// Error indicates that credentials are too long to be sent to the proxy
+    #[fail(display = "SOCKS5 username or password is too long")]
+    InvalidCredentialsError,
+    /// Error indicates that the proxy rejected our credentials
+    #[fail(display = "SOCKS5 proxy authentication failed with status {}", status)]
+    AuthError {
+        /// Status received from the proxy
+        status: u8,
+    },
+    /// Error indicates that the SOCKS5 proxy couldn't connect to the
+    /// destination
+    #[fail(display = "SOCKS5 proxy failed to connect with reply {}", reply)]
+    Socks5ConnectError {
+        /// Reply code received from the proxy
+        reply: u8,
+    },
+    /// Error indicates that the HTTP proxy couldn't connect to the
+    /// destination or requires authentication
+    #[fail(display = "HTTP proxy failed to connect with status {}", status)]
+    HttpConnectError {
+        /// Status code received from the proxy
+        status: u16,
+    },
+    /// Error indicates that the proxy sent invalid response
+    #[fail(display = "Invalid proxy response: {}", message)]
+    InvalidResponseError {
+        /// Description of the problem
+        message: String,
+    },
+}
+
+impl From<IoError> for ProxyError {
+    fn from(error: IoError) -> ProxyError {
+        ProxyError::IoError {
+            error
+        }
+    }
+}
+
+/// Future that resolves to `TcpStream` connected to the destination
+pub type ProxyFuture = Box<Future<Item = TcpStream, Error = ProxyError> + Send>;
+
+impl Proxy {
+    /// Address of the proxy
+    pub fn addr(&self) -> SocketAddr {
+        match *self {
+            Proxy::Socks5 { addr, .. } => addr,
+            Proxy::Http { addr } => addr,
+        }
+    }
+
+    /// Connect to the destination via the proxy
+    pub fn connect(&self, dest: &SocketAddr) -> ProxyFuture {
+        let proxy_addr = self.addr();
+        let stream = TcpStream::connect(&proxy_addr)
+            .map_err(move |error| ProxyError::ConnectError { addr: proxy_addr, error });
+
+        let dest = *dest;
+        match *self {
+            Proxy::Socks5 { ref auth, .. } => {
+                let auth = auth.clone();
+                Box::new(stream.and_then(move |stream| socks5_connect(stream, auth, dest)))
+            },
+            Proxy::Http { .. } =>
+                Box::new(stream.and_then(move |stream| http_connect(stream, dest))),
+        }
+    }
+}
+
+/// Connect to the destination via the proxy if it's specified or directly
+/// otherwise
+pub fn connect(proxy: Option<&Proxy>, dest: &SocketAddr) -> ProxyFuture {
+    if let Some(proxy) = proxy {
+        proxy.connect(dest)
+    } else {
+        let dest = *dest;
+        Box::new(TcpStream::connect(&dest)
+            .map_err(move |error| ProxyError::ConnectError { addr: dest, error }))
+    }
+}
+
+/// Make SOCKS5 negotiation on the stream connected to the proxy
+fn socks5_connect(stream: TcpStream, auth: Option<Socks5Auth>, dest: SocketAddr) -> ProxyFuture {
+    let greeting = if auth.is_some() {
+        vec![SOCKS5_VERSION, 2, SOCKS5_AUTH_NONE, SOCKS5_AUTH_PASSWORD]
+    } else {
+        vec![SOCKS5_VERSION, 1, SOCKS5_AUTH_NONE]
+    };
+
+    let future = write_all(stream, greeting)
+        .and_then(|(stream, _)| read_exact(stream, [0; 2]))
+        .map_err(ProxyError::from)
+        .and_then(move |(stream, response)| -> ProxyFuture {
+            if response[0] != SOCKS5_VERSION {
+                return Box::new(future::err(ProxyError::InvalidResponseError {
+                    message: format!("unexpected SOCKS version {}", response[0]),
+                }))
+            }
+            match (response[1], auth) {
+                (SOCKS5_AUTH_NONE, _) => Box::new(future::ok(stream)),
+                (SOCKS5_AUTH_PASSWORD, Some(auth)) => socks5_auth(stream, &auth),
+                (SOCKS5_AUTH_NO_ACCEPTABLE, _) | (SOCKS5_AUTH_PASSWORD, None) =>
+                    Box::new(future::err(ProxyError::UnsupportedAuthError)),
+                (method, _) => Box::new(future::err(ProxyError::InvalidResponseError {
+                    message: format!("unexpected SOCKS5 authentication method {}", method),
+                })),
+            }
+        })
+        .and_then(move |stream| write_all(stream, socks5_connect_request(dest)).map_err(ProxyError::from))
+        .and_then(|(stream, _)| read_exact(stream, [0; 4]).map_err(ProxyError::from))
+        .and_then(|(stream, reply)| -> Box<Future<Item = (TcpStream, usize), Error = ProxyError> + Send> {
+            if reply[0] != SOCKS5_VERSION {
+                return Box::new(future::err(ProxyError::InvalidResponseError {
+                    message: format!("unexpected SOCKS version {}", reply[0]),
+                }))
+            }
+            if reply[1] != SOCKS5_REPLY_SUCCEEDED {
+                return Box::new(future::err(ProxyError::Socks5ConnectError { reply: reply[1] }))
+            }
+            // bound address and port follow the reply
+            match reply[3] {
+                SOCKS5_ATYP_IPV4 => Box::new(future::ok((stream, 4 + 2))),
+                SOCKS5_ATYP_IPV6 => Box::new(future::ok((stream, 16 + 2))),
+                SOCKS5_ATYP_DOMAIN => Box::new(read_exact(stream, [0; 1])
+                    .map(|(stream, len)| (stream, len[0] as usize + 2))
+                    .map_err(ProxyError::from)),
+                atyp => Box::new(future::err(ProxyError::InvalidResponseError {
+                    message: format!("unexpected SOCKS5 address type {}", atyp),
+                })),
+            }
+        })
+        .and_then(|(stream, len)| read_exact(stream, vec![0; len]).map_err(ProxyError::from))
+        .map(|(stream, _bound_addr)| stream);
+
+    Box::new(future)
+}
+
+/// Make SOCKS5 username/password authentication
+fn socks5_auth(stream: TcpStream, auth: &Socks5Auth) -> ProxyFuture {
+    let (username, password) = (auth.username.as_bytes(), auth.password.as_bytes());
+    if username.len() > 255 || password.len() > 255 {
+        return Box::new(future::err(ProxyError::InvalidCredentialsError))
+    }
+
+    let mut request = Vec::with_capacity(3 + username.len() + password.len());
+    request.push(SOCKS5_PASSWORD_VERSION);
+    request.push(username.len() as u8);
+    request.extend_from_slice(username);
+    request.push(password.len() as u8);
+    request.extend_from_slice(password);
+
+    let future = write_all(stream, request)
+        .and_then(|(stream, _)| read_exact(stream, [0; 2]))
+        .map_err(ProxyError::from)
+        .and_then(|(stream, response)|
+            if response[1] == 0 {
+                Ok(stream)
+            } else {
+                Err(ProxyError::AuthError { status: response[1] })
+            }
+        );
+
+    Box::new(future)
+}
+
+/// Create SOCKS5 `CONNECT` request to the destination
+fn socks5_connect_request(dest: SocketAddr) -> Vec<u8> {
+    let mut request = vec![SOCKS5_VERSION, SOCKS5_CMD_CONNECT, 0];
+    match dest.ip() {
+        IpAddr::V4(ip) => {
+            request.push(SOCKS5_ATYP_IPV4);
+            request.extend_from_slice(&ip.octets());
+        },
+        IpAddr::V6(ip) => {
+            request.push(SOCKS5_ATYP_IPV6);
+            request.extend_from_slice(&ip.octets());
+        },
+    }
+    request.push((dest.port() >> 8) as u8);
+    request.push(dest.port() as u8);
+    request
+}
+
+/// Make HTTP `CONNECT` request on the stream connected to the proxy
+fn http_connect(stream: TcpStream, dest: SocketAddr) -> ProxyFuture {
+    let request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", dest, dest);
+
+    let future = write_all(stream, request.into_bytes())
+        .map_err(ProxyError::from)
+        // read response byte by byte to not read data after headers
+        .and_then(|(stream, _)| future::loop_fn((stream, Vec::new()), |(stream, mut response)|
+            read_exact(stream, [0; 1])
+                .map_err(ProxyError::from)
+                .and_then(move |(stream, byte)| {
+                    response.push(byte[0]);
+                    if response.ends_with(b"\r\n\r\n") {
+                        Ok(Loop::Break((stream, response)))
+                    } else if response.len() >= HTTP_MAX_RESPONSE_SIZE {
+                        Err(ProxyError::InvalidResponseError {
+                            message: "HTTP response is too long".to_owned(),
+                        })
+                    } else {
+                        Ok(Loop::Continue((stream, response)))
+                    }
+                })
+        ))
+        .and_then(|(stream, response)| {
+            let status = http_status(&response)?;
+            if status / 100 == 2 {
+                Ok(stream)
+            } else {
+                Err(ProxyError::HttpConnectError { status })
+            }
+        });
+
+    Box::new(future)
+}
+
+/// Get status code from HTTP response status line
+fn http_status(response: &[u8]) -> Result<u16, ProxyError> {
+    let response = String::from_utf8_lossy(response);
+    let status_line = response.lines().next().unwrap_or("");
+    let mut parts = status_line.split_whitespace();
+    match (parts.next(), parts.next().and_then(|status| status.parse().ok())) {
+        (Some(version), Some(status)) if version.starts_with("HTTP/") => Ok(status),
+        _ => Err(ProxyError::InvalidResponseError {
+            message: format!("invalid HTTP status line: {:?}", status_line),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{ErrorKind as IoErrorKind};
+
+    use futures::Stream;
+    use tokio;
+    use tokio::net::TcpListener;
+
+    /// Run a proxy stand-in that accepts one connection and handles it with
+    /// `handler` together with the client future
+    fn run_with_proxy<H, F, C>(addr: SocketAddr, handler: H, client: C)
+        where H: FnOnce(TcpStream) -> F + Send + 'static,
+              F: Future<Item = (), Error = IoError> + Send + 'static,
+              C: Future<Item = (), Error = ()> + Send + 'static
+    {
+        let listener = TcpListener::bind(&addr).unwrap();
+        let proxy = listener.incoming()
+            .into_future()
+            .map_err(|(e, _)| e)
+            .and_then(move |(stream, _)| handler(stream.unwrap()))
+            .map_err(|e| panic!("Proxy error: {:?}", e));
+
+        tokio::run(proxy.join(client).map(|_| ()));
+    }
+
+    /// Read exactly `expected.len()` bytes and check that they are equal to
+    /// `expected`
+    fn expect(stream: TcpStream, expected: Vec<u8>) -> Box<Future<Item = TcpStream, Error = IoError> + Send> {
+        Box::new(read_exact(stream, vec![0; expected.len()])
+            .map(move |(stream, received)| {
+                assert_eq!(received, expected);
+                stream
+            }))
+    }
+
+    #[test]
+    fn proxy_error_display() {
+        format!("{}", ProxyError::ConnectError {
+            addr: "127.0.0.1:12345".parse().unwrap(),
+            error: IoError::new(IoErrorKind::Other, "io error"),
+        });
+        format!("{}", ProxyError::IoError {
+            error: IoError::new(IoErrorKind::Other, "io error"),
+        });
+        format!("{}", ProxyError::UnsupportedAuthError);
+        format!("{}", ProxyError::InvalidCredentialsError);
+        format!("{}", ProxyError::AuthError { status: 1 });
+        format!("{}", ProxyError::Socks5ConnectError { reply: 5 });
+        format!("{}", ProxyError::HttpConnectError { status: 407 });
+        format!("{}", ProxyError::InvalidResponseError { message: "error".to_owned() });
+    }
+
+    #[test]
+    fn socks5_connect_request_v4() {
+        let request = socks5_connect_request("1.2.3.4:33445".parse().unwrap());
+        assert_eq!(request, vec![5, 1, 0, 1, 1, 2, 3, 4, 0x82, 0xa5]);
+    }
+
+    #[test]
+    fn socks5_connect_request_v6() {
+        let request = socks5_connect_request("[::1]:443".parse().unwrap());
+        let mut expected = vec![5, 1, 0, 4];
+        expected.extend_from_slice(&[0; 15]);
+        expected.extend_from_slice(&[1, 0x01, 0xbb]);
+        assert_eq!(request, expected);
+    }
+
+    #[test]
+    fn http_status_valid() {
+        assert_eq!(http_status(b"HTTP/1.1 200 Connection established\r\n\r\n").unwrap(), 200);
+        assert_eq!(http_status(b"HTTP/1.0 407 Proxy Authentication Required\r\n\r\n").unwrap(), 407);
+    }
+
+    #[test]
+    fn http_status_invalid() {
+        assert!(http_status(b"SSH-2.0-OpenSSH\r\n\r\n").is_err());
+        assert!(http_status(b"HTTP/1.1 OK\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn socks5_no_auth() {
+        let addr = "127.0.0.1:12354".parse().unwrap();
+        let dest: SocketAddr = "1.2.3.4:33445".parse().unwrap();
+        let proxy = Proxy::Socks5 { addr, auth: None };
+
+        let handler = move |stream| expect(stream, vec![5, 1, 0])
+            .and_then(|stream| write_all(stream, vec![5, 0]))
+            .and_then(move |(stream, _)| expect(stream, socks5_connect_request(dest)))
+            .and_then(|stream| write_all(stream, vec![5, 0, 0, 1, 127, 0, 0, 1, 0, 42, 13]))
+            .map(|_| ());
+
+        let client = proxy.connect(&dest)
+            .and_then(|stream| read_exact(stream, [0; 1]).map_err(ProxyError::from))
+            .map(|(_stream, data)| {
+                // data after the reply belongs to the tunnel
+                assert_eq!(data, [13]);
+            })
+            .map_err(|e| panic!("Client error: {:?}", e));
+
+        run_with_proxy(addr, handler, client);
+    }
+
+    #[test]
+    fn socks5_auth() {
+        let addr = "127.0.0.1:12355".parse().unwrap();
+        let dest: SocketAddr = "1.2.3.4:33445".parse().unwrap();
+        let proxy = Proxy::Socks5 {
+            addr,
+            auth: Some(Socks5Auth { username: "user".to_owned(), password: "pass".to_owned() }),
+        };
+
+        let handler = move |stream| expect(stream, vec![5, 2, 0, 2])
+            .and_then(|stream| write_all(stream, vec![5, 2]))
+            .and_then(|(stream, _)| expect(stream, b"\x01\x04user\x04pass".to_vec()))
+            .and_then(|stream| write_all(stream, vec![1, 0]))
+            .and_then(move |(stream, _)| expect(stream, socks5_connect_request(dest)))
+            .and_then(|stream| write_all(stream, vec![5, 0, 0, 3, 4, b'h', b'o', b's', b't', 0, 42]))
+            .map(|_| ());
+
+        let client = proxy.connect(&dest)
+            .map(|_stream| ())
+            .map_err(|e| panic!("Client error: {:?}", e));
+
+        run_with_proxy(addr, handler, client);
+    }
+
+    #[test]
+    fn socks5_auth_failed() {
+        let addr = "127.0.0.1:12356".parse().unwrap();
+        let dest: SocketAddr = "1.2.3.4:33445".parse().unwrap();
+        let proxy = Proxy::Socks5 {
+            addr,
+            auth: Some(Socks5Auth { username: "user".to_owned(), password: "wrong".to_owned() }),
+        };
+
+        let handler = |stream| expect(stream, vec![5, 2, 0, 2])
+            .and_then(|stream| write_all(stream, vec![5, 2]))
+            .and_then(|(stream, _)| expect(stream, b"\x01\x04user\x05wrong".to_vec()))
+            .and_then(|stream| write_all(stream, vec![1, 1]))
+            .map(|_| ());
+
+        let client = proxy.connect(&dest)
+            .then(|res| {
+                match res {
+                    Err(ProxyError::AuthError { status: 1 }) => {},
+                    res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+                }
+                Ok(())
+            });
+
+        run_with_proxy(addr, handler, client);
+    }
+
+    #[test]
+    fn socks5_auth_required() {
+        let addr = "127.0.0.1:12357".parse().unwrap();
+        let dest: SocketAddr = "1.2.3.4:33445".parse().unwrap();
+        let proxy = Proxy::Socks5 { addr, auth: None };
+
+        let handler = |stream| expect(stream, vec![5, 1, 0])
+            .and_then(|stream| write_all(stream, vec![5, 0xff]))
+            .map(|_| ());
+
+        let client = proxy.connect(&dest)
+            .then(|res| {
+                match res {
+                    Err(ProxyError::UnsupportedAuthError) => {},
+                    res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+                }
+                Ok(())
+            });
+
+        run_with_proxy(addr, handler, client);
+    }
+
+    #[test]
+    fn socks5_connect_failed() {
+        let addr = "127.0.0.1:12358".parse().unwrap();
+        let dest: SocketAddr = "1.2.3.4:33445".parse().unwrap();
+        let proxy = Proxy::Socks5 { addr, auth: None };
+
+        let handler = move |stream| expect(stream, vec![5, 1, 0])
+            .and_then(|stream| write_all(stream, vec![5, 0]))
+            .and_then(move |(stream, _)| expect(stream, socks5_connect_request(dest)))
+            // connection refused
+            .and_then(|stream| write_all(stream, vec![5, 5, 0, 1, 0, 0, 0, 0, 0, 0]))
+            .map(|_| ());
+
+        let client = proxy.connect(&dest)
+            .then(|res| {
+                match res {
+                    Err(ProxyError::Socks5ConnectError { reply: 5 }) => {},
+                    res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+                }
+                Ok(())
+            });
+
+        run_with_proxy(addr, handler, client);
+    }
+
+    #[test]
+    fn http_connect_ok() {
+        let addr = "127.0.0.1:12359".parse().unwrap();
+        let dest: SocketAddr = "[::1]:33445".parse().unwrap();
+        let proxy = Proxy::Http { addr };
+
+        let handler = |stream| expect(stream, b"CONNECT [::1]:33445 HTTP/1.1\r\nHost: [::1]:33445\r\n\r\n".to_vec())
+            .and_then(|stream| write_all(stream, b"HTTP/1.1 200 Connection established\r\n\r\n\x2a".to_vec()))
+            .map(|_| ());
+
+        let client = proxy.connect(&dest)
+            .and_then(|stream| read_exact(stream, [0; 1]).map_err(ProxyError::from))
+            .map(|(_stream, data)| {
+                // data after headers belongs to the tunnel
+                assert_eq!(data, [42]);
+            })
+            .map_err(|e| panic!("Client error: {:?}", e));
+
+        run_with_proxy(addr, handler, client);
+    }
+
+    #[test]
+    fn http_connect_failed() {
+        let addr = "127.0.0.1:12360".parse().unwrap();
+        let dest: SocketAddr = "1.2.3.4:33445".parse().unwrap();
+        let proxy = Proxy::Http { addr };
+
+        let handler = |stream| expect(stream, b"CONNECT 1.2.3.4:33445 HTTP/1.1\r\nHost: 1.2.3.4:33445\r\n\r\n".to_vec())
+            .and_then(|stream| write_all(stream, b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n".to_vec()))
+            .map(|_| ());
+
+        let client = proxy.connect(&dest)
+            .then(|res| {
+                match res {
+                    Err(ProxyError::HttpConnectError { status: 407 }) => {},
+                    res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+                }
+                Ok(())
+            });
+
+        run_with_proxy(addr, handler, client);
+    }
+
+    #[test]
+    fn connect_proxy_unavailable() {
+        // nobody listens on this port
+        let proxy = Proxy::Http { addr: "127.0.0.1:12361".parse().unwrap() };
+
+        let res = proxy.connect(&"1.2.3.4:33445".parse().unwrap()).wait();
+        match res {
+            Err(ProxyError::ConnectError { .. }) => {},
+            res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+        }
+    }
+}
@@ -5,6 +5,7 @@ use toxcore::crypto_core::*;
 use toxcore::tcp::packet::*;
 use toxcore::io_tokio::*;
 use toxcore::onion::packet::InnerOnionResponse;
+use toxcore::tcp::server::stats::ClientInfo;
 use toxcore::time::*;
 use toxcore::utils::*;
 
@@ -48,7 +49,13 @@ pub struct Client {
     /// Last time sent PingRequest packet
     last_pinged: Instant,
     /// Last time received PongResponse
-    last_pong_resp: Instant
+    last_pong_resp: Instant,
+    /// Time when the client was connected
+    connected_time: Instant,
+    /// Token bucket to limit rate of forwarded packets
+    packets_bucket: Option<TokenBucket>,
+    /// Token bucket to limit rate of forwarded bytes
+    bytes_bucket: Option<TokenBucket>,
 }
 
 impl Client {
@@ -63,7 +70,10 @@ impl Client {
             links: [None; 240],
             ping_id: 0,
             last_pinged: clock_now(),
-            last_pong_resp: clock_now()
+            last_pong_resp: clock_now(),
+            connected_time: clock_now(),
+            packets_bucket: None,
+            bytes_bucket: None,
         }
     }
 
@@ -109,6 +119,51 @@ impl Client {
         clock_elapsed(self.last_pinged) >= Duration::from_secs(TCP_PING_FREQUENCY)
     }
 
+    /** Time when the client was connected
+    */
+    pub fn connected_time(&self) -> Instant {
+        self.connected_time
+    }
+
+    /** Get information about the client
+    */
+    pub fn info(&self) -> ClientInfo {
+        ClientInfo {
+            pk: self.pk,
+            ip_addr: self.ip_addr,
+            port: self.port,
+            connected_time: self.connected_time,
+            links_count: self.links_count(),
+        }
+    }
+
+    /** Set rate limits of forwarded packets and bytes
+    */
+    pub fn set_rate_limits(&mut self, packets_rate: Option<RateLimit>, bytes_rate: Option<RateLimit>) {
+        self.packets_bucket = packets_rate.map(TokenBucket::new);
+        self.bytes_bucket = bytes_rate.map(TokenBucket::new);
+    }
+
+    /** Take a token for forwarded packet. Returns `false` if packets rate
+    limit is exceeded
+    */
+    pub fn take_packet_token(&mut self) -> bool {
+        self.packets_bucket.as_mut().map_or(true, |bucket| bucket.try_take(1))
+    }
+
+    /** Take tokens for forwarded bytes. Returns `false` if bytes rate limit is
+    exceeded
+    */
+    pub fn take_bytes_tokens(&mut self, bytes: usize) -> bool {
+        self.bytes_bucket.as_mut().map_or(true, |bucket| bucket.try_take(bytes as u64))
+    }
+
+    /** Number of links of the client
+    */
+    pub fn links_count(&self) -> usize {
+        self.links.iter().filter(|link| link.is_some()).count()
+    }
+
     /** Return index of of the link by PK
 
     Some(index + 16) if link exists
@@ -0,0 +1,85 @@
+/*! Limits that protect TCP relay server from abusive clients
+*/
+
+use std::net::IpAddr;
+
+pub use toxcore::utils::RateLimit;
+
+/** Configurable limits of TCP relay server. `None` means that there is no
+limit. By default nothing is limited.
+
+Connections that exceed the limit of pending connections are closed before
+the handshake. Clients that exceed limits on the number of clients are
+disconnected right after the handshake. Clients that exceed rate limits of
+forwarded `Data`, `OobSend` and `OnionRequest` packets are disconnected as
+well. Clients that exceed the limit of links get `RouteResponse` with 0
+connection id as if there is no free space for a new link.
+*/
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ServerLimits {
+    /// Maximum number of connected clients
+    pub max_clients: Option<usize>,
+    /// Maximum number of clients connected from the same IP address
+    pub max_clients_per_ip: Option<usize>,
+    /// Maximum number of clients connected from the same subnet which is /24
+    /// for IPv4 and /64 for IPv6
+    pub max_clients_per_subnet: Option<usize>,
+    /// Maximum number of links each client can have
+    pub max_links_per_client: Option<usize>,
+    /// Maximum number of connections that didn't complete the handshake yet
+    pub max_pending_connections: Option<usize>,
+    /// Rate limit of forwarded packets per client
+    pub packets_rate: Option<RateLimit>,
+    /// Rate limit of forwarded bytes per client
+    pub bytes_rate: Option<RateLimit>,
+}
+
+/// Counters of limits violations
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct LimitViolations {
+    /// Number of clients rejected because of `max_clients` limit
+    pub clients: u64,
+    /// Number of clients rejected because of `max_clients_per_ip` limit
+    pub clients_per_ip: u64,
+    /// Number of clients rejected because of `max_clients_per_subnet` limit
+    pub clients_per_subnet: u64,
+    /// Number of connections rejected because of `max_pending_connections`
+    /// limit
+    pub pending_connections: u64,
+    /// Number of `RouteRequest`s rejected because of `max_links_per_client`
+    /// limit
+    pub links: u64,
+    /// Number of clients disconnected because of `packets_rate` limit
+    pub packets_rate: u64,
+    /// Number of clients disconnected because of `bytes_rate` limit
+    pub bytes_rate: u64,
+}
+
+/// Get subnet of the IP address which is /24 for IPv4 and /64 for IPv6.
+pub fn subnet(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let o = ip.octets();
+            IpAddr::from([o[0], o[1], o[2], 0])
+        },
+        IpAddr::V6(ip) => {
+            let s = ip.segments();
+            IpAddr::from([s[0], s[1], s[2], s[3], 0, 0, 0, 0])
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subnet_v4() {
+        assert_eq!(subnet("1.2.3.4".parse().unwrap()), "1.2.3.0".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn subnet_v6() {
+        assert_eq!(subnet("1:2:3:4:5:6:7:8".parse().unwrap()), "1:2:3:4::".parse::<IpAddr>().unwrap());
+    }
+}
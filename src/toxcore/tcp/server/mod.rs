@@ -2,9 +2,13 @@
 */
 
 mod client;
+mod limits;
 mod server;
 mod server_ext;
+mod stats;
 
 pub use self::client::Client;
-pub use self::server::Server;
-pub use self::server_ext::ServerExt;
+pub use self::limits::{LimitViolations, RateLimit, ServerLimits};
+pub use self::server::{ActiveConnection, PendingConnection, Server};
+pub use self::server_ext::{ServerExt, ShutdownHandle, ShutdownSignal};
+pub use self::stats::{ClientInfo, ListenerInfo, RelayStats, ServerStats, TrafficStats};
@@ -4,6 +4,9 @@
 use toxcore::crypto_core::*;
 use toxcore::onion::packet::InnerOnionResponse;
 use toxcore::tcp::server::client::Client;
+use toxcore::tcp::server::limits::*;
+use toxcore::tcp::server::server_ext::{TCP_HANDSHAKE_TIMEOUT, TCP_IDLE_TIMEOUT};
+use toxcore::tcp::server::stats::*;
 use toxcore::tcp::packet::*;
 use toxcore::io_tokio::IoFuture;
 
@@ -11,7 +14,7 @@ use std::io::{Error, ErrorKind};
 use std::collections::HashMap;
 use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use futures::{Sink, Stream, Future, future, stream};
 use futures::sync::mpsc;
@@ -28,17 +31,67 @@ responses from UPD socket and send them to `Server::handle_udp_onion_response`,
 channels, take packets from `futures::sync::mpsc::UnboundedReceiver<Packet>` send them back
 to clients via network.
 */
-#[derive(Default, Clone)]
+#[derive(Clone)]
 pub struct Server {
     state: Arc<RwLock<ServerState>>,
     // None if the server is not responsible to handle OnionRequests
     onion_sink: Option<mpsc::UnboundedSender<(OnionRequest, SocketAddr)>>,
+    // Limits that protect the server from abusive clients
+    limits: ServerLimits,
+    // Time for the client to complete the handshake
+    handshake_timeout: Duration,
+    // Time for receiving packets from the client before it's disconnected
+    idle_timeout: Duration,
+}
+
+impl Default for Server {
+    fn default() -> Server {
+        Server {
+            state: Arc::new(RwLock::new(ServerState::default())),
+            onion_sink: None,
+            limits: ServerLimits::default(),
+            handshake_timeout: Duration::from_secs(TCP_HANDSHAKE_TIMEOUT),
+            idle_timeout: Duration::from_secs(TCP_IDLE_TIMEOUT),
+        }
+    }
 }
 
 #[derive(Default)]
 struct ServerState {
     pub connected_clients: HashMap<PublicKey, Client>,
     pub keys_by_addr: HashMap<(IpAddr, /*port*/ u16), PublicKey>,
+    pub violations: LimitViolations,
+    pub pending_connections: usize,
+    pub active_connections: usize,
+    pub shutting_down: bool,
+    pub stats: RelayStats,
+    pub listeners: HashMap<SocketAddr, ListenerInfo>,
+}
+
+/** A connection that didn't complete the handshake yet. The number of pending
+connections of the server is decreased when it's dropped.
+*/
+pub struct PendingConnection {
+    state: Arc<RwLock<ServerState>>,
+}
+
+impl Drop for PendingConnection {
+    fn drop(&mut self) {
+        self.state.write().pending_connections -= 1;
+    }
+}
+
+/** A connection of the client that completed the handshake. The number of
+active connections of the server is decreased when it's dropped.
+*/
+pub struct ActiveConnection {
+    state: Arc<RwLock<ServerState>>,
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.state.write().active_connections -= 1;
+    }
 }
 
 
@@ -53,15 +106,239 @@ impl Server {
     pub fn set_udp_onion_sink(&mut self, onion_sink: mpsc::UnboundedSender<(OnionRequest, SocketAddr)>) {
         self.onion_sink = Some(onion_sink)
     }
-    /** Insert the client into connected_clients. Do nothing else.
+    /** Set limits that protect the server from abusive clients
+    */
+    pub fn set_limits(&mut self, limits: ServerLimits) {
+        self.limits = limits
+    }
+    /** Set time for the client to complete the handshake. Default value is
+    `TCP_HANDSHAKE_TIMEOUT` seconds.
+    */
+    pub fn set_handshake_timeout(&mut self, handshake_timeout: Duration) {
+        self.handshake_timeout = handshake_timeout
+    }
+    /** Get time for the client to complete the handshake
+    */
+    pub fn handshake_timeout(&self) -> Duration {
+        self.handshake_timeout
+    }
+    /** Set time for receiving packets from the client after which it's
+    disconnected. Default value is `TCP_IDLE_TIMEOUT` seconds.
+    */
+    pub fn set_idle_timeout(&mut self, idle_timeout: Duration) {
+        self.idle_timeout = idle_timeout
+    }
+    /** Get time for receiving packets from the client after which it's
+    disconnected
+    */
+    pub fn idle_timeout(&self) -> Duration {
+        self.idle_timeout
+    }
+    /** Get counters of limits violations
+    */
+    pub fn limit_violations(&self) -> LimitViolations {
+        self.state.read().violations
+    }
+    /** Get snapshot of the server statistics
+    */
+    pub fn stats(&self) -> ServerStats {
+        let state = self.state.read();
+        ServerStats {
+            clients: state.connected_clients.values().map(Client::info).collect(),
+            relay: state.stats,
+            limit_violations: state.violations,
+            listeners: state.listeners.values().cloned().collect(),
+        }
+    }
+    /** Get information about connected client by its PK
+    */
+    pub fn client_info(&self, pk: &PublicKey) -> Option<ClientInfo> {
+        self.state.read().connected_clients.get(pk).map(Client::info)
+    }
+    /** Forcibly disconnect the client by its PK. Linked clients get
+    DisconnectNotification the same way as if the client disconnected itself.
+    */
+    pub fn disconnect_client(&self, pk: &PublicKey) -> IoFuture<()> {
+        debug!("Disconnecting TCP client {:?} by admin request", pk);
+        self.shutdown_client(pk)
+    }
+    /** Count a connection that failed to complete the handshake
+    */
+    pub fn add_handshake_failure(&self) {
+        self.state.write().stats.handshake_failures += 1;
+    }
+    /** Register a new connection that is going to make the handshake. Return
+    `None` and count the violation if there are too many pending connections.
+    */
+    pub fn add_pending_connection(&self) -> Option<PendingConnection> {
+        let mut state = self.state.write();
+        if let Some(max_pending_connections) = self.limits.max_pending_connections {
+            if state.pending_connections >= max_pending_connections {
+                state.violations.pending_connections += 1;
+                return None
+            }
+        }
+        state.pending_connections += 1;
+        Some(PendingConnection { state: self.state.clone() })
+    }
+    /** Register a listener with the given local address. New listeners are
+    enabled. Statistics of the listener are kept if it's already registered.
+    */
+    pub fn add_listener(&self, addr: SocketAddr) {
+        self.state.write().listeners.entry(addr).or_insert(ListenerInfo {
+            addr,
+            enabled: true,
+            accepted_connections: 0,
+            dropped_connections: 0,
+        });
+    }
+    /** Enable or disable the listener with the given local address. Disabled
+    listener drops new connections while already connected clients stay
+    connected.
+    */
+    pub fn set_listener_enabled(&self, addr: &SocketAddr, enabled: bool) -> Result<(), Error> {
+        if let Some(listener) = self.state.write().listeners.get_mut(addr) {
+            listener.enabled = enabled;
+            Ok(())
+        } else {
+            Err(Error::new(ErrorKind::Other, "Cannot find listener by addr"))
+        }
+    }
+    /** Get information about the listener by its local address
+    */
+    pub fn listener_info(&self, addr: &SocketAddr) -> Option<ListenerInfo> {
+        self.state.read().listeners.get(addr).cloned()
+    }
+    /** Check if the listener with the given local address should accept a
+    new connection and count it.
+    */
+    pub fn accept_connection(&self, addr: &SocketAddr) -> bool {
+        let mut state = self.state.write();
+        if let Some(listener) = state.listeners.get_mut(addr) {
+            if listener.enabled {
+                listener.accepted_connections += 1;
+            } else {
+                listener.dropped_connections += 1;
+            }
+            listener.enabled
+        } else {
+            false
+        }
+    }
+    /** Register a new connection of the client that completed the handshake.
+    The connection is considered active until the returned guard is dropped.
+    */
+    pub fn add_active_connection(&self) -> ActiveConnection {
+        self.state.write().active_connections += 1;
+        ActiveConnection { state: self.state.clone() }
+    }
+    /** Get the number of connections that completed the handshake and are
+    not closed yet
+    */
+    pub fn active_connections(&self) -> usize {
+        self.state.read().active_connections
+    }
+    /** Check if the server is shutting down and doesn't accept new clients
+    */
+    pub fn is_shutting_down(&self) -> bool {
+        self.state.read().shutting_down
+    }
+    /** Gracefully shutdown all connected clients. Linked clients get
+    DisconnectNotification before they are removed. New clients are not
+    accepted after this call.
+    */
+    pub fn shutdown(&self) -> IoFuture<()> {
+        let mut state = self.state.write();
+        state.shutting_down = true;
+
+        let keys = state.connected_clients.keys()
+            .cloned()
+            .collect::<Vec<PublicKey>>();
+
+        debug!("Shutting down {} TCP clients", keys.len());
+
+        let shutdowns = keys.iter()
+            .map(|key| {
+                self.shutdown_client_inner(key, &mut state)
+            })
+            .collect::<Vec<_>>();
+
+        let shutdown_stream = stream::futures_unordered(shutdowns).then(|_| Ok(()));
+
+        Box::new(shutdown_stream.for_each(Ok))
+    }
+    /** Insert the client into connected_clients without checking limits on
+    the number of clients. Rate limits are applied to the client.
     */
     pub fn insert(&self, client: Client) {
         let mut state = self.state.write();
+        self.insert_inner(client, &mut state)
+    }
+    /** Insert the client into connected_clients if it doesn't exceed limits
+    on the number of clients. Otherwise return an error and count the
+    violation.
+    */
+    pub fn try_insert(&self, client: Client) -> Result<(), Error> {
+        let mut state = self.state.write();
+
+        if state.shutting_down {
+            return Err(Error::new(ErrorKind::Other, "Server is shutting down"))
+        }
+        if let Some(max_clients) = self.limits.max_clients {
+            if state.connected_clients.len() >= max_clients {
+                state.violations.clients += 1;
+                return Err(Error::new(ErrorKind::Other, "Too many clients"))
+            }
+        }
+        if let Some(max_clients_per_ip) = self.limits.max_clients_per_ip {
+            let count = state.connected_clients.values()
+                .filter(|other| other.ip_addr() == client.ip_addr())
+                .count();
+            if count >= max_clients_per_ip {
+                state.violations.clients_per_ip += 1;
+                return Err(Error::new(ErrorKind::Other, "Too many clients from the same IP address"))
+            }
+        }
+        if let Some(max_clients_per_subnet) = self.limits.max_clients_per_subnet {
+            let client_subnet = subnet(client.ip_addr());
+            let count = state.connected_clients.values()
+                .filter(|other| subnet(other.ip_addr()) == client_subnet)
+                .count();
+            if count >= max_clients_per_subnet {
+                state.violations.clients_per_subnet += 1;
+                return Err(Error::new(ErrorKind::Other, "Too many clients from the same subnet"))
+            }
+        }
+
+        self.insert_inner(client, &mut state);
+        Ok(())
+    }
+    /** Actual insertion is done here.
+    */
+    fn insert_inner(&self, mut client: Client, state: &mut ServerState) {
+        client.set_rate_limits(self.limits.packets_rate, self.limits.bytes_rate);
         state.keys_by_addr
             .insert((client.ip_addr(), client.port()), client.pk());
         state.connected_clients
             .insert(client.pk(), client);
     }
+    /** Take tokens from the client's buckets for a forwarded packet with
+    payload of the given size. Return an error and count the violation if
+    the client exceeds rate limits.
+    */
+    fn check_rate_limits(state: &mut ServerState, pk: &PublicKey, size: usize) -> Result<(), Error> {
+        if let Some(client) = state.connected_clients.get_mut(pk) {
+            if !client.take_packet_token() {
+                state.violations.packets_rate += 1;
+                return Err(Error::new(ErrorKind::Other, "Packets rate limit is exceeded"))
+            }
+            if !client.take_bytes_tokens(size) {
+                state.violations.bytes_rate += 1;
+                return Err(Error::new(ErrorKind::Other, "Bytes rate limit is exceeded"))
+            }
+        }
+        Ok(())
+    }
     /**The main processing function. Call in on each incoming packet from connected and
     handshaked client.
     */
@@ -83,8 +360,15 @@ impl Server {
     /** Send `OnionResponse` packet to the client by it's `std::net::IpAddr`.
     */
     pub fn handle_udp_onion_response(&self, ip_addr: IpAddr, port: u16, payload: InnerOnionResponse) -> IoFuture<()> {
-        let state = self.state.read();
-        if let Some(client) = state.keys_by_addr.get(&(ip_addr, port)).and_then(|pk| state.connected_clients.get(pk)) {
+        let mut state = self.state.write();
+        let state = &mut *state;
+        let connected_clients = &state.connected_clients;
+        if let Some(client) = state.keys_by_addr.get(&(ip_addr, port)).and_then(|pk| connected_clients.get(pk)) {
+            let size = match payload {
+                InnerOnionResponse::OnionAnnounceResponse(ref response) => response.payload.len(),
+                InnerOnionResponse::OnionDataResponse(ref response) => response.payload.len(),
+            };
+            state.stats.onion_responses.add(size);
             client.send_onion_response(payload)
         } else {
             Box::new( future::err(
@@ -139,6 +423,7 @@ impl Server {
 
     fn handle_route_request(&self, pk: &PublicKey, packet: &RouteRequest) -> IoFuture<()> {
         let mut state = self.state.write();
+        let state = &mut *state;
         let b_id_in_client_a = {
             // check if client was already linked to pk
             if let Some(client_a) = state.connected_clients.get_mut(pk) {
@@ -149,6 +434,10 @@ impl Server {
                 if let Some(b_id_in_client_a) = client_a.get_connection_id(&packet.pk) {
                     // send RouteResponse if client was already linked to pk
                     return client_a.send_route_response(&packet.pk, b_id_in_client_a)
+                } else if self.limits.max_links_per_client.map_or(false, |max_links| client_a.links_count() >= max_links) {
+                    // send RouteResponse(0) if client has too many links
+                    state.violations.links += 1;
+                    return client_a.send_route_response(&packet.pk, 0)
                 } else if let Some(b_id_in_client_a) = client_a.insert_connection_id(&packet.pk) {
                     // new link was inserted into client.links
                     b_id_in_client_a
@@ -287,8 +576,13 @@ impl Server {
                     "OobSend wrong data length"
             )))
         }
-        let state = self.state.read();
+        let mut state = self.state.write();
+        if let Err(e) = Server::check_rate_limits(&mut state, pk, packet.data.len()) {
+            return Box::new(future::err(e))
+        }
+        let state = &mut *state;
         if let Some(client_b) = state.connected_clients.get(&packet.destination_pk) {
+            state.stats.oob.add(packet.data.len());
             client_b.send_oob(pk, packet.data)
         } else {
             // Do nothing because client_b is not connected to server
@@ -303,9 +597,14 @@ impl Server {
     }
     fn handle_onion_request(&self, pk: &PublicKey, packet: OnionRequest) -> IoFuture<()> {
         if let Some(ref onion_sink) = self.onion_sink {
-            let state = self.state.read();
+            let mut state = self.state.write();
+            if let Err(e) = Server::check_rate_limits(&mut state, pk, packet.payload.len()) {
+                return Box::new(future::err(e))
+            }
+            let state = &mut *state;
             if let Some(client) = state.connected_clients.get(&pk) {
                 let saddr = SocketAddr::new(client.ip_addr(), client.port());
+                state.stats.onion_requests.add(packet.payload.len());
                 Box::new(onion_sink.clone() // clone sink for 1 send only
                     .send((packet, saddr))
                     .map(|_sink| ()) // ignore sink because it was cloned
@@ -333,7 +632,11 @@ impl Server {
         )))
     }
     fn handle_data(&self, pk: &PublicKey, packet: Data) -> IoFuture<()> {
-        let state = self.state.read();
+        let mut state = self.state.write();
+        if let Err(e) = Server::check_rate_limits(&mut state, pk, packet.data.len()) {
+            return Box::new(future::err(e))
+        }
+        let state = &mut *state;
         let client_b_pk = {
             if let Some(client_a) = state.connected_clients.get(pk) {
                 if let Some(client_b_pk) = client_a.get_link(packet.connection_id) {
@@ -355,6 +658,7 @@ impl Server {
         };
         if let Some(client_b) = state.connected_clients.get(&client_b_pk) {
             if let Some(a_id_in_client_b) = client_b.get_connection_id(pk) {
+                state.stats.data.add(packet.data.len());
                 client_b.send_data(a_id_in_client_b, packet.data)
             } else {
                 // Do nothing because
@@ -374,6 +678,8 @@ impl Server {
             .map(|(key, _client)| *key)
             .collect::<Vec<PublicKey>>();
 
+        state.stats.ping_timeouts += keys.len() as u64;
+
         let remove_timedouts = keys.iter()
             .map(|key| {
                 self.shutdown_client_inner(key, state)
@@ -410,6 +716,8 @@ mod tests {
     use ::toxcore::tcp::packet::*;
     use ::toxcore::tcp::server::{Client, Server};
     use toxcore::tcp::server::client::*;
+    use toxcore::tcp::server::limits::*;
+    use toxcore::tcp::server::stats::*;
 
     use futures::sync::mpsc;
     use futures::{Stream, Future};
@@ -799,6 +1107,318 @@ mod tests {
         ));
     }
     #[test]
+    fn add_pending_connection() {
+        let mut server = Server::new();
+        server.set_limits(ServerLimits { max_pending_connections: Some(1), ..ServerLimits::default() });
+
+        let pending_connection = server.add_pending_connection();
+        assert!(pending_connection.is_some());
+        assert!(server.add_pending_connection().is_none());
+        assert_eq!(server.limit_violations().pending_connections, 1);
+
+        // dropped connection frees the slot
+        drop(pending_connection);
+        assert!(server.add_pending_connection().is_some());
+    }
+    #[test]
+    fn stats() {
+        let server = Server::new();
+
+        let (client_1, _rx_1) = create_random_client("1.2.3.4:12345".parse().unwrap());
+        let client_pk_1 = client_1.pk();
+        server.insert(client_1);
+
+        let (client_2, _rx_2) = create_random_client("1.2.3.5:12345".parse().unwrap());
+        let client_pk_2 = client_2.pk();
+        server.insert(client_2);
+
+        // link clients with each other
+        server.handle_packet(&client_pk_1, Packet::RouteRequest(
+            RouteRequest { pk: client_pk_2 }
+        )).wait().unwrap();
+        server.handle_packet(&client_pk_2, Packet::RouteRequest(
+            RouteRequest { pk: client_pk_1 }
+        )).wait().unwrap();
+
+        server.handle_packet(&client_pk_1, Packet::Data(
+            Data { connection_id: 16, data: vec![13; 42] }
+        )).wait().unwrap();
+        server.handle_packet(&client_pk_1, Packet::OobSend(
+            OobSend { destination_pk: client_pk_2, data: vec![13; 24] }
+        )).wait().unwrap();
+        server.handle_packet(&client_pk_2, Packet::OobSend(
+            OobSend { destination_pk: client_pk_1, data: vec![13; 24] }
+        )).wait().unwrap();
+
+        let stats = server.stats();
+        assert_eq!(stats.clients.len(), 2);
+        assert_eq!(stats.relay.data, TrafficStats { packets: 1, bytes: 42 });
+        assert_eq!(stats.relay.oob, TrafficStats { packets: 2, bytes: 48 });
+        assert_eq!(stats.relay.onion_requests, TrafficStats::default());
+
+        let info = server.client_info(&client_pk_1).unwrap();
+        assert_eq!(info.pk, client_pk_1);
+        assert_eq!(info.ip_addr, "1.2.3.4".parse::<IpAddr>().unwrap());
+        assert_eq!(info.port, 12345);
+        assert_eq!(info.links_count, 1);
+
+        assert!(server.client_info(&gen_keypair().0).is_none());
+    }
+    #[test]
+    fn disconnect_client() {
+        let server = Server::new();
+
+        let (client_1, _rx_1) = create_random_client("1.2.3.4:12345".parse().unwrap());
+        let client_pk_1 = client_1.pk();
+        server.insert(client_1);
+
+        let (client_2, rx_2) = create_random_client("1.2.3.5:12345".parse().unwrap());
+        let client_pk_2 = client_2.pk();
+        server.insert(client_2);
+
+        server.handle_packet(&client_pk_1, Packet::RouteRequest(
+            RouteRequest { pk: client_pk_2 }
+        )).wait().unwrap();
+        server.handle_packet(&client_pk_2, Packet::RouteRequest(
+            RouteRequest { pk: client_pk_1 }
+        )).wait().unwrap();
+
+        server.disconnect_client(&client_pk_1).wait().unwrap();
+
+        assert!(server.client_info(&client_pk_1).is_none());
+
+        // the linked client is notified
+        let packets = rx_2.take(3).collect().wait().unwrap();
+        assert_eq!(packets[2], Packet::DisconnectNotification(
+            DisconnectNotification { connection_id: 16 }
+        ));
+    }
+    #[test]
+    fn shutdown() {
+        let server = Server::new();
+
+        let (client_1, rx_1) = create_random_client("1.2.3.4:12345".parse().unwrap());
+        let client_pk_1 = client_1.pk();
+        server.insert(client_1);
+
+        let (client_2, rx_2) = create_random_client("1.2.3.5:12345".parse().unwrap());
+        let client_pk_2 = client_2.pk();
+        server.insert(client_2);
+
+        // link clients with each other
+        server.handle_packet(&client_pk_1, Packet::RouteRequest(
+            RouteRequest { pk: client_pk_2 }
+        )).wait().unwrap();
+        server.handle_packet(&client_pk_2, Packet::RouteRequest(
+            RouteRequest { pk: client_pk_1 }
+        )).wait().unwrap();
+
+        server.shutdown().wait().unwrap();
+
+        assert!(server.is_shutting_down());
+        assert!(server.stats().clients.is_empty());
+
+        // one of the clients is notified about the other one and all
+        // channels are closed
+        let packets_1 = rx_1.collect().wait().unwrap();
+        let packets_2 = rx_2.collect().wait().unwrap();
+        let notifications = packets_1.iter().chain(packets_2.iter())
+            .filter(|packet| **packet == Packet::DisconnectNotification(
+                DisconnectNotification { connection_id: 16 }
+            ))
+            .count();
+        assert_eq!(notifications, 1);
+
+        // new clients are rejected
+        let (client_3, _rx_3) = create_random_client("1.2.3.6:12345".parse().unwrap());
+        assert!(server.try_insert(client_3).is_err());
+    }
+    #[test]
+    fn add_active_connection() {
+        let server = Server::new();
+
+        let active_connection = server.add_active_connection();
+        assert_eq!(server.active_connections(), 1);
+
+        drop(active_connection);
+        assert_eq!(server.active_connections(), 0);
+    }
+    #[test]
+    fn listeners() {
+        let server = Server::new();
+
+        let addr_1 = "0.0.0.0:33445".parse().unwrap();
+        let addr_2 = "[::]:443".parse().unwrap();
+        server.add_listener(addr_1);
+        server.add_listener(addr_2);
+
+        assert!(server.accept_connection(&addr_1));
+        assert!(server.accept_connection(&addr_2));
+
+        server.set_listener_enabled(&addr_2, false).unwrap();
+        assert!(server.accept_connection(&addr_1));
+        assert!(!server.accept_connection(&addr_2));
+
+        assert_eq!(server.listener_info(&addr_1).unwrap(), ListenerInfo {
+            addr: addr_1,
+            enabled: true,
+            accepted_connections: 2,
+            dropped_connections: 0,
+        });
+        assert_eq!(server.listener_info(&addr_2).unwrap(), ListenerInfo {
+            addr: addr_2,
+            enabled: false,
+            accepted_connections: 1,
+            dropped_connections: 1,
+        });
+        assert_eq!(server.stats().listeners.len(), 2);
+
+        // unknown listener
+        let addr_3 = "0.0.0.0:3389".parse().unwrap();
+        assert!(!server.accept_connection(&addr_3));
+        assert!(server.set_listener_enabled(&addr_3, false).is_err());
+        assert!(server.listener_info(&addr_3).is_none());
+    }
+    #[test]
+    fn try_insert_max_clients() {
+        let mut server = Server::new();
+        server.set_limits(ServerLimits { max_clients: Some(1), ..ServerLimits::default() });
+
+        let (client_1, _rx_1) = create_random_client("1.2.3.4:12345".parse().unwrap());
+        assert!(server.try_insert(client_1).is_ok());
+
+        let (client_2, _rx_2) = create_random_client("1.2.4.5:12345".parse().unwrap());
+        let client_pk_2 = client_2.pk();
+        assert!(server.try_insert(client_2).is_err());
+
+        assert!(!server.state.read().connected_clients.contains_key(&client_pk_2));
+        assert_eq!(server.limit_violations().clients, 1);
+    }
+    #[test]
+    fn try_insert_max_clients_per_ip() {
+        let mut server = Server::new();
+        server.set_limits(ServerLimits { max_clients_per_ip: Some(1), ..ServerLimits::default() });
+
+        let (client_1, _rx_1) = create_random_client("1.2.3.4:12345".parse().unwrap());
+        assert!(server.try_insert(client_1).is_ok());
+
+        let (client_2, _rx_2) = create_random_client("1.2.3.4:12346".parse().unwrap());
+        assert!(server.try_insert(client_2).is_err());
+
+        // another IP is fine
+        let (client_3, _rx_3) = create_random_client("1.2.3.5:12345".parse().unwrap());
+        assert!(server.try_insert(client_3).is_ok());
+
+        assert_eq!(server.limit_violations().clients_per_ip, 1);
+    }
+    #[test]
+    fn try_insert_max_clients_per_subnet() {
+        let mut server = Server::new();
+        server.set_limits(ServerLimits { max_clients_per_subnet: Some(2), ..ServerLimits::default() });
+
+        let (client_1, _rx_1) = create_random_client("1.2.3.4:12345".parse().unwrap());
+        assert!(server.try_insert(client_1).is_ok());
+
+        let (client_2, _rx_2) = create_random_client("1.2.3.5:12345".parse().unwrap());
+        assert!(server.try_insert(client_2).is_ok());
+
+        let (client_3, _rx_3) = create_random_client("1.2.3.6:12345".parse().unwrap());
+        assert!(server.try_insert(client_3).is_err());
+
+        // another subnet is fine
+        let (client_4, _rx_4) = create_random_client("1.2.4.6:12345".parse().unwrap());
+        assert!(server.try_insert(client_4).is_ok());
+
+        assert_eq!(server.limit_violations().clients_per_subnet, 1);
+    }
+    #[test]
+    fn handle_route_request_max_links() {
+        let mut server = Server::new();
+        server.set_limits(ServerLimits { max_links_per_client: Some(1), ..ServerLimits::default() });
+
+        let (client_1, rx_1) = create_random_client("1.2.3.4:12345".parse().unwrap());
+        let client_pk_1 = client_1.pk();
+        server.insert(client_1);
+
+        let client_pk_2 = gen_keypair().0;
+        let client_pk_3 = gen_keypair().0;
+
+        server.handle_packet(&client_pk_1, Packet::RouteRequest(
+            RouteRequest { pk: client_pk_2 }
+        )).wait().unwrap();
+        let (packet, rx_1) = rx_1.into_future().wait().unwrap();
+        assert_eq!(packet.unwrap(), Packet::RouteResponse(
+            RouteResponse { pk: client_pk_2, connection_id: 16 }
+        ));
+
+        server.handle_packet(&client_pk_1, Packet::RouteRequest(
+            RouteRequest { pk: client_pk_3 }
+        )).wait().unwrap();
+        let (packet, rx_1) = rx_1.into_future().wait().unwrap();
+        assert_eq!(packet.unwrap(), Packet::RouteResponse(
+            RouteResponse { pk: client_pk_3, connection_id: 0 }
+        ));
+
+        // existing link is still returned
+        server.handle_packet(&client_pk_1, Packet::RouteRequest(
+            RouteRequest { pk: client_pk_2 }
+        )).wait().unwrap();
+        let (packet, _rx_1) = rx_1.into_future().wait().unwrap();
+        assert_eq!(packet.unwrap(), Packet::RouteResponse(
+            RouteResponse { pk: client_pk_2, connection_id: 16 }
+        ));
+
+        assert_eq!(server.limit_violations().links, 1);
+    }
+    #[test]
+    fn handle_oob_send_packets_rate() {
+        let mut server = Server::new();
+        server.set_limits(ServerLimits {
+            packets_rate: Some(RateLimit { rate: 1, burst: 2 }),
+            ..ServerLimits::default()
+        });
+
+        let (client_1, _rx_1) = create_random_client("1.2.3.4:12345".parse().unwrap());
+        let client_pk_1 = client_1.pk();
+        server.insert(client_1);
+
+        let client_pk_2 = gen_keypair().0;
+
+        for _ in 0 .. 2 {
+            assert!(server.handle_packet(&client_pk_1, Packet::OobSend(
+                OobSend { destination_pk: client_pk_2, data: vec![13; 42] }
+            )).wait().is_ok());
+        }
+
+        assert!(server.handle_packet(&client_pk_1, Packet::OobSend(
+            OobSend { destination_pk: client_pk_2, data: vec![13; 42] }
+        )).wait().is_err());
+
+        assert_eq!(server.limit_violations().packets_rate, 1);
+    }
+    #[test]
+    fn handle_data_bytes_rate() {
+        let mut server = Server::new();
+        server.set_limits(ServerLimits {
+            bytes_rate: Some(RateLimit { rate: 100, burst: 100 }),
+            ..ServerLimits::default()
+        });
+
+        let (client_1, _rx_1) = create_random_client("1.2.3.4:12345".parse().unwrap());
+        let client_pk_1 = client_1.pk();
+        server.insert(client_1);
+
+        assert!(server.handle_packet(&client_pk_1, Packet::Data(
+            Data { connection_id: 16, data: vec![13; 60] }
+        )).wait().is_ok());
+
+        assert!(server.handle_packet(&client_pk_1, Packet::Data(
+            Data { connection_id: 16, data: vec![13; 60] }
+        )).wait().is_err());
+
+        assert_eq!(server.limit_violations().bytes_rate, 1);
+    }
+    #[test]
     fn handle_onion_request() {
         let (udp_onion_sink, udp_onion_stream) = mpsc::unbounded();
         let mut server = Server::new();
@@ -1398,5 +2018,6 @@ mod tests {
         assert!(!server.state.read().connected_clients.contains_key(&pk_1));
         assert!(!server.state.read().connected_clients.contains_key(&pk_2));
         assert!(server.state.read().connected_clients.contains_key(&pk_3));
+        assert_eq!(server.stats().relay.ping_timeouts, 2);
     }
 }
@@ -1,14 +1,17 @@
 /*! Extension trait for run TCP server on `TcpStream` and ping sender
 */
 
-use std::io::{Error as IoError};
+use std::io::{Error as IoError, ErrorKind};
+use std::net::SocketAddr;
 use std::time::{Duration, Instant};
 
-use futures::{future, Future, Sink, Stream};
-use futures::sync::mpsc;
+use futures::{future, stream, Async, Future, Poll, Sink, Stream};
+use futures::future::Either;
+use futures::sync::{mpsc, oneshot};
 use tokio;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::{TcpStream, TcpListener};
-use tokio::util::FutureExt;
+use tokio::util::{FutureExt, StreamExt};
 use tokio_codec::Framed;
 use tokio::timer::{Error as TimerError, Interval};
 use tokio::timer::timeout::{Error as TimeoutError};
@@ -17,10 +20,66 @@ use toxcore::crypto_core::*;
 use toxcore::tcp::codec::{DecodeError, EncodeError, Codec};
 use toxcore::tcp::handshake::make_server_handshake;
 use toxcore::tcp::server::{Client, Server};
+use toxcore::tcp::server::client;
+use toxcore::tcp::websocket;
 
 /// Interval in seconds for Tcp Ping sender
 const TCP_PING_INTERVAL: u64 = 1;
 
+/// Timeout in seconds for the client to complete the handshake
+pub const TCP_HANDSHAKE_TIMEOUT: u64 = 10;
+
+/// Timeout in seconds for receiving packets from the client. The server pings
+/// every client so silent clients will be disconnected after this timeout the
+/// same way as clients that don't respond to pings.
+pub const TCP_IDLE_TIMEOUT: u64 = client::TCP_PING_FREQUENCY + client::TCP_PING_TIMEOUT;
+
+/// Default timeout in seconds for flushing clients' write queues during
+/// graceful shutdown
+pub const TCP_DRAIN_TIMEOUT: u64 = 10;
+
+/// Interval in milliseconds for checking whether all connections are closed
+/// during graceful shutdown
+const TCP_DRAIN_CHECK_INTERVAL: u64 = 100;
+
+/** Handle that is used to trigger graceful shutdown of the server started
+with `ServerExt::run_with_shutdown`.
+*/
+pub struct ShutdownHandle {
+    tx: oneshot::Sender<()>,
+}
+
+impl ShutdownHandle {
+    /// Create new `ShutdownHandle` and the signal that should be passed to
+    /// `ServerExt::run_with_shutdown`
+    pub fn new() -> (ShutdownHandle, ShutdownSignal) {
+        let (tx, rx) = oneshot::channel();
+        (ShutdownHandle { tx }, ShutdownSignal { rx })
+    }
+
+    /// Trigger graceful shutdown of the server
+    pub fn shutdown(self) {
+        // ignore error since the server can be already stopped
+        let _ = self.tx.send(());
+    }
+}
+
+/** Future that resolves when `ShutdownHandle::shutdown` is called. It never
+resolves if the handle is dropped without calling `shutdown`.
+*/
+pub struct ShutdownSignal {
+    rx: oneshot::Receiver<()>,
+}
+
+impl Future for ShutdownSignal {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        self.rx.poll().or_else(|oneshot::Canceled| Ok(Async::NotReady))
+    }
+}
+
 /// Error that can happen during server execution
 #[derive(Debug, Fail)]
 pub enum ServerRunError {
@@ -44,6 +103,22 @@ pub enum ServerRunError {
         #[fail(cause)]
         error: IoError
     },
+    /// Error indicates that we couldn't get local address of a listener
+    #[fail(display = "Failed to get local address: {:?}", error)]
+    LocalAddrError {
+        /// Local address error
+        #[fail(cause)]
+        error: IoError
+    },
+    /// Drain wakeups timer error
+    #[fail(display = "Drain wakeups timer error: {:?}", error)]
+    DrainWakeupsError {
+        /// Timer error
+        error: TimerError
+    },
+    /// Error indicates that the server was started without listeners
+    #[fail(display = "No listeners to run the server on")]
+    NoListenersError,
 }
 
 /// Error that can happen during TCP connection execution
@@ -80,6 +155,17 @@ pub enum ConnectionError {
         #[fail(cause)]
         error: IoError
     },
+    /// Error indicates that the client didn't complete the handshake in time
+    #[fail(display = "Server handshake timed out")]
+    ServerHandshakeTimeoutError,
+    /// Error indicates that there are too many connections that didn't
+    /// complete the handshake yet
+    #[fail(display = "Too many pending connections")]
+    TooManyPendingConnectionsError,
+    /// Error indicates that we didn't receive packets from the client for too
+    /// long
+    #[fail(display = "Client is idle for too long")]
+    IdleTimeoutError,
     /// Packet handling error
     #[fail(display = "Packet handling error: {:?}", error)]
     PacketHandlingError {
@@ -87,6 +173,13 @@ pub enum ConnectionError {
         #[fail(cause)]
         error: IoError
     },
+    /// Error indicates that the client was rejected because of server limits
+    #[fail(display = "Client is rejected: {:?}", error)]
+    ClientRejectedError {
+        /// Client insertion error
+        #[fail(cause)]
+        error: IoError
+    },
 }
 
 /// Extension trait for running TCP server on incoming `TcpStream` and ping sender
@@ -95,42 +188,135 @@ pub trait ServerExt {
     /// `tokio::spawn` inside so it should be executed via tokio to be able to
     /// get tokio default executor.
     fn run(self: Self, listner: TcpListener, dht_sk: SecretKey) -> Box<Future<Item = (), Error = ServerRunError> + Send>;
+    /// Running TCP ping sender and incoming `TcpStream` until `shutdown`
+    /// future resolves. Then the server stops accepting new connections,
+    /// disconnects all clients notifying their links and waits up to
+    /// `drain_timeout` for clients' write queues to be flushed.
+    fn run_with_shutdown<S>(self: Self, listner: TcpListener, dht_sk: SecretKey, shutdown: S, drain_timeout: Duration)
+        -> Box<Future<Item = (), Error = ServerRunError> + Send>
+        where S: Future<Item = (), Error = ()> + Send + 'static;
+    /// Running TCP ping sender and incoming `TcpStream`s from several
+    /// listeners until `shutdown` future resolves. Clients connected to
+    /// different listeners share the same server state so they can link to
+    /// each other. Listeners can be disabled at runtime with
+    /// `Server::set_listener_enabled`. At least one listener is required.
+    fn run_listeners<S>(self: Self, listners: Vec<TcpListener>, dht_sk: SecretKey, shutdown: S, drain_timeout: Duration)
+        -> Box<Future<Item = (), Error = ServerRunError> + Send>
+        where S: Future<Item = (), Error = ()> + Send + 'static;
+    /// Running TCP ping sender, incoming `TcpStream`s from several listeners
+    /// and incoming WebSocket connections from `websocket_listners` until
+    /// `shutdown` future resolves. WebSocket clients share the same server
+    /// state with TCP clients.
+    fn run_websocket_listeners<S>(self: Self, listners: Vec<TcpListener>, websocket_listners: Vec<TcpListener>,
+        dht_sk: SecretKey, shutdown: S, drain_timeout: Duration)
+        -> Box<Future<Item = (), Error = ServerRunError> + Send>
+        where S: Future<Item = (), Error = ()> + Send + 'static;
     /// Running TCP server on incoming `TcpStream`
     fn run_connection(self: Self, stream: TcpStream, dht_sk: SecretKey) -> Box<Future<Item = (), Error = ConnectionError> + Send>;
+    /// Running TCP server on incoming `TcpStream` that should be upgraded to
+    /// WebSocket connection
+    fn run_websocket_connection(self: Self, stream: TcpStream, dht_sk: SecretKey) -> Box<Future<Item = (), Error = ConnectionError> + Send>;
 }
 
 impl ServerExt for Server {
     fn run(self: Self, listner: TcpListener, dht_sk: SecretKey) -> Box<Future<Item = (), Error = ServerRunError> + Send> {
-        let self_c = self.clone();
+        self.run_with_shutdown(listner, dht_sk, future::empty(), Duration::from_secs(TCP_DRAIN_TIMEOUT))
+    }
 
-        let connections_future = listner.incoming()
-            .map_err(|error| ServerRunError::IncomingError { error })
-            .for_each(move |stream| {
-                tokio::spawn(
-                    self_c.clone()
-                        .run_connection(stream, dht_sk.clone())
-                        .map_err(|e| {
-                            error!("Error while running tcp connection: {:?}", e);
-                            ()
-                        })
-                );
-                Ok(())
-            });
+    fn run_with_shutdown<S>(self: Self, listner: TcpListener, dht_sk: SecretKey, shutdown: S, drain_timeout: Duration)
+        -> Box<Future<Item = (), Error = ServerRunError> + Send>
+        where S: Future<Item = (), Error = ()> + Send + 'static
+    {
+        self.run_listeners(vec![listner], dht_sk, shutdown, drain_timeout)
+    }
+
+    fn run_listeners<S>(self: Self, listners: Vec<TcpListener>, dht_sk: SecretKey, shutdown: S, drain_timeout: Duration)
+        -> Box<Future<Item = (), Error = ServerRunError> + Send>
+        where S: Future<Item = (), Error = ()> + Send + 'static
+    {
+        self.run_websocket_listeners(listners, Vec::new(), dht_sk, shutdown, drain_timeout)
+    }
+
+    fn run_websocket_listeners<S>(self: Self, listners: Vec<TcpListener>, websocket_listners: Vec<TcpListener>,
+        dht_sk: SecretKey, shutdown: S, drain_timeout: Duration)
+        -> Box<Future<Item = (), Error = ServerRunError> + Send>
+        where S: Future<Item = (), Error = ()> + Send + 'static
+    {
+        if listners.is_empty() && websocket_listners.is_empty() {
+            return Box::new(future::err(ServerRunError::NoListenersError))
+        }
+
+        let mut incomings = Vec::with_capacity(listners.len() + websocket_listners.len());
+        let listners = listners.into_iter().map(|listner| (listner, false))
+            .chain(websocket_listners.into_iter().map(|listner| (listner, true)));
+        for (listner, websocket) in listners {
+            let local_addr = match listner.local_addr() {
+                Ok(local_addr) => local_addr,
+                Err(error) => return Box::new(future::err(ServerRunError::LocalAddrError { error })),
+            };
+            if websocket {
+                debug!("TCP server is listening for WebSocket connections on {}", local_addr);
+            } else {
+                debug!("TCP server is listening on {}", local_addr);
+            }
+            self.add_listener(local_addr);
+
+            let self_c = self.clone();
+            let dht_sk = dht_sk.clone();
+            let incoming = listner.incoming()
+                .map_err(|error| ServerRunError::IncomingError { error })
+                .for_each(move |stream| {
+                    if !self_c.accept_connection(&local_addr) {
+                        trace!("Drop TCP connection since listener {} is disabled", local_addr);
+                        return Ok(())
+                    }
+                    let connection = if websocket {
+                        self_c.clone().run_websocket_connection(stream, dht_sk.clone())
+                    } else {
+                        self_c.clone().run_connection(stream, dht_sk.clone())
+                    };
+                    tokio::spawn(
+                        connection
+                            .map_err(|e| {
+                                error!("Error while running tcp connection: {:?}", e);
+                                ()
+                            })
+                    );
+                    Ok(())
+                });
+            incomings.push(incoming);
+        }
+
+        let connections_future = stream::futures_unordered(incomings).for_each(Ok);
 
         let interval = Duration::from_secs(TCP_PING_INTERVAL);
         let wakeups = Interval::new(Instant::now(), interval);
+        let self_c = self.clone();
         let ping_future = wakeups
             .map_err(|error| ServerRunError::PingWakeupsError { error })
             .for_each(move |_instant| {
                 trace!("Tcp server ping sender wake up");
-                self.send_pings()
+                self_c.send_pings()
                     .map_err(|error| ServerRunError::SendPingsError { error })
             });
 
-        let future = connections_future
+        let serve_future = connections_future
             .select(ping_future)
             .map(|_| ()).map_err(|(e, _)| e);
 
+        let future = serve_future
+            .select2(shutdown)
+            .then(move |result| -> Box<Future<Item = (), Error = ServerRunError> + Send> {
+                match result {
+                    Ok(Either::A(((), _shutdown))) => Box::new(future::ok(())),
+                    Err(Either::A((error, _shutdown))) => Box::new(future::err(error)),
+                    // serve_future is dropped here so new connections are not
+                    // accepted anymore
+                    Ok(Either::B(((), _serve_future))) | Err(Either::B(((), _serve_future))) =>
+                        drain(self, drain_timeout),
+                }
+            });
+
         Box::new(future)
     }
 
@@ -144,65 +330,163 @@ impl ServerExt for Server {
 
         debug!("A new TCP client connected from {}", addr);
 
-        let register_client = make_server_handshake(stream, dht_sk.clone())
-            .map_err(|error| ConnectionError::ServerHandshakeError { error })
-            .map(|(stream, channel, client_pk)| {
-                debug!("Handshake for TCP client {:?} is completed", client_pk);
-                (stream, channel, client_pk)
-            });
+        run_stream(self, addr, future::ok(stream), dht_sk)
+    }
 
-        let server_c = self.clone();
-        let process = register_client.and_then(move |(stream, channel, client_pk)| {
-            let secure_socket = Framed::new(stream, Codec::new(channel));
-            let (to_client, from_client) = secure_socket.split();
-            let (to_client_tx, to_client_rx) = mpsc::unbounded();
-
-            server_c.insert(Client::new(to_client_tx, &client_pk, addr.ip(), addr.port()));
-
-            let server_c_c = server_c.clone();
-            // processor = for each Packet from client process it
-            let processor = from_client
-                .map_err(|error| ConnectionError::DecodePacketError { error })
-                .for_each(move |packet| {
-                    debug!("Handle {:?} => {:?}", client_pk, packet);
-                    server_c_c.handle_packet(&client_pk, packet)
-                        .map_err(|error| ConnectionError::PacketHandlingError { error } )
-                });
+    fn run_websocket_connection(self: Self, stream: TcpStream, dht_sk: SecretKey) -> Box<Future<Item = (), Error = ConnectionError> + Send> {
+        let addr = match stream.peer_addr() {
+            Ok(addr) => addr,
+            Err(error) => return Box::new(future::err(ConnectionError::PeerAddrError {
+                error
+            })),
+        };
 
-            // writer = for each Packet from to_client_rx send it to client
-            let writer = to_client_rx
-                .map_err(|()| unreachable!("rx can't fail"))
-                .fold(to_client, move |to_client, packet| {
-                    trace!("Sending TCP packet {:?} to {:?}", packet, client_pk);
-                    to_client.send(packet)
-                        .timeout(Duration::from_secs(30))
-                        .map_err(|error| ConnectionError::SendPacketError {
-                            error
-                        })
-                })
-                // drop to_client when to_client_rx stream is exhausted
-                .map(|_to_client| ());
-
-            processor
-                .select(writer).map(|_| ()).map_err(|(e, _)| e)
-                .then(move |r_processing| {
-                    debug!("Shutdown a client with PK {:?}", &client_pk);
-                    // ignore shutdown error since the client can be already
-                    // shutdown at this moment
-                    server_c.shutdown_client(&client_pk)
-                        .then(move |_| r_processing)
-                })
-        });
+        debug!("A new WebSocket client connected from {}", addr);
 
-        Box::new(process)
+        run_stream(self, addr, websocket::accept(stream), dht_sk)
     }
 }
 
+/// Running TCP server on the stream that is returned by `upgrade` future.
+/// Both the upgrade and the handshake should be completed in the server's
+/// handshake timeout.
+fn run_stream<T, U>(server: Server, addr: SocketAddr, upgrade: U, dht_sk: SecretKey) -> Box<Future<Item = (), Error = ConnectionError> + Send>
+    where T: AsyncRead + AsyncWrite + Send + 'static,
+          U: Future<Item = T, Error = IoError> + Send + 'static
+{
+    let pending_connection = match server.add_pending_connection() {
+        Some(pending_connection) => pending_connection,
+        None => return Box::new(future::err(ConnectionError::TooManyPendingConnectionsError)),
+    };
+
+    let server_c = server.clone();
+    let register_client = upgrade
+        .and_then(move |stream| make_server_handshake(stream, dht_sk))
+        .timeout(server.handshake_timeout())
+        .map_err(|error|
+            if error.is_elapsed() {
+                ConnectionError::ServerHandshakeTimeoutError
+            } else if error.is_inner() {
+                ConnectionError::ServerHandshakeError { error: error.into_inner().unwrap() }
+            } else {
+                ConnectionError::ServerHandshakeError {
+                    error: IoError::new(ErrorKind::Other, format!("Handshake timer error: {:?}", error))
+                }
+            }
+        )
+        .then(move |result| {
+            // the connection is not pending anymore
+            drop(pending_connection);
+            if result.is_err() {
+                server_c.add_handshake_failure();
+            }
+            result
+        })
+        .map(|(stream, channel, client_pk)| {
+            debug!("Handshake for TCP client {:?} is completed", client_pk);
+            (stream, channel, client_pk)
+        });
+
+    let server_c = server.clone();
+    let process = register_client.and_then(move |(stream, channel, client_pk)| -> Box<Future<Item = (), Error = ConnectionError> + Send> {
+        let secure_socket = Framed::new(stream, Codec::new(channel));
+        let (to_client, from_client) = secure_socket.split();
+        let (to_client_tx, to_client_rx) = mpsc::unbounded();
+
+        if let Err(error) = server_c.try_insert(Client::new(to_client_tx, &client_pk, addr.ip(), addr.port())) {
+            debug!("TCP client {:?} from {} is rejected: {}", client_pk, addr, error);
+            return Box::new(future::err(ConnectionError::ClientRejectedError { error }))
+        }
+        let active_connection = server_c.add_active_connection();
+
+        let server_c_c = server_c.clone();
+        // processor = for each Packet from client process it
+        let processor = from_client
+            .timeout(server_c.idle_timeout())
+            .map_err(|error|
+                if error.is_elapsed() {
+                    ConnectionError::IdleTimeoutError
+                } else if error.is_inner() {
+                    ConnectionError::DecodePacketError { error: error.into_inner().unwrap() }
+                } else {
+                    ConnectionError::IncomingError {
+                        error: IoError::new(ErrorKind::Other, format!("Idle timer error: {:?}", error))
+                    }
+                }
+            )
+            .for_each(move |packet| {
+                debug!("Handle {:?} => {:?}", client_pk, packet);
+                server_c_c.handle_packet(&client_pk, packet)
+                    .map_err(|error| ConnectionError::PacketHandlingError { error } )
+            });
+
+        // writer = for each Packet from to_client_rx send it to client
+        let writer = to_client_rx
+            .map_err(|()| unreachable!("rx can't fail"))
+            .fold(to_client, move |to_client, packet| {
+                trace!("Sending TCP packet {:?} to {:?}", packet, client_pk);
+                to_client.send(packet)
+                    .timeout(Duration::from_secs(30))
+                    .map_err(|error| ConnectionError::SendPacketError {
+                        error
+                    })
+            })
+            // drop to_client when to_client_rx stream is exhausted
+            .map(|_to_client| ());
+
+        Box::new(processor
+            .select(writer).map(|_| ()).map_err(|(e, _)| e)
+            .then(move |r_processing| {
+                debug!("Shutdown a client with PK {:?}", &client_pk);
+                // ignore shutdown error since the client can be already
+                // shutdown at this moment
+                server_c.shutdown_client(&client_pk)
+                    .then(move |_| {
+                        drop(active_connection);
+                        r_processing
+                    })
+            }))
+    });
+
+    Box::new(process)
+}
+
+/// Disconnect all clients of the server and wait up to `drain_timeout` until
+/// their connections are closed.
+fn drain(server: Server, drain_timeout: Duration) -> Box<Future<Item = (), Error = ServerRunError> + Send> {
+    info!("Shutting down TCP server");
+
+    let server_c = server.clone();
+    let interval = Duration::from_millis(TCP_DRAIN_CHECK_INTERVAL);
+    let connections_closed = Interval::new(Instant::now(), interval)
+        .map_err(|error| ServerRunError::DrainWakeupsError { error })
+        .take_while(move |_instant| Ok(server_c.active_connections() > 0))
+        .for_each(|_instant| Ok(()));
+
+    let future = server.shutdown()
+        // ignore shutdown errors since clients can be already disconnected
+        .then(|_| connections_closed)
+        .timeout(drain_timeout)
+        .or_else(move |error|
+            if error.is_elapsed() {
+                warn!("{} TCP connections are not closed after drain timeout", server.active_connections());
+                Ok(())
+            } else if error.is_inner() {
+                Err(error.into_inner().unwrap())
+            } else {
+                Err(ServerRunError::DrainWakeupsError { error: error.into_timer().unwrap() })
+            }
+        );
+
+    Box::new(future)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     use std::io::{ErrorKind as IoErrorKind};
+    use std::net::SocketAddr;
 
     use failure::Error;
 
@@ -211,13 +495,14 @@ mod tests {
 
     use toxcore::tcp::codec::Codec;
     use toxcore::tcp::handshake::make_client_handshake;
-    use toxcore::tcp::packet::{Packet, PingRequest, PongResponse};
+    use toxcore::tcp::packet::*;
 
     use tokio_executor;
     use tokio_timer::clock::*;
 
     use toxcore::time::*;
     use toxcore::tcp::server::client::*;
+    use toxcore::tcp::server::ServerLimits;
 
     #[test]
     fn server_run_error_display() {
@@ -230,6 +515,13 @@ mod tests {
         format!("{}", ServerRunError::SendPingsError {
             error: IoError::new(IoErrorKind::Other, "io error"),
         });
+        format!("{}", ServerRunError::LocalAddrError {
+            error: IoError::new(IoErrorKind::Other, "io error"),
+        });
+        format!("{}", ServerRunError::DrainWakeupsError {
+            error: TimerError::shutdown(),
+        });
+        format!("{}", ServerRunError::NoListenersError);
     }
 
     #[test]
@@ -254,6 +546,24 @@ mod tests {
         format!("{}", ConnectionError::PacketHandlingError {
             error: IoError::new(IoErrorKind::Other, "io error"),
         });
+        assert_eq!(
+            format!("{}", ConnectionError::ClientRejectedError {
+                error: IoError::new(IoErrorKind::Other, "io error"),
+            }),
+            "Client is rejected: Custom { kind: Other, error: \"io error\" }"
+        );
+        assert_eq!(
+            format!("{}", ConnectionError::ServerHandshakeTimeoutError),
+            "Server handshake timed out"
+        );
+        assert_eq!(
+            format!("{}", ConnectionError::TooManyPendingConnectionsError),
+            "Too many pending connections"
+        );
+        assert_eq!(
+            format!("{}", ConnectionError::IdleTimeoutError),
+            "Client is idle for too long"
+        );
     }
 
     #[test]
@@ -310,6 +620,193 @@ mod tests {
         tokio::run(both);
     }
 
+    #[test]
+    fn run_connection_too_many_pending_connections() {
+        let (_server_pk, server_sk) = gen_keypair();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut server = Server::new();
+        server.set_limits(ServerLimits { max_pending_connections: Some(0), ..ServerLimits::default() });
+        let server_c = server.clone();
+
+        let server = listener.incoming()
+            .into_future() // take the first connection
+            .map_err(|(e, _other_incomings)| Error::from(e))
+            .map(|(connection, _other_incomings)| connection.unwrap())
+            .and_then(move |stream|
+                server.run_connection(stream, server_sk)
+                    .then(|r| {
+                        match r {
+                            Err(ConnectionError::TooManyPendingConnectionsError) => {},
+                            r => panic!("Unexpected result: {:?}", r),
+                        }
+                        Ok(())
+                    })
+            );
+
+        let client = TcpStream::connect(&addr)
+            .map_err(Error::from);
+
+        let both = server.join(client)
+            .then(|r| {
+                assert!(r.is_ok());
+                r
+            })
+            .map(|_| ()).map_err(|_| ());
+
+        tokio::run(both);
+
+        assert_eq!(server_c.limit_violations().pending_connections, 1);
+    }
+
+    #[test]
+    fn run_connection_handshake_failure() {
+        let (_server_pk, server_sk) = gen_keypair();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = Server::new();
+        let server_c = server.clone();
+
+        let server = listener.incoming()
+            .into_future() // take the first connection
+            .map_err(|(e, _other_incomings)| Error::from(e))
+            .map(|(connection, _other_incomings)| connection.unwrap())
+            .and_then(move |stream|
+                server.run_connection(stream, server_sk)
+                    .then(|r| {
+                        match r {
+                            Err(ConnectionError::ServerHandshakeError { .. }) => {},
+                            r => panic!("Unexpected result: {:?}", r),
+                        }
+                        Ok(())
+                    })
+            );
+
+        // the client closes the connection without sending handshake
+        let client = TcpStream::connect(&addr)
+            .map(drop)
+            .map_err(Error::from);
+
+        let both = server.join(client)
+            .then(|r| {
+                assert!(r.is_ok());
+                r
+            })
+            .map(|_| ()).map_err(|_| ());
+
+        tokio::run(both);
+
+        assert_eq!(server_c.stats().relay.handshake_failures, 1);
+    }
+
+    #[test]
+    fn run_connection_handshake_timeout() {
+        let (_server_pk, server_sk) = gen_keypair();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut server = Server::new();
+        server.set_handshake_timeout(Duration::from_millis(100));
+        let server_c = server.clone();
+
+        let server = listener.incoming()
+            .into_future() // take the first connection
+            .map_err(|(e, _other_incomings)| Error::from(e))
+            .map(|(connection, _other_incomings)| connection.unwrap())
+            .and_then(move |stream|
+                server.run_connection(stream, server_sk)
+                    .then(|r| {
+                        match r {
+                            Err(ConnectionError::ServerHandshakeTimeoutError) => {},
+                            r => panic!("Unexpected result: {:?}", r),
+                        }
+                        Ok(())
+                    })
+            );
+
+        // the client connects but doesn't send handshake so the server closes
+        // the connection after the timeout
+        let client = TcpStream::connect(&addr)
+            .and_then(|stream| tokio::io::read_to_end(stream, Vec::new()))
+            .map(|(_stream, data)| assert!(data.is_empty()))
+            .map_err(Error::from);
+
+        let both = server.join(client)
+            .then(|r| {
+                assert!(r.is_ok());
+                r
+            })
+            .map(|_| ()).map_err(|_| ());
+
+        tokio::run(both);
+
+        assert_eq!(server_c.stats().relay.handshake_failures, 1);
+    }
+
+    #[test]
+    fn run_connection_idle_timeout() {
+        let (client_pk, client_sk) = gen_keypair();
+        let (server_pk, server_sk) = gen_keypair();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut server = Server::new();
+        server.set_idle_timeout(Duration::from_millis(100));
+        let server_c = server.clone();
+
+        let server = listener.incoming()
+            .into_future() // take the first connection
+            .map_err(|(e, _other_incomings)| Error::from(e))
+            .map(|(connection, _other_incomings)| connection.unwrap())
+            .and_then(move |stream|
+                server.run_connection(stream, server_sk)
+                    .then(|r| {
+                        match r {
+                            Err(ConnectionError::IdleTimeoutError) => {},
+                            r => panic!("Unexpected result: {:?}", r),
+                        }
+                        Ok(())
+                    })
+            );
+
+        // the client completes the handshake but doesn't send anything so the
+        // server disconnects it after the timeout
+        let client = TcpStream::connect(&addr)
+            .map_err(Error::from)
+            .and_then(move |socket| {
+                make_client_handshake(socket, &client_pk, &client_sk, &server_pk)
+                    .map_err(Error::from)
+            })
+            .and_then(|(stream, channel)| {
+                let secure_socket = Framed::new(stream, Codec::new(channel));
+                let (to_server, from_server) = secure_socket.split();
+                from_server.collect()
+                    .map(move |packets| {
+                        assert!(packets.is_empty());
+                        drop(to_server);
+                    })
+                    .map_err(Error::from)
+            });
+
+        let both = server.join(client)
+            .then(|r| {
+                assert!(r.is_ok());
+                r
+            })
+            .map(|_| ()).map_err(|_| ());
+
+        tokio::run(both);
+
+        assert_eq!(server_c.active_connections(), 0);
+        assert!(server_c.stats().clients.is_empty());
+    }
+
     #[test]
     fn run() {
         let (client_pk, client_sk) = gen_keypair();
@@ -376,4 +873,161 @@ mod tests {
             enter.block_on(runtime.shutdown_on_idle()).unwrap();
         });
     }
+
+    #[test]
+    fn run_with_shutdown() {
+        let (client_pk, client_sk) = gen_keypair();
+        let (server_pk, server_sk) = gen_keypair();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let (shutdown_handle, shutdown_signal) = ShutdownHandle::new();
+
+        let server = Server::new();
+        let server_c = server.clone();
+
+        let server_future = server.run_with_shutdown(listener, server_sk, shutdown_signal, Duration::from_secs(TCP_DRAIN_TIMEOUT))
+            .map_err(Error::from);
+
+        let client = TcpStream::connect(&addr)
+            .map_err(Error::from)
+            .and_then(move |socket| {
+                make_client_handshake(socket, &client_pk, &client_sk, &server_pk)
+                    .map_err(Error::from)
+            })
+            .and_then(|(stream, channel)| {
+                let secure_socket = Framed::new(stream, Codec::new(channel));
+                let (to_server, from_server) = secure_socket.split();
+                let packet = Packet::PingRequest(PingRequest {
+                    ping_id: 42
+                });
+                to_server.send(packet)
+                    .map(|to_server| (to_server, from_server))
+                    .map_err(Error::from)
+            })
+            .and_then(|(to_server, from_server)| {
+                from_server.into_future()
+                    .map(|(packet, from_server_c)| {
+                        assert_eq!(packet.unwrap(), Packet::PongResponse(PongResponse {
+                            ping_id: 42
+                        }));
+                        (to_server, from_server_c)
+                    })
+                    .map_err(|(e, _)| Error::from(e))
+            })
+            .and_then(move |(to_server, from_server)| {
+                shutdown_handle.shutdown();
+                // the server closes the connection
+                from_server.into_future()
+                    .map(move |(packet, _)| {
+                        assert!(packet.is_none());
+                        drop(to_server);
+                    })
+                    .map_err(|(e, _)| Error::from(e))
+            });
+
+        let both = server_future.join(client)
+            .then(|r| {
+                assert!(r.is_ok());
+                r
+            })
+            .map(|_| ()).map_err(|_| ());
+
+        tokio::run(both);
+
+        assert!(server_c.is_shutting_down());
+        assert_eq!(server_c.active_connections(), 0);
+        assert!(server_c.stats().clients.is_empty());
+    }
+
+    /// Connect to the server, request a route to `other_pk` and wait until
+    /// the other client is connected
+    fn link_client(addr: SocketAddr, client_pk: PublicKey, client_sk: SecretKey, server_pk: PublicKey, other_pk: PublicKey)
+        -> Box<Future<Item = (), Error = Error> + Send> {
+        let future = TcpStream::connect(&addr)
+            .map_err(Error::from)
+            .and_then(move |socket| {
+                make_client_handshake(socket, &client_pk, &client_sk, &server_pk)
+                    .map_err(Error::from)
+            })
+            .and_then(move |(stream, channel)| {
+                let secure_socket = Framed::new(stream, Codec::new(channel));
+                let (to_server, from_server) = secure_socket.split();
+                let packet = Packet::RouteRequest(RouteRequest {
+                    pk: other_pk
+                });
+                to_server.send(packet)
+                    .map(|to_server| (to_server, from_server))
+                    .map_err(Error::from)
+            })
+            .and_then(move |(to_server, from_server)| {
+                from_server.take(2).collect()
+                    .map(move |packets| {
+                        assert_eq!(packets[0], Packet::RouteResponse(RouteResponse {
+                            pk: other_pk,
+                            connection_id: 16,
+                        }));
+                        assert_eq!(packets[1], Packet::ConnectNotification(ConnectNotification {
+                            connection_id: 16,
+                        }));
+                        drop(to_server);
+                    })
+                    .map_err(Error::from)
+            });
+        Box::new(future)
+    }
+
+    #[test]
+    fn run_listeners_empty() {
+        let (_server_pk, server_sk) = gen_keypair();
+
+        let result = Server::new()
+            .run_listeners(Vec::new(), server_sk, future::empty(), Duration::from_secs(TCP_DRAIN_TIMEOUT))
+            .wait();
+        match result {
+            Err(ServerRunError::NoListenersError) => {},
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn run_listeners() {
+        let (client_pk_1, client_sk_1) = gen_keypair();
+        let (client_pk_2, client_sk_2) = gen_keypair();
+        let (server_pk, server_sk) = gen_keypair();
+
+        let listener_1 = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let listener_2 = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr_1 = listener_1.local_addr().unwrap();
+        let addr_2 = listener_2.local_addr().unwrap();
+
+        let (shutdown_handle, shutdown_signal) = ShutdownHandle::new();
+
+        let server = Server::new();
+        let server_c = server.clone();
+
+        let listeners = vec![listener_1, listener_2];
+        let server_future = server.run_listeners(listeners, server_sk, shutdown_signal, Duration::from_secs(TCP_DRAIN_TIMEOUT))
+            .map_err(Error::from);
+
+        // clients connected to different listeners can link to each other
+        let client_1 = link_client(addr_1, client_pk_1, client_sk_1, server_pk, client_pk_2);
+        let client_2 = link_client(addr_2, client_pk_2, client_sk_2, server_pk, client_pk_1);
+        let clients = client_1.join(client_2)
+            .map(move |_| shutdown_handle.shutdown());
+
+        let both = server_future.join(clients)
+            .then(|r| {
+                assert!(r.is_ok());
+                r
+            })
+            .map(|_| ()).map_err(|_| ());
+
+        tokio::run(both);
+
+        let stats = server_c.stats();
+        assert_eq!(stats.listeners.len(), 2);
+        assert!(stats.listeners.iter().all(|listener| listener.accepted_connections == 1));
+    }
 }
@@ -0,0 +1,83 @@
+/*! Statistics of TCP relay server
+*/
+
+use std::net::{IpAddr, SocketAddr};
+use std::time::Instant;
+
+use toxcore::crypto_core::*;
+use toxcore::tcp::server::limits::LimitViolations;
+
+/// Counters of relayed packets and their bytes
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct TrafficStats {
+    /// Number of relayed packets
+    pub packets: u64,
+    /// Number of relayed bytes of packets payload
+    pub bytes: u64,
+}
+
+impl TrafficStats {
+    /// Count relayed packet with payload of the given size
+    pub fn add(&mut self, size: usize) {
+        self.packets += 1;
+        self.bytes += size as u64;
+    }
+}
+
+/// Counters of relayed traffic and failures
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct RelayStats {
+    /// `Data` packets relayed between linked clients
+    pub data: TrafficStats,
+    /// `OobSend` packets relayed as `OobReceive`
+    pub oob: TrafficStats,
+    /// `OnionRequest` packets sent to UDP socket
+    pub onion_requests: TrafficStats,
+    /// Onion responses sent back to clients as `OnionResponse`
+    pub onion_responses: TrafficStats,
+    /// Number of connections that failed to complete the handshake
+    pub handshake_failures: u64,
+    /// Number of clients removed because they didn't respond to pings
+    pub ping_timeouts: u64,
+}
+
+/// Information about connected client
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ClientInfo {
+    /// `PublicKey` of the client
+    pub pk: PublicKey,
+    /// IP address of the client
+    pub ip_addr: IpAddr,
+    /// Port of the client
+    pub port: u16,
+    /// Time when the client was connected
+    pub connected_time: Instant,
+    /// Number of links of the client
+    pub links_count: usize,
+}
+
+/// Information about a listener of the server
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ListenerInfo {
+    /// Local address of the listener
+    pub addr: SocketAddr,
+    /// Whether the listener accepts new connections
+    pub enabled: bool,
+    /// Number of accepted connections
+    pub accepted_connections: u64,
+    /// Number of connections dropped because the listener was disabled
+    pub dropped_connections: u64,
+}
+
+/// Snapshot of TCP relay server statistics
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ServerStats {
+    /// Information about all connected clients
+    pub clients: Vec<ClientInfo>,
+    /// Counters of relayed traffic and failures
+    pub relay: RelayStats,
+    /// Counters of limits violations
+    pub limit_violations: LimitViolations,
+    /// Information about all listeners of the server
+    pub listeners: Vec<ListenerInfo>,
+}
@@ -0,0 +1,688 @@
+/*! WebSocket transport for TCP relay protocol
+
+Clients that can't open raw TCP connections (e.g. browsers) can connect to
+the relay via WebSocket ([RFC 6455](https://tools.ietf.org/html/rfc6455)).
+After the HTTP upgrade both sides exchange binary messages that carry the same
+bytes as raw TCP connection: the handshake and encrypted packets. Message
+boundaries don't matter so `WebSocketStream` implements `AsyncRead` and
+`AsyncWrite` and can be used with `make_client_handshake`,
+`make_server_handshake` and `Codec` the same way as `TcpStream`.
+*/
+
+use std::cmp;
+use std::io::{Error, ErrorKind, Read, Write};
+
+use base64;
+use bytes::{BufMut, BytesMut};
+use futures::{future, Async, Future, Poll};
+use futures::future::Loop;
+use sha1::Sha1;
+use tokio::io::{read_exact, write_all, AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+
+use toxcore::crypto_core::*;
+use toxcore::io_tokio::IoFuture;
+
+/// GUID that is appended to `Sec-WebSocket-Key` to calculate
+/// `Sec-WebSocket-Accept`
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// The only supported version of WebSocket protocol
+const WEBSOCKET_VERSION: &str = "13";
+/// Maximum size of HTTP request or response headers during the upgrade
+const MAX_HEADERS_SIZE: usize = 4096;
+/// Maximum size of payload of received frames. TCP packets are much smaller
+/// so there is no need to accept bigger frames.
+pub const MAX_FRAME_PAYLOAD_SIZE: usize = 65_535;
+/// Size of buffer for reading data from the socket
+const READ_CHUNK_SIZE: usize = 4096;
+
+/// Request path that is used by default to connect to WebSocket relays
+pub const WEBSOCKET_DEFAULT_PATH: &str = "/";
+
+/// Opcode of continuation frame
+const OPCODE_CONTINUATION: u8 = 0x0;
+/// Opcode of text frame
+const OPCODE_TEXT: u8 = 0x1;
+/// Opcode of binary frame
+const OPCODE_BINARY: u8 = 0x2;
+/// Opcode of close frame
+const OPCODE_CLOSE: u8 = 0x8;
+/// Opcode of ping frame
+const OPCODE_PING: u8 = 0x9;
+/// Opcode of pong frame
+const OPCODE_PONG: u8 = 0xA;
+
+/// Side of WebSocket connection. Clients must mask frames they send and
+/// servers must not.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Role {
+    /// Side that initiated the connection
+    Client,
+    /// Side that accepted the connection
+    Server,
+}
+
+/// Received WebSocket frame
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Frame {
+    /// Whether this frame is the final fragment of a message
+    fin: bool,
+    /// Opcode of the frame
+    opcode: u8,
+    /// Unmasked payload of the frame
+    payload: Vec<u8>,
+}
+
+/// Encode a frame to the buffer masking it with `mask` if it's specified
+fn encode_frame(opcode: u8, payload: &[u8], mask: Option<[u8; 4]>, buf: &mut BytesMut) {
+    let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+    buf.reserve(2 + 8 + 4 + payload.len());
+    buf.put_u8(0x80 | opcode);
+    if payload.len() < 126 {
+        buf.put_u8(mask_bit | payload.len() as u8);
+    } else if payload.len() <= 0xffff {
+        buf.put_u8(mask_bit | 126);
+        buf.put_u16_be(payload.len() as u16);
+    } else {
+        buf.put_u8(mask_bit | 127);
+        buf.put_u64_be(payload.len() as u64);
+    }
+    if let Some(mask) = mask {
+        buf.put_slice(&mask);
+        buf.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    } else {
+        buf.put_slice(payload);
+    }
+}
+
+/// Decode a frame from the buffer. Returns `None` if the buffer doesn't
+/// contain the whole frame yet.
+fn decode_frame(role: Role, buf: &mut BytesMut) -> Result<Option<Frame>, Error> {
+    if buf.len() < 2 {
+        return Ok(None)
+    }
+    let fin = buf[0] & 0x80 != 0;
+    if buf[0] & 0x70 != 0 {
+        return Err(Error::new(ErrorKind::InvalidData, "WebSocket frame has reserved bits set"))
+    }
+    let opcode = buf[0] & 0x0f;
+    let masked = buf[1] & 0x80 != 0;
+    if masked != (role == Role::Server) {
+        return Err(Error::new(ErrorKind::InvalidData, "WebSocket frame has invalid mask bit"))
+    }
+
+    let (payload_len, mut offset) = match buf[1] & 0x7f {
+        126 => {
+            if buf.len() < 4 {
+                return Ok(None)
+            }
+            ((u64::from(buf[2]) << 8) | u64::from(buf[3]), 4)
+        },
+        127 => {
+            if buf.len() < 10 {
+                return Ok(None)
+            }
+            let len = buf[2..10].iter().fold(0u64, |len, &b| (len << 8) | u64::from(b));
+            (len, 10)
+        },
+        len => (u64::from(len), 2),
+    };
+    if payload_len > MAX_FRAME_PAYLOAD_SIZE as u64 {
+        return Err(Error::new(ErrorKind::InvalidData, "WebSocket frame is too big"))
+    }
+    let payload_len = payload_len as usize;
+    if opcode & 0x8 != 0 && (!fin || payload_len > 125) {
+        return Err(Error::new(ErrorKind::InvalidData, "WebSocket control frame is invalid"))
+    }
+
+    let mask = if masked {
+        if buf.len() < offset + 4 {
+            return Ok(None)
+        }
+        let mask = [buf[offset], buf[offset + 1], buf[offset + 2], buf[offset + 3]];
+        offset += 4;
+        Some(mask)
+    } else {
+        None
+    };
+
+    if buf.len() < offset + payload_len {
+        return Ok(None)
+    }
+    buf.advance(offset);
+    let mut payload = buf.split_to(payload_len).to_vec();
+    if let Some(mask) = mask {
+        for (i, b) in payload.iter_mut().enumerate() {
+            *b ^= mask[i % 4];
+        }
+    }
+
+    Ok(Some(Frame { fin, opcode, payload }))
+}
+
+/// Generate random mask for a frame sent by client
+fn gen_mask() -> [u8; 4] {
+    let mut mask = [0; 4];
+    randombytes_into(&mut mask);
+    mask
+}
+
+/** Stream of bytes carried in binary WebSocket messages over `TcpStream`.
+
+Each write is sent as a separate binary message. Received pings are answered
+automatically, close frame is treated as the end of the stream.
+*/
+pub struct WebSocketStream {
+    /// Underlying TCP stream
+    stream: TcpStream,
+    /// Side of the connection
+    role: Role,
+    /// Data received from the socket that is not decoded yet
+    read_buf: BytesMut,
+    /// Payload of received binary messages that is not read yet
+    payload: BytesMut,
+    /// Encoded frames that are not written to the socket yet
+    write_buf: BytesMut,
+    /// Whether close frame was received or the socket was closed
+    closed: bool,
+}
+
+impl WebSocketStream {
+    /// Create new `WebSocketStream` on the stream that completed the HTTP
+    /// upgrade
+    pub fn new(stream: TcpStream, role: Role) -> WebSocketStream {
+        WebSocketStream {
+            stream,
+            role,
+            read_buf: BytesMut::new(),
+            payload: BytesMut::new(),
+            write_buf: BytesMut::new(),
+            closed: false,
+        }
+    }
+
+    /// Underlying TCP stream
+    pub fn get_ref(&self) -> &TcpStream {
+        &self.stream
+    }
+
+    /// Encode a frame to the write buffer
+    fn buffer_frame(&mut self, opcode: u8, payload: &[u8]) {
+        let mask = if self.role == Role::Client { Some(gen_mask()) } else { None };
+        encode_frame(opcode, payload, mask, &mut self.write_buf);
+    }
+
+    /// Write buffered frames to the socket
+    fn flush_write_buf(&mut self) -> Result<(), Error> {
+        while !self.write_buf.is_empty() {
+            let n = self.stream.write(&self.write_buf)?;
+            if n == 0 {
+                return Err(Error::new(ErrorKind::WriteZero, "Failed to write WebSocket frame"))
+            }
+            self.write_buf.advance(n);
+        }
+        Ok(())
+    }
+
+    /// Write buffered frames to the socket ignoring `WouldBlock` errors since
+    /// the frames stay buffered
+    fn try_flush_write_buf(&mut self) -> Result<(), Error> {
+        match self.flush_write_buf() {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Handle decoded frame
+    fn handle_frame(&mut self, frame: Frame) -> Result<(), Error> {
+        match frame.opcode {
+            OPCODE_BINARY | OPCODE_CONTINUATION => {
+                self.payload.extend_from_slice(&frame.payload);
+                Ok(())
+            },
+            OPCODE_PING => {
+                self.buffer_frame(OPCODE_PONG, &frame.payload);
+                self.try_flush_write_buf()
+            },
+            OPCODE_PONG => Ok(()),
+            OPCODE_CLOSE => {
+                debug!("WebSocket close frame is received");
+                self.closed = true;
+                self.buffer_frame(OPCODE_CLOSE, &frame.payload);
+                self.try_flush_write_buf()
+            },
+            OPCODE_TEXT =>
+                Err(Error::new(ErrorKind::InvalidData, "WebSocket text messages are not supported")),
+            opcode =>
+                Err(Error::new(ErrorKind::InvalidData, format!("Unknown WebSocket opcode {}", opcode))),
+        }
+    }
+}
+
+impl Read for WebSocketStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        loop {
+            if !self.payload.is_empty() {
+                let n = cmp::min(buf.len(), self.payload.len());
+                buf[..n].copy_from_slice(&self.payload.split_to(n));
+                return Ok(n)
+            }
+            if self.closed {
+                return Ok(0)
+            }
+            if let Some(frame) = decode_frame(self.role, &mut self.read_buf)? {
+                self.handle_frame(frame)?;
+                continue
+            }
+
+            let mut chunk = [0; READ_CHUNK_SIZE];
+            let n = self.stream.read(&mut chunk)?;
+            if n == 0 {
+                self.closed = true;
+                if !self.read_buf.is_empty() {
+                    return Err(Error::new(ErrorKind::UnexpectedEof, "WebSocket frame is truncated"))
+                }
+            }
+            self.read_buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
+
+impl Write for WebSocketStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        // don't buffer new frames until previous ones are written
+        self.flush_write_buf()?;
+        let n = cmp::min(buf.len(), MAX_FRAME_PAYLOAD_SIZE);
+        self.buffer_frame(OPCODE_BINARY, &buf[..n]);
+        self.try_flush_write_buf()?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.flush_write_buf()?;
+        self.stream.flush()
+    }
+}
+
+impl AsyncRead for WebSocketStream {}
+
+impl AsyncWrite for WebSocketStream {
+    fn shutdown(&mut self) -> Poll<(), Error> {
+        if let Async::NotReady = self.poll_flush()? {
+            return Ok(Async::NotReady)
+        }
+        AsyncWrite::shutdown(&mut self.stream)
+    }
+}
+
+/// Calculate `Sec-WebSocket-Accept` for the `Sec-WebSocket-Key`
+fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(WEBSOCKET_GUID.as_bytes());
+    base64::encode(&sha1.digest().bytes())
+}
+
+/// Read HTTP headers from the stream up to the empty line. Data is read byte
+/// by byte to not read WebSocket frames that can follow headers.
+fn read_headers(stream: TcpStream) -> IoFuture<(TcpStream, String)> {
+    let future = future::loop_fn((stream, Vec::new()), |(stream, mut headers)|
+        read_exact(stream, [0; 1]).and_then(move |(stream, byte)| {
+            headers.push(byte[0]);
+            if headers.ends_with(b"\r\n\r\n") {
+                String::from_utf8(headers)
+                    .map(|headers| Loop::Break((stream, headers)))
+                    .map_err(|_| Error::new(ErrorKind::InvalidData, "HTTP headers are not valid UTF-8"))
+            } else if headers.len() >= MAX_HEADERS_SIZE {
+                Err(Error::new(ErrorKind::InvalidData, "HTTP headers are too long"))
+            } else {
+                Ok(Loop::Continue((stream, headers)))
+            }
+        })
+    );
+    Box::new(future)
+}
+
+/// Parsed HTTP request or response head
+struct HttpHead<'a> {
+    /// Request or status line
+    start_line: &'a str,
+    /// Headers names and values
+    headers: Vec<(&'a str, &'a str)>,
+}
+
+impl<'a> HttpHead<'a> {
+    /// Parse HTTP head
+    fn parse(head: &'a str) -> Result<HttpHead<'a>, Error> {
+        let mut lines = head.split("\r\n").filter(|line| !line.is_empty());
+        let start_line = lines.next()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "HTTP start line is missing"))?;
+        let headers = lines
+            .map(|line| {
+                let mut parts = line.splitn(2, ':');
+                match (parts.next(), parts.next()) {
+                    (Some(name), Some(value)) => Ok((name.trim(), value.trim())),
+                    _ => Err(Error::new(ErrorKind::InvalidData, format!("Invalid HTTP header: {:?}", line))),
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(HttpHead { start_line, headers })
+    }
+
+    /// Get header value by case-insensitive name
+    fn header(&self, name: &str) -> Option<&'a str> {
+        self.headers.iter()
+            .find(|&&(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|&(_, value)| value)
+    }
+
+    /// Check if comma-separated header value contains the token
+    fn header_contains(&self, name: &str, token: &str) -> bool {
+        self.header(name).map_or(false, |value|
+            value.split(',').any(|t| t.trim().eq_ignore_ascii_case(token))
+        )
+    }
+}
+
+/// Validate WebSocket upgrade request and get `Sec-WebSocket-Key` from it
+fn parse_upgrade_request(request: &str) -> Result<String, Error> {
+    let head = HttpHead::parse(request)?;
+    let mut parts = head.start_line.split_whitespace();
+    if parts.next() != Some("GET") || parts.next().is_none() || parts.next().map_or(true, |v| !v.starts_with("HTTP/1.1")) {
+        return Err(Error::new(ErrorKind::InvalidData, format!("Invalid WebSocket request line: {:?}", head.start_line)))
+    }
+    if !head.header_contains("Upgrade", "websocket") || !head.header_contains("Connection", "upgrade") {
+        return Err(Error::new(ErrorKind::InvalidData, "HTTP request is not a WebSocket upgrade"))
+    }
+    if head.header("Sec-WebSocket-Version") != Some(WEBSOCKET_VERSION) {
+        return Err(Error::new(ErrorKind::InvalidData, "Unsupported WebSocket version"))
+    }
+    head.header("Sec-WebSocket-Key")
+        .map(|key| key.to_owned())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Sec-WebSocket-Key is missing"))
+}
+
+/// Validate WebSocket upgrade response
+fn check_upgrade_response(response: &str, key: &str) -> Result<(), Error> {
+    let head = HttpHead::parse(response)?;
+    let mut parts = head.start_line.split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some(version), Some("101")) if version.starts_with("HTTP/") => {},
+        _ => return Err(Error::new(ErrorKind::Other, format!("WebSocket upgrade is rejected: {:?}", head.start_line))),
+    }
+    if !head.header_contains("Upgrade", "websocket") || !head.header_contains("Connection", "upgrade") {
+        return Err(Error::new(ErrorKind::InvalidData, "HTTP response is not a WebSocket upgrade"))
+    }
+    if head.header("Sec-WebSocket-Accept") != Some(accept_key(key).as_str()) {
+        return Err(Error::new(ErrorKind::InvalidData, "Invalid Sec-WebSocket-Accept"))
+    }
+    Ok(())
+}
+
+/// Accept WebSocket upgrade request on the server side
+pub fn accept(stream: TcpStream) -> IoFuture<WebSocketStream> {
+    let future = read_headers(stream)
+        .and_then(|(stream, request)| -> IoFuture<WebSocketStream> {
+            match parse_upgrade_request(&request) {
+                Ok(key) => {
+                    let response = format!(
+                        "HTTP/1.1 101 Switching Protocols\r\n\
+                         Upgrade: websocket\r\n\
+                         Connection: Upgrade\r\n\
+                         Sec-WebSocket-Accept: {}\r\n\r\n",
+                        accept_key(&key)
+                    );
+                    Box::new(write_all(stream, response.into_bytes())
+                        .map(|(stream, _)| WebSocketStream::new(stream, Role::Server)))
+                },
+                Err(error) => {
+                    let response = b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n".to_vec();
+                    // ignore write error since the request is invalid anyway
+                    Box::new(write_all(stream, response).then(|_| Err(error)))
+                },
+            }
+        });
+    Box::new(future)
+}
+
+/// Make WebSocket upgrade request on the client side. `host` is sent in the
+/// `Host` header and `path` is the requested resource.
+pub fn connect(stream: TcpStream, host: &str, path: &str) -> IoFuture<WebSocketStream> {
+    let mut key_bytes = [0; 16];
+    randombytes_into(&mut key_bytes);
+    let key = base64::encode(&key_bytes);
+    let request = format!(
+        "GET {} HTTP/1.1\r\n\
+         Host: {}\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Key: {}\r\n\
+         Sec-WebSocket-Version: {}\r\n\r\n",
+        path, host, key, WEBSOCKET_VERSION
+    );
+
+    let future = write_all(stream, request.into_bytes())
+        .and_then(|(stream, _)| read_headers(stream))
+        .and_then(move |(stream, response)| {
+            check_upgrade_response(&response, &key)?;
+            Ok(WebSocketStream::new(stream, Role::Client))
+        });
+    Box::new(future)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::{Sink, Stream};
+    use tokio;
+    use tokio::net::TcpListener;
+    use tokio_codec::Framed;
+
+    use toxcore::tcp::codec::Codec;
+    use toxcore::tcp::handshake::{make_client_handshake, make_server_handshake};
+    use toxcore::tcp::packet::*;
+
+    #[test]
+    fn accept_key_rfc_example() {
+        // example from RFC 6455
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn encode_decode_frame_masked() {
+        let mut buf = BytesMut::new();
+        encode_frame(OPCODE_BINARY, &[1, 2, 3], Some([4, 5, 6, 7]), &mut buf);
+        assert_eq!(&buf[..], &[0x82, 0x83, 4, 5, 6, 7, 1 ^ 4, 2 ^ 5, 3 ^ 6][..]);
+
+        let frame = decode_frame(Role::Server, &mut buf).unwrap().unwrap();
+        assert_eq!(frame, Frame { fin: true, opcode: OPCODE_BINARY, payload: vec![1, 2, 3] });
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn encode_decode_frame_extended_len() {
+        let payload = vec![42; 300];
+        let mut buf = BytesMut::new();
+        encode_frame(OPCODE_BINARY, &payload, None, &mut buf);
+        assert_eq!(&buf[..4], &[0x82, 126, 1, 44][..]);
+
+        let frame = decode_frame(Role::Client, &mut buf).unwrap().unwrap();
+        assert_eq!(frame.payload, payload);
+    }
+
+    #[test]
+    fn decode_frame_incomplete() {
+        let mut buf = BytesMut::new();
+        encode_frame(OPCODE_BINARY, &[1, 2, 3], Some(gen_mask()), &mut buf);
+        let mut partial = BytesMut::from(&buf[..buf.len() - 1]);
+        assert!(decode_frame(Role::Server, &mut partial).unwrap().is_none());
+        assert_eq!(partial.len(), buf.len() - 1);
+    }
+
+    #[test]
+    fn decode_frame_unmasked_from_client() {
+        let mut buf = BytesMut::new();
+        encode_frame(OPCODE_BINARY, &[1, 2, 3], None, &mut buf);
+        assert!(decode_frame(Role::Server, &mut buf).is_err());
+    }
+
+    #[test]
+    fn decode_frame_too_big() {
+        let mut buf = BytesMut::from(&[0x82, 127, 0, 0, 0, 0, 0, 1, 0, 0][..]);
+        assert!(decode_frame(Role::Client, &mut buf).is_err());
+    }
+
+    #[test]
+    fn parse_upgrade_request_valid() {
+        let request = "GET / HTTP/1.1\r\nHost: example.com\r\nupgrade: WebSocket\r\n\
+                       Connection: keep-alive, Upgrade\r\nSec-WebSocket-Key: key\r\n\
+                       Sec-WebSocket-Version: 13\r\n\r\n";
+        assert_eq!(parse_upgrade_request(request).unwrap(), "key");
+    }
+
+    #[test]
+    fn parse_upgrade_request_invalid() {
+        let request = "GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        assert!(parse_upgrade_request(request).is_err());
+        let request = "POST / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                       Sec-WebSocket-Key: key\r\nSec-WebSocket-Version: 13\r\n\r\n";
+        assert!(parse_upgrade_request(request).is_err());
+        let request = "GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                       Sec-WebSocket-Version: 13\r\n\r\n";
+        assert!(parse_upgrade_request(request).is_err());
+    }
+
+    #[test]
+    fn check_upgrade_response_invalid_accept() {
+        let response = "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+                        Connection: Upgrade\r\nSec-WebSocket-Accept: invalid\r\n\r\n";
+        assert!(check_upgrade_response(response, "key").is_err());
+    }
+
+    #[test]
+    fn check_upgrade_response_rejected() {
+        let response = "HTTP/1.1 403 Forbidden\r\n\r\n";
+        assert!(check_upgrade_response(response, "key").is_err());
+    }
+
+    #[test]
+    fn handshake_and_packets() {
+        crypto_init();
+        let (client_pk, client_sk) = gen_keypair();
+        let (server_pk, server_sk) = gen_keypair();
+
+        let addr = "127.0.0.1:12362".parse().unwrap();
+        let listener = TcpListener::bind(&addr).unwrap();
+
+        let server = listener.incoming()
+            .into_future()
+            .map_err(|(e, _)| e)
+            .and_then(|(stream, _)| accept(stream.unwrap()))
+            .and_then(move |stream| make_server_handshake(stream, server_sk))
+            .and_then(|(stream, channel, _client_pk)| {
+                Framed::new(stream, Codec::new(channel))
+                    .into_future()
+                    .map_err(|(e, _)| Error::new(ErrorKind::Other, format!("{:?}", e)))
+            })
+            .and_then(|(packet, stream)| {
+                assert_eq!(packet, Some(Packet::PingRequest(PingRequest { ping_id: 42 })));
+                stream.send(Packet::PongResponse(PongResponse { ping_id: 42 }))
+                    .map_err(|e| Error::new(ErrorKind::Other, format!("{:?}", e)))
+            })
+            .map(|_| ())
+            .map_err(|e| panic!("Server error: {:?}", e));
+
+        let client = TcpStream::connect(&addr)
+            .and_then(move |stream| connect(stream, &addr.to_string(), WEBSOCKET_DEFAULT_PATH))
+            .and_then(move |stream| make_client_handshake(stream, &client_pk, &client_sk, &server_pk))
+            .and_then(|(stream, channel)| {
+                Framed::new(stream, Codec::new(channel))
+                    .send(Packet::PingRequest(PingRequest { ping_id: 42 }))
+                    .map_err(|e| Error::new(ErrorKind::Other, format!("{:?}", e)))
+            })
+            .and_then(|stream| stream.into_future().map_err(|(e, _)| Error::new(ErrorKind::Other, format!("{:?}", e))))
+            .map(|(packet, _stream)| {
+                assert_eq!(packet, Some(Packet::PongResponse(PongResponse { ping_id: 42 })));
+            })
+            .map_err(|e| panic!("Client error: {:?}", e));
+
+        tokio::run(server.join(client).map(|_| ()));
+    }
+
+    #[test]
+    fn ping_and_close() {
+        let addr = "127.0.0.1:12363".parse().unwrap();
+        let listener = TcpListener::bind(&addr).unwrap();
+
+        // raw server that sends ping, data and close frames
+        let server = listener.incoming()
+            .into_future()
+            .map_err(|(e, _)| e)
+            .and_then(|(stream, _)| read_headers(stream.unwrap()))
+            .and_then(|(stream, request)| {
+                let key = parse_upgrade_request(&request).unwrap();
+                let mut response = format!(
+                    "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+                     Connection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                    accept_key(&key)
+                ).into_bytes();
+                let mut frames = BytesMut::new();
+                encode_frame(OPCODE_PING, &[7], None, &mut frames);
+                encode_frame(OPCODE_BINARY, &[1, 2], None, &mut frames);
+                encode_frame(OPCODE_CLOSE, &[], None, &mut frames);
+                response.extend_from_slice(&frames);
+                write_all(stream, response)
+            })
+            .and_then(|(stream, _)| read_exact(stream, [0; 7]))
+            .map(|(stream, pong)| {
+                let mut pong = BytesMut::from(&pong[..]);
+                let frame = decode_frame(Role::Server, &mut pong).unwrap().unwrap();
+                assert_eq!(frame, Frame { fin: true, opcode: OPCODE_PONG, payload: vec![7] });
+                stream
+            })
+            .and_then(|stream| read_exact(stream, [0; 6]))
+            .map(|(_stream, close)| {
+                let mut close = BytesMut::from(&close[..]);
+                let frame = decode_frame(Role::Server, &mut close).unwrap().unwrap();
+                assert_eq!(frame.opcode, OPCODE_CLOSE);
+            })
+            .map_err(|e| panic!("Server error: {:?}", e));
+
+        let client = TcpStream::connect(&addr)
+            .and_then(move |stream| connect(stream, &addr.to_string(), WEBSOCKET_DEFAULT_PATH))
+            .and_then(|stream| tokio::io::read_to_end(stream, Vec::new()))
+            .map(|(_stream, data)| {
+                // close frame ends the stream
+                assert_eq!(data, vec![1, 2]);
+            })
+            .map_err(|e| panic!("Client error: {:?}", e));
+
+        tokio::run(server.join(client).map(|_| ()));
+    }
+
+    #[test]
+    fn accept_invalid_request() {
+        let addr = "127.0.0.1:12364".parse().unwrap();
+        let listener = TcpListener::bind(&addr).unwrap();
+
+        let server = listener.incoming()
+            .into_future()
+            .map_err(|(e, _)| e)
+            .and_then(|(stream, _)| accept(stream.unwrap()))
+            .then(|result| {
+                assert!(result.is_err());
+                Ok(())
+            });
+
+        let client = TcpStream::connect(&addr)
+            .and_then(|stream| write_all(stream, b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n".to_vec()))
+            .and_then(|(stream, _)| tokio::io::read_to_end(stream, Vec::new()))
+            .map(|(_stream, response)| {
+                assert!(response.starts_with(b"HTTP/1.1 400"));
+            })
+            .map_err(|e| panic!("Client error: {:?}", e));
+
+        tokio::run(server.join(client).map(|_| ()));
+    }
+}
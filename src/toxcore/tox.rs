@@ -0,0 +1,878 @@
+/*! High level Tox API
+
+`Tox` owns UDP socket, DHT server, TCP relays, `net_crypto`, messenger and
+conferences state and wires them together with channels. Applications create
+it with `ToxBuilder`, run the future returned by `Tox::run` and receive
+everything that happens with friends and conferences from a single stream of
+messenger events.
+
+Friends are found by their long term keys from `ToxId` via onion. Onion
+client announces our long term `PublicKey` to DHT nodes close to it and
+searches the nodes our friends are announced at. Friend requests are sent
+through these nodes, and so are our DHT `PublicKey` and DHT nodes close to us.
+When a friend's DHT `PublicKey` is learned DHT searches for the friend and
+`net_crypto` connects to the address it finds. The DHT `PublicKey` of a friend
+can also be provided by the application via `Tox::set_friend_dht_pk` if it's
+already known.
+
+E.g.
+
+```no_run
+extern crate futures;
+extern crate tokio;
+extern crate tox;
+
+use futures::{Future, Stream};
+
+use tox::toxcore::tox::ToxBuilder;
+
+# fn main() {
+let (tox, events) = ToxBuilder::new()
+    .udp_addr("0.0.0.0:33445".parse().unwrap())
+    .build()
+    .unwrap();
+
+println!("My Tox ID: {}", tox.tox_id());
+
+let events_future = events
+    .for_each(|event| {
+        println!("Event: {:?}", event);
+        Ok(())
+    })
+    .map_err(|()| ());
+let future = tox.run()
+    .map_err(|e| println!("Tox error: {}", e))
+    .select(events_future)
+    .map(|_| ())
+    .map_err(|_| ());
+
+tokio::run(future);
+# }
+```
+
+*/
+
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::{future, Future, Sink, Stream};
+use futures::sync::mpsc;
+use parking_lot::Mutex;
+use tokio;
+use tokio::net::{UdpFramed, UdpSocket};
+use tokio::timer::Interval;
+
+use toxcore::conference::Conferences;
+use toxcore::crypto_core::*;
+use toxcore::dht::codec::*;
+use toxcore::dht::lan_discovery::LanDiscoverySender;
+use toxcore::dht::packed_node::PackedNode;
+use toxcore::dht::packet::Packet as DhtPacket;
+use toxcore::dht::server::Server;
+use toxcore::friend_connection::FriendConnections;
+use toxcore::friend_connection::packet::FriendRequests as FriendRequestsPacket;
+use toxcore::friend_requests::{FriendRequests, SendFriendRequestError};
+use toxcore::io_tokio::*;
+use toxcore::messenger::{Event, Messenger, MessageKind, OutboxSnapshot, SendMessageError};
+use toxcore::messenger::file_transfer::FileTransfers;
+use toxcore::messenger::msi::Msi;
+use toxcore::messenger::packet::PeerStatus;
+use toxcore::net_crypto::{NetCrypto, NetCryptoNewArgs};
+use toxcore::onion::client::OnionClient;
+use toxcore::onion::packet::{OnionAnnounceResponse, OnionDataResponse};
+use toxcore::tcp::connections::{Connections, IncomingPacket, OutgoingPacket};
+use toxcore::toxid::{NoSpam, ToxId};
+
+/// Default address of the UDP socket.
+pub const DEFAULT_UDP_ADDR: &str = "0.0.0.0:33445";
+
+/// Interval in milliseconds for running the main loop of `net_crypto`. It
+/// should be run at least 20 times per second.
+const NET_CRYPTO_MAIN_LOOP_INTERVAL: u64 = 50;
+
+/// Lossless packets with lower ids belong to the friend connection module,
+/// other packets are handled by messenger.
+const MESSENGER_PACKET_ID_START: u8 = 0x18;
+
+/// Lossless packets with ids in this range are handled by conferences.
+const CONFERENCE_PACKET_ID_START: u8 = 0x60;
+
+/// Lossless packets with ids in this range are handled by conferences.
+const CONFERENCE_PACKET_ID_END: u8 = 0x63;
+
+/// Id of lossy conference packets.
+const LOSSY_CONFERENCE_PACKET_ID: u8 = 0xc7;
+
+/// Builder for `Tox` object.
+#[derive(Clone, Debug)]
+pub struct ToxBuilder {
+    /// Address to bind the UDP socket to.
+    udp_addr: SocketAddr,
+    /// Our long term keys. Random keys are generated if they are not set.
+    keys: Option<(PublicKey, SecretKey)>,
+    /// Our `NoSpam`. Random `NoSpam` is generated if it's not set.
+    nospam: Option<NoSpam>,
+    /// Nodes the DHT server bootstraps from.
+    bootstrap_nodes: Vec<PackedNode>,
+    /// TCP relays we connect to.
+    tcp_relays: Vec<(SocketAddr, PublicKey)>,
+    /// Whether LAN discovery is enabled.
+    lan_discovery: bool,
+}
+
+impl Default for ToxBuilder {
+    fn default() -> Self {
+        ToxBuilder::new()
+    }
+}
+
+impl ToxBuilder {
+    /// Create new `ToxBuilder` with default options.
+    pub fn new() -> ToxBuilder {
+        ToxBuilder {
+            udp_addr: DEFAULT_UDP_ADDR.parse().unwrap(),
+            keys: None,
+            nospam: None,
+            bootstrap_nodes: Vec::new(),
+            tcp_relays: Vec::new(),
+            lan_discovery: true,
+        }
+    }
+
+    /// Set address to bind the UDP socket to. IPv6 mode is enabled when the
+    /// address is IPv6. Default is `DEFAULT_UDP_ADDR`.
+    pub fn udp_addr(mut self, udp_addr: SocketAddr) -> ToxBuilder {
+        self.udp_addr = udp_addr;
+        self
+    }
+
+    /// Set our long term keys. They should be stored by the application to
+    /// keep the same `ToxId` between restarts.
+    pub fn keys(mut self, pk: PublicKey, sk: SecretKey) -> ToxBuilder {
+        self.keys = Some((pk, sk));
+        self
+    }
+
+    /// Set our `NoSpam`.
+    pub fn nospam(mut self, nospam: NoSpam) -> ToxBuilder {
+        self.nospam = Some(nospam);
+        self
+    }
+
+    /// Add a node the DHT server will bootstrap from.
+    pub fn bootstrap_node(mut self, node: PackedNode) -> ToxBuilder {
+        self.bootstrap_nodes.push(node);
+        self
+    }
+
+    /// Add a TCP relay to connect to. Friends can be reached via relays
+    /// when direct UDP connection is not possible.
+    pub fn tcp_relay(mut self, addr: SocketAddr, pk: PublicKey) -> ToxBuilder {
+        self.tcp_relays.push((addr, pk));
+        self
+    }
+
+    /// Enable or disable LAN discovery. It's enabled by default.
+    pub fn lan_discovery(mut self, enable: bool) -> ToxBuilder {
+        self.lan_discovery = enable;
+        self
+    }
+
+    /// Bind the UDP socket and create `Tox` object together with the stream
+    /// of messenger events.
+    pub fn build(self) -> Result<(Tox, mpsc::UnboundedReceiver<Event>), Error> {
+        let socket = UdpSocket::bind(&self.udp_addr)?;
+        socket.set_broadcast(true)?;
+        let is_ipv6 = self.udp_addr.is_ipv6();
+        if is_ipv6 {
+            socket.set_multicast_loop_v6(true)?;
+        }
+        let local_addr = socket.local_addr()?;
+
+        let (real_pk, real_sk) = self.keys.unwrap_or_else(gen_keypair);
+        let (dht_pk, dht_sk) = gen_keypair();
+
+        let (udp_tx, udp_rx) = mpsc::unbounded();
+        let (dht_pk_tx, dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, lossy_rx) = mpsc::unbounded();
+        let (net_crypto_status_tx, net_crypto_status_rx) = mpsc::unbounded();
+        let (tcp_tx, tcp_rx) = mpsc::unbounded();
+        let (tcp_incoming_tx, tcp_incoming_rx) = mpsc::unbounded();
+        let (connection_status_tx, connection_status_rx) = mpsc::unbounded();
+        let (friend_request_packet_tx, friend_request_packet_rx) = mpsc::unbounded();
+        let (friend_request_tx, friend_request_rx) = mpsc::unbounded();
+        let (onion_announce_response_tx, onion_announce_response_rx) = mpsc::unbounded();
+        let (onion_data_response_tx, onion_data_response_rx) = mpsc::unbounded();
+        let (event_tx, event_rx) = mpsc::unbounded();
+
+        let mut dht = Server::new(udp_tx.clone(), dht_pk, dht_sk.clone());
+        dht.enable_lan_discovery(self.lan_discovery);
+        dht.enable_ipv6_mode(is_ipv6);
+        for node in self.bootstrap_nodes {
+            dht.add_initial_bootstrap(node);
+        }
+        dht.set_onion_announce_response_sink(onion_announce_response_tx);
+        dht.set_onion_data_response_sink(onion_data_response_tx);
+
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx: udp_tx.clone(),
+            dht_pk_tx: dht_pk_tx.clone(),
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            precomputed_keys: dht.get_precomputed_keys(),
+        });
+        net_crypto.set_connection_status_sink(net_crypto_status_tx);
+        net_crypto.set_tcp_sink(tcp_tx);
+        dht.set_net_crypto(net_crypto.clone());
+
+        let tcp_connections = Connections::new(dht_pk, dht_sk, tcp_incoming_tx);
+
+        let mut friend_connections = FriendConnections::new(net_crypto.clone(), tcp_connections.clone());
+        friend_connections.set_connection_status_sink(connection_status_tx);
+        friend_connections.set_friend_request_sink(friend_request_packet_tx.clone());
+
+        let mut onion_client = OnionClient::new(dht.clone(), real_pk, real_sk.clone());
+        onion_client.set_dht_pk_sink(dht_pk_tx.clone());
+        onion_client.set_friend_request_sink(friend_request_packet_tx);
+
+        let nospam = self.nospam.unwrap_or_else(NoSpam::new);
+        let mut friend_requests = FriendRequests::new(real_pk, nospam, friend_connections.clone(), onion_client.clone());
+        friend_requests.set_friend_request_sink(friend_request_tx);
+
+        let mut messenger = Messenger::new(net_crypto.clone(), friend_connections.clone(), friend_requests.clone());
+        messenger.set_event_sink(event_tx.clone());
+
+        let mut conferences = Conferences::new(
+            real_pk,
+            dht_pk,
+            net_crypto.clone(),
+            friend_connections.clone(),
+            messenger.clone()
+        );
+        conferences.set_event_sink(event_tx);
+        conferences.set_dht_pk_sink(dht_pk_tx);
+
+        let lan_discovery = if self.lan_discovery {
+            Some(LanDiscoverySender::new(udp_tx, dht_pk, is_ipv6))
+        } else {
+            None
+        };
+
+        let channels = Channels {
+            socket,
+            udp_rx,
+            dht_pk_rx,
+            lossless_rx,
+            lossy_rx,
+            net_crypto_status_rx,
+            tcp_rx,
+            tcp_incoming_rx,
+            connection_status_rx,
+            friend_request_packet_rx,
+            friend_request_rx,
+            onion_announce_response_rx,
+            onion_data_response_rx,
+            lan_discovery,
+        };
+
+        let tox = Tox {
+            real_pk,
+            real_sk,
+            dht_pk,
+            local_addr,
+            dht,
+            net_crypto,
+            tcp_connections,
+            friend_connections,
+            onion_client,
+            friend_requests,
+            messenger,
+            conferences,
+            tcp_relays: self.tcp_relays,
+            channels: Arc::new(Mutex::new(Some(channels))),
+        };
+
+        Ok((tox, event_rx))
+    }
+}
+
+/// The socket and receiving halves of channels that connect modules of
+/// `Tox`. They are consumed when `Tox` starts running.
+struct Channels {
+    /// UDP socket for DHT and `net_crypto` packets.
+    socket: UdpSocket,
+    /// Packets that should be sent to the UDP socket.
+    udp_rx: mpsc::UnboundedReceiver<(DhtPacket, SocketAddr)>,
+    /// DHT `PublicKey`s of friends learned by `net_crypto` or onion.
+    dht_pk_rx: mpsc::UnboundedReceiver<(PublicKey, PublicKey)>,
+    /// Lossless packets received by `net_crypto`.
+    lossless_rx: mpsc::UnboundedReceiver<(PublicKey, Vec<u8>)>,
+    /// Lossy packets received by `net_crypto`.
+    lossy_rx: mpsc::UnboundedReceiver<(PublicKey, Vec<u8>)>,
+    /// Status changes of crypto connections.
+    net_crypto_status_rx: mpsc::UnboundedReceiver<(PublicKey, bool)>,
+    /// Packets that `net_crypto` sends to TCP relays.
+    tcp_rx: mpsc::UnboundedReceiver<(OutgoingPacket, PublicKey)>,
+    /// Packets received from TCP relays.
+    tcp_incoming_rx: mpsc::UnboundedReceiver<(IncomingPacket, PublicKey)>,
+    /// Status changes of friend connections.
+    connection_status_rx: mpsc::UnboundedReceiver<(PublicKey, bool)>,
+    /// `FriendRequests` packets received via `net_crypto` or onion.
+    friend_request_packet_rx: mpsc::UnboundedReceiver<(PublicKey, FriendRequestsPacket)>,
+    /// Friend requests that passed all checks.
+    friend_request_rx: mpsc::UnboundedReceiver<(PublicKey, Vec<u8>)>,
+    /// `OnionAnnounceResponse` packets received by the DHT server.
+    onion_announce_response_rx: mpsc::UnboundedReceiver<(OnionAnnounceResponse, SocketAddr)>,
+    /// `OnionDataResponse` packets received by the DHT server.
+    onion_data_response_rx: mpsc::UnboundedReceiver<(OnionDataResponse, SocketAddr)>,
+    /// LAN discovery sender if it's enabled.
+    lan_discovery: Option<LanDiscoverySender>,
+}
+
+/// Handle every item received from the channel. Handling errors are logged
+/// and ignored.
+fn handle_channel<T, F>(rx: mpsc::UnboundedReceiver<T>, handler: F) -> IoFuture<()>
+    where T: Send + 'static,
+          F: Fn(T) -> IoFuture<()> + Send + 'static
+{
+    let future = rx
+        .map_err(|()| Error::new(ErrorKind::Other, "rx error"))
+        .for_each(move |item| handler(item).or_else(|e| {
+            debug!("Failed to handle received item: {}", e);
+            Ok(())
+        }));
+    Box::new(future)
+}
+
+/// Tox instance that owns all modules needed to communicate with friends.
+#[derive(Clone)]
+pub struct Tox {
+    /// Our long term `PublicKey`.
+    real_pk: PublicKey,
+    /// Our long term `SecretKey`.
+    real_sk: SecretKey,
+    /// Our DHT `PublicKey`.
+    dht_pk: PublicKey,
+    /// Address the UDP socket is bound to.
+    local_addr: SocketAddr,
+    /// DHT server.
+    dht: Server,
+    /// `NetCrypto` module.
+    net_crypto: NetCrypto,
+    /// TCP connections to relays.
+    tcp_connections: Connections,
+    /// Friend connections module.
+    friend_connections: FriendConnections,
+    /// Onion client that finds friends by their long term keys.
+    onion_client: OnionClient,
+    /// Friend requests module.
+    friend_requests: FriendRequests,
+    /// Messenger module.
+    messenger: Messenger,
+    /// Conferences module.
+    conferences: Conferences,
+    /// TCP relays we connect to when `Tox` starts running.
+    tcp_relays: Vec<(SocketAddr, PublicKey)>,
+    /// Socket and channels that are consumed by `run`.
+    channels: Arc<Mutex<Option<Channels>>>,
+}
+
+impl Tox {
+    /// Get our long term `PublicKey`.
+    pub fn pk(&self) -> PublicKey {
+        self.real_pk
+    }
+
+    /// Get our long term `SecretKey`.
+    pub fn sk(&self) -> SecretKey {
+        self.real_sk.clone()
+    }
+
+    /// Get our DHT `PublicKey`. It's generated on every start.
+    pub fn dht_pk(&self) -> PublicKey {
+        self.dht_pk
+    }
+
+    /// Get address the UDP socket is bound to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Get our `ToxId` that should be given to friends.
+    pub fn tox_id(&self) -> ToxId {
+        let mut tox_id = ToxId::new(self.real_pk);
+        tox_id.new_nospam(Some(self.friend_requests.nospam()));
+        tox_id
+    }
+
+    /// Change our `NoSpam`. Friend requests sent to the old `ToxId` will be
+    /// dropped.
+    pub fn set_nospam(&self, nospam: NoSpam) {
+        self.friend_requests.set_nospam(nospam);
+    }
+
+    /// Get messenger module to query info about friends.
+    pub fn messenger(&self) -> &Messenger {
+        &self.messenger
+    }
+
+    /// Get file transfers module to send and receive files.
+    pub fn file_transfers(&self) -> &FileTransfers {
+        self.messenger.file_transfers()
+    }
+
+    /// Get msi module to make and answer audio/video calls.
+    pub fn msi(&self) -> &Msi {
+        self.messenger.msi()
+    }
+
+    /// Get conferences module to create, join and leave conferences.
+    pub fn conferences(&self) -> &Conferences {
+        &self.conferences
+    }
+
+    /// Add a friend by its `ToxId` and send a friend request to it.
+    pub fn add_friend(&self, tox_id: ToxId, message: String) -> Result<(), SendFriendRequestError> {
+        let dht_pk = self.friend_connections.friend_dht_pk(tox_id.pk);
+        self.messenger.add_friend(tox_id, message)?;
+        self.search_friend(tox_id.pk, dht_pk);
+        Ok(())
+    }
+
+    /// Add a friend without sending a friend request. It's used to accept
+    /// received friend requests.
+    pub fn add_friend_norequest(&self, friend_pk: PublicKey) {
+        let dht_pk = self.friend_connections.friend_dht_pk(friend_pk);
+        self.messenger.add_friend_norequest(friend_pk);
+        self.onion_client.add_friend(friend_pk);
+        self.search_friend(friend_pk, dht_pk);
+    }
+
+    /// Start searching for a newly added friend in DHT if its DHT
+    /// `PublicKey` was taken from crypto connection that existed before.
+    fn search_friend(&self, friend_pk: PublicKey, old_dht_pk: Option<PublicKey>) {
+        if old_dht_pk.is_some() {
+            return
+        }
+        if let Some(dht_pk) = self.friend_connections.friend_dht_pk(friend_pk) {
+            self.dht.add_friend(dht_pk);
+        }
+    }
+
+    /// Remove a friend.
+    pub fn remove_friend(&self, friend_pk: PublicKey) -> IoFuture<()> {
+        self.onion_client.remove_friend(friend_pk);
+        self.messenger.remove_friend(friend_pk)
+    }
+
+    /// Set DHT `PublicKey` of a friend or a conference peer. Crypto
+    /// connection to it is created and the DHT server starts searching for
+    /// it. DHT `PublicKey`s of friends are found via onion, so this is needed
+    /// only when the key is known in advance.
+    pub fn set_friend_dht_pk(&self, friend_pk: PublicKey, dht_pk: PublicKey) -> IoFuture<()> {
+        let is_known = self.messenger.has_friend(friend_pk) || self.conferences.has_connection(friend_pk);
+        if is_known && self.friend_connections.friend_dht_pk(friend_pk) != Some(dht_pk) {
+            self.dht.add_friend(dht_pk);
+        }
+        self.friend_connections.handle_dht_pk(friend_pk, dht_pk)
+    }
+
+    /// Queue chat message to a friend. `MessageReceipt` event with the
+    /// returned id is sent when the friend receives the message.
+    pub fn send_message(&self, friend_pk: PublicKey, message: String) -> Result<u32, SendMessageError> {
+        self.messenger.send_message(friend_pk, MessageKind::Normal, message)
+    }
+
+    /// Queue action message to a friend. `MessageReceipt` event with the
+    /// returned id is sent when the friend receives the message.
+    pub fn send_action(&self, friend_pk: PublicKey, action: String) -> Result<u32, SendMessageError> {
+        self.messenger.send_message(friend_pk, MessageKind::Action, action)
+    }
+
+    /// Get snapshot of chat messages that are not delivered yet.
+    pub fn outbox_snapshot(&self) -> OutboxSnapshot {
+        self.messenger.outbox_snapshot()
+    }
+
+    /// Restore chat messages that were not delivered before restart. Friends
+    /// should be added before this call.
+    pub fn restore_outbox(&self, snapshot: &OutboxSnapshot) {
+        self.messenger.restore_outbox(snapshot)
+    }
+
+    /// Let the friend know whether we are typing a message to it.
+    pub fn set_typing(&self, friend_pk: PublicKey, is_typing: bool) -> IoFuture<()> {
+        self.messenger.set_typing(friend_pk, is_typing)
+    }
+
+    /// Set our name and send it to all online friends and conferences.
+    pub fn set_name(&self, name: String) -> IoFuture<()> {
+        let conferences = self.conferences.clone();
+        Box::new(self.messenger.set_name(name).and_then(move |()| conferences.send_name()))
+    }
+
+    /// Set our status message and send it to all online friends.
+    pub fn set_status_message(&self, message: String) -> IoFuture<()> {
+        self.messenger.set_status_message(message)
+    }
+
+    /// Set our status and send it to all online friends.
+    pub fn set_status(&self, status: PeerStatus) -> IoFuture<()> {
+        self.messenger.set_status(status)
+    }
+
+    /// Pass lossless packet to the friend connection, conferences or
+    /// messenger module depending on its id.
+    fn handle_lossless(&self, friend_pk: PublicKey, data: &[u8]) -> IoFuture<()> {
+        match data.first() {
+            Some(&id) if id < MESSENGER_PACKET_ID_START =>
+                self.friend_connections.handle_lossless(friend_pk, data),
+            Some(&id) if id >= CONFERENCE_PACKET_ID_START && id <= CONFERENCE_PACKET_ID_END =>
+                self.conferences.handle_lossless(friend_pk, data),
+            _ => self.messenger.handle_lossless(friend_pk, data),
+        }
+    }
+
+    /// Pass lossy packet to conferences if it belongs to them. Other lossy
+    /// packets are dropped.
+    fn handle_lossy(&self, friend_pk: PublicKey, data: &[u8]) -> IoFuture<()> {
+        match data.first() {
+            Some(&LOSSY_CONFERENCE_PACKET_ID) => self.conferences.handle_lossy(friend_pk, data),
+            _ => {
+                trace!("Dropping lossy packet from {:?}", friend_pk);
+                Box::new(future::ok(()))
+            },
+        }
+    }
+
+    /// Run the main loop of `net_crypto` periodically.
+    fn run_net_crypto(&self) -> IoFuture<()> {
+        let net_crypto = self.net_crypto.clone();
+        let interval = Duration::from_millis(NET_CRYPTO_MAIN_LOOP_INTERVAL);
+        let wakeups = Interval::new(Instant::now(), interval);
+        let future = wakeups
+            .map_err(|e| Error::new(ErrorKind::Other, format!("Net crypto timer error: {:?}", e)))
+            .for_each(move |_instant| net_crypto.main_loop().or_else(|e| {
+                debug!("Net crypto main loop error: {}", e);
+                Ok(())
+            }));
+        Box::new(future)
+    }
+
+    /// Run UDP socket reading and writing.
+    fn run_socket(&self, socket: UdpSocket, udp_rx: mpsc::UnboundedReceiver<(DhtPacket, SocketAddr)>) -> IoFuture<()> {
+        let local_addr = self.local_addr;
+        let (sink, stream) = UdpFramed::new(socket, DhtCodec).split();
+
+        let dht = self.dht.clone();
+        let reader = stream
+            .then(|result| match result {
+                Ok(packet) => Ok(Some(packet)),
+                // ignore packet decode errors
+                Err(ref e) if e.as_fail().downcast_ref::<DecodeError>().is_some() => {
+                    debug!("Packet decode error: {:?}", e);
+                    Ok(None)
+                },
+                Err(e) => Err(Error::new(ErrorKind::Other, e.compat())),
+            })
+            .filter_map(|packet| packet)
+            .for_each(move |(packet, addr)| {
+                trace!("Received packet {:?}", packet);
+                dht.handle_packet(packet, addr).or_else(|e| {
+                    debug!("Failed to handle packet: {}", e);
+                    Ok(())
+                })
+            });
+
+        let writer = udp_rx
+            .map_err(|()| Error::new(ErrorKind::Other, "rx error"))
+            // filter out IPv6 packets if node is running in IPv4 mode
+            .filter(move |&(ref _packet, addr)| !(local_addr.is_ipv4() && addr.is_ipv6()))
+            .fold(sink, move |sink, (packet, mut addr)| {
+                if local_addr.is_ipv6() {
+                    if let IpAddr::V4(ip) = addr.ip() {
+                        addr = SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), addr.port());
+                    }
+                }
+                trace!("Sending packet {:?} to {:?}", packet, addr);
+                sink.send((packet, addr)).map_err(|e| Error::new(ErrorKind::Other, e.compat()))
+            })
+            // drop sink when rx stream is exhausted
+            .map(|_sink| ());
+
+        Box::new(reader.select(writer).map(|_| ()).map_err(|(e, _)| e))
+    }
+
+    /// Run all modules of `Tox`. This function uses `tokio::spawn` inside to
+    /// keep connections to TCP relays alive so it should be executed via
+    /// tokio. The result future is completed only in case of an error. It can
+    /// be run only once.
+    pub fn run(&self) -> IoFuture<()> {
+        let channels = if let Some(channels) = self.channels.lock().take() {
+            channels
+        } else {
+            return Box::new(future::err(Error::new(
+                ErrorKind::Other,
+                "Tox is already running"
+            )))
+        };
+
+        let mut futures = vec![
+            self.run_socket(channels.socket, channels.udp_rx),
+            self.dht.clone().run(),
+            self.run_net_crypto(),
+            Box::new(self.tcp_connections.clone().run()
+                .map_err(|e| Error::new(ErrorKind::Other, e.compat()))),
+            self.friend_connections.clone().run(),
+            self.onion_client.clone().run(),
+            self.friend_requests.clone().run(),
+            self.messenger.clone().run(),
+            self.conferences.clone().run(),
+        ];
+        if let Some(lan_discovery) = channels.lan_discovery {
+            futures.push(lan_discovery.run());
+        }
+
+        let tox = self.clone();
+        futures.push(handle_channel(channels.dht_pk_rx, move |(friend_pk, dht_pk)|
+            tox.set_friend_dht_pk(friend_pk, dht_pk)
+        ));
+        let tox = self.clone();
+        futures.push(handle_channel(channels.lossless_rx, move |(friend_pk, data)|
+            tox.handle_lossless(friend_pk, &data)
+        ));
+        let tox = self.clone();
+        futures.push(handle_channel(channels.lossy_rx, move |(friend_pk, data)|
+            tox.handle_lossy(friend_pk, &data)
+        ));
+        let friend_connections = self.friend_connections.clone();
+        futures.push(handle_channel(channels.net_crypto_status_rx, move |(friend_pk, status)|
+            friend_connections.handle_connection_status(friend_pk, status)
+        ));
+        let tcp_connections = self.tcp_connections.clone();
+        futures.push(handle_channel(channels.tcp_rx, move |(packet, relay_pk)|
+            Box::new(tcp_connections.send_to_relay(&relay_pk, packet)
+                .map_err(|e| Error::new(ErrorKind::Other, e.compat())))
+        ));
+        let net_crypto = self.net_crypto.clone();
+        futures.push(handle_channel(channels.tcp_incoming_rx, move |(packet, relay_pk)|
+            net_crypto.handle_tcp_packet(packet, relay_pk)
+        ));
+        let onion_client = self.onion_client.clone();
+        let messenger = self.messenger.clone();
+        let conferences = self.conferences.clone();
+        futures.push(handle_channel(channels.connection_status_rx, move |(friend_pk, status)| {
+            // connected friends are not searched via onion
+            onion_client.set_friend_connected(friend_pk, status);
+            Box::new(messenger.handle_connection_status(friend_pk, status)
+                .join(conferences.handle_connection_status(friend_pk, status))
+                .map(|_| ()))
+        }));
+        let friend_requests = self.friend_requests.clone();
+        futures.push(handle_channel(channels.friend_request_packet_rx, move |(friend_pk, packet)|
+            friend_requests.handle_friend_request(friend_pk, packet)
+        ));
+        let onion_client = self.onion_client.clone();
+        futures.push(handle_channel(channels.onion_announce_response_rx, move |(packet, _addr)|
+            onion_client.handle_announce_response(&packet)
+        ));
+        let onion_client = self.onion_client.clone();
+        futures.push(handle_channel(channels.onion_data_response_rx, move |(packet, _addr)|
+            onion_client.handle_data_response(&packet)
+        ));
+        let messenger = self.messenger.clone();
+        futures.push(handle_channel(channels.friend_request_rx, move |(friend_pk, message)|
+            messenger.handle_friend_request(friend_pk, message)
+        ));
+
+        let tcp_connections = self.tcp_connections.clone();
+        let tcp_relays = self.tcp_relays.clone();
+        let future = future::lazy(move || {
+            for (addr, relay_pk) in tcp_relays {
+                tokio::spawn(tcp_connections.add_relay(addr, relay_pk)
+                    .map_err(move |e| debug!("TCP relay {} failed: {}", addr, e)));
+            }
+            future::select_all(futures)
+                .map(|_| ())
+                .map_err(|(e, _, _)| e)
+        });
+
+        Box::new(future)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::runtime::Runtime;
+    use tokio::timer::Timeout;
+
+    use toxcore::binary_io::*;
+    use toxcore::friend_connection::packet::Packet as FriendConnectionPacket;
+    use toxcore::dht::packet::MAX_CRYPTO_DATA_SIZE;
+    use toxcore::messenger::packet::{Nickname, Packet as MessengerPacket};
+
+    /// Maximum time in seconds to wait for a single event in loopback tests.
+    const EVENT_TIMEOUT: u64 = 60;
+
+    fn build_tox() -> (Tox, mpsc::UnboundedReceiver<Event>) {
+        ToxBuilder::new()
+            .udp_addr("127.0.0.1:0".parse().unwrap())
+            .lan_discovery(false)
+            .build()
+            .unwrap()
+    }
+
+    /// Receive events skipping them until one matches the predicate.
+    fn wait_event<F>(runtime: &mut Runtime, mut events: mpsc::UnboundedReceiver<Event>, predicate: F) -> mpsc::UnboundedReceiver<Event>
+        where F: Fn(&Event) -> bool
+    {
+        loop {
+            let future = Timeout::new(events.into_future().map_err(|_| ()), Duration::from_secs(EVENT_TIMEOUT));
+            let (event, rest) = runtime.block_on(future).unwrap();
+            events = rest;
+            if predicate(&event.unwrap()) {
+                return events
+            }
+        }
+    }
+
+    #[test]
+    fn build() {
+        let (pk, sk) = gen_keypair();
+        let nospam = NoSpam([42; 4]);
+        let (tox, _events) = ToxBuilder::new()
+            .udp_addr("127.0.0.1:0".parse().unwrap())
+            .keys(pk, sk.clone())
+            .nospam(nospam)
+            .build()
+            .unwrap();
+
+        assert_eq!(tox.pk(), pk);
+        assert_eq!(tox.sk(), sk);
+        assert_ne!(tox.local_addr().port(), 0);
+
+        let tox_id = tox.tox_id();
+        assert!(tox_id.is_valid());
+        assert_eq!(tox_id.pk, pk);
+        assert_eq!(tox_id.nospam(), nospam);
+
+        let new_nospam = NoSpam([43; 4]);
+        tox.set_nospam(new_nospam);
+        assert_eq!(tox.tox_id().nospam(), new_nospam);
+    }
+
+    #[test]
+    fn add_friend() {
+        let (tox, _events) = build_tox();
+
+        assert_eq!(
+            tox.add_friend(tox.tox_id(), "Hello".to_owned()),
+            Err(SendFriendRequestError::OwnKeyError)
+        );
+
+        let tox_id = ToxId::new(gen_keypair().0);
+        tox.add_friend(tox_id, "Hello".to_owned()).unwrap();
+        assert!(tox.messenger().has_friend(tox_id.pk));
+
+        let dht_pk = gen_keypair().0;
+        tox.set_friend_dht_pk(tox_id.pk, dht_pk).wait().unwrap();
+        assert_eq!(tox.friend_connections.friend_dht_pk(tox_id.pk), Some(dht_pk));
+
+        tox.remove_friend(tox_id.pk).wait().unwrap();
+        assert!(!tox.messenger().has_friend(tox_id.pk));
+    }
+
+    #[test]
+    fn run_twice() {
+        let (tox, _events) = build_tox();
+
+        let _future = tox.run();
+        assert!(tox.run().wait().is_err());
+    }
+
+    #[test]
+    fn run_events() {
+        let (tox, events) = build_tox();
+        let friend_pk = gen_keypair().0;
+        let mut runtime = Runtime::new().unwrap();
+        runtime.spawn(tox.run().map_err(|e| panic!("Tox error: {}", e)));
+
+        // friend request received via net_crypto passes all modules
+        let request = FriendRequestsPacket::new(tox.friend_requests.nospam(), b"Hello".to_vec());
+        let mut buf = [0; MAX_CRYPTO_DATA_SIZE];
+        let (_, size) = FriendConnectionPacket::FriendRequests(request).to_bytes((&mut buf, 0)).unwrap();
+        runtime.block_on(tox.handle_lossless(friend_pk, &buf[..size])).unwrap();
+
+        let (event, events) = runtime.block_on(events.into_future()).map_err(|_| ()).unwrap();
+        assert_eq!(event, Some(Event::FriendRequest { pk: friend_pk, message: "Hello".to_owned() }));
+
+        tox.add_friend_norequest(friend_pk);
+        let packet = MessengerPacket::Nickname(Nickname::new("Alice".to_owned()));
+        let (_, size) = packet.to_bytes((&mut buf, 0)).unwrap();
+        runtime.block_on(tox.handle_lossless(friend_pk, &buf[..size])).unwrap();
+
+        let (event, _events) = runtime.block_on(events.into_future()).map_err(|_| ()).unwrap();
+        assert_eq!(event, Some(Event::FriendName { pk: friend_pk, name: "Alice".to_owned() }));
+
+        runtime.shutdown_now();
+    }
+
+    fn build_tox_with_bootstrap_node(node: PackedNode) -> (Tox, mpsc::UnboundedReceiver<Event>) {
+        ToxBuilder::new()
+            .udp_addr("127.0.0.1:0".parse().unwrap())
+            .lan_discovery(false)
+            .bootstrap_node(node)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn loopback_add_friend_and_exchange_messages() {
+        let mut runtime = Runtime::new().unwrap();
+
+        // onion paths need at least three DHT nodes besides Alice and Bob
+        let (seed, _seed_events) = build_tox();
+        let seed_node = PackedNode::new(seed.local_addr(), &seed.dht_pk());
+        runtime.spawn(seed.run().map_err(|e| panic!("Seed error: {}", e)));
+        for _ in 0 .. 2 {
+            let (node, _events) = build_tox_with_bootstrap_node(seed_node);
+            runtime.spawn(node.run().map_err(|e| panic!("Node error: {}", e)));
+        }
+
+        let (alice, alice_events) = build_tox_with_bootstrap_node(seed_node);
+        let (bob, bob_events) = build_tox_with_bootstrap_node(seed_node);
+        let alice_pk = alice.pk();
+        let bob_pk = bob.pk();
+
+        runtime.spawn(alice.run().map_err(|e| panic!("Alice error: {}", e)));
+        runtime.spawn(bob.run().map_err(|e| panic!("Bob error: {}", e)));
+
+        // Alice knows only ToxId of Bob, DHT key of Bob is found via onion
+        alice.add_friend(bob.tox_id(), "Hello".to_owned()).unwrap();
+
+        let bob_events = wait_event(&mut runtime, bob_events, |event|
+            *event == Event::FriendRequest { pk: alice_pk, message: "Hello".to_owned() }
+        );
+        bob.add_friend_norequest(alice_pk);
+        bob.send_message(alice_pk, "Hi Alice".to_owned()).unwrap();
+
+        let _alice_events = wait_event(&mut runtime, alice_events, |event|
+            *event == Event::FriendMessage { pk: bob_pk, message: "Hi Alice".to_owned() }
+        );
+        alice.send_message(bob_pk, "Hi Bob".to_owned()).unwrap();
+
+        let _bob_events = wait_event(&mut runtime, bob_events, |event|
+            *event == Event::FriendMessage { pk: alice_pk, message: "Hi Bob".to_owned() }
+        );
+
+        runtime.shutdown_now();
+    }
+}
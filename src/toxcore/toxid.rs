@@ -222,6 +222,35 @@ impl ToxId {
         }
         self.checksum = Self::checksum(&self.pk, self.nospam);
     }
+
+    /// Get `NoSpam` of the `ToxId`.
+    pub fn nospam(&self) -> NoSpam {
+        self.nospam
+    }
+
+    /** Check if checksum of the `ToxId` matches its `PublicKey` and `NoSpam`.
+
+    E.g.
+
+    ```
+    use self::tox::toxcore::binary_io::*;
+    use self::tox::toxcore::crypto_core::gen_keypair;
+    use self::tox::toxcore::toxid::{ToxId, TOXIDBYTES};
+
+    let (pk, _) = gen_keypair();
+    let toxid = ToxId::new(pk);
+    assert!(toxid.is_valid());
+
+    let mut buf = [0; TOXIDBYTES];
+    toxid.to_bytes((&mut buf, 0)).unwrap();
+    buf[TOXIDBYTES - 1] ^= 0xff;
+    let (_, toxid) = ToxId::from_bytes(&buf).unwrap();
+    assert!(!toxid.is_valid());
+    ```
+    */
+    pub fn is_valid(&self) -> bool {
+        Self::checksum(&self.pk, self.nospam) == self.checksum
+    }
 }
 
 impl FromBytes for ToxId {
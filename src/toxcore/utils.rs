@@ -1,7 +1,10 @@
 /*! Common utility functions
 */
 
+use std::time::Instant;
+
 use toxcore::crypto_core::*;
+use toxcore::time::*;
 
 /// Generate non-zero ping_id
 pub fn gen_ping_id() -> u64 {
@@ -11,3 +14,132 @@ pub fn gen_ping_id() -> u64 {
     }
     ping_id
 }
+
+/// Rate limit of a token bucket. The bucket is refilled with `rate` tokens per
+/// second and can't hold more than `burst` tokens.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RateLimit {
+    /// Number of tokens added to the bucket every second
+    pub rate: u32,
+    /// Maximum number of tokens the bucket can hold
+    pub burst: u32,
+}
+
+/// Number of fractional units in one token. Tokens are stored in these units
+/// to refill the bucket precisely even when it's checked very often.
+const TOKEN_UNITS: u64 = 1000;
+
+/// Token bucket that is used to limit rate of packets or bytes.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TokenBucket {
+    /// Rate limit of this bucket
+    limit: RateLimit,
+    /// Current amount of tokens in `TOKEN_UNITS`
+    units: u64,
+    /// Time when the bucket was refilled last time
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Create new full `TokenBucket`
+    pub fn new(limit: RateLimit) -> TokenBucket {
+        TokenBucket {
+            limit,
+            units: u64::from(limit.burst) * TOKEN_UNITS,
+            last_refill: clock_now(),
+        }
+    }
+
+    /// Refill the bucket according to time elapsed since the last refill
+    fn refill(&mut self) {
+        let now = clock_now();
+        let elapsed = now - self.last_refill;
+        let elapsed_ms = elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis());
+        // units per millisecond are equal to tokens per second
+        let added = elapsed_ms.saturating_mul(u64::from(self.limit.rate));
+        if added > 0 {
+            let capacity = u64::from(self.limit.burst) * TOKEN_UNITS;
+            self.units = self.units.saturating_add(added).min(capacity);
+            self.last_refill = now;
+        }
+    }
+
+    /// Check if the bucket has the given amount of tokens without taking
+    /// them.
+    pub fn can_take(&mut self, tokens: u64) -> bool {
+        self.refill();
+        tokens.saturating_mul(TOKEN_UNITS) <= self.units
+    }
+
+    /// Take the given amount of tokens from the bucket. Returns `false` if
+    /// there are not enough tokens.
+    pub fn try_take(&mut self, tokens: u64) -> bool {
+        self.refill();
+        let units = tokens.saturating_mul(TOKEN_UNITS);
+        if units <= self.units {
+            self.units -= units;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use tokio_executor;
+    use tokio_timer::clock::*;
+
+    #[test]
+    fn token_bucket_burst() {
+        let mut bucket = TokenBucket::new(RateLimit { rate: 10, burst: 3 });
+
+        assert!(bucket.try_take(1));
+        assert!(bucket.try_take(2));
+        assert!(!bucket.try_take(1));
+    }
+
+    #[test]
+    fn token_bucket_can_take() {
+        let mut bucket = TokenBucket::new(RateLimit { rate: 10, burst: 1 });
+
+        assert!(bucket.can_take(1));
+        assert!(!bucket.can_take(2));
+        // tokens are not taken
+        assert!(bucket.try_take(1));
+        assert!(!bucket.can_take(1));
+    }
+
+    #[test]
+    fn token_bucket_refill() {
+        let mut bucket = TokenBucket::new(RateLimit { rate: 10, burst: 20 });
+
+        assert!(bucket.try_take(20));
+        assert!(!bucket.try_take(1));
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(
+            Instant::now() + Duration::from_millis(500)
+        ));
+
+        with_default(&clock, &mut enter, |_| {
+            // 5 tokens are added in 500 ms
+            assert!(bucket.try_take(5));
+            assert!(!bucket.try_take(1));
+        });
+
+        let clock = Clock::new_with_now(ConstNow(
+            Instant::now() + Duration::from_secs(100)
+        ));
+
+        with_default(&clock, &mut enter, |_| {
+            // bucket can't hold more than burst
+            assert!(bucket.try_take(20));
+            assert!(!bucket.try_take(1));
+        });
+    }
+}